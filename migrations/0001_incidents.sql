@@ -0,0 +1,12 @@
+-- Upstream incident timeline written by the processors, read by GET /admin/incidents
+CREATE TABLE IF NOT EXISTS incidents (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    host TEXT NOT NULL,
+    kind TEXT NOT NULL,
+    started_at INTEGER NOT NULL,
+    ended_at INTEGER,
+    detail TEXT
+);
+
+CREATE INDEX IF NOT EXISTS incidents_open ON incidents (host, kind, ended_at);
+CREATE INDEX IF NOT EXISTS incidents_started_at ON incidents (started_at);
@@ -0,0 +1,9 @@
+-- Per-day request counts by tenant and label set, read by GET /admin/usage
+CREATE TABLE IF NOT EXISTS usage (
+    day INTEGER NOT NULL,
+    tenant TEXT NOT NULL,
+    labels TEXT NOT NULL,
+    requests INTEGER NOT NULL DEFAULT 0,
+    failed INTEGER NOT NULL DEFAULT 0,
+    PRIMARY KEY (day, tenant, labels)
+);
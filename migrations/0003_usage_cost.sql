@@ -0,0 +1,4 @@
+-- Summed per-request cost estimates (see X-Proxy-Cost), read by GET /admin/usage
+ALTER TABLE usage ADD COLUMN subrequests INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE usage ADD COLUMN do_requests INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE usage ADD COLUMN bytes_egressed INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,9 @@
+-- Per-hour request counts per region for the proxy's own SLOs, read by GET /admin/slo
+CREATE TABLE IF NOT EXISTS slo (
+    hour INTEGER NOT NULL,
+    region TEXT NOT NULL,
+    requests INTEGER NOT NULL DEFAULT 0,
+    errors INTEGER NOT NULL DEFAULT 0,
+    slow INTEGER NOT NULL DEFAULT 0,
+    PRIMARY KEY (hour, region)
+);
@@ -0,0 +1,10 @@
+-- Per-minute outbound calls by upstream host, read by GET /admin/pacing/report
+CREATE TABLE IF NOT EXISTS pacing_minutes (
+    minute INTEGER NOT NULL,
+    host TEXT NOT NULL,
+    requests INTEGER NOT NULL DEFAULT 0,
+    throttled INTEGER NOT NULL DEFAULT 0,
+    rate_limited INTEGER NOT NULL DEFAULT 0,
+    peak_concurrency INTEGER NOT NULL DEFAULT 0,
+    PRIMARY KEY (minute, host)
+);
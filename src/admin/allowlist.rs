@@ -0,0 +1,50 @@
+use serde::Deserialize;
+use worker::*;
+
+use crate::allowlist::{self, HostRule};
+use crate::canonical_url;
+use crate::error::ProxyError;
+use crate::tenants::TenantStore;
+
+#[derive(Deserialize)]
+struct TestRequest {
+    url: String,
+    /// Evaluate the stored allowlist of this tenant
+    #[serde(default)]
+    tenant: Option<String>,
+    /// Evaluate these rules instead, e.g. before saving them on a tenant
+    #[serde(default)]
+    rules: Option<Vec<String>>,
+}
+
+/// POST /admin/allowlist/test - dry-run a URL against a tenant's or ad-hoc allowlist
+pub async fn test(mut req: Request, env: &Env) -> Result<Response> {
+    let test = match req.json::<TestRequest>().await {
+        Ok(test) => test,
+        Err(e) => return ProxyError::InvalidRequest(e.to_string()).to_response(None),
+    };
+
+    // Checked in canonical form, as the edge checks requests
+    let url = match canonical_url::parse(&test.url) {
+        Some(url) if url.has_host() => url,
+        _ => return ProxyError::InvalidRequest(format!("Invalid URL: {}", test.url)).to_response(None),
+    };
+
+    let rules = match (test.tenant, test.rules) {
+        (None, Some(rules)) => {
+            if let Err(e) = HostRule::parse_all(&rules) {
+                return e.to_response(None);
+            }
+            rules
+        }
+        (Some(id), None) => match TenantStore::new(env)?.get(&id).await? {
+            Some(tenant) => tenant.allowed_hosts,
+            None => return ProxyError::NotFound(format!("Unknown tenant: {}", id)).to_response(None),
+        },
+        _ => {
+            return ProxyError::InvalidRequest("Give exactly one of tenant or rules".to_string()).to_response(None)
+        }
+    };
+
+    Response::from_json(&allowlist::evaluate(&rules, &url))
+}
@@ -0,0 +1,30 @@
+use worker::*;
+
+use crate::cache::CacheRules;
+use crate::error::ProxyError;
+use crate::log_info;
+
+/// GET /admin/cache-rules - every result cache rule
+pub async fn get(env: &Env) -> Result<Response> {
+    Response::from_json(&CacheRules::load_fresh(env).await?)
+}
+
+/// PUT /admin/cache-rules - replace the rules
+///
+/// Entries cached under a removed rule are no longer read, and expire with their TTL.
+pub async fn save(mut req: Request, env: &Env) -> Result<Response> {
+    let mut rules = match req.json::<CacheRules>().await {
+        Ok(rules) => rules,
+        Err(e) => return ProxyError::InvalidRequest(e.to_string()).to_response(None),
+    };
+    if let Err(e) = rules.validate() {
+        return e.to_response(None);
+    }
+    for rule in &mut rules.0 {
+        rule.host = rule.host.as_ref().map(|host| host.to_lowercase());
+    }
+    rules.save(env).await?;
+    log_info!("Cache rules set ({} rules)", rules.0.len());
+
+    Response::from_json(&rules)
+}
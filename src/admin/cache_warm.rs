@@ -0,0 +1,31 @@
+use worker::*;
+
+use crate::cache_warm::{self, WarmRequest};
+use crate::config::Config;
+use crate::error::ProxyError;
+use crate::tenants::TenantStore;
+
+/// POST /admin/cache/warm - prefetch cacheable requests into the shards that will serve them
+///
+/// Runs synchronously at a controlled rate and returns what was warmed.
+pub async fn run(mut req: Request, env: &Env, config: &Config) -> Result<Response> {
+    let mut warm = match req.json::<WarmRequest>().await {
+        Ok(warm) => warm,
+        Err(e) => return ProxyError::InvalidRequest(e.to_string()).to_response(None),
+    };
+    warm.regions = warm.regions.iter().map(|region| region.to_lowercase()).collect();
+    warm.regions.sort();
+    warm.regions.dedup();
+    if let Err(e) = warm.validate() {
+        return e.to_response(None);
+    }
+    let tenant = match &warm.tenant {
+        Some(id) => match TenantStore::new(env)?.get(id).await? {
+            Some(tenant) => Some(tenant),
+            None => return ProxyError::NotFound(format!("Unknown tenant: {}", id)).to_response(None),
+        },
+        None => None,
+    };
+
+    Response::from_json(&cache_warm::run(env, config, &warm, tenant.as_ref()).await?)
+}
@@ -0,0 +1,93 @@
+use serde::Serialize;
+use worker::*;
+
+use super::pagination::{Page, PageRequest};
+use crate::archive::{time_marker, Archive};
+use crate::capture::{CapturedRequest, SamplingConfig, CAPTURE_PREFIX};
+use crate::error::ProxyError;
+use crate::log_info;
+
+/// A listed capture; fetch `GET /admin/captures/{id}` for the payloads
+#[derive(Serialize)]
+struct CaptureEntry {
+    id: String,
+    at: u64,
+}
+
+/// GET /admin/captures/sampling
+pub async fn get_sampling(env: &Env) -> Result<Response> {
+    Response::from_json(&SamplingConfig::load_fresh(env).await?)
+}
+
+/// PUT /admin/captures/sampling - replace the sampling config
+pub async fn save_sampling(mut req: Request, env: &Env) -> Result<Response> {
+    let config = match req.json::<SamplingConfig>().await {
+        Ok(config) => config,
+        Err(e) => return ProxyError::InvalidRequest(e.to_string()).to_response(None),
+    };
+    if let Err(e) = config.validate() {
+        return e.to_response(None);
+    }
+    config.save(env).await?;
+    log_info!("Capture sampling set to {} with {} rules", config.rate, config.rules.len());
+
+    Response::from_json(&config)
+}
+
+/// GET /admin/captures - captured requests, oldest first
+///
+/// Query: `from` and `to` (epoch millis, `to` exclusive), plus `cursor` and `limit`.
+pub async fn list(req: &Request, env: &Env) -> Result<Response> {
+    let archive = match Archive::new(env) {
+        Some(archive) => archive,
+        None => return ProxyError::NotFound("No ARCHIVE bucket is bound".to_string()).to_response(None),
+    };
+
+    let url = req.url()?;
+    let page = match PageRequest::from_query(&url) {
+        Ok(page) => page,
+        Err(e) => return e.to_response(None),
+    };
+    let mut from = 0;
+    let mut to = u64::MAX;
+    for (key, value) in url.query_pairs() {
+        let parsed = match key.as_ref() {
+            "from" => value.parse().map(|v| from = v).map_err(|_| "from"),
+            "to" => value.parse().map(|v| to = v).map_err(|_| "to"),
+            _ => Ok(()),
+        };
+        if let Err(param) = parsed {
+            return ProxyError::InvalidRequest(format!("Invalid {} parameter: {}", param, value)).to_response(None);
+        }
+    }
+
+    // The cursor is the id of the last capture already listed
+    let start_after = match &page.cursor {
+        Some(id) => format!("{}{}", CAPTURE_PREFIX, id),
+        None => time_marker(CAPTURE_PREFIX, from),
+    };
+    let keys = archive
+        .keys_after(CAPTURE_PREFIX, start_after, to, page.limit as usize + 1)
+        .await?;
+    let entries = keys
+        .into_iter()
+        .map(|key| {
+            let id = key.trim_start_matches(CAPTURE_PREFIX).to_string();
+            let at = id.split('-').next().and_then(|at| at.parse().ok()).unwrap_or_default();
+            CaptureEntry { id, at }
+        })
+        .collect();
+    Page::from_rows(entries, &page, None, |entry| entry.id.clone()).to_response()
+}
+
+/// GET /admin/captures/{id} - a captured request with its full response
+pub async fn get(env: &Env, id: &str) -> Result<Response> {
+    let archive = match Archive::new(env) {
+        Some(archive) => archive,
+        None => return ProxyError::NotFound("No ARCHIVE bucket is bound".to_string()).to_response(None),
+    };
+    match archive.get_json::<CapturedRequest>(&format!("{}{}", CAPTURE_PREFIX, id)).await? {
+        Some(capture) => Response::from_json(&capture),
+        None => ProxyError::NotFound(format!("Unknown capture: {}", id)).to_response(None),
+    }
+}
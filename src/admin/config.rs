@@ -0,0 +1,49 @@
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use worker::*;
+
+use crate::config::{Config, Setting, Source};
+
+/// KV documents holding deployment-wide settings, each edited through its own admin route
+const DOCUMENTS: &[&str] = &[
+    crate::cache::CACHE_RULES_KEY,
+    #[cfg(feature = "archive-r2")]
+    crate::capture::SAMPLING_KEY,
+    crate::default_headers::DEFAULT_HEADERS_KEY,
+    crate::handlers::pacing::PACING_KEY,
+    crate::keep_warm::KEEP_WARM_KEY,
+    crate::retry::IDEMPOTENT_ACTIONS_KEY,
+    crate::retry::RETRY_POLICIES_KEY,
+    crate::routing::SHARD_WEIGHTS_KEY,
+    crate::sandbox::SANDBOX_KEY,
+    crate::stubs::STUBS_KEY,
+];
+
+#[derive(Serialize)]
+struct EffectiveConfig<'a> {
+    #[serde(flatten)]
+    config: &'a Config,
+    /// Stored documents; `null` (with a default source) means the built-in default
+    documents: BTreeMap<&'static str, Setting<Value>>,
+}
+
+/// GET /admin/config/effective - resolved variables and KV documents, with their sources
+pub async fn effective(env: &Env, config: &Config) -> Result<Response> {
+    let kv = env.kv("CONFIG")?;
+    let mut documents = BTreeMap::new();
+    for key in DOCUMENTS {
+        let setting = match kv.get(key).json::<Value>().await? {
+            Some(value) => Setting {
+                value,
+                source: Source::Kv(key.to_string()),
+            },
+            None => Setting {
+                value: Value::Null,
+                source: Source::Default,
+            },
+        };
+        documents.insert(*key, setting);
+    }
+    Response::from_json(&EffectiveConfig { config, documents })
+}
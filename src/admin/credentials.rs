@@ -0,0 +1,144 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use worker::*;
+
+use crate::allowlist::HostRule;
+use crate::credentials::{is_valid_credential_name, Credential, CredentialHealth, CredentialStore};
+use crate::error::ProxyError;
+use crate::log_info;
+
+#[derive(Deserialize)]
+struct SaveCredentialRequest {
+    #[serde(default)]
+    headers: HashMap<String, String>,
+    /// Key for `query_key` auth
+    #[serde(default)]
+    secret: Option<String>,
+    #[serde(default)]
+    allowed_tenants: Vec<String>,
+    #[serde(default)]
+    allowed_hosts: Vec<String>,
+    #[serde(default)]
+    backup: Option<String>,
+    #[serde(default)]
+    failure_threshold: Option<u32>,
+    #[serde(default)]
+    alert_webhook: Option<String>,
+}
+
+/// Credential as shown by the admin API: header values and secret masked, plus health
+#[derive(Serialize)]
+struct CredentialView {
+    #[serde(flatten)]
+    credential: Credential,
+    healthy: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    health: Option<CredentialHealth>,
+}
+
+async fn view(store: &CredentialStore, credential: &Credential) -> Result<CredentialView> {
+    let health = store.health(&credential.name).await?;
+    Ok(CredentialView {
+        credential: credential.redacted(),
+        healthy: health.is_none(),
+        health,
+    })
+}
+
+/// GET /admin/credentials
+pub async fn list(env: &Env) -> Result<Response> {
+    let store = CredentialStore::new(env)?;
+    let mut views = Vec::new();
+    for credential in store.list().await? {
+        views.push(view(&store, &credential).await?);
+    }
+    Response::from_json(&views)
+}
+
+/// GET /admin/credentials/{name}
+pub async fn get(env: &Env, name: &str) -> Result<Response> {
+    let store = CredentialStore::new(env)?;
+    match store.get(name).await? {
+        Some(credential) => Response::from_json(&view(&store, &credential).await?),
+        None => ProxyError::NotFound(format!("Unknown credential: {}", name)).to_response(None),
+    }
+}
+
+/// PUT /admin/credentials/{name} - create or replace a credential
+///
+/// Replacing a credential (e.g. after rotating an expired password) also
+/// marks it healthy again.
+pub async fn save(mut req: Request, env: &Env, name: &str) -> Result<Response> {
+    if !is_valid_credential_name(name) {
+        return ProxyError::InvalidRequest(format!(
+            "Credential name must be 1-64 chars of [A-Za-z0-9._-]: {}",
+            name
+        ))
+        .to_response(None);
+    }
+
+    let save = match req.json::<SaveCredentialRequest>().await {
+        Ok(save) => save,
+        Err(e) => return ProxyError::InvalidRequest(e.to_string()).to_response(None),
+    };
+    if save.headers.is_empty() && save.secret.is_none() {
+        return ProxyError::InvalidRequest("A credential needs headers or a secret".to_string()).to_response(None);
+    }
+    if save.failure_threshold == Some(0) {
+        return ProxyError::InvalidRequest("failure_threshold must be at least 1".to_string()).to_response(None);
+    }
+    if let Err(e) = HostRule::parse_all(&save.allowed_hosts) {
+        return e.to_response(None);
+    }
+    if save.backup.as_deref() == Some(name) {
+        return ProxyError::InvalidRequest("A credential can't be its own backup".to_string()).to_response(None);
+    }
+
+    let store = CredentialStore::new(env)?;
+    if let Some(backup) = &save.backup {
+        if store.get(backup).await?.is_none() {
+            return ProxyError::NotFound(format!("Unknown backup credential: {}", backup)).to_response(None);
+        }
+    }
+
+    let credential = Credential {
+        name: name.to_string(),
+        headers: save.headers,
+        secret: save.secret,
+        allowed_tenants: save.allowed_tenants,
+        allowed_hosts: save.allowed_hosts,
+        backup: save.backup,
+        failure_threshold: save.failure_threshold.unwrap_or(5),
+        alert_webhook: save.alert_webhook,
+    };
+    store.save(&credential).await?;
+    store.mark_healthy(name).await?;
+    log_info!("Credential {} saved", name);
+
+    Response::from_json(&view(&store, &credential).await?)
+}
+
+/// DELETE /admin/credentials/{name}
+pub async fn delete(env: &Env, name: &str) -> Result<Response> {
+    let store = CredentialStore::new(env)?;
+    if store.get(name).await?.is_none() {
+        return ProxyError::NotFound(format!("Unknown credential: {}", name)).to_response(None);
+    }
+    store.delete(name).await?;
+    log_info!("Credential {} deleted", name);
+
+    Ok(Response::empty()?.with_status(204))
+}
+
+/// POST /admin/credentials/{name}/reset - mark a credential healthy again
+pub async fn reset(env: &Env, name: &str) -> Result<Response> {
+    let store = CredentialStore::new(env)?;
+    let credential = match store.get(name).await? {
+        Some(credential) => credential,
+        None => return ProxyError::NotFound(format!("Unknown credential: {}", name)).to_response(None),
+    };
+    store.mark_healthy(name).await?;
+    log_info!("Credential {} marked healthy", name);
+
+    Response::from_json(&view(&store, &credential).await?)
+}
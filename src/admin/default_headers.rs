@@ -0,0 +1,29 @@
+use worker::*;
+
+use crate::default_headers::DefaultHeaders;
+use crate::error::ProxyError;
+use crate::log_info;
+
+/// GET /admin/default-headers - every default header rule
+pub async fn get(env: &Env) -> Result<Response> {
+    Response::from_json(&DefaultHeaders::load_fresh(env).await?)
+}
+
+/// PUT /admin/default-headers - replace the rules
+pub async fn save(mut req: Request, env: &Env) -> Result<Response> {
+    let mut rules = match req.json::<DefaultHeaders>().await {
+        Ok(rules) => rules,
+        Err(e) => return ProxyError::InvalidRequest(e.to_string()).to_response(None),
+    };
+    if let Err(e) = rules.validate() {
+        return e.to_response(None);
+    }
+    for rule in &mut rules.0 {
+        rule.region = rule.region.as_ref().map(|region| region.to_lowercase());
+        rule.host = rule.host.as_ref().map(|host| host.to_lowercase());
+    }
+    rules.save(env).await?;
+    log_info!("Default headers set ({} rules)", rules.0.len());
+
+    Response::from_json(&rules)
+}
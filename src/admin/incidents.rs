@@ -0,0 +1,58 @@
+use worker::*;
+
+use super::pagination::{Page, PageRequest};
+use crate::error::ProxyError;
+use crate::incidents::IncidentLog;
+
+/// GET /admin/incidents - upstream incident timeline, newest first
+///
+/// Query: `host`, `since` (epoch millis; incidents still ongoing or ended
+/// after it), `open=true` for ongoing incidents only, plus `cursor` and `limit`.
+pub async fn list(req: &Request, env: &Env) -> Result<Response> {
+    let log = match IncidentLog::new(env) {
+        Some(log) => log,
+        None => return ProxyError::NotFound("No DB database is bound".to_string()).to_response(None),
+    };
+
+    let url = req.url()?;
+    let page = match PageRequest::from_query(&url) {
+        Ok(page) => page,
+        Err(e) => return e.to_response(None),
+    };
+    let mut host = None;
+    let mut since = 0;
+    let mut open_only = false;
+    for (key, value) in url.query_pairs() {
+        let parsed = match key.as_ref() {
+            "host" => {
+                host = Some(value.to_lowercase());
+                Ok(())
+            }
+            "since" => value.parse().map(|v| since = v).map_err(|_| "since"),
+            "open" => value.parse().map(|v| open_only = v).map_err(|_| "open"),
+            _ => Ok(()),
+        };
+        if let Err(param) = parsed {
+            return ProxyError::InvalidRequest(format!("Invalid {} parameter: {}", param, value)).to_response(None);
+        }
+    }
+
+    // The cursor is the (started_at, id) of the last incident already listed
+    let after = match page.cursor.as_deref().map(parse_position) {
+        Some(Some(after)) => Some(after),
+        Some(None) => return ProxyError::InvalidRequest("Invalid cursor".to_string()).to_response(None),
+        None => None,
+    };
+
+    let incidents = log.list(host.as_deref(), since, open_only, after, page.limit + 1).await?;
+    let total = log.count(host.as_deref(), since, open_only).await?;
+    Page::from_rows(incidents, &page, Some(total), |incident| {
+        format!("{}:{}", incident.started_at, incident.id)
+    })
+    .to_response()
+}
+
+fn parse_position(cursor: &str) -> Option<(u64, i64)> {
+    let (started_at, id) = cursor.split_once(':')?;
+    Some((started_at.parse().ok()?, id.parse().ok()?))
+}
@@ -0,0 +1,39 @@
+use worker::*;
+
+use crate::config::Config;
+use crate::error::ProxyError;
+use crate::keep_warm::{self, KeepWarm};
+use crate::log_info;
+
+/// GET /admin/keep-warm - regions pinged and how often
+pub async fn get(env: &Env) -> Result<Response> {
+    Response::from_json(&KeepWarm::load_fresh(env).await?)
+}
+
+/// PUT /admin/keep-warm - replace the settings; an empty `regions` turns pings off
+pub async fn save(mut req: Request, env: &Env) -> Result<Response> {
+    let mut settings = match req.json::<KeepWarm>().await {
+        Ok(settings) => settings,
+        Err(e) => return ProxyError::InvalidRequest(e.to_string()).to_response(None),
+    };
+    settings.regions = settings.regions.iter().map(|region| region.to_lowercase()).collect();
+    settings.regions.sort();
+    settings.regions.dedup();
+    if let Err(e) = settings.validate() {
+        return e.to_response(None);
+    }
+    settings.save(env).await?;
+    log_info!(
+        "Keep-warm set to {} regions every {} minutes",
+        settings.regions.len(),
+        settings.interval_minutes
+    );
+
+    Response::from_json(&settings)
+}
+
+/// POST /admin/keep-warm/run - ping the configured regions now
+pub async fn run(env: &Env, config: &Config) -> Result<Response> {
+    let settings = KeepWarm::load_fresh(env).await?;
+    Response::from_json(&keep_warm::run(env, config, &settings).await)
+}
@@ -0,0 +1,28 @@
+use worker::*;
+
+use crate::error::ProxyError;
+use crate::locale::LocaleRules;
+use crate::log_info;
+
+/// GET /admin/locale-rules - every locale rule
+pub async fn get(env: &Env) -> Result<Response> {
+    Response::from_json(&LocaleRules::load_fresh(env).await?)
+}
+
+/// PUT /admin/locale-rules - replace the rules
+pub async fn save(mut req: Request, env: &Env) -> Result<Response> {
+    let mut rules = match req.json::<LocaleRules>().await {
+        Ok(rules) => rules,
+        Err(e) => return ProxyError::InvalidRequest(e.to_string()).to_response(None),
+    };
+    if let Err(e) = rules.validate() {
+        return e.to_response(None);
+    }
+    for rule in &mut rules.0 {
+        rule.host = rule.host.to_lowercase();
+    }
+    rules.save(env).await?;
+    log_info!("Locale rules set ({} rules)", rules.0.len());
+
+    Response::from_json(&rules)
+}
@@ -0,0 +1,59 @@
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use worker::*;
+
+use crate::error::ProxyError;
+use crate::maintenance::{MaintenanceStore, MaintenanceWindow};
+use crate::log_info;
+
+#[derive(Deserialize)]
+struct SaveCalendarRequest {
+    windows: Vec<MaintenanceWindow>,
+}
+
+/// GET /admin/maintenance - every host's calendar
+pub async fn list(env: &Env) -> Result<Response> {
+    let store = MaintenanceStore::new(env)?;
+    let mut calendars = BTreeMap::new();
+    for host in store.hosts().await? {
+        let windows = store.get(&host).await?;
+        calendars.insert(host, windows);
+    }
+    Response::from_json(&calendars)
+}
+
+/// GET /admin/maintenance/{host}
+pub async fn get(env: &Env, host: &str) -> Result<Response> {
+    Response::from_json(&MaintenanceStore::new(env)?.get(&host.to_lowercase()).await?)
+}
+
+/// PUT /admin/maintenance/{host} - replace a host's calendar
+pub async fn save(mut req: Request, env: &Env, host: &str) -> Result<Response> {
+    let save = match req.json::<SaveCalendarRequest>().await {
+        Ok(save) => save,
+        Err(e) => return ProxyError::InvalidRequest(e.to_string()).to_response(None),
+    };
+    if let Some(window) = save.windows.iter().find(|window| window.ends_at <= window.starts_at) {
+        return ProxyError::InvalidRequest(format!(
+            "Window ends_at ({}) must be after starts_at ({})",
+            window.ends_at, window.starts_at
+        ))
+        .to_response(None);
+    }
+
+    let host = host.to_lowercase();
+    let store = MaintenanceStore::new(env)?;
+    store.save(&host, &save.windows).await?;
+    log_info!("Maintenance calendar for {} updated ({} windows)", host, save.windows.len());
+
+    Response::from_json(&store.get(&host).await?)
+}
+
+/// DELETE /admin/maintenance/{host}
+pub async fn delete(env: &Env, host: &str) -> Result<Response> {
+    let host = host.to_lowercase();
+    MaintenanceStore::new(env)?.delete(&host).await?;
+    log_info!("Maintenance calendar for {} deleted", host);
+
+    Ok(Response::empty()?.with_status(204))
+}
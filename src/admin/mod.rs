@@ -0,0 +1,133 @@
+use worker::*;
+
+use crate::config::Config;
+use crate::error::ProxyError;
+
+mod allowlist;
+mod cache_rules;
+mod cache_warm;
+#[cfg(feature = "archive-r2")]
+mod captures;
+mod config;
+mod credentials;
+mod default_headers;
+mod incidents;
+mod keep_warm;
+mod locale_rules;
+mod maintenance;
+mod pacing;
+mod pagination;
+mod policy_overrides;
+mod regions;
+#[cfg(feature = "archive-r2")]
+mod replay;
+mod retry_policies;
+mod sandbox;
+mod shards;
+mod slo;
+mod soap_allowlist;
+mod templates;
+mod tenants;
+mod usage;
+
+/// Dispatches `/admin/*` requests
+///
+/// Callers must already have passed `auth::validate_admin_token`.
+pub async fn handle(req: Request, env: &Env, config: &Config, path: &str) -> Result<Response> {
+    let segments: Vec<&str> = path
+        .trim_start_matches("/admin/")
+        .split('/')
+        .filter(|s| !s.is_empty())
+        .collect();
+
+    match (req.method(), segments.as_slice()) {
+        (Method::Post, ["allowlist", "test"]) => allowlist::test(req, env).await,
+        #[cfg(feature = "archive-r2")]
+        (Method::Get, ["captures"]) => captures::list(&req, env).await,
+        #[cfg(feature = "archive-r2")]
+        (Method::Get, ["captures", "sampling"]) => captures::get_sampling(env).await,
+        #[cfg(feature = "archive-r2")]
+        (Method::Put, ["captures", "sampling"]) => captures::save_sampling(req, env).await,
+        #[cfg(feature = "archive-r2")]
+        (Method::Get, ["captures", id]) => captures::get(env, id).await,
+        (Method::Get, ["cache-rules"]) => cache_rules::get(env).await,
+        (Method::Put, ["cache-rules"]) => cache_rules::save(req, env).await,
+        (Method::Post, ["cache", "warm"]) => cache_warm::run(req, env, config).await,
+        (Method::Get, ["config", "effective"]) => config::effective(env, config).await,
+        (Method::Get, ["credentials"]) => credentials::list(env).await,
+        (Method::Get, ["credentials", name]) => credentials::get(env, name).await,
+        (Method::Put, ["credentials", name]) => credentials::save(req, env, name).await,
+        (Method::Delete, ["credentials", name]) => credentials::delete(env, name).await,
+        (Method::Post, ["credentials", name, "reset"]) => credentials::reset(env, name).await,
+        (Method::Get, ["default-headers"]) => default_headers::get(env).await,
+        (Method::Put, ["default-headers"]) => default_headers::save(req, env).await,
+        (Method::Get, ["idempotent-actions"]) => retry_policies::list_idempotent_actions(env).await,
+        (Method::Put, ["idempotent-actions", host]) => retry_policies::save_idempotent_actions(req, env, host).await,
+        (Method::Delete, ["idempotent-actions", host]) => retry_policies::delete_idempotent_actions(env, host).await,
+        (Method::Get, ["incidents"]) => incidents::list(&req, env).await,
+        (Method::Get, ["keep-warm"]) => keep_warm::get(env).await,
+        (Method::Put, ["keep-warm"]) => keep_warm::save(req, env).await,
+        (Method::Post, ["keep-warm", "run"]) => keep_warm::run(env, config).await,
+        (Method::Get, ["locale-rules"]) => locale_rules::get(env).await,
+        (Method::Put, ["locale-rules"]) => locale_rules::save(req, env).await,
+        (Method::Get, ["maintenance"]) => maintenance::list(env).await,
+        (Method::Get, ["maintenance", host]) => maintenance::get(env, host).await,
+        (Method::Put, ["maintenance", host]) => maintenance::save(req, env, host).await,
+        (Method::Delete, ["maintenance", host]) => maintenance::delete(env, host).await,
+        (Method::Get, ["pacing"]) => pacing::list(env).await,
+        (Method::Get, ["pacing", "report"]) => pacing::report(&req, env).await,
+        (Method::Put, ["pacing", host]) => pacing::save(req, env, host).await,
+        (Method::Delete, ["pacing", host]) => pacing::delete(env, host).await,
+        (Method::Get, ["policy-overrides"]) => policy_overrides::list(&req, env).await,
+        (Method::Get, ["regions", "drains"]) => regions::list_drains(env).await,
+        (Method::Get, ["regions", region, "drain"]) => regions::get_drain(env, config, region).await,
+        (Method::Put, ["regions", region, "drain"]) => regions::start_drain(req, env, region).await,
+        (Method::Delete, ["regions", region, "drain"]) => regions::end_drain(env, region).await,
+        (Method::Get, ["regions", region, "export"]) => regions::export(env, config, region).await,
+        (Method::Post, ["regions", region, "import"]) => regions::import(req, env, config, region).await,
+        #[cfg(feature = "archive-r2")]
+        (Method::Post, ["replay"]) => replay::run(req, env, config).await,
+        #[cfg(feature = "archive-r2")]
+        (Method::Get, ["replay", "audit"]) => replay::audit(&req, env).await,
+        #[cfg(feature = "archive-r2")]
+        (Method::Post, ["replay", request_id]) => replay::replay_one(req, env, config, request_id).await,
+        (Method::Get, ["retry-policies"]) => retry_policies::list(env).await,
+        (Method::Get, ["retry-policies", name]) => retry_policies::get(env, name).await,
+        (Method::Put, ["retry-policies", name]) => retry_policies::save(req, env, name).await,
+        (Method::Delete, ["retry-policies", name]) => retry_policies::delete(env, name).await,
+        (Method::Get, ["sandbox"]) => sandbox::get(env).await,
+        (Method::Put, ["sandbox"]) => sandbox::save(req, env).await,
+        (Method::Get, ["shards", "activity"]) => shards::activity(&req, env, config).await,
+        (Method::Get, ["shards", "weights"]) => shards::get_weights(env, config).await,
+        (Method::Put, ["shards", "weights"]) => shards::update_weights(req, env, config).await,
+        (Method::Get, ["shards", shard, "storage"]) => shards::storage_report(env, shard).await,
+        (Method::Get, ["shards", shard, "stats"]) => shards::stats(env, shard).await,
+        (Method::Post, ["shards", shard, "gc"]) => shards::run_gc(env, shard).await,
+        (Method::Get, ["shards", shard, "keys"]) => shards::keys(&req, env, shard).await,
+        (Method::Post, ["shards", shard, "reset"]) => shards::reset(req, env, shard).await,
+        (Method::Get, ["slo"]) => slo::report(env, config).await,
+        (Method::Get, ["soap-allowlist"]) => soap_allowlist::list(env).await,
+        (Method::Put, ["soap-allowlist", host]) => soap_allowlist::save(req, env, host).await,
+        (Method::Delete, ["soap-allowlist", host]) => soap_allowlist::delete(env, host).await,
+        (Method::Get, ["templates"]) => templates::list(env).await,
+        (Method::Get, ["templates", name]) => templates::get(env, name).await,
+        (Method::Put, ["templates", name]) => templates::save(req, env, name).await,
+        (Method::Delete, ["templates", name]) => templates::delete(req, env, name).await,
+        (Method::Get, ["templates", name, "audit"]) => templates::audit(&req, env, name).await,
+        (Method::Post, ["templates", name, "versions"]) => templates::create_version(req, env, name).await,
+        (Method::Post, ["templates", name, "versions", version, "publish"]) => {
+            templates::publish(req, env, name, version).await
+        }
+        (Method::Get, ["tenants"]) => tenants::list(env).await,
+        (Method::Post, ["tenants"]) => tenants::create(req, env).await,
+        (Method::Get, ["tenants", id]) => tenants::get(env, id).await,
+        (Method::Patch, ["tenants", id]) => tenants::update(req, env, id).await,
+        (Method::Delete, ["tenants", id]) => tenants::delete(env, id).await,
+        (Method::Post, ["tenants", id, "tokens"]) => tenants::issue_token(req, env, id).await,
+        (Method::Delete, ["tenants", id, "tokens", token_id]) => tenants::revoke_token(env, id, token_id).await,
+        (Method::Put, ["tenants", id, "template-pins", name]) => templates::pin(req, env, id, name).await,
+        (Method::Delete, ["tenants", id, "template-pins", name]) => templates::unpin(req, env, id, name).await,
+        (Method::Get, ["usage"]) => usage::report(&req, env).await,
+        _ => ProxyError::NotFound(format!("No admin route for {:?} {}", req.method(), path)).to_response(None),
+    }
+}
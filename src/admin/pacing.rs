@@ -0,0 +1,72 @@
+use std::collections::BTreeMap;
+use worker::*;
+
+use crate::error::ProxyError;
+use crate::handlers::pacing::{PacingConfig, PacingLimit};
+use crate::pacing_report::PacingLog;
+use crate::log_info;
+
+/// GET /admin/pacing - token bucket limits of every paced host
+pub async fn list(env: &Env) -> Result<Response> {
+    let config = PacingConfig::load_fresh(env).await?;
+    Response::from_json(&config.0.into_iter().collect::<BTreeMap<_, _>>())
+}
+
+/// PUT /admin/pacing/{host} - set a host's rate and burst
+pub async fn save(mut req: Request, env: &Env, host: &str) -> Result<Response> {
+    let limit = match req.json::<PacingLimit>().await {
+        Ok(limit) => limit,
+        Err(e) => return ProxyError::InvalidRequest(e.to_string()).to_response(None),
+    };
+    if !limit.rate_per_second.is_finite() || limit.rate_per_second <= 0.0 || limit.burst == 0 {
+        return ProxyError::InvalidRequest("rate_per_second and burst must be positive".to_string()).to_response(None);
+    }
+
+    let host = host.to_lowercase();
+    let mut config = PacingConfig::load_fresh(env).await?;
+    config.0.insert(host.clone(), limit);
+    config.save(env).await?;
+    log_info!("Pacing for {} set to {}/s, burst {}", host, limit.rate_per_second, limit.burst);
+
+    Response::from_json(&limit)
+}
+
+/// DELETE /admin/pacing/{host}
+pub async fn delete(env: &Env, host: &str) -> Result<Response> {
+    let host = host.to_lowercase();
+    let mut config = PacingConfig::load_fresh(env).await?;
+    if config.0.remove(&host).is_none() {
+        return ProxyError::NotFound(format!("No pacing configured for {}", host)).to_response(None);
+    }
+    config.save(env).await?;
+    log_info!("Pacing for {} removed", host);
+
+    Ok(Response::empty()?.with_status(204))
+}
+
+/// GET /admin/pacing/report - one day's outbound calls per upstream host
+///
+/// Query: `day` (epoch millis within the UTC day, default today) and `host`.
+pub async fn report(req: &Request, env: &Env) -> Result<Response> {
+    let log = match PacingLog::new(env) {
+        Some(log) => log,
+        None => return ProxyError::NotFound("No DB database is bound".to_string()).to_response(None),
+    };
+
+    let mut day = Date::now().as_millis();
+    let mut host = None;
+    for (key, value) in req.url()?.query_pairs() {
+        match key.as_ref() {
+            "day" => match value.parse() {
+                Ok(at) => day = at,
+                Err(_) => {
+                    return ProxyError::InvalidRequest(format!("Invalid day parameter: {}", value)).to_response(None)
+                }
+            },
+            "host" => host = Some(value.to_string()),
+            _ => {}
+        }
+    }
+
+    Response::from_json(&log.report(day, host.as_deref()).await?)
+}
@@ -0,0 +1,147 @@
+use serde::Serialize;
+use worker::*;
+
+use crate::error::ProxyError;
+
+/// Items per page unless `limit` is given
+pub const DEFAULT_PAGE_SIZE: u32 = 100;
+
+/// Largest `limit` accepted by any list endpoint
+pub const MAX_PAGE_SIZE: u32 = 500;
+
+/// Position and size of a requested page, from the `cursor` and `limit` query parameters
+pub struct PageRequest {
+    /// Decoded cursor of the previous page; `None` for the first page
+    pub cursor: Option<String>,
+    pub limit: u32,
+}
+
+/// One page of a list endpoint
+///
+/// Every admin list endpoint returns this envelope. Clients pass
+/// `next_cursor` back as `cursor` until it's `null`; cursors are opaque.
+#[derive(Serialize)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub next_cursor: Option<String>,
+    /// Number of items across all pages, if known; may be stale by the next page
+    pub total_estimate: Option<u64>,
+}
+
+impl PageRequest {
+    /// Reads `cursor` and `limit`, ignoring the endpoint's other parameters
+    pub fn from_query(url: &Url) -> std::result::Result<PageRequest, ProxyError> {
+        let mut page = PageRequest {
+            cursor: None,
+            limit: DEFAULT_PAGE_SIZE,
+        };
+        for (key, value) in url.query_pairs() {
+            match key.as_ref() {
+                "cursor" if !value.is_empty() => page.cursor = Some(decode_cursor(&value)?),
+                "limit" => match value.parse::<u32>() {
+                    Ok(limit) if (1..=MAX_PAGE_SIZE).contains(&limit) => page.limit = limit,
+                    _ => {
+                        return Err(ProxyError::InvalidRequest(format!(
+                            "limit must be between 1 and {}: {}",
+                            MAX_PAGE_SIZE, value
+                        )))
+                    }
+                },
+                _ => {}
+            }
+        }
+        Ok(page)
+    }
+}
+
+impl<T: Serialize> Page<T> {
+    /// Pages a list that is loaded whole (e.g. a KV value), by offset
+    pub fn from_vec(mut items: Vec<T>, request: &PageRequest) -> std::result::Result<Page<T>, ProxyError> {
+        let offset = match &request.cursor {
+            Some(cursor) => cursor
+                .parse::<usize>()
+                .map_err(|_| ProxyError::InvalidRequest("Invalid cursor".to_string()))?,
+            None => 0,
+        };
+        let total = items.len();
+        let end = offset.saturating_add(request.limit as usize).min(total);
+        let items: Vec<T> = items.drain(offset.min(total)..end).collect();
+        Ok(Page {
+            items,
+            next_cursor: (end < total).then(|| encode_cursor(&end.to_string())),
+            total_estimate: Some(total as u64),
+        })
+    }
+
+    /// Builds a page from up to `limit + 1` rows fetched after the cursor
+    ///
+    /// The extra row only signals that another page exists; `cursor_of`
+    /// turns the last returned item into the next cursor (e.g. its sort key).
+    pub fn from_rows(
+        mut rows: Vec<T>,
+        request: &PageRequest,
+        total_estimate: Option<u64>,
+        cursor_of: impl Fn(&T) -> String,
+    ) -> Page<T> {
+        let has_more = rows.len() > request.limit as usize;
+        rows.truncate(request.limit as usize);
+        Page {
+            next_cursor: if has_more { rows.last().map(|last| encode_cursor(&cursor_of(last))) } else { None },
+            items: rows,
+            total_estimate,
+        }
+    }
+
+    /// Builds a page from a store that pages itself (e.g. a KV list), passing its cursor on
+    pub fn from_cursor(items: Vec<T>, next_cursor: Option<String>) -> Page<T> {
+        Page {
+            items,
+            next_cursor: next_cursor.map(|cursor| encode_cursor(&cursor)),
+            total_estimate: None,
+        }
+    }
+
+    pub fn to_response(&self) -> Result<Response> {
+        Response::from_json(self)
+    }
+}
+
+/// Hex-encodes a cursor so clients treat it as opaque
+fn encode_cursor(position: &str) -> String {
+    position.bytes().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn decode_cursor(cursor: &str) -> std::result::Result<String, ProxyError> {
+    let invalid = || ProxyError::InvalidRequest("Invalid cursor".to_string());
+    let bytes = (0..cursor.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(cursor.get(i..i + 2).ok_or_else(invalid)?, 16).map_err(|_| invalid()))
+        .collect::<std::result::Result<Vec<u8>, ProxyError>>()?;
+    String::from_utf8(bytes).map_err(|_| invalid())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_offset_pages_walk_the_whole_list() {
+        let mut request = PageRequest { cursor: None, limit: 2 };
+        let mut seen = Vec::new();
+        loop {
+            let page = Page::from_vec((1..=5).collect::<Vec<u32>>(), &request).unwrap();
+            assert_eq!(page.total_estimate, Some(5));
+            seen.extend(page.items);
+            match page.next_cursor {
+                Some(cursor) => request.cursor = Some(decode_cursor(&cursor).unwrap()),
+                None => break,
+            }
+        }
+        assert_eq!(seen, vec![1, 2, 3, 4, 5]);
+
+        let page = Page::from_rows(vec![9, 8, 7], &PageRequest { cursor: None, limit: 2 }, None, |n| n.to_string());
+        assert_eq!(page.items, vec![9, 8]);
+        assert_eq!(decode_cursor(&page.next_cursor.unwrap()).unwrap(), "8");
+        assert!(decode_cursor("zz").is_err() && decode_cursor("383").is_err());
+    }
+}
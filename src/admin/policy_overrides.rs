@@ -0,0 +1,29 @@
+use worker::*;
+
+use super::pagination::{Page, PageRequest};
+use crate::policy_override::{self, OverrideAuditEntry};
+
+/// GET /admin/policy-overrides - audit trail of overridden requests, newest first, paginated
+pub async fn list(req: &Request, env: &Env) -> Result<Response> {
+    let page = match PageRequest::from_query(&req.url()?) {
+        Ok(page) => page,
+        Err(e) => return e.to_response(None),
+    };
+    let kv = env.kv("CONFIG")?;
+    let mut list = kv.list().prefix(policy_override::AUDIT_PREFIX.to_string()).limit(page.limit as u64);
+    if let Some(cursor) = page.cursor {
+        list = list.cursor(cursor);
+    }
+    let listed = list.execute().await?;
+    let mut entries = Vec::new();
+    for key in listed.keys {
+        // Entries too large for metadata are read from their value
+        let entry = match key.metadata.and_then(|metadata| serde_json::from_value(metadata).ok()) {
+            Some(entry) => Some(entry),
+            None => kv.get(&key.name).json::<OverrideAuditEntry>().await?,
+        };
+        entries.extend(entry);
+    }
+    let next_cursor = if listed.list_complete { None } else { listed.cursor };
+    Page::from_cursor(entries, next_cursor).to_response()
+}
@@ -0,0 +1,177 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use worker::*;
+
+use crate::config::Config;
+use crate::drain::{self, RegionDrain, RegionDrains, RegionExport, StoredEntry};
+use crate::error::ProxyError;
+use crate::processors::registry::{self, ShardStatus};
+use crate::routing::{self, REGION_CODES};
+use crate::log_info;
+
+#[derive(Deserialize)]
+struct StartDrainRequest {
+    #[serde(default)]
+    redirect_region: Option<String>,
+    #[serde(default)]
+    reason: Option<String>,
+}
+
+#[derive(Serialize)]
+struct DrainView<'a> {
+    region: &'a str,
+    drain: &'a RegionDrain,
+    /// Shards with their `active` flag relative to the drain start; none
+    /// active means the work the region had when it started draining is done
+    #[serde(skip_serializing_if = "Option::is_none")]
+    shards: Option<Vec<ShardStatus>>,
+}
+
+#[derive(Serialize)]
+struct ImportReport {
+    region: String,
+    /// Entries stored per receiving shard
+    shards: BTreeMap<String, u64>,
+}
+
+fn region(code: &str) -> std::result::Result<&'static str, ProxyError> {
+    REGION_CODES
+        .iter()
+        .find(|known| **known == code.to_lowercase())
+        .copied()
+        .ok_or_else(|| ProxyError::NotFound(format!("Unknown region: {}", code)))
+}
+
+/// GET /admin/regions/drains - every region being drained
+pub async fn list_drains(env: &Env) -> Result<Response> {
+    Response::from_json(RegionDrains::load(env).await.regions())
+}
+
+/// GET /admin/regions/{region}/drain - drain status and shard activity since it started
+pub async fn get_drain(env: &Env, config: &Config, code: &str) -> Result<Response> {
+    let region = match region(code) {
+        Ok(region) => region,
+        Err(e) => return e.to_response(None),
+    };
+    let drains = RegionDrains::load(env).await;
+    let drain = match drains.get(region) {
+        Some(drain) => drain,
+        None => return ProxyError::NotFound(format!("Region {} isn't draining", region)).to_response(None),
+    };
+    let shards = registry::region_status(env, region, config.pool_size(region), drain.started_at).await?;
+    Response::from_json(&DrainView { region, drain, shards })
+}
+
+/// PUT /admin/regions/{region}/drain - stop routing new requests to a region
+pub async fn start_drain(mut req: Request, env: &Env, code: &str) -> Result<Response> {
+    let start = match req.json::<StartDrainRequest>().await {
+        Ok(start) => start,
+        Err(e) => return ProxyError::InvalidRequest(e.to_string()).to_response(None),
+    };
+    let region = match region(code) {
+        Ok(region) => region,
+        Err(e) => return e.to_response(None),
+    };
+
+    let mut drains = RegionDrains::load(env).await;
+    let drain = RegionDrain {
+        // Restarting a drain (e.g. to change the redirect) keeps its start
+        started_at: drains.get(region).map_or_else(|| Date::now().as_millis(), |drain| drain.started_at),
+        redirect_region: start.redirect_region.map(|redirect| redirect.to_lowercase()),
+        reason: start.reason,
+    };
+    if let Err(e) = drains.start(region, drain) {
+        return e.to_response(None);
+    }
+    drains.save(env).await?;
+    log_info!("Region {} draining", region);
+
+    Response::from_json(drains.regions())
+}
+
+/// DELETE /admin/regions/{region}/drain - put a region back into service
+pub async fn end_drain(env: &Env, code: &str) -> Result<Response> {
+    let region = match region(code) {
+        Ok(region) => region,
+        Err(e) => return e.to_response(None),
+    };
+    let mut drains = RegionDrains::load(env).await;
+    if !drains.end(region) {
+        return ProxyError::NotFound(format!("Region {} isn't draining", region)).to_response(None);
+    }
+    drains.save(env).await?;
+    log_info!("Region {} back in service", region);
+
+    Ok(Response::empty()?.with_status(204))
+}
+
+/// GET /admin/regions/{region}/export - live storage of every shard in the region's pool
+///
+/// Poll states, cached responses, circuit breakers and credential failure
+/// counts, in the form `POST /admin/regions/{region}/import` takes.
+pub async fn export(env: &Env, config: &Config, code: &str) -> Result<Response> {
+    let region = match region(code) {
+        Ok(region) => region,
+        Err(e) => return e.to_response(None),
+    };
+    let mut shards = BTreeMap::new();
+    for index in 0..config.pool_size(region) {
+        let stub = routing::shard_stub(env, &routing::shard_name(region, index))?;
+        let mut response = stub.fetch_with_str("http://internal/__internal/export").await?;
+        let entries: Vec<StoredEntry> = response.json().await?;
+        if !entries.is_empty() {
+            shards.insert(index, entries);
+        }
+    }
+    log_info!(
+        "Region {} exported: {} entries from {} shards",
+        region,
+        shards.values().map(Vec::len).sum::<usize>(),
+        shards.len()
+    );
+    Response::from_json(&RegionExport {
+        region: region.to_string(),
+        exported_at: Date::now().as_millis(),
+        shards,
+    })
+}
+
+/// POST /admin/regions/{region}/import - store another region's export in this region's shards
+pub async fn import(mut req: Request, env: &Env, config: &Config, code: &str) -> Result<Response> {
+    let export = match req.json::<RegionExport>().await {
+        Ok(export) => export,
+        Err(e) => return ProxyError::InvalidRequest(e.to_string()).to_response(None),
+    };
+    let region = match region(code) {
+        Ok(region) => region,
+        Err(e) => return e.to_response(None),
+    };
+    if let Err(e) = RegionDrains::load(env).await.check(region) {
+        return ProxyError::InvalidRequest(format!("Can't import into a draining region: {}", e.message()))
+            .to_response(None);
+    }
+
+    let pool_size = config.pool_size(region);
+    let mut targets: BTreeMap<u32, Vec<StoredEntry>> = BTreeMap::new();
+    for (index, entries) in export.shards {
+        targets.entry(drain::import_index(index, pool_size)).or_default().extend(entries);
+    }
+
+    let mut report = ImportReport {
+        region: region.to_string(),
+        shards: BTreeMap::new(),
+    };
+    for (index, entries) in targets {
+        let shard = routing::shard_name(region, index);
+        let mut init = RequestInit::new();
+        init.method = Method::Post;
+        init.body = Some(serde_json::to_string(&entries)?.into());
+        let request = Request::new_with_init("http://internal/__internal/import", &init)?;
+        let mut response = routing::shard_stub(env, &shard)?.fetch_with_request(request).await?;
+        let imported: serde_json::Value = response.json().await?;
+        report.shards.insert(shard, imported["imported"].as_u64().unwrap_or_default());
+    }
+    log_info!("Region {} imported the export of {}", region, export.region);
+
+    Response::from_json(&report)
+}
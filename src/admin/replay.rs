@@ -0,0 +1,494 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::time::Duration;
+use worker::*;
+
+use super::pagination::{Page, PageRequest};
+use crate::archive::{self, Archive, ArchivedRequest};
+use crate::auth::Identity;
+use crate::config::Config;
+use crate::error::ProxyError;
+use crate::labels::Labels;
+use crate::logger::{self, LogLevel};
+use crate::routing_trace::RoutingTrace;
+use crate::templates;
+use crate::tenants::{TenantStore, TenantToken, TokenScope};
+use crate::{log_error, log_info, route_to_processor, ForwardedBody, ProcessorRegion};
+
+/// Maximum number of archived requests replayed by one job
+const MAX_REPLAY_REQUESTS: usize = 500;
+
+/// Subrequests one replay job may make; Workers allow 1000 per invocation,
+/// and the rest is left for the admin call's own reads
+const SUBREQUEST_BUDGET: usize = 900;
+
+/// Subrequests of replaying one request: the shard weights read and the processor fetch
+const SUBREQUESTS_PER_REPLAY: usize = 2;
+
+/// Maximum replay rate, to protect the target upstream
+const MAX_RATE_PER_SECOND: u32 = 50;
+
+/// Maximum number of mismatches listed individually in the summary
+const MAX_LISTED_MISMATCHES: usize = 50;
+
+/// KV key prefix of the single-request replay audit trail, one key per replay
+const AUDIT_PREFIX: &str = "replay-audit/";
+
+/// How long audit entries are kept
+const AUDIT_TTL_SECS: u64 = 90 * 24 * 60 * 60;
+
+/// KV's limit on a key's metadata, where entries are kept so listing needs no reads
+const MAX_AUDIT_METADATA_BYTES: usize = 1024;
+
+#[derive(Deserialize)]
+struct ReplayRequest {
+    /// Archive time range as epoch millis, `to` exclusive
+    from: u64,
+    to: u64,
+    /// Region to replay against
+    region: String,
+    /// Replace the host of every upstream URL (e.g. a carrier's new environment)
+    #[serde(default)]
+    target_host: Option<String>,
+    /// Only replay requests of this tenant
+    #[serde(default)]
+    tenant: Option<String>,
+    /// Only replay requests carrying all of these labels
+    #[serde(default)]
+    labels: Labels,
+    #[serde(default = "default_rate")]
+    rate_per_second: u32,
+    #[serde(default = "default_limit")]
+    limit: usize,
+    /// `next_cursor` of the previous job over the same range
+    #[serde(default)]
+    cursor: Option<String>,
+}
+
+/// Body of `POST /admin/replay/{request_id}`; everything defaults to the original request
+#[derive(Default, Deserialize)]
+struct SingleReplayRequest {
+    #[serde(default)]
+    region: Option<String>,
+    #[serde(default)]
+    target_host: Option<String>,
+    /// Expand the original template invocation with this version instead
+    #[serde(default)]
+    template_version: Option<u32>,
+}
+
+/// One archived request replayed by an admin, linking the original to the replay
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ReplayAuditEntry {
+    at: u64,
+    /// Value of the X-Admin-Actor header, or "admin"
+    actor: String,
+    request_id: String,
+    /// Archive key of the original request
+    key: String,
+    /// Request ID the replay ran under, in the logs and the upstream's `X-Attempt` IDs
+    replay_request_id: Option<String>,
+    region: String,
+    #[serde(default)]
+    target_host: Option<String>,
+    #[serde(default)]
+    template_version: Option<u32>,
+    archived_status: u16,
+    replayed_status: u16,
+    body_matches: bool,
+}
+
+#[derive(Serialize)]
+struct SingleReplay {
+    #[serde(flatten)]
+    entry: ReplayAuditEntry,
+    /// The replayed response as the caller would have received it
+    response: Value,
+}
+
+fn default_rate() -> u32 {
+    5
+}
+
+fn default_limit() -> usize {
+    100
+}
+
+#[derive(Serialize)]
+struct Mismatch {
+    key: String,
+    archived_status: u16,
+    replayed_status: u16,
+    body_matches: bool,
+}
+
+#[derive(Default, Serialize)]
+struct ReplaySummary {
+    replayed: u32,
+    status_matches: u32,
+    body_matches: u32,
+    errors: u32,
+    mismatches: Vec<Mismatch>,
+    /// More mismatches than listed individually
+    mismatches_truncated: bool,
+    /// Archived requests read, matching the filters or not
+    scanned: u32,
+    /// Where the next job over the range resumes; `None` once the range is done
+    next_cursor: Option<String>,
+}
+
+/// Points every upstream URL in a request body (including saga steps) at another host
+fn retarget(body: &str, host: &str) -> Option<String> {
+    fn set_host(request: &mut Value, host: &str) {
+        let url = request
+            .get("url")
+            .and_then(Value::as_str)
+            .and_then(|url| Url::parse(url).ok());
+        if let Some(mut url) = url {
+            if url.set_host(Some(host)).is_ok() {
+                request["url"] = Value::String(url.to_string());
+            }
+        }
+    }
+
+    let mut value = serde_json::from_str::<Value>(body).ok()?;
+    match value.get_mut("steps").and_then(Value::as_array_mut) {
+        Some(steps) => {
+            for step in steps {
+                if let Some(request) = step.get_mut("request") {
+                    set_host(request, host);
+                }
+                if let Some(request) = step.get_mut("compensation").and_then(|c| c.get_mut("request")) {
+                    set_host(request, host);
+                }
+            }
+        }
+        None => set_host(&mut value, host),
+    }
+    Some(value.to_string())
+}
+
+/// The identity an archived request ran under, `None` for a tenant that no longer exists
+///
+/// Tokens aren't archived, so a tenant's request is replayed under a token
+/// named "replay" with no scope beyond the tenant's own policy.
+async fn archived_identity(env: &Env, tenant_id: &str) -> Result<Option<Identity>> {
+    if tenant_id == Identity::Root.name() {
+        return Ok(Some(Identity::Root));
+    }
+    let token = TenantToken {
+        id: "replay".to_string(),
+        hash: String::new(),
+        created_at: Date::now().as_millis(),
+        scope: TokenScope {
+            name: Some("replay".to_string()),
+            ..Default::default()
+        },
+    };
+    let tenant = TenantStore::new(env)?.get(tenant_id).await?;
+    Ok(tenant.map(|tenant| Identity::Tenant(Box::new(tenant), token)))
+}
+
+/// Sends an archived request's `body` to a processor of `region` as `identity`
+async fn send(
+    env: &Env,
+    config: &Config,
+    record: &ArchivedRequest,
+    identity: &Identity,
+    request_type: &str,
+    body: String,
+    region: ProcessorRegion,
+) -> Result<(u16, String)> {
+    let mut response = route_to_processor(
+        env,
+        config,
+        &record.path,
+        None,
+        ForwardedBody::Read(body),
+        region,
+        request_type,
+        identity,
+        None,
+        None,
+        &record.labels,
+        record.end_user.as_ref(),
+        false,
+        None,
+        LogLevel::Info,
+        &mut RoutingTrace::default(),
+    )
+    .await?;
+    Ok((response.status_code(), response.text().await.unwrap_or_default()))
+}
+
+/// POST /admin/replay - re-execute archived requests against a region or upstream
+///
+/// Runs synchronously at a controlled rate and returns a diff summary
+/// comparing replayed status and body fingerprint with the archived ones.
+/// Requests are read in key order and filtered before they count against
+/// `limit`; a job stops before it would run out of subrequests and returns
+/// `next_cursor` to resume from.
+pub async fn run(mut req: Request, env: &Env, config: &Config) -> Result<Response> {
+    let replay = match req.json::<ReplayRequest>().await {
+        Ok(replay) => replay,
+        Err(e) => return ProxyError::InvalidRequest(e.to_string()).to_response(None),
+    };
+    let region = match ProcessorRegion::from_code(&replay.region) {
+        Some(region) => region,
+        None => return ProxyError::InvalidRequest(format!("Unknown region: {}", replay.region)).to_response(None),
+    };
+    if replay.to <= replay.from {
+        return ProxyError::InvalidRequest("to must be after from".to_string()).to_response(None);
+    }
+    let archive = match Archive::new(env) {
+        Some(archive) => archive,
+        None => return ProxyError::NotFound("No ARCHIVE bucket is bound".to_string()).to_response(None),
+    };
+
+    let rate = replay.rate_per_second.clamp(1, MAX_RATE_PER_SECOND);
+    let pause = Duration::from_millis(1000 / rate as u64);
+    let limit = replay.limit.min(MAX_REPLAY_REQUESTS);
+    // The listing is one subrequest, and each listed key costs at least its read
+    let mut budget = SUBREQUEST_BUDGET - 1;
+    let keys = archive.keys_between(replay.from, replay.to, replay.cursor.as_deref(), budget).await?;
+    let more_keys = keys.len() == budget;
+    log_info!("Replaying up to {} of {} archived requests against {} at {}/s", limit, keys.len(), replay.region, rate);
+
+    let mut summary = ReplaySummary::default();
+    let mut identities: HashMap<String, Option<Identity>> = HashMap::new();
+    let mut last_read: Option<String> = None;
+    for key in keys {
+        // Room for the read, the tenant's lookup and the replay
+        if summary.replayed as usize >= limit || budget < 2 + SUBREQUESTS_PER_REPLAY {
+            summary.next_cursor = last_read.take().or(replay.cursor.clone());
+            break;
+        }
+        budget -= 1;
+        summary.scanned += 1;
+        last_read = Some(key.clone());
+        let record = match archive.get(&key).await? {
+            Some(record) => record,
+            None => continue,
+        };
+        if replay.tenant.as_ref().is_some_and(|tenant| *tenant != record.tenant) {
+            continue;
+        }
+        if !record.labels.matches(&replay.labels) {
+            continue;
+        }
+
+        // Replay under the archived tenant's identity, so its policy, weight and budgets apply
+        if !identities.contains_key(&record.tenant) {
+            budget -= 1;
+            let identity = archived_identity(env, &record.tenant).await?;
+            identities.insert(record.tenant.clone(), identity);
+        }
+        let identity = match identities.get(&record.tenant).and_then(Option::as_ref) {
+            Some(identity) => identity,
+            None => {
+                log_info!("Replay of {} skipped: tenant {} no longer exists", key, record.tenant);
+                summary.errors += 1;
+                continue;
+            }
+        };
+
+        let body = match &replay.target_host {
+            Some(host) => retarget(&record.body, host).unwrap_or_else(|| record.body.clone()),
+            None => record.body.clone(),
+        };
+
+        if summary.replayed > 0 {
+            Delay::from(pause).await;
+        }
+        summary.replayed += 1;
+        budget -= SUBREQUESTS_PER_REPLAY;
+
+        let (http_status, text) = match send(env, config, &record, identity, &record.request_type, body, region).await {
+            Ok(response) => response,
+            Err(e) => {
+                log_info!("Replay of {} failed: {}", key, e);
+                summary.errors += 1;
+                continue;
+            }
+        };
+        let (status, fingerprint) = archive::fingerprint(http_status, &text);
+
+        let status_matches = status == record.response_status;
+        let body_matches = fingerprint == record.response_fingerprint;
+        summary.status_matches += u32::from(status_matches);
+        summary.body_matches += u32::from(body_matches);
+        if !status_matches || !body_matches {
+            if summary.mismatches.len() < MAX_LISTED_MISMATCHES {
+                summary.mismatches.push(Mismatch {
+                    key,
+                    archived_status: record.response_status,
+                    replayed_status: status,
+                    body_matches,
+                });
+            } else {
+                summary.mismatches_truncated = true;
+            }
+        }
+    }
+    // Every listed key was read, but the range may go on past them
+    if summary.next_cursor.is_none() && more_keys {
+        summary.next_cursor = last_read;
+    }
+
+    log_info!(
+        "Replay finished: {} of {} read replayed, {} status matches, {} body matches, {} errors",
+        summary.replayed,
+        summary.scanned,
+        summary.status_matches,
+        summary.body_matches,
+        summary.errors
+    );
+    Response::from_json(&summary)
+}
+
+/// POST /admin/replay/{request_id} - re-execute one archived request
+///
+/// Runs against the original region, or the `region`, `target_host` or
+/// `template_version` of the body, and returns the replayed response. The
+/// replay is linked to the original in the replay audit trail.
+pub async fn replay_one(mut req: Request, env: &Env, config: &Config, request_id: &str) -> Result<Response> {
+    let text = req.text().await?;
+    let replay = match text.trim() {
+        "" => SingleReplayRequest::default(),
+        text => match serde_json::from_str::<SingleReplayRequest>(text) {
+            Ok(replay) => replay,
+            Err(e) => return ProxyError::InvalidRequest(e.to_string()).to_response(None),
+        },
+    };
+    let archive = match Archive::new(env) {
+        Some(archive) => archive,
+        None => return ProxyError::NotFound("No ARCHIVE bucket is bound".to_string()).to_response(None),
+    };
+    let (key, record) = match archive.find(request_id).await? {
+        Some(found) => found,
+        None => return ProxyError::NotFound(format!("No archived request {}", request_id)).to_response(None),
+    };
+    let region_code = replay.region.clone().unwrap_or_else(|| record.region.clone());
+    let region = match ProcessorRegion::from_code(&region_code) {
+        Some(region) => region,
+        None => return ProxyError::InvalidRequest(format!("Unknown region: {}", region_code)).to_response(None),
+    };
+
+    // A template version is expanded from the original invocation, as the edge would have
+    let (body, request_type) = match (replay.template_version, &record.invocation) {
+        (None, _) => (record.body.clone(), record.request_type.clone()),
+        (Some(_), None) => {
+            let e = ProxyError::InvalidRequest(format!("Request {} didn't use a template", request_id));
+            return e.to_response(None);
+        }
+        (Some(version), Some(invocation)) => {
+            let mut invocation = serde_json::from_str::<Value>(invocation)?;
+            invocation["version"] = Value::from(version);
+            match templates::resolve(env, &invocation.to_string(), None).await {
+                Ok(Some(expanded)) => expanded,
+                Ok(None) => (record.body.clone(), record.request_type.clone()),
+                Err(e) => return e.to_response(None),
+            }
+        }
+    };
+    let body = match &replay.target_host {
+        Some(host) => retarget(&body, host).unwrap_or(body),
+        None => body,
+    };
+
+    let identity = match archived_identity(env, &record.tenant).await? {
+        Some(identity) => identity,
+        None => {
+            let e = ProxyError::NotFound(format!("Tenant {} of {} no longer exists", record.tenant, request_id));
+            return e.to_response(None);
+        }
+    };
+
+    log_info!("Replaying {} ({}) against {} as {}", request_id, key, region.code(), identity.name());
+    let (http_status, text) = match send(env, config, &record, &identity, &request_type, body, region).await {
+        Ok(response) => response,
+        Err(e) => return ProxyError::Upstream(format!("Replay failed: {}", e)).to_response(None),
+    };
+    let (status, fingerprint) = archive::fingerprint(http_status, &text);
+    let entry = ReplayAuditEntry {
+        at: Date::now().as_millis(),
+        actor: req
+            .headers()
+            .get("X-Admin-Actor")
+            .ok()
+            .flatten()
+            .unwrap_or_else(|| "admin".to_string()),
+        request_id: request_id.to_string(),
+        key,
+        replay_request_id: logger::current_request_id(),
+        region: region.code().to_string(),
+        target_host: replay.target_host,
+        template_version: replay.template_version,
+        archived_status: record.response_status,
+        replayed_status: status,
+        body_matches: fingerprint == record.response_fingerprint,
+    };
+    // The replay has already run, so a failed write is logged rather than hiding its result
+    if let Err(e) = record_audit(env, &entry).await {
+        log_error!("Failed to record replay of {}: {}", request_id, e);
+    }
+    let response = serde_json::from_str(&text).unwrap_or(Value::String(text));
+    Response::from_json(&SingleReplay { entry, response })
+}
+
+/// GET /admin/replay/audit - single-request replays, newest first, paginated
+pub async fn audit(req: &Request, env: &Env) -> Result<Response> {
+    let page = match PageRequest::from_query(&req.url()?) {
+        Ok(page) => page,
+        Err(e) => return e.to_response(None),
+    };
+    let kv = env.kv("CONFIG")?;
+    let mut list = kv.list().prefix(AUDIT_PREFIX.to_string()).limit(page.limit as u64);
+    if let Some(cursor) = page.cursor {
+        list = list.cursor(cursor);
+    }
+    let listed = list.execute().await?;
+    let mut entries = Vec::new();
+    for key in listed.keys {
+        // Entries too large for metadata are read from their value
+        let entry = match key.metadata.and_then(|metadata| serde_json::from_value(metadata).ok()) {
+            Some(entry) => Some(entry),
+            None => kv.get(&key.name).json::<ReplayAuditEntry>().await?,
+        };
+        entries.extend(entry);
+    }
+    let next_cursor = if listed.list_complete { None } else { listed.cursor };
+    Page::from_cursor(entries, next_cursor).to_response()
+}
+
+/// Key of an audit entry; inverted time sorts KV's ascending listing newest first
+fn audit_key(at: u64) -> String {
+    format!("{}{:020}-{}", AUDIT_PREFIX, u64::MAX - at, uuid::Uuid::new_v4().simple())
+}
+
+/// Writes one replay to its own key, so concurrent replays can't overwrite each other's entries
+async fn record_audit(env: &Env, entry: &ReplayAuditEntry) -> Result<()> {
+    let value = serde_json::to_string(entry)?;
+    let mut put = env.kv("CONFIG")?.put(&audit_key(entry.at), &value)?.expiration_ttl(AUDIT_TTL_SECS);
+    if value.len() <= MAX_AUDIT_METADATA_BYTES {
+        put = put.metadata(entry)?;
+    }
+    put.execute().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_audit_keys_sort_newest_first() {
+        let older = audit_key(1_767_225_600_000);
+        let newer = audit_key(1_767_229_200_000);
+        assert!(newer < older);
+        assert!(older.starts_with(AUDIT_PREFIX));
+        // Two replays in the same millisecond get keys of their own
+        assert_ne!(audit_key(1_767_225_600_000), older);
+    }
+}
@@ -0,0 +1,122 @@
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use worker::*;
+
+use crate::error::ProxyError;
+use crate::log_info;
+use crate::retry::{is_valid_policy_name, IdempotentActions, RetryPolicies, RetryPolicy};
+
+#[derive(Deserialize)]
+struct IdempotentActionsRequest {
+    actions: Vec<String>,
+}
+
+/// GET /admin/retry-policies - every named policy with its attachments
+pub async fn list(env: &Env) -> Result<Response> {
+    let policies = RetryPolicies::load_fresh(env).await?;
+    Response::from_json(&policies.0.into_iter().collect::<BTreeMap<_, _>>())
+}
+
+/// GET /admin/retry-policies/{name}
+pub async fn get(env: &Env, name: &str) -> Result<Response> {
+    match RetryPolicies::load_fresh(env).await?.0.remove(name) {
+        Some(policy) => Response::from_json(&policy),
+        None => ProxyError::NotFound(format!("Retry policy not found: {}", name)).to_response(None),
+    }
+}
+
+/// PUT /admin/retry-policies/{name} - create or replace a policy and its attachments
+///
+/// A host or tenant can be attached to one policy only, so the applied
+/// policy is never ambiguous.
+pub async fn save(mut req: Request, env: &Env, name: &str) -> Result<Response> {
+    if !is_valid_policy_name(name) {
+        return ProxyError::InvalidRequest(format!("Invalid retry policy name: {}", name)).to_response(None);
+    }
+    let mut policy = match req.json::<RetryPolicy>().await {
+        Ok(policy) => policy,
+        Err(e) => return ProxyError::InvalidRequest(e.to_string()).to_response(None),
+    };
+    if let Err(message) = policy.validate() {
+        return ProxyError::InvalidRequest(message).to_response(None);
+    }
+    policy.hosts = policy.hosts.iter().map(|host| host.to_lowercase()).collect();
+
+    let mut policies = RetryPolicies::load_fresh(env).await?;
+    for (other_name, other) in policies.0.iter().filter(|(other_name, _)| *other_name != name) {
+        if let Some(host) = policy.hosts.iter().find(|host| other.hosts.contains(host)) {
+            return ProxyError::InvalidRequest(format!("Host {} is already attached to policy {}", host, other_name))
+                .to_response(None);
+        }
+        if let Some(tenant) = policy.tenants.iter().find(|tenant| other.tenants.contains(tenant)) {
+            return ProxyError::InvalidRequest(format!(
+                "Tenant {} is already attached to policy {}",
+                tenant, other_name
+            ))
+            .to_response(None);
+        }
+    }
+
+    policies.0.insert(name.to_string(), policy.clone());
+    policies.save(env).await?;
+    log_info!(
+        "Retry policy {} saved ({} hosts, {} tenants)",
+        name,
+        policy.hosts.len(),
+        policy.tenants.len()
+    );
+
+    Response::from_json(&policy)
+}
+
+/// DELETE /admin/retry-policies/{name} - attached hosts and tenants stop retrying
+pub async fn delete(env: &Env, name: &str) -> Result<Response> {
+    let mut policies = RetryPolicies::load_fresh(env).await?;
+    if policies.0.remove(name).is_none() {
+        return ProxyError::NotFound(format!("Retry policy not found: {}", name)).to_response(None);
+    }
+    policies.save(env).await?;
+    log_info!("Retry policy {} deleted", name);
+
+    Ok(Response::empty()?.with_status(204))
+}
+
+/// GET /admin/idempotent-actions - SOAP actions that are retried, by host
+pub async fn list_idempotent_actions(env: &Env) -> Result<Response> {
+    let actions = IdempotentActions::load_fresh(env).await?;
+    Response::from_json(&actions.0.into_iter().collect::<BTreeMap<_, _>>())
+}
+
+/// PUT /admin/idempotent-actions/{host} - replace the host's idempotent SOAP actions
+pub async fn save_idempotent_actions(mut req: Request, env: &Env, host: &str) -> Result<Response> {
+    let mut request = match req.json::<IdempotentActionsRequest>().await {
+        Ok(request) => request,
+        Err(e) => return ProxyError::InvalidRequest(e.to_string()).to_response(None),
+    };
+    if request.actions.iter().any(|action| action.trim().is_empty()) {
+        return ProxyError::InvalidRequest("Actions must not be empty".to_string()).to_response(None);
+    }
+    request.actions.sort();
+    request.actions.dedup();
+
+    let host = host.to_lowercase();
+    let mut actions = IdempotentActions::load_fresh(env).await?;
+    actions.0.insert(host.clone(), request.actions.clone());
+    actions.save(env).await?;
+    log_info!("{} idempotent actions registered for {}", request.actions.len(), host);
+
+    Response::from_json(&request.actions)
+}
+
+/// DELETE /admin/idempotent-actions/{host} - the host's SOAP actions stop being retried
+pub async fn delete_idempotent_actions(env: &Env, host: &str) -> Result<Response> {
+    let host = host.to_lowercase();
+    let mut actions = IdempotentActions::load_fresh(env).await?;
+    if actions.0.remove(&host).is_none() {
+        return ProxyError::NotFound(format!("No idempotent actions registered for {}", host)).to_response(None);
+    }
+    actions.save(env).await?;
+    log_info!("Idempotent actions for {} removed", host);
+
+    Ok(Response::empty()?.with_status(204))
+}
@@ -0,0 +1,26 @@
+use worker::*;
+
+use crate::error::ProxyError;
+use crate::sandbox::SandboxConfig;
+use crate::log_info;
+
+/// GET /admin/sandbox - the sandbox tenant's echo endpoints
+pub async fn get(env: &Env) -> Result<Response> {
+    Response::from_json(&SandboxConfig::load_fresh(env).await?)
+}
+
+/// PUT /admin/sandbox - replace the echo endpoints
+pub async fn save(mut req: Request, env: &Env) -> Result<Response> {
+    let config = match req.json::<SandboxConfig>().await {
+        Ok(config) => config,
+        Err(e) => return ProxyError::InvalidRequest(e.to_string()).to_response(None),
+    };
+    let config = match config.normalized() {
+        Ok(config) => config,
+        Err(e) => return e.to_response(None),
+    };
+    config.save(env).await?;
+    log_info!("Sandbox echo endpoints set ({})", config.echo_endpoints.len());
+
+    Response::from_json(&config)
+}
@@ -0,0 +1,208 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use worker::*;
+
+use super::pagination::{Page, PageRequest};
+use crate::config::Config;
+use crate::error::ProxyError;
+use crate::processors::registry::{self, ShardStatus};
+use crate::processors::storage::StoredKey;
+use crate::routing::{self, ShardWeights, REGION_CODES};
+use crate::log_info;
+
+/// Window for `active` in the activity listing unless `active_within` is given
+const DEFAULT_ACTIVE_WITHIN_SECS: u64 = 60 * 60;
+
+#[derive(Deserialize)]
+struct UpdateWeightsRequest {
+    /// Shard name (e.g. "weur-7") to new weight; 0 drains the shard
+    weights: HashMap<String, u32>,
+}
+
+#[derive(Deserialize)]
+struct ResetRequest {
+    /// Storage namespaces to clear (e.g. "breaker")
+    namespaces: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct WeightsView {
+    regions: BTreeMap<&'static str, BTreeMap<String, u32>>,
+}
+
+fn view(config: &Config, weights: &ShardWeights) -> WeightsView {
+    WeightsView {
+        regions: REGION_CODES
+            .iter()
+            .map(|code| (*code, weights.region_view(code, config.pool_size(code))))
+            .collect(),
+    }
+}
+
+/// GET /admin/shards/weights - effective weight of every shard
+pub async fn get_weights(env: &Env, config: &Config) -> Result<Response> {
+    let weights = ShardWeights::load(env).await;
+    Response::from_json(&view(config, &weights))
+}
+
+/// PUT /admin/shards/weights - merge new weights into the stored config
+pub async fn update_weights(mut req: Request, env: &Env, config: &Config) -> Result<Response> {
+    let update = match req.json::<UpdateWeightsRequest>().await {
+        Ok(update) => update,
+        Err(e) => return ProxyError::InvalidRequest(e.to_string()).to_response(None),
+    };
+
+    let mut weights = ShardWeights::load(env).await;
+    for (shard, weight) in &update.weights {
+        if let Err(msg) = weights.set(shard, *weight) {
+            return ProxyError::InvalidRequest(msg).to_response(None);
+        }
+    }
+
+    // Refuse to drain a whole region; that must be done by removing the route instead
+    if let Some(region) = REGION_CODES
+        .iter()
+        .find(|code| !weights.has_active_shard(code, config.pool_size(code)))
+    {
+        return ProxyError::InvalidRequest(format!("Update would drain every shard in region {}", region))
+            .to_response(None);
+    }
+
+    weights.save(env).await?;
+    log_info!("Shard weights updated: {:?}", update.weights);
+
+    Response::from_json(&view(config, &weights))
+}
+
+#[derive(Serialize)]
+struct ActivityView {
+    active_within: u64,
+    regions: BTreeMap<&'static str, Vec<ShardStatus>>,
+}
+
+/// GET /admin/shards/activity - recent activity and init time of every shard
+///
+/// Reads the per-region registries, so no shard is woken. Query: `region`
+/// to list one region, `active_within` (seconds, default one hour) for the
+/// `active` flag.
+pub async fn activity(req: &Request, env: &Env, config: &Config) -> Result<Response> {
+    let mut regions: Vec<&'static str> = REGION_CODES.to_vec();
+    let mut active_within = DEFAULT_ACTIVE_WITHIN_SECS;
+    for (key, value) in req.url()?.query_pairs() {
+        match key.as_ref() {
+            "region" => match REGION_CODES.iter().find(|code| **code == value.to_lowercase()) {
+                Some(code) => regions = vec![*code],
+                None => return ProxyError::NotFound(format!("Unknown region: {}", value)).to_response(None),
+            },
+            "active_within" => match value.parse() {
+                Ok(secs) => active_within = secs,
+                Err(_) => {
+                    return ProxyError::InvalidRequest(format!("Invalid active_within parameter: {}", value))
+                        .to_response(None)
+                }
+            },
+            _ => {}
+        }
+    }
+
+    let active_since = Date::now().as_millis().saturating_sub(active_within.saturating_mul(1000));
+    let mut view = ActivityView {
+        active_within,
+        regions: BTreeMap::new(),
+    };
+    for region in regions {
+        match registry::region_status(env, region, config.pool_size(region), active_since).await? {
+            Some(statuses) => {
+                view.regions.insert(region, statuses);
+            }
+            None => return ProxyError::NotFound("No SHARD_REGISTRY namespace is bound".to_string()).to_response(None),
+        }
+    }
+    Response::from_json(&view)
+}
+
+/// Forwards an admin request to an internal maintenance endpoint of one shard
+async fn call_shard(env: &Env, shard: &str, internal_path: &str, method: Method) -> Result<Response> {
+    let req = Request::new(&format!("http://internal{}", internal_path), method)?;
+    fetch_shard(env, shard, req).await
+}
+
+async fn fetch_shard(env: &Env, shard: &str, req: Request) -> Result<Response> {
+    // "{region}-registry" reaches the region's registry, which holds the breakers its shards share
+    if let Some(region_code) = shard.strip_suffix("-registry").filter(|code| REGION_CODES.contains(code)) {
+        return match registry::stub(env, region_code) {
+            Some(stub) => stub?.fetch_with_request(req).await,
+            None => ProxyError::NotFound(format!("No registry is bound for {}", region_code)).to_response(None),
+        };
+    }
+    if routing::parse_shard_name(shard).is_none() {
+        return ProxyError::NotFound(format!("Unknown shard: {}", shard)).to_response(None);
+    }
+    routing::shard_stub(env, shard)?.fetch_with_request(req).await
+}
+
+/// GET /admin/shards/{shard}/storage - storage usage and GC stats of one shard
+pub async fn storage_report(env: &Env, shard: &str) -> Result<Response> {
+    call_shard(env, shard, "/__internal/storage", Method::Get).await
+}
+
+/// GET /admin/shards/{shard}/stats - concurrency slots of one shard, with each tenant's in-flight requests
+pub async fn stats(env: &Env, shard: &str) -> Result<Response> {
+    call_shard(env, shard, "/__internal/stats", Method::Get).await
+}
+
+/// POST /admin/shards/{shard}/gc - run a GC sweep on one shard now
+pub async fn run_gc(env: &Env, shard: &str) -> Result<Response> {
+    log_info!("Manual storage GC requested for shard {}", shard);
+    call_shard(env, shard, "/__internal/gc", Method::Post).await
+}
+
+/// GET /admin/shards/{shard}/keys - stored keys of one shard, in key order
+///
+/// Values are redacted outside namespaces known to hold only proxy state
+/// (breakers, credential failure counts). Query: `namespace` to list one
+/// namespace, plus `cursor` and `limit`.
+pub async fn keys(req: &Request, env: &Env, shard: &str) -> Result<Response> {
+    let url = req.url()?;
+    let page = match PageRequest::from_query(&url) {
+        Ok(page) => page,
+        Err(e) => return e.to_response(None),
+    };
+    let mut internal = Url::parse("http://internal/__internal/keys")?;
+    {
+        let mut query = internal.query_pairs_mut();
+        query.append_pair("limit", &(page.limit + 1).to_string());
+        if let Some(after) = &page.cursor {
+            query.append_pair("after", after);
+        }
+        if let Some((_, namespace)) = url.query_pairs().find(|(key, _)| key == "namespace") {
+            query.append_pair("namespace", &namespace);
+        }
+    }
+    let mut response = fetch_shard(env, shard, Request::new(internal.as_str(), Method::Get)?).await?;
+    if response.status_code() != 200 {
+        return Ok(response);
+    }
+    let keys: Vec<StoredKey> = response.json().await?;
+    Page::from_rows(keys, &page, None, |key| key.key.clone()).to_response()
+}
+
+/// POST /admin/shards/{shard}/reset - delete every key of the given namespaces on one shard
+pub async fn reset(mut req: Request, env: &Env, shard: &str) -> Result<Response> {
+    let reset = match req.json::<ResetRequest>().await {
+        Ok(reset) => reset,
+        Err(e) => return ProxyError::InvalidRequest(e.to_string()).to_response(None),
+    };
+    if reset.namespaces.is_empty() {
+        return ProxyError::InvalidRequest("namespaces must not be empty".to_string()).to_response(None);
+    }
+    if let Some(namespace) = reset.namespaces.iter().find(|namespace| namespace.is_empty() || namespace.contains('/')) {
+        return ProxyError::InvalidRequest(format!("Invalid namespace: {:?}", namespace)).to_response(None);
+    }
+    log_info!("Storage reset requested for shard {}: {:?}", shard, reset.namespaces);
+
+    let mut init = RequestInit::new();
+    init.method = Method::Post;
+    init.body = Some(serde_json::to_string(&reset.namespaces)?.into());
+    fetch_shard(env, shard, Request::new_with_init("http://internal/__internal/clear", &init)?).await
+}
@@ -0,0 +1,14 @@
+use worker::*;
+
+use crate::config::Config;
+use crate::error::ProxyError;
+use crate::slo::SloLog;
+
+/// GET /admin/slo - the proxy's availability and latency burn rates per region
+pub async fn report(env: &Env, config: &Config) -> Result<Response> {
+    let log = match SloLog::new(env) {
+        Some(log) => log,
+        None => return ProxyError::NotFound("No DB database is bound".to_string()).to_response(None),
+    };
+    Response::from_json(&log.report(Date::now().as_millis(), config.slo_targets()).await?)
+}
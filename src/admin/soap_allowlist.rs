@@ -0,0 +1,45 @@
+use std::collections::BTreeMap;
+use worker::*;
+
+use crate::error::ProxyError;
+use crate::log_info;
+use crate::soap_allowlist::{HostActions, SoapAllowlist};
+
+/// GET /admin/soap-allowlist - permitted SOAP actions, by host
+pub async fn list(env: &Env) -> Result<Response> {
+    let allowlist = SoapAllowlist::load_fresh(env).await?;
+    Response::from_json(&allowlist.0.into_iter().collect::<BTreeMap<_, _>>())
+}
+
+/// PUT /admin/soap-allowlist/{host} - replace the host's permitted actions
+pub async fn save(mut req: Request, env: &Env, host: &str) -> Result<Response> {
+    let mut entry = match req.json::<HostActions>().await {
+        Ok(entry) => entry,
+        Err(e) => return ProxyError::InvalidRequest(e.to_string()).to_response(None),
+    };
+    if let Err(e) = entry.validate() {
+        return e.to_response(None);
+    }
+    entry.normalize();
+
+    let host = host.to_lowercase();
+    let mut allowlist = SoapAllowlist::load_fresh(env).await?;
+    allowlist.0.insert(host.clone(), entry.clone());
+    allowlist.save(env).await?;
+    log_info!("SOAP allowlist for {} set: {} actions, {} tenant lists", host, entry.actions.len(), entry.tenants.len());
+
+    Response::from_json(&entry)
+}
+
+/// DELETE /admin/soap-allowlist/{host} - the host accepts any action again
+pub async fn delete(env: &Env, host: &str) -> Result<Response> {
+    let host = host.to_lowercase();
+    let mut allowlist = SoapAllowlist::load_fresh(env).await?;
+    if allowlist.0.remove(&host).is_none() {
+        return ProxyError::NotFound(format!("No SOAP allowlist for {}", host)).to_response(None);
+    }
+    allowlist.save(env).await?;
+    log_info!("SOAP allowlist for {} removed", host);
+
+    Ok(Response::empty()?.with_status(204))
+}
@@ -0,0 +1,216 @@
+use serde::Deserialize;
+use serde_json::Value;
+use worker::*;
+
+use super::pagination::{Page, PageRequest};
+use crate::error::ProxyError;
+use crate::templates::{is_valid_template_name, AuditEntry, RequestTemplate, TemplateStore};
+use crate::tenants::TenantStore;
+use crate::log_info;
+
+#[derive(Deserialize)]
+struct CreateVersionRequest {
+    #[serde(default)]
+    request_type: Option<String>,
+    request: Value,
+}
+
+#[derive(Deserialize)]
+struct PinRequest {
+    version: u32,
+}
+
+/// Who made an admin change, from the optional X-Admin-Actor header
+fn actor(req: &Request) -> String {
+    req.headers()
+        .get("X-Admin-Actor")
+        .ok()
+        .flatten()
+        .unwrap_or_else(|| "admin".to_string())
+}
+
+fn audit_entry(req: &Request, action: &str, version: Option<u32>, tenant: Option<&str>) -> AuditEntry {
+    AuditEntry {
+        at: Date::now().as_millis(),
+        actor: actor(req),
+        action: action.to_string(),
+        version,
+        tenant: tenant.map(str::to_string),
+    }
+}
+
+/// Parses and validates a new version body, returning (request_type, request)
+async fn parse_version(req: &mut Request) -> std::result::Result<(String, Value), ProxyError> {
+    let create = req
+        .json::<CreateVersionRequest>()
+        .await
+        .map_err(|e| ProxyError::InvalidRequest(e.to_string()))?;
+
+    let request_type = create.request_type.unwrap_or_else(|| "http".to_string()).to_lowercase();
+    if request_type != "http" && request_type != "soap" {
+        return Err(ProxyError::InvalidRequest(format!("Unsupported request_type: {}", request_type)));
+    }
+    if create.request.get("url").and_then(Value::as_str).is_none() {
+        return Err(ProxyError::InvalidRequest("Template request must contain a url".to_string()));
+    }
+    Ok((request_type, create.request))
+}
+
+/// GET /admin/templates - names of all saved templates
+pub async fn list(env: &Env) -> Result<Response> {
+    Response::from_json(&TemplateStore::new(env)?.list().await?)
+}
+
+/// GET /admin/templates/{name} - all versions and the published pointer
+pub async fn get(env: &Env, name: &str) -> Result<Response> {
+    match TemplateStore::new(env)?.get(name).await? {
+        Some(template) => Response::from_json(&template),
+        None => ProxyError::NotFound(format!("Unknown template: {}", name)).to_response(None),
+    }
+}
+
+/// POST /admin/templates/{name}/versions - add a draft version
+///
+/// Drafts are only used by explicit `version` invocations and pinned tenants.
+pub async fn create_version(mut req: Request, env: &Env, name: &str) -> Result<Response> {
+    add_version(&mut req, env, name, false).await
+}
+
+/// PUT /admin/templates/{name} - add a version and publish it immediately
+pub async fn save(mut req: Request, env: &Env, name: &str) -> Result<Response> {
+    add_version(&mut req, env, name, true).await
+}
+
+async fn add_version(req: &mut Request, env: &Env, name: &str, publish: bool) -> Result<Response> {
+    if !is_valid_template_name(name) {
+        return ProxyError::InvalidRequest(format!(
+            "Template name must be 1-128 chars of [A-Za-z0-9._-]: {}",
+            name
+        ))
+        .to_response(None);
+    }
+
+    let (request_type, request) = match parse_version(req).await {
+        Ok(version) => version,
+        Err(e) => return e.to_response(None),
+    };
+
+    let store = TemplateStore::new(env)?;
+    let mut template = store.get(name).await?.unwrap_or_else(|| RequestTemplate::new(name));
+    let version = template.add_draft(request_type, request);
+    store.record(name, audit_entry(req, "create_version", Some(version), None)).await?;
+
+    if publish {
+        template.publish(version);
+        store.record(name, audit_entry(req, "publish", Some(version), None)).await?;
+    }
+
+    store.save(&template).await?;
+    log_info!("Template {} version {} created (published: {})", name, version, publish);
+
+    Ok(Response::from_json(&template)?.with_status(201))
+}
+
+/// POST /admin/templates/{name}/versions/{version}/publish - publish (or roll back to) a version
+pub async fn publish(req: Request, env: &Env, name: &str, version: &str) -> Result<Response> {
+    let version = match version.parse::<u32>() {
+        Ok(version) => version,
+        Err(_) => return ProxyError::InvalidRequest(format!("Invalid version: {}", version)).to_response(None),
+    };
+
+    let store = TemplateStore::new(env)?;
+    let mut template = match store.get(name).await? {
+        Some(template) => template,
+        None => return ProxyError::NotFound(format!("Unknown template: {}", name)).to_response(None),
+    };
+
+    if !template.publish(version) {
+        return ProxyError::NotFound(format!("Template {} has no version {}", name, version)).to_response(None);
+    }
+
+    store.save(&template).await?;
+    store.record(name, audit_entry(&req, "publish", Some(version), None)).await?;
+    log_info!("Template {} version {} published", name, version);
+
+    Response::from_json(&template)
+}
+
+/// GET /admin/templates/{name}/audit - change history of a template, oldest first, paginated
+pub async fn audit(req: &Request, env: &Env, name: &str) -> Result<Response> {
+    let page = match PageRequest::from_query(&req.url()?) {
+        Ok(page) => page,
+        Err(e) => return e.to_response(None),
+    };
+    let trail = TemplateStore::new(env)?.audit_trail(name).await?;
+    match Page::from_vec(trail, &page) {
+        Ok(page) => page.to_response(),
+        Err(e) => e.to_response(None),
+    }
+}
+
+/// DELETE /admin/templates/{name}
+pub async fn delete(req: Request, env: &Env, name: &str) -> Result<Response> {
+    let store = TemplateStore::new(env)?;
+    if store.get(name).await?.is_none() {
+        return ProxyError::NotFound(format!("Unknown template: {}", name)).to_response(None);
+    }
+    store.delete(name).await?;
+    store.record(name, audit_entry(&req, "delete", None, None)).await?;
+    log_info!("Template {} deleted", name);
+
+    Ok(Response::empty()?.with_status(204))
+}
+
+/// PUT /admin/tenants/{id}/template-pins/{name} - pin a tenant to a template version
+pub async fn pin(mut req: Request, env: &Env, tenant_id: &str, name: &str) -> Result<Response> {
+    let pin = match req.json::<PinRequest>().await {
+        Ok(pin) => pin,
+        Err(e) => return ProxyError::InvalidRequest(e.to_string()).to_response(None),
+    };
+
+    let templates = TemplateStore::new(env)?;
+    let template_has_version = templates
+        .get(name)
+        .await?
+        .map(|template| template.version(pin.version).is_some())
+        .unwrap_or(false);
+    if !template_has_version {
+        return ProxyError::NotFound(format!("Template {} has no version {}", name, pin.version))
+            .to_response(None);
+    }
+
+    let tenants = TenantStore::new(env)?;
+    let mut tenant = match tenants.get(tenant_id).await? {
+        Some(tenant) => tenant,
+        None => return ProxyError::NotFound(format!("Unknown tenant: {}", tenant_id)).to_response(None),
+    };
+
+    tenant.template_pins.insert(name.to_string(), pin.version);
+    tenants.save(&tenant).await?;
+    templates
+        .record(name, audit_entry(&req, "pin", Some(pin.version), Some(tenant_id)))
+        .await?;
+    log_info!("Tenant {} pinned to template {} version {}", tenant_id, name, pin.version);
+
+    Response::from_json(&tenant)
+}
+
+/// DELETE /admin/tenants/{id}/template-pins/{name} - follow the published version again
+pub async fn unpin(req: Request, env: &Env, tenant_id: &str, name: &str) -> Result<Response> {
+    let tenants = TenantStore::new(env)?;
+    let mut tenant = match tenants.get(tenant_id).await? {
+        Some(tenant) => tenant,
+        None => return ProxyError::NotFound(format!("Unknown tenant: {}", tenant_id)).to_response(None),
+    };
+
+    if tenant.template_pins.remove(name).is_none() {
+        return ProxyError::NotFound(format!("Tenant {} is not pinned to {}", tenant_id, name)).to_response(None);
+    }
+    tenants.save(&tenant).await?;
+    TemplateStore::new(env)?
+        .record(name, audit_entry(&req, "unpin", None, Some(tenant_id)))
+        .await?;
+    log_info!("Tenant {} unpinned from template {}", tenant_id, name);
+
+    Response::from_json(&tenant)
+}
@@ -0,0 +1,399 @@
+use serde::{Deserialize, Serialize};
+use worker::*;
+
+use crate::allowlist::HostRule;
+use crate::end_user::EndUserForwarding;
+use crate::error::ProxyError;
+use crate::guardrails::Guardrails;
+use crate::handlers::registry::REQUEST_TYPES;
+use crate::routing::REGION_CODES;
+use crate::tenants::{is_valid_tenant_id, RateLimit, RegionPolicy, RoutingRule, Tenant, TenantStore, TokenScope};
+use crate::webhooks::WebhookChallenge;
+use crate::log_info;
+
+/// Highest `concurrency_weight`, so no tenant can crowd the others out entirely
+const MAX_CONCURRENCY_WEIGHT: u32 = 100;
+
+#[derive(Deserialize)]
+struct CreateTenantRequest {
+    id: String,
+    name: String,
+    #[serde(default)]
+    rate_limit: Option<RateLimit>,
+    #[serde(default)]
+    allowed_hosts: Option<Vec<String>>,
+    #[serde(default)]
+    region_policy: Option<RegionPolicy>,
+    #[serde(default)]
+    routing_rules: Option<Vec<RoutingRule>>,
+    #[serde(default)]
+    retry_budget: Option<f64>,
+    #[serde(default)]
+    concurrency_weight: Option<u32>,
+    #[serde(default)]
+    webhook_challenges: Option<Vec<WebhookChallenge>>,
+    #[serde(default)]
+    end_user_forwarding: Option<EndUserForwarding>,
+    #[serde(default)]
+    guardrails: Option<Guardrails>,
+    /// Name and limits of the first token
+    #[serde(default)]
+    token: Option<TokenScope>,
+}
+
+#[derive(Deserialize)]
+struct UpdateTenantRequest {
+    #[serde(default)]
+    name: Option<String>,
+    #[serde(default)]
+    rate_limit: Option<RateLimit>,
+    #[serde(default)]
+    allowed_hosts: Option<Vec<String>>,
+    #[serde(default)]
+    region_policy: Option<RegionPolicy>,
+    #[serde(default)]
+    routing_rules: Option<Vec<RoutingRule>>,
+    #[serde(default)]
+    retry_budget: Option<f64>,
+    #[serde(default)]
+    concurrency_weight: Option<u32>,
+    #[serde(default)]
+    webhook_challenges: Option<Vec<WebhookChallenge>>,
+    #[serde(default)]
+    end_user_forwarding: Option<EndUserForwarding>,
+    #[serde(default)]
+    guardrails: Option<Guardrails>,
+}
+
+/// Response for calls that issue a token; the plaintext is only ever returned here
+#[derive(Serialize)]
+struct IssuedToken<'a> {
+    tenant: &'a Tenant,
+    token: String,
+}
+
+fn validate_region_policy(policy: &RegionPolicy) -> std::result::Result<(), ProxyError> {
+    let unknown = policy
+        .allowed_regions
+        .iter()
+        .chain(policy.default_region.iter())
+        .find(|region| !REGION_CODES.contains(&region.to_lowercase().as_str()));
+    match unknown {
+        Some(region) => Err(ProxyError::InvalidRequest(format!("Unknown region: {}", region))),
+        None => Ok(()),
+    }
+}
+
+fn validate_routing_rules(rules: &[RoutingRule]) -> std::result::Result<(), ProxyError> {
+    for rule in rules {
+        if !rule.pointer.starts_with('/') {
+            return Err(ProxyError::InvalidRequest(format!(
+                "Routing rule pointer must be a JSON pointer starting with '/': {}",
+                rule.pointer
+            )));
+        }
+        if !REGION_CODES.contains(&rule.region.to_lowercase().as_str()) {
+            return Err(ProxyError::InvalidRequest(format!("Unknown region: {}", rule.region)));
+        }
+    }
+    Ok(())
+}
+
+fn validate_token_scope(scope: &TokenScope) -> std::result::Result<(), ProxyError> {
+    if scope.name.as_ref().is_some_and(|name| name.is_empty() || name.len() > 64) {
+        return Err(ProxyError::InvalidRequest("Token name must be 1-64 characters".to_string()));
+    }
+    if let Some(region) = scope
+        .allowed_regions
+        .iter()
+        .find(|region| !REGION_CODES.contains(&region.to_lowercase().as_str()))
+    {
+        return Err(ProxyError::InvalidRequest(format!("Unknown region: {}", region)));
+    }
+    if let Some(request_type) = scope
+        .allowed_request_types
+        .iter()
+        .find(|request_type| !REQUEST_TYPES.contains(&request_type.to_lowercase().as_str()))
+    {
+        return Err(ProxyError::InvalidRequest(format!("Unknown request type: {}", request_type)));
+    }
+    Ok(())
+}
+
+fn validate_retry_budget(ratio: f64) -> std::result::Result<(), ProxyError> {
+    if ratio > 0.0 && ratio <= 1.0 {
+        Ok(())
+    } else {
+        Err(ProxyError::InvalidRequest(format!(
+            "retry_budget must be greater than 0 and at most 1: {}",
+            ratio
+        )))
+    }
+}
+
+fn validate_concurrency_weight(weight: u32) -> std::result::Result<(), ProxyError> {
+    if (1..=MAX_CONCURRENCY_WEIGHT).contains(&weight) {
+        Ok(())
+    } else {
+        Err(ProxyError::InvalidRequest(format!(
+            "concurrency_weight must be between 1 and {}: {}",
+            MAX_CONCURRENCY_WEIGHT, weight
+        )))
+    }
+}
+
+fn validate_webhook_challenges(challenges: &[WebhookChallenge]) -> std::result::Result<(), ProxyError> {
+    for (index, challenge) in challenges.iter().enumerate() {
+        challenge.validate()?;
+        if challenges[..index].iter().any(|other| other.name == challenge.name) {
+            return Err(ProxyError::InvalidRequest(format!(
+                "Duplicate webhook challenge name: {}",
+                challenge.name
+            )));
+        }
+    }
+    Ok(())
+}
+
+async fn load(store: &TenantStore, id: &str) -> Result<std::result::Result<Tenant, ProxyError>> {
+    Ok(store
+        .get(id)
+        .await?
+        .ok_or_else(|| ProxyError::NotFound(format!("Unknown tenant: {}", id))))
+}
+
+/// GET /admin/tenants
+pub async fn list(env: &Env) -> Result<Response> {
+    let tenants = TenantStore::new(env)?.list().await?;
+    Response::from_json(&tenants)
+}
+
+/// GET /admin/tenants/{id}
+pub async fn get(env: &Env, id: &str) -> Result<Response> {
+    match load(&TenantStore::new(env)?, id).await? {
+        Ok(tenant) => Response::from_json(&tenant),
+        Err(e) => e.to_response(None),
+    }
+}
+
+/// POST /admin/tenants - provision a tenant and issue its first token
+pub async fn create(mut req: Request, env: &Env) -> Result<Response> {
+    let create = match req.json::<CreateTenantRequest>().await {
+        Ok(create) => create,
+        Err(e) => return ProxyError::InvalidRequest(e.to_string()).to_response(None),
+    };
+
+    if !is_valid_tenant_id(&create.id) {
+        return ProxyError::InvalidRequest(format!(
+            "Tenant id must be 1-64 chars of [a-z0-9-_], and not root: {}",
+            create.id
+        ))
+        .to_response(None);
+    }
+
+    let store = TenantStore::new(env)?;
+    if store.get(&create.id).await?.is_some() {
+        return ProxyError::InvalidRequest(format!("Tenant already exists: {}", create.id)).to_response(None);
+    }
+
+    let mut tenant = Tenant::new(&create.id, &create.name);
+    if let Some(rate_limit) = create.rate_limit {
+        tenant.rate_limit = rate_limit;
+    }
+    if let Some(allowed_hosts) = create.allowed_hosts {
+        if let Err(e) = HostRule::parse_all(&allowed_hosts) {
+            return e.to_response(None);
+        }
+        tenant.allowed_hosts = allowed_hosts;
+    }
+    if let Some(region_policy) = create.region_policy {
+        if let Err(e) = validate_region_policy(&region_policy) {
+            return e.to_response(None);
+        }
+        tenant.region_policy = region_policy;
+    }
+    if let Some(routing_rules) = create.routing_rules {
+        if let Err(e) = validate_routing_rules(&routing_rules) {
+            return e.to_response(None);
+        }
+        tenant.routing_rules = routing_rules;
+    }
+    if let Some(ratio) = create.retry_budget {
+        if let Err(e) = validate_retry_budget(ratio) {
+            return e.to_response(None);
+        }
+        tenant.retry_budget = Some(ratio);
+    }
+    if let Some(weight) = create.concurrency_weight {
+        if let Err(e) = validate_concurrency_weight(weight) {
+            return e.to_response(None);
+        }
+        tenant.concurrency_weight = Some(weight);
+    }
+    if let Some(challenges) = create.webhook_challenges {
+        if let Err(e) = validate_webhook_challenges(&challenges) {
+            return e.to_response(None);
+        }
+        tenant.webhook_challenges = challenges;
+    }
+    if let Some(forwarding) = create.end_user_forwarding {
+        if let Err(e) = forwarding.validate() {
+            return e.to_response(None);
+        }
+        tenant.end_user_forwarding = Some(forwarding);
+    }
+    if let Some(guardrails) = create.guardrails {
+        if let Err(e) = guardrails.validate() {
+            return e.to_response(None);
+        }
+        tenant.guardrails = Some(guardrails);
+    }
+
+    let scope = create.token.unwrap_or_default();
+    if let Err(e) = validate_token_scope(&scope) {
+        return e.to_response(None);
+    }
+    let token = tenant.issue_token(scope);
+    store.save(&tenant).await?;
+    log_info!("Tenant {} created", tenant.id);
+
+    Ok(Response::from_json(&IssuedToken { tenant: &tenant, token })?.with_status(201))
+}
+
+/// PATCH /admin/tenants/{id} - update name, limits, policies, routing rules, or retry budget
+pub async fn update(mut req: Request, env: &Env, id: &str) -> Result<Response> {
+    let update = match req.json::<UpdateTenantRequest>().await {
+        Ok(update) => update,
+        Err(e) => return ProxyError::InvalidRequest(e.to_string()).to_response(None),
+    };
+
+    let store = TenantStore::new(env)?;
+    let mut tenant = match load(&store, id).await? {
+        Ok(tenant) => tenant,
+        Err(e) => return e.to_response(None),
+    };
+
+    if let Some(name) = update.name {
+        tenant.name = name;
+    }
+    if let Some(rate_limit) = update.rate_limit {
+        tenant.rate_limit = rate_limit;
+    }
+    if let Some(allowed_hosts) = update.allowed_hosts {
+        if let Err(e) = HostRule::parse_all(&allowed_hosts) {
+            return e.to_response(None);
+        }
+        tenant.allowed_hosts = allowed_hosts;
+    }
+    if let Some(region_policy) = update.region_policy {
+        if let Err(e) = validate_region_policy(&region_policy) {
+            return e.to_response(None);
+        }
+        tenant.region_policy = region_policy;
+    }
+    if let Some(routing_rules) = update.routing_rules {
+        if let Err(e) = validate_routing_rules(&routing_rules) {
+            return e.to_response(None);
+        }
+        tenant.routing_rules = routing_rules;
+    }
+    if let Some(ratio) = update.retry_budget {
+        if let Err(e) = validate_retry_budget(ratio) {
+            return e.to_response(None);
+        }
+        tenant.retry_budget = Some(ratio);
+    }
+    if let Some(weight) = update.concurrency_weight {
+        if let Err(e) = validate_concurrency_weight(weight) {
+            return e.to_response(None);
+        }
+        tenant.concurrency_weight = Some(weight);
+    }
+    if let Some(challenges) = update.webhook_challenges {
+        if let Err(e) = validate_webhook_challenges(&challenges) {
+            return e.to_response(None);
+        }
+        tenant.webhook_challenges = challenges;
+    }
+    if let Some(forwarding) = update.end_user_forwarding {
+        if let Err(e) = forwarding.validate() {
+            return e.to_response(None);
+        }
+        tenant.end_user_forwarding = Some(forwarding);
+    }
+    if let Some(guardrails) = update.guardrails {
+        if let Err(e) = guardrails.validate() {
+            return e.to_response(None);
+        }
+        tenant.guardrails = Some(guardrails);
+    }
+
+    store.save(&tenant).await?;
+    log_info!("Tenant {} updated", tenant.id);
+
+    Response::from_json(&tenant)
+}
+
+/// DELETE /admin/tenants/{id} - remove the tenant and revoke all of its tokens
+pub async fn delete(env: &Env, id: &str) -> Result<Response> {
+    let store = TenantStore::new(env)?;
+    let tenant = match load(&store, id).await? {
+        Ok(tenant) => tenant,
+        Err(e) => return e.to_response(None),
+    };
+
+    store.delete(&tenant).await?;
+    log_info!("Tenant {} deleted", tenant.id);
+
+    Ok(Response::empty()?.with_status(204))
+}
+
+/// POST /admin/tenants/{id}/tokens - issue an additional token (for rotation
+/// or a narrower job); the optional body is the token's name and limits
+pub async fn issue_token(mut req: Request, env: &Env, id: &str) -> Result<Response> {
+    let body = req.text().await?;
+    let scope = if body.trim().is_empty() {
+        TokenScope::default()
+    } else {
+        match serde_json::from_str::<TokenScope>(&body) {
+            Ok(scope) => scope,
+            Err(e) => return ProxyError::InvalidRequest(e.to_string()).to_response(None),
+        }
+    };
+    if let Err(e) = validate_token_scope(&scope) {
+        return e.to_response(None);
+    }
+
+    let store = TenantStore::new(env)?;
+    let mut tenant = match load(&store, id).await? {
+        Ok(tenant) => tenant,
+        Err(e) => return e.to_response(None),
+    };
+
+    let token = tenant.issue_token(scope);
+    store.save(&tenant).await?;
+    log_info!("Token issued for tenant {}", tenant.id);
+
+    Ok(Response::from_json(&IssuedToken { tenant: &tenant, token })?.with_status(201))
+}
+
+/// DELETE /admin/tenants/{id}/tokens/{token_id} - revoke one token
+pub async fn revoke_token(env: &Env, id: &str, token_id: &str) -> Result<Response> {
+    let store = TenantStore::new(env)?;
+    let mut tenant = match load(&store, id).await? {
+        Ok(tenant) => tenant,
+        Err(e) => return e.to_response(None),
+    };
+
+    let position = match tenant.tokens.iter().position(|token| token.id == token_id) {
+        Some(position) => position,
+        None => return ProxyError::NotFound(format!("Unknown token: {}", token_id)).to_response(None),
+    };
+
+    let token = tenant.tokens.remove(position);
+    store.revoke_token(&token).await?;
+    store.save(&tenant).await?;
+    log_info!("Token {} revoked for tenant {}", token_id, tenant.id);
+
+    Response::from_json(&tenant)
+}
@@ -0,0 +1,45 @@
+use worker::*;
+
+use crate::error::ProxyError;
+use crate::labels::Labels;
+use crate::usage::UsageLog;
+
+/// GET /admin/usage - per-day request counts by tenant and label set
+///
+/// Query: `from` and `to` (epoch millis, `to` exclusive; default the last
+/// 7 days), `tenant`, and any number of `label=key=value` filters.
+pub async fn report(req: &Request, env: &Env) -> Result<Response> {
+    let log = match UsageLog::new(env) {
+        Some(log) => log,
+        None => return ProxyError::NotFound("No DB database is bound".to_string()).to_response(None),
+    };
+
+    let now = Date::now().as_millis();
+    let mut from = now.saturating_sub(7 * 86_400_000);
+    let mut to = now;
+    let mut tenant = None;
+    let mut filter = Labels::default();
+    for (key, value) in req.url()?.query_pairs() {
+        let parsed = match key.as_ref() {
+            "from" => value.parse().map(|v| from = v).map_err(|_| "from"),
+            "to" => value.parse().map(|v| to = v).map_err(|_| "to"),
+            "tenant" => {
+                tenant = Some(value.to_string());
+                Ok(())
+            }
+            "label" => match Labels::parse(&value) {
+                Ok(label) => {
+                    filter.0.extend(label.0);
+                    Ok(())
+                }
+                Err(_) => Err("label"),
+            },
+            _ => Ok(()),
+        };
+        if let Err(param) = parsed {
+            return ProxyError::InvalidRequest(format!("Invalid {} parameter: {}", param, value)).to_response(None);
+        }
+    }
+
+    Response::from_json(&log.report(from, to, tenant.as_deref(), &filter).await?)
+}
@@ -0,0 +1,161 @@
+use serde::Serialize;
+use worker::Url;
+
+use crate::error::ProxyError;
+
+/// One entry of a tenant's host allowlist
+///
+/// Written as `[scheme://]host[:port]`, where the host may start with `*.`
+/// to allow every subdomain (but not the domain itself):
+/// `api.example.com`, `*.didx.example`, `https://api.example.com:8443`.
+/// A rule without a scheme allows http and https; one without a port allows any port.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HostRule {
+    scheme: Option<String>,
+    host: HostPattern,
+    port: Option<u16>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum HostPattern {
+    Exact(String),
+    /// Suffix including its leading dot, e.g. ".didx.example"
+    Subdomains(String),
+}
+
+/// Outcome of checking one URL against an allowlist, as shown by the dry-run endpoint
+#[derive(Debug, Serialize)]
+pub struct Evaluation {
+    pub allowed: bool,
+    /// False when the allowlist is empty, so every URL is allowed
+    pub enforced: bool,
+    /// First rule that allowed the URL
+    pub matched_rule: Option<String>,
+    pub scheme: String,
+    pub host: Option<String>,
+    /// Explicit or scheme default port
+    pub port: Option<u16>,
+}
+
+impl HostRule {
+    pub fn parse(rule: &str) -> Result<HostRule, ProxyError> {
+        let invalid = |reason: &str| ProxyError::InvalidRequest(format!("Invalid allowlist rule {:?}: {}", rule, reason));
+        let rule_lower = rule.trim().to_lowercase();
+
+        let (scheme, rest) = match rule_lower.split_once("://") {
+            Some((scheme, _)) if scheme != "http" && scheme != "https" => {
+                return Err(invalid("scheme must be http or https"))
+            }
+            Some((scheme, rest)) => (Some(scheme.to_string()), rest),
+            None => (None, rule_lower.as_str()),
+        };
+        if rest.contains('/') {
+            return Err(invalid("rules can't contain a path"));
+        }
+
+        // IPv6 literals keep their brackets, as in `Url::host_str`
+        let (host, port) = match rest.rfind(':') {
+            Some(colon) if !rest[colon..].contains(']') => (&rest[..colon], Some(&rest[colon + 1..])),
+            _ => (rest, None),
+        };
+        let port = match port {
+            Some(port) => Some(port.parse::<u16>().map_err(|_| invalid("port must be a number"))?),
+            None => None,
+        };
+
+        let host = match host.strip_prefix('*') {
+            Some(suffix) if suffix.starts_with('.') && suffix.len() > 1 => HostPattern::Subdomains(suffix.to_string()),
+            Some(_) => return Err(invalid("wildcards are only allowed as a leading `*.`")),
+            None => HostPattern::Exact(host.to_string()),
+        };
+        let name = match &host {
+            HostPattern::Exact(name) => name.as_str(),
+            HostPattern::Subdomains(suffix) => &suffix[1..],
+        };
+        let valid_name = name.starts_with('[') && name.ends_with(']')
+            || !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.');
+        if !valid_name {
+            return Err(invalid("not a host name"));
+        }
+
+        Ok(HostRule { scheme, host, port })
+    }
+
+    /// Parses every rule, failing on the first invalid one
+    pub fn parse_all(rules: &[String]) -> Result<Vec<HostRule>, ProxyError> {
+        rules.iter().map(|rule| HostRule::parse(rule)).collect()
+    }
+
+    pub fn matches(&self, url: &Url) -> bool {
+        if self.scheme.as_deref().is_some_and(|scheme| scheme != url.scheme()) {
+            return false;
+        }
+        if self.port.is_some() && self.port != url.port_or_known_default() {
+            return false;
+        }
+        let host = match url.host_str() {
+            Some(host) => host.to_lowercase(),
+            None => return false,
+        };
+        match &self.host {
+            HostPattern::Exact(name) => host == *name,
+            HostPattern::Subdomains(suffix) => host.ends_with(suffix.as_str()),
+        }
+    }
+}
+
+/// Checks `url` against `rules` (as stored on a tenant); an empty list allows everything
+///
+/// Rules that no longer parse never match, so a bad entry fails closed.
+pub fn evaluate(rules: &[String], url: &Url) -> Evaluation {
+    let matched_rule = rules
+        .iter()
+        .find(|rule| HostRule::parse(rule).is_ok_and(|parsed| parsed.matches(url)))
+        .cloned();
+    Evaluation {
+        allowed: rules.is_empty() || matched_rule.is_some(),
+        enforced: !rules.is_empty(),
+        matched_rule,
+        scheme: url.scheme().to_string(),
+        host: url.host_str().map(str::to_lowercase),
+        port: url.port_or_known_default(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn allowed(rule: &str, url: &str) -> bool {
+        HostRule::parse(rule).unwrap().matches(&Url::parse(url).unwrap())
+    }
+
+    #[test]
+    fn test_rules_match_scheme_host_and_port() {
+        assert!(allowed("API.example.com", "http://api.example.com:8080/x"));
+        assert!(allowed("*.didx.example", "https://a.b.didx.example/"));
+        assert!(!allowed("*.didx.example", "https://didx.example/"));
+        assert!(!allowed("*.didx.example", "https://evildidx.example/"));
+        assert!(allowed("https://api.example.com", "https://api.example.com/"));
+        assert!(!allowed("https://api.example.com", "http://api.example.com/"));
+        assert!(allowed("api.example.com:443", "https://api.example.com/"));
+        assert!(!allowed("api.example.com:8443", "https://api.example.com/"));
+        assert!(allowed("[::1]:8080", "http://[::1]:8080/"));
+
+        for bad in ["ftp://x.example", "x.example/path", "a.*.example", "*", "x.example:http", ""] {
+            assert!(HostRule::parse(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_evaluate_reports_matched_rule() {
+        let rules = vec!["bad/rule".to_string(), "*.example.com".to_string()];
+        let evaluation = evaluate(&rules, &Url::parse("https://api.example.com/v1").unwrap());
+        assert!(evaluation.allowed && evaluation.enforced);
+        assert_eq!(evaluation.matched_rule.as_deref(), Some("*.example.com"));
+        assert_eq!(evaluation.port, Some(443));
+
+        let open = evaluate(&[], &Url::parse("http://anything.test").unwrap());
+        assert!(open.allowed && !open.enforced && open.matched_rule.is_none());
+    }
+}
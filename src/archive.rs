@@ -0,0 +1,192 @@
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use worker::*;
+
+use crate::compression;
+use crate::end_user::EndUser;
+use crate::labels::Labels;
+
+/// R2 key prefix for archived requests; keys sort chronologically
+const ARCHIVE_PREFIX: &str = "requests/";
+
+/// R2 key prefix of the pointers from a request ID to its archived request
+const REQUEST_ID_PREFIX: &str = "request-ids/";
+
+/// Maximum keys fetched per R2 list call
+const LIST_PAGE_SIZE: u32 = 1000;
+
+/// A proxied request and a summary of its result, stored in the ARCHIVE bucket
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ArchivedRequest {
+    pub at: u64,
+    pub path: String,
+    pub region: String,
+    pub request_type: String,
+    pub tenant: String,
+    /// The request's `X-Request-Id`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    #[serde(default)]
+    pub labels: Labels,
+    /// From `X-End-User`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub end_user: Option<EndUser>,
+    /// Request body after template expansion
+    pub body: String,
+    /// The template invocation the body was expanded from, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub invocation: Option<String>,
+    /// Upstream status (or the proxy's own status for proxy errors)
+    pub response_status: u16,
+    /// See `fingerprint`
+    pub response_fingerprint: String,
+}
+
+/// Summarizes a proxy response as (status, fingerprint) for later comparison
+///
+/// The fingerprint is a SHA-256 of the upstream `body` field only: upstream
+/// headers (Date, request ids) differ on every call.
+pub fn fingerprint(http_status: u16, response_text: &str) -> (u16, String) {
+    let parsed = serde_json::from_str::<Value>(response_text).ok();
+    let status = parsed
+        .as_ref()
+        .and_then(|value| value.get("status"))
+        .and_then(Value::as_u64)
+        .map(|status| status as u16)
+        .unwrap_or(http_status);
+    let canonical = parsed
+        .as_ref()
+        .and_then(|value| value.get("body"))
+        .map(Value::to_string)
+        .unwrap_or_else(|| response_text.to_string());
+    let hash = Sha256::digest(canonical.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    (status, hash)
+}
+
+/// Key position of `at` under `prefix`; keys are the marker plus a unique suffix
+pub fn time_marker(prefix: &str, at: u64) -> String {
+    format!("{}{:013}", prefix, at)
+}
+
+/// Request archive in the optional ARCHIVE R2 bucket
+pub struct Archive {
+    bucket: Bucket,
+    compress: bool,
+}
+
+impl Archive {
+    /// Returns `None` when no ARCHIVE bucket is bound, which disables archiving
+    pub fn new(env: &Env) -> Option<Archive> {
+        env.bucket("ARCHIVE").ok().map(|bucket| Archive { bucket, compress: false })
+    }
+
+    /// Compresses what this archive writes with the shared dictionary (`ARCHIVE_COMPRESSION`);
+    /// reads handle both forms either way
+    pub fn compressing(mut self, compress: bool) -> Archive {
+        self.compress = compress;
+        self
+    }
+
+    /// Stores a request, and a pointer to it under its request ID for `find`
+    ///
+    /// A request ID the caller reused points at its latest request.
+    pub async fn put(&self, record: &ArchivedRequest) -> Result<()> {
+        let key = self.put_json(ARCHIVE_PREFIX, record.at, record).await?;
+        if let Some(request_id) = &record.request_id {
+            self.bucket.put(format!("{}{}", REQUEST_ID_PREFIX, request_id), key).execute().await?;
+        }
+        Ok(())
+    }
+
+    /// The archived request with `request_id`, with its key
+    pub async fn find(&self, request_id: &str) -> Result<Option<(String, ArchivedRequest)>> {
+        let pointer = match self.bucket.get(format!("{}{}", REQUEST_ID_PREFIX, request_id)).execute().await? {
+            Some(pointer) => pointer,
+            None => return Ok(None),
+        };
+        let key = match pointer.body() {
+            Some(body) => body.text().await?,
+            None => return Ok(None),
+        };
+        Ok(self.get(&key).await?.map(|record| (key, record)))
+    }
+
+    /// Stores a record under `prefix`, keyed by its time; returns the key
+    pub async fn put_json<T: Serialize>(&self, prefix: &str, at: u64, record: &T) -> Result<String> {
+        let key = format!("{}-{}", time_marker(prefix, at), uuid::Uuid::new_v4().simple());
+        let mut data = serde_json::to_vec(record)?;
+        if self.compress {
+            data = compression::compress(&data);
+        }
+        self.bucket.put(key.clone(), data).execute().await?;
+        Ok(key)
+    }
+
+    /// Keys of requests archived in `[from, to)` (epoch millis), oldest first,
+    /// resuming after the key `cursor` when given
+    pub async fn keys_between(
+        &self,
+        from: u64,
+        to: u64,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<String>> {
+        let marker = time_marker(ARCHIVE_PREFIX, from);
+        let start_after = cursor.map(str::to_string).filter(|cursor| *cursor > marker).unwrap_or(marker);
+        self.keys_after(ARCHIVE_PREFIX, start_after, to, limit).await
+    }
+
+    /// Keys under `prefix` sorting after `start_after` and before time `to`, oldest first
+    pub async fn keys_after(&self, prefix: &str, mut start_after: String, to: u64, limit: usize) -> Result<Vec<String>> {
+        let end = time_marker(prefix, to);
+        let mut keys = Vec::new();
+
+        while keys.len() < limit {
+            let page = self
+                .bucket
+                .list()
+                .prefix(prefix.to_string())
+                .start_after(start_after.clone())
+                .limit(LIST_PAGE_SIZE)
+                .execute()
+                .await?;
+            let objects = page.objects();
+            for object in &objects {
+                let key = object.key();
+                if key >= end || keys.len() >= limit {
+                    return Ok(keys);
+                }
+                keys.push(key);
+            }
+            match objects.last() {
+                Some(last) if page.truncated() => start_after = last.key(),
+                _ => break,
+            }
+        }
+        Ok(keys)
+    }
+
+    pub async fn get(&self, key: &str) -> Result<Option<ArchivedRequest>> {
+        self.get_json(key).await
+    }
+
+    pub async fn get_json<T: DeserializeOwned>(&self, key: &str) -> Result<Option<T>> {
+        let object = match self.bucket.get(key).execute().await? {
+            Some(object) => object,
+            None => return Ok(None),
+        };
+        let mut data = match object.body() {
+            Some(body) => body.bytes().await?,
+            None => return Ok(None),
+        };
+        if compression::is_compressed(&data) {
+            data = compression::decompress(&data).map_err(|e| Error::RustError(format!("{}: {}", key, e)))?;
+        }
+        Ok(Some(serde_json::from_slice(&data)?))
+    }
+}
@@ -1,5 +1,12 @@
 use worker::*;
 
+use crate::config::Config;
+use crate::tenants::{Tenant, TenantStore, TenantToken};
+use crate::log_info;
+
+/// Header carrying the token's log label to the Durable Object
+pub const TOKEN_HEADER: &str = "X-Token";
+
 /// Authentication error responses
 pub struct AuthError;
 
@@ -10,41 +17,98 @@ impl AuthError {
     }
 }
 
-/// Validates the authentication token from the Authorization header
+/// Name of the `AUTH_TOKEN` identity; no tenant may take it
+pub const ROOT_TENANT_ID: &str = "root";
+
+/// Who a proxy request was authenticated as
+#[derive(Debug, Clone)]
+pub enum Identity {
+    /// The deployment-wide `AUTH_TOKEN`, with no tenant restrictions
+    Root,
+    /// A token issued to a tenant through `/admin/tenants`, with its own scope
+    Tenant(Box<Tenant>, TenantToken),
+}
+
+impl Identity {
+    /// Name used in logs and forwarded to the Durable Object
+    pub fn name(&self) -> &str {
+        match self {
+            Identity::Root => ROOT_TENANT_ID,
+            Identity::Tenant(tenant, _) => &tenant.id,
+        }
+    }
+
+    /// Which of the tenant's tokens was used, for logs
+    pub fn token_label(&self) -> Option<&str> {
+        match self {
+            Identity::Root => None,
+            Identity::Tenant(_, token) => Some(token.label()),
+        }
+    }
+}
+
+/// Authenticates a proxy request against `AUTH_TOKEN` or the tenant tokens in KV
 ///
 /// Expected header format: `Authorization: Bearer <token>`
+pub async fn authenticate(req: &Request, env: &Env, config: &Config) -> Result<Identity> {
+    let token = bearer_token(req)?;
+
+    if config.auth_token() == Some(token.as_str()) {
+        log_info!("Authentication successful");
+        return Ok(Identity::Root);
+    }
+
+    // Tenant tokens are only available when the CONFIG namespace is bound
+    if let Ok(store) = TenantStore::new(env) {
+        if let Some((tenant, token)) = store.find_by_token(&token).await? {
+            log_info!("Authentication successful (tenant: {}, token: {})", tenant.id, token.label());
+            return Ok(Identity::Tenant(Box::new(tenant), token));
+        }
+    }
+
+    log_info!("Authentication failed: Invalid token");
+    Err(worker::Error::RustError("Invalid token".to_string()))
+}
+
+/// Validates the admin token used for `/admin/*` endpoints
 ///
-/// Returns Ok(()) if the token is valid, Err(AuthError) otherwise
-pub fn validate_token(req: &Request, env: &Env) -> Result<()> {
-    // Get the expected token from environment variable
-    let expected_token = env.secret("AUTH_TOKEN")?.to_string();
+/// Admin endpoints change routing and configuration, so they require the
+/// separate `ADMIN_TOKEN` secret rather than the proxy `AUTH_TOKEN`.
+pub fn validate_admin_token(req: &Request, config: &Config) -> Result<()> {
+    // Get the expected token from the config snapshot
+    let expected_token = config
+        .admin_token()
+        .ok_or_else(|| worker::Error::RustError("ADMIN_TOKEN is not set".to_string()))?;
 
+    // Validate the token
+    if bearer_token(req)? != expected_token {
+        log_info!("Admin authentication failed: Invalid token");
+        return Err(worker::Error::RustError("Invalid token".to_string()));
+    }
+
+    log_info!("Admin authentication successful");
+    Ok(())
+}
+
+/// Extracts the bearer token from the Authorization header
+fn bearer_token(req: &Request) -> Result<String> {
     // Get the Authorization header
     let auth_header = req
         .headers()
         .get("Authorization")?
         .ok_or_else(|| {
-            console_log!("Authentication failed: Missing Authorization header");
+            log_info!("Authentication failed: Missing Authorization header");
             worker::Error::RustError("Missing Authorization header".to_string())
         })?;
 
     // Check if it starts with "Bearer "
     if !auth_header.starts_with("Bearer ") {
-        console_log!("Authentication failed: Invalid Authorization header format");
+        log_info!("Authentication failed: Invalid Authorization header format");
         return Err(worker::Error::RustError("Invalid Authorization header format".to_string()));
     }
 
     // Extract the token
-    let token = auth_header.strip_prefix("Bearer ").unwrap_or("");
-
-    // Validate the token
-    if token != expected_token {
-        console_log!("Authentication failed: Invalid token");
-        return Err(worker::Error::RustError("Invalid token".to_string()));
-    }
-
-    console_log!("Authentication successful");
-    Ok(())
+    Ok(auth_header.strip_prefix("Bearer ").unwrap_or("").to_string())
 }
 
 #[cfg(test)]
@@ -58,4 +122,10 @@ mod tests {
         let token = auth_header.strip_prefix("Bearer ").unwrap();
         assert_eq!(token, "test-token-123");
     }
+
+    #[test]
+    fn test_root_identity() {
+        assert_eq!(Identity::Root.name(), ROOT_TENANT_ID);
+        assert_eq!(Identity::Root.token_label(), None);
+    }
 }
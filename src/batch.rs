@@ -0,0 +1,132 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use worker::*;
+
+use crate::error::ProxyError;
+use crate::timing::TimingReport;
+
+/// Maximum number of entries in one batch
+pub const MAX_BATCH_SIZE: usize = 50;
+
+/// Maximum (and default) number of entries in flight at once
+pub const MAX_CONCURRENCY: usize = 6;
+
+/// Body of a `POST /batch` request
+#[derive(Debug, Deserialize)]
+pub struct BatchRequest {
+    pub requests: Vec<BatchEntry>,
+    /// Entries in flight at once, 1 to `MAX_CONCURRENCY`
+    #[serde(default)]
+    pub concurrency: Option<usize>,
+}
+
+/// One request of a batch, run as if it had been sent on its own
+#[derive(Debug, Deserialize)]
+pub struct BatchEntry {
+    /// Sent as X-Request-Type; the batch's own header when absent
+    #[serde(default)]
+    pub request_type: Option<String>,
+    /// Sent as X-CF-Region; the batch's own header when absent
+    #[serde(default)]
+    pub region: Option<String>,
+    /// The `RequestData`, `SoapRequestData` or saga body
+    pub request: Value,
+}
+
+impl BatchRequest {
+    pub fn validate(&self) -> std::result::Result<(), ProxyError> {
+        if self.requests.is_empty() || self.requests.len() > MAX_BATCH_SIZE {
+            return Err(ProxyError::InvalidRequest(format!(
+                "A batch must have 1-{} requests, got {}",
+                MAX_BATCH_SIZE,
+                self.requests.len()
+            )));
+        }
+        if let Some(concurrency) = self.concurrency {
+            if concurrency == 0 || concurrency > MAX_CONCURRENCY {
+                return Err(ProxyError::InvalidRequest(format!(
+                    "concurrency must be 1-{}",
+                    MAX_CONCURRENCY
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn concurrency(&self) -> usize {
+        self.concurrency.unwrap_or(MAX_CONCURRENCY)
+    }
+}
+
+/// Outcome of one batch entry, in the position of its request
+#[derive(Debug, Serialize)]
+pub struct BatchItemResult {
+    /// HTTP status the entry would have had as a single request
+    pub status: u16,
+    /// Region that processed the entry (absent when refused at the edge)
+    pub region: Option<String>,
+    /// The entry's request ID in the logs: the batch's, suffixed with the entry's index
+    pub request_id: String,
+    /// The single-request response body: JSON when it parses, text otherwise
+    pub body: Value,
+}
+
+impl BatchItemResult {
+    pub fn new(status: u16, region: Option<String>, request_id: String, text: &str) -> BatchItemResult {
+        BatchItemResult {
+            status,
+            region,
+            request_id,
+            body: serde_json::from_str(text).unwrap_or_else(|_| Value::String(text.to_string())),
+        }
+    }
+
+    /// Reads an entry's single-request response
+    pub async fn from_response(
+        mut response: Response,
+        region_header: &str,
+        request_id: String,
+    ) -> Result<BatchItemResult> {
+        let region = response.headers().get(region_header)?;
+        let text = response.text().await?;
+        Ok(BatchItemResult::new(response.status_code(), region, request_id, &text))
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct BatchResponse {
+    pub results: Vec<BatchItemResult>,
+    /// When each entry ran, by index, and how many actually ran at once
+    pub timing: TimingReport,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_batch_validation() {
+        let batch: BatchRequest = serde_json::from_value(json!({
+            "requests": [
+                {"request": {"url": "https://api.example.com/a", "method": "get"}},
+                {"request_type": "soap", "region": "weur", "request": {"url": "https://soap.example.com/"}}
+            ]
+        }))
+        .unwrap();
+        assert!(batch.validate().is_ok());
+        assert_eq!(batch.concurrency(), MAX_CONCURRENCY);
+        assert_eq!(batch.requests[1].region.as_deref(), Some("weur"));
+
+        let empty: BatchRequest = serde_json::from_value(json!({"requests": []})).unwrap();
+        assert!(empty.validate().is_err());
+        let serial: BatchRequest =
+            serde_json::from_value(json!({"concurrency": 0, "requests": [{"request": {}}]})).unwrap();
+        assert!(serial.validate().is_err());
+
+        let item = BatchItemResult::new(502, Some("wnam".to_string()), "batch-0".to_string(), "Bad Gateway");
+        assert_eq!(item.body, json!("Bad Gateway"));
+        let item = BatchItemResult::new(200, None, "batch-1".to_string(), r#"{"status": 200}"#);
+        assert_eq!(item.body, json!({"status": 200}));
+    }
+}
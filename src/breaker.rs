@@ -0,0 +1,214 @@
+use serde::{Deserialize, Serialize};
+use worker::*;
+
+use crate::error::ProxyError;
+use crate::processors::registry;
+use crate::{log_error, log_info};
+
+/// Storage namespace of breaker states in the region's registry DO, or in
+/// the processor DO when the registry isn't bound (see `storage`)
+pub const BREAKER_NAMESPACE: &str = "breaker";
+
+/// Consecutive failed requests that open a host's breaker
+const FAILURE_THRESHOLD: u32 = 5;
+
+/// How long an open breaker rejects requests before letting a probe through
+const OPEN_MS: u64 = 30_000;
+
+/// A probe that hasn't reported back after this long (the Workers fetch
+/// limit) is presumed lost, and another one is let through
+const PROBE_TIMEOUT_MS: u64 = 30_000;
+
+/// Circuit breaker of one upstream host, shared by the shards of a region
+///
+/// Closed breakers aren't stored; a success deletes the state.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Breaker {
+    /// Consecutive failed requests
+    pub failures: u32,
+    /// When the breaker opened (epoch millis); unset while closed
+    pub opened_at: Option<u64>,
+    /// When the half-open probe was let through, if one is in flight
+    pub probe_started_at: Option<u64>,
+}
+
+/// Whether a request may go upstream
+#[derive(Debug, PartialEq)]
+pub enum Admission {
+    Allowed,
+    /// Allowed as the one probe of a half-open breaker
+    Probe,
+    Rejected { retry_after_secs: u64 },
+}
+
+impl Breaker {
+    /// Decides about a request at `now`; a probe is recorded in `self`
+    pub fn admit(&mut self, now: u64) -> Admission {
+        let opened_at = match self.opened_at {
+            Some(opened_at) => opened_at,
+            None => return Admission::Allowed,
+        };
+        let half_open_at = opened_at + OPEN_MS;
+        if now < half_open_at {
+            return Admission::Rejected {
+                retry_after_secs: (half_open_at - now).div_ceil(1000),
+            };
+        }
+        match self.probe_started_at {
+            Some(started_at) if now < started_at + PROBE_TIMEOUT_MS => Admission::Rejected {
+                retry_after_secs: 1,
+            },
+            _ => {
+                self.probe_started_at = Some(now);
+                Admission::Probe
+            }
+        }
+    }
+
+    /// Records a request's outcome; returns true when this opened the breaker
+    pub fn record(&mut self, now: u64, is_error: bool) -> bool {
+        if !is_error {
+            *self = Breaker::default();
+            return false;
+        }
+        self.failures += 1;
+        // A failed probe reopens for another full period
+        let reopen = self.opened_at.is_some() && self.probe_started_at.is_some();
+        if reopen || (self.opened_at.is_none() && self.failures >= FAILURE_THRESHOLD) {
+            self.opened_at = Some(now);
+            self.probe_started_at = None;
+            return true;
+        }
+        false
+    }
+}
+
+fn key(host: &str) -> String {
+    format!("{}/{}", BREAKER_NAMESPACE, host.to_lowercase())
+}
+
+/// A breaker check (no `is_error`) or outcome a shard sends to its region's registry
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BreakerCall {
+    pub host: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub is_error: Option<bool>,
+}
+
+/// The registry's answer to a check; outcomes are answered with the default
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct BreakerAnswer {
+    /// Set when the breaker is open and the request must not go upstream
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry_after_secs: Option<u64>,
+    /// Whether the host has a stored breaker, which a success has to clear
+    #[serde(default)]
+    pub tracked: bool,
+}
+
+impl BreakerCall {
+    /// Applies the call to the breakers in `storage`, the registry's or, without one, the shard's
+    pub async fn apply(&self, storage: &Storage) -> Result<BreakerAnswer> {
+        let stored = storage.get::<Breaker>(&key(&self.host)).await?;
+        let now = Date::now().as_millis();
+        match (stored, self.is_error) {
+            (None, None) | (None, Some(false)) => Ok(BreakerAnswer::default()),
+            (Some(mut breaker), None) => {
+                let retry_after_secs = match breaker.admit(now) {
+                    Admission::Allowed => None,
+                    Admission::Probe => {
+                        log_info!("Circuit breaker of {} half-open, probing", self.host);
+                        storage.put(&key(&self.host), &breaker).await?;
+                        None
+                    }
+                    Admission::Rejected { retry_after_secs } => Some(retry_after_secs),
+                };
+                Ok(BreakerAnswer { retry_after_secs, tracked: true })
+            }
+            (Some(_), Some(false)) => {
+                storage.delete(&key(&self.host)).await?;
+                Ok(BreakerAnswer::default())
+            }
+            (stored, Some(true)) => {
+                let mut breaker = stored.unwrap_or_default();
+                if breaker.record(now, true) {
+                    log_info!("Circuit breaker of {} opened after {} failures", self.host, breaker.failures);
+                }
+                storage.put(&key(&self.host), &breaker).await?;
+                Ok(BreakerAnswer { retry_after_secs: None, tracked: true })
+            }
+        }
+    }
+}
+
+/// Sends the call to the region's registry, which every shard of the region
+/// shares, or applies it to the shard's own storage when there's no registry
+///
+/// Errors let the request through; the breaker only protects upstreams.
+async fn send(env: &Env, state: &State, region_code: &str, call: &BreakerCall) -> BreakerAnswer {
+    if let Some(answer) = registry::breaker(env, region_code, call).await {
+        return answer;
+    }
+    call.apply(&state.storage()).await.unwrap_or_else(|e| {
+        log_error!("Failed to update circuit breaker of {}: {}", call.host, e);
+        BreakerAnswer::default()
+    })
+}
+
+/// Fails fast with `CIRCUIT_OPEN` while the host's breaker is open
+///
+/// Returns whether the host's breaker is tracked, i.e. whether a success
+/// must be recorded to close it.
+pub async fn check(env: &Env, state: &State, region_code: &str, host: &str) -> std::result::Result<bool, ProxyError> {
+    let call = BreakerCall { host: host.to_string(), is_error: None };
+    let answer = send(env, state, region_code, &call).await;
+    match answer.retry_after_secs {
+        Some(retry_after_secs) => Err(ProxyError::CircuitOpen {
+            host: host.to_string(),
+            retry_after_secs,
+        }),
+        None => Ok(answer.tracked),
+    }
+}
+
+/// Records a request's outcome in the host's breaker
+pub async fn record(env: &Env, state: &State, region_code: &str, host: &str, is_error: bool) {
+    let call = BreakerCall { host: host.to_string(), is_error: Some(is_error) };
+    send(env, state, region_code, &call).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_breaker_opens_probes_and_closes() {
+        let mut breaker = Breaker::default();
+        for i in 1..FAILURE_THRESHOLD {
+            assert!(!breaker.record(i as u64, true));
+            assert_eq!(breaker.admit(i as u64), Admission::Allowed);
+        }
+        assert!(breaker.record(1_000, true));
+        assert_eq!(breaker.admit(2_000), Admission::Rejected { retry_after_secs: 29 });
+
+        // Half-open: one probe at a time; a failed probe reopens
+        let half_open_at = 1_000 + OPEN_MS;
+        assert_eq!(breaker.admit(half_open_at), Admission::Probe);
+        assert_eq!(breaker.admit(half_open_at + 1), Admission::Rejected { retry_after_secs: 1 });
+        assert!(breaker.record(half_open_at + 500, true));
+        assert!(matches!(breaker.admit(half_open_at + 1_000), Admission::Rejected { .. }));
+
+        // A lost probe is replaced; a successful one closes the breaker
+        let half_open_at = half_open_at + 500 + OPEN_MS;
+        assert_eq!(breaker.admit(half_open_at), Admission::Probe);
+        assert_eq!(breaker.admit(half_open_at + PROBE_TIMEOUT_MS), Admission::Probe);
+        assert!(!breaker.record(half_open_at + PROBE_TIMEOUT_MS + 100, false));
+        assert_eq!(breaker, Breaker::default());
+
+        // Checks go to the registry without an outcome
+        let check = BreakerCall { host: "api.example".to_string(), is_error: None };
+        assert_eq!(serde_json::to_string(&check).unwrap(), r#"{"host":"api.example"}"#);
+        let answer: BreakerAnswer = serde_json::from_str(r#"{"retry_after_secs": 12, "tracked": true}"#).unwrap();
+        assert_eq!(answer.retry_after_secs, Some(12));
+    }
+}
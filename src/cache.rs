@@ -0,0 +1,493 @@
+use futures_util::lock::Mutex;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::time::Duration;
+use worker::*;
+
+use crate::canonical_url;
+use crate::credentials::UpstreamAuth;
+use crate::error::ProxyError;
+use crate::handlers::registry::{Failure, ProcessorContext};
+use crate::handlers::response::ResponseData;
+use crate::handlers::xml::RawBody;
+use crate::handlers::upstream::{self, UpstreamRequest};
+use crate::handlers::ApiResponse;
+use crate::processors::storage;
+use crate::{log_error, log_info};
+
+/// KV key holding the cache rules
+pub const CACHE_RULES_KEY: &str = "cache-rules";
+
+/// Storage namespace of cached responses in the processor DO (see `storage`)
+pub const CACHE_NAMESPACE: &str = "cache";
+
+/// Longest a response may be cached
+pub const MAX_CACHE_TTL_SECS: u64 = 24 * 60 * 60;
+
+/// Longest an expired response is kept to answer while it's being refetched
+const MAX_STALE: Duration = Duration::from_secs(5 * 60);
+
+/// Caches the results of one SOAP operation for `ttl_secs`
+///
+/// SOAP calls are all POSTs, so nothing is cached unless a rule names the
+/// action. Only pure lookups (e.g. `getDIDCountry`) should get a rule.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CacheRule {
+    /// SOAP action, e.g. "getDIDCountry" (case-sensitive, as sent upstream)
+    pub action: String,
+    /// Target host; a rule without one applies to the action on every host
+    #[serde(default)]
+    pub host: Option<String>,
+    pub ttl_secs: u64,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct CacheRules(pub Vec<CacheRule>);
+
+impl CacheRules {
+    /// Loads rules from the CONFIG KV namespace; no config caches nothing
+    pub async fn load(env: &Env) -> CacheRules {
+        let kv = match env.kv("CONFIG") {
+            Ok(kv) => kv,
+            Err(_) => return CacheRules::default(),
+        };
+        match kv.get(CACHE_RULES_KEY).cache_ttl(60).json::<CacheRules>().await {
+            Ok(rules) => rules.unwrap_or_default(),
+            Err(e) => {
+                log_error!("Failed to load cache rules, caching nothing: {}", e);
+                CacheRules::default()
+            }
+        }
+    }
+
+    /// Reads rules bypassing the edge cache, for the admin API
+    pub async fn load_fresh(env: &Env) -> Result<CacheRules> {
+        Ok(env.kv("CONFIG")?.get(CACHE_RULES_KEY).json::<CacheRules>().await?.unwrap_or_default())
+    }
+
+    pub async fn save(&self, env: &Env) -> Result<()> {
+        env.kv("CONFIG")?
+            .put(CACHE_RULES_KEY, serde_json::to_string(self)?)?
+            .execute()
+            .await?;
+        Ok(())
+    }
+
+    pub fn validate(&self) -> std::result::Result<(), ProxyError> {
+        for rule in &self.0 {
+            if rule.action.is_empty() {
+                return Err(ProxyError::InvalidRequest("Cache rule needs an action".to_string()));
+            }
+            if rule.ttl_secs == 0 || rule.ttl_secs > MAX_CACHE_TTL_SECS {
+                return Err(ProxyError::InvalidRequest(format!(
+                    "ttl_secs of {} must be 1-{}",
+                    rule.action, MAX_CACHE_TTL_SECS
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// TTL for an action on a host; a host-specific rule wins over a general one
+    pub fn ttl(&self, action: &str, host: &str) -> Option<Duration> {
+        let matching = |with_host: bool| {
+            self.0.iter().find(|rule| {
+                rule.action == action
+                    && rule.host.is_some() == with_host
+                    && rule.host.as_deref().is_none_or(|rule_host| rule_host.eq_ignore_ascii_case(host))
+            })
+        };
+        matching(true)
+            .or_else(|| matching(false))
+            .map(|rule| Duration::from_secs(rule.ttl_secs))
+    }
+}
+
+/// Caching asked for by an HTTP request's `cache` field
+#[derive(Debug, Clone, Deserialize)]
+pub struct CacheSpec {
+    pub ttl_seconds: u64,
+    /// Entry name chosen by the caller, e.g. "rates-usd"; by default the
+    /// method, URL, params, headers and credential identify the entry
+    #[serde(default)]
+    pub key: Option<String>,
+}
+
+impl CacheSpec {
+    pub fn validate(&self) -> std::result::Result<(), ProxyError> {
+        if self.ttl_seconds == 0 || self.ttl_seconds > MAX_CACHE_TTL_SECS {
+            return Err(ProxyError::InvalidRequest(format!(
+                "cache.ttl_seconds must be 1-{}",
+                MAX_CACHE_TTL_SECS
+            )));
+        }
+        if self.key.as_deref() == Some("") {
+            return Err(ProxyError::InvalidRequest("cache.key must not be empty".to_string()));
+        }
+        Ok(())
+    }
+}
+
+/// A cached successful upstream response
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CachedResponse {
+    pub status: u16,
+    pub headers: HashMap<String, String>,
+    pub body: Value,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub raw_body: Option<RawBody>,
+    /// When the response was stored (epoch millis)
+    pub stored_at: u64,
+}
+
+impl CachedResponse {
+    fn is_fresh(&self, ttl: Duration, now: u64) -> bool {
+        now.saturating_sub(self.stored_at) < ttl.as_millis() as u64
+    }
+}
+
+/// Cache use reported in response metadata
+#[derive(Debug, Clone, Serialize)]
+pub struct CacheUsage {
+    pub hit: bool,
+    /// Age of a cached response in seconds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub age_secs: Option<u64>,
+    pub ttl_secs: u64,
+    /// The response expired and another request is refetching it
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub stale: bool,
+    /// The response was refetched by another request this one waited for
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub coalesced: bool,
+}
+
+/// Cache keys being refetched by this DO, so a hot key that expires is
+/// fetched from the upstream once rather than by every request that misses
+#[derive(Default)]
+pub struct Refreshes(RefCell<HashMap<String, Rc<Mutex<()>>>>);
+
+impl Refreshes {
+    fn in_flight(&self, key: &str) -> bool {
+        self.0.borrow().get(key).is_some_and(|lock| lock.try_lock().is_none())
+    }
+
+    fn join(&self, key: &str) -> Flight<'_> {
+        let lock = self.0.borrow_mut().entry(key.to_string()).or_default().clone();
+        Flight {
+            refreshes: self,
+            key: key.to_string(),
+            lock,
+        }
+    }
+}
+
+/// A request's part in refetching one key; the last one out removes the key's lock
+struct Flight<'a> {
+    refreshes: &'a Refreshes,
+    key: String,
+    lock: Rc<Mutex<()>>,
+}
+
+impl Drop for Flight<'_> {
+    fn drop(&mut self) {
+        // Held by the map and this flight only: nobody else is waiting
+        if Rc::strong_count(&self.lock) <= 2 {
+            self.refreshes.0.borrow_mut().remove(&self.key);
+        }
+    }
+}
+
+/// Answers with a cached response
+fn cached_response(cached: CachedResponse, ttl: Duration, usage: CacheUsage, minimal: bool) -> ApiResponse {
+    let mut response = ApiResponse::Success(ResponseData {
+        status: cached.status,
+        headers: cached.headers,
+        body: cached.body,
+        raw_body: cached.raw_body,
+        metadata: None,
+        redirected_to: None,
+    });
+    response.metadata_mut().cache = Some(CacheUsage {
+        age_secs: Some(Date::now().as_millis().saturating_sub(cached.stored_at) / 1000),
+        ttl_secs: ttl.as_secs(),
+        ..usage
+    });
+    if minimal {
+        response.into_minimal()
+    } else {
+        response
+    }
+}
+
+/// Storage key for a canonical description of a cached call
+fn hashed_key(canonical: &Value) -> String {
+    use sha2::{Digest, Sha256};
+
+    let hash: String = Sha256::digest(canonical.to_string().as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    format!("{}/{}", CACHE_NAMESPACE, hash)
+}
+
+/// Spellings of one URL share an entry (see `canonical_url`)
+fn canonical(url: &str) -> String {
+    canonical_url::canonicalize(url).unwrap_or_else(|| url.to_string())
+}
+
+/// Storage key of a SOAP call's cached result
+///
+/// Derived from the tenant, credential, URL, namespace, action, `SOAPAction`,
+/// locale, params and headers. Params are sorted by name, so the same lookup
+/// with its params in another order shares the entry. As with `http_key`,
+/// headers are part of it so callers with different `Authorization` never
+/// share an entry, and so is the `SOAPAction` actually sent.
+#[cfg(feature = "soap")]
+#[allow(clippy::too_many_arguments)]
+pub fn soap_key(
+    tenant_id: &str,
+    credential: Option<&str>,
+    url: &str,
+    namespace: &str,
+    action: &str,
+    soap_action: &str,
+    locale: Option<&str>,
+    params: &[(String, Value)],
+    headers: &HashMap<String, String>,
+) -> String {
+    let mut params: Vec<&(String, Value)> = params.iter().collect();
+    params.sort_by(|a, b| a.0.cmp(&b.0));
+    let mut headers: Vec<(String, &String)> = headers.iter().map(|(k, v)| (k.to_lowercase(), v)).collect();
+    headers.sort();
+    hashed_key(&serde_json::json!([
+        tenant_id,
+        credential,
+        canonical(url),
+        namespace,
+        action,
+        soap_action,
+        locale,
+        params,
+        headers
+    ]))
+}
+
+/// Storage key of an HTTP call's cached response
+///
+/// A caller-chosen `key` is only scoped to the tenant. Otherwise the method,
+/// URL, params, headers, credential and `auth` are hashed; headers are part
+/// of it so callers with different `Authorization` never share an entry. The
+/// `auth` block names the vault credential, never the key itself.
+#[allow(clippy::too_many_arguments)]
+pub fn http_key(
+    tenant_id: &str,
+    spec: &CacheSpec,
+    credential: Option<&str>,
+    auth: Option<&UpstreamAuth>,
+    method: &str,
+    url: &str,
+    params: &HashMap<String, String>,
+    headers: &HashMap<String, String>,
+) -> String {
+    if let Some(key) = &spec.key {
+        return hashed_key(&serde_json::json!([tenant_id, key]));
+    }
+    // Header names are case-insensitive; param names aren't
+    let mut headers: Vec<(String, &String)> = headers.iter().map(|(k, v)| (k.to_lowercase(), v)).collect();
+    headers.sort();
+    let mut params: Vec<(&String, &String)> = params.iter().collect();
+    params.sort();
+    hashed_key(&serde_json::json!([tenant_id, credential, auth, method, canonical(url), params, headers]))
+}
+
+/// Answers from this shard's storage under `key`, or runs the request and
+/// stores a successful response for `ttl`
+///
+/// An expired response is kept a while longer: when one request is already
+/// refetching it, others get the stale copy instead of going upstream too.
+/// Without one, they wait for that refetch. Storage errors fall through to
+/// the upstream; the cache is only an optimization.
+pub async fn execute<R: UpstreamRequest>(
+    ctx: &ProcessorContext<'_>,
+    mut request: R,
+    key: &str,
+    ttl: Duration,
+) -> std::result::Result<ApiResponse, Failure> {
+    let minimal = request.minimal();
+    let read = || async {
+        storage::get_live::<CachedResponse>(ctx.state, key).await.unwrap_or_else(|e| {
+            log_error!("Failed to read cache: {}", e);
+            None
+        })
+    };
+    let is_fresh = |cached: &CachedResponse| cached.is_fresh(ttl, Date::now().as_millis());
+    let usage = CacheUsage {
+        hit: true,
+        age_secs: None,
+        ttl_secs: ttl.as_secs(),
+        stale: false,
+        coalesced: false,
+    };
+
+    match read().await {
+        Some(cached) if is_fresh(&cached) => {
+            log_info!("Cache hit for {}", request.url());
+            return Ok(cached_response(cached, ttl, usage, minimal));
+        }
+        Some(stale) if ctx.cache_refreshes.in_flight(key) => {
+            log_info!("Stale cache hit for {} while it's refetched", request.url());
+            return Ok(cached_response(stale, ttl, CacheUsage { stale: true, ..usage }, minimal));
+        }
+        _ => {}
+    }
+
+    // One request per key refetches; the rest wait for it and read what it stored
+    let flight = ctx.cache_refreshes.join(key);
+    let _refetching = match flight.lock.try_lock() {
+        Some(guard) => guard,
+        None => {
+            let guard = flight.lock.lock().await;
+            if let Some(cached) = read().await.filter(is_fresh) {
+                log_info!("Cache hit for {} after waiting for its refetch", request.url());
+                return Ok(cached_response(cached, ttl, CacheUsage { coalesced: true, ..usage }, minimal));
+            }
+            guard
+        }
+    };
+
+    // Store the full envelope, so later non-minimal callers still get headers
+    request.set_minimal(false);
+    let mut response = upstream::execute(ctx, request).await?;
+    if let ApiResponse::Success(data) = &response {
+        let cached = CachedResponse {
+            status: data.status,
+            headers: data.headers.clone(),
+            body: data.body.clone(),
+            raw_body: data.raw_body.clone(),
+            stored_at: Date::now().as_millis(),
+        };
+        if let Err(e) = storage::put_with_ttl(ctx.state, key, cached, ttl + ttl.min(MAX_STALE)).await {
+            log_error!("Failed to store cache entry: {}", e);
+        }
+        response.metadata_mut().cache = Some(CacheUsage { hit: false, ..usage });
+    }
+    Ok(if minimal { response.into_minimal() } else { response })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_host_rule_wins_over_general_rule() {
+        let rules: CacheRules = serde_json::from_str(
+            r#"[
+                {"action": "getDIDCountry", "ttl_secs": 3600},
+                {"action": "getDIDCountry", "host": "soap.example.com", "ttl_secs": 60}
+            ]"#,
+        )
+        .unwrap();
+        assert!(rules.validate().is_ok());
+        assert_eq!(rules.ttl("getDIDCountry", "SOAP.example.com"), Some(Duration::from_secs(60)));
+        assert_eq!(rules.ttl("getDIDCountry", "other.example"), Some(Duration::from_secs(3600)));
+        assert_eq!(rules.ttl("buyDID", "soap.example.com"), None);
+    }
+
+    #[test]
+    fn test_refetch_is_shared_per_key() {
+        let refreshes = Refreshes::default();
+        let first = refreshes.join("cache/a");
+        let guard = first.lock.try_lock().unwrap();
+        assert!(refreshes.in_flight("cache/a"));
+        assert!(!refreshes.in_flight("cache/b"));
+
+        // A second request for the key waits on the same lock
+        let second = refreshes.join("cache/a");
+        assert!(second.lock.try_lock().is_none());
+        drop(guard);
+        drop(first);
+        assert!(!refreshes.in_flight("cache/a"));
+        drop(second);
+        assert!(refreshes.0.borrow().is_empty());
+
+        let cached = CachedResponse {
+            status: 200,
+            headers: HashMap::new(),
+            body: Value::Null,
+            raw_body: None,
+            stored_at: 1_000,
+        };
+        assert!(cached.is_fresh(Duration::from_secs(60), 60_999));
+        assert!(!cached.is_fresh(Duration::from_secs(60), 61_000));
+    }
+
+    #[cfg(feature = "soap")]
+    #[test]
+    fn test_soap_key_scopes_entries() {
+        use serde_json::json;
+
+        let a = vec![("did".to_string(), json!("1234")), ("country".to_string(), json!("US"))];
+        let b = vec![("country".to_string(), json!("US")), ("did".to_string(), json!("1234"))];
+        let no_headers = HashMap::new();
+        let key_of = |tenant: &str, url: &str, locale: Option<&str>, params: &[(String, serde_json::Value)]| {
+            soap_key(tenant, None, url, "urn:x", "getDIDCountry", "\"\"", locale, params, &no_headers)
+        };
+        let url = "https://soap.example.com/service";
+        let key = key_of("root", "https://SOAP.example.com/service", None, &a);
+        assert_eq!(key, key_of("root", url, None, &b));
+        assert!(key.starts_with("cache/"));
+
+        assert_ne!(key, key_of("billing", url, None, &a));
+        let c = vec![("did".to_string(), json!(1234)), ("country".to_string(), json!("US"))];
+        assert_ne!(key, key_of("root", url, None, &c));
+        assert_ne!(key, key_of("root", url, Some("de-CH"), &a));
+
+        // The `soap_action` override and headers are what's actually sent
+        let sent = |soap_action: &str, headers: &HashMap<String, String>| {
+            soap_key("root", None, url, "urn:x", "getDIDCountry", soap_action, None, &a, headers)
+        };
+        assert_eq!(key, sent("\"\"", &no_headers));
+        assert_ne!(key, sent("\"urn:v2\"", &no_headers));
+        let auth = |token: &str| HashMap::from([("Authorization".to_string(), token.to_string())]);
+        assert_ne!(key, sent("\"\"", &auth("Bearer a")));
+        assert_ne!(sent("\"\"", &auth("Bearer a")), sent("\"\"", &auth("Bearer b")));
+    }
+
+    #[test]
+    fn test_http_key_scopes_entries() {
+        let spec = |key: Option<&str>| CacheSpec {
+            ttl_seconds: 60,
+            key: key.map(str::to_string),
+        };
+        let params = HashMap::from([("currency".to_string(), "USD".to_string())]);
+        let auth = |token: &str| HashMap::from([("Authorization".to_string(), token.to_string())]);
+        let rates = |tenant: &str, key: Option<&str>, method: &str, url: &str, token: &str| {
+            http_key(tenant, &spec(key), None, None, method, url, &params, &auth(token))
+        };
+        let key = rates("root", None, "GET", "https://API.example.com/rates", "a");
+        assert_eq!(key, rates("root", None, "GET", "https://api.example.com/rates", "a"));
+        assert_ne!(key, rates("root", None, "GET", "https://api.example.com/rates", "b"));
+
+        // A named entry is shared across URLs, but never across tenants
+        let named = rates("root", Some("rates"), "GET", "https://a.example", "a");
+        assert_eq!(named, rates("root", Some("rates"), "HEAD", "https://b.example", "b"));
+        assert_ne!(named, rates("billing", Some("rates"), "GET", "https://a.example", "a"));
+
+        // Query-string keys from different vault credentials never share an entry
+        let query_key = |key_ref: &str| UpstreamAuth::QueryKey {
+            param_name: "key".to_string(),
+            key_ref: key_ref.to_string(),
+        };
+        let url = "https://maps.example.com/geocode";
+        let (prod, test) = (query_key("maps-prod"), query_key("maps-test"));
+        assert_ne!(
+            http_key("root", &spec(None), None, Some(&prod), "GET", url, &params, &auth("a")),
+            http_key("root", &spec(None), None, Some(&test), "GET", url, &params, &auth("a"))
+        );
+    }
+}
@@ -0,0 +1,394 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use std::collections::BTreeMap;
+use std::time::Duration;
+use worker::*;
+
+use crate::auth::Identity;
+use crate::cache::CacheRules;
+use crate::config::Config;
+use crate::drain::RegionDrains;
+use crate::error::ProxyError;
+use crate::labels::Labels;
+use crate::logger::LogLevel;
+use crate::routing::REGION_CODES;
+use crate::routing_trace::RoutingTrace;
+use crate::templates::{self, expand};
+use crate::tenants::{self, Tenant, TenantToken, TokenScope};
+use crate::{log_info, route_to_processor, ForwardedBody, ProcessorRegion};
+
+/// Maximum number of requests one warm-up sends, over all its regions
+pub const MAX_WARM_REQUESTS: usize = 500;
+
+/// Maximum warm-up rate, to protect the upstreams being prefetched
+const MAX_RATE_PER_SECOND: u32 = 50;
+
+/// Maximum number of failures listed individually in the report
+const MAX_LISTED_ERRORS: usize = 50;
+
+/// Name the warm-up's requests are logged under in the processors
+const WARM_TOKEN_NAME: &str = "cache-warm";
+
+/// Body of `POST /admin/cache/warm`: cacheable requests prefetched into the
+/// result caches of the shards that will serve them
+#[derive(Debug, Deserialize)]
+pub struct WarmRequest {
+    /// Regions whose shards are warmed; every request is sent to each
+    pub regions: Vec<String>,
+    /// Cache entries are per tenant; unset warms the `AUTH_TOKEN` caller's
+    #[serde(default)]
+    pub tenant: Option<String>,
+    #[serde(default)]
+    pub requests: Vec<WarmEntry>,
+    /// A template invoked once per combination of its matrix values
+    #[serde(default)]
+    pub template: Option<TemplateMatrix>,
+    #[serde(default = "default_rate")]
+    pub rate_per_second: u32,
+}
+
+fn default_rate() -> u32 {
+    5
+}
+
+/// One request to prefetch, as a caller would send it to the proxy
+#[derive(Debug, Clone, Deserialize)]
+pub struct WarmEntry {
+    /// Sent as X-Request-Type; a template invocation brings its own
+    #[serde(default)]
+    pub request_type: Option<String>,
+    /// Sent as X-Routing-Key, for callers that pick the shard by key
+    #[serde(default)]
+    pub routing_key: Option<String>,
+    /// The request body or template invocation; a string is sent as is
+    pub request: Value,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TemplateMatrix {
+    pub name: String,
+    #[serde(default)]
+    pub version: Option<u32>,
+    /// Vars every invocation gets
+    #[serde(default)]
+    pub vars: Map<String, Value>,
+    /// Var name to the values it takes, e.g. `{"country": ["DE", "FR"]}`
+    pub matrix: BTreeMap<String, Vec<Value>>,
+    /// X-Routing-Key of each invocation, with `{{var}}` placeholders
+    #[serde(default)]
+    pub routing_key: Option<String>,
+}
+
+/// What a warm-up did
+#[derive(Debug, Default, Serialize)]
+pub struct WarmReport {
+    pub sent: u32,
+    /// Responses fetched from the upstream and stored
+    pub warmed: u32,
+    /// Responses that were cached and fresh already
+    pub already_cached: u32,
+    /// Sent, but the upstream call failed, so nothing was stored
+    pub failed: u32,
+    /// Not sent: not cacheable, refused by the tenant's policy, or the region is draining
+    pub skipped: u32,
+    pub errors: Vec<WarmError>,
+    /// More failures and skips than listed individually
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub errors_truncated: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct WarmError {
+    /// Position in `requests`, followed by the template's combinations;
+    /// unset when the whole region was skipped
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub index: Option<usize>,
+    pub region: String,
+    pub error: String,
+}
+
+impl WarmReport {
+    fn error(&mut self, index: Option<usize>, region: &str, error: String) {
+        if self.errors.len() < MAX_LISTED_ERRORS {
+            self.errors.push(WarmError { index, region: region.to_string(), error });
+        } else {
+            self.errors_truncated = true;
+        }
+    }
+}
+
+impl TemplateMatrix {
+    /// Invocations in matrix order: the last var changes fastest
+    fn invocations(&self) -> std::result::Result<Vec<WarmEntry>, ProxyError> {
+        let mut combinations = vec![self.vars.clone()];
+        for (name, values) in &self.matrix {
+            combinations = combinations
+                .into_iter()
+                .flat_map(|vars| {
+                    values.iter().map(move |value| {
+                        let mut vars = vars.clone();
+                        vars.insert(name.clone(), value.clone());
+                        vars
+                    })
+                })
+                .collect();
+        }
+        combinations
+            .into_iter()
+            .map(|vars| {
+                let routing_key = match &self.routing_key {
+                    Some(key) => match expand(&Value::String(key.clone()), &vars)? {
+                        Value::String(key) => Some(key),
+                        other => Some(other.to_string()),
+                    },
+                    None => None,
+                };
+                let mut invocation = json!({"template": self.name, "vars": vars});
+                if let Some(version) = self.version {
+                    invocation["version"] = json!(version);
+                }
+                Ok(WarmEntry { request_type: None, routing_key, request: invocation })
+            })
+            .collect()
+    }
+
+    fn size(&self) -> usize {
+        self.matrix.values().fold(1, |size, values| size.saturating_mul(values.len()))
+    }
+}
+
+impl WarmRequest {
+    pub fn validate(&self) -> std::result::Result<(), ProxyError> {
+        if self.regions.is_empty() {
+            return Err(ProxyError::InvalidRequest("regions must list at least one region".to_string()));
+        }
+        if let Some(region) = self.regions.iter().find(|region| !REGION_CODES.contains(&region.as_str())) {
+            return Err(ProxyError::InvalidRequest(format!("Unknown region: {}", region)));
+        }
+        if let Some(template) = &self.template {
+            if let Some((name, _)) = template.matrix.iter().find(|(_, values)| values.is_empty()) {
+                return Err(ProxyError::InvalidRequest(format!("Matrix var {} has no values", name)));
+            }
+        }
+        let requests = self.requests.len().saturating_add(self.template.as_ref().map_or(0, TemplateMatrix::size));
+        let total = requests.saturating_mul(self.regions.len());
+        if requests == 0 || total > MAX_WARM_REQUESTS {
+            return Err(ProxyError::InvalidRequest(format!(
+                "A warm-up must send 1-{} requests over all its regions, got {}",
+                MAX_WARM_REQUESTS, total
+            )));
+        }
+        // Surfaces routing keys with placeholders the matrix doesn't fill
+        self.entries().map(|_| ())
+    }
+
+    /// `requests`, followed by the template's invocations
+    pub fn entries(&self) -> std::result::Result<Vec<WarmEntry>, ProxyError> {
+        let mut entries = self.requests.clone();
+        if let Some(template) = &self.template {
+            entries.extend(template.invocations()?);
+        }
+        Ok(entries)
+    }
+}
+
+/// Refuses a request the processor wouldn't answer from its result cache:
+/// an HTTP GET or HEAD with `cache`, or a SOAP call with a cache rule
+///
+/// Anything else would go upstream on every warm-up without being stored,
+/// which for a POST may well change something there.
+pub fn check_cacheable(request_type: &str, body: &Value, rules: &CacheRules) -> std::result::Result<(), ProxyError> {
+    let not_cacheable = |reason: &str| Err(ProxyError::InvalidRequest(format!("Not cacheable: {}", reason)));
+    if body.get("poll").is_some_and(|poll| !poll.is_null()) {
+        return not_cacheable("polls always go upstream");
+    }
+    match request_type.to_lowercase().as_str() {
+        "" | "http" => {
+            let method = body.get("method").and_then(Value::as_str).unwrap_or("get").to_lowercase();
+            if body.get("cache").is_none_or(Value::is_null) {
+                not_cacheable("the request has no cache field")
+            } else if method != "get" && method != "head" {
+                not_cacheable("only GET and HEAD requests are cached")
+            } else {
+                Ok(())
+            }
+        }
+        "soap" => {
+            let action = body.get("action").and_then(Value::as_str).unwrap_or_default();
+            let host = body
+                .get("url")
+                .and_then(Value::as_str)
+                .and_then(|url| Url::parse(url).ok())
+                .and_then(|url| url.host_str().map(str::to_string))
+                .unwrap_or_default();
+            match rules.ttl(action, &host) {
+                Some(_) => Ok(()),
+                None => not_cacheable(&format!("no cache rule for SOAP action {} on {}", action, host)),
+            }
+        }
+        other => not_cacheable(&format!("{} requests aren't cached", other)),
+    }
+}
+
+/// Sends every entry to each region at `rate_per_second`, as `tenant` when set
+///
+/// Requests take the route a caller's would: templates are expanded, and the
+/// shard is picked from the routing key or the (expanded) body, so the entry
+/// lands in the cache of the shard that will serve the caller.
+pub async fn run(env: &Env, config: &Config, warm: &WarmRequest, tenant: Option<&Tenant>) -> Result<WarmReport> {
+    let entries = warm.entries().map_err(|e| Error::RustError(e.message()))?;
+    let identity = match tenant {
+        Some(tenant) => {
+            let token = TenantToken {
+                id: WARM_TOKEN_NAME.to_string(),
+                hash: String::new(),
+                created_at: Date::now().as_millis(),
+                scope: TokenScope { name: Some(WARM_TOKEN_NAME.to_string()), ..Default::default() },
+            };
+            Identity::Tenant(Box::new(tenant.clone()), token)
+        }
+        None => Identity::Root,
+    };
+    let rules = CacheRules::load(env).await;
+    let drains = RegionDrains::load(env).await;
+    let rate = warm.rate_per_second.clamp(1, MAX_RATE_PER_SECOND);
+    let pause = Duration::from_millis(1000 / rate as u64);
+    log_info!(
+        "Warming {} requests in {} regions for {} at {}/s",
+        entries.len(),
+        warm.regions.len(),
+        identity.name(),
+        rate
+    );
+
+    let mut report = WarmReport::default();
+    for region_code in &warm.regions {
+        let Some(region) = ProcessorRegion::from_code(region_code) else { continue };
+        if let Err(e) = drains.check(region_code) {
+            report.skipped += entries.len() as u32;
+            report.error(None, region_code, e.message());
+            continue;
+        }
+        for (index, entry) in entries.iter().enumerate() {
+            let mut body = match &entry.request {
+                Value::String(body) => body.clone(),
+                request => request.to_string(),
+            };
+            let mut request_type = entry.request_type.clone().unwrap_or_default();
+            let checked = async {
+                if let Some((expanded, template_request_type)) = templates::resolve(env, &body, tenant).await? {
+                    body = expanded;
+                    request_type = template_request_type;
+                }
+                let value = serde_json::from_str::<Value>(&body)
+                    .map_err(|e| ProxyError::InvalidRequest(format!("Invalid request body: {}", e)))?;
+                check_cacheable(&request_type, &value, &rules)?;
+                match tenant {
+                    Some(tenant) => tenants::check_policy(tenant, Some(region_code.as_str()), &body),
+                    None => Ok(()),
+                }
+            };
+            if let Err(e) = checked.await {
+                report.skipped += 1;
+                report.error(Some(index), region_code, e.message());
+                continue;
+            }
+
+            if report.sent > 0 {
+                Delay::from(pause).await;
+            }
+            report.sent += 1;
+            let response = route_to_processor(
+                env,
+                config,
+                "/",
+                entry.routing_key.as_deref(),
+                ForwardedBody::Read(body),
+                region,
+                &request_type,
+                &identity,
+                None,
+                None,
+                &Labels::default(),
+                None,
+                false,
+                None,
+                LogLevel::Info,
+                &mut RoutingTrace::default(),
+            )
+            .await;
+            let (status, answer) = match response {
+                Ok(mut response) => (response.status_code(), response.json::<Value>().await.unwrap_or_default()),
+                Err(e) => (500, json!({ "message": e.to_string() })),
+            };
+            if !(200..300).contains(&status) {
+                report.failed += 1;
+                let message = answer["message"].as_str().map_or_else(|| format!("status {}", status), str::to_string);
+                report.error(Some(index), region_code, message);
+            } else if answer["metadata"]["cache"]["hit"].as_bool() == Some(true) {
+                report.already_cached += 1;
+            } else {
+                report.warmed += 1;
+            }
+        }
+    }
+
+    log_info!(
+        "Cache warm-up finished: {} sent, {} warmed, {} already cached, {} failed, {} skipped",
+        report.sent,
+        report.warmed,
+        report.already_cached,
+        report.failed,
+        report.skipped
+    );
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_matrix_expansion_and_cacheable_requests() {
+        let warm: WarmRequest = serde_json::from_value(json!({
+            "regions": ["weur", "enam"],
+            "requests": [{"request": {"url": "https://rates.example/usd", "cache": {"ttl_seconds": 600}}}],
+            "template": {
+                "name": "didx.getDIDCountry",
+                "vars": {"format": "json"},
+                "matrix": {"country": ["DE", "FR"], "type": ["geo", "mobile"]},
+                "routing_key": "{{country}}"
+            }
+        }))
+        .unwrap();
+        assert!(warm.validate().is_ok());
+        let entries = warm.entries().unwrap();
+        assert_eq!(entries.len(), 5);
+        assert_eq!(
+            entries[2].request,
+            json!({"template": "didx.getDIDCountry", "vars": {"country": "DE", "format": "json", "type": "mobile"}})
+        );
+        assert_eq!(entries[4].routing_key.as_deref(), Some("FR"));
+
+        let too_many = WarmRequest { regions: vec!["weur".to_string(); 101], ..warm };
+        assert!(too_many.validate().is_err());
+        let invalid = |body: Value| serde_json::from_value::<WarmRequest>(body).unwrap().validate().is_err();
+        assert!(invalid(json!({"regions": ["weur"]})));
+        assert!(invalid(json!({"regions": ["mars"], "requests": [{"request": {}}]})));
+        assert!(invalid(json!({"regions": ["weur"], "template": {"name": "t", "matrix": {"country": []}}})));
+
+        let rules: CacheRules = serde_json::from_value(json!([{"action": "getDIDCountry", "ttl_secs": 3600}])).unwrap();
+        let cacheable = |request_type: &str, body: Value| check_cacheable(request_type, &body, &rules).is_ok();
+        assert!(cacheable("", json!({"url": "https://a.example", "cache": {"ttl_seconds": 60}})));
+        assert!(cacheable("http", json!({"url": "https://a.example", "method": "HEAD", "cache": {"ttl_seconds": 60}})));
+        assert!(!cacheable(
+            "http",
+            json!({"url": "https://a.example", "method": "post", "cache": {"ttl_seconds": 60}})
+        ));
+        assert!(!cacheable("http", json!({"url": "https://a.example"})));
+        assert!(cacheable("soap", json!({"url": "https://soap.didx.example", "action": "getDIDCountry"})));
+        assert!(!cacheable("soap", json!({"url": "https://soap.didx.example", "action": "buyDID"})));
+        assert!(!cacheable("saga", json!({"steps": []})));
+        assert!(!cacheable("soap", json!({"action": "getDIDCountry", "poll": {"until": "$.done"}})));
+    }
+}
@@ -0,0 +1,89 @@
+use worker::Url;
+
+/// Parses an upstream URL into its canonical form
+///
+/// Parsing already converts IDN hosts to punycode, lowercases the scheme and
+/// host, strips the scheme's default port and removes dot segments. On top of
+/// that the host's trailing dot and the fragment (never sent upstream) are
+/// dropped, and percent-escapes are normalized: unreserved characters are
+/// decoded and the rest use uppercase hex. The query keeps its parameter
+/// order, which some upstreams give meaning to.
+pub fn parse(raw: &str) -> Option<Url> {
+    let mut url = Url::parse(raw.trim()).ok()?;
+    if let Some(host) = url.domain().and_then(|domain| domain.strip_suffix('.')) {
+        let host = host.to_string();
+        url.set_host(Some(&host)).ok()?;
+    }
+    url.set_fragment(None);
+    let path = normalize_escapes(url.path());
+    url.set_path(&path);
+    if let Some(query) = url.query().map(normalize_escapes) {
+        url.set_query(Some(&query));
+    }
+    Some(url)
+}
+
+/// The canonical form of `raw`, or `None` when it isn't a valid URL
+pub fn canonicalize(raw: &str) -> Option<String> {
+    parse(raw).map(|url| url.to_string())
+}
+
+fn is_unreserved(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'.' | b'_' | b'~')
+}
+
+/// Decodes escaped unreserved characters and uppercases the hex of other escapes (RFC 3986, 6.2.2)
+fn normalize_escapes(text: &str) -> String {
+    // Parsing has percent-encoded everything else, so the text is ASCII
+    let bytes = text.as_bytes();
+    let mut normalized = String::with_capacity(text.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = match bytes.get(i..i + 3) {
+            Some([b'%', high, low]) => std::str::from_utf8(&[*high, *low])
+                .ok()
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok()),
+            _ => None,
+        };
+        match escaped {
+            Some(byte) if is_unreserved(byte) => normalized.push(byte as char),
+            Some(byte) => normalized.push_str(&format!("%{:02X}", byte)),
+            None => {
+                normalized.push(bytes[i] as char);
+                i += 1;
+                continue;
+            }
+        }
+        i += 3;
+    }
+    normalized
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_spellings_of_one_url_share_a_canonical_form() {
+        let canonical = "https://api.example.com/v1/rates?currency=USD&amount=10";
+        for spelling in [
+            "HTTPS://API.Example.COM:443/v1/rates?currency=USD&amount=10",
+            "https://api.example.com./v1/./beta/../rates?currency=USD&amount=10",
+            "https://api.example.com/v1/%72ates?currency=%55SD&amount=10#top",
+        ] {
+            assert_eq!(canonicalize(spelling).as_deref(), Some(canonical), "{}", spelling);
+        }
+
+        // Reserved escapes keep their meaning, with uppercase hex
+        assert_eq!(
+            canonicalize("https://api.example.com/files/a%2fb?q=a%26b").as_deref(),
+            Some("https://api.example.com/files/a%2Fb?q=a%26b")
+        );
+        assert_eq!(
+            canonicalize("https://bücher.example/katalog").as_deref(),
+            Some("https://xn--bcher-kva.example/katalog")
+        );
+        assert_eq!(canonicalize("http://api.example.com:8080").as_deref(), Some("http://api.example.com:8080/"));
+        assert_eq!(canonicalize("not a url"), None);
+    }
+}
@@ -0,0 +1,53 @@
+use serde::Serialize;
+use worker::*;
+
+use crate::handlers::registry::REQUEST_TYPES;
+use crate::processors::registry;
+use crate::routing::{self, REGION_CODES};
+
+/// What this deployment supports, served at `GET /capabilities`
+///
+/// Compiled-in features are only reported when their bindings exist too, so
+/// a client sees what will actually work in this environment.
+#[derive(Debug, Serialize)]
+pub struct Capabilities {
+    /// Version of the proxy API
+    pub api_version: &'static str,
+    /// Accepted `X-Request-Type` values
+    pub request_types: Vec<&'static str>,
+    /// Compiled-in regions whose processor namespace is bound
+    pub regions: Vec<&'static str>,
+    pub default_region: &'static str,
+    /// SOAP result caching (`/admin/cache-rules`)
+    pub cache: bool,
+    /// Request archive and replay (`ARCHIVE` bucket)
+    pub archive: bool,
+    /// Deferred processing; every request is proxied synchronously, so this is
+    /// always false for now
+    pub queue: bool,
+    /// Usage and incident history (`DB` database)
+    pub history: bool,
+    /// Region-wide pacing and shard activity (`SHARD_REGISTRY`)
+    pub shard_registry: bool,
+}
+
+impl Capabilities {
+    pub fn detect(env: &Env) -> Capabilities {
+        let config_bound = env.kv("CONFIG").is_ok();
+        Capabilities {
+            api_version: env!("CARGO_PKG_VERSION"),
+            request_types: REQUEST_TYPES.to_vec(),
+            regions: REGION_CODES
+                .iter()
+                .copied()
+                .filter(|code| env.durable_object(&format!("{}_PROCESSOR", code.to_uppercase())).is_ok())
+                .collect(),
+            default_region: routing::DEFAULT_REGION,
+            cache: cfg!(feature = "soap") && config_bound,
+            archive: cfg!(feature = "archive-r2") && env.bucket("ARCHIVE").is_ok(),
+            queue: false,
+            history: env.d1("DB").is_ok(),
+            shard_registry: registry::is_bound(env),
+        }
+    }
+}
@@ -0,0 +1,152 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use worker::js_sys;
+use worker::*;
+
+use crate::error::ProxyError;
+use crate::end_user::EndUser;
+use crate::labels::Labels;
+use crate::log_error;
+
+/// R2 key prefix for captured payloads, next to the request archive
+pub const CAPTURE_PREFIX: &str = "captures/";
+
+/// KV key holding the sampling config
+pub const SAMPLING_KEY: &str = "capture-sampling";
+
+/// Which share of requests is captured with its full response
+///
+/// Independent of the request archive (which keeps every request but only a
+/// fingerprint of the response): captures are for investigating what
+/// upstreams actually returned, so they're sampled to keep the bucket small.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct SamplingConfig {
+    /// Share of requests (0.0-1.0) captured when no rule matches
+    #[serde(default)]
+    pub rate: f64,
+    /// Evaluated in order; the first matching rule sets the rate
+    #[serde(default)]
+    pub rules: Vec<SamplingRule>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SamplingRule {
+    /// Only requests of this tenant ("root" for the shared token)
+    #[serde(default)]
+    pub tenant: Option<String>,
+    /// Only requests carrying all of these labels
+    #[serde(default)]
+    pub labels: Labels,
+    pub rate: f64,
+}
+
+/// A request captured with the full response it got
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CapturedRequest {
+    pub at: u64,
+    pub path: String,
+    pub region: String,
+    pub request_type: String,
+    pub tenant: String,
+    #[serde(default)]
+    pub labels: Labels,
+    /// From `X-End-User`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub end_user: Option<EndUser>,
+    /// Request body after template expansion
+    pub body: String,
+    pub response_status: u16,
+    pub response_headers: BTreeMap<String, String>,
+    /// Proxy response body as sent to the caller
+    pub response_body: String,
+}
+
+impl SamplingRule {
+    fn matches(&self, tenant: &str, labels: &Labels) -> bool {
+        self.tenant.as_deref().is_none_or(|rule_tenant| rule_tenant == tenant) && labels.matches(&self.labels)
+    }
+}
+
+impl SamplingConfig {
+    /// Loads the config from the CONFIG KV namespace; no config captures nothing
+    pub async fn load(env: &Env) -> SamplingConfig {
+        let kv = match env.kv("CONFIG") {
+            Ok(kv) => kv,
+            Err(_) => return SamplingConfig::default(),
+        };
+        match kv.get(SAMPLING_KEY).cache_ttl(60).json::<SamplingConfig>().await {
+            Ok(config) => config.unwrap_or_default(),
+            Err(e) => {
+                log_error!("Failed to load capture sampling config, not capturing: {}", e);
+                SamplingConfig::default()
+            }
+        }
+    }
+
+    /// Reads the config bypassing the edge cache, for the admin API
+    pub async fn load_fresh(env: &Env) -> Result<SamplingConfig> {
+        Ok(env.kv("CONFIG")?.get(SAMPLING_KEY).json::<SamplingConfig>().await?.unwrap_or_default())
+    }
+
+    pub async fn save(&self, env: &Env) -> Result<()> {
+        env.kv("CONFIG")?
+            .put(SAMPLING_KEY, serde_json::to_string(self)?)?
+            .execute()
+            .await?;
+        Ok(())
+    }
+
+    pub fn validate(&self) -> std::result::Result<(), ProxyError> {
+        let mut rates = std::iter::once(self.rate).chain(self.rules.iter().map(|rule| rule.rate));
+        if rates.any(|rate| !(0.0..=1.0).contains(&rate)) {
+            return Err(ProxyError::InvalidRequest("Sampling rates must be between 0 and 1".to_string()));
+        }
+        if self.rules.iter().any(|rule| rule.tenant.is_none() && rule.labels.is_empty()) {
+            return Err(ProxyError::InvalidRequest("Sampling rules need a tenant or labels".to_string()));
+        }
+        Ok(())
+    }
+
+    /// Capture rate of a request
+    pub fn rate_for(&self, tenant: &str, labels: &Labels) -> f64 {
+        self.rules
+            .iter()
+            .find(|rule| rule.matches(tenant, labels))
+            .map_or(self.rate, |rule| rule.rate)
+    }
+
+    /// Decides whether to capture a request
+    pub fn sample(&self, tenant: &str, labels: &Labels) -> bool {
+        let rate = self.rate_for(tenant, labels);
+        rate > 0.0 && js_sys::Math::random() < rate
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_first_matching_rule_sets_the_rate() {
+        let config: SamplingConfig = serde_json::from_str(
+            r#"{"rate": 0.01, "rules": [
+                {"labels": {"flow": "port-in"}, "rate": 1.0},
+                {"tenant": "billing", "rate": 0.1}
+            ]}"#,
+        )
+        .unwrap();
+        assert!(config.validate().is_ok());
+
+        let port_in = Labels::parse("flow=port-in,team=ops").unwrap();
+        assert_eq!(config.rate_for("billing", &port_in), 1.0);
+        assert_eq!(config.rate_for("billing", &Labels::default()), 0.1);
+        assert_eq!(config.rate_for("root", &Labels::default()), 0.01);
+
+        let mut invalid = config.clone();
+        invalid.rate = 1.5;
+        assert!(invalid.validate().is_err());
+        invalid.rate = 0.0;
+        invalid.rules[0].labels = Labels::default();
+        assert!(invalid.validate().is_err());
+    }
+}
@@ -0,0 +1,462 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
+use crate::deadline::DEADLINE_HEADER;
+use crate::labels::LABELS_HEADER;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HttpMethod {
+    Get,
+    Post,
+    Put,
+    Delete,
+    Patch,
+    Head,
+    Options,
+}
+
+/// An HTTP proxy request (`X-Request-Type: http`)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HttpRequest {
+    pub url: String,
+    pub method: HttpMethod,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub params: HashMap<String, String>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub headers: HashMap<String, String>,
+    /// Sent instead of `params`, which then go in the query string
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub body: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub body_format: Option<BodyFormat>,
+    /// `auto` (default), `text` or `base64`; base64 bodies come with `metadata.body_encoding`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub response_format: Option<ResponseFormat>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub assert_region: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub response_schema: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub xml_to_json: Option<Value>,
+    /// Fragments returned instead of the whole body: `$` JSONPaths or `/` XPaths by name
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub extract: Option<BTreeMap<String, String>>,
+    /// `{pick, coerce, rename, flatten}` reshaping the JSON body
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub transform: Option<Value>,
+    /// End user's language, e.g. "de-CH"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub locale: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub credential: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub auth: Option<UpstreamAuth>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub minimal: bool,
+    /// `{max_attempts, backoff, retry_on_statuses, ...}`, as in a retry policy
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retries: Option<Value>,
+    /// GET and HEAD only
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cache: Option<CacheSpec>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub poll: Option<PollSpec>,
+    /// Return only the status and headers; the body is `null` and never downloaded
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub headers_only: bool,
+    /// Unset, every redirect is followed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub redirect: Option<RedirectPolicy>,
+}
+
+/// Whether `HttpRequest`'s upstream redirects are followed, and how many
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct RedirectPolicy {
+    /// When false, a 3xx comes back with its `location` header
+    pub follow: bool,
+    /// 1-20 when following
+    pub max: u8,
+}
+
+/// How `HttpRequest::body` is encoded
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BodyFormat {
+    Raw,
+    Json,
+    Form,
+    /// `body` holds base64; the decoded bytes are sent
+    Base64,
+}
+
+/// How `HttpRequest`'s response body is returned
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ResponseFormat {
+    /// Base64 for binary content types, text otherwise
+    Auto,
+    Text,
+    Base64,
+}
+
+/// Authenticates with a stored credential other than through its headers
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum UpstreamAuth {
+    /// Sends the credential's `secret` as query parameter `param_name`
+    QueryKey { param_name: String, key_ref: String },
+}
+
+/// Caches a GET or HEAD response in the proxy for `ttl_seconds`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CacheSpec {
+    pub ttl_seconds: u64,
+    /// Entry name; by default the method, URL, params and headers identify the entry
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+}
+
+/// Reports in `metadata.poll` whether the response changed since the last
+/// poll with the same `key`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PollSpec {
+    pub key: String,
+    /// Return a `null` body when nothing changed
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub omit_unchanged_body: bool,
+}
+
+impl HttpRequest {
+    pub fn new(method: HttpMethod, url: &str) -> HttpRequest {
+        HttpRequest {
+            url: url.to_string(),
+            method,
+            params: HashMap::new(),
+            headers: HashMap::new(),
+            body: None,
+            body_format: None,
+            response_format: None,
+            assert_region: false,
+            response_schema: None,
+            xml_to_json: None,
+            extract: None,
+            transform: None,
+            locale: None,
+            credential: None,
+            auth: None,
+            minimal: false,
+            retries: None,
+            cache: None,
+            poll: None,
+            headers_only: false,
+            redirect: None,
+        }
+    }
+}
+
+/// A SOAP proxy request (`X-Request-Type: soap`)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SoapRequest {
+    pub url: String,
+    pub action: String,
+    pub namespace: String,
+    /// Ordered `[name, value]` pairs; the order is kept in the envelope
+    #[serde(default)]
+    pub params: Vec<(String, Value)>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub headers: HashMap<String, String>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub assert_region: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub response_schema: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub xml_to_json: Option<Value>,
+    /// Fragments returned instead of the whole body: `$` JSONPaths or `/` XPaths by name
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub extract: Option<BTreeMap<String, String>>,
+    /// `{pick, coerce, rename, flatten}` reshaping the JSON body
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub transform: Option<Value>,
+    /// End user's language, e.g. "de-CH"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub locale: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub credential: Option<String>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub minimal: bool,
+    /// `{max_attempts, backoff, retry_on_statuses, ...}`, as in a retry policy
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retries: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub poll: Option<PollSpec>,
+    /// The proxy generates the `MessageID` and reports it in `metadata.message_id`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ws_addressing: Option<WsAddressing>,
+}
+
+/// WS-Addressing headers for a SOAP request; unset fields get the proxy's defaults
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct WsAddressing {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub to: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub action: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reply_to: Option<String>,
+}
+
+impl SoapRequest {
+    pub fn new(url: &str, action: &str, namespace: &str) -> SoapRequest {
+        SoapRequest {
+            url: url.to_string(),
+            action: action.to_string(),
+            namespace: namespace.to_string(),
+            params: Vec::new(),
+            headers: HashMap::new(),
+            assert_region: false,
+            response_schema: None,
+            xml_to_json: None,
+            extract: None,
+            transform: None,
+            locale: None,
+            credential: None,
+            minimal: false,
+            retries: None,
+            poll: None,
+            ws_addressing: None,
+        }
+    }
+
+    /// Appends a param, keeping the order
+    pub fn param(mut self, name: &str, value: impl Into<Value>) -> SoapRequest {
+        self.params.push((name.to_string(), value.into()));
+        self
+    }
+}
+
+/// The proxy's answer to an HTTP or SOAP request
+///
+/// Upstream error statuses are answers too (`UpstreamError`); only errors of
+/// the proxy itself become `ClientError::Proxy`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum ProxyResponse {
+    Success {
+        status: u16,
+        headers: HashMap<String, String>,
+        body: Value,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        metadata: Option<Value>,
+    },
+    UpstreamError {
+        status: u16,
+        message: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        metadata: Option<Value>,
+    },
+    /// A success with `minimal: true`
+    Minimal { status: u16, body: Value },
+}
+
+impl ProxyResponse {
+    /// Upstream HTTP status
+    pub fn status(&self) -> u16 {
+        match self {
+            ProxyResponse::Success { status, .. }
+            | ProxyResponse::UpstreamError { status, .. }
+            | ProxyResponse::Minimal { status, .. } => *status,
+        }
+    }
+}
+
+/// Machine-readable code of a proxy error (see `GET /errors`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ErrorCode {
+    InvalidRequest,
+    NotFound,
+    PolicyViolation,
+    /// The tenant is over its requests per minute; `Retry-After` says when to try again
+    RateLimited,
+    RegionAssertionFailed,
+    ContractViolation,
+    UpstreamMaintenance,
+    CircuitOpen,
+    /// The region is being taken out of service; the error names the one to use instead
+    RegionDraining,
+    DeadlineExceeded,
+    /// `X-Expires-At` passed before the request was sent, so it was dropped
+    Expired,
+    PayloadTooLarge,
+    ResponseTooLarge,
+    UpstreamError,
+    /// `details` holds the GraphQL upstream's `errors` and any partial `data`
+    #[serde(rename = "GRAPHQL_ERROR")]
+    GraphQlError,
+    /// `details` holds the JSON-RPC `error` object: `code`, `message` and any `data`
+    #[serde(rename = "JSONRPC_ERROR")]
+    JsonRpcError,
+    /// `details` holds the XML-RPC fault's `code` (`faultCode`) and `message` (`faultString`)
+    #[serde(rename = "XMLRPC_FAULT")]
+    XmlRpcFault,
+    InternalError,
+    /// A code added to the proxy after this client was built
+    #[serde(other)]
+    Unknown,
+}
+
+/// Body of a proxy error response
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProxyErrorBody {
+    pub status: u16,
+    pub code: ErrorCode,
+    pub message: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub details: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<Value>,
+}
+
+#[derive(Debug)]
+pub enum ClientError {
+    /// The proxy could not be reached or its answer could not be read
+    Transport(reqwest::Error),
+    /// The proxy rejected or failed the request
+    Proxy {
+        error: ProxyErrorBody,
+        /// From `Retry-After`, sent with `UPSTREAM_MAINTENANCE` and `CIRCUIT_OPEN`
+        retry_after_secs: Option<u64>,
+    },
+    /// A non-JSON error, e.g. the 403 for a wrong token
+    Status { status: u16, body: String },
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientError::Transport(e) => write!(f, "Proxy request failed: {}", e),
+            ClientError::Proxy { error, .. } => write!(f, "{:?}: {}", error.code, error.message),
+            ClientError::Status { status, body } => write!(f, "Proxy returned {}: {}", status, body),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+impl From<reqwest::Error> for ClientError {
+    fn from(e: reqwest::Error) -> Self {
+        ClientError::Transport(e)
+    }
+}
+
+/// Per-call routing and accounting options, sent as request headers
+#[derive(Debug, Clone, Default)]
+pub struct CallOptions {
+    /// `X-CF-Region`, e.g. "weur"
+    pub region: Option<String>,
+    /// `X-Deadline` as epoch millis
+    pub deadline: Option<u64>,
+    /// `X-Labels`, e.g. `[("team", "billing")]`
+    pub labels: Vec<(String, String)>,
+}
+
+/// Calls the proxy with a bearer token
+#[derive(Debug, Clone)]
+pub struct ProxyClient {
+    http: reqwest::Client,
+    base_url: String,
+    token: String,
+}
+
+impl ProxyClient {
+    pub fn new(base_url: &str, token: &str) -> ProxyClient {
+        ProxyClient {
+            http: reqwest::Client::new(),
+            base_url: base_url.trim_end_matches('/').to_string(),
+            token: token.to_string(),
+        }
+    }
+
+    pub async fn http(&self, request: &HttpRequest, options: &CallOptions) -> Result<ProxyResponse, ClientError> {
+        self.send("http", request, options).await
+    }
+
+    pub async fn soap(&self, request: &SoapRequest, options: &CallOptions) -> Result<ProxyResponse, ClientError> {
+        self.send("soap", request, options).await
+    }
+
+    async fn send<T: Serialize>(
+        &self,
+        request_type: &str,
+        body: &T,
+        options: &CallOptions,
+    ) -> Result<ProxyResponse, ClientError> {
+        let mut request = self
+            .http
+            .post(format!("{}/", self.base_url))
+            .bearer_auth(&self.token)
+            .header("X-Request-Type", request_type)
+            .json(body);
+        if let Some(region) = &options.region {
+            request = request.header("X-CF-Region", region);
+        }
+        if let Some(deadline) = options.deadline {
+            request = request.header(DEADLINE_HEADER, deadline.to_string());
+        }
+        if !options.labels.is_empty() {
+            let labels: Vec<String> = options.labels.iter().map(|(key, value)| format!("{}={}", key, value)).collect();
+            request = request.header(LABELS_HEADER, labels.join(","));
+        }
+
+        let response = request.send().await?;
+        let status = response.status().as_u16();
+        let retry_after_secs = response
+            .headers()
+            .get("Retry-After")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse().ok());
+        let text = response.text().await?;
+        if status == 200 {
+            if let Ok(answer) = serde_json::from_str::<ProxyResponse>(&text) {
+                return Ok(answer);
+            }
+        }
+        match serde_json::from_str::<ProxyErrorBody>(&text) {
+            Ok(error) => Err(ClientError::Proxy { error, retry_after_secs }),
+            Err(_) => Err(ClientError::Status { status, body: text }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::ProxyError;
+
+    #[test]
+    fn test_client_types_match_the_server() {
+        #[cfg(feature = "soap")]
+        {
+            let request = SoapRequest::new("https://soap.example.com/service", "getDIDCountry", "urn:getDIDCountry")
+                .param("did", "1234567890")
+                .param("country", "US");
+            let parsed: crate::handlers::soap_handler::SoapRequestData =
+                serde_json::from_value(serde_json::to_value(&request).unwrap()).unwrap();
+            assert_eq!(parsed.params.len(), 2);
+        }
+
+        let request = HttpRequest::new(HttpMethod::Get, "https://api.example.com/users");
+        let parsed: crate::handlers::http_handler::RequestData =
+            serde_json::from_value(serde_json::to_value(&request).unwrap()).unwrap();
+        assert!(matches!(parsed.method, crate::handlers::http_handler::HttpMethod::Get));
+
+        // Every code in the server's catalog is known to the client
+        for entry in ProxyError::catalog() {
+            let code: ErrorCode = serde_json::from_value(serde_json::json!(entry.code)).unwrap();
+            assert_ne!(code, ErrorCode::Unknown, "{}", entry.code);
+        }
+    }
+}
@@ -0,0 +1,204 @@
+use std::collections::HashMap;
+
+/// Marks compressed data; JSON (what the archive stores otherwise) never starts with a NUL
+const MAGIC: &[u8] = b"\0APZ";
+
+/// Shared dictionaries by id, shipped with the worker
+///
+/// A changed dictionary gets a new id and the old one stays, so objects
+/// written before a deploy still decompress after it.
+const DICTIONARIES: &[(u8, &[u8])] = &[(1, include_bytes!("../dictionaries/soap-v1.dict"))];
+
+/// Dictionary new data is compressed with
+const CURRENT_DICTIONARY: u8 = 1;
+
+/// Shortest back-reference worth encoding
+const MIN_MATCH: usize = 4;
+
+/// Longest back-reference one op encodes
+const MAX_MATCH: usize = MIN_MATCH + 0x7f;
+
+/// Longest literal run one op encodes
+const MAX_LITERALS: usize = 0x80;
+
+/// Most recent earlier positions tried per 4-byte prefix
+const MAX_CANDIDATES: usize = 16;
+
+fn dictionary(id: u8) -> Option<&'static [u8]> {
+    DICTIONARIES.iter().find(|(dictionary_id, _)| *dictionary_id == id).map(|(_, bytes)| *bytes)
+}
+
+pub fn is_compressed(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+/// Compresses `data` against the current shared dictionary
+///
+/// LZ77 over the dictionary followed by the data, so boilerplate (SOAP
+/// envelopes, namespaces, record keys) becomes short back-references into
+/// the dictionary. After the magic and the dictionary id, each op is a tag
+/// byte followed by either `tag + 1` literal bytes (tag < 0x80), or the
+/// varint distance back of a match of `(tag & 0x7f) + 4` bytes.
+pub fn compress(data: &[u8]) -> Vec<u8> {
+    let dictionary = dictionary(CURRENT_DICTIONARY).unwrap_or_default();
+    let mut window = Vec::with_capacity(dictionary.len() + data.len());
+    window.extend_from_slice(dictionary);
+    window.extend_from_slice(data);
+
+    let mut out = Vec::with_capacity(data.len() / 2 + MAGIC.len() + 1);
+    out.extend_from_slice(MAGIC);
+    out.push(CURRENT_DICTIONARY);
+
+    let mut candidates: HashMap<[u8; MIN_MATCH], Vec<usize>> = HashMap::new();
+    let remember = |candidates: &mut HashMap<[u8; MIN_MATCH], Vec<usize>>, pos: usize| {
+        if let Some(prefix) = window.get(pos..pos + MIN_MATCH) {
+            let positions = candidates.entry(prefix.try_into().unwrap_or_default()).or_default();
+            if positions.len() == MAX_CANDIDATES {
+                positions.remove(0);
+            }
+            positions.push(pos);
+        }
+    };
+    for pos in 0..dictionary.len() {
+        remember(&mut candidates, pos);
+    }
+
+    let mut literals_from = dictionary.len();
+    let mut pos = dictionary.len();
+    while pos + MIN_MATCH <= window.len() {
+        let best = window[pos..pos + MIN_MATCH]
+            .try_into()
+            .ok()
+            .and_then(|prefix: [u8; MIN_MATCH]| candidates.get(&prefix))
+            .into_iter()
+            .flatten()
+            .map(|&start| {
+                // A match may run into the bytes it produces; decoding copies byte by byte
+                let len = window[start..]
+                    .iter()
+                    .zip(&window[pos..])
+                    .take(MAX_MATCH)
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                (len, pos - start)
+            })
+            .max_by_key(|&(len, distance)| (len, std::cmp::Reverse(distance)));
+
+        match best {
+            Some((len, distance)) if len >= MIN_MATCH => {
+                push_literals(&mut out, &window[literals_from..pos]);
+                out.push(0x80 | (len - MIN_MATCH) as u8);
+                push_varint(&mut out, distance);
+                for matched in pos..pos + len {
+                    remember(&mut candidates, matched);
+                }
+                pos += len;
+                literals_from = pos;
+            }
+            _ => {
+                remember(&mut candidates, pos);
+                pos += 1;
+            }
+        }
+    }
+    push_literals(&mut out, &window[literals_from..]);
+    out
+}
+
+/// Restores data written by `compress`, with whichever dictionary it names
+pub fn decompress(data: &[u8]) -> Result<Vec<u8>, String> {
+    let body = data.strip_prefix(MAGIC).ok_or("Not compressed data")?;
+    let (&id, mut ops) = body.split_first().ok_or("Truncated header")?;
+    let dictionary = dictionary(id).ok_or_else(|| format!("Unknown compression dictionary {}", id))?;
+
+    let mut window = dictionary.to_vec();
+    while let Some((&tag, rest)) = ops.split_first() {
+        if tag < 0x80 {
+            let len = tag as usize + 1;
+            let literals = rest.get(..len).ok_or("Truncated literals")?;
+            window.extend_from_slice(literals);
+            ops = &rest[len..];
+        } else {
+            let len = (tag & 0x7f) as usize + MIN_MATCH;
+            let (distance, used) = read_varint(rest).ok_or("Truncated match")?;
+            if distance == 0 || distance > window.len() {
+                return Err(format!("Match distance {} out of range", distance));
+            }
+            let start = window.len() - distance;
+            for i in start..start + len {
+                window.push(window[i]);
+            }
+            ops = &rest[used..];
+        }
+    }
+    Ok(window.split_off(dictionary.len()))
+}
+
+fn push_literals(out: &mut Vec<u8>, literals: &[u8]) {
+    for run in literals.chunks(MAX_LITERALS) {
+        out.push((run.len() - 1) as u8);
+        out.extend_from_slice(run);
+    }
+}
+
+/// LEB128: 7 bits per byte, high bit set on all but the last
+fn push_varint(out: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        out.push((value & 0x7f) as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+/// The value and the bytes it took
+fn read_varint(bytes: &[u8]) -> Option<(usize, usize)> {
+    let mut value = 0usize;
+    for (i, &byte) in bytes.iter().enumerate().take(5) {
+        value |= ((byte & 0x7f) as usize) << (7 * i);
+        if byte < 0x80 {
+            return Some((value, i + 1));
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trips_and_shrinks_soap_boilerplate() {
+        let envelope = "<?xml version=\\\"1.0\\\" encoding=\\\"ISO-8859-1\\\"?><SOAP-ENV:Envelope \
+            SOAP-ENV:encodingStyle=\\\"http://schemas.xmlsoap.org/soap/encoding/\\\" \
+            xmlns:SOAP-ENV=\\\"http://schemas.xmlsoap.org/soap/envelope/\\\" \
+            xmlns:xsd=\\\"http://www.w3.org/2001/XMLSchema\\\" \
+            xmlns:xsi=\\\"http://www.w3.org/2001/XMLSchema-instance\\\" \
+            xmlns:SOAP-ENC=\\\"http://schemas.xmlsoap.org/soap/encoding/\\\"><SOAP-ENV:Body>\
+            <ns1:getDIDCountryResponse xmlns:ns1=\\\"urn:didx\\\"><return xsi:type=\\\"xsd:string\\\">US</return>\
+            </ns1:getDIDCountryResponse></SOAP-ENV:Body></SOAP-ENV:Envelope>";
+        let record = format!(
+            concat!(
+                r#"{{"at":1767229200000,"path":"/","region":"weur","request_type":"soap","#,
+                r#""tenant":"acme","labels":{{}},"#,
+                r#""response_status":200,"response_body":"{{\"status\":200,\"body\":\"{}\"}}"}}"#
+            ),
+            envelope
+        );
+        let compressed = compress(record.as_bytes());
+        assert!(is_compressed(&compressed));
+        assert!(compressed.len() * 4 < record.len(), "{} of {} bytes", compressed.len(), record.len());
+        assert_eq!(decompress(&compressed).unwrap(), record.as_bytes());
+
+        // Data the dictionary knows nothing about still round-trips, including long repeats
+        for data in [Vec::new(), vec![7u8; 1000], (0..=255u8).cycle().take(5000).collect()] {
+            assert_eq!(decompress(&compress(&data)).unwrap(), data);
+        }
+
+        assert!(!is_compressed(br#"{"at":1}"#));
+        let mut unknown = compress(b"hello");
+        unknown[MAGIC.len()] = 0xff;
+        assert!(decompress(&unknown).is_err());
+        let truncated = &compressed[..compressed.len() - 3];
+        assert!(decompress(truncated).map(|data| data != record.as_bytes()).unwrap_or(true));
+    }
+}
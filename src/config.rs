@@ -0,0 +1,360 @@
+use serde::Serialize;
+use serde_json::json;
+use std::collections::BTreeMap;
+use worker::*;
+
+use crate::dns_guard::{Blocklist, TargetGuard};
+use crate::routing::{self, DEFAULT_POOL_SIZE, REGION_CODES};
+use crate::slo::SloTargets;
+
+/// Header carrying the edge's config version to the Durable Object
+pub const VERSION_HEADER: &str = "X-Config-Version";
+
+/// Worker variable that turns DNS rebinding checks off when set to "false"
+const DNS_GUARD_VAR: &str = "DNS_REBINDING_CHECK";
+
+/// Worker variable listing extra networks and hosts upstream calls may not reach
+const SSRF_BLOCKLIST_VAR: &str = "SSRF_BLOCKLIST";
+
+/// Worker variable overriding the largest accepted request body, in bytes
+const REQUEST_LIMIT_VAR: &str = "MAX_REQUEST_BYTES";
+
+/// Worker variable overriding the largest upstream response body, in bytes
+const RESPONSE_LIMIT_VAR: &str = "MAX_RESPONSE_BYTES";
+
+/// Worker variable turning on dictionary compression of archived records: "dictionary" or "none"
+const ARCHIVE_COMPRESSION_VAR: &str = "ARCHIVE_COMPRESSION";
+
+/// Worker variable setting the proxy's availability objective, in percent of requests
+const SLO_AVAILABILITY_VAR: &str = "SLO_AVAILABILITY";
+
+/// Worker variable setting the proxy overhead a request should stay under, in milliseconds
+const SLO_LATENCY_MS_VAR: &str = "SLO_LATENCY_MS";
+
+/// Worker variable setting the percent of requests that should stay under `SLO_LATENCY_MS`
+const SLO_LATENCY_VAR: &str = "SLO_LATENCY";
+
+/// Worker variable naming the header upstream attempts carry their ID in, or "none"
+const ATTEMPT_HEADER_VAR: &str = "ATTEMPT_HEADER";
+
+/// Worker variable setting how many requests one shard runs at once, shared between tenants; 0 is unlimited
+const SHARD_CONCURRENCY_VAR: &str = "SHARD_CONCURRENCY";
+
+/// Worker variable setting the pool size of every region; `DO_POOL_SIZE_<REGION>`
+/// (e.g. `DO_POOL_SIZE_WEUR`) overrides it for one region
+const POOL_SIZE_VAR: &str = "DO_POOL_SIZE";
+
+/// Secret accepted as the root proxy token
+const AUTH_TOKEN_SECRET: &str = "AUTH_TOKEN";
+
+/// Secret guarding `/admin/*` and policy overrides
+const ADMIN_TOKEN_SECRET: &str = "ADMIN_TOKEN";
+
+const DEFAULT_MAX_REQUEST_BYTES: u64 = 10 * 1024 * 1024;
+
+const DEFAULT_MAX_RESPONSE_BYTES: u64 = 25 * 1024 * 1024;
+
+const DEFAULT_SLO_AVAILABILITY: f64 = 99.9;
+
+const DEFAULT_SLO_LATENCY_MS: u64 = 250;
+
+const DEFAULT_SLO_LATENCY: f64 = 99.0;
+
+const DEFAULT_ATTEMPT_HEADER: &str = "X-Attempt";
+
+const DEFAULT_SHARD_CONCURRENCY: u32 = 64;
+
+/// Where a resolved setting came from
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "type", content = "name", rename_all = "snake_case")]
+pub enum Source {
+    /// Built in: the variable is unset, or its value was invalid (see `warnings`)
+    Default,
+    /// A worker variable
+    Var(String),
+    /// A key of the CONFIG KV namespace
+    Kv(String),
+}
+
+/// A resolved value and where it came from
+#[derive(Debug, Clone, Serialize)]
+pub struct Setting<T> {
+    pub value: T,
+    pub source: Source,
+}
+
+/// Worker variables and secrets, resolved and validated once per invocation
+///
+/// Every module reads its settings from the same snapshot, so a request sees
+/// one consistent configuration from start to end. The edge forwards its
+/// `version` to the processor, which logs when its own snapshot differs (a
+/// deploy rolling out between the two).
+#[derive(Debug, Clone, Serialize)]
+pub struct Config {
+    /// Fingerprint of the resolved values, secrets included
+    pub version: String,
+    /// Whether upstream hosts are resolved and checked for internal addresses
+    pub dns_guard: Setting<bool>,
+    /// Refused on top of internal addresses and metadata service names
+    pub ssrf_blocklist: Setting<Blocklist>,
+    pub max_request_bytes: Setting<u64>,
+    pub max_response_bytes: Setting<u64>,
+    /// Whether archived requests and captures are compressed with the shared dictionary
+    pub archive_compression: Setting<bool>,
+    /// The proxy's own objectives, in percent of requests (see `slo`)
+    pub slo_availability: Setting<f64>,
+    pub slo_latency_ms: Setting<u64>,
+    pub slo_latency: Setting<f64>,
+    pub attempt_header: Setting<Option<String>>,
+    /// Concurrency slots of each shard (see `processors::slots`)
+    pub shard_concurrency: Setting<u32>,
+    /// Shards per compiled-in region
+    pub pool_sizes: BTreeMap<&'static str, Setting<u32>>,
+    /// Whether each secret is set; values are never shown
+    pub secrets: BTreeMap<&'static str, bool>,
+    /// Variables whose values were invalid and ignored
+    pub warnings: Vec<String>,
+    #[serde(skip)]
+    auth_token: Option<String>,
+    #[serde(skip)]
+    admin_token: Option<String>,
+}
+
+/// Reads variables, falling back to defaults and noting invalid values
+struct Resolver<F> {
+    var: F,
+    warnings: Vec<String>,
+}
+
+impl<F: Fn(&str) -> Option<String>> Resolver<F> {
+    /// The first of `names` set to a valid value, or `default`
+    fn setting<T>(&mut self, names: &[&str], parse: impl Fn(&str) -> Option<T>, default: T) -> Setting<T> {
+        for name in names {
+            if let Some(raw) = (self.var)(name) {
+                match parse(&raw) {
+                    Some(value) => {
+                        return Setting {
+                            value,
+                            source: Source::Var(name.to_string()),
+                        }
+                    }
+                    None => self.warnings.push(format!("{}={:?} is invalid and was ignored", name, raw)),
+                }
+            }
+        }
+        Setting {
+            value: default,
+            source: Source::Default,
+        }
+    }
+}
+
+fn parse_bool(value: &str) -> Option<bool> {
+    match value.trim().to_lowercase().as_str() {
+        "true" => Some(true),
+        "false" => Some(false),
+        _ => None,
+    }
+}
+
+fn parse_compression(value: &str) -> Option<bool> {
+    match value.trim().to_lowercase().as_str() {
+        "dictionary" => Some(true),
+        "none" => Some(false),
+        _ => None,
+    }
+}
+
+fn parse_percent(value: &str) -> Option<f64> {
+    value.trim().parse().ok().filter(|percent| *percent > 0.0 && *percent < 100.0)
+}
+
+fn parse_limit(value: &str) -> Option<u64> {
+    value.trim().parse().ok().filter(|limit| *limit > 0)
+}
+
+fn parse_count(value: &str) -> Option<u32> {
+    value.trim().parse().ok()
+}
+
+fn parse_header_name(value: &str) -> Option<Option<String>> {
+    match value.trim() {
+        name if name.eq_ignore_ascii_case("none") => Some(None),
+        name => http::HeaderName::from_bytes(name.as_bytes()).ok().map(|_| Some(name.to_string())),
+    }
+}
+
+impl Config {
+    pub fn load(env: &Env) -> Config {
+        Config::resolve(
+            |name| env.var(name).ok().map(|value| value.to_string()),
+            |name| env.secret(name).ok().map(|value| value.to_string()),
+        )
+    }
+
+    fn resolve(var: impl Fn(&str) -> Option<String>, secret: impl Fn(&str) -> Option<String>) -> Config {
+        let mut resolver = Resolver {
+            var,
+            warnings: Vec::new(),
+        };
+        let dns_guard = resolver.setting(&[DNS_GUARD_VAR], parse_bool, true);
+        let ssrf_blocklist = resolver.setting(&[SSRF_BLOCKLIST_VAR], Blocklist::parse, Blocklist::default());
+        let max_request_bytes = resolver.setting(&[REQUEST_LIMIT_VAR], parse_limit, DEFAULT_MAX_REQUEST_BYTES);
+        let max_response_bytes = resolver.setting(&[RESPONSE_LIMIT_VAR], parse_limit, DEFAULT_MAX_RESPONSE_BYTES);
+        let archive_compression = resolver.setting(&[ARCHIVE_COMPRESSION_VAR], parse_compression, false);
+        let slo_availability = resolver.setting(&[SLO_AVAILABILITY_VAR], parse_percent, DEFAULT_SLO_AVAILABILITY);
+        let slo_latency_ms = resolver.setting(&[SLO_LATENCY_MS_VAR], parse_limit, DEFAULT_SLO_LATENCY_MS);
+        let slo_latency = resolver.setting(&[SLO_LATENCY_VAR], parse_percent, DEFAULT_SLO_LATENCY);
+        let default_attempt_header = Some(DEFAULT_ATTEMPT_HEADER.to_string());
+        let attempt_header = resolver.setting(&[ATTEMPT_HEADER_VAR], parse_header_name, default_attempt_header);
+        let shard_concurrency = resolver.setting(&[SHARD_CONCURRENCY_VAR], parse_count, DEFAULT_SHARD_CONCURRENCY);
+        let pool_sizes = REGION_CODES
+            .iter()
+            .map(|code| {
+                let region_var = format!("{}_{}", POOL_SIZE_VAR, code.to_uppercase());
+                let names = [region_var.as_str(), POOL_SIZE_VAR];
+                (*code, resolver.setting(&names, routing::parse_pool_size, DEFAULT_POOL_SIZE))
+            })
+            .collect();
+        let auth_token = secret(AUTH_TOKEN_SECRET);
+        let admin_token = secret(ADMIN_TOKEN_SECRET);
+
+        let mut config = Config {
+            version: String::new(),
+            dns_guard,
+            ssrf_blocklist,
+            max_request_bytes,
+            max_response_bytes,
+            archive_compression,
+            slo_availability,
+            slo_latency_ms,
+            slo_latency,
+            attempt_header,
+            shard_concurrency,
+            pool_sizes,
+            secrets: BTreeMap::from([
+                (AUTH_TOKEN_SECRET, auth_token.is_some()),
+                (ADMIN_TOKEN_SECRET, admin_token.is_some()),
+            ]),
+            warnings: resolver.warnings,
+            auth_token,
+            admin_token,
+        };
+        config.version = config.fingerprint();
+        config
+    }
+
+    /// Short SHA-256 of the values; sources don't matter, rotated secrets do
+    fn fingerprint(&self) -> String {
+        use sha2::{Digest, Sha256};
+
+        let pool_sizes: BTreeMap<_, _> = self.pool_sizes.iter().map(|(code, size)| (code, size.value)).collect();
+        let values = json!([
+            self.dns_guard.value,
+            self.ssrf_blocklist.value.entries(),
+            self.max_request_bytes.value,
+            self.max_response_bytes.value,
+            self.archive_compression.value,
+            self.slo_availability.value,
+            self.slo_latency_ms.value,
+            self.slo_latency.value,
+            self.attempt_header.value,
+            self.shard_concurrency.value,
+            pool_sizes,
+            self.auth_token,
+            self.admin_token,
+        ]);
+        Sha256::digest(values.to_string().as_bytes())
+            .iter()
+            .take(6)
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+
+    /// SSRF checks for upstream targets
+    pub fn target_guard(&self) -> TargetGuard {
+        TargetGuard {
+            resolve: self.dns_guard.value,
+            blocklist: self.ssrf_blocklist.value.clone(),
+            sandbox: None,
+        }
+    }
+
+    /// The proxy's own availability and latency objectives
+    pub fn slo_targets(&self) -> SloTargets {
+        SloTargets {
+            availability: self.slo_availability.value,
+            latency_ms: self.slo_latency_ms.value,
+            latency: self.slo_latency.value,
+        }
+    }
+
+    /// Number of shards requests are spread over in a region
+    pub fn pool_size(&self, region_code: &str) -> u32 {
+        self.pool_sizes
+            .get(region_code)
+            .map(|size| size.value)
+            .unwrap_or(DEFAULT_POOL_SIZE)
+    }
+
+    /// The root proxy token (`AUTH_TOKEN`), when set
+    pub fn auth_token(&self) -> Option<&str> {
+        self.auth_token.as_deref()
+    }
+
+    /// The admin token (`ADMIN_TOKEN`), when set
+    pub fn admin_token(&self) -> Option<&str> {
+        self.admin_token.as_deref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_config_resolution() {
+        let region = REGION_CODES[0];
+        let region_var = format!("DO_POOL_SIZE_{}", region.to_uppercase());
+        let vars = HashMap::from([
+            ("MAX_REQUEST_BYTES", "1048576".to_string()),
+            ("MAX_RESPONSE_BYTES", "lots".to_string()),
+            ("DO_POOL_SIZE", "20".to_string()),
+            ("SLO_AVAILABILITY", "100".to_string()),
+            ("ATTEMPT_HEADER", "None".to_string()),
+            ("SHARD_CONCURRENCY", "0".to_string()),
+            (region_var.as_str(), "500".to_string()),
+        ]);
+        let secrets = HashMap::from([("AUTH_TOKEN", "secret-token".to_string())]);
+        let config = Config::resolve(|name| vars.get(name).cloned(), |name| secrets.get(name).cloned());
+
+        assert!(config.dns_guard.value);
+        assert_eq!(config.dns_guard.source, Source::Default);
+        assert_eq!(config.max_request_bytes.value, 1048576);
+        assert_eq!(config.max_request_bytes.source, Source::Var("MAX_REQUEST_BYTES".to_string()));
+        assert_eq!(config.max_response_bytes.value, DEFAULT_MAX_RESPONSE_BYTES);
+
+        // An invalid regional size falls back to the global one, and is reported
+        assert_eq!(config.pool_size(region), 20);
+        assert_eq!(config.pool_sizes[region].source, Source::Var("DO_POOL_SIZE".to_string()));
+        // A 100% objective leaves no error budget to burn
+        assert_eq!(config.slo_availability.value, DEFAULT_SLO_AVAILABILITY);
+        assert_eq!(config.attempt_header.value, None);
+        assert_eq!(parse_header_name("X-Try"), Some(Some("X-Try".to_string())));
+        assert_eq!(parse_header_name("bad header"), None);
+        assert_eq!(config.shard_concurrency.value, 0);
+        assert_eq!(config.warnings.len(), 3);
+
+        assert_eq!(config.auth_token(), Some("secret-token"));
+        assert_eq!(config.admin_token(), None);
+        let shown = serde_json::to_string(&config).unwrap();
+        assert!(!shown.contains("secret-token"));
+
+        // Rotating a secret changes the version
+        let rotated = HashMap::from([("AUTH_TOKEN", "rotated-token".to_string())]);
+        let next = Config::resolve(|name| vars.get(name).cloned(), |name| rotated.get(name).cloned());
+        assert_ne!(config.version, next.version);
+        assert_eq!(config.version.len(), 12);
+    }
+}
@@ -0,0 +1,102 @@
+use serde::{Deserialize, Serialize};
+use std::cell::Cell;
+use std::ops::AddAssign;
+
+/// Response header the processor reports every request's cost in; the edge
+/// adds it to usage accounting
+pub const COST_HEADER: &str = "X-Proxy-Cost";
+
+/// Request header (`true`) asking for the cost block in the response metadata
+pub const INCLUDE_COST_HEADER: &str = "X-Cost-Estimate";
+
+/// What one proxied request used in Workers billing terms
+///
+/// CPU time isn't included: the Workers runtime doesn't expose it, and
+/// `Date.now()` doesn't advance while a request runs on the CPU.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CostEstimate {
+    /// Upstream fetches made by the processor, retries included
+    pub subrequests: u32,
+    /// Durable Object requests (the processor call)
+    pub do_requests: u32,
+    /// Approximate bytes sent to upstreams: URL, headers and body of every attempt
+    pub bytes_egressed: u64,
+}
+
+impl CostEstimate {
+    /// The cost of reaching a processor, before any upstream call
+    pub fn processor_call() -> CostEstimate {
+        CostEstimate {
+            do_requests: 1,
+            ..CostEstimate::default()
+        }
+    }
+
+    /// Cost of `attempts` upstream calls of `bytes` each
+    pub fn upstream(attempts: u32, bytes: u64) -> CostEstimate {
+        CostEstimate {
+            subrequests: attempts,
+            do_requests: 0,
+            bytes_egressed: bytes * u64::from(attempts),
+        }
+    }
+
+    /// Formats the cost for `COST_HEADER`, e.g. `subrequests=2; do_requests=1; bytes_egressed=812`
+    pub fn to_header(self) -> String {
+        format!(
+            "subrequests={}; do_requests={}; bytes_egressed={}",
+            self.subrequests, self.do_requests, self.bytes_egressed
+        )
+    }
+
+    /// Parses `to_header` output; unknown keys are ignored
+    pub fn from_header(value: &str) -> Option<CostEstimate> {
+        let mut cost = CostEstimate::default();
+        for part in value.split(';') {
+            let (key, number) = part.trim().split_once('=')?;
+            match key {
+                "subrequests" => cost.subrequests = number.parse().ok()?,
+                "do_requests" => cost.do_requests = number.parse().ok()?,
+                "bytes_egressed" => cost.bytes_egressed = number.parse().ok()?,
+                _ => {}
+            }
+        }
+        Some(cost)
+    }
+}
+
+impl AddAssign for CostEstimate {
+    fn add_assign(&mut self, other: CostEstimate) {
+        self.subrequests += other.subrequests;
+        self.do_requests += other.do_requests;
+        self.bytes_egressed += other.bytes_egressed;
+    }
+}
+
+/// Adds `cost` to a request's running total
+pub fn add(total: &Cell<CostEstimate>, cost: CostEstimate) {
+    let mut sum = total.get();
+    sum += cost;
+    total.set(sum);
+}
+
+/// Approximate size of an upstream request on the wire, without the HTTP framing
+pub fn request_bytes<'a>(url: &str, headers: impl IntoIterator<Item = (&'a String, &'a String)>, body: usize) -> u64 {
+    // ": " and CRLF per header line
+    let headers: usize = headers.into_iter().map(|(name, value)| name.len() + value.len() + 4).sum();
+    (url.len() + headers + body) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cost_header_round_trip() {
+        let mut cost = CostEstimate::processor_call();
+        cost += CostEstimate::upstream(3, 200);
+        assert_eq!(cost.to_header(), "subrequests=3; do_requests=1; bytes_egressed=600");
+        assert_eq!(CostEstimate::from_header(&cost.to_header()), Some(cost));
+        assert_eq!(CostEstimate::from_header("subrequests=x"), None);
+    }
+}
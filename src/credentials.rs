@@ -0,0 +1,432 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use worker::*;
+
+use crate::allowlist::HostRule;
+use crate::auth::ROOT_TENANT_ID;
+use crate::error::ProxyError;
+use crate::handlers::upstream;
+use crate::handlers::ApiResponse;
+use crate::{log_error, log_info};
+
+/// KV key prefix for stored upstream credentials
+const CREDENTIAL_PREFIX: &str = "credential:";
+
+/// KV key prefix for credentials marked unhealthy (absent while healthy)
+const HEALTH_PREFIX: &str = "credential-health:";
+
+/// DO storage namespace counting consecutive auth failures per credential
+const FAILURES_NAMESPACE: &str = "credential-failures";
+
+/// An upstream credential that requests reference by name
+///
+/// Its headers are added to the upstream request, and its `secret` is what
+/// `query_key` auth sends in the query string. After `failure_threshold`
+/// consecutive 401/403 responses it's marked unhealthy, the alert webhook is
+/// notified, and requests switch to `backup` when one is configured.
+/// Only the shared token and `allowed_tenants` may use it, and only on
+/// targets matching `allowed_hosts`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Credential {
+    pub name: String,
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    /// API key for upstreams that take it in the query string
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub secret: Option<String>,
+    /// Tenants that may reference the credential; empty leaves it to the shared token
+    #[serde(default)]
+    pub allowed_tenants: Vec<String>,
+    /// Allowlist rules (see `HostRule`) for the upstreams it's sent to; empty allows any host
+    #[serde(default)]
+    pub allowed_hosts: Vec<String>,
+    /// Credential used while this one is unhealthy
+    #[serde(default)]
+    pub backup: Option<String>,
+    #[serde(default = "default_failure_threshold")]
+    pub failure_threshold: u32,
+    /// URL that receives a JSON POST when the credential is marked unhealthy
+    #[serde(default)]
+    pub alert_webhook: Option<String>,
+}
+
+fn default_failure_threshold() -> u32 {
+    5
+}
+
+/// Why a credential was marked unhealthy
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CredentialHealth {
+    pub marked_unhealthy_at: u64,
+    pub consecutive_failures: u32,
+    pub last_status: u16,
+}
+
+/// How a request authenticates upstream besides credential headers
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum UpstreamAuth {
+    /// Appends the `secret` of credential `key_ref` as query parameter `param_name`
+    QueryKey { param_name: String, key_ref: String },
+}
+
+/// A resolved query-string key, added to the URL only when sending
+///
+/// Its `Debug` output hides the value, so it can't leak through request dumps.
+#[derive(Clone)]
+pub struct QueryKey {
+    pub param: String,
+    pub value: String,
+}
+
+impl std::fmt::Debug for QueryKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("QueryKey").field("param", &self.param).field("value", &"***").finish()
+    }
+}
+
+/// Masks the value of query parameter `param` in `url`, for URLs that are reported or logged
+pub fn redact_query_param(url: &str, param: &str) -> String {
+    let mut parsed = match Url::parse(url) {
+        Ok(parsed) => parsed,
+        Err(_) => return url.to_string(),
+    };
+    if !parsed.query_pairs().any(|(name, _)| name == param) {
+        return url.to_string();
+    }
+    let pairs: Vec<(String, String)> = parsed
+        .query_pairs()
+        .map(|(name, value)| {
+            let value = if name == param { "***".to_string() } else { value.into_owned() };
+            (name.into_owned(), value)
+        })
+        .collect();
+    parsed.query_pairs_mut().clear().extend_pairs(pairs);
+    parsed.to_string()
+}
+
+/// Which credential a request actually used, reported in response metadata
+#[derive(Debug, Clone, Serialize)]
+pub struct CredentialUsage {
+    pub requested: String,
+    pub used: String,
+    /// True when the requested credential is unhealthy and its backup was used
+    pub fallback: bool,
+}
+
+#[derive(Serialize)]
+struct UnhealthyAlert<'a> {
+    event: &'static str,
+    credential: &'a str,
+    backup: Option<&'a str>,
+    #[serde(flatten)]
+    health: &'a CredentialHealth,
+}
+
+impl Credential {
+    /// Same credential with header values and the secret masked, for admin listings
+    pub fn redacted(&self) -> Credential {
+        Credential {
+            headers: self.headers.keys().map(|k| (k.clone(), "***".to_string())).collect(),
+            secret: self.secret.as_ref().map(|_| "***".to_string()),
+            ..self.clone()
+        }
+    }
+
+    /// Refuses a tenant that wasn't given the credential, or a target it isn't bound to
+    pub fn check_access(&self, tenant_id: &str, url: &str) -> std::result::Result<(), ProxyError> {
+        if tenant_id != ROOT_TENANT_ID && !self.allowed_tenants.iter().any(|allowed| allowed == tenant_id) {
+            return Err(ProxyError::PolicyViolation(format!(
+                "Tenant {} may not use credential {}",
+                tenant_id, self.name
+            )));
+        }
+        let url = Url::parse(url).map_err(|_| ProxyError::InvalidRequest(format!("Invalid URL: {}", url)))?;
+        let allowed = self.allowed_hosts.is_empty()
+            || self.allowed_hosts.iter().any(|rule| HostRule::parse(rule).is_ok_and(|rule| rule.matches(&url)));
+        if !allowed {
+            return Err(ProxyError::PolicyViolation(format!(
+                "Credential {} may not be sent to {}",
+                self.name,
+                url.host_str().unwrap_or_default()
+            )));
+        }
+        Ok(())
+    }
+
+    /// Like `check_access`, but the secret also needs `allowed_hosts`
+    ///
+    /// A key in the query string ends up in the access logs of whatever it's
+    /// sent to, so it's never sent to a host the caller picks freely.
+    pub fn check_query_key_access(&self, tenant_id: &str, url: &str) -> std::result::Result<(), ProxyError> {
+        if self.allowed_hosts.is_empty() {
+            return Err(ProxyError::PolicyViolation(format!(
+                "Credential {} has no allowed_hosts, so its secret isn't sent as a query key",
+                self.name
+            )));
+        }
+        self.check_access(tenant_id, url)
+    }
+}
+
+/// Credential names are used in KV keys and URLs
+pub fn is_valid_credential_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 64
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+}
+
+/// Credentials and their health in the CONFIG KV namespace
+pub struct CredentialStore {
+    kv: kv::KvStore,
+}
+
+impl CredentialStore {
+    pub fn new(env: &Env) -> Result<CredentialStore> {
+        Ok(CredentialStore { kv: env.kv("CONFIG")? })
+    }
+
+    pub async fn get(&self, name: &str) -> Result<Option<Credential>> {
+        Ok(self
+            .kv
+            .get(&format!("{}{}", CREDENTIAL_PREFIX, name))
+            .json::<Credential>()
+            .await?)
+    }
+
+    async fn get_cached(&self, name: &str) -> Result<Option<Credential>> {
+        Ok(self
+            .kv
+            .get(&format!("{}{}", CREDENTIAL_PREFIX, name))
+            .cache_ttl(60)
+            .json::<Credential>()
+            .await?)
+    }
+
+    pub async fn list(&self) -> Result<Vec<Credential>> {
+        let mut credentials = Vec::new();
+        let mut cursor: Option<String> = None;
+        loop {
+            let mut list = self.kv.list().prefix(CREDENTIAL_PREFIX.to_string());
+            if let Some(cursor) = cursor.take() {
+                list = list.cursor(cursor);
+            }
+            let page = list.execute().await?;
+            for key in page.keys {
+                if let Some(name) = key.name.strip_prefix(CREDENTIAL_PREFIX) {
+                    if let Some(credential) = self.get(name).await? {
+                        credentials.push(credential);
+                    }
+                }
+            }
+            if page.list_complete {
+                break;
+            }
+            cursor = page.cursor;
+        }
+        Ok(credentials)
+    }
+
+    pub async fn save(&self, credential: &Credential) -> Result<()> {
+        self.kv
+            .put(&format!("{}{}", CREDENTIAL_PREFIX, credential.name), serde_json::to_string(credential)?)?
+            .execute()
+            .await?;
+        Ok(())
+    }
+
+    pub async fn delete(&self, name: &str) -> Result<()> {
+        self.kv.delete(&format!("{}{}", CREDENTIAL_PREFIX, name)).await?;
+        self.mark_healthy(name).await
+    }
+
+    /// Health record of an unhealthy credential; `None` means healthy
+    pub async fn health(&self, name: &str) -> Result<Option<CredentialHealth>> {
+        Ok(self
+            .kv
+            .get(&format!("{}{}", HEALTH_PREFIX, name))
+            .json::<CredentialHealth>()
+            .await?)
+    }
+
+    pub async fn mark_unhealthy(&self, name: &str, health: &CredentialHealth) -> Result<()> {
+        self.kv
+            .put(&format!("{}{}", HEALTH_PREFIX, name), serde_json::to_string(health)?)?
+            .execute()
+            .await?;
+        Ok(())
+    }
+
+    pub async fn mark_healthy(&self, name: &str) -> Result<()> {
+        self.kv.delete(&format!("{}{}", HEALTH_PREFIX, name)).await?;
+        Ok(())
+    }
+}
+
+/// Looks up a credential by name, switching to its backup while it's unhealthy
+///
+/// `tenant_id` must be allowed to use the credential and `url` must match its
+/// hosts; a backup that doesn't allow both isn't switched to.
+pub async fn resolve(
+    env: &Env,
+    name: &str,
+    tenant_id: &str,
+    url: &str,
+) -> std::result::Result<(Credential, CredentialUsage), ProxyError> {
+    let store = CredentialStore::new(env)?;
+    let credential = store
+        .get_cached(name)
+        .await?
+        .ok_or_else(|| ProxyError::InvalidRequest(format!("Unknown credential: {}", name)))?;
+    credential.check_access(tenant_id, url)?;
+
+    if store.health(name).await?.is_some() {
+        if let Some(backup_name) = &credential.backup {
+            if store.health(backup_name).await?.is_none() {
+                let backup = store.get_cached(backup_name).await?;
+                if let Some(backup) = backup.filter(|backup| backup.check_access(tenant_id, url).is_ok()) {
+                    log_info!("Credential {} is unhealthy, using backup {}", name, backup_name);
+                    let usage = CredentialUsage {
+                        requested: name.to_string(),
+                        used: backup.name.clone(),
+                        fallback: true,
+                    };
+                    return Ok((backup, usage));
+                }
+            }
+        }
+        log_info!("Credential {} is unhealthy and has no usable backup", name);
+    }
+
+    let usage = CredentialUsage {
+        requested: name.to_string(),
+        used: credential.name.clone(),
+        fallback: false,
+    };
+    Ok((credential, usage))
+}
+
+/// Tracks consecutive auth failures for the credential a request used
+///
+/// Counting happens per DO, so the threshold applies per shard. Reaching it
+/// marks the credential unhealthy for every shard and fires the alert once.
+/// Bookkeeping failures are logged, never surfaced to the caller.
+pub async fn record_outcome(env: &Env, state: &State, credential: &Credential, response: &ApiResponse) {
+    if let Err(e) = track_failures(env, state, credential, response.status()).await {
+        log_error!("Failed to record outcome for credential {}: {}", credential.name, e);
+    }
+}
+
+async fn track_failures(env: &Env, state: &State, credential: &Credential, status: u16) -> Result<()> {
+    let storage = state.storage();
+    let key = format!("{}/{}", FAILURES_NAMESPACE, credential.name);
+
+    if status != 401 && status != 403 {
+        if storage.get::<u32>(&key).await?.is_some() {
+            storage.delete(&key).await?;
+        }
+        return Ok(());
+    }
+
+    let failures = storage.get::<u32>(&key).await?.unwrap_or(0) + 1;
+    storage.put(&key, failures).await?;
+    if failures != credential.failure_threshold {
+        return Ok(());
+    }
+
+    let health = CredentialHealth {
+        marked_unhealthy_at: Date::now().as_millis(),
+        consecutive_failures: failures,
+        last_status: status,
+    };
+    CredentialStore::new(env)?.mark_unhealthy(&credential.name, &health).await?;
+    log_error!(
+        "Credential {} marked unhealthy after {} consecutive {} responses",
+        credential.name,
+        failures,
+        status
+    );
+
+    if let Some(webhook) = &credential.alert_webhook {
+        send_alert(webhook, credential, &health).await;
+    }
+    Ok(())
+}
+
+async fn send_alert(webhook: &str, credential: &Credential, health: &CredentialHealth) {
+    let alert = UnhealthyAlert {
+        event: "credential_unhealthy",
+        credential: &credential.name,
+        backup: credential.backup.as_deref(),
+        health,
+    };
+    match upstream::client().post(webhook).json(&alert).send().await {
+        Ok(response) if response.status().is_success() => {}
+        Ok(response) => log_error!("Credential alert webhook returned {}", response.status()),
+        Err(e) => log_error!("Credential alert webhook failed: {}", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_query_keys_stay_out_of_reported_urls() {
+        let auth: UpstreamAuth = serde_json::from_str(
+            r#"{"type": "query_key", "param_name": "key", "key_ref": "maps-prod"}"#,
+        )
+        .unwrap();
+        let UpstreamAuth::QueryKey { param_name, key_ref } = auth;
+        assert_eq!((param_name.as_str(), key_ref.as_str()), ("key", "maps-prod"));
+
+        assert_eq!(
+            redact_query_param("https://maps.example.com/geocode?address=Main+St&key=s3cr3t", "key"),
+            "https://maps.example.com/geocode?address=Main+St&key=***"
+        );
+        let untouched = "https://maps.example.com/geocode?address=Main%20St";
+        assert_eq!(redact_query_param(untouched, "key"), untouched);
+
+        let key = QueryKey { param: "key".to_string(), value: "s3cr3t".to_string() };
+        assert!(!format!("{:?}", key).contains("s3cr3t"));
+    }
+
+    #[test]
+    fn test_query_keys_need_a_host_binding() {
+        let mut credential: Credential =
+            serde_json::from_str(r#"{"name": "maps-prod", "secret": "s3cr3t", "allowed_tenants": ["billing"]}"#)
+                .unwrap();
+        let target = "https://maps.example.com/geocode/json";
+        assert_eq!(credential.check_query_key_access("billing", target).unwrap_err().code(), "POLICY_VIOLATION");
+        assert_eq!(credential.check_query_key_access("root", target).unwrap_err().code(), "POLICY_VIOLATION");
+
+        credential.allowed_hosts = vec!["https://maps.example.com".to_string()];
+        assert!(credential.check_query_key_access("billing", target).is_ok());
+        assert!(credential.check_query_key_access("catalog", target).is_err());
+        assert!(credential.check_query_key_access("billing", "https://echo.attacker.example/?q=1").is_err());
+    }
+
+    #[test]
+    fn test_credentials_are_bound_to_tenants_and_hosts() {
+        let mut credential: Credential = serde_json::from_str(
+            r#"{"name": "didx-primary", "headers": {"Authorization": "Basic dXNlcjpwYXNz"}}"#,
+        )
+        .unwrap();
+        let target = "https://api.didx.example/v1/numbers";
+        // Unbound credentials are for the shared token only
+        assert!(credential.check_access("root", target).is_ok());
+        assert_eq!(credential.check_access("billing", target).unwrap_err().code(), "POLICY_VIOLATION");
+
+        // Another tenant's credential can't be borrowed
+        credential.allowed_tenants = vec!["billing".to_string()];
+        assert!(credential.check_access("billing", target).is_ok());
+        assert_eq!(credential.check_access("catalog", target).unwrap_err().code(), "POLICY_VIOLATION");
+
+        // Nor sent to a host the caller controls
+        credential.allowed_hosts = vec!["*.didx.example".to_string()];
+        assert!(credential.check_access("billing", target).is_ok());
+        let foreign = credential.check_access("billing", "https://collector.attacker.example/").unwrap_err();
+        assert_eq!(foreign.code(), "POLICY_VIOLATION");
+        assert!(credential.check_access("root", "https://collector.attacker.example/").is_err());
+    }
+}
@@ -0,0 +1,66 @@
+use std::time::Duration;
+use worker::*;
+
+use crate::error::ProxyError;
+
+/// Header carrying the caller's absolute deadline as epoch millis
+pub const DEADLINE_HEADER: &str = "X-Deadline";
+
+/// The point after which the caller no longer waits for a result
+///
+/// Checked at the edge, on arrival in the DO (after queueing), before each
+/// upstream call, and applied as the upstream timeout, so work the caller
+/// has abandoned is dropped instead of completed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Deadline(pub u64);
+
+impl Deadline {
+    /// Reads `X-Deadline`; a missing header means no deadline
+    pub fn from_headers(headers: &Headers) -> std::result::Result<Option<Deadline>, ProxyError> {
+        match headers.get(DEADLINE_HEADER)? {
+            Some(value) => Deadline::parse(&value).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    pub fn parse(value: &str) -> std::result::Result<Deadline, ProxyError> {
+        value
+            .trim()
+            .parse::<u64>()
+            .map(Deadline)
+            .map_err(|_| ProxyError::InvalidRequest(format!("{} must be epoch milliseconds: {}", DEADLINE_HEADER, value)))
+    }
+
+    /// Budget left at `now` (epoch millis), or `None` once the deadline has passed
+    pub fn remaining_at(&self, now: u64) -> Option<Duration> {
+        (now < self.0).then(|| Duration::from_millis(self.0 - now))
+    }
+
+    /// Budget left, or `DeadlineExceeded` once the deadline has passed
+    pub fn check(&self) -> std::result::Result<Duration, ProxyError> {
+        self.remaining_at(Date::now().as_millis())
+            .ok_or(ProxyError::DeadlineExceeded { deadline: self.0 })
+    }
+
+    pub fn is_exceeded(&self) -> bool {
+        self.check().is_err()
+    }
+}
+
+/// Remaining budget of an optional deadline; `Ok(None)` when there is no deadline
+pub fn remaining(deadline: Option<Deadline>) -> std::result::Result<Option<Duration>, ProxyError> {
+    deadline.map(|deadline| deadline.check()).transpose()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_deadline_parse_and_remaining() {
+        let deadline = Deadline::parse(" 1767229200000 ").unwrap();
+        assert_eq!(deadline.remaining_at(1767229199000), Some(Duration::from_secs(1)));
+        assert_eq!(deadline.remaining_at(1767229200000), None);
+        assert!(Deadline::parse("in 5s").is_err());
+    }
+}
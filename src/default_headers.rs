@@ -0,0 +1,132 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use worker::*;
+
+use crate::error::ProxyError;
+use crate::routing::REGION_CODES;
+use crate::log_error;
+
+/// KV key holding the default header rules
+pub const DEFAULT_HEADERS_KEY: &str = "default-headers";
+
+/// Outbound headers added to every upstream request a rule matches
+///
+/// A rule without `region` or `host` applies everywhere. Caller headers always
+/// win; among rules, the more specific one wins (host and region over host
+/// over region over neither), then the one listed first.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HeaderRule {
+    /// Processor region code, e.g. "eeur"
+    #[serde(default)]
+    pub region: Option<String>,
+    /// Target host, e.g. "api.carrier.example"
+    #[serde(default)]
+    pub host: Option<String>,
+    pub headers: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct DefaultHeaders(pub Vec<HeaderRule>);
+
+impl HeaderRule {
+    fn matches(&self, region_code: &str, host: &str) -> bool {
+        self.region.as_deref().is_none_or(|region| region.eq_ignore_ascii_case(region_code))
+            && self.host.as_deref().is_none_or(|rule_host| rule_host.eq_ignore_ascii_case(host))
+    }
+
+    fn specificity(&self) -> u8 {
+        u8::from(self.region.is_some()) + 2 * u8::from(self.host.is_some())
+    }
+}
+
+impl DefaultHeaders {
+    /// Loads rules from the CONFIG KV namespace; no config adds no headers
+    pub async fn load(env: &Env) -> DefaultHeaders {
+        let kv = match env.kv("CONFIG") {
+            Ok(kv) => kv,
+            Err(_) => return DefaultHeaders::default(),
+        };
+        match kv.get(DEFAULT_HEADERS_KEY).cache_ttl(60).json::<DefaultHeaders>().await {
+            Ok(rules) => rules.unwrap_or_default(),
+            Err(e) => {
+                log_error!("Failed to load default headers, adding none: {}", e);
+                DefaultHeaders::default()
+            }
+        }
+    }
+
+    /// Reads rules bypassing the edge cache, for the admin API
+    pub async fn load_fresh(env: &Env) -> Result<DefaultHeaders> {
+        Ok(env.kv("CONFIG")?.get(DEFAULT_HEADERS_KEY).json::<DefaultHeaders>().await?.unwrap_or_default())
+    }
+
+    pub async fn save(&self, env: &Env) -> Result<()> {
+        env.kv("CONFIG")?
+            .put(DEFAULT_HEADERS_KEY, serde_json::to_string(self)?)?
+            .execute()
+            .await?;
+        Ok(())
+    }
+
+    pub fn validate(&self) -> std::result::Result<(), ProxyError> {
+        for rule in &self.0 {
+            if let Some(region) = &rule.region {
+                if !REGION_CODES.contains(&region.to_lowercase().as_str()) {
+                    return Err(ProxyError::InvalidRequest(format!("Unknown region: {}", region)));
+                }
+            }
+            for (name, value) in &rule.headers {
+                let valid_name = !name.is_empty()
+                    && name.bytes().all(|b| b.is_ascii_alphanumeric() || b"-_!#$%&'*+.^`|~".contains(&b));
+                if !valid_name || value.contains(['\r', '\n']) {
+                    return Err(ProxyError::InvalidRequest(format!("Invalid header: {}", name)));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds the matching defaults to `headers` without replacing any header already set
+    pub fn apply(&self, region_code: &str, host: &str, headers: &mut HashMap<String, String>) {
+        let mut rules: Vec<&HeaderRule> = self.0.iter().filter(|rule| rule.matches(region_code, host)).collect();
+        rules.sort_by_key(|rule| std::cmp::Reverse(rule.specificity()));
+        for (name, value) in rules.into_iter().flat_map(|rule| &rule.headers) {
+            if !headers.keys().any(|existing| existing.eq_ignore_ascii_case(name)) {
+                headers.insert(name.clone(), value.clone());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_defaults_merge_beneath_caller_headers() {
+        let defaults: DefaultHeaders = serde_json::from_str(
+            r#"[
+                {"headers": {"User-Agent": "ApiProxy/2.0", "X-Env": "prod"}},
+                {"region": "eeur", "headers": {"X-Partner-Id": "eu-1", "X-Env": "eu"}},
+                {"region": "eeur", "host": "api.carrier.example", "headers": {"X-Partner-Id": "carrier-eu"}}
+            ]"#,
+        )
+        .unwrap();
+        assert!(defaults.validate().is_ok());
+
+        let mut headers = HashMap::from([("x-env".to_string(), "caller".to_string())]);
+        defaults.apply("EEUR", "api.carrier.example", &mut headers);
+        assert_eq!(headers["x-env"], "caller");
+        assert_eq!(headers["X-Partner-Id"], "carrier-eu");
+        assert_eq!(headers["User-Agent"], "ApiProxy/2.0");
+
+        let mut headers = HashMap::new();
+        defaults.apply("WNAM", "api.carrier.example", &mut headers);
+        assert_eq!(headers.len(), 2);
+        assert_eq!(headers["X-Env"], "prod");
+
+        let invalid: DefaultHeaders = serde_json::from_str(r#"[{"headers": {"Bad Header": "x"}}]"#).unwrap();
+        assert!(invalid.validate().is_err());
+    }
+}
@@ -0,0 +1,355 @@
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use std::cell::Cell;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use worker::Url;
+
+use crate::cost::{self, CostEstimate};
+use crate::error::ProxyError;
+use crate::handlers::upstream;
+use crate::sandbox::{Sandbox, SandboxRoute};
+
+/// DNS-over-HTTPS JSON endpoint; Workers have no resolver API of their own
+const DOH_URL: &str = "https://cloudflare-dns.com/dns-query";
+
+/// Cloud metadata services and names that only resolve inside a network, refused without a lookup
+const BLOCKED_HOSTS: &[&str] = &["localhost", "metadata", "metadata.google.internal", "metadata.goog", "instance-data"];
+
+/// Suffixes of names that only resolve inside a network
+const BLOCKED_SUFFIXES: &[&str] = &[".localhost", ".internal", ".local", ".localdomain"];
+
+/// Record types in DoH answers
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+
+#[derive(Deserialize)]
+struct DohResponse {
+    #[serde(rename = "Status")]
+    status: u32,
+    #[serde(rename = "Answer", default)]
+    answer: Vec<DohAnswer>,
+}
+
+#[derive(Deserialize)]
+struct DohAnswer {
+    #[serde(rename = "type")]
+    record_type: u16,
+    data: String,
+}
+
+/// Networks and host names upstream calls may not reach on top of the built-in ones
+///
+/// Parsed from the comma-separated `SSRF_BLOCKLIST` variable. Entries are
+/// networks (`203.0.113.0/24`, `2001:db8::/32`), single addresses, host
+/// names, or `*.` suffixes (`*.corp.example`).
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(transparent)]
+pub struct Blocklist {
+    entries: Vec<String>,
+    #[serde(skip)]
+    networks: Vec<(IpAddr, u8)>,
+    #[serde(skip)]
+    hosts: Vec<String>,
+}
+
+impl Blocklist {
+    /// `None` when any entry is invalid
+    pub fn parse(value: &str) -> Option<Blocklist> {
+        let mut blocklist = Blocklist::default();
+        for entry in value.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+            let entry = entry.to_lowercase();
+            if let Some(network) = parse_network(&entry) {
+                blocklist.networks.push(network);
+            } else if is_host_pattern(&entry) {
+                blocklist.hosts.push(entry.clone());
+            } else {
+                return None;
+            }
+            blocklist.entries.push(entry);
+        }
+        Some(blocklist)
+    }
+
+    pub fn entries(&self) -> &[String] {
+        &self.entries
+    }
+
+    fn blocks_ip(&self, ip: IpAddr) -> bool {
+        self.networks.iter().any(|network| in_network(ip, *network))
+    }
+
+    fn blocks_host(&self, host: &str) -> bool {
+        self.hosts.iter().any(|pattern| match pattern.strip_prefix("*.") {
+            Some(suffix) => host.strip_suffix(suffix).is_some_and(|rest| rest.ends_with('.')),
+            None => host == pattern,
+        })
+    }
+}
+
+fn parse_network(entry: &str) -> Option<(IpAddr, u8)> {
+    let (address, prefix) = match entry.split_once('/') {
+        Some((address, prefix)) => (address, Some(prefix.parse::<u8>().ok()?)),
+        None => (entry, None),
+    };
+    let ip: IpAddr = address.parse().ok()?;
+    let max = if ip.is_ipv4() { 32 } else { 128 };
+    let prefix = prefix.unwrap_or(max);
+    (prefix <= max).then_some((ip, prefix))
+}
+
+fn is_host_pattern(entry: &str) -> bool {
+    let name = entry.strip_prefix("*.").unwrap_or(entry);
+    !name.is_empty()
+        && name.split('.').all(|label| {
+            !label.is_empty() && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+}
+
+fn in_network(ip: IpAddr, (network, prefix): (IpAddr, u8)) -> bool {
+    match (ip, network) {
+        (IpAddr::V4(ip), IpAddr::V4(network)) => {
+            let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+            u32::from(ip) & mask == u32::from(network) & mask
+        }
+        (IpAddr::V6(ip), IpAddr::V6(network)) => {
+            let mask = u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0);
+            u128::from(ip) & mask == u128::from(network) & mask
+        }
+        // An IPv4-mapped address reaches the IPv4 host
+        (IpAddr::V6(ip), IpAddr::V4(_)) => ip
+            .to_ipv4_mapped()
+            .is_some_and(|ip| in_network(IpAddr::V4(ip), (network, prefix))),
+        (IpAddr::V4(_), IpAddr::V6(_)) => false,
+    }
+}
+
+/// The checks every upstream target gets before it's called
+#[derive(Debug, Clone, Default)]
+pub struct TargetGuard {
+    /// Resolve host names and check every address (`DNS_REBINDING_CHECK`)
+    pub resolve: bool,
+    pub blocklist: Blocklist,
+    /// Set for the sandbox tenant, whose calls may only reach its mocks and echo endpoints
+    pub sandbox: Option<Sandbox>,
+}
+
+impl TargetGuard {
+    /// Refuses a target the proxy must not be used to reach
+    ///
+    /// Only http and https are allowed, and internal or blocklisted IP
+    /// literals, metadata service names and blocklisted names are refused
+    /// outright. With `resolve`, names are also resolved (A and AAAA) at call
+    /// time and every address must be public and not blocklisted, so a
+    /// hostname that passed the allowlist can't be pointed at a private
+    /// network later. The DNS lookups are added to `cost`.
+    pub async fn check(&self, url: &str, cost: &Cell<CostEstimate>) -> Result<(), ProxyError> {
+        // A sandbox mock is answered without a network call, so there's nothing to resolve
+        if let Some(sandbox) = &self.sandbox {
+            if sandbox.route(url)? == SandboxRoute::Mock {
+                return Ok(());
+            }
+        }
+        let host = self.check_url(url)?;
+        if !self.resolve || literal_ip(&host).is_some() {
+            return Ok(());
+        }
+        cost::add(
+            cost,
+            CostEstimate {
+                subrequests: 2,
+                ..CostEstimate::default()
+            },
+        );
+        let addresses = resolve(&host)
+            .await
+            .map_err(|e| ProxyError::Upstream(format!("Could not resolve {}: {:#}", host, e)))?;
+        addresses.into_iter().try_for_each(|ip| self.check_ip(&host, ip))
+    }
+
+    /// What can be told from the URL alone; returns the host
+    fn check_url(&self, url: &str) -> Result<String, ProxyError> {
+        let parsed = Url::parse(url).map_err(|_| ProxyError::InvalidRequest(format!("Invalid target URL: {}", url)))?;
+        if !matches!(parsed.scheme(), "http" | "https") {
+            return Err(ProxyError::PolicyViolation(format!(
+                "Scheme {} is not allowed, only http and https",
+                parsed.scheme()
+            )));
+        }
+        let host = parsed
+            .host_str()
+            .map(str::to_lowercase)
+            .ok_or_else(|| ProxyError::InvalidRequest(format!("Target URL has no host: {}", url)))?;
+        match literal_ip(&host) {
+            Some(ip) => self.check_ip(&host, ip)?,
+            None if is_blocked_name(&host) || self.blocklist.blocks_host(&host) => {
+                return Err(ProxyError::PolicyViolation(format!("Host {} is blocked", host)));
+            }
+            None => {}
+        }
+        Ok(host)
+    }
+
+    fn check_ip(&self, host: &str, ip: IpAddr) -> Result<(), ProxyError> {
+        if is_internal(ip) {
+            return Err(ProxyError::PolicyViolation(format!(
+                "Host {} resolves to internal address {}",
+                host, ip
+            )));
+        }
+        if self.blocklist.blocks_ip(ip) {
+            return Err(ProxyError::PolicyViolation(format!(
+                "Host {} resolves to blocked address {}",
+                host, ip
+            )));
+        }
+        Ok(())
+    }
+}
+
+fn literal_ip(host: &str) -> Option<IpAddr> {
+    host.trim_start_matches('[').trim_end_matches(']').parse().ok()
+}
+
+fn is_blocked_name(host: &str) -> bool {
+    let host = host.trim_end_matches('.');
+    BLOCKED_HOSTS.contains(&host) || BLOCKED_SUFFIXES.iter().any(|suffix| host.ends_with(suffix))
+}
+
+/// Final URL of a response when the upstream redirected to another origin
+///
+/// Redirects are followed by the runtime, so this is the only hop visible to the proxy.
+pub fn redirect_target(requested: &str, final_url: &Url) -> Option<String> {
+    let requested = Url::parse(requested).ok()?;
+    (requested.origin() != final_url.origin()).then(|| final_url.to_string())
+}
+
+async fn resolve(host: &str) -> anyhow::Result<Vec<IpAddr>> {
+    let client = upstream::client();
+    let mut addresses = Vec::new();
+    for record_type in ["A", "AAAA"] {
+        let response: DohResponse = client
+            .get(DOH_URL)
+            .query(&[("name", host), ("type", record_type)])
+            .header("accept", "application/dns-json")
+            .send()
+            .await
+            .context("DNS query failed")?
+            .json()
+            .await
+            .context("Invalid DNS response")?;
+        // NXDOMAIN and the like leave nothing to check; the upstream call fails on its own
+        if response.status != 0 {
+            continue;
+        }
+        addresses.extend(
+            response
+                .answer
+                .iter()
+                .filter(|answer| answer.record_type == TYPE_A || answer.record_type == TYPE_AAAA)
+                .filter_map(|answer| answer.data.parse::<IpAddr>().ok()),
+        );
+    }
+    Ok(addresses)
+}
+
+/// True for loopback, private, link-local, shared (CGNAT) and other non-public ranges
+pub fn is_internal(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_internal_v4(ip),
+        IpAddr::V6(ip) => is_internal_v6(ip),
+    }
+}
+
+fn is_internal_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+    ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_multicast()
+        || a == 0
+        // Shared address space (100.64.0.0/10), IETF protocol assignments, benchmarking, reserved
+        || (a == 100 && (64..128).contains(&b))
+        || (a == 192 && b == 0 && ip.octets()[2] == 0)
+        || (a == 198 && (b == 18 || b == 19))
+        || a >= 240
+}
+
+fn is_internal_v6(ip: Ipv6Addr) -> bool {
+    // IPv4-mapped (::ffff:a.b.c.d) and NAT64 (64:ff9b::/96) addresses reach the embedded IPv4 host
+    if let Some(v4) = ip.to_ipv4_mapped() {
+        return is_internal_v4(v4);
+    }
+    let segments = ip.segments();
+    if segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0] {
+        let [.., a, b, c, d] = ip.octets();
+        return is_internal_v4(Ipv4Addr::new(a, b, c, d));
+    }
+    ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_multicast()
+        // Unique local (fc00::/7) and link-local (fe80::/10)
+        || (segments[0] & 0xfe00) == 0xfc00
+        || (segments[0] & 0xffc0) == 0xfe80
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_internal_addresses() {
+        for internal in [
+            "127.0.0.1", "10.1.2.3", "172.16.0.1", "192.168.1.1", "169.254.169.254", "100.64.0.1", "0.0.0.0",
+            "::1", "fd00::1", "fe80::1", "::ffff:10.0.0.1", "64:ff9b::a9fe:a9fe",
+        ] {
+            assert!(is_internal(internal.parse().unwrap()), "{}", internal);
+        }
+        for public in ["8.8.8.8", "100.128.0.1", "192.0.2.1", "2606:4700::1111", "::ffff:1.1.1.1"] {
+            assert!(!is_internal(public.parse().unwrap()), "{}", public);
+        }
+    }
+
+    #[test]
+    fn test_target_checks_without_lookups() {
+        let guard = TargetGuard {
+            resolve: false,
+            blocklist: Blocklist::parse("203.0.113.0/24, 2001:db8::/32, *.corp.example, billing.example").unwrap(),
+            sandbox: None,
+        };
+        for blocked in [
+            "ftp://files.example.com/a",
+            "file:///etc/passwd",
+            "http://127.1/",
+            "http://[::1]:8080/",
+            "http://169.254.169.254/latest/meta-data/",
+            "http://metadata.google.internal/computeMetadata/v1/",
+            "http://printer.local/",
+            "https://203.0.113.9/",
+            "https://[::ffff:203.0.113.9]/",
+            "https://vault.corp.example/",
+            "https://billing.example/",
+        ] {
+            assert!(guard.check_url(blocked).is_err(), "{}", blocked);
+        }
+        for allowed in [
+            "https://api.example.com/v1",
+            "http://8.8.8.8/",
+            "https://corp.example/",
+            "https://ebilling.example/",
+        ] {
+            assert!(guard.check_url(allowed).is_ok(), "{}", allowed);
+        }
+        assert!(Blocklist::parse("10.0.0.0/33").is_none());
+        assert!(Blocklist::parse("not a host").is_none());
+    }
+
+    #[test]
+    fn test_redirect_target_only_for_other_origins() {
+        let same = Url::parse("https://api.example.com/v2?x=1").unwrap();
+        assert_eq!(redirect_target("https://api.example.com/v1", &same), None);
+        let other = Url::parse("http://api.example.com/v1").unwrap();
+        assert_eq!(redirect_target("https://api.example.com/v1", &other), Some(other.to_string()));
+    }
+}
@@ -0,0 +1,186 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use worker::*;
+
+use crate::error::ProxyError;
+use crate::routing::REGION_CODES;
+use crate::log_error;
+
+/// KV key holding the regions being drained
+pub const REGION_DRAINS_KEY: &str = "region-drains";
+
+/// Response header naming the region to send requests to instead of a drained one
+pub const REDIRECT_REGION_HEADER: &str = "X-CF-Region-Redirect";
+
+/// A region being taken out of service
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RegionDrain {
+    /// Start as epoch millis
+    #[serde(default)]
+    pub started_at: u64,
+    /// Region callers are told to use instead
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub redirect_region: Option<String>,
+    /// Shown to callers, e.g. "weur decommissioned on 2026-11-01"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+/// Drained regions by code
+///
+/// A drained region accepts no new requests; its Durable Objects finish the
+/// work they already have. Requests aren't moved to the redirect region
+/// automatically, since they may be pinned to the drained one for data residency.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct RegionDrains(BTreeMap<String, RegionDrain>);
+
+impl RegionDrains {
+    /// Loads the drained regions from the CONFIG KV namespace
+    ///
+    /// Falls back to none if the namespace isn't bound or the value can't be
+    /// read, so a config problem never takes a region out of service.
+    pub async fn load(env: &Env) -> RegionDrains {
+        let kv = match env.kv("CONFIG") {
+            Ok(kv) => kv,
+            Err(_) => return RegionDrains::default(),
+        };
+
+        match kv.get(REGION_DRAINS_KEY).cache_ttl(60).json::<RegionDrains>().await {
+            Ok(drains) => drains.unwrap_or_default(),
+            Err(e) => {
+                log_error!("Failed to load region drains, draining none: {}", e);
+                RegionDrains::default()
+            }
+        }
+    }
+
+    /// Persists the drained regions to the CONFIG KV namespace
+    pub async fn save(&self, env: &Env) -> Result<()> {
+        let kv = env.kv("CONFIG")?;
+        kv.put(REGION_DRAINS_KEY, serde_json::to_string(self)?)?
+            .execute()
+            .await?;
+        Ok(())
+    }
+
+    pub fn get(&self, region_code: &str) -> Option<&RegionDrain> {
+        self.0.get(region_code)
+    }
+
+    pub fn regions(&self) -> &BTreeMap<String, RegionDrain> {
+        &self.0
+    }
+
+    /// Starts (or updates) draining a region
+    ///
+    /// The redirect region must be another compiled-in region that isn't draining itself.
+    pub fn start(&mut self, region_code: &str, drain: RegionDrain) -> std::result::Result<(), ProxyError> {
+        if !REGION_CODES.contains(&region_code) {
+            return Err(ProxyError::NotFound(format!("Unknown region: {}", region_code)));
+        }
+        if let Some(redirect) = &drain.redirect_region {
+            if redirect == region_code || !REGION_CODES.contains(&redirect.as_str()) {
+                return Err(ProxyError::InvalidRequest(format!("Invalid redirect_region: {}", redirect)));
+            }
+            if self.0.contains_key(redirect) {
+                return Err(ProxyError::InvalidRequest(format!("redirect_region {} is draining", redirect)));
+            }
+        }
+        // Refuse to take the last region out of service; remove the route instead
+        if REGION_CODES.iter().all(|code| *code == region_code || self.0.contains_key(*code)) {
+            return Err(ProxyError::InvalidRequest(format!(
+                "Draining {} would leave no region in service",
+                region_code
+            )));
+        }
+        self.0.insert(region_code.to_string(), drain);
+        Ok(())
+    }
+
+    /// Ends draining a region; returns whether it was draining
+    pub fn end(&mut self, region_code: &str) -> bool {
+        self.0.remove(region_code).is_some()
+    }
+
+    /// Refuses new requests to a drained region
+    pub fn check(&self, region_code: &str) -> std::result::Result<(), ProxyError> {
+        match self.0.get(region_code) {
+            Some(drain) => Err(ProxyError::RegionDraining {
+                region: region_code.to_string(),
+                redirect_region: drain.redirect_region.clone(),
+                reason: drain.reason.clone(),
+            }),
+            None => Ok(()),
+        }
+    }
+}
+
+/// A value a shard stored, as exported from it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredEntry {
+    pub key: String,
+    pub value: serde_json::Value,
+}
+
+/// Storage exported from every shard of a region, keyed by shard index
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RegionExport {
+    pub region: String,
+    pub exported_at: u64,
+    pub shards: BTreeMap<u32, Vec<StoredEntry>>,
+}
+
+/// Shard of an importing region that gets the entries of exported shard `index`
+///
+/// Shards keep their index when the pools are the same size, so entries land
+/// on the shard that traffic hashing to the old one now reaches.
+pub fn import_index(index: u32, pool_size: u32) -> u32 {
+    index % pool_size.max(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_drain_and_redirect() {
+        let (region, other) = match REGION_CODES {
+            [region, other, ..] => (*region, *other),
+            // A single-region build can never drain
+            [region] => {
+                let drain = RegionDrain { started_at: 1, redirect_region: None, reason: None };
+                assert!(RegionDrains::default().start(region, drain).is_err());
+                return;
+            }
+            [] => unreachable!(),
+        };
+        let drain = |redirect: &str| RegionDrain {
+            started_at: 1,
+            redirect_region: Some(redirect.to_string()),
+            reason: Some("decommissioned".to_string()),
+        };
+
+        let mut drains = RegionDrains::default();
+        assert!(drains.check(region).is_ok());
+        assert!(drains.start(region, drain(region)).is_err());
+        assert!(drains.start(region, drain("mars")).is_err());
+        assert!(drains.start("mars", drain(other)).is_err());
+        drains.start(region, drain(other)).unwrap();
+
+        match drains.check(region) {
+            Err(ProxyError::RegionDraining { redirect_region, .. }) => {
+                assert_eq!(redirect_region.as_deref(), Some(other))
+            }
+            result => panic!("expected RegionDraining, got {:?}", result),
+        }
+        assert!(drains.check(other).is_ok());
+        // The redirect target can't start draining toward a drained region
+        assert!(drains.start(other, drain(region)).is_err());
+
+        assert!(drains.end(region));
+        assert!(!drains.end(region));
+        assert_eq!(import_index(7, 10), 7);
+        assert_eq!(import_index(7, 4), 3);
+    }
+}
@@ -0,0 +1,182 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::error::ProxyError;
+
+/// Header carrying the end user a request is made for, e.g. `X-End-User: id=u-8f2c, country=DE`
+pub const END_USER_HEADER: &str = "X-End-User";
+
+/// Header carrying the tenant's `end_user_forwarding` (JSON) from the edge to the processor
+pub const FORWARDING_HEADER: &str = "X-End-User-Forwarding";
+
+/// Maximum length of an end-user id
+const MAX_ID_LENGTH: usize = 128;
+
+/// The end user a request is made for, as the calling application identifies them
+///
+/// The id is opaque: it is logged, archived and forwarded as given, never
+/// looked up. Only id-like characters are accepted, so e-mail addresses and
+/// names can't end up in the logs by mistake.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EndUser {
+    pub id: String,
+    /// ISO 3166-1 alpha-2 code, uppercase
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub country: Option<String>,
+}
+
+fn is_valid_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_ID_LENGTH
+        && id.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | ':'))
+}
+
+impl EndUser {
+    /// Parses `id=<id>, country=<code>`; `country` is optional
+    pub fn parse(header: &str) -> std::result::Result<EndUser, ProxyError> {
+        let invalid = |message: String| ProxyError::InvalidRequest(format!("Invalid {}: {}", END_USER_HEADER, message));
+        let mut id = None;
+        let mut country = None;
+        for pair in header.split(',').map(str::trim).filter(|pair| !pair.is_empty()) {
+            let (key, value) = pair
+                .split_once('=')
+                .ok_or_else(|| invalid(format!("expected key=value, got {}", pair)))?;
+            match key.trim() {
+                "id" => id = Some(value.trim().to_string()),
+                "country" => country = Some(value.trim().to_uppercase()),
+                other => return Err(invalid(format!("unknown key {}", other))),
+            }
+        }
+        let id = id.ok_or_else(|| invalid("id is required".to_string()))?;
+        if !is_valid_id(&id) {
+            return Err(invalid(format!(
+                "id must be 1-{} characters of [A-Za-z0-9-_.:]",
+                MAX_ID_LENGTH
+            )));
+        }
+        if let Some(country) = &country {
+            if country.len() != 2 || !country.chars().all(|c| c.is_ascii_uppercase()) {
+                return Err(invalid(format!("country must be a 2-letter code, got {}", country)));
+            }
+        }
+        Ok(EndUser { id, country })
+    }
+}
+
+impl fmt::Display for EndUser {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "id={}", self.id)?;
+        if let Some(country) = &self.country {
+            write!(f, ",country={}", country)?;
+        }
+        Ok(())
+    }
+}
+
+/// Upstream headers a tenant's end-user context is sent in
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EndUserForwarding {
+    /// Header for the id, e.g. "X-End-User-Id"
+    pub id_header: String,
+    /// Header for the country; the country isn't sent when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub country_header: Option<String>,
+    /// Target hosts that get the headers; empty sends them to every host
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub hosts: Vec<String>,
+}
+
+impl EndUserForwarding {
+    pub fn validate(&self) -> std::result::Result<(), ProxyError> {
+        for name in std::iter::once(&self.id_header).chain(&self.country_header) {
+            let valid_name = !name.is_empty()
+                && name.bytes().all(|b| b.is_ascii_alphanumeric() || b"-_!#$%&'*+.^`|~".contains(&b));
+            if !valid_name {
+                return Err(ProxyError::InvalidRequest(format!("Invalid end-user header: {}", name)));
+            }
+        }
+        if self.hosts.iter().any(|host| host.is_empty()) {
+            return Err(ProxyError::InvalidRequest("end_user_forwarding hosts can't be empty".to_string()));
+        }
+        Ok(())
+    }
+
+    /// Sets the end-user headers for a call to `host`, replacing any the caller sent
+    pub fn apply(&self, end_user: &EndUser, host: &str, headers: &mut HashMap<String, String>) {
+        if !self.hosts.is_empty() && !self.hosts.iter().any(|allowed| allowed.eq_ignore_ascii_case(host)) {
+            return;
+        }
+        let mut set = |name: &str, value: &str| {
+            headers.retain(|existing, _| !existing.eq_ignore_ascii_case(name));
+            headers.insert(name.to_string(), value.to_string());
+        };
+        set(&self.id_header, &end_user.id);
+        if let (Some(name), Some(country)) = (&self.country_header, &end_user.country) {
+            set(name, country);
+        }
+    }
+}
+
+/// An end user whose context the tenant forwards upstream, as the processor sees it
+#[derive(Debug, Clone)]
+pub struct ForwardedEndUser {
+    pub end_user: EndUser,
+    pub forwarding: EndUserForwarding,
+}
+
+impl ForwardedEndUser {
+    /// Reads what the edge forwarded; `None` when there's no end user or nothing to forward
+    pub fn from_headers(end_user: Option<&str>, forwarding: Option<&str>) -> Option<ForwardedEndUser> {
+        Some(ForwardedEndUser {
+            end_user: EndUser::parse(end_user?).ok()?,
+            forwarding: serde_json::from_str(forwarding?).ok()?,
+        })
+    }
+
+    pub fn apply(&self, host: &str, headers: &mut HashMap<String, String>) {
+        self.forwarding.apply(&self.end_user, host, headers);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_end_user_parsing_and_forwarding() {
+        let end_user = EndUser::parse(" id=u-8f2c , country=de ").unwrap();
+        assert_eq!(end_user.id, "u-8f2c");
+        assert_eq!(end_user.country.as_deref(), Some("DE"));
+        assert_eq!(EndUser::parse(&end_user.to_string()).unwrap(), end_user);
+        assert_eq!(EndUser::parse("id=42").unwrap().country, None);
+        for invalid in ["", "country=DE", "id=jane@example.com", "id=42,country=DEU", "id=42,name=Jane"] {
+            assert!(EndUser::parse(invalid).is_err(), "{}", invalid);
+        }
+
+        let forwarding: EndUserForwarding = serde_json::from_value(serde_json::json!({
+            "id_header": "X-End-User-Id",
+            "country_header": "X-End-User-Country",
+            "hosts": ["api.carrier.example"]
+        }))
+        .unwrap();
+        assert!(forwarding.validate().is_ok());
+        let forwarded = ForwardedEndUser::from_headers(
+            Some(&end_user.to_string()),
+            Some(&serde_json::to_string(&forwarding).unwrap()),
+        )
+        .unwrap();
+
+        // The configured value replaces one the caller sent under another spelling
+        let mut headers = HashMap::from([("x-end-user-id".to_string(), "spoofed".to_string())]);
+        forwarded.apply("API.carrier.example", &mut headers);
+        assert_eq!(headers.len(), 2);
+        assert_eq!(headers["X-End-User-Id"], "u-8f2c");
+        assert_eq!(headers["X-End-User-Country"], "DE");
+
+        let mut other_host = HashMap::new();
+        forwarded.apply("api.other.example", &mut other_host);
+        assert!(other_host.is_empty());
+        assert!(ForwardedEndUser::from_headers(None, Some("{}")).is_none());
+    }
+}
@@ -0,0 +1,497 @@
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::collections::HashSet;
+use worker::*;
+
+use crate::handlers::parse::JsonErrorDetails;
+use crate::handlers::{RegionAssertion, ResponseMetadata};
+
+/// Response header naming who caused an error response: `caller`, `upstream` or `proxy`
+pub const FAULT_HEADER: &str = "X-Error-Fault";
+
+/// Who caused an error, so the proxy's own SLOs can leave out failures it didn't cause
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Fault {
+    /// The request was invalid or not allowed
+    Caller,
+    /// The upstream failed, was slow, or is paused
+    Upstream,
+    /// The proxy or the platform under it failed
+    Proxy,
+}
+
+impl Fault {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Fault::Caller => "caller",
+            Fault::Upstream => "upstream",
+            Fault::Proxy => "proxy",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Fault> {
+        match value {
+            "caller" => Some(Fault::Caller),
+            "upstream" => Some(Fault::Upstream),
+            "proxy" => Some(Fault::Proxy),
+            _ => None,
+        }
+    }
+}
+
+/// Errors produced by the proxy itself (as opposed to upstream error statuses)
+///
+/// Each variant maps to a stable machine-readable `code` and an HTTP status,
+/// so callers can branch on the code instead of parsing the message.
+#[derive(Debug)]
+pub enum ProxyError {
+    /// The request body could not be parsed
+    InvalidRequest(String),
+    /// The request body isn't valid JSON for its request type; shares
+    /// `INVALID_REQUEST` and adds the error position and field
+    InvalidJson {
+        message: String,
+        details: JsonErrorDetails,
+    },
+    /// No route matches the request path
+    NotFound(String),
+    /// The caller's tenant policy doesn't allow this request
+    PolicyViolation(String),
+    /// The caller's tenant is over its requests per minute
+    RateLimited {
+        tenant: String,
+        requests_per_minute: u32,
+        retry_after_secs: u64,
+    },
+    /// The DO is running outside the region it was asked to assert
+    RegionAssertionFailed(RegionAssertion),
+    /// The upstream response doesn't match the expected response schema
+    ContractViolation(String),
+    /// The target host is in a scheduled maintenance window
+    UpstreamMaintenance {
+        host: String,
+        reason: Option<String>,
+        retry_after_secs: u64,
+    },
+    /// The target host's circuit breaker is open after repeated failures
+    CircuitOpen { host: String, retry_after_secs: u64 },
+    /// The selected region is being taken out of service
+    RegionDraining {
+        region: String,
+        redirect_region: Option<String>,
+        reason: Option<String>,
+    },
+    /// The caller's `X-Deadline` passed before the request could complete
+    DeadlineExceeded { deadline: u64 },
+    /// The caller's `X-Expires-At` passed while the request was still waiting to be sent
+    Expired { expires_at: u64 },
+    /// The request body is over `MAX_REQUEST_BYTES`
+    PayloadTooLarge { limit: u64 },
+    /// The upstream response body is over `MAX_RESPONSE_BYTES`
+    ResponseTooLarge { limit: u64 },
+    /// The upstream call could not be completed
+    Upstream(String),
+    /// A GraphQL upstream answered with a non-empty `errors` array
+    GraphQl { errors: Vec<Value>, data: Option<Value> },
+    /// A JSON-RPC upstream answered with an `error` object
+    JsonRpc {
+        code: i64,
+        message: String,
+        data: Option<Value>,
+    },
+    /// An XML-RPC upstream answered with a `fault`
+    XmlRpcFault { code: i64, message: String },
+    /// A proxy-side dependency (KV, DO storage, ...) failed
+    Internal(String),
+}
+
+#[derive(Serialize)]
+struct ErrorBody {
+    status: u16,
+    code: &'static str,
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    details: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    metadata: Option<ResponseMetadata>,
+}
+
+/// One entry of the machine-readable error catalog served at `GET /errors`
+#[derive(Serialize)]
+pub struct ErrorCatalogEntry {
+    pub code: &'static str,
+    pub status: u16,
+    pub description: &'static str,
+    /// Whether repeating the same request later can succeed
+    pub retryable: bool,
+    pub fault: Fault,
+}
+
+impl ProxyError {
+    /// One instance of every variant, used to build the catalog
+    ///
+    /// `description` and `retryable` match exhaustively, so a new variant
+    /// doesn't compile until it's documented; add it here as well.
+    fn examples() -> Vec<ProxyError> {
+        vec![
+            ProxyError::InvalidRequest(String::new()),
+            ProxyError::InvalidJson {
+                message: String::new(),
+                details: JsonErrorDetails {
+                    line: 0,
+                    column: 0,
+                    path: None,
+                    snippet: String::new(),
+                    docs: String::new(),
+                },
+            },
+            ProxyError::NotFound(String::new()),
+            ProxyError::PolicyViolation(String::new()),
+            ProxyError::RateLimited {
+                tenant: String::new(),
+                requests_per_minute: 0,
+                retry_after_secs: 0,
+            },
+            ProxyError::RegionAssertionFailed(RegionAssertion {
+                expected: String::new(),
+                actual_colo: String::new(),
+                actual_region: None,
+                passed: false,
+            }),
+            ProxyError::ContractViolation(String::new()),
+            ProxyError::UpstreamMaintenance {
+                host: String::new(),
+                reason: None,
+                retry_after_secs: 0,
+            },
+            ProxyError::CircuitOpen {
+                host: String::new(),
+                retry_after_secs: 0,
+            },
+            ProxyError::RegionDraining {
+                region: String::new(),
+                redirect_region: None,
+                reason: None,
+            },
+            ProxyError::DeadlineExceeded { deadline: 0 },
+            ProxyError::Expired { expires_at: 0 },
+            ProxyError::PayloadTooLarge { limit: 0 },
+            ProxyError::ResponseTooLarge { limit: 0 },
+            ProxyError::Upstream(String::new()),
+            ProxyError::GraphQl {
+                errors: Vec::new(),
+                data: None,
+            },
+            ProxyError::JsonRpc {
+                code: 0,
+                message: String::new(),
+                data: None,
+            },
+            ProxyError::XmlRpcFault {
+                code: 0,
+                message: String::new(),
+            },
+            ProxyError::Internal(String::new()),
+        ]
+    }
+
+    /// Every error code the proxy can return, each listed once
+    pub fn catalog() -> Vec<ErrorCatalogEntry> {
+        let mut seen = HashSet::new();
+        ProxyError::examples()
+            .iter()
+            .filter(|e| seen.insert(e.code()))
+            .map(|e| ErrorCatalogEntry {
+                code: e.code(),
+                status: e.status(),
+                description: e.description(),
+                retryable: e.retryable(),
+                fault: e.fault(),
+            })
+            .collect()
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            ProxyError::InvalidRequest(_) | ProxyError::InvalidJson { .. } => "INVALID_REQUEST",
+            ProxyError::NotFound(_) => "NOT_FOUND",
+            ProxyError::PolicyViolation(_) => "POLICY_VIOLATION",
+            ProxyError::RateLimited { .. } => "RATE_LIMITED",
+            ProxyError::RegionAssertionFailed(_) => "REGION_ASSERTION_FAILED",
+            ProxyError::ContractViolation(_) => "CONTRACT_VIOLATION",
+            ProxyError::UpstreamMaintenance { .. } => "UPSTREAM_MAINTENANCE",
+            ProxyError::CircuitOpen { .. } => "CIRCUIT_OPEN",
+            ProxyError::RegionDraining { .. } => "REGION_DRAINING",
+            ProxyError::DeadlineExceeded { .. } => "DEADLINE_EXCEEDED",
+            ProxyError::Expired { .. } => "EXPIRED",
+            ProxyError::PayloadTooLarge { .. } => "PAYLOAD_TOO_LARGE",
+            ProxyError::ResponseTooLarge { .. } => "RESPONSE_TOO_LARGE",
+            ProxyError::Upstream(_) => "UPSTREAM_ERROR",
+            ProxyError::GraphQl { .. } => "GRAPHQL_ERROR",
+            ProxyError::JsonRpc { .. } => "JSONRPC_ERROR",
+            ProxyError::XmlRpcFault { .. } => "XMLRPC_FAULT",
+            ProxyError::Internal(_) => "INTERNAL_ERROR",
+        }
+    }
+
+    pub fn status(&self) -> u16 {
+        match self {
+            ProxyError::InvalidRequest(_) | ProxyError::InvalidJson { .. } => 400,
+            ProxyError::NotFound(_) => 404,
+            ProxyError::PolicyViolation(_) => 403,
+            ProxyError::RateLimited { .. } => 429,
+            ProxyError::RegionAssertionFailed(_) => 421,
+            ProxyError::ContractViolation(_)
+            | ProxyError::ResponseTooLarge { .. }
+            | ProxyError::GraphQl { .. }
+            | ProxyError::JsonRpc { .. }
+            | ProxyError::XmlRpcFault { .. } => 502,
+            ProxyError::UpstreamMaintenance { .. }
+            | ProxyError::CircuitOpen { .. }
+            | ProxyError::RegionDraining { .. } => 503,
+            ProxyError::DeadlineExceeded { .. } => 504,
+            ProxyError::Expired { .. } => 410,
+            ProxyError::PayloadTooLarge { .. } => 413,
+            ProxyError::Upstream(_) => 500,
+            ProxyError::Internal(_) => 500,
+        }
+    }
+
+    pub fn description(&self) -> &'static str {
+        match self {
+            ProxyError::InvalidRequest(_) | ProxyError::InvalidJson { .. } => {
+                "The request body could not be parsed or failed validation"
+            }
+            ProxyError::NotFound(_) => "Unknown route, template, or other named resource",
+            ProxyError::PolicyViolation(_) => "The tenant's region or host policy doesn't allow the request",
+            ProxyError::RateLimited { .. } => "The tenant is over its requests per minute; see Retry-After",
+            ProxyError::RegionAssertionFailed(_) => {
+                "assert_region was set and the Durable Object runs outside the selected region"
+            }
+            ProxyError::ContractViolation(_) => "The upstream response doesn't match an enforced response_schema",
+            ProxyError::UpstreamMaintenance { .. } => {
+                "The target host is in a scheduled maintenance window; see Retry-After"
+            }
+            ProxyError::CircuitOpen { .. } => {
+                "The target host failed repeatedly and isn't contacted for now; see Retry-After"
+            }
+            ProxyError::RegionDraining { .. } => {
+                "The selected region is being taken out of service; see X-CF-Region-Redirect"
+            }
+            ProxyError::DeadlineExceeded { .. } => "The X-Deadline passed before the request could complete",
+            ProxyError::Expired { .. } => "The X-Expires-At passed before the request was sent, so it was dropped",
+            ProxyError::PayloadTooLarge { .. } => "The request body is larger than the proxy accepts",
+            ProxyError::ResponseTooLarge { .. } => "The upstream response body is larger than the proxy reads",
+            ProxyError::Upstream(_) => "The upstream call could not be completed",
+            ProxyError::GraphQl { .. } => "The GraphQL upstream returned errors; they're listed in details.errors",
+            ProxyError::JsonRpc { .. } => "The JSON-RPC upstream returned an error; its code is in details.code",
+            ProxyError::XmlRpcFault { .. } => "The XML-RPC upstream returned a fault; its faultCode is in details.code",
+            ProxyError::Internal(_) => "A proxy-side dependency (KV, DO storage) failed",
+        }
+    }
+
+    pub fn retryable(&self) -> bool {
+        match self {
+            ProxyError::InvalidRequest(_)
+            | ProxyError::InvalidJson { .. }
+            | ProxyError::NotFound(_)
+            | ProxyError::PolicyViolation(_)
+            | ProxyError::RegionAssertionFailed(_)
+            | ProxyError::ContractViolation(_)
+            | ProxyError::DeadlineExceeded { .. }
+            | ProxyError::Expired { .. }
+            | ProxyError::PayloadTooLarge { .. }
+            | ProxyError::ResponseTooLarge { .. }
+            // Mostly query and validation errors, which fail again the same way
+            | ProxyError::GraphQl { .. }
+            | ProxyError::JsonRpc { .. }
+            | ProxyError::XmlRpcFault { .. } => false,
+            ProxyError::RateLimited { .. }
+            | ProxyError::UpstreamMaintenance { .. }
+            | ProxyError::CircuitOpen { .. }
+            | ProxyError::RegionDraining { .. }
+            | ProxyError::Upstream(_)
+            | ProxyError::Internal(_) => true,
+        }
+    }
+
+    /// Who caused the error; only `Proxy` faults count against the proxy's SLOs
+    pub fn fault(&self) -> Fault {
+        match self {
+            ProxyError::InvalidRequest(_)
+            | ProxyError::InvalidJson { .. }
+            | ProxyError::NotFound(_)
+            | ProxyError::PolicyViolation(_)
+            | ProxyError::RateLimited { .. }
+            | ProxyError::PayloadTooLarge { .. }
+            // The caller chose when the work goes stale
+            | ProxyError::Expired { .. }
+            // Drains are planned, and the caller is told where to go instead
+            | ProxyError::RegionDraining { .. } => Fault::Caller,
+            // A request's time is nearly all spent waiting on upstreams
+            ProxyError::DeadlineExceeded { .. }
+            | ProxyError::ContractViolation(_)
+            | ProxyError::UpstreamMaintenance { .. }
+            | ProxyError::CircuitOpen { .. }
+            | ProxyError::ResponseTooLarge { .. }
+            | ProxyError::Upstream(_)
+            | ProxyError::GraphQl { .. }
+            | ProxyError::JsonRpc { .. }
+            | ProxyError::XmlRpcFault { .. } => Fault::Upstream,
+            ProxyError::RegionAssertionFailed(_) | ProxyError::Internal(_) => Fault::Proxy,
+        }
+    }
+
+    pub fn message(&self) -> String {
+        match self {
+            ProxyError::InvalidRequest(msg) => format!("Invalid request: {}", msg),
+            ProxyError::InvalidJson { message, .. } => format!("Invalid request: {}", message),
+            ProxyError::NotFound(msg) => msg.clone(),
+            ProxyError::PolicyViolation(msg) => format!("Policy violation: {}", msg),
+            ProxyError::RateLimited { tenant, requests_per_minute, retry_after_secs } => format!(
+                "Tenant {} is limited to {} requests per minute; retry in {}s",
+                tenant, requests_per_minute, retry_after_secs
+            ),
+            ProxyError::RegionAssertionFailed(assertion) => format!(
+                "Region assertion failed: expected {}, running in {} ({})",
+                assertion.expected,
+                assertion.actual_colo,
+                assertion.actual_region.as_deref().unwrap_or("unmapped datacenter")
+            ),
+            ProxyError::ContractViolation(msg) => format!("Response contract violation: {}", msg),
+            ProxyError::UpstreamMaintenance { host, reason, .. } => format!(
+                "{} is in scheduled maintenance{}",
+                host,
+                reason.as_deref().map(|r| format!(": {}", r)).unwrap_or_default()
+            ),
+            ProxyError::CircuitOpen { host, retry_after_secs } => format!(
+                "{} is failing; requests to it are paused for {}s",
+                host, retry_after_secs
+            ),
+            ProxyError::RegionDraining { region, redirect_region, reason } => format!(
+                "Region {} is draining{}{}",
+                region,
+                reason.as_deref().map(|r| format!(": {}", r)).unwrap_or_default(),
+                redirect_region.as_deref().map(|r| format!("; send requests to {}", r)).unwrap_or_default()
+            ),
+            ProxyError::DeadlineExceeded { deadline } => {
+                format!("Deadline {} passed before the request could complete", deadline)
+            }
+            ProxyError::Expired { expires_at } => {
+                format!("Request expired at {} before it was sent; it was dropped", expires_at)
+            }
+            ProxyError::PayloadTooLarge { limit } => format!("Request body is larger than {} bytes", limit),
+            ProxyError::ResponseTooLarge { limit } => format!("Upstream response is larger than {} bytes", limit),
+            ProxyError::Upstream(msg) => format!("Upstream error: {}", msg),
+            ProxyError::GraphQl { errors, .. } => {
+                let first = errors.first().and_then(|error| error["message"].as_str()).unwrap_or("no message");
+                match errors.len() {
+                    0 | 1 => format!("GraphQL error: {}", first),
+                    count => format!("GraphQL error: {} (and {} more)", first, count - 1),
+                }
+            }
+            ProxyError::JsonRpc { code, message, .. } => format!("JSON-RPC error {}: {}", code, message),
+            ProxyError::XmlRpcFault { code, message } => format!("XML-RPC fault {}: {}", code, message),
+            ProxyError::Internal(msg) => format!("Internal error: {}", msg),
+        }
+    }
+
+    /// Structured part of the error returned as `details`
+    fn details(&self) -> Option<Value> {
+        match self {
+            ProxyError::InvalidJson { details, .. } => serde_json::to_value(details).ok(),
+            ProxyError::GraphQl { errors, data } => Some(match data {
+                Some(data) => json!({"errors": errors, "data": data}),
+                None => json!({"errors": errors}),
+            }),
+            ProxyError::JsonRpc { code, message, data } => Some(match data {
+                Some(data) => json!({"code": code, "message": message, "data": data}),
+                None => json!({"code": code, "message": message}),
+            }),
+            ProxyError::XmlRpcFault { code, message } => Some(json!({"code": code, "message": message})),
+            _ => None,
+        }
+    }
+
+    /// Builds the JSON error response, attaching metadata when present
+    pub fn to_response(&self, metadata: Option<ResponseMetadata>) -> Result<Response> {
+        let body = ErrorBody {
+            status: self.status(),
+            code: self.code(),
+            message: self.message(),
+            details: self.details(),
+            metadata,
+        };
+        let mut response = Response::from_json(&body)?.with_status(self.status());
+        response.headers_mut().set(FAULT_HEADER, self.fault().as_str())?;
+        if let ProxyError::UpstreamMaintenance { retry_after_secs, .. }
+        | ProxyError::CircuitOpen { retry_after_secs, .. }
+        | ProxyError::RateLimited { retry_after_secs, .. } = self
+        {
+            response.headers_mut().set("Retry-After", &retry_after_secs.to_string())?;
+        }
+        if let ProxyError::RegionDraining { redirect_region: Some(redirect), .. } = self {
+            response.headers_mut().set(crate::drain::REDIRECT_REGION_HEADER, redirect)?;
+        }
+        if let ProxyError::Expired { expires_at } = self {
+            response.headers_mut().set(crate::expiry::EXPIRES_AT_HEADER, &expires_at.to_string())?;
+        }
+        Ok(response)
+    }
+}
+
+impl std::fmt::Display for ProxyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.code(), self.message())
+    }
+}
+
+impl From<worker::Error> for ProxyError {
+    fn from(e: worker::Error) -> Self {
+        ProxyError::Internal(e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_catalog_lists_every_code_once() {
+        let codes: Vec<&str> = ProxyError::catalog().iter().map(|entry| entry.code).collect();
+        // Codes are a public contract; removing one is a breaking change for clients
+        assert_eq!(
+            codes,
+            vec![
+                "INVALID_REQUEST",
+                "NOT_FOUND",
+                "POLICY_VIOLATION",
+                "RATE_LIMITED",
+                "REGION_ASSERTION_FAILED",
+                "CONTRACT_VIOLATION",
+                "UPSTREAM_MAINTENANCE",
+                "CIRCUIT_OPEN",
+                "REGION_DRAINING",
+                "DEADLINE_EXCEEDED",
+                "EXPIRED",
+                "PAYLOAD_TOO_LARGE",
+                "RESPONSE_TOO_LARGE",
+                "UPSTREAM_ERROR",
+                "GRAPHQL_ERROR",
+                "JSONRPC_ERROR",
+                "XMLRPC_FAULT",
+                "INTERNAL_ERROR",
+            ]
+        );
+
+        // Only the proxy's own failures may burn its error budget
+        let proxy_faults: Vec<&str> = ProxyError::catalog()
+            .iter()
+            .filter(|entry| entry.fault == Fault::Proxy)
+            .map(|entry| entry.code)
+            .collect();
+        assert_eq!(proxy_faults, vec!["REGION_ASSERTION_FAILED", "INTERNAL_ERROR"]);
+        assert_eq!(Fault::parse(Fault::Upstream.as_str()), Some(Fault::Upstream));
+    }
+}
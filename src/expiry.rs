@@ -0,0 +1,183 @@
+use serde::Serialize;
+use std::cell::Cell;
+use worker::*;
+
+use crate::config::Config;
+use crate::cost::CostEstimate;
+use crate::dns_guard::TargetGuard;
+use crate::error::ProxyError;
+use crate::tenants::Tenant;
+use crate::{log_error, log_info};
+
+/// Header carrying the time after which a request must no longer be sent, as epoch millis
+pub const EXPIRES_AT_HEADER: &str = "X-Expires-At";
+
+/// Header carrying the URL told when a request is dropped as expired
+pub const EXPIRY_CALLBACK_HEADER: &str = "X-Expiry-Callback";
+
+/// The point after which a request is stale, e.g. a provisioning call held up by an outage
+///
+/// Unlike a `Deadline` it doesn't limit how long the caller waits, nor cut
+/// an upstream call short: it's checked whenever the request leaves a wait
+/// (the DO queue, a concurrency slot, a maintenance delay, pacing, a retry
+/// backoff), and a request past it is dropped with `EXPIRED` instead of sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Expiry(pub u64);
+
+impl Expiry {
+    /// Reads `X-Expires-At`; a missing header means the request doesn't expire
+    pub fn from_headers(headers: &Headers) -> std::result::Result<Option<Expiry>, ProxyError> {
+        match headers.get(EXPIRES_AT_HEADER)? {
+            Some(value) => Expiry::parse(&value).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    pub fn parse(value: &str) -> std::result::Result<Expiry, ProxyError> {
+        value.trim().parse::<u64>().map(Expiry).map_err(|_| {
+            ProxyError::InvalidRequest(format!("{} must be epoch milliseconds: {}", EXPIRES_AT_HEADER, value))
+        })
+    }
+
+    /// Whether the request has expired by `at` (epoch millis)
+    pub fn is_past_at(&self, at: u64) -> bool {
+        at >= self.0
+    }
+
+    /// `Expired` once the request has expired
+    pub fn check(&self) -> std::result::Result<(), ProxyError> {
+        if self.is_past_at(Date::now().as_millis()) {
+            return Err(ProxyError::Expired { expires_at: self.0 });
+        }
+        Ok(())
+    }
+}
+
+/// Checks an optional expiry; `Ok` when the request doesn't expire
+pub fn check(expiry: Option<Expiry>) -> std::result::Result<(), ProxyError> {
+    expiry.map_or(Ok(()), |expiry| expiry.check())
+}
+
+/// Reads `X-Expiry-Callback`; a missing header means nobody is told
+pub fn callback_url(headers: &Headers, tenant: Option<&Tenant>) -> std::result::Result<Option<Url>, ProxyError> {
+    match headers.get(EXPIRY_CALLBACK_HEADER)? {
+        Some(value) => parse_callback(&value, tenant).map(Some),
+        None => Ok(None),
+    }
+}
+
+/// A callback must be http(s), and a tenant may only point it at its allowed hosts
+pub fn parse_callback(value: &str, tenant: Option<&Tenant>) -> std::result::Result<Url, ProxyError> {
+    let url = Url::parse(value)
+        .ok()
+        .filter(|url| matches!(url.scheme(), "http" | "https") && url.has_host())
+        .ok_or_else(|| {
+            ProxyError::InvalidRequest(format!("{} must be an http(s) URL: {}", EXPIRY_CALLBACK_HEADER, value))
+        })?;
+    if let Some(tenant) = tenant.filter(|tenant| !tenant.allows_url(&url)) {
+        return Err(ProxyError::PolicyViolation(format!(
+            "Host {} is not allowed for tenant {}",
+            url.host_str().unwrap_or_default(),
+            tenant.id
+        )));
+    }
+    Ok(url)
+}
+
+/// What the expiry callback is sent
+#[derive(Debug, Serialize)]
+pub struct ExpiredNotice {
+    pub status: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    pub tenant: String,
+    pub expires_at: u64,
+    pub dropped_at: u64,
+}
+
+impl ExpiredNotice {
+    pub fn new(request_id: Option<String>, tenant: &str, expiry: Expiry) -> ExpiredNotice {
+        ExpiredNotice {
+            status: "EXPIRED",
+            request_id,
+            tenant: tenant.to_string(),
+            expires_at: expiry.0,
+            dropped_at: Date::now().as_millis(),
+        }
+    }
+}
+
+/// Posts the notice to the callback, through the same SSRF checks as upstream calls
+///
+/// Runs after the response is sent; failures are only logged.
+pub async fn notify(guard: TargetGuard, url: Url, notice: ExpiredNotice) {
+    if let Err(e) = guard.check(url.as_str(), &Cell::new(CostEstimate::default())).await {
+        log_info!("Expiry callback to {} refused: {}", url, e);
+        return;
+    }
+    let sent: Result<Response> = async {
+        let mut init = RequestInit::new();
+        init.method = Method::Post;
+        let headers = Headers::new();
+        headers.set("Content-Type", "application/json")?;
+        init.headers = headers;
+        init.body = Some(serde_json::to_string(&notice)?.into());
+        Fetch::Request(Request::new_with_init(url.as_str(), &init)?).send().await
+    }
+    .await;
+    match sent {
+        Ok(response) if response.status_code() < 300 => {
+            log_info!("Expiry callback to {} sent", url);
+        }
+        Ok(response) => log_error!("Expiry callback to {} answered {}", url, response.status_code()),
+        Err(e) => log_error!("Expiry callback to {} failed: {}", url, e),
+    }
+}
+
+/// Tells `callback`, once the response is sent, when `response` drops the request as expired
+pub fn notify_if_expired(
+    ctx: &Context,
+    config: &Config,
+    callback: Option<&Url>,
+    tenant: &str,
+    response: &Response,
+) -> Result<()> {
+    let callback = match callback {
+        Some(callback) if response.status_code() == 410 => callback.clone(),
+        _ => return Ok(()),
+    };
+    let expires_at = match response.headers().get(EXPIRES_AT_HEADER)?.and_then(|value| Expiry::parse(&value).ok()) {
+        Some(expires_at) => expires_at,
+        None => return Ok(()),
+    };
+    let notice = ExpiredNotice::new(crate::logger::current_request_id(), tenant, expires_at);
+    ctx.wait_until(notify(config.target_guard(), callback, notice));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expiry_parse_and_callback_policy() {
+        let expiry = Expiry::parse(" 1767229200000 ").unwrap();
+        assert!(!expiry.is_past_at(1767229199999));
+        assert!(expiry.is_past_at(1767229200000));
+        assert_eq!(Expiry::parse("tomorrow").unwrap_err().code(), "INVALID_REQUEST");
+        assert_eq!(ProxyError::Expired { expires_at: expiry.0 }.status(), 410);
+
+        let callback = "https://hooks.billing.example/expired";
+        let mut tenant: Tenant = serde_json::from_value(serde_json::json!({
+            "id": "billing",
+            "name": "Billing",
+            "created_at": 0,
+            "allowed_hosts": ["api.carrier.example"]
+        }))
+        .unwrap();
+        assert_eq!(parse_callback(callback, Some(&tenant)).unwrap_err().code(), "POLICY_VIOLATION");
+        tenant.allowed_hosts.push("hooks.billing.example".to_string());
+        assert!(parse_callback(callback, Some(&tenant)).is_ok());
+        assert_eq!(parse_callback("ftp://hooks.billing.example/", None).unwrap_err().code(), "INVALID_REQUEST");
+    }
+}
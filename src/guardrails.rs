@@ -0,0 +1,167 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::batch::MAX_BATCH_SIZE;
+use crate::error::ProxyError;
+use crate::handlers::pipeline::MAX_PIPELINE_STEPS;
+use crate::handlers::saga::MAX_SAGA_STEPS;
+use crate::retry::{RetryPolicy, MAX_ATTEMPTS};
+
+/// A tenant's caps on how much upstream work one request may ask for
+///
+/// Each cap narrows the deployment-wide maximum for the tenant; unset keeps
+/// it. `max_subrequests` bounds the worst case of the others combined: every
+/// step and compensation sent with all the attempts its `retries` allow, summed
+/// over a batch's entries.
+///
+/// There's no cap on pagination pages: the proxy never follows pagination, so
+/// each page is a request of its own, bounded by the tenant's rate limit.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Guardrails {
+    /// Steps of a saga or pipeline
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_steps: Option<usize>,
+    /// Entries of a `POST /batch`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_batch_size: Option<usize>,
+    /// `max_attempts` of a request's or step's own `retries` block
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_attempts: Option<u32>,
+    /// Upstream calls one request (or batch) may make at worst
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_subrequests: Option<u32>,
+}
+
+/// The requests a body sends upstream: every step and compensation of a
+/// saga or pipeline, or the body itself
+fn requests<'a>(request_type: &str, body: &'a Value) -> Vec<&'a Value> {
+    match request_type {
+        "saga" | "pipeline" => steps(body)
+            .iter()
+            .flat_map(|step| [Some(step), step.get("compensation")])
+            .flatten()
+            .map(|request| &request["request"])
+            .collect(),
+        _ => vec![body],
+    }
+}
+
+fn steps(body: &Value) -> &[Value] {
+    body.get("steps").and_then(Value::as_array).map(Vec::as_slice).unwrap_or_default()
+}
+
+/// Worst-case upstream calls of one request, every request sent with all its attempts
+pub fn subrequests(request_type: &str, body: &Value) -> u32 {
+    requests(request_type, body).into_iter().map(attempts).sum()
+}
+
+/// Attempts a request's own `retries` block allows; 1 without one, as
+/// host and tenant retry policies are the operator's to bound
+fn attempts(request: &Value) -> u32 {
+    request
+        .get("retries")
+        .and_then(|retries| serde_json::from_value::<RetryPolicy>(retries.clone()).ok())
+        .map_or(1, |policy| policy.max_attempts.max(1))
+}
+
+impl Guardrails {
+    pub fn validate(&self) -> std::result::Result<(), ProxyError> {
+        let max_steps = MAX_SAGA_STEPS.max(MAX_PIPELINE_STEPS);
+        let caps = [
+            ("max_steps", self.max_steps.map(|cap| cap as u64), max_steps as u64),
+            ("max_batch_size", self.max_batch_size.map(|cap| cap as u64), MAX_BATCH_SIZE as u64),
+            ("max_attempts", self.max_attempts.map(u64::from), MAX_ATTEMPTS as u64),
+            ("max_subrequests", self.max_subrequests.map(u64::from), u32::MAX as u64),
+        ];
+        for (name, cap, max) in caps {
+            if let Some(cap) = cap.filter(|cap| !(1..=max).contains(cap)) {
+                return Err(ProxyError::InvalidRequest(format!(
+                    "guardrails.{} must be between 1 and {}: {}",
+                    name, max, cap
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Refuses a request asking for more steps, attempts or subrequests than
+    /// `tenant_id` allows; an unparseable body is left to its handler to reject
+    pub fn check(&self, tenant_id: &str, request_type: &str, body: &str) -> std::result::Result<(), ProxyError> {
+        let body = match serde_json::from_str::<Value>(body) {
+            Ok(body) => body,
+            Err(_) => return Ok(()),
+        };
+        let steps = match request_type {
+            "saga" | "pipeline" => steps(&body).len(),
+            _ => 0,
+        };
+        exceeds(tenant_id, "steps", steps as u64, self.max_steps.map(|cap| cap as u64))?;
+        for request in requests(request_type, &body) {
+            exceeds(tenant_id, "attempts", attempts(request).into(), self.max_attempts.map(u64::from))?;
+        }
+        exceeds(tenant_id, "subrequests", subrequests(request_type, &body).into(), self.max_subrequests.map(u64::from))
+    }
+
+    /// Refuses a batch with more entries, or more worst-case subrequests
+    /// across its entries, than `tenant_id` allows
+    pub fn check_batch(&self, tenant_id: &str, size: usize, subrequests: u32) -> std::result::Result<(), ProxyError> {
+        exceeds(tenant_id, "batch entries", size as u64, self.max_batch_size.map(|cap| cap as u64))?;
+        exceeds(tenant_id, "subrequests", subrequests.into(), self.max_subrequests.map(u64::from))
+    }
+}
+
+fn exceeds(tenant_id: &str, what: &str, count: u64, cap: Option<u64>) -> std::result::Result<(), ProxyError> {
+    match cap {
+        Some(cap) if count > cap => Err(ProxyError::PolicyViolation(format!(
+            "Tenant {} allows at most {} {} per request, got {}",
+            tenant_id, cap, what, count
+        ))),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_caps_steps_attempts_and_subrequests() {
+        let guardrails: Guardrails =
+            serde_json::from_value(json!({"max_steps": 3, "max_attempts": 3, "max_subrequests": 8})).unwrap();
+        let saga = |steps: Value| json!({"steps": steps}).to_string();
+        let step = |retries: Value| json!({"name": "s", "request": {"url": "https://a.example", "retries": retries}});
+
+        assert!(guardrails.check("billing", "saga", &saga(json!([step(json!({"max_attempts": 3}))]))).is_ok());
+        let error = guardrails.check("billing", "pipeline", &saga(json!(vec![step(json!(null)); 4]))).unwrap_err();
+        assert_eq!(error.code(), "POLICY_VIOLATION");
+        assert_eq!(error.message(), "Policy violation: Tenant billing allows at most 3 steps per request, got 4");
+        // `retries: {}` means the default 3 attempts, within the cap
+        assert!(guardrails.check("billing", "http", r#"{"url": "https://a.example", "retries": {}}"#).is_ok());
+        assert!(guardrails
+            .check("billing", "http", r#"{"url": "https://a.example", "retries": {"max_attempts": 4}}"#)
+            .is_err());
+
+        // 3 steps of 3 attempts: 9 subrequests, one over
+        let mut steps = vec![step(json!({"max_attempts": 3})); 3];
+        assert!(guardrails.check("billing", "saga", &saga(json!(steps))).is_err());
+        steps[2] = step(json!(null));
+        assert_eq!(subrequests("saga", &json!({"steps": steps})), 7);
+        // A compensation is sent too when a later step fails
+        steps[0]["compensation"] = json!({"request": {"url": "https://a.example", "retries": {"max_attempts": 2}}});
+        assert_eq!(subrequests("saga", &json!({"steps": steps})), 9);
+        assert!(guardrails.check("billing", "saga", &saga(json!(steps))).is_err());
+        // Steps aren't counted for other request types, nor is unparseable JSON
+        assert!(guardrails.check("billing", "http", &saga(json!(vec![step(json!(null)); 4]))).is_ok());
+        assert!(guardrails.check("billing", "saga", "{").is_ok());
+
+        assert!(Guardrails { max_batch_size: Some(5), ..Default::default() }.check_batch("billing", 6, 6).is_err());
+        assert!(guardrails.check_batch("billing", 6, 8).is_ok());
+        assert!(guardrails.check_batch("billing", 3, 9).is_err());
+
+        assert!(guardrails.validate().is_ok());
+        assert!(Guardrails { max_steps: Some(0), ..Default::default() }.validate().is_err());
+        assert!(Guardrails { max_batch_size: Some(MAX_BATCH_SIZE + 1), ..Default::default() }.validate().is_err());
+        assert!(Guardrails { max_attempts: Some(MAX_ATTEMPTS + 1), ..Default::default() }.validate().is_err());
+    }
+}
@@ -0,0 +1,165 @@
+use encoding_rs::{Encoding, UTF_8};
+
+/// How far into the body to look for an XML declaration
+const XML_DECLARATION_WINDOW: usize = 256;
+
+/// An upstream body decoded to UTF-8
+pub struct Decoded {
+    pub text: String,
+    /// Charset the body was transcoded from; `None` when it already was UTF-8
+    pub original_charset: Option<String>,
+}
+
+/// Decodes an upstream body to UTF-8
+///
+/// The charset comes from a byte order mark, then the `Content-Type` header,
+/// then an XML declaration (`<?xml ... encoding="windows-1251"?>`), and
+/// defaults to UTF-8. Unknown charsets are read as UTF-8. Bytes that aren't
+/// valid in the charset become U+FFFD, as `Response::text()` did before.
+pub fn decode(bytes: &[u8], content_type: Option<&str>) -> Decoded {
+    let declared = content_type
+        .and_then(from_content_type)
+        .or_else(|| from_xml_declaration(bytes));
+    let encoding = match Encoding::for_bom(bytes) {
+        Some((encoding, _)) => Some(encoding),
+        None => declared.as_deref().and_then(|label| Encoding::for_label(label.as_bytes())),
+    }
+    .unwrap_or(UTF_8);
+
+    // `decode` strips the BOM and lets it override the declared charset
+    let (text, used, _) = encoding.decode(bytes);
+    let original_charset = (used != UTF_8).then(|| match declared {
+        // Report the charset as the upstream named it, e.g. "iso-8859-1"
+        Some(label) if Encoding::for_label(label.as_bytes()) == Some(used) => label,
+        _ => used.name().to_ascii_lowercase(),
+    });
+    Decoded {
+        text: text.into_owned(),
+        original_charset,
+    }
+}
+
+/// Charset `label` names, if a request body can be encoded in it
+///
+/// Encoding to UTF-16 (or the `replacement` pseudo-charset) produces UTF-8,
+/// so those are refused, as are labels that don't fit in a header as-is.
+pub fn for_request(label: &str) -> Option<&'static Encoding> {
+    let token = !label.is_empty()
+        && label.bytes().all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.' | b':'));
+    Encoding::for_label(label.as_bytes()).filter(|encoding| token && encoding.output_encoding() == *encoding)
+}
+
+/// Encodes a request body in `encoding`
+///
+/// Characters the charset can't represent become numeric character
+/// references (`&#1046;`), which XML reads back as the same characters.
+pub fn encode(text: &str, encoding: &'static Encoding) -> Vec<u8> {
+    encoding.encode(text).0.into_owned()
+}
+
+/// Whether a Content-Type names a binary format, which decoding as text would corrupt
+///
+/// Text-based formats under the same top-level types (`image/svg+xml`) aren't binary.
+pub fn is_binary(content_type: Option<&str>) -> bool {
+    let mime = match content_type.and_then(|value| value.split(';').next()) {
+        Some(mime) => mime.trim().to_ascii_lowercase(),
+        None => return false,
+    };
+    if mime.ends_with("+xml") || mime.ends_with("+json") {
+        return false;
+    }
+    let (kind, subtype) = mime.split_once('/').unwrap_or((&mime, ""));
+    match kind {
+        "image" | "audio" | "video" | "font" => true,
+        "application" => {
+            matches!(
+                subtype,
+                "octet-stream" | "pdf" | "zip" | "gzip" | "x-gzip" | "x-tar" | "x-7z-compressed" | "wasm"
+                    | "msword" | "vnd.ms-excel" | "protobuf" | "x-protobuf"
+            ) || subtype.starts_with("vnd.openxmlformats-officedocument.")
+        }
+        _ => false,
+    }
+}
+
+/// `charset` parameter of a Content-Type value, lowercased
+fn from_content_type(content_type: &str) -> Option<String> {
+    content_type.split(';').skip(1).find_map(|param| {
+        let (name, value) = param.split_once('=')?;
+        name.trim()
+            .eq_ignore_ascii_case("charset")
+            .then(|| value.trim().trim_matches(['"', '\'']).to_ascii_lowercase())
+            .filter(|charset| !charset.is_empty())
+    })
+}
+
+/// `encoding` pseudo-attribute of a leading XML declaration, lowercased
+fn from_xml_declaration(bytes: &[u8]) -> Option<String> {
+    // The declaration itself is ASCII in every charset we can decode here
+    let head = &bytes[..bytes.len().min(XML_DECLARATION_WINDOW)];
+    let head = String::from_utf8_lossy(head);
+    let declaration = head.trim_start().strip_prefix("<?xml")?;
+    let declaration = &declaration[..declaration.find("?>")?];
+    let rest = &declaration[declaration.find("encoding")? + "encoding".len()..];
+    let rest = rest.trim_start().strip_prefix('=')?.trim_start();
+    let quote = rest.chars().next().filter(|c| *c == '"' || *c == '\'')?;
+    let value = rest[1..].split(quote).next()?;
+    (!value.is_empty()).then(|| value.to_ascii_lowercase())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_declared_charsets() {
+        // "Иван" in Windows-1251
+        let cyrillic = [0xC8, 0xE2, 0xE0, 0xED];
+        let decoded = decode(&cyrillic, Some("text/plain; charset=Windows-1251"));
+        assert_eq!(decoded.text, "Иван");
+        assert_eq!(decoded.original_charset.as_deref(), Some("windows-1251"));
+
+        let mut xml = b"<?xml version=\"1.0\" encoding='ISO-8859-1'?><name>".to_vec();
+        xml.extend([0x4A, 0x6F, 0x73, 0xE9]);
+        xml.extend(b"</name>");
+        let decoded = decode(&xml, Some("text/xml"));
+        assert!(decoded.text.ends_with("<name>José</name>"));
+        assert_eq!(decoded.original_charset.as_deref(), Some("iso-8859-1"));
+
+        let decoded = decode("Иван".as_bytes(), Some("application/json; charset=utf-8"));
+        assert_eq!(decoded.text, "Иван");
+        assert_eq!(decoded.original_charset, None);
+
+        // A UTF-8 BOM wins over a wrong header
+        let mut bom = vec![0xEF, 0xBB, 0xBF];
+        bom.extend("Иван".as_bytes());
+        let decoded = decode(&bom, Some("text/plain; charset=windows-1251"));
+        assert_eq!(decoded.text, "Иван");
+        assert_eq!(decoded.original_charset, None);
+    }
+
+    #[test]
+    fn test_encode_requests() {
+        let cyrillic = for_request("windows-1251").unwrap();
+        assert_eq!(encode("<n>Иван</n>", cyrillic), b"<n>\xC8\xE2\xE0\xED</n>");
+        // Latin-1 has no Cyrillic or CJK, so those go as references
+        let latin = for_request("ISO-8859-1").unwrap();
+        assert_eq!(encode("José Ж 東", latin), b"Jos\xE9 &#1046; &#26481;");
+        assert_eq!(encode("東京", for_request("utf-8").unwrap()), "東京".as_bytes());
+
+        assert!(for_request("utf-16le").is_none());
+        assert!(for_request("klingon").is_none());
+        assert!(for_request(" utf-8").is_none());
+    }
+
+    #[test]
+    fn test_binary_content_types() {
+        for binary in ["application/pdf", "image/PNG", "application/octet-stream; name=\"a.bin\"", "font/woff2"] {
+            assert!(is_binary(Some(binary)), "{}", binary);
+        }
+        for text in ["application/json", "text/csv; charset=utf-8", "image/svg+xml", "application/soap+xml"] {
+            assert!(!is_binary(Some(text)), "{}", text);
+        }
+        assert!(!is_binary(None));
+    }
+}
@@ -0,0 +1,141 @@
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+
+use super::registry::Failure;
+use super::response::ApiResponse;
+use super::xml::{RawBody, XmlOptions};
+use crate::error::ProxyError;
+use crate::json_path::JsonPath;
+use crate::xpath::{self, XPath};
+
+/// Fragments of the response body to return instead of all of it, by name
+///
+/// An expression starting with `$` is a JSONPath into the body; one starting
+/// with `/` is an XPath into the XML the body was read from.
+pub type Extract = BTreeMap<String, String>;
+
+enum Selector {
+    Json(JsonPath),
+    Xml(XPath),
+}
+
+fn selector(expression: &str) -> Result<Selector, String> {
+    match expression.trim_start().chars().next() {
+        Some('$') => JsonPath::parse(expression).map(Selector::Json),
+        Some('/') => XPath::parse(expression).map(Selector::Xml),
+        _ => Err(format!("extract expressions start with $ (JSONPath) or / (XPath): {}", expression)),
+    }
+}
+
+/// Refuses expressions that don't parse, and XPaths with no XML left to run on
+pub fn validate(extract: &Extract, xml_to_json: Option<&XmlOptions>) -> Result<(), ProxyError> {
+    for (name, expression) in extract {
+        if name.is_empty() {
+            return Err(ProxyError::InvalidRequest("extract names can't be empty".to_string()));
+        }
+        let selector =
+            selector(expression).map_err(|e| ProxyError::InvalidRequest(format!("extract {}: {}", name, e)))?;
+        if matches!(selector, Selector::Xml(_)) && xml_to_json.is_some_and(|options| options.raw_body.is_none()) {
+            return Err(ProxyError::InvalidRequest(format!(
+                "extract {}: an XPath needs the XML, so drop xml_to_json or set its raw_body",
+                name
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// The values each expression selects from `body`, or from the XML it was read from
+///
+/// A JSONPath with a wildcard yields an array of its matches, any other its
+/// single match. An XPath yields its one match, or an array when it matches
+/// several. Either is `null` when nothing matches.
+pub fn select(extract: &Extract, body: &Value, raw_body: Option<&RawBody>) -> Result<Map<String, Value>, String> {
+    let mut tree = None;
+    let mut values = Map::new();
+    for (name, expression) in extract {
+        let value = match selector(expression)? {
+            Selector::Json(path) => {
+                let matches = path.select(body);
+                match (path.is_multi(), matches.first()) {
+                    (true, _) => Value::Array(matches.into_iter().cloned().collect()),
+                    (false, Some(value)) => (*value).clone(),
+                    (false, None) => Value::Null,
+                }
+            }
+            Selector::Xml(path) => {
+                if tree.is_none() {
+                    let xml = raw_body
+                        .map(|raw| raw.xml.as_str())
+                        .or(body.as_str())
+                        .ok_or_else(|| "the response body isn't XML".to_string())?;
+                    tree = Some(xpath::parse_tree(xml).map_err(|e| format!("{:#}", e))?);
+                }
+                let nodes = path.select(tree.as_ref().unwrap());
+                match nodes.as_slice() {
+                    [] => Value::Null,
+                    [node] => node.to_json(),
+                    nodes => Value::Array(nodes.iter().map(|node| node.to_json()).collect()),
+                }
+            }
+        };
+        values.insert(name.clone(), value);
+    }
+    Ok(values)
+}
+
+/// Replaces a successful response's body with what `extract` selects from it
+///
+/// Error responses are returned as they are. The XML of `raw_body` is dropped
+/// along with the rest of the body. The failure is boxed, as it carries the
+/// response's metadata.
+pub fn apply(extract: &Extract, outcome: Result<ApiResponse, Failure>) -> Result<ApiResponse, Box<Failure>> {
+    let mut response = outcome?;
+    let (body, raw_body) = match &mut response {
+        ApiResponse::Success(data) => (&mut data.body, &mut data.raw_body),
+        ApiResponse::Minimal(data) => (&mut data.body, &mut data.raw_body),
+        _ => return Ok(response),
+    };
+    match select(extract, body, raw_body.as_ref()) {
+        Ok(values) => {
+            *body = Value::Object(values);
+            *raw_body = None;
+            Ok(response)
+        }
+        Err(e) => Err(Box::new(Failure {
+            error: ProxyError::Upstream(format!("Can't extract from the response body: {}", e)),
+            metadata: Some(response.metadata_mut().clone()),
+        })),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_select_json_and_xml() {
+        let extract = |pairs: &[(&str, &str)]| -> Extract {
+            pairs.iter().map(|(name, expression)| (name.to_string(), expression.to_string())).collect()
+        };
+
+        let body = json!({"order": {"id": 42, "lines": [{"sku": "A"}, {"sku": "B"}]}});
+        let json = extract(&[("id", "$.order.id"), ("skus", "$.order.lines[*].sku"), ("total", "$.order.total")]);
+        assert!(validate(&json, None).is_ok());
+        assert_eq!(
+            Value::Object(select(&json, &body, None).unwrap()),
+            json!({"id": 42, "skus": ["A", "B"], "total": null})
+        );
+
+        let xml = json!("<Envelope><Body><quote symbol=\"ACME\"><price>9.5</price></quote></Body></Envelope>");
+        let xpaths = extract(&[("price", "//quote/price/text()"), ("symbol", "/Envelope/Body/quote/@symbol")]);
+        assert_eq!(Value::Object(select(&xpaths, &xml, None).unwrap()), json!({"price": "9.5", "symbol": "ACME"}));
+        assert!(select(&xpaths, &body, None).is_err());
+
+        let converted = XmlOptions::default();
+        assert!(validate(&xpaths, Some(&converted)).is_err());
+        assert!(validate(&json, Some(&converted)).is_ok());
+        assert!(validate(&extract(&[("id", "order.id")]), None).is_err());
+    }
+}
@@ -0,0 +1,155 @@
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+use worker::*;
+
+use super::http_handler::RequestData;
+use super::parse::from_json;
+use super::registry::{Failure, ProcessorContext, ProxyHandler};
+use super::response::ApiResponse;
+use super::upstream::{self, UpstreamPreview};
+use crate::error::ProxyError;
+use crate::log_debug;
+
+/// Accept header sent when the request doesn't set one: the GraphQL over HTTP
+/// media type, with plain JSON for servers that predate it
+const DEFAULT_ACCEPT: &str = "application/graphql-response+json, application/json";
+
+/// A GraphQL operation (`X-Request-Type: graphql`), POSTed as JSON
+#[derive(Debug, Deserialize)]
+pub struct GraphQlRequestData {
+    /// The GraphQL endpoint, e.g. "https://api.example.com/graphql"
+    pub url: String,
+    /// The query or mutation document
+    pub query: String,
+    #[serde(default)]
+    pub variables: Option<Map<String, Value>>,
+    /// Which operation of a document with several to run
+    #[serde(default)]
+    pub operation_name: Option<String>,
+    /// Request headers as key-value pairs
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+}
+
+impl GraphQlRequestData {
+    /// The POST that runs the operation, as an HTTP request
+    fn fetch_request(&self) -> std::result::Result<RequestData, ProxyError> {
+        if self.query.trim().is_empty() {
+            return Err(ProxyError::InvalidRequest("GraphQL query is empty".to_string()));
+        }
+        let mut headers = self.headers.clone();
+        if !headers.keys().any(|name| name.eq_ignore_ascii_case("accept")) {
+            headers.insert("Accept".to_string(), DEFAULT_ACCEPT.to_string());
+        }
+        let body = json!({
+            "query": self.query,
+            "variables": self.variables,
+            "operationName": self.operation_name,
+        });
+        serde_json::from_value(json!({
+            "url": self.url,
+            "method": "post",
+            "headers": headers,
+            "body": body.to_string(),
+            "body_format": "json",
+        }))
+        .map_err(|e| ProxyError::InvalidRequest(format!("Invalid GraphQL request: {}", e)))
+    }
+
+    pub fn preview(&self) -> std::result::Result<UpstreamPreview, ProxyError> {
+        Ok(self.fetch_request()?.preview())
+    }
+}
+
+/// The error for a GraphQL response body with a non-empty `errors` array
+///
+/// GraphQL servers answer most failures with a 200, so the status alone
+/// doesn't tell success from failure. Partial `data` is kept in the error.
+pub fn graphql_errors(body: &mut Value) -> Option<ProxyError> {
+    let errors = match body.get_mut("errors") {
+        Some(Value::Array(errors)) if !errors.is_empty() => std::mem::take(errors),
+        _ => return None,
+    };
+    let data = body.get_mut("data").map(Value::take).filter(|data| !data.is_null());
+    Some(ProxyError::GraphQl { errors, data })
+}
+
+pub struct GraphQlHandler;
+
+impl ProxyHandler for GraphQlHandler {
+    const NAME: &'static str = "GraphQL";
+    type Request = GraphQlRequestData;
+    type Outcome = std::result::Result<ApiResponse, Failure>;
+
+    fn parse(&self, body: &str) -> std::result::Result<GraphQlRequestData, ProxyError> {
+        from_json(body, Self::NAME)
+    }
+
+    async fn execute(&self, ctx: &ProcessorContext<'_>, request: GraphQlRequestData) -> Self::Outcome {
+        log_debug!(
+            ctx.log_level,
+            "GraphQL operation: {}, url: {}",
+            request.operation_name.as_deref().unwrap_or("(anonymous)"),
+            request.url
+        );
+        let mut response = upstream::execute(ctx, request.fetch_request()?).await?;
+        if let ApiResponse::Success(data) = &mut response {
+            if let Some(error) = graphql_errors(&mut data.body) {
+                return Err(Failure { error, metadata: data.metadata.take() });
+            }
+        }
+        Ok(response)
+    }
+
+    fn respond(&self, _ctx: &ProcessorContext<'_>, outcome: Self::Outcome) -> Result<Response> {
+        upstream::respond(Self::NAME, outcome)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_errors_array_is_a_failure() {
+        let request: GraphQlRequestData = serde_json::from_value(json!({
+            "url": "https://api.example.com/graphql",
+            "query": "query Number($id: ID!) { number(id: $id) { e164 } }",
+            "variables": {"id": "42"},
+            "operation_name": "Number",
+            "headers": {"accept": "application/json"}
+        }))
+        .unwrap();
+        let fetch = request.fetch_request().unwrap();
+        let body: Value = serde_json::from_str(fetch.body.as_deref().unwrap()).unwrap();
+        assert_eq!(body["operationName"], "Number");
+        assert_eq!(body["variables"], json!({"id": "42"}));
+        assert_eq!(fetch.headers.len(), 1);
+
+        let mut ok = json!({"data": {"number": {"e164": "+15551234567"}}});
+        assert!(graphql_errors(&mut ok).is_none());
+        assert!(graphql_errors(&mut json!({"data": {}, "errors": []})).is_none());
+
+        let mut partial = json!({
+            "data": {"number": null},
+            "errors": [
+                {"message": "Number 42 not found", "path": ["number"]},
+                {"message": "Rate limited", "extensions": {"code": "THROTTLED"}}
+            ]
+        });
+        let error = graphql_errors(&mut partial).unwrap();
+        assert_eq!(error.code(), "GRAPHQL_ERROR");
+        assert_eq!(error.message(), "GraphQL error: Number 42 not found (and 1 more)");
+        match error {
+            ProxyError::GraphQl { errors, data } => {
+                assert_eq!(errors[1]["extensions"]["code"], "THROTTLED");
+                assert_eq!(data, Some(json!({"number": null})));
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+
+        let empty = GraphQlRequestData { query: " ".to_string(), ..request };
+        assert!(empty.fetch_request().is_err());
+    }
+}
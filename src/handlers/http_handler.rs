@@ -1,14 +1,37 @@
 use anyhow::Context as AnyhowContext;
+use futures_util::stream::{LocalBoxStream, StreamExt};
 use reqwest::{
     header::{HeaderMap, HeaderName, HeaderValue},
-    Client, Method as ReqwestMethod,
+    Method as ReqwestMethod,
 };
 use serde::{Deserialize, Deserializer, Serialize};
-use serde_json::Value;
 use std::collections::HashMap;
+use std::time::Duration;
 use std::str::FromStr;
+use super::charset;
+use super::extract::{self, Extract};
+use super::response::{ApiResponse, ErrorResponseData, ResponseData, ResponseMetadata, StreamedResponse};
+use super::schema::ResponseSchemaSpec;
+use super::transform::{self, Transform};
+use super::xml::{self, XmlOptions};
+use super::parse::from_json;
+use super::redirect::{self, RedirectPolicy};
+use super::registry::{Failure, ProcessorContext, ProxyHandler};
+use super::upstream::{self, UpstreamPreview, UpstreamRequest};
+use crate::cache::{self, CacheSpec};
+use crate::canonical_url;
+use crate::cost;
+use crate::credentials::{self, QueryKey, UpstreamAuth};
+use crate::dns_guard;
+use crate::error::ProxyError;
+use crate::limits;
+use crate::locale::{self, LocaleRules};
 use crate::logger::LogLevel;
+use crate::poll::{self, PollSpec};
+use crate::retry::RetryPolicy;
+use crate::sandbox::SandboxRoute;
 use crate::{log_info, log_debug};
+use worker::Url;
 
 #[derive(Debug, Clone, Copy, Serialize)]
 pub enum HttpMethod {
@@ -43,6 +66,13 @@ impl<'de> Deserialize<'de> for HttpMethod {
     }
 }
 
+impl HttpMethod {
+    /// Whether sending the request twice has the same effect as sending it once
+    pub fn is_idempotent(&self) -> bool {
+        !matches!(self, HttpMethod::Post | HttpMethod::Patch)
+    }
+}
+
 impl From<HttpMethod> for ReqwestMethod {
     fn from(method: HttpMethod) -> Self {
         match method {
@@ -57,7 +87,69 @@ impl From<HttpMethod> for ReqwestMethod {
     }
 }
 
-#[derive(Debug, Deserialize)]
+/// How `body` is put on the wire
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BodyFormat {
+    /// Sent as is (plain text, XML, ...)
+    #[default]
+    Raw,
+    /// Must be valid JSON
+    Json,
+    /// An already encoded `a=1&b=2` string; without `body`, `params` are form-encoded instead of JSON
+    Form,
+    /// Decoded, and the bytes sent as is
+    Base64,
+}
+
+impl BodyFormat {
+    /// Content-Type sent when the request doesn't set one
+    pub fn content_type(self) -> &'static str {
+        match self {
+            BodyFormat::Raw => "text/plain; charset=utf-8",
+            BodyFormat::Json => "application/json",
+            BodyFormat::Form => "application/x-www-form-urlencoded",
+            BodyFormat::Base64 => "application/octet-stream",
+        }
+    }
+}
+
+/// How the upstream response body is returned
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ResponseFormat {
+    /// Base64 for binary content types (PDFs, images, ...), decoded text otherwise
+    #[default]
+    Auto,
+    /// Always decoded as text
+    Text,
+    /// Always the raw bytes, base64-encoded
+    Base64,
+}
+
+/// Whether the response comes in the JSON envelope or is passed through
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ResponseMode {
+    /// `{status, headers, body}`, with the body read and decoded
+    #[default]
+    Envelope,
+    /// The upstream status, headers and body as is, streamed without buffering
+    Stream,
+}
+
+impl ResponseFormat {
+    /// Whether a body with this Content-Type is returned base64-encoded
+    pub fn encodes(self, content_type: Option<&str>) -> bool {
+        match self {
+            ResponseFormat::Auto => charset::is_binary(content_type),
+            ResponseFormat::Text => false,
+            ResponseFormat::Base64 => true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
 pub struct RequestData {
     /// URL to request
     pub url: String,
@@ -73,38 +165,304 @@ pub struct RequestData {
     /// Request headers as key-value pairs
     #[serde(default)]
     pub headers: HashMap<String, String>,
+
+    /// Request body sent instead of `params`, which then go in the query string
+    #[serde(default)]
+    pub body: Option<String>,
+
+    /// How `body` is encoded; `raw` when unset
+    #[serde(default)]
+    pub body_format: Option<BodyFormat>,
+
+    /// How the response body is returned; binary content types are base64-encoded by default
+    #[serde(default)]
+    pub response_format: ResponseFormat,
+
+    /// Pass a successful response through instead of wrapping it in the envelope
+    #[serde(default)]
+    pub mode: ResponseMode,
+
+    /// Whether and how far redirects are followed; the runtime follows them all when unset
+    #[serde(default)]
+    pub redirect: Option<RedirectPolicy>,
+
+    /// Fail the request if the DO is not running in its expected region
+    #[serde(default)]
+    pub assert_region: bool,
+
+    /// Expected response JSON Schema; violations are flagged in metadata
+    #[serde(default)]
+    pub response_schema: Option<ResponseSchemaSpec>,
+
+    /// Convert an XML response body to JSON using these options
+    #[serde(default)]
+    pub xml_to_json: Option<XmlOptions>,
+
+    /// Return only these fragments of a successful response's body, by name
+    #[serde(default)]
+    pub extract: Option<Extract>,
+
+    /// Reshape a successful response's JSON body, after `extract`
+    #[serde(default)]
+    pub transform: Option<Transform>,
+
+    /// End user's language, e.g. "de-CH"; sent as `Accept-Language` unless a locale rule says otherwise
+    #[serde(default)]
+    pub locale: Option<String>,
+
+    /// Stored upstream credential whose headers are added to the request
+    #[serde(default)]
+    pub credential: Option<String>,
+
+    /// Vault credential sent other than as headers (e.g. a query-string key)
+    #[serde(default)]
+    pub auth: Option<UpstreamAuth>,
+
+    /// Return only `{status, body}` for successful responses
+    #[serde(default)]
+    pub minimal: bool,
+
+    /// Retry behaviour for this request; a host's retry policy still wins
+    #[serde(default)]
+    pub retries: Option<RetryPolicy>,
+
+    /// Serve GET and HEAD responses from this shard's storage for a while
+    #[serde(default)]
+    pub cache: Option<CacheSpec>,
+
+    /// Report whether the response changed since the last poll of this key
+    #[serde(default)]
+    pub poll: Option<PollSpec>,
+
+    /// Return the status and headers without reading the response body
+    #[serde(default)]
+    pub headers_only: bool,
+
+    /// Resolved `query_key` auth, set by the processor and kept out of `url`
+    #[serde(skip)]
+    pub query_key: Option<QueryKey>,
+
+    /// Upstream timeout, set by the processor from the caller's `X-Deadline`
+    #[serde(skip)]
+    pub timeout: Option<Duration>,
+
+    /// Largest response body read, set by the processor from `MAX_RESPONSE_BYTES`
+    #[serde(skip)]
+    pub max_response_bytes: Option<u64>,
+
+    /// How the call is answered when the sandbox tenant made it, set by the processor
+    #[serde(skip)]
+    pub sandbox: Option<SandboxRoute>,
 }
 
 fn default_method() -> HttpMethod {
     HttpMethod::Post
 }
 
-#[derive(Serialize)]
-pub struct ResponseData {
-    pub status: u16,
-    pub headers: HashMap<String, String>,
-    pub body: Value,
+impl RequestData {
+    /// Whether the response body is left unread: HEAD responses have none,
+    /// and their `Content-Length` describes the resource, not the response
+    pub fn skips_body(&self) -> bool {
+        self.headers_only || matches!(self.method, HttpMethod::Head)
+    }
+
+    /// Whether `params` go in the query string rather than the body
+    fn params_in_query(&self) -> bool {
+        self.body.is_some() || matches!(self.method, HttpMethod::Get | HttpMethod::Head | HttpMethod::Delete)
+    }
+
+    /// Checks `body` against `body_format` and the method, before anything is sent
+    pub fn validate_body(&self) -> std::result::Result<(), ProxyError> {
+        if self.body.is_none() {
+            return match self.body_format {
+                Some(format @ (BodyFormat::Raw | BodyFormat::Base64)) => Err(ProxyError::InvalidRequest(format!(
+                    "body_format {} needs a body",
+                    format!("{:?}", format).to_lowercase()
+                ))),
+                _ => Ok(()),
+            };
+        }
+        if matches!(self.method, HttpMethod::Get | HttpMethod::Head) {
+            return Err(ProxyError::InvalidRequest("body isn't allowed for GET and HEAD requests".to_string()));
+        }
+        self.body_bytes().map(|_| ())
+    }
+
+    /// Refuses options that need the response body read, which a streamed one never is
+    pub fn validate_mode(&self) -> std::result::Result<(), ProxyError> {
+        if self.mode == ResponseMode::Stream
+            && (self.minimal
+                || self.headers_only
+                || self.response_schema.is_some()
+                || self.xml_to_json.is_some()
+                || self.extract.is_some()
+                || self.transform.is_some()
+                || self.cache.is_some()
+                || self.poll.is_some())
+        {
+            return Err(ProxyError::InvalidRequest(
+                "mode stream can't be combined with minimal, headers_only, response_schema, xml_to_json, extract, \
+                 transform, cache or poll"
+                    .to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Checks the `redirect` block; an unfollowed redirect must not be cached as the response
+    pub fn validate_redirect(&self) -> std::result::Result<(), ProxyError> {
+        match &self.redirect {
+            Some(policy) if !policy.follow && self.cache.is_some() => Err(ProxyError::InvalidRequest(
+                "redirect with follow false can't be combined with cache".to_string(),
+            )),
+            Some(policy) => policy.validate(),
+            None => Ok(()),
+        }
+    }
+
+    /// Sends `locale` where the target host's locale rule says, beneath the caller's own headers and params
+    pub fn apply_locale(&mut self, rules: &LocaleRules) -> std::result::Result<(), ProxyError> {
+        let Some(locale) = &self.locale else {
+            return Ok(());
+        };
+        let locale = locale::normalize(locale)?;
+        let host = Url::parse(&self.url)
+            .ok()
+            .and_then(|url| url.host_str().map(str::to_string))
+            .unwrap_or_default();
+        let mut target = rules.target(&host, &locale);
+        target.apply_header(&mut self.headers);
+        if let Some((name, value)) = target.param {
+            self.params.entry(name).or_insert(value);
+        }
+        Ok(())
+    }
+
+    /// `body` decoded as `body_format` says, when set
+    pub fn body_bytes(&self) -> std::result::Result<Option<Vec<u8>>, ProxyError> {
+        use base64::{engine::general_purpose::STANDARD, Engine};
+
+        let body = match &self.body {
+            Some(body) => body,
+            None => return Ok(None),
+        };
+        let bytes = match self.body_format.unwrap_or_default() {
+            BodyFormat::Raw | BodyFormat::Form => body.as_bytes().to_vec(),
+            BodyFormat::Json => {
+                serde_json::from_str::<serde::de::IgnoredAny>(body)
+                    .map_err(|e| ProxyError::InvalidRequest(format!("body isn't valid JSON: {}", e)))?;
+                body.as_bytes().to_vec()
+            }
+            BodyFormat::Base64 => STANDARD
+                .decode(body.trim())
+                .map_err(|e| ProxyError::InvalidRequest(format!("body isn't valid base64: {}", e)))?,
+        };
+        Ok(Some(bytes))
+    }
+
+    /// Approximate bytes this request sends upstream, for cost estimates
+    pub fn egress_bytes(&self) -> u64 {
+        // Query string: "key=value&" per param
+        let query: usize = self.params.iter().map(|(key, value)| key.len() + value.len() + 2).sum();
+        let body = match self.body_bytes() {
+            Ok(Some(bytes)) => query + bytes.len(),
+            _ if self.params_in_query() => query,
+            // Form encoding is about as long as the query string
+            _ if self.body_format == Some(BodyFormat::Form) => query,
+            _ => serde_json::to_vec(&self.params).map(|body| body.len()).unwrap_or(0),
+        };
+        cost::request_bytes(&self.url, &self.headers, body)
+    }
+
+    pub fn preview(&self) -> UpstreamPreview {
+        let mut url = canonical_url::canonicalize(&self.url).unwrap_or_else(|| self.url.clone());
+        if self.params_in_query() {
+            if let Some(mut parsed) = canonical_url::parse(&self.url) {
+                parsed.query_pairs_mut().extend_pairs(&self.params);
+                url = parsed.to_string();
+            }
+        }
+        let mut header_names: Vec<String> = self.headers.keys().cloned().collect();
+        header_names.sort();
+        UpstreamPreview {
+            method: format!("{:?}", self.method).to_uppercase(),
+            url,
+            header_names,
+            envelope: None,
+            bytes: self.egress_bytes(),
+        }
+    }
 }
 
-#[derive(Serialize)]
-pub struct ErrorResponseData {
-    pub status: u16,
-    pub message: String,
+/// An upstream response, received through the shared client or, when the
+/// request has its own redirect policy, the runtime's fetch
+enum Received {
+    Reqwest(reqwest::Response),
+    Fetch { response: worker::Response, url: Url },
 }
 
-#[derive(Serialize)]
-#[serde(untagged)]
-pub enum ApiResponse {
-    Success(ResponseData),
-    Error(ErrorResponseData),
+impl Received {
+    fn status(&self) -> u16 {
+        match self {
+            Received::Reqwest(response) => response.status().as_u16(),
+            Received::Fetch { response, .. } => response.status_code(),
+        }
+    }
+
+    /// Final URL, after any redirects followed
+    fn url(&self) -> &Url {
+        match self {
+            Received::Reqwest(response) => response.url(),
+            Received::Fetch { url, .. } => url,
+        }
+    }
+
+    /// Headers with text values
+    fn headers(&self) -> HashMap<String, String> {
+        match self {
+            Received::Reqwest(response) => response
+                .headers()
+                .iter()
+                .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+                .collect(),
+            Received::Fetch { response, .. } => response.headers().entries().collect(),
+        }
+    }
+
+    fn content_length(&self) -> Option<u64> {
+        match self {
+            Received::Reqwest(response) => response.content_length(),
+            Received::Fetch { response, .. } => {
+                response.headers().get("content-length").ok().flatten().and_then(|length| length.parse().ok())
+            }
+        }
+    }
+
+    async fn bytes(self) -> anyhow::Result<Vec<u8>> {
+        match self {
+            Received::Reqwest(response) => Ok(response.bytes().await?.to_vec()),
+            Received::Fetch { mut response, .. } => response.bytes().await.map_err(|e| anyhow::anyhow!("{}", e)),
+        }
+    }
+
+    fn into_stream(self) -> worker::Result<LocalBoxStream<'static, worker::Result<Vec<u8>>>> {
+        match self {
+            Received::Reqwest(response) => Ok(response
+                .bytes_stream()
+                .map(|chunk| {
+                    chunk
+                        .map(|chunk| chunk.to_vec())
+                        .map_err(|e| worker::Error::RustError(format!("Failed to read response body: {}", e)))
+                })
+                .boxed_local()),
+            Received::Fetch { mut response, .. } => Ok(response.stream()?.boxed_local()),
+        }
+    }
 }
 
 /// Process an HTTP request by forwarding it to the target URL
 pub async fn process_request(data: RequestData, log_level: LogLevel) -> anyhow::Result<ApiResponse> {
-    // Create a client
-    let client = Client::builder()
-        .build()
-        .context("Failed to create HTTP client")?;
+    let client = upstream::client();
 
     // Parse headers
     let mut headers = HeaderMap::new();
@@ -116,23 +474,18 @@ pub async fn process_request(data: RequestData, log_level: LogLevel) -> anyhow::
         headers.insert(header_name, header_value);
     }
 
-    // Add default headers if not already set
-    if !headers.contains_key("user-agent") {
-        headers.insert(
-            HeaderName::from_static("user-agent"),
-            HeaderValue::from_static("ApiProxy/1.0"),
-        );
+    let body = data.body_bytes().map_err(|e| anyhow::anyhow!("{}", e))?;
+    if body.is_some() && !headers.contains_key("content-type") {
+        let format = data.body_format.unwrap_or_default();
+        headers.insert(reqwest::header::CONTENT_TYPE, HeaderValue::from_static(format.content_type()));
     }
 
     // Build and send the request
     let method: ReqwestMethod = data.method.into();
     let mut request = client.request(method, &data.url).headers(headers);
 
-    // Add parameters based on method
-    if matches!(
-        data.method,
-        HttpMethod::Get | HttpMethod::Head | HttpMethod::Delete
-    ) {
+    // Add parameters based on method, or to the query string when the body is given
+    if data.params_in_query() {
         request = request.query(&data.params);
         log_debug!(
             log_level,
@@ -140,6 +493,18 @@ pub async fn process_request(data: RequestData, log_level: LogLevel) -> anyhow::
             data.method,
             data.url
         );
+        if let Some(bytes) = body {
+            log_debug!(log_level, "Request body: {} bytes", bytes.len());
+            request = request.body(bytes);
+        }
+    } else if data.body_format == Some(BodyFormat::Form) {
+        request = request.form(&data.params);
+        log_debug!(
+            log_level,
+            "Sending {:?} request to {} with form body",
+            data.method,
+            data.url
+        );
     } else {
         request = request.json(&data.params);
         log_debug!(
@@ -152,39 +517,141 @@ pub async fn process_request(data: RequestData, log_level: LogLevel) -> anyhow::
 
     log_debug!(log_level, "Request headers: {} custom headers", data.headers.len());
 
+    // Added last, after the URL has been logged
+    if let Some(key) = &data.query_key {
+        request = request.query(&[(key.param.as_str(), key.value.as_str())]);
+    }
+
+    if let Some(timeout) = data.timeout {
+        request = request.timeout(timeout);
+    }
+
+    // Registered stubs answer instead of the network (tests and mock-mode builds), as
+    // the mock registry does for the sandbox tenant
+    if let Some(answer) = crate::stubs::answer(&data.url, None, data.timeout, data.sandbox).await {
+        return answer.map(|mut reply| {
+            if data.skips_body() {
+                reply.body.clear();
+            }
+            reply.into_api_response(data.xml_to_json.as_ref(), log_level)
+        });
+    }
+
     // Send the request
     // Note: Cloudflare Workers enforces a 30-second timeout on all fetch requests
-    let response = request.send().await.context("Failed to send request")?;
+    // The runtime picks the HTTP version and TLS version; neither is exposed or selectable here,
+    // and neither is the certificate it validated
+    // reqwest errors include the URL, which would carry a query-string key
+    let response = match data.redirect {
+        // The runtime would follow every redirect, so the request's own policy needs a fetch of ours
+        Some(policy) => {
+            let request = request.build().context("Failed to build request")?;
+            let (response, url) = redirect::send(request, policy, log_level).await?;
+            Received::Fetch { response, url }
+        }
+        None => Received::Reqwest(
+            request
+                .send()
+                .await
+                .map_err(|e| if data.query_key.is_some() { e.without_url() } else { e })
+                .context("Failed to send request")?,
+        ),
+    };
+    let mut redirected_to = dns_guard::redirect_target(&data.url, response.url());
+    if let (Some(target), Some(key)) = (&mut redirected_to, &data.query_key) {
+        *target = credentials::redact_query_param(target, &key.param);
+    }
 
     // Process the response
-    let status = response.status().as_u16();
-    let status_text = response
-        .status()
-        .canonical_reason()
+    let status = response.status();
+    let status_text = reqwest::StatusCode::from_u16(status)
+        .ok()
+        .and_then(|code| code.canonical_reason())
         .unwrap_or("Unknown Status");
 
     // Log the response status
     log_info!("Response status: {}", status);
 
+    // Interim statuses (100 Continue, 103 Early Hints) are consumed by the runtime;
+    // one surfacing as the final response means the upstream broke the exchange
+    if (100..200).contains(&status) {
+        anyhow::bail!("Upstream answered with interim status {} and no final response", status);
+    }
+
+    // A redirect the caller chose not to follow is theirs to act on, `Location` included
+    if (300..400).contains(&status) && data.redirect.is_some_and(|policy| !policy.follow) {
+        log_debug!(log_level, "Redirect {} returned unfollowed", status);
+        return Ok(ApiResponse::Success(ResponseData {
+            status,
+            headers: response.headers(),
+            body: serde_json::Value::Null,
+            raw_body: None,
+            metadata: None,
+            redirected_to,
+        }));
+    }
+
     // Check if it's a success status (200-299)
     if (200..300).contains(&status) {
         // For success responses, return the full response data
+        let header_map = response.headers();
 
-        // Convert response headers to HashMap
-        let mut header_map = HashMap::new();
-        for (key, value) in response.headers() {
-            if let Ok(v) = value.to_str() {
-                header_map.insert(key.to_string(), v.to_string());
-            }
+        // Status and headers are all a header-only caller gets; the body is never downloaded
+        if data.skips_body() {
+            log_debug!(log_level, "Response body skipped ({} headers)", header_map.len());
+            return Ok(ApiResponse::Success(ResponseData {
+                status,
+                headers: header_map,
+                body: serde_json::Value::Null,
+                raw_body: None,
+                metadata: None,
+                redirected_to,
+            }));
         }
 
         // Try to parse as JSON first
-        let text = response
-            .text()
-            .await
-            .context("Failed to read response body")?;
-        let body = serde_json::from_str::<Value>(&text)
-            .unwrap_or_else(|_| Value::String(text.clone()));
+        let content_type = header_map.get("content-type").cloned();
+        // Refuse a declared oversized body before buffering it, and a chunked one once read
+        limits::check_response(response.content_length(), data.max_response_bytes)?;
+
+        if data.mode == ResponseMode::Stream {
+            log_debug!(log_level, "Streaming response body ({} headers)", header_map.len());
+            let response = passthrough(response, status, &header_map, data.max_response_bytes)
+                .map_err(|e| anyhow::anyhow!("Failed to stream response: {}", e))?;
+            return Ok(ApiResponse::Stream(StreamedResponse {
+                status,
+                response,
+                metadata: None,
+                redirected_to,
+            }));
+        }
+        let bytes = response.bytes().await.context("Failed to read response body")?;
+        limits::check_response(Some(bytes.len() as u64), data.max_response_bytes)?;
+
+        // Binary bodies would be corrupted by decoding; the Content-Type header stays as sent
+        if data.response_format.encodes(content_type.as_deref()) {
+            use base64::{engine::general_purpose::STANDARD, Engine};
+            log_debug!(log_level, "Response body size: {} bytes, returned as base64", bytes.len());
+            return Ok(ApiResponse::Success(ResponseData {
+                status,
+                headers: header_map,
+                body: serde_json::Value::String(STANDARD.encode(&bytes)),
+                raw_body: None,
+                metadata: Some(ResponseMetadata {
+                    body_encoding: Some("base64".to_string()),
+                    ..Default::default()
+                }),
+                redirected_to,
+            }));
+        }
+
+        let decoded = charset::decode(&bytes, content_type.as_deref());
+        if let Some(original) = &decoded.original_charset {
+            log_debug!(log_level, "Transcoded response body from {} to UTF-8", original);
+        }
+        let text = decoded.text;
+        let body = xml::response_body(status, &text, data.xml_to_json.as_ref());
+        let raw_body = xml::raw_body(status, &text, data.xml_to_json.as_ref());
 
         // Log the full response
         log_debug!(log_level, "Response headers: {} headers", header_map.len());
@@ -194,6 +661,12 @@ pub async fn process_request(data: RequestData, log_level: LogLevel) -> anyhow::
             status,
             headers: header_map,
             body,
+            raw_body,
+            metadata: decoded.original_charset.map(|original_charset| ResponseMetadata {
+                original_charset: Some(original_charset),
+                ..Default::default()
+            }),
+            redirected_to,
         }))
     } else {
         // For error responses, return only the status code and message
@@ -202,6 +675,263 @@ pub async fn process_request(data: RequestData, log_level: LogLevel) -> anyhow::
         Ok(ApiResponse::Error(ErrorResponseData {
             status,
             message: status_text.to_string(),
+            metadata: None,
+            redirected_to,
         }))
     }
 }
+
+/// The upstream response with its body streamed through, never held in full
+///
+/// A body that turns out larger than `limit` is cut off with an error once
+/// the limit is passed; the status has been sent by then.
+fn passthrough(
+    response: Received,
+    status: u16,
+    headers: &HashMap<String, String>,
+    limit: Option<u64>,
+) -> worker::Result<worker::Response> {
+    let mut read = 0u64;
+    let body = response.into_stream()?.map(move |chunk| {
+        let chunk = chunk?;
+        read += chunk.len() as u64;
+        limits::check_response(Some(read), limit).map_err(|e| worker::Error::RustError(e.to_string()))?;
+        Ok::<_, worker::Error>(chunk)
+    });
+    let mut passed = worker::Response::from_stream(body)?.with_status(status);
+    for (name, value) in headers {
+        // The runtime has already decoded the body, and frames the new response itself
+        if !matches!(name.as_str(), "content-length" | "content-encoding" | "transfer-encoding" | "connection") {
+            passed.headers_mut().set(name, value)?;
+        }
+    }
+    Ok(passed)
+}
+
+/// `X-Request-Type: http` (also used when the header is missing)
+pub struct HttpHandler;
+
+impl UpstreamRequest for RequestData {
+    fn url(&self) -> &str {
+        &self.url
+    }
+
+    fn url_mut(&mut self) -> &mut String {
+        &mut self.url
+    }
+
+    fn assert_region(&self) -> bool {
+        self.assert_region
+    }
+
+    fn credential(&self) -> Option<&str> {
+        self.credential.as_deref()
+    }
+
+    fn auth(&self) -> Option<&UpstreamAuth> {
+        self.auth.as_ref()
+    }
+
+    fn set_query_key(&mut self, key: QueryKey) {
+        self.query_key = Some(key);
+    }
+
+    fn headers_mut(&mut self) -> &mut HashMap<String, String> {
+        &mut self.headers
+    }
+
+    fn response_schema(&self) -> Option<&ResponseSchemaSpec> {
+        self.response_schema.as_ref()
+    }
+
+    fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    fn set_max_response_bytes(&mut self, limit: u64) {
+        self.max_response_bytes = Some(limit);
+    }
+
+    fn set_sandbox(&mut self, route: SandboxRoute) -> std::result::Result<(), ProxyError> {
+        // An echo endpoint mustn't send the call on to a real upstream
+        if route == SandboxRoute::Echo {
+            self.redirect = Some(RedirectPolicy {
+                follow: false,
+                max: redirect::MAX_REDIRECTS,
+            });
+        }
+        self.sandbox = Some(route);
+        Ok(())
+    }
+
+    fn minimal(&self) -> bool {
+        self.minimal
+    }
+
+    fn set_minimal(&mut self, minimal: bool) {
+        self.minimal = minimal;
+    }
+
+    fn retries(&self) -> Option<&RetryPolicy> {
+        self.retries.as_ref()
+    }
+
+    fn egress_bytes(&self) -> u64 {
+        RequestData::egress_bytes(self)
+    }
+
+    async fn idempotent(&self, _env: &worker::Env, _host: &str) -> bool {
+        self.method.is_idempotent()
+    }
+
+    async fn send(self, log_level: LogLevel) -> anyhow::Result<ApiResponse> {
+        process_request(self, log_level).await
+    }
+}
+
+impl ProxyHandler for HttpHandler {
+    const NAME: &'static str = "HTTP";
+    type Request = RequestData;
+    type Outcome = std::result::Result<ApiResponse, Failure>;
+
+    fn parse(&self, body: &str) -> std::result::Result<RequestData, ProxyError> {
+        from_json(body, Self::NAME)
+    }
+
+    async fn execute(&self, ctx: &ProcessorContext<'_>, mut request: RequestData) -> Self::Outcome {
+        log_debug!(ctx.log_level, "HTTP method: {:?}, url: {}", request.method, request.url);
+        request.validate_body()?;
+        request.validate_mode()?;
+        request.validate_redirect()?;
+        // A header-only answer has no body to check, and must not be cached as the full response
+        if request.headers_only
+            && (request.minimal
+                || request.response_schema.is_some()
+                || request.extract.is_some()
+                || request.transform.is_some()
+                || request.poll.is_some()
+                || request.cache.is_some())
+        {
+            return Err(ProxyError::InvalidRequest(
+                "headers_only can't be combined with minimal, response_schema, extract, transform, poll or cache"
+                    .to_string(),
+            )
+            .into());
+        }
+
+        if request.locale.is_some() {
+            request.apply_locale(&LocaleRules::load(ctx.env).await)?;
+        }
+
+        // Extracted and transformed after the cache, which keeps the whole response for every caller
+        let extract_spec = request.extract.take();
+        let transform_spec = request.transform.take();
+        if let Some(spec) = &extract_spec {
+            extract::validate(spec, request.xml_to_json.as_ref())?;
+        }
+        if let Some(spec) = &transform_spec {
+            spec.validate()?;
+        }
+        let mut outcome = fetch(ctx, request).await;
+        if let Some(spec) = &extract_spec {
+            outcome = extract::apply(spec, outcome).map_err(|failure| *failure);
+        }
+        if let Some(spec) = &transform_spec {
+            outcome = transform::apply(spec, outcome).map_err(|failure| *failure);
+        }
+        outcome
+    }
+
+    fn respond(&self, _ctx: &ProcessorContext<'_>, outcome: Self::Outcome) -> worker::Result<worker::Response> {
+        upstream::respond(Self::NAME, outcome)
+    }
+}
+
+/// The whole response of an HTTP call, from a poll, this shard's cache or the upstream
+async fn fetch(ctx: &ProcessorContext<'_>, request: RequestData) -> std::result::Result<ApiResponse, Failure> {
+    if let Some(spec) = request.poll.clone() {
+        if request.cache.is_some() {
+            return Err(ProxyError::InvalidRequest("poll can't be combined with cache".to_string()).into());
+        }
+        return poll::execute(ctx, request, &spec).await;
+    }
+    let spec = match &request.cache {
+        Some(spec) => spec,
+        None => return upstream::execute(ctx, request).await,
+    };
+    if !matches!(request.method, HttpMethod::Get | HttpMethod::Head) {
+        return Err(ProxyError::InvalidRequest("cache is only allowed for GET and HEAD requests".to_string()).into());
+    }
+    spec.validate()?;
+    let key = cache::http_key(
+        ctx.tenant_id,
+        spec,
+        request.credential.as_deref(),
+        request.auth.as_ref(),
+        &format!("{:?}", request.method).to_uppercase(),
+        &request.url,
+        &request.params,
+        &request.headers,
+    );
+    let ttl = Duration::from_secs(spec.ttl_seconds);
+    cache::execute(ctx, request, &key, ttl).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn request(extra: serde_json::Value) -> RequestData {
+        let mut data = json!({"url": "https://legacy.example.com/orders", "params": {"page": "2"}});
+        data.as_object_mut().unwrap().extend(extra.as_object().unwrap().clone());
+        serde_json::from_value(data).unwrap()
+    }
+
+    #[test]
+    fn test_body_formats() {
+        let xml = request(json!({"body": "<order><id>42</id></order>"}));
+        assert!(xml.validate_body().is_ok());
+        assert_eq!(xml.body_bytes().unwrap().unwrap(), b"<order><id>42</id></order>");
+        assert!(xml.preview().url.ends_with("/orders?page=2"));
+
+        let binary = request(json!({"body": "AAEC/w==", "body_format": "base64"}));
+        assert_eq!(binary.body_bytes().unwrap().unwrap(), vec![0, 1, 2, 255]);
+        assert!(request(json!({"body": "not base64!", "body_format": "base64"})).validate_body().is_err());
+        assert!(request(json!({"body": "{\"id\": ", "body_format": "json"})).validate_body().is_err());
+
+        // Without a body, `form` only changes how params are encoded
+        let form = request(json!({"body_format": "form"}));
+        assert!(form.validate_body().is_ok());
+        assert_eq!(form.body_bytes().unwrap(), None);
+        assert!(form.preview().url.ends_with("/orders"));
+        assert!(request(json!({"body_format": "raw"})).validate_body().is_err());
+        assert!(request(json!({"method": "get", "body": "text"})).validate_body().is_err());
+
+        // Response bodies: binary content types are base64-encoded unless the caller says otherwise
+        assert_eq!(xml.response_format, ResponseFormat::Auto);
+        assert!(xml.response_format.encodes(Some("application/pdf")));
+        assert!(!xml.response_format.encodes(Some("text/xml")));
+        let forced = request(json!({"response_format": "base64"}));
+        assert!(forced.response_format.encodes(Some("application/json")));
+        assert!(!ResponseFormat::Text.encodes(Some("image/png")));
+
+        // A streamed body is never read, so nothing may need it
+        assert!(request(json!({"method": "get", "mode": "stream"})).validate_mode().is_ok());
+        assert!(request(json!({"mode": "stream", "minimal": true})).validate_mode().is_err());
+        assert!(request(json!({"mode": "stream", "cache": {"ttl_seconds": 60}})).validate_mode().is_err());
+
+        // Only POST and PATCH may take effect twice when retried
+        assert!(!xml.method.is_idempotent());
+        assert!(request(json!({"method": "put"})).method.is_idempotent());
+        assert!(!request(json!({"method": "patch"})).method.is_idempotent());
+
+        // An unfollowed redirect is a response of its own, which a cache would hand to other callers
+        let unfollowed = json!({"method": "get", "redirect": {"follow": false}});
+        assert!(request(unfollowed.clone()).validate_redirect().is_ok());
+        let mut cached = unfollowed;
+        cached["cache"] = json!({"ttl_seconds": 60});
+        assert!(request(cached).validate_redirect().is_err());
+        assert!(request(json!({"redirect": {"max": 50}})).validate_redirect().is_err());
+    }
+}
@@ -0,0 +1,190 @@
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use worker::*;
+
+use super::http_handler::RequestData;
+use super::parse::from_json;
+use super::registry::{Failure, ProcessorContext, ProxyHandler};
+use super::response::ApiResponse;
+use super::upstream::{self, UpstreamPreview};
+use crate::error::ProxyError;
+use crate::log_debug;
+
+/// A JSON-RPC 2.0 call (`X-Request-Type: jsonrpc`), POSTed as one request object
+#[derive(Debug, Deserialize)]
+pub struct JsonRpcRequestData {
+    /// The JSON-RPC endpoint, e.g. "https://rpc.example.com/v1"
+    pub url: String,
+    /// Remote method to call, e.g. "numbers.reserve"
+    pub method: String,
+    /// By-position (array) or by-name (object) parameters
+    #[serde(default)]
+    pub params: Option<Value>,
+    /// String or number sent as the request `id`; a fresh UUID when unset
+    #[serde(default = "new_id")]
+    pub id: Value,
+    /// Request headers as key-value pairs
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+}
+
+fn new_id() -> Value {
+    Value::String(uuid::Uuid::new_v4().to_string())
+}
+
+impl JsonRpcRequestData {
+    fn validate(&self) -> std::result::Result<(), ProxyError> {
+        if self.method.is_empty() || self.method.starts_with("rpc.") {
+            return Err(ProxyError::InvalidRequest(format!("JSON-RPC method {:?} is empty or reserved", self.method)));
+        }
+        if !matches!(self.params, None | Some(Value::Array(_)) | Some(Value::Object(_))) {
+            return Err(ProxyError::InvalidRequest("JSON-RPC params must be an array or an object".to_string()));
+        }
+        if !self.id.is_string() && !self.id.is_number() {
+            return Err(ProxyError::InvalidRequest("JSON-RPC id must be a string or a number".to_string()));
+        }
+        Ok(())
+    }
+
+    /// The POST carrying the request object, as an HTTP request
+    fn fetch_request(&self) -> std::result::Result<RequestData, ProxyError> {
+        self.validate()?;
+        let mut envelope = json!({"jsonrpc": "2.0", "method": self.method, "id": self.id});
+        if let Some(params) = &self.params {
+            envelope["params"] = params.clone();
+        }
+        serde_json::from_value(json!({
+            "url": self.url,
+            "method": "post",
+            "headers": self.headers,
+            "body": envelope.to_string(),
+            "body_format": "json",
+        }))
+        .map_err(|e| ProxyError::InvalidRequest(format!("Invalid JSON-RPC request: {}", e)))
+    }
+
+    pub fn preview(&self) -> std::result::Result<UpstreamPreview, ProxyError> {
+        Ok(self.fetch_request()?.preview())
+    }
+
+    /// The id as reported in `metadata.message_id`
+    fn message_id(&self) -> String {
+        match &self.id {
+            Value::String(id) => id.clone(),
+            other => other.to_string(),
+        }
+    }
+}
+
+/// The `result` of a JSON-RPC response to the request with `id`
+///
+/// An `error` object becomes `ProxyError::JsonRpc`. A body that isn't a
+/// JSON-RPC 2.0 response, or answers another id, is an upstream error; a
+/// `null` id is accepted with an `error`, which servers send when they
+/// couldn't read the request's id.
+pub fn response_result(id: &Value, body: &mut Value) -> std::result::Result<Value, ProxyError> {
+    let response = match body {
+        Value::Object(response) if response.get("jsonrpc").and_then(Value::as_str) == Some("2.0") => response,
+        _ => return Err(ProxyError::Upstream("The response isn't a JSON-RPC 2.0 response object".to_string())),
+    };
+    let answered = response.get("id").cloned().unwrap_or_default();
+    let error = response.get_mut("error").map(Value::take);
+    if &answered != id && !(answered.is_null() && error.is_some()) {
+        return Err(ProxyError::Upstream(format!("JSON-RPC response id {} doesn't match request id {}", answered, id)));
+    }
+    match error {
+        Some(Value::Object(mut error)) => Err(ProxyError::JsonRpc {
+            code: error.get("code").and_then(Value::as_i64).unwrap_or_default(),
+            message: match error.remove("message") {
+                Some(Value::String(message)) => message,
+                _ => "no message".to_string(),
+            },
+            data: error.remove("data"),
+        }),
+        Some(other) => Err(ProxyError::Upstream(format!("JSON-RPC error isn't an object: {}", other))),
+        None => response
+            .remove("result")
+            .ok_or_else(|| ProxyError::Upstream("JSON-RPC response has neither result nor error".to_string())),
+    }
+}
+
+pub struct JsonRpcHandler;
+
+impl ProxyHandler for JsonRpcHandler {
+    const NAME: &'static str = "JSON-RPC";
+    type Request = JsonRpcRequestData;
+    type Outcome = std::result::Result<ApiResponse, Failure>;
+
+    fn parse(&self, body: &str) -> std::result::Result<JsonRpcRequestData, ProxyError> {
+        from_json(body, Self::NAME)
+    }
+
+    async fn execute(&self, ctx: &ProcessorContext<'_>, request: JsonRpcRequestData) -> Self::Outcome {
+        log_debug!(ctx.log_level, "JSON-RPC method: {}, id: {}, url: {}", request.method, request.id, request.url);
+        let mut response = upstream::execute(ctx, request.fetch_request()?).await?;
+        response.metadata_mut().message_id = Some(request.message_id());
+        if let ApiResponse::Success(data) = &mut response {
+            match response_result(&request.id, &mut data.body) {
+                Ok(result) => data.body = result,
+                Err(error) => return Err(Failure { error, metadata: data.metadata.take() }),
+            }
+        }
+        Ok(response)
+    }
+
+    fn respond(&self, _ctx: &ProcessorContext<'_>, outcome: Self::Outcome) -> Result<Response> {
+        upstream::respond(Self::NAME, outcome)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_envelope_and_response_checks() {
+        let request: JsonRpcRequestData = serde_json::from_value(json!({
+            "url": "https://rpc.example.com/v1",
+            "method": "numbers.reserve",
+            "params": {"e164": "+15551234567"},
+            "id": 7
+        }))
+        .unwrap();
+        let fetch = request.fetch_request().unwrap();
+        let envelope: Value = serde_json::from_str(fetch.body.as_deref().unwrap()).unwrap();
+        assert_eq!(
+            envelope,
+            json!({"jsonrpc": "2.0", "method": "numbers.reserve", "params": {"e164": "+15551234567"}, "id": 7})
+        );
+        assert_eq!(request.message_id(), "7");
+
+        let id = json!(7);
+        let mut ok = json!({"jsonrpc": "2.0", "id": 7, "result": {"reserved": true}});
+        assert_eq!(response_result(&id, &mut ok).unwrap(), json!({"reserved": true}));
+
+        let mut failed = json!({
+            "jsonrpc": "2.0",
+            "id": 7,
+            "error": {"code": -32602, "message": "Invalid params", "data": {"field": "e164"}}
+        });
+        let error = response_result(&id, &mut failed).unwrap_err();
+        assert_eq!(error.code(), "JSONRPC_ERROR");
+        assert_eq!(error.message(), "JSON-RPC error -32602: Invalid params");
+        assert!(matches!(error, ProxyError::JsonRpc { data: Some(_), .. }));
+
+        // A parse error can't name the id it answers
+        let mut unparsed = json!({"jsonrpc": "2.0", "id": null, "error": {"code": -32700, "message": "Parse error"}});
+        assert!(matches!(response_result(&id, &mut unparsed), Err(ProxyError::JsonRpc { code: -32700, .. })));
+
+        let mut other = json!({"jsonrpc": "2.0", "id": 8, "result": 1});
+        assert!(matches!(response_result(&id, &mut other), Err(ProxyError::Upstream(_))));
+        let mut legacy = json!({"id": 7, "result": 1});
+        assert!(matches!(response_result(&id, &mut legacy), Err(ProxyError::Upstream(_))));
+
+        let invalid = |spec: Value| serde_json::from_value::<JsonRpcRequestData>(spec).unwrap().validate().is_err();
+        assert!(invalid(json!({"url": "https://rpc.example.com", "method": "rpc.discover"})));
+        assert!(invalid(json!({"url": "https://rpc.example.com", "method": "a", "params": "x"})));
+        assert!(invalid(json!({"url": "https://rpc.example.com", "method": "a", "id": null})));
+    }
+}
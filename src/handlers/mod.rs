@@ -1,5 +1,23 @@
+pub mod charset;
+pub mod extract;
+pub mod graphql_handler;
 pub mod http_handler;
+pub mod jsonrpc_handler;
+pub mod pacing;
+pub mod parse;
+pub mod pipeline;
+pub mod redirect;
+pub mod registry;
+pub mod response;
+pub mod saga;
+pub mod schema;
+#[cfg(feature = "soap")]
 pub mod soap_handler;
+pub mod transform;
+pub mod upstream;
+#[cfg(feature = "soap")]
+pub mod wsdl;
+pub mod xml;
+pub mod xmlrpc_handler;
 
-pub use http_handler::{process_request, RequestData};
-pub use soap_handler::{process_soap_request, SoapRequestData};
+pub use response::{ApiResponse, RegionAssertion, ResponseMetadata};
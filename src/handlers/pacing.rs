@@ -0,0 +1,202 @@
+use serde::{Deserialize, Serialize};
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::time::Duration;
+use worker::*;
+
+use super::registry::ProcessorContext;
+use crate::cost::{self, CostEstimate};
+use crate::pacing_report::{self, OutboundCalls};
+use crate::processors::registry;
+use crate::log_error;
+
+/// KV key holding pacing limits for all hosts
+pub const PACING_KEY: &str = "pacing";
+
+/// Token bucket limits for one upstream host
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct PacingLimit {
+    /// Sustained calls per second
+    pub rate_per_second: f64,
+    /// Calls allowed back to back before pacing starts
+    pub burst: u32,
+}
+
+/// Pacing limits keyed by lowercase host
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct PacingConfig(pub HashMap<String, PacingLimit>);
+
+impl PacingConfig {
+    /// Loads limits from the CONFIG KV namespace; no config means no pacing
+    pub async fn load(env: &Env) -> PacingConfig {
+        let kv = match env.kv("CONFIG") {
+            Ok(kv) => kv,
+            Err(_) => return PacingConfig::default(),
+        };
+        match kv.get(PACING_KEY).cache_ttl(60).json::<PacingConfig>().await {
+            Ok(config) => config.unwrap_or_default(),
+            Err(e) => {
+                log_error!("Failed to load pacing config, not pacing: {}", e);
+                PacingConfig::default()
+            }
+        }
+    }
+
+    /// Reads limits bypassing the edge cache, for admin read-modify-write
+    pub async fn load_fresh(env: &Env) -> Result<PacingConfig> {
+        Ok(env.kv("CONFIG")?.get(PACING_KEY).json::<PacingConfig>().await?.unwrap_or_default())
+    }
+
+    pub async fn save(&self, env: &Env) -> Result<()> {
+        env.kv("CONFIG")?
+            .put(PACING_KEY, serde_json::to_string(self)?)?
+            .execute()
+            .await?;
+        Ok(())
+    }
+}
+
+/// Token bucket that goes into debt instead of rejecting
+///
+/// Each call takes a token; when none are left the caller is told how long
+/// to wait for its token, so a burst is spread out rather than refused.
+#[derive(Debug, Clone, Copy)]
+pub struct TokenBucket {
+    tokens: f64,
+    updated_at: u64,
+}
+
+impl TokenBucket {
+    pub fn full(limit: &PacingLimit, now: u64) -> TokenBucket {
+        TokenBucket {
+            tokens: limit.burst as f64,
+            updated_at: now,
+        }
+    }
+
+    /// Takes one token at `now` (epoch millis) and returns how long to wait for it
+    pub fn reserve(&mut self, limit: &PacingLimit, now: u64) -> Duration {
+        let elapsed = now.saturating_sub(self.updated_at) as f64 / 1000.0;
+        self.tokens = (self.tokens + elapsed * limit.rate_per_second).min(limit.burst as f64);
+        self.updated_at = now.max(self.updated_at);
+        self.tokens -= 1.0;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / limit.rate_per_second)
+        }
+    }
+}
+
+/// Bucket state by host, kept in memory for the life of the instance
+pub type Buckets = RefCell<HashMap<String, TokenBucket>>;
+
+/// Shapes outgoing calls of one DO according to the pacing config
+///
+/// Tokens come from the region's coordinator (the shard registry), so a
+/// host's limit holds for the whole region rather than per shard. When the
+/// registry isn't bound or can't be reached, the DO's own bucket is used.
+pub struct Pacer<'a> {
+    config: PacingConfig,
+    buckets: &'a Buckets,
+    outbound: &'a OutboundCalls,
+    env: &'a Env,
+    region_code: &'a str,
+    tenant_id: &'a str,
+    cost: &'a Cell<CostEstimate>,
+    attempt_header: Option<String>,
+}
+
+impl<'a> Pacer<'a> {
+    pub fn new(config: PacingConfig, ctx: &ProcessorContext<'a>) -> Pacer<'a> {
+        Pacer {
+            config,
+            buckets: ctx.pacing,
+            outbound: ctx.outbound,
+            env: ctx.env,
+            region_code: ctx.region_code,
+            tenant_id: ctx.tenant_id,
+            cost: ctx.cost,
+            attempt_header: ctx.attempt_header.clone(),
+        }
+    }
+
+    pub fn env(&self) -> &'a Env {
+        self.env
+    }
+
+    /// Tenant the steps are run for
+    pub fn tenant_id(&self) -> &'a str {
+        self.tenant_id
+    }
+
+    /// Header the steps' upstream calls carry their attempt ID in, if any
+    pub fn attempt_header(&self) -> Option<&str> {
+        self.attempt_header.as_deref()
+    }
+
+    /// Waits for a token for the URL's host; returns the time waited
+    pub async fn acquire(&self, url: &str) -> Duration {
+        let host = match Url::parse(url).ok().and_then(|url| url.host_str().map(str::to_lowercase)) {
+            Some(host) => host,
+            None => return Duration::ZERO,
+        };
+        let limit = match self.config.0.get(&host) {
+            Some(limit) if limit.rate_per_second > 0.0 => *limit,
+            _ => return Duration::ZERO,
+        };
+
+        let wait = match registry::reserve_token(self.env, &self.region_code.to_lowercase(), &host, &limit).await {
+            Some(wait) => {
+                cost::add(self.cost, CostEstimate { do_requests: 1, ..CostEstimate::default() });
+                wait
+            }
+            None => {
+                let now = Date::now().as_millis();
+                self.buckets
+                    .borrow_mut()
+                    .entry(host)
+                    .or_insert_with(|| TokenBucket::full(&limit, now))
+                    .reserve(&limit, now)
+            }
+        };
+        if !wait.is_zero() {
+            Delay::from(wait).await;
+        }
+        wait
+    }
+
+    /// Runs a call to the URL's host, counting it toward the pacing report as
+    /// throttled when `acquire` made it wait
+    pub async fn track<R>(
+        &self,
+        url: &str,
+        waited: Duration,
+        call: impl std::future::Future<Output = R>,
+        status: impl Fn(&R) -> Option<u16>,
+    ) -> R {
+        let host = Url::parse(url).ok().and_then(|url| url.host_str().map(str::to_string)).unwrap_or_default();
+        pacing_report::track(self.env, self.outbound, &host, !waited.is_zero(), call, status).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bucket_spreads_burst_after_capacity() {
+        let limit = PacingLimit {
+            rate_per_second: 2.0,
+            burst: 2,
+        };
+        let mut bucket = TokenBucket::full(&limit, 0);
+
+        let waits: Vec<u128> = (0..5).map(|_| bucket.reserve(&limit, 0).as_millis()).collect();
+        assert_eq!(waits, vec![0, 0, 500, 1000, 1500]);
+
+        // After the backlog drains and the bucket refills, calls are immediate again
+        assert_eq!(bucket.reserve(&limit, 3_000).as_millis(), 0);
+    }
+}
@@ -0,0 +1,176 @@
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::error::ProxyError;
+
+/// Where the README documents the request fields
+const DOCS_BASE: &str = "https://github.com/erimeilis/api-proxy#";
+
+/// Characters of context on each side of the error position
+const SNIPPET_CONTEXT: usize = 40;
+
+/// Where and why a request body failed to parse, returned with `INVALID_REQUEST`
+#[derive(Debug, Clone, Serialize)]
+pub struct JsonErrorDetails {
+    /// 1-based line and column of the error in the body
+    pub line: usize,
+    pub column: usize,
+    /// Path of the offending field (e.g. `params[2]`, `response_schema.enforce`)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    /// The body around the error with string values masked
+    pub snippet: String,
+    /// Documentation of the offending field
+    pub docs: String,
+}
+
+/// Parses a request body, describing failures precisely enough for support tickets
+///
+/// `kind` names the request type in the message (e.g. "SOAP").
+pub fn from_json<T: DeserializeOwned>(text: &str, kind: &str) -> std::result::Result<T, ProxyError> {
+    let deserializer = &mut serde_json::Deserializer::from_str(text);
+    serde_path_to_error::deserialize(deserializer).map_err(|e| {
+        let path = e.path().to_string();
+        let inner = e.into_inner();
+        let message = inner.to_string();
+
+        // A missing field is reported at its parent, so name it explicitly
+        let missing = missing_field(&message);
+        let path = match (path.as_str(), missing) {
+            (".", Some(field)) => Some(field.to_string()),
+            (".", None) => None,
+            (parent, Some(field)) => Some(format!("{}.{}", parent, field)),
+            (path, None) => Some(path.to_string()),
+        };
+
+        ProxyError::InvalidJson {
+            message: format!("Invalid {} JSON: {}", kind, message),
+            details: JsonErrorDetails {
+                line: inner.line(),
+                column: inner.column(),
+                docs: docs_url(path.as_deref(), kind),
+                path,
+                snippet: snippet(text, inner.line(), inner.column()),
+            },
+        }
+    })
+}
+
+fn missing_field(message: &str) -> Option<&str> {
+    let rest = message.strip_prefix("missing field `")?;
+    rest.split('`').next()
+}
+
+/// README section documenting a field, falling back to the request type's schema
+fn docs_url(path: Option<&str>, kind: &str) -> String {
+    let field = path
+        .and_then(|path| path.split(['.', '[']).next())
+        .unwrap_or_default();
+    let anchor = match field {
+        "response_schema" => "response-contracts",
+        "xml_to_json" => "xml-to-json-conversion",
+        "extract" => "response-extraction",
+        "transform" => "response-transformation",
+        "credential" => "upstream-credentials",
+        "locale" => "locale-forwarding",
+        "assert_region" => "region-assertion",
+        "labels" => "request-labels",
+        "steps" if kind == "pipeline" => "request-pipelines",
+        "steps" => "multi-step-transactions-sagas",
+        _ => match kind {
+            "SOAP" => "soap-request-1",
+            "WSDL" => "wsdl-introspection",
+            "GraphQL" => "graphql-requests",
+            "JSON-RPC" => "json-rpc-requests",
+            "XML-RPC" => "xml-rpc-requests",
+            "saga" => "multi-step-transactions-sagas",
+            "pipeline" => "request-pipelines",
+            _ => "http-proxy-request-1",
+        },
+    };
+    format!("{}{}", DOCS_BASE, anchor)
+}
+
+/// The error's line around `column`, with JSON string values replaced by `"***"`
+///
+/// Keys stay readable so the structure is recognizable; values may hold
+/// phone numbers or secrets and never leave the proxy.
+fn snippet(text: &str, line: usize, column: usize) -> String {
+    let source = match text.lines().nth(line.saturating_sub(1)) {
+        Some(source) => source,
+        None => return String::new(),
+    };
+
+    let mut redacted = String::new();
+    let mut error_at = None;
+    let mut chars = source.char_indices().peekable();
+    while let Some((offset, c)) = chars.next() {
+        if error_at.is_none() && offset + 1 >= column {
+            error_at = Some(redacted.chars().count());
+        }
+        if c != '"' {
+            redacted.push(c);
+            continue;
+        }
+
+        // Consume the whole string literal, then decide whether it's a key
+        let mut literal = String::from('"');
+        let mut escaped = false;
+        for (_, c) in chars.by_ref() {
+            literal.push(c);
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == '"' {
+                break;
+            }
+        }
+        let rest = chars.peek().map(|(offset, _)| &source[*offset..]).unwrap_or_default();
+        if rest.trim_start().starts_with(':') {
+            redacted.push_str(&literal);
+        } else {
+            redacted.push_str("\"***\"");
+        }
+    }
+
+    let chars: Vec<char> = redacted.chars().collect();
+    let error_at = error_at.unwrap_or(chars.len());
+    let start = error_at.saturating_sub(SNIPPET_CONTEXT);
+    let end = (error_at + SNIPPET_CONTEXT).min(chars.len());
+    chars[start..end].iter().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+
+    #[derive(Debug, Deserialize)]
+    #[allow(dead_code)]
+    struct Probe {
+        url: String,
+        #[serde(default)]
+        params: Vec<(String, u32)>,
+    }
+
+    #[test]
+    fn test_parse_error_details() {
+        let missing = from_json::<Probe>(r#"{"params": []}"#, "HTTP").unwrap_err();
+        let details = match missing {
+            ProxyError::InvalidJson { details, .. } => details,
+            other => panic!("unexpected error: {:?}", other),
+        };
+        assert_eq!(details.path.as_deref(), Some("url"));
+        assert_eq!(details.docs, "https://github.com/erimeilis/api-proxy#http-proxy-request-1");
+
+        let body = "{\n  \"url\": \"https://secret.example/?token=abc\",\n  \"params\": [[\"to\", \"15551234\"]]\n}";
+        let details = match from_json::<Probe>(body, "HTTP").unwrap_err() {
+            ProxyError::InvalidJson { details, .. } => details,
+            other => panic!("unexpected error: {:?}", other),
+        };
+        assert_eq!(details.line, 3);
+        assert_eq!(details.path.as_deref(), Some("params[0][1]"));
+        assert_eq!(details.snippet, r#"  "params": [["***", "***"]]"#);
+    }
+}
@@ -0,0 +1,347 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::cell::Cell;
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use super::pacing::{Pacer, PacingConfig};
+use super::parse::from_json;
+use super::registry::{ProcessorContext, ProxyHandler};
+use super::response::ApiResponse;
+use super::saga::{run_step, StepRequest, StepStatus};
+use super::upstream::UpstreamPreview;
+use crate::cost::CostEstimate;
+use crate::deadline::Deadline;
+use crate::dns_guard::TargetGuard;
+use crate::end_user::ForwardedEndUser;
+use crate::error::{Fault, ProxyError, FAULT_HEADER};
+use crate::expiry::{self, Expiry};
+use crate::json_path::JsonPath;
+use crate::logger::LogLevel;
+use crate::templates::placeholders;
+use crate::timing::{StepTiming, Timer, TimingReport};
+use crate::{log_debug, log_info};
+
+/// Maximum number of steps in one pipeline
+pub const MAX_PIPELINE_STEPS: usize = 10;
+
+/// A chain of upstream calls (`X-Request-Type: pipeline`), e.g. login → fetch token → call API
+///
+/// Steps run in order inside one processor, so the whole chain stays in the
+/// region. Values a step extracts from its response are available to later
+/// steps as `{{var}}` placeholders. Unlike a saga, nothing is undone when a
+/// step fails; the remaining steps are skipped.
+#[derive(Debug, Deserialize)]
+pub struct PipelineRequestData {
+    pub steps: Vec<PipelineStep>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PipelineStep {
+    pub name: String,
+    #[serde(flatten)]
+    pub request: StepRequest,
+    /// Variables set from the step's response by JSONPath, which sees the
+    /// response as `{status, headers, body}` (e.g. `"token": "$.body.access_token"`)
+    #[serde(default)]
+    pub extract: BTreeMap<String, String>,
+}
+
+#[derive(Serialize)]
+pub struct PipelineStepReport {
+    pub name: String,
+    pub status: StepStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response: Option<ApiResponse>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Values the step extracted
+    #[serde(skip_serializing_if = "Map::is_empty")]
+    pub extracted: Map<String, Value>,
+    /// Time the step waited for its host's pacing token, if any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub paced_ms: Option<u64>,
+}
+
+/// Outcome of the whole pipeline, returned as a single response
+#[derive(Serialize)]
+pub struct PipelineResult {
+    /// Every step succeeded
+    pub completed: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub failed_step: Option<String>,
+    pub steps: Vec<PipelineStepReport>,
+    /// Total time steps waited for pacing tokens
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub paced_ms: Option<u64>,
+    /// Estimated Workers usage of the whole pipeline (with `X-Cost-Estimate: true`)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cost: Option<CostEstimate>,
+    /// When each step ran, and which earlier steps' values it used
+    pub timing: TimingReport,
+}
+
+impl PipelineRequestData {
+    pub fn validate(&self) -> std::result::Result<(), ProxyError> {
+        if self.steps.is_empty() || self.steps.len() > MAX_PIPELINE_STEPS {
+            return Err(ProxyError::InvalidRequest(format!(
+                "A pipeline needs 1-{} steps",
+                MAX_PIPELINE_STEPS
+            )));
+        }
+        let mut names = HashSet::new();
+        let mut vars = HashSet::new();
+        for step in &self.steps {
+            if step.name.is_empty() || !names.insert(step.name.as_str()) {
+                return Err(ProxyError::InvalidRequest(format!(
+                    "Pipeline step names must be unique and non-empty: {:?}",
+                    step.name
+                )));
+            }
+            for (var, path) in &step.extract {
+                if var.is_empty() || var.contains(['.', '{', '}']) || !vars.insert(var.as_str()) {
+                    return Err(ProxyError::InvalidRequest(format!(
+                        "Step {}: extracted variables need unique names without dots or braces: {:?}",
+                        step.name, var
+                    )));
+                }
+                JsonPath::parse(path)
+                    .map_err(|e| ProxyError::InvalidRequest(format!("Step {}: {}", step.name, e)))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The steps' upstream calls; placeholders for extracted values stay unexpanded
+    pub fn preview(&self) -> std::result::Result<Vec<UpstreamPreview>, ProxyError> {
+        self.steps.iter().map(|step| step.request.preview(&step.name)).collect()
+    }
+}
+
+impl PipelineStepReport {
+    fn new(name: &str, status: StepStatus) -> PipelineStepReport {
+        PipelineStepReport {
+            name: name.to_string(),
+            status,
+            response: None,
+            error: None,
+            extracted: Map::new(),
+            paced_ms: None,
+        }
+    }
+}
+
+/// Values `extract` selects from a step's `response`
+///
+/// A path with a wildcard yields an array of its matches; any other path its
+/// single match. A path that matches nothing fails the step.
+fn extract(
+    extract: &BTreeMap<String, String>,
+    response: &Value,
+) -> std::result::Result<Map<String, Value>, String> {
+    let mut values = Map::new();
+    for (var, path) in extract {
+        let path_expr = JsonPath::parse(path)?;
+        let matches = path_expr.select(response);
+        let value = match (path_expr.is_multi(), matches.first()) {
+            (_, None) => return Err(format!("Nothing matched {} for {}", path, var)),
+            (true, _) => Value::Array(matches.into_iter().cloned().collect()),
+            (false, Some(value)) => (*value).clone(),
+        };
+        values.insert(var.clone(), value);
+    }
+    Ok(values)
+}
+
+/// Executes a pipeline, stopping at the first step that fails
+///
+/// Steps are paced, bounded by the deadline and expiry and checked against
+/// the SSRF `guard` as saga steps are.
+#[allow(clippy::too_many_arguments)]
+pub async fn process_pipeline(
+    data: PipelineRequestData,
+    pacer: &Pacer<'_>,
+    deadline: Option<Deadline>,
+    expiry: Option<Expiry>,
+    cost: &Cell<CostEstimate>,
+    guard: &TargetGuard,
+    max_response_bytes: u64,
+    end_user: Option<&ForwardedEndUser>,
+    log_level: LogLevel,
+) -> PipelineResult {
+    // Extracted values, and the step that set each one
+    let mut vars = Map::new();
+    let mut set_by: HashMap<String, String> = HashMap::new();
+    let mut reports: Vec<PipelineStepReport> = Vec::new();
+    let mut failed_step = None;
+    let mut paced_total = 0;
+    let timer = Timer::start();
+    let mut timings: Vec<StepTiming> = Vec::new();
+
+    for step in &data.steps {
+        if failed_step.is_some() {
+            reports.push(PipelineStepReport::new(&step.name, StepStatus::Skipped));
+            continue;
+        }
+        log_debug!(log_level, "Pipeline step {} ({})", step.name, step.request.request_type);
+        let started_ms = timer.elapsed_ms();
+        let (result, paced_ms) = run_step(
+            &step.request,
+            &vars,
+            pacer,
+            deadline,
+            expiry,
+            cost,
+            guard,
+            max_response_bytes,
+            end_user,
+            log_level,
+        )
+        .await;
+        let used: HashSet<&String> = placeholders(&step.request.request)
+            .iter()
+            .filter_map(|name| set_by.get(name.split('.').next().unwrap_or_default()))
+            .collect();
+        let depends_on = data.steps.iter().map(|step| &step.name).filter(|name| used.contains(name)).cloned().collect();
+        timings.push(timer.step(&step.name, started_ms, depends_on));
+        paced_total += paced_ms.unwrap_or(0);
+
+        let mut report = PipelineStepReport::new(&step.name, StepStatus::Succeeded);
+        report.paced_ms = paced_ms;
+        let result = result.map_err(Box::new).and_then(|response| {
+            let value = serde_json::to_value(&response).unwrap_or(Value::Null);
+            match extract(&step.extract, &value) {
+                Ok(extracted) => Ok((response, extracted)),
+                Err(e) => Err(Box::new((Some(response), e))),
+            }
+        });
+        match result {
+            Ok((response, extracted)) => {
+                for (var, value) in &extracted {
+                    vars.insert(var.clone(), value.clone());
+                    set_by.insert(var.clone(), step.name.clone());
+                }
+                report.response = Some(response);
+                report.extracted = extracted;
+            }
+            Err(failure) => {
+                let (response, error) = *failure;
+                log_info!("Pipeline step {} failed: {}", step.name, error);
+                report.status = StepStatus::Failed;
+                report.response = response;
+                report.error = Some(error);
+                failed_step = Some(step.name.clone());
+            }
+        }
+        reports.push(report);
+    }
+
+    PipelineResult {
+        completed: failed_step.is_none(),
+        failed_step,
+        steps: reports,
+        paced_ms: (paced_total > 0).then_some(paced_total),
+        cost: None,
+        timing: timer.report(1, timings),
+    }
+}
+
+/// `X-Request-Type: pipeline`
+pub struct PipelineHandler;
+
+impl ProxyHandler for PipelineHandler {
+    const NAME: &'static str = "pipeline";
+    type Request = PipelineRequestData;
+    type Outcome = PipelineResult;
+
+    fn parse(&self, body: &str) -> std::result::Result<PipelineRequestData, ProxyError> {
+        let pipeline = from_json::<PipelineRequestData>(body, Self::NAME)?;
+        pipeline.validate()?;
+        Ok(pipeline)
+    }
+
+    async fn execute(&self, ctx: &ProcessorContext<'_>, pipeline: PipelineRequestData) -> PipelineResult {
+        let pacer = Pacer::new(PacingConfig::load(ctx.env).await, ctx);
+        let result = process_pipeline(
+            pipeline,
+            &pacer,
+            ctx.deadline,
+            ctx.expiry,
+            ctx.cost,
+            &ctx.guard,
+            ctx.max_response_bytes,
+            ctx.forward_end_user.as_ref(),
+            ctx.log_level,
+        )
+        .await;
+        log_info!("Pipeline finished, completed: {}", result.completed);
+        result
+    }
+
+    fn respond(&self, ctx: &ProcessorContext<'_>, mut result: PipelineResult) -> worker::Result<worker::Response> {
+        let expired = ctx.expiry.filter(|expiry| expiry.check().is_err());
+        let status = if result.completed {
+            200
+        } else if ctx.deadline.is_some_and(|deadline| deadline.is_exceeded()) {
+            504
+        } else if expired.is_some() {
+            410
+        } else {
+            502
+        };
+        result.cost = ctx.include_cost.then(|| ctx.cost.get());
+        let mut response = worker::Response::from_json(&result)?.with_status(status);
+        match (status, expired) {
+            (200, _) => {}
+            // Marked as the edge expects, so the expiry callback is told
+            (410, Some(expiry)) => {
+                response.headers_mut().set(FAULT_HEADER, Fault::Caller.as_str())?;
+                response.headers_mut().set(expiry::EXPIRES_AT_HEADER, &expiry.0.to_string())?;
+            }
+            _ => response.headers_mut().set(FAULT_HEADER, Fault::Upstream.as_str())?,
+        }
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_extract_and_validate() {
+        let pipeline: PipelineRequestData = serde_json::from_value(json!({"steps": [
+            {
+                "name": "login",
+                "request_type": "http",
+                "request": {"url": "https://auth.example/token", "method": "POST"},
+                "extract": {"token": "$.body.access_token", "scopes": "$.body.scopes[*]"}
+            },
+            {
+                "name": "call",
+                "request_type": "http",
+                "request": {"url": "https://api.example/v1/me", "headers": {"Authorization": "Bearer {{token}}"}}
+            }
+        ]}))
+        .unwrap();
+        assert!(pipeline.validate().is_ok());
+
+        let response = json!({"status": 200, "body": {"access_token": "t0k", "scopes": ["read", "write"]}});
+        let values = extract(&pipeline.steps[0].extract, &response).unwrap();
+        assert_eq!(values["token"], json!("t0k"));
+        assert_eq!(values["scopes"], json!(["read", "write"]));
+        assert!(extract(&pipeline.steps[0].extract, &json!({"status": 401, "body": {}})).is_err());
+
+        // Variables are global to the pipeline, so two steps can't set the same one
+        let duplicate: PipelineRequestData = serde_json::from_value(json!({"steps": [
+            {"name": "a", "request": {}, "extract": {"id": "$.body.id"}},
+            {"name": "b", "request": {}, "extract": {"id": "$.body.id"}}
+        ]}))
+        .unwrap();
+        assert!(duplicate.validate().is_err());
+        let bad_path: PipelineRequestData =
+            serde_json::from_value(json!({"steps": [{"name": "a", "request": {}, "extract": {"id": "body.id"}}]}))
+                .unwrap();
+        assert!(bad_path.validate().is_err());
+    }
+}
@@ -0,0 +1,192 @@
+use anyhow::Context as AnyhowContext;
+use futures_util::future::{select, Either};
+use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION, CONTENT_LENGTH, CONTENT_TYPE, COOKIE, USER_AGENT};
+use reqwest::Method;
+use serde::{Deserialize, Serialize};
+use worker::{Delay, Fetch, RequestInit, RequestRedirect, Url};
+
+use super::upstream;
+use crate::error::ProxyError;
+use crate::logger::LogLevel;
+use crate::log_debug;
+
+/// Most redirects a request may follow, the runtime's own limit
+pub const MAX_REDIRECTS: u8 = 20;
+
+/// How a request's upstream redirects are handled
+///
+/// Without one the runtime follows every redirect itself.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct RedirectPolicy {
+    /// Follow redirects; when false, a 3xx is returned with its `Location` header
+    #[serde(default = "default_follow")]
+    pub follow: bool,
+    /// Redirects followed before the request fails
+    #[serde(default = "default_max")]
+    pub max: u8,
+}
+
+fn default_follow() -> bool {
+    true
+}
+
+fn default_max() -> u8 {
+    MAX_REDIRECTS
+}
+
+impl RedirectPolicy {
+    pub fn validate(&self) -> std::result::Result<(), ProxyError> {
+        if self.follow && (self.max == 0 || self.max > MAX_REDIRECTS) {
+            return Err(ProxyError::InvalidRequest(format!("redirect.max must be 1-{}", MAX_REDIRECTS)));
+        }
+        Ok(())
+    }
+}
+
+/// The request a redirect leads to
+#[derive(Debug, PartialEq)]
+pub struct Hop {
+    pub method: Method,
+    pub url: Url,
+    pub keeps_body: bool,
+}
+
+/// Where a response with `status` and `location` sends the request next, if anywhere
+///
+/// 303, and 301 or 302 after a POST, continue as a GET without the body, as
+/// browsers do; 307 and 308 repeat the request as it was.
+pub fn next_hop(status: u16, method: &Method, current: &Url, location: &str) -> Option<Hop> {
+    if !matches!(status, 301 | 302 | 303 | 307 | 308) {
+        return None;
+    }
+    let url = current.join(location).ok()?;
+    let to_get = (status == 303 && *method != Method::HEAD) || (matches!(status, 301 | 302) && *method == Method::POST);
+    Some(Hop {
+        method: if to_get { Method::GET } else { method.clone() },
+        url,
+        keeps_body: !to_get,
+    })
+}
+
+/// Sends `request` through the runtime's fetch, handling redirects under `policy`
+///
+/// Returns the final response and its URL. The request's timeout covers every hop.
+pub async fn send(
+    request: reqwest::Request,
+    policy: RedirectPolicy,
+    log_level: LogLevel,
+) -> anyhow::Result<(worker::Response, Url)> {
+    let timeout = request.timeout().copied();
+    let hops = Box::pin(follow(request, policy, log_level));
+    match timeout {
+        Some(timeout) => match select(hops, Box::pin(Delay::from(timeout))).await {
+            Either::Left((result, _)) => result,
+            Either::Right(_) => anyhow::bail!("Upstream request timed out after {}ms", timeout.as_millis()),
+        },
+        None => hops.await,
+    }
+}
+
+async fn follow(
+    request: reqwest::Request,
+    policy: RedirectPolicy,
+    log_level: LogLevel,
+) -> anyhow::Result<(worker::Response, Url)> {
+    let mut method = request.method().clone();
+    let mut url = request.url().clone();
+    let mut headers = request.headers().clone();
+    let mut body = request.body().and_then(|body| body.as_bytes()).map(<[u8]>::to_vec);
+    // The shared client adds its defaults on send, which this path bypasses
+    headers
+        .entry(USER_AGENT)
+        .or_insert(HeaderValue::from_static(upstream::DEFAULT_USER_AGENT));
+
+    let mut followed = 0;
+    loop {
+        let response = fetch(&method, &url, &headers, body.as_deref()).await?;
+        let hop = match (policy.follow, response.headers().get("location").ok().flatten()) {
+            (true, Some(location)) => next_hop(response.status_code(), &method, &url, &location),
+            _ => None,
+        };
+        let Some(hop) = hop else {
+            return Ok((response, url));
+        };
+        if followed == policy.max {
+            anyhow::bail!("Upstream redirected more than {} times", policy.max);
+        }
+        followed += 1;
+        // Only the origin is logged, since the URL may carry a query-string key
+        log_debug!(
+            log_level,
+            "Following {} redirect to {}",
+            response.status_code(),
+            hop.url.origin().ascii_serialization()
+        );
+
+        // Credentials aren't handed to another origin
+        if hop.url.origin() != url.origin() {
+            headers.remove(AUTHORIZATION);
+            headers.remove(COOKIE);
+        }
+        if !hop.keeps_body {
+            body = None;
+            headers.remove(CONTENT_TYPE);
+            headers.remove(CONTENT_LENGTH);
+        }
+        method = hop.method;
+        url = hop.url;
+    }
+}
+
+/// One upstream call that returns a redirect as is
+async fn fetch(
+    method: &Method,
+    url: &Url,
+    headers: &HeaderMap,
+    body: Option<&[u8]>,
+) -> anyhow::Result<worker::Response> {
+    let js_headers = worker::Headers::new();
+    for (name, value) in headers {
+        let value = value.to_str().context(format!("Invalid header value for {}", name))?;
+        js_headers
+            .append(name.as_str(), value)
+            .map_err(|e| anyhow::anyhow!("Invalid header {}: {}", name, e))?;
+    }
+    let mut init = RequestInit::new();
+    init.method = worker::Method::from(method.as_str().to_string());
+    init.headers = js_headers;
+    init.body = body.map(|body| worker::js_sys::Uint8Array::from(body).into());
+    init.redirect = RequestRedirect::Manual;
+    let request = worker::Request::new_with_init(url.as_str(), &init)
+        .map_err(|e| anyhow::anyhow!("Failed to build request: {}", e))?;
+    Fetch::Request(request)
+        .send()
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to send request: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redirect_hops() {
+        let current = Url::parse("https://billing.example/invoices/42").unwrap();
+        let hop = next_hop(302, &Method::POST, &current, "/login?next=%2Finvoices%2F42").unwrap();
+        assert_eq!(hop.method, Method::GET);
+        assert_eq!(hop.url.as_str(), "https://billing.example/login?next=%2Finvoices%2F42");
+        assert!(!hop.keeps_body);
+
+        let hop = next_hop(307, &Method::POST, &current, "https://portal.example/invoices/42").unwrap();
+        assert_eq!((hop.method, hop.keeps_body), (Method::POST, true));
+        assert_eq!(next_hop(303, &Method::PUT, &current, "/done").unwrap().method, Method::GET);
+        assert!(next_hop(304, &Method::GET, &current, "/cached").is_none());
+        assert!(next_hop(300, &Method::GET, &current, "/choices").is_none());
+
+        let policy: RedirectPolicy = serde_json::from_str(r#"{"follow": false}"#).unwrap();
+        assert_eq!(policy.max, MAX_REDIRECTS);
+        assert!(policy.validate().is_ok());
+        assert!(RedirectPolicy { follow: true, max: 0 }.validate().is_err());
+        assert!(RedirectPolicy { follow: true, max: 21 }.validate().is_err());
+    }
+}
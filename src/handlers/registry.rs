@@ -0,0 +1,199 @@
+use std::cell::Cell;
+use worker::*;
+
+use super::graphql_handler::GraphQlHandler;
+use super::http_handler::HttpHandler;
+use super::jsonrpc_handler::JsonRpcHandler;
+use super::pacing;
+use super::pipeline::PipelineHandler;
+use super::response::ResponseMetadata;
+use super::saga::SagaHandler;
+#[cfg(feature = "soap")]
+use super::soap_handler::SoapHandler;
+use super::upstream::UpstreamPreview;
+#[cfg(feature = "soap")]
+use super::wsdl::WsdlHandler;
+use super::xmlrpc_handler::XmlRpcHandler;
+use crate::cache;
+use crate::cost::{self, CostEstimate, COST_HEADER};
+use crate::deadline::Deadline;
+use crate::dns_guard::TargetGuard;
+use crate::end_user::ForwardedEndUser;
+use crate::error::ProxyError;
+use crate::expiry::Expiry;
+use crate::incidents::ErrorRates;
+use crate::logger::{self, LogLevel};
+use crate::pacing_report::OutboundCalls;
+use crate::retry::{RetryBudgets, TenantBudget};
+use crate::slo;
+use crate::{log_error, log_info};
+
+/// What a handler may use from the Durable Object processing the request
+pub struct ProcessorContext<'a> {
+    pub env: &'a Env,
+    pub state: &'a State,
+    /// Region code of the processor, e.g. "WNAM"
+    pub region_code: &'static str,
+    /// Datacenter the DO actually runs in
+    pub actual_colo: &'a str,
+    /// Tenant id forwarded by the edge ("root" for the shared token)
+    pub tenant_id: &'a str,
+    pub deadline: Option<Deadline>,
+    /// The caller's `X-Expires-At`; checked again before each upstream call and retry
+    pub expiry: Option<Expiry>,
+    pub log_level: LogLevel,
+    pub pacing: &'a pacing::Buckets,
+    /// Calls per host counted for the pacing report, shared by the DO's requests
+    pub outbound: &'a OutboundCalls,
+    pub retry_budgets: &'a RetryBudgets,
+    /// The tenant's region-wide retry budget, when it has one
+    pub tenant_budget: Option<TenantBudget>,
+    pub error_rates: &'a ErrorRates,
+    /// Cache keys being refetched, shared by the DO's requests
+    pub cache_refreshes: &'a cache::Refreshes,
+    /// Cost of the request so far, reported in `COST_HEADER`
+    pub cost: &'a Cell<CostEstimate>,
+    /// Whether the caller asked for the cost in the response metadata
+    pub include_cost: bool,
+    /// SSRF checks for upstream targets (`DNS_REBINDING_CHECK`, `SSRF_BLOCKLIST`)
+    pub guard: TargetGuard,
+    /// Largest upstream response body read (`MAX_RESPONSE_BYTES`)
+    pub max_response_bytes: u64,
+    /// The `X-End-User` context, when the tenant forwards it upstream
+    pub forward_end_user: Option<ForwardedEndUser>,
+    /// Header each upstream attempt carries its attempt ID in (`ATTEMPT_HEADER`)
+    pub attempt_header: Option<String>,
+}
+
+impl ProcessorContext<'_> {
+    pub fn add_cost(&self, cost: CostEstimate) {
+        cost::add(self.cost, cost);
+    }
+}
+
+/// A proxy error together with the metadata gathered before it happened
+pub struct Failure {
+    pub error: ProxyError,
+    pub metadata: Option<ResponseMetadata>,
+}
+
+impl Failure {
+    pub fn new(error: ProxyError, metadata: ResponseMetadata) -> Failure {
+        Failure {
+            error,
+            metadata: if metadata.is_empty() { None } else { Some(metadata) },
+        }
+    }
+}
+
+impl From<ProxyError> for Failure {
+    fn from(error: ProxyError) -> Self {
+        Failure { error, metadata: None }
+    }
+}
+
+/// A request protocol selected by `X-Request-Type`
+///
+/// New protocols implement this and add a line to `dispatch`; the processor
+/// macro stays untouched.
+// Workers run on a single thread, so handler futures don't need to be Send
+#[allow(async_fn_in_trait)]
+pub trait ProxyHandler {
+    /// Name used in logs, e.g. "SOAP"
+    const NAME: &'static str;
+    type Request;
+    type Outcome;
+
+    fn parse(&self, body: &str) -> std::result::Result<Self::Request, ProxyError>;
+
+    async fn execute(&self, ctx: &ProcessorContext<'_>, request: Self::Request) -> Self::Outcome;
+
+    fn respond(&self, ctx: &ProcessorContext<'_>, outcome: Self::Outcome) -> Result<Response>;
+}
+
+/// `X-Request-Type` values this build handles
+pub const REQUEST_TYPES: &[&str] = &[
+    "http",
+    #[cfg(feature = "soap")]
+    "soap",
+    #[cfg(feature = "soap")]
+    "wsdl",
+    "graphql",
+    "jsonrpc",
+    "xmlrpc",
+    "saga",
+    "pipeline",
+    // Opened with an Upgrade request and bridged by the processor, never dispatched
+    "websocket",
+];
+
+/// Why a `websocket` request that reached a handler is refused
+const WEBSOCKET_WITHOUT_UPGRADE: &str = "WebSocket connections are opened with an Upgrade: websocket request";
+
+/// Runs a request through the handler registered for its `X-Request-Type`
+///
+/// A missing or unknown type is handled as HTTP, as it always has been.
+/// Types whose Cargo feature is off are rejected rather than sent as HTTP.
+/// The response carries the request's cost in `COST_HEADER`.
+pub async fn dispatch(request_type: &str, ctx: &ProcessorContext<'_>, body: &str) -> Result<Response> {
+    let mut response = match request_type {
+        "saga" => run(&SagaHandler, ctx, body).await,
+        "pipeline" => run(&PipelineHandler, ctx, body).await,
+        "graphql" => run(&GraphQlHandler, ctx, body).await,
+        "jsonrpc" => run(&JsonRpcHandler, ctx, body).await,
+        "xmlrpc" => run(&XmlRpcHandler, ctx, body).await,
+        "websocket" => ProxyError::InvalidRequest(WEBSOCKET_WITHOUT_UPGRADE.to_string()).to_response(None),
+        #[cfg(feature = "soap")]
+        "soap" => run(&SoapHandler, ctx, body).await,
+        #[cfg(feature = "soap")]
+        "wsdl" => run(&WsdlHandler, ctx, body).await,
+        #[cfg(not(feature = "soap"))]
+        "soap" | "wsdl" => not_compiled(request_type),
+        _ => run(&HttpHandler, ctx, body).await,
+    }?;
+    response.headers_mut().set(COST_HEADER, &ctx.cost.get().to_header())?;
+    response.headers_mut().set(slo::UPSTREAM_MS_HEADER, &logger::upstream_ms().to_string())?;
+    Ok(response)
+}
+
+/// Parses a request as its handler would and describes its upstream calls,
+/// without sending anything (`validate_only`)
+pub fn preview(request_type: &str, body: &str) -> std::result::Result<Vec<UpstreamPreview>, ProxyError> {
+    match request_type {
+        "saga" => SagaHandler.parse(body)?.preview(),
+        "pipeline" => PipelineHandler.parse(body)?.preview(),
+        "graphql" => Ok(vec![GraphQlHandler.parse(body)?.preview()?]),
+        "jsonrpc" => Ok(vec![JsonRpcHandler.parse(body)?.preview()?]),
+        "xmlrpc" => Ok(vec![XmlRpcHandler.parse(body)?.preview()?]),
+        "websocket" => Err(ProxyError::InvalidRequest(WEBSOCKET_WITHOUT_UPGRADE.to_string())),
+        #[cfg(feature = "soap")]
+        "soap" => Ok(vec![SoapHandler.parse(body)?.preview()]),
+        #[cfg(feature = "soap")]
+        "wsdl" => Ok(vec![WsdlHandler.parse(body)?.preview()?]),
+        #[cfg(not(feature = "soap"))]
+        "soap" | "wsdl" => Err(ProxyError::InvalidRequest(format!(
+            "Request type {} is not enabled in this build",
+            request_type
+        ))),
+        _ => Ok(vec![HttpHandler.parse(body)?.preview()]),
+    }
+}
+
+#[cfg(not(feature = "soap"))]
+fn not_compiled(request_type: &str) -> Result<Response> {
+    ProxyError::InvalidRequest(format!("Request type {} is not enabled in this build", request_type)).to_response(None)
+}
+
+async fn run<H: ProxyHandler>(handler: &H, ctx: &ProcessorContext<'_>, body: &str) -> Result<Response> {
+    log_info!("Processing {} request", H::NAME);
+
+    let request = match handler.parse(body) {
+        Ok(request) => request,
+        Err(e) => {
+            log_error!("Failed to parse {} request JSON: {}", H::NAME, e);
+            return e.to_response(None);
+        }
+    };
+    let outcome = handler.execute(ctx, request).await;
+    handler.respond(ctx, outcome)
+}
@@ -0,0 +1,258 @@
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use worker::Response;
+
+use super::schema::SchemaValidation;
+use super::xml::RawBody;
+use crate::cache::CacheUsage;
+use crate::cost::CostEstimate;
+use crate::credentials::CredentialUsage;
+use crate::poll::PollUsage;
+use crate::retry::RetryUsage;
+
+#[derive(Serialize)]
+pub struct ResponseData {
+    pub status: u16,
+    pub headers: HashMap<String, String>,
+    pub body: Value,
+    /// The XML `body` was converted from, with `xml_to_json.raw_body`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub raw_body: Option<RawBody>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<ResponseMetadata>,
+    /// Final URL when the upstream redirected to another origin
+    #[serde(skip)]
+    pub redirected_to: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct ErrorResponseData {
+    pub status: u16,
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<ResponseMetadata>,
+    /// Final URL when the upstream redirected to another origin
+    #[serde(skip)]
+    pub redirected_to: Option<String>,
+}
+
+/// A successful response with everything but the upstream body stripped
+#[derive(Serialize)]
+pub struct MinimalResponseData {
+    pub status: u16,
+    pub body: Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub raw_body: Option<RawBody>,
+    /// Still available to the proxy itself, but never returned
+    #[serde(skip)]
+    pub metadata: Option<ResponseMetadata>,
+}
+
+/// Response header marking a passed-through upstream body (`mode: "stream"`), as opposed to the JSON envelope
+pub const STREAM_HEADER: &str = "X-Proxy-Stream";
+
+/// Response header carrying the metadata of a streamed response, as JSON
+pub const METADATA_HEADER: &str = "X-Proxy-Metadata";
+
+/// A successful response whose upstream body is passed on unread
+pub struct StreamedResponse {
+    pub status: u16,
+    /// Status, upstream headers and the body stream
+    pub response: Response,
+    pub metadata: Option<ResponseMetadata>,
+    /// Final URL when the upstream redirected to another origin
+    pub redirected_to: Option<String>,
+}
+
+impl StreamedResponse {
+    /// The upstream response, with the stream marker and any metadata as headers
+    pub fn into_response(self) -> worker::Result<Response> {
+        let mut response = self.response;
+        response.headers_mut().set(STREAM_HEADER, "true")?;
+        if let Some(metadata) = self.metadata.filter(|metadata| !metadata.is_empty()) {
+            response.headers_mut().set(METADATA_HEADER, &serde_json::to_string(&metadata)?)?;
+        }
+        Ok(response)
+    }
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+pub enum ApiResponse {
+    Success(ResponseData),
+    Error(ErrorResponseData),
+    Minimal(MinimalResponseData),
+    /// Never serialized; `upstream::respond` returns it as is
+    #[serde(skip_serializing)]
+    Stream(StreamedResponse),
+}
+
+impl ApiResponse {
+    /// Upstream HTTP status of the response
+    pub fn status(&self) -> u16 {
+        match self {
+            ApiResponse::Success(data) => data.status,
+            ApiResponse::Error(data) => data.status,
+            ApiResponse::Minimal(data) => data.status,
+            ApiResponse::Stream(data) => data.status,
+        }
+    }
+
+    /// Final URL when the upstream redirected to another origin
+    pub fn redirected_to(&self) -> Option<&str> {
+        match self {
+            ApiResponse::Success(data) => data.redirected_to.as_deref(),
+            ApiResponse::Error(data) => data.redirected_to.as_deref(),
+            ApiResponse::Minimal(_) => None,
+            ApiResponse::Stream(data) => data.redirected_to.as_deref(),
+        }
+    }
+
+    /// Returns the response metadata block, creating an empty one if needed
+    pub fn metadata_mut(&mut self) -> &mut ResponseMetadata {
+        let metadata = match self {
+            ApiResponse::Success(data) => &mut data.metadata,
+            ApiResponse::Error(data) => &mut data.metadata,
+            ApiResponse::Minimal(data) => &mut data.metadata,
+            ApiResponse::Stream(data) => &mut data.metadata,
+        };
+        metadata.get_or_insert_with(ResponseMetadata::default)
+    }
+
+    /// Drops headers and metadata from a successful response (`minimal: true`)
+    ///
+    /// Errors keep their full envelope, since that's when callers need the details.
+    pub fn into_minimal(self) -> ApiResponse {
+        match self {
+            ApiResponse::Success(data) => ApiResponse::Minimal(MinimalResponseData {
+                status: data.status,
+                body: data.body,
+                raw_body: data.raw_body,
+                metadata: data.metadata,
+            }),
+            other => other,
+        }
+    }
+}
+
+/// Optional proxy-side information attached to a response
+///
+/// Every field is skipped when unset, so responses stay unchanged for
+/// callers that don't opt into any metadata-producing feature.
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct ResponseMetadata {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub region_assertion: Option<RegionAssertion>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub schema_validation: Option<SchemaValidation>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub credential: Option<CredentialUsage>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retry: Option<RetryUsage>,
+    /// Charset the upstream body was transcoded from to UTF-8 (e.g. "windows-1251")
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub original_charset: Option<String>,
+    /// Estimated Workers usage of the request (with `X-Cost-Estimate: true`)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cost: Option<CostEstimate>,
+    /// Whether the response came from the processor's result cache
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cache: Option<CacheUsage>,
+    /// Whether a polled response changed since the last poll
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub poll: Option<PollUsage>,
+    /// URL sent upstream, when normalizing changed the one requested
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub canonical_url: Option<String>,
+    /// WS-Addressing `MessageID` or JSON-RPC `id` sent upstream, for correlating with its logs
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message_id: Option<String>,
+    /// "base64" when the body is the upstream's raw bytes, base64-encoded
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub body_encoding: Option<String>,
+}
+
+impl ResponseMetadata {
+    /// True when no feature has populated any field
+    pub fn is_empty(&self) -> bool {
+        self.region_assertion.is_none()
+            && self.schema_validation.is_none()
+            && self.credential.is_none()
+            && self.retry.is_none()
+            && self.original_charset.is_none()
+            && self.cost.is_none()
+            && self.cache.is_none()
+            && self.poll.is_none()
+            && self.canonical_url.is_none()
+            && self.message_id.is_none()
+            && self.body_encoding.is_none()
+    }
+}
+
+/// Result of checking the DO's actual datacenter against its expected region
+#[derive(Debug, Clone, Serialize)]
+pub struct RegionAssertion {
+    pub expected: String,
+    pub actual_colo: String,
+    pub actual_region: Option<String>,
+    pub passed: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixture(json: &str) -> Value {
+        serde_json::from_str(json).unwrap()
+    }
+
+    fn headers() -> HashMap<String, String> {
+        HashMap::from([("content-type".to_string(), "text/xml; charset=ISO-8859-1".to_string())])
+    }
+
+    #[test]
+    fn test_envelopes_keep_the_shape_laravel_reads() {
+        let body = Value::String("<?xml version=\"1.0\"?><Result>OK</Result>".to_string());
+        let success = ApiResponse::Success(ResponseData {
+            status: 200,
+            headers: headers(),
+            body: body.clone(),
+            raw_body: None,
+            metadata: None,
+            redirected_to: Some("https://elsewhere.example/".to_string()),
+        });
+        assert_eq!(
+            serde_json::to_value(&success).unwrap(),
+            fixture(include_str!("../../tests/fixtures/laravel/response_success.json"))
+        );
+
+        let error = ApiResponse::Error(ErrorResponseData {
+            status: 500,
+            message: "Internal Server Error".to_string(),
+            metadata: None,
+            redirected_to: None,
+        });
+        assert_eq!(
+            serde_json::to_value(&error).unwrap(),
+            fixture(include_str!("../../tests/fixtures/laravel/response_error.json"))
+        );
+
+        let minimal = ApiResponse::Success(ResponseData {
+            status: 200,
+            headers: headers(),
+            body,
+            raw_body: None,
+            metadata: Some(ResponseMetadata {
+                original_charset: Some("iso-8859-1".to_string()),
+                ..Default::default()
+            }),
+            redirected_to: None,
+        })
+        .into_minimal();
+        assert_eq!(
+            serde_json::to_value(&minimal).unwrap(),
+            fixture(include_str!("../../tests/fixtures/laravel/response_minimal.json"))
+        );
+    }
+}
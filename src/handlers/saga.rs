@@ -0,0 +1,546 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::cell::Cell;
+use std::collections::HashSet;
+use std::time::Duration;
+
+use super::http_handler::{process_request, RequestData, ResponseMode};
+use super::pacing::{Pacer, PacingConfig};
+use super::parse::from_json;
+use super::registry::{ProcessorContext, ProxyHandler};
+use super::response::ApiResponse;
+use super::upstream::{UpstreamPreview, UpstreamRequest};
+#[cfg(feature = "soap")]
+use super::soap_handler::{process_soap_request, SoapRequestData};
+use crate::cost::{self, CostEstimate};
+use crate::deadline::{self, Deadline};
+use crate::dns_guard::TargetGuard;
+use crate::end_user::ForwardedEndUser;
+use crate::error::{Fault, ProxyError, FAULT_HEADER};
+use crate::expiry::{self, Expiry};
+use crate::locale::LocaleRules;
+use crate::logger::{self, LogLevel};
+#[cfg(feature = "soap")]
+use crate::soap_allowlist;
+use crate::templates::{expand, placeholders};
+use crate::timing::{StepTiming, Timer, TimingReport};
+use crate::{log_debug, log_error, log_info};
+
+/// Maximum number of steps in one saga
+pub const MAX_SAGA_STEPS: usize = 10;
+
+/// A multi-step transaction (`X-Request-Type: saga`)
+///
+/// Steps run in order. When a step fails, the compensations of the steps
+/// that already succeeded run in reverse order, so a half-finished flow
+/// doesn't leave dangling state (e.g. number reservations) upstream.
+#[derive(Debug, Deserialize)]
+pub struct SagaRequestData {
+    pub steps: Vec<SagaStep>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SagaStep {
+    /// Unique step name; later steps and compensations reference its
+    /// response as `{{name.body...}}`
+    pub name: String,
+    #[serde(flatten)]
+    pub request: StepRequest,
+    /// Request that undoes this step if a later step fails
+    #[serde(default)]
+    pub compensation: Option<StepRequest>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct StepRequest {
+    /// "soap" (default) or "http"
+    #[serde(default = "default_step_type")]
+    pub request_type: String,
+    /// A `SoapRequestData` or `RequestData` body, with optional placeholders
+    pub request: Value,
+}
+
+fn default_step_type() -> String {
+    "soap".to_string()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SagaOutcome {
+    /// Every step succeeded
+    Committed,
+    /// A step failed and every completed step was compensated
+    Compensated,
+    /// A step failed and at least one compensation failed too; needs manual cleanup
+    CompensationFailed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StepStatus {
+    Succeeded,
+    Failed,
+    Compensated,
+    CompensationFailed,
+    /// Not run because an earlier step failed
+    Skipped,
+}
+
+#[derive(Serialize)]
+pub struct StepReport {
+    pub name: String,
+    pub status: StepStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response: Option<ApiResponse>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub compensation: Option<ApiResponse>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub compensation_error: Option<String>,
+    /// Time the step waited for its host's pacing token, if any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub paced_ms: Option<u64>,
+}
+
+/// Outcome of the whole saga, returned as a single response
+#[derive(Serialize)]
+pub struct SagaResult {
+    pub outcome: SagaOutcome,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub failed_step: Option<String>,
+    pub steps: Vec<StepReport>,
+    /// Total time steps and compensations waited for pacing tokens
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub paced_ms: Option<u64>,
+    /// Estimated Workers usage of the whole saga (with `X-Cost-Estimate: true`)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cost: Option<CostEstimate>,
+    /// When each step and compensation ran, and which earlier steps it used
+    pub timing: TimingReport,
+}
+
+impl SagaRequestData {
+    pub fn validate(&self) -> std::result::Result<(), ProxyError> {
+        if self.steps.is_empty() || self.steps.len() > MAX_SAGA_STEPS {
+            return Err(ProxyError::InvalidRequest(format!(
+                "A saga needs 1-{} steps",
+                MAX_SAGA_STEPS
+            )));
+        }
+        let mut names = HashSet::new();
+        for step in &self.steps {
+            if step.name.is_empty() || !names.insert(step.name.as_str()) {
+                return Err(ProxyError::InvalidRequest(format!(
+                    "Saga step names must be unique and non-empty: {:?}",
+                    step.name
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// The steps' upstream calls; placeholders referring to earlier steps stay unexpanded
+    pub fn preview(&self) -> std::result::Result<Vec<UpstreamPreview>, ProxyError> {
+        self.steps.iter().map(|step| step.request.preview(&step.name)).collect()
+    }
+}
+
+impl StepRequest {
+    /// The step's upstream call, with its placeholders unexpanded
+    pub fn preview(&self, name: &str) -> std::result::Result<UpstreamPreview, ProxyError> {
+        let request = self.request.clone();
+        let invalid = |e: serde_json::Error| ProxyError::InvalidRequest(format!("Step {}: {}", name, e));
+        match self.request_type.to_lowercase().as_str() {
+            #[cfg(feature = "soap")]
+            "soap" => Ok(serde_json::from_value::<SoapRequestData>(request).map_err(invalid)?.preview()),
+            "http" => Ok(serde_json::from_value::<RequestData>(request).map_err(invalid)?.preview()),
+            other => Err(ProxyError::InvalidRequest(format!(
+                "Step {}: unsupported request_type {}",
+                name, other
+            ))),
+        }
+    }
+}
+
+impl StepReport {
+    fn new(name: &str, status: StepStatus) -> StepReport {
+        StepReport {
+            name: name.to_string(),
+            status,
+            response: None,
+            error: None,
+            compensation: None,
+            compensation_error: None,
+            paced_ms: None,
+        }
+    }
+}
+
+/// A step's response, or the error with the upstream response if there was one
+pub type StepResult = std::result::Result<ApiResponse, (Option<ApiResponse>, String)>;
+
+/// Steps among `earlier` whose responses `request` references in placeholders
+fn dependencies(request: &StepRequest, earlier: &[SagaStep]) -> Vec<String> {
+    let used = placeholders(&request.request);
+    earlier
+        .iter()
+        .filter(|step| {
+            let prefix = format!("{}.", step.name);
+            used.iter().any(|name| *name == step.name || name.starts_with(&prefix))
+        })
+        .map(|step| step.name.clone())
+        .collect()
+}
+
+/// Runs one step request; upstream non-2xx statuses count as failures
+///
+/// Waits for the upstream host's pacing token first and returns the time waited;
+/// the call counts toward the host's pacing report.
+/// With a deadline, the step fails once it has passed and the upstream call
+/// only gets the remaining budget. Past the expiry, the step isn't sent.
+/// The upstream call is added to `cost`.
+/// The target and any cross-origin redirect must pass the `guard`'s SSRF
+/// checks. Responses over `max_response_bytes` fail the step. The end-user
+/// context is added to the step's headers when the tenant forwards it, and a
+/// `locale` is sent where the host's locale rule says. SOAP steps must be
+/// permitted by the SOAP allowlist.
+#[allow(clippy::too_many_arguments)]
+pub async fn run_step(
+    step: &StepRequest,
+    vars: &Map<String, Value>,
+    pacer: &Pacer<'_>,
+    deadline: Option<Deadline>,
+    expiry: Option<Expiry>,
+    cost: &Cell<CostEstimate>,
+    guard: &TargetGuard,
+    max_response_bytes: u64,
+    end_user: Option<&ForwardedEndUser>,
+    log_level: LogLevel,
+) -> (StepResult, Option<u64>) {
+    let mut request = match expand(&step.request, vars) {
+        Ok(request) => request,
+        Err(e) => return (Err((None, e.to_string())), None),
+    };
+    // Refused before pacing, so the step doesn't wait for a token it won't use
+    #[cfg(feature = "soap")]
+    if step.request_type.eq_ignore_ascii_case("soap") {
+        let field = |name: &str| request.get(name).and_then(Value::as_str).unwrap_or_default();
+        if let Err(e) = soap_allowlist::check(pacer.env(), pacer.tenant_id(), field("url"), field("action")).await {
+            return (Err((None, e.message())), None);
+        }
+    }
+    let started_at = worker::Date::now().as_millis();
+
+    let waited = match request.get("url").and_then(Value::as_str) {
+        Some(url) => pacer.acquire(url).await,
+        None => std::time::Duration::ZERO,
+    };
+    let paced_ms = (!waited.is_zero()).then_some(waited.as_millis() as u64);
+    if let Some(ms) = paced_ms {
+        log_debug!(log_level, "Step paced for {}ms", ms);
+    }
+
+    let timeout = match deadline::remaining(deadline) {
+        Ok(timeout) => timeout,
+        Err(e) => return (Err((None, e.message())), paced_ms),
+    };
+    if let Err(e) = expiry::check(expiry) {
+        return (Err((None, e.message())), paced_ms);
+    }
+    let url = request.get("url").and_then(Value::as_str).unwrap_or_default().to_string();
+    if let Some(attempt_id) = logger::next_attempt_id() {
+        log_info!("Step upstream attempt {}", attempt_id);
+        if let (Some(header), Some(request)) = (pacer.attempt_header(), request.as_object_mut()) {
+            let headers = request.entry("headers").or_insert_with(|| Value::Object(Map::new()));
+            if let Some(headers) = headers.as_object_mut() {
+                headers.insert(header.to_string(), Value::String(attempt_id));
+            }
+        }
+    }
+    let locale_rules = match request.get("locale") {
+        Some(_) => LocaleRules::load(pacer.env()).await,
+        None => LocaleRules::default(),
+    };
+    let call =
+        dispatch(step, request, timeout, cost, guard, max_response_bytes, end_user, &locale_rules, log_level);
+    let result = pacer.track(&url, waited, call, step_status).await;
+    logger::add_upstream_ms(worker::Date::now().as_millis().saturating_sub(started_at));
+    (result, paced_ms)
+}
+
+/// Status the upstream answered a step with, if it answered
+fn step_status(result: &StepResult) -> Option<u16> {
+    match result {
+        Ok(response) | Err((Some(response), _)) => Some(response.status()),
+        Err((None, _)) => None,
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn dispatch(
+    step: &StepRequest,
+    request: Value,
+    timeout: Option<Duration>,
+    cost: &Cell<CostEstimate>,
+    guard: &TargetGuard,
+    max_response_bytes: u64,
+    end_user: Option<&ForwardedEndUser>,
+    locale_rules: &LocaleRules,
+    log_level: LogLevel,
+) -> StepResult {
+    let url = request.get("url").and_then(Value::as_str).unwrap_or_default();
+    guard.check(url, cost).await.map_err(|e| (None, e.message()))?;
+    let sandbox = match &guard.sandbox {
+        Some(sandbox) => Some(sandbox.route(url).map_err(|e| (None, e.message()))?),
+        None => None,
+    };
+    let host = worker::Url::parse(url)
+        .ok()
+        .and_then(|url| url.host_str().map(str::to_string))
+        .unwrap_or_default();
+
+    let response = match step.request_type.to_lowercase().as_str() {
+        #[cfg(feature = "soap")]
+        "soap" => {
+            let mut data = serde_json::from_value::<SoapRequestData>(request)
+                .map_err(|e| (None, format!("Invalid SOAP step request: {}", e)))?;
+            data.validate().map_err(|e| (None, e.message()))?;
+            data.timeout = timeout;
+            data.max_response_bytes = Some(max_response_bytes);
+            if let Some(route) = sandbox {
+                data.set_sandbox(route).map_err(|e| (None, e.message()))?;
+            }
+            if let Some(end_user) = end_user {
+                end_user.apply(&host, &mut data.headers);
+            }
+            data.apply_locale(locale_rules).map_err(|e| (None, e.message()))?;
+            cost::add(cost, CostEstimate::upstream(1, data.egress_bytes()));
+            process_soap_request(data, log_level).await
+        }
+        "http" => {
+            let mut data = serde_json::from_value::<RequestData>(request)
+                .map_err(|e| (None, format!("Invalid HTTP step request: {}", e)))?;
+            // Later steps and the saga result need the body read
+            if data.mode == ResponseMode::Stream {
+                return Err((None, "mode stream isn't supported in saga steps".to_string()));
+            }
+            data.timeout = timeout;
+            data.max_response_bytes = Some(max_response_bytes);
+            if let Some(route) = sandbox {
+                data.set_sandbox(route).map_err(|e| (None, e.message()))?;
+            }
+            if let Some(end_user) = end_user {
+                end_user.apply(&host, &mut data.headers);
+            }
+            data.apply_locale(locale_rules).map_err(|e| (None, e.message()))?;
+            cost::add(cost, CostEstimate::upstream(1, data.egress_bytes()));
+            process_request(data, log_level).await
+        }
+        other => return Err((None, format!("Unsupported step request_type: {}", other))),
+    };
+
+    if let Ok(response) = &response {
+        if let Some(target) = response.redirected_to() {
+            guard.check(target, cost).await.map_err(|e| (None, e.message()))?;
+        }
+    }
+
+    match response {
+        Ok(response) if (200..300).contains(&response.status()) => Ok(response),
+        Ok(response) => {
+            let message = format!("Upstream returned {}", response.status());
+            Err((Some(response), message))
+        }
+        Err(e) => Err((None, format!("{:#}", e))),
+    }
+}
+
+/// Executes a saga, compensating completed steps if one fails
+///
+/// Calls toward paced hosts are spread out by the pacer rather than sent as a burst.
+/// Steps stop at the caller's deadline or expiry, but compensations always run,
+/// since leaving reservations dangling upstream is worse than finishing late.
+#[allow(clippy::too_many_arguments)]
+pub async fn process_saga(
+    data: SagaRequestData,
+    pacer: &Pacer<'_>,
+    deadline: Option<Deadline>,
+    expiry: Option<Expiry>,
+    cost: &Cell<CostEstimate>,
+    guard: &TargetGuard,
+    max_response_bytes: u64,
+    end_user: Option<&ForwardedEndUser>,
+    log_level: LogLevel,
+) -> SagaResult {
+    // Step responses by name, for placeholders in later steps and compensations
+    let mut vars = Map::new();
+    let mut reports: Vec<StepReport> = Vec::new();
+    let mut failed_at = None;
+    let mut paced_total = 0;
+    let timer = Timer::start();
+    let mut timings: Vec<StepTiming> = Vec::new();
+
+    for (index, step) in data.steps.iter().enumerate() {
+        log_debug!(log_level, "Saga step {} ({})", step.name, step.request.request_type);
+        let started_ms = timer.elapsed_ms();
+        let (result, paced_ms) = run_step(
+            &step.request,
+            &vars,
+            pacer,
+            deadline,
+            expiry,
+            cost,
+            guard,
+            max_response_bytes,
+            end_user,
+            log_level,
+        )
+        .await;
+        timings.push(timer.step(&step.name, started_ms, dependencies(&step.request, &data.steps[..index])));
+        paced_total += paced_ms.unwrap_or(0);
+        match result {
+            Ok(response) => {
+                vars.insert(step.name.clone(), serde_json::to_value(&response).unwrap_or(Value::Null));
+                let mut report = StepReport::new(&step.name, StepStatus::Succeeded);
+                report.response = Some(response);
+                report.paced_ms = paced_ms;
+                reports.push(report);
+            }
+            Err((response, error)) => {
+                log_info!("Saga step {} failed: {}", step.name, error);
+                let mut report = StepReport::new(&step.name, StepStatus::Failed);
+                report.response = response;
+                report.error = Some(error);
+                report.paced_ms = paced_ms;
+                reports.push(report);
+                failed_at = Some(index);
+                break;
+            }
+        }
+    }
+
+    let failed_at = match failed_at {
+        Some(index) => index,
+        None => {
+            return SagaResult {
+                outcome: SagaOutcome::Committed,
+                failed_step: None,
+                steps: reports,
+                paced_ms: (paced_total > 0).then_some(paced_total),
+                cost: None,
+                timing: timer.report(1, timings),
+            }
+        }
+    };
+
+    // Undo completed steps, most recent first
+    let mut outcome = SagaOutcome::Compensated;
+    for index in (0..failed_at).rev() {
+        let compensation = match &data.steps[index].compensation {
+            Some(compensation) => compensation,
+            None => continue,
+        };
+        let report = &mut reports[index];
+        let started_ms = timer.elapsed_ms();
+        // Compensations run whatever the deadline and expiry
+        let (result, paced_ms) = run_step(
+            compensation,
+            &vars,
+            pacer,
+            None,
+            None,
+            cost,
+            guard,
+            max_response_bytes,
+            end_user,
+            log_level,
+        )
+        .await;
+        let name = format!("{} (compensation)", report.name);
+        timings.push(timer.step(name, started_ms, dependencies(compensation, &data.steps[..=index])));
+        paced_total += paced_ms.unwrap_or(0);
+        match result {
+            Ok(response) => {
+                report.status = StepStatus::Compensated;
+                report.compensation = Some(response);
+            }
+            Err((response, error)) => {
+                log_error!("Saga compensation for {} failed: {}", report.name, error);
+                report.status = StepStatus::CompensationFailed;
+                report.compensation = response;
+                report.compensation_error = Some(error);
+                outcome = SagaOutcome::CompensationFailed;
+            }
+        }
+    }
+
+    for step in &data.steps[failed_at + 1..] {
+        reports.push(StepReport::new(&step.name, StepStatus::Skipped));
+    }
+
+    SagaResult {
+        outcome,
+        failed_step: Some(data.steps[failed_at].name.clone()),
+        steps: reports,
+        paced_ms: (paced_total > 0).then_some(paced_total),
+        cost: None,
+        timing: timer.report(1, timings),
+    }
+}
+
+/// `X-Request-Type: saga`
+pub struct SagaHandler;
+
+impl ProxyHandler for SagaHandler {
+    const NAME: &'static str = "saga";
+    type Request = SagaRequestData;
+    type Outcome = SagaResult;
+
+    fn parse(&self, body: &str) -> std::result::Result<SagaRequestData, ProxyError> {
+        let saga = from_json::<SagaRequestData>(body, Self::NAME)?;
+        saga.validate()?;
+        Ok(saga)
+    }
+
+    async fn execute(&self, ctx: &ProcessorContext<'_>, saga: SagaRequestData) -> SagaResult {
+        let pacer = Pacer::new(PacingConfig::load(ctx.env).await, ctx);
+        let result = process_saga(
+            saga,
+            &pacer,
+            ctx.deadline,
+            ctx.expiry,
+            ctx.cost,
+            &ctx.guard,
+            ctx.max_response_bytes,
+            ctx.forward_end_user.as_ref(),
+            ctx.log_level,
+        )
+        .await;
+        log_info!("Saga finished: {:?}", result.outcome);
+        result
+    }
+
+    fn respond(&self, ctx: &ProcessorContext<'_>, mut result: SagaResult) -> worker::Result<worker::Response> {
+        // The result is returned even past the deadline or expiry: it reports which compensations ran
+        let expired = ctx.expiry.filter(|expiry| expiry.check().is_err());
+        let status = match result.outcome {
+            SagaOutcome::Committed => 200,
+            _ if ctx.deadline.is_some_and(|deadline| deadline.is_exceeded()) => 504,
+            _ if expired.is_some() => 410,
+            _ => 502,
+        };
+        result.cost = ctx.include_cost.then(|| ctx.cost.get());
+        let mut response = worker::Response::from_json(&result)?.with_status(status);
+        match (status, expired) {
+            (200, _) => {}
+            // Marked as the edge expects, so the expiry callback is told
+            (410, Some(expiry)) => {
+                response.headers_mut().set(FAULT_HEADER, Fault::Caller.as_str())?;
+                response.headers_mut().set(expiry::EXPIRES_AT_HEADER, &expiry.0.to_string())?;
+            }
+            _ => response.headers_mut().set(FAULT_HEADER, Fault::Upstream.as_str())?,
+        }
+        Ok(response)
+    }
+}
@@ -0,0 +1,261 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use worker::Env;
+
+use super::response::ApiResponse;
+use crate::error::ProxyError;
+use crate::templates::TemplateStore;
+
+/// Maximum number of violations reported per response
+const MAX_VIOLATIONS: usize = 20;
+
+/// Expected shape of the upstream response body
+///
+/// Either an inline JSON Schema or a reference to the `response_schema` of
+/// a saved template's published version.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ResponseSchemaSpec {
+    #[serde(default)]
+    pub schema: Option<Value>,
+    /// Template whose published version carries the schema
+    #[serde(default)]
+    pub template: Option<String>,
+    /// Fail the request on a violation instead of only flagging it
+    #[serde(default)]
+    pub enforce: bool,
+}
+
+/// Schema validation result reported in response metadata
+#[derive(Debug, Clone, Serialize)]
+pub struct SchemaValidation {
+    pub valid: bool,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub violations: Vec<String>,
+}
+
+/// Validates a successful upstream response against the spec
+///
+/// Records the result in the response metadata. Returns a contract
+/// violation error only when the spec is enforced and validation failed.
+/// Upstream error statuses aren't validated; they have their own handling.
+pub async fn check_response(
+    env: &Env,
+    spec: &ResponseSchemaSpec,
+    response: &mut ApiResponse,
+) -> std::result::Result<(), ProxyError> {
+    let body = match response {
+        ApiResponse::Success(data) => &data.body,
+        ApiResponse::Minimal(data) => &data.body,
+        ApiResponse::Error(_) | ApiResponse::Stream(_) => return Ok(()),
+    };
+
+    let schema = resolve_schema(env, spec).await?;
+    let violations = validate(&schema, body);
+    let validation = SchemaValidation {
+        valid: violations.is_empty(),
+        violations,
+    };
+
+    let failed = !validation.valid;
+    response.metadata_mut().schema_validation = Some(validation.clone());
+
+    if failed && spec.enforce {
+        return Err(ProxyError::ContractViolation(validation.violations.join("; ")));
+    }
+    Ok(())
+}
+
+async fn resolve_schema(env: &Env, spec: &ResponseSchemaSpec) -> std::result::Result<Value, ProxyError> {
+    if let Some(schema) = &spec.schema {
+        return Ok(schema.clone());
+    }
+
+    let name = spec
+        .template
+        .as_deref()
+        .ok_or_else(|| ProxyError::InvalidRequest("response_schema needs a schema or a template".to_string()))?;
+    let template = TemplateStore::new(env)?
+        .get_cached(name)
+        .await?
+        .ok_or_else(|| ProxyError::NotFound(format!("Unknown template: {}", name)))?;
+
+    template
+        .published
+        .and_then(|version| template.version(version))
+        .and_then(|version| version.request.get("response_schema"))
+        .and_then(|spec| spec.get("schema"))
+        .cloned()
+        .ok_or_else(|| ProxyError::NotFound(format!("Template {} has no published response_schema", name)))
+}
+
+/// Validates a value against a JSON Schema subset
+///
+/// Supported keywords: type, enum, const, properties, required,
+/// additionalProperties, items, minItems, maxItems, minLength, maxLength,
+/// minimum, maximum, anyOf. Other keywords are ignored.
+pub fn validate(schema: &Value, value: &Value) -> Vec<String> {
+    let mut violations = Vec::new();
+    validate_at(schema, value, "$", &mut violations);
+    violations.truncate(MAX_VIOLATIONS);
+    violations
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(n) if n.is_i64() || n.is_u64() => "integer",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+fn matches_type(expected: &str, value: &Value) -> bool {
+    let actual = type_name(value);
+    actual == expected || (expected == "number" && actual == "integer")
+}
+
+fn validate_at(schema: &Value, value: &Value, path: &str, violations: &mut Vec<String>) {
+    if violations.len() >= MAX_VIOLATIONS {
+        return;
+    }
+    let schema = match schema.as_object() {
+        Some(schema) => schema,
+        None => return,
+    };
+
+    if let Some(expected) = schema.get("type") {
+        let allowed: Vec<&str> = match expected {
+            Value::String(t) => vec![t.as_str()],
+            Value::Array(types) => types.iter().filter_map(Value::as_str).collect(),
+            _ => Vec::new(),
+        };
+        if !allowed.is_empty() && !allowed.iter().any(|t| matches_type(t, value)) {
+            violations.push(format!("{}: expected {}, got {}", path, allowed.join("|"), type_name(value)));
+            return;
+        }
+    }
+
+    if let Some(options) = schema.get("enum").and_then(Value::as_array) {
+        if !options.contains(value) {
+            violations.push(format!("{}: value not in enum", path));
+        }
+    }
+    if let Some(expected) = schema.get("const") {
+        if expected != value {
+            violations.push(format!("{}: expected const {}", path, expected));
+        }
+    }
+
+    if let Some(any_of) = schema.get("anyOf").and_then(Value::as_array) {
+        let matched = any_of.iter().any(|option| {
+            let mut nested = Vec::new();
+            validate_at(option, value, path, &mut nested);
+            nested.is_empty()
+        });
+        if !matched {
+            violations.push(format!("{}: matches none of anyOf", path));
+        }
+    }
+
+    match value {
+        Value::Object(map) => {
+            if let Some(required) = schema.get("required").and_then(Value::as_array) {
+                for key in required.iter().filter_map(Value::as_str) {
+                    if !map.contains_key(key) {
+                        violations.push(format!("{}: missing required property {}", path, key));
+                    }
+                }
+            }
+            let properties = schema.get("properties").and_then(Value::as_object);
+            for (key, item) in map {
+                let item_path = format!("{}.{}", path, key);
+                match properties.and_then(|p| p.get(key)) {
+                    Some(item_schema) => validate_at(item_schema, item, &item_path, violations),
+                    None => match schema.get("additionalProperties") {
+                        Some(Value::Bool(false)) => {
+                            violations.push(format!("{}: unexpected property", item_path))
+                        }
+                        Some(extra @ Value::Object(_)) => validate_at(extra, item, &item_path, violations),
+                        _ => {}
+                    },
+                }
+            }
+        }
+        Value::Array(items) => {
+            if let Some(min) = schema.get("minItems").and_then(Value::as_u64) {
+                if (items.len() as u64) < min {
+                    violations.push(format!("{}: expected at least {} items", path, min));
+                }
+            }
+            if let Some(max) = schema.get("maxItems").and_then(Value::as_u64) {
+                if (items.len() as u64) > max {
+                    violations.push(format!("{}: expected at most {} items", path, max));
+                }
+            }
+            if let Some(item_schema) = schema.get("items") {
+                for (i, item) in items.iter().enumerate() {
+                    validate_at(item_schema, item, &format!("{}[{}]", path, i), violations);
+                }
+            }
+        }
+        Value::String(s) => {
+            let len = s.chars().count() as u64;
+            if let Some(min) = schema.get("minLength").and_then(Value::as_u64) {
+                if len < min {
+                    violations.push(format!("{}: shorter than {}", path, min));
+                }
+            }
+            if let Some(max) = schema.get("maxLength").and_then(Value::as_u64) {
+                if len > max {
+                    violations.push(format!("{}: longer than {}", path, max));
+                }
+            }
+        }
+        Value::Number(n) => {
+            let n = n.as_f64().unwrap_or_default();
+            if let Some(min) = schema.get("minimum").and_then(Value::as_f64) {
+                if n < min {
+                    violations.push(format!("{}: less than minimum {}", path, min));
+                }
+            }
+            if let Some(max) = schema.get("maximum").and_then(Value::as_f64) {
+                if n > max {
+                    violations.push(format!("{}: greater than maximum {}", path, max));
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_validate_reports_paths() {
+        let schema = json!({
+            "type": "object",
+            "required": ["country", "dids"],
+            "properties": {
+                "country": {"type": "string", "minLength": 2},
+                "dids": {"type": "array", "items": {"type": "integer"}}
+            }
+        });
+
+        assert!(validate(&schema, &json!({"country": "US", "dids": [1, 2]})).is_empty());
+
+        let violations = validate(&schema, &json!({"country": 1, "dids": [1, "2"]}));
+        assert_eq!(
+            violations,
+            vec!["$.country: expected string, got integer", "$.dids[1]: expected integer, got string"]
+        );
+
+        let violations = validate(&schema, &json!({"dids": []}));
+        assert_eq!(violations, vec!["$: missing required property country"]);
+    }
+}
@@ -1,16 +1,78 @@
 use anyhow::Context as AnyhowContext;
-use reqwest::{
-    header::{HeaderMap, HeaderName, HeaderValue},
-    Client,
-};
-use serde::{Deserialize, Serialize};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use serde::Deserialize;
 use serde_json::Value;
 use std::collections::HashMap;
+use std::time::Duration;
 use std::str::FromStr;
+use super::charset;
+use super::extract::{self, Extract};
+use super::response::{ApiResponse, ErrorResponseData, ResponseData, ResponseMetadata};
+use super::schema::ResponseSchemaSpec;
+use super::transform::{self, Transform};
+use super::xml::{self, html_escape, XmlOptions};
+use super::parse::from_json;
+use super::registry::{Failure, ProcessorContext, ProxyHandler};
+use super::upstream::{self, UpstreamPreview, UpstreamRequest};
+use crate::cache::{self, CacheRules};
+use crate::canonical_url;
+use crate::cost;
+use crate::dns_guard;
+use crate::error::ProxyError;
+use crate::limits;
+use crate::locale::{self, LocaleRules};
 use crate::logger::LogLevel;
+use crate::poll::{self, PollSpec};
+use crate::retry::{IdempotentActions, RetryPolicy};
+use crate::sandbox::SandboxRoute;
 use crate::{log_info, log_debug};
+use worker::Url;
 
-#[derive(Debug, Deserialize)]
+/// Prefix nusoap gives the action element's namespace
+const DEFAULT_NAMESPACE_PREFIX: &str = "ns1766";
+
+/// Charset nusoap declares and encodes the envelope in
+const DEFAULT_ENCODING: &str = "ISO-8859-1";
+
+/// `User-Agent` nusoap sends
+const NUSOAP_USER_AGENT: &str = "NuSOAP/0.9.17 (1.123)";
+
+/// WS-Addressing 1.0 namespace
+const WSA_NAMESPACE: &str = "http://www.w3.org/2005/08/addressing";
+
+/// `wsa:ReplyTo` address asking for the reply on the same HTTP connection
+const WSA_ANONYMOUS: &str = "http://www.w3.org/2005/08/addressing/anonymous";
+
+/// WS-Addressing headers (`wsa:To`, `wsa:Action`, `wsa:MessageID`, `wsa:ReplyTo`)
+#[derive(Debug, Clone, Deserialize)]
+pub struct WsAddressing {
+    /// `wsa:To`; the request URL when unset
+    #[serde(default)]
+    pub to: Option<String>,
+    /// `wsa:Action`; `{namespace}/{action}` (`{namespace}:{action}` for a URN) when unset
+    #[serde(default)]
+    pub action: Option<String>,
+    /// `wsa:ReplyTo` address; anonymous (this connection) when unset
+    #[serde(default)]
+    pub reply_to: Option<String>,
+    /// `wsa:MessageID`, fresh for every request; retries of the request resend it
+    #[serde(skip, default = "new_message_id")]
+    pub message_id: String,
+}
+
+fn new_message_id() -> String {
+    format!("urn:uuid:{}", uuid::Uuid::new_v4())
+}
+
+fn default_namespace_prefix() -> String {
+    DEFAULT_NAMESPACE_PREFIX.to_string()
+}
+
+fn default_encoding() -> String {
+    DEFAULT_ENCODING.to_string()
+}
+
+#[derive(Debug, Clone, Deserialize)]
 pub struct SoapRequestData {
     /// URL to send the SOAP request to
     pub url: String,
@@ -25,37 +87,171 @@ pub struct SoapRequestData {
     #[serde(default)]
     pub params: Vec<(String, Value)>,
 
+    /// `SOAPAction` header, e.g. "urn:didx#getDIDCountry"; empty (`""`) like nusoap when unset
+    #[serde(default)]
+    pub soap_action: Option<String>,
+
+    /// Prefix of the action element's namespace
+    #[serde(default = "default_namespace_prefix")]
+    pub namespace_prefix: String,
+
+    /// `User-Agent` header; nusoap's when unset
+    #[serde(default)]
+    pub user_agent: Option<String>,
+
+    /// Charset of the envelope (e.g. "UTF-8"), named in its XML declaration and `Content-Type`
+    #[serde(default = "default_encoding")]
+    pub encoding: String,
+
     /// Request headers as key-value pairs
     #[serde(default)]
     pub headers: HashMap<String, String>,
-}
 
-#[derive(Serialize)]
-pub struct ResponseData {
-    pub status: u16,
-    pub headers: HashMap<String, String>,
-    pub body: Value,
-}
+    /// Fail the request if the DO is not running in its expected region
+    #[serde(default)]
+    pub assert_region: bool,
 
-#[derive(Serialize)]
-pub struct ErrorResponseData {
-    pub status: u16,
-    pub message: String,
+    /// Expected response JSON Schema; violations are flagged in metadata
+    #[serde(default)]
+    pub response_schema: Option<ResponseSchemaSpec>,
+
+    /// Convert an XML response body to JSON using these options
+    #[serde(default)]
+    pub xml_to_json: Option<XmlOptions>,
+
+    /// Return only these fragments of a successful response's body, by name
+    #[serde(default)]
+    pub extract: Option<Extract>,
+
+    /// Reshape a successful response's JSON body, after `extract`
+    #[serde(default)]
+    pub transform: Option<Transform>,
+
+    /// End user's language, e.g. "de-CH"; sent as `Accept-Language` unless a locale rule says otherwise
+    #[serde(default)]
+    pub locale: Option<String>,
+
+    /// Stored upstream credential whose headers are added to the request
+    #[serde(default)]
+    pub credential: Option<String>,
+
+    /// Return only `{status, body}` for successful responses
+    #[serde(default)]
+    pub minimal: bool,
+
+    /// Retry behaviour for this request; a host's retry policy still wins
+    #[serde(default)]
+    pub retries: Option<RetryPolicy>,
+
+    /// Report whether the response changed since the last poll of this key
+    #[serde(default)]
+    pub poll: Option<PollSpec>,
+
+    /// Add WS-Addressing headers to the envelope
+    #[serde(default)]
+    pub ws_addressing: Option<WsAddressing>,
+
+    /// Upstream timeout, set by the processor from the caller's `X-Deadline`
+    #[serde(skip)]
+    pub timeout: Option<Duration>,
+
+    /// Largest response body read, set by the processor from `MAX_RESPONSE_BYTES`
+    #[serde(skip)]
+    pub max_response_bytes: Option<u64>,
+
+    /// How the call is answered when the sandbox tenant made it, set by the processor
+    #[serde(skip)]
+    pub sandbox: Option<SandboxRoute>,
 }
 
-#[derive(Serialize)]
-#[serde(untagged)]
-pub enum ApiResponse {
-    Success(ResponseData),
-    Error(ErrorResponseData),
+impl SoapRequestData {
+    pub fn validate(&self) -> std::result::Result<(), ProxyError> {
+        let mut chars = self.namespace_prefix.chars();
+        let valid_prefix = chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+            && chars.all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'));
+        if !valid_prefix {
+            return Err(ProxyError::InvalidRequest(format!(
+                "namespace_prefix must be an XML name: {:?}",
+                self.namespace_prefix
+            )));
+        }
+        for (field, value) in [("soap_action", &self.soap_action), ("user_agent", &self.user_agent)] {
+            if value.as_deref().is_some_and(|value| HeaderValue::from_str(value).is_err()) {
+                return Err(ProxyError::InvalidRequest(format!("{} isn't a valid header value", field)));
+            }
+        }
+        if charset::for_request(&self.encoding).is_none() {
+            return Err(ProxyError::InvalidRequest(format!("Unsupported encoding: {:?}", self.encoding)));
+        }
+        if let Some(extract) = &self.extract {
+            extract::validate(extract, self.xml_to_json.as_ref())?;
+        }
+        if let Some(transform) = &self.transform {
+            transform.validate()?;
+        }
+        if let Some(locale) = &self.locale {
+            locale::normalize(locale)?;
+        }
+        Ok(())
+    }
+
+    /// Sends `locale` where the target host's locale rule says, beneath the caller's own headers and params
+    pub fn apply_locale(&mut self, rules: &LocaleRules) -> std::result::Result<(), ProxyError> {
+        let Some(locale) = &self.locale else {
+            return Ok(());
+        };
+        let locale = locale::normalize(locale)?;
+        let host = Url::parse(&self.url)
+            .ok()
+            .and_then(|url| url.host_str().map(str::to_string))
+            .unwrap_or_default();
+        let mut target = rules.target(&host, &locale);
+        target.apply_header(&mut self.headers);
+        if let Some((name, value)) = target.param {
+            if !self.params.iter().any(|(existing, _)| *existing == name) {
+                self.params.push((name, Value::String(value)));
+            }
+        }
+        self.locale = Some(locale);
+        Ok(())
+    }
+
+    /// The envelope as sent, in the requested charset
+    fn encoded_envelope(&self) -> Vec<u8> {
+        let encoding = charset::for_request(&self.encoding).unwrap_or(encoding_rs::WINDOWS_1252);
+        charset::encode(&build_envelope(self), encoding)
+    }
+
+    /// The `SOAPAction` header, quoted as SOAP 1.1 requires
+    fn soap_action_header(&self) -> String {
+        match self.soap_action.as_deref().map(str::trim) {
+            Some(action) if action.starts_with('"') && action.ends_with('"') && action.len() > 1 => action.to_string(),
+            Some(action) => format!("\"{}\"", action),
+            None => "\"\"".to_string(),
+        }
+    }
+
+    /// Approximate bytes this request sends upstream, for cost estimates
+    pub fn egress_bytes(&self) -> u64 {
+        cost::request_bytes(&self.url, &self.headers, self.encoded_envelope().len())
+    }
+
+    pub fn preview(&self) -> UpstreamPreview {
+        let mut header_names: Vec<String> = self.headers.keys().cloned().collect();
+        header_names.sort();
+        UpstreamPreview {
+            method: "POST".to_string(),
+            url: canonical_url::canonicalize(&self.url).unwrap_or_else(|| self.url.clone()),
+            header_names,
+            envelope: Some(build_envelope(self)),
+            bytes: self.egress_bytes(),
+        }
+    }
 }
 
 /// Process a SOAP request by building SOAP envelope and forwarding to target URL
 pub async fn process_soap_request(data: SoapRequestData, log_level: LogLevel) -> anyhow::Result<ApiResponse> {
-    // Create a client
-    let client = Client::builder()
-        .build()
-        .context("Failed to create HTTP client")?;
+    let client = upstream::client();
 
     // Parse headers
     let mut headers = HeaderMap::new();
@@ -67,60 +263,21 @@ pub async fn process_soap_request(data: SoapRequestData, log_level: LogLevel) ->
         headers.insert(header_name, header_value);
     }
 
-    // Add SOAP-specific headers that match nusoap exactly
+    // Add SOAP-specific headers; unless the request overrides them they match nusoap exactly
     headers.insert(
         HeaderName::from_static("content-type"),
-        HeaderValue::from_static("text/xml; charset=ISO-8859-1"),
+        HeaderValue::from_str(&format!("text/xml; charset={}", data.encoding)).context("Invalid encoding")?,
     );
     headers.insert(
         HeaderName::from_static("soapaction"),
-        HeaderValue::from_static("\"\""), // Empty SOAPAction like nusoap
+        HeaderValue::from_str(&data.soap_action_header()).context("Invalid soap_action")?,
     );
     headers.insert(
         HeaderName::from_static("user-agent"),
-        HeaderValue::from_static("NuSOAP/0.9.17 (1.123)"), // Match nusoap exactly
-    );
-
-    // Build SOAP body content with namespace prefix (like nusoap does)
-    // Use ns1766 as the namespace prefix to match nusoap format exactly
-    let mut soap_body_content = format!(
-        "<ns1766:{} xmlns:ns1766=\"{}\">",
-        data.action, data.namespace
+        HeaderValue::from_str(data.user_agent.as_deref().unwrap_or(NUSOAP_USER_AGENT)).context("Invalid user_agent")?,
     );
 
-    // Add parameters with type hints
-    // Match nusoap behavior: numeric keys become __numeric_N
-    // Vec preserves exact order from Laravel
-    for (key, value) in &data.params {
-        // Check if key is numeric and convert to __numeric_N format like nusoap
-        let xml_key = if key.chars().all(|c| c.is_numeric()) {
-            format!("__numeric_{}", key)
-        } else {
-            key.clone()
-        };
-
-        let (type_hint, value_str) = match value {
-            Value::Bool(b) => ("xsd:boolean", b.to_string()),
-            Value::Number(n) => ("xsd:int", n.to_string()),
-            Value::String(s) => ("xsd:string", html_escape(s)),
-            Value::Null => ("xsd:string", String::new()),
-            _ => ("xsd:string", value.to_string()),
-        };
-
-        soap_body_content.push_str(&format!(
-            "<{} xsi:type=\"{}\">{}</{}>",
-            xml_key, type_hint, value_str, xml_key
-        ));
-    }
-
-    soap_body_content.push_str(&format!("</ns1766:{}>", data.action));
-
-    // Construct complete SOAP envelope - DidX needs the EXACT format that nusoap sends
-    // CRITICAL: Must be single line with NO newlines (except XML declaration)
-    let soap_envelope = format!(
-        "<?xml version=\"1.0\" encoding=\"ISO-8859-1\"?><SOAP-ENV:Envelope SOAP-ENV:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\" xmlns:SOAP-ENV=\"http://schemas.xmlsoap.org/soap/envelope/\" xmlns:xsd=\"http://www.w3.org/2001/XMLSchema\" xmlns:xsi=\"http://www.w3.org/2001/XMLSchema-instance\" xmlns:SOAP-ENC=\"http://schemas.xmlsoap.org/soap/encoding/\"><SOAP-ENV:Body>{}</SOAP-ENV:Body></SOAP-ENV:Envelope>",
-        soap_body_content
-    );
+    let soap_envelope = build_envelope(&data);
 
     log_debug!(
         log_level,
@@ -130,12 +287,23 @@ pub async fn process_soap_request(data: SoapRequestData, log_level: LogLevel) ->
         data.params.len()
     );
 
-    // Build and send the request
-    let request = client.post(&data.url).headers(headers).body(soap_envelope);
+    // Registered stubs answer instead of the network (tests and mock-mode builds), as
+    // the mock registry does for the sandbox tenant
+    let envelope = Some(soap_envelope.as_str());
+    if let Some(answer) = crate::stubs::answer(&data.url, envelope, data.timeout, data.sandbox).await {
+        return answer.map(|reply| reply.into_api_response(data.xml_to_json.as_ref(), log_level));
+    }
+
+    // Build and send the request, in the charset the envelope declares
+    let mut request = client.post(&data.url).headers(headers).body(data.encoded_envelope());
+    if let Some(timeout) = data.timeout {
+        request = request.timeout(timeout);
+    }
 
     // Send the request
     // Note: Cloudflare Workers enforces a 30-second timeout on all fetch requests
     let response = request.send().await.context("Failed to send SOAP request")?;
+    let redirected_to = dns_guard::redirect_target(&data.url, response.url());
 
     // Process the response
     let status = response.status().as_u16();
@@ -146,6 +314,12 @@ pub async fn process_soap_request(data: SoapRequestData, log_level: LogLevel) ->
 
     log_info!("SOAP response status: {}", status);
 
+    // Interim statuses (100 Continue, 103 Early Hints) are consumed by the runtime;
+    // one surfacing as the final response means the upstream broke the exchange
+    if (100..200).contains(&status) {
+        anyhow::bail!("Upstream answered with interim status {} and no final response", status);
+    }
+
     // Check if it's a success status (200-299)
     if (200..300).contains(&status) {
         // Convert response headers to HashMap
@@ -157,14 +331,23 @@ pub async fn process_soap_request(data: SoapRequestData, log_level: LogLevel) ->
         }
 
         // Get the response text
-        let text = response
-            .text()
+        let content_type = header_map.get("content-type").cloned();
+        // Refuse a declared oversized body before buffering it, and a chunked one once read
+        limits::check_response(response.content_length(), data.max_response_bytes)?;
+        let bytes = response
+            .bytes()
             .await
             .context("Failed to read SOAP response body")?;
+        limits::check_response(Some(bytes.len() as u64), data.max_response_bytes)?;
+        let decoded = charset::decode(&bytes, content_type.as_deref());
+        if let Some(original) = &decoded.original_charset {
+            log_debug!(log_level, "Transcoded SOAP response body from {} to UTF-8", original);
+        }
+        let text = decoded.text;
 
-        // Return the SOAP XML response as a string
-        let body = serde_json::from_str::<Value>(&text)
-            .unwrap_or_else(|_| Value::String(text.clone()));
+        // Return the SOAP XML response as a string, or converted to JSON on request
+        let body = xml::response_body(status, &text, data.xml_to_json.as_ref());
+        let raw_body = xml::raw_body(status, &text, data.xml_to_json.as_ref());
 
         log_debug!(log_level, "SOAP response headers: {} headers", header_map.len());
         log_debug!(log_level, "SOAP response body size: {} bytes", text.len());
@@ -173,6 +356,12 @@ pub async fn process_soap_request(data: SoapRequestData, log_level: LogLevel) ->
             status,
             headers: header_map,
             body,
+            raw_body,
+            metadata: decoded.original_charset.map(|original_charset| ResponseMetadata {
+                original_charset: Some(original_charset),
+                ..Default::default()
+            }),
+            redirected_to,
         }))
     } else {
         log_debug!(log_level, "SOAP error response: {}", status_text);
@@ -180,15 +369,400 @@ pub async fn process_soap_request(data: SoapRequestData, log_level: LogLevel) ->
         Ok(ApiResponse::Error(ErrorResponseData {
             status,
             message: status_text.to_string(),
+            metadata: None,
+            redirected_to,
         }))
     }
 }
 
-/// HTML escape helper for SOAP parameter values
-fn html_escape(s: &str) -> String {
-    s.replace('&', "&amp;")
-        .replace('<', "&lt;")
-        .replace('>', "&gt;")
-        .replace('"', "&quot;")
-        .replace('\'', "&apos;")
+/// Builds the SOAP envelope exactly as nusoap formats it
+fn build_envelope(data: &SoapRequestData) -> String {
+    // Build SOAP body content with namespace prefix (like nusoap does)
+    // The default prefix, ns1766, matches nusoap format exactly
+    let prefix = &data.namespace_prefix;
+    let mut soap_body_content = format!(
+        "<{}:{} xmlns:{}=\"{}\">",
+        prefix, data.action, prefix, data.namespace
+    );
+
+    // Add parameters with type hints
+    // Vec preserves exact order from Laravel
+    for (key, value) in &data.params {
+        serialize_param(key, value, &mut soap_body_content);
+    }
+
+    soap_body_content.push_str(&format!("</{}:{}>", prefix, data.action));
+
+    // Construct complete SOAP envelope - DidX needs the EXACT format that nusoap sends
+    // CRITICAL: Must be single line with NO newlines (except XML declaration)
+    format!(
+        "<?xml version=\"1.0\" encoding=\"{}\"?><SOAP-ENV:Envelope SOAP-ENV:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\" xmlns:SOAP-ENV=\"http://schemas.xmlsoap.org/soap/envelope/\" xmlns:xsd=\"http://www.w3.org/2001/XMLSchema\" xmlns:xsi=\"http://www.w3.org/2001/XMLSchema-instance\" xmlns:SOAP-ENC=\"http://schemas.xmlsoap.org/soap/encoding/\">{}<SOAP-ENV:Body>{}</SOAP-ENV:Body></SOAP-ENV:Envelope>",
+        data.encoding,
+        ws_addressing_header(data),
+        soap_body_content
+    )
+}
+
+/// The `xsi:type` nusoap gives a value
+fn type_hint(value: &Value) -> &'static str {
+    match value {
+        Value::Bool(_) => "xsd:boolean",
+        Value::Number(_) => "xsd:int",
+        Value::String(_) | Value::Null => "xsd:string",
+        Value::Array(_) => "SOAP-ENC:Array",
+        Value::Object(_) => "SOAP-ENC:Struct",
+    }
+}
+
+/// Appends one parameter element, recursing into objects and arrays as nusoap does
+///
+/// Numeric keys become `__numeric_N`. An object becomes a `SOAP-ENC:Struct` of
+/// its fields in order; an array a `SOAP-ENC:Array` of `item` elements whose
+/// `arrayType` is the items' common type, or `xsd:anyType` when they differ.
+fn serialize_param(key: &str, value: &Value, out: &mut String) {
+    let name = if key.chars().all(|c| c.is_numeric()) {
+        format!("__numeric_{}", key)
+    } else {
+        key.to_string()
+    };
+    match value {
+        Value::Object(fields) => {
+            out.push_str(&format!("<{} xsi:type=\"SOAP-ENC:Struct\">", name));
+            for (field, value) in fields {
+                serialize_param(field, value, out);
+            }
+        }
+        Value::Array(items) => {
+            let item_type = match items.split_first() {
+                Some((first, rest)) if rest.iter().all(|item| type_hint(item) == type_hint(first)) => type_hint(first),
+                _ => "xsd:anyType",
+            };
+            out.push_str(&format!(
+                "<{} xsi:type=\"SOAP-ENC:Array\" SOAP-ENC:arrayType=\"{}[{}]\">",
+                name,
+                item_type,
+                items.len()
+            ));
+            for item in items {
+                serialize_param("item", item, out);
+            }
+        }
+        scalar => {
+            let text = match scalar {
+                Value::String(s) => html_escape(s),
+                Value::Null => String::new(),
+                _ => scalar.to_string(),
+            };
+            out.push_str(&format!("<{} xsi:type=\"{}\">{}", name, type_hint(scalar), text));
+        }
+    }
+    out.push_str(&format!("</{}>", name));
+}
+
+/// The `SOAP-ENV:Header` carrying WS-Addressing, or nothing when it wasn't asked for
+fn ws_addressing_header(data: &SoapRequestData) -> String {
+    let Some(wsa) = &data.ws_addressing else {
+        return String::new();
+    };
+    let to = wsa.to.as_deref().unwrap_or(&data.url);
+    let action = wsa.action.clone().unwrap_or_else(|| {
+        let delimiter = if data.namespace.starts_with("urn:") { ':' } else { '/' };
+        format!("{}{}{}", data.namespace.trim_end_matches(delimiter), delimiter, data.action)
+    });
+    let reply_to = wsa.reply_to.as_deref().unwrap_or(WSA_ANONYMOUS);
+    format!(
+        "<SOAP-ENV:Header xmlns:wsa=\"{}\"><wsa:To>{}</wsa:To><wsa:Action>{}</wsa:Action>\
+         <wsa:MessageID>{}</wsa:MessageID><wsa:ReplyTo><wsa:Address>{}</wsa:Address></wsa:ReplyTo></SOAP-ENV:Header>",
+        WSA_NAMESPACE,
+        html_escape(to),
+        html_escape(&action),
+        wsa.message_id,
+        html_escape(reply_to)
+    )
+}
+
+/// `X-Request-Type: soap`
+pub struct SoapHandler;
+
+impl UpstreamRequest for SoapRequestData {
+    fn url(&self) -> &str {
+        &self.url
+    }
+
+    fn url_mut(&mut self) -> &mut String {
+        &mut self.url
+    }
+
+    fn assert_region(&self) -> bool {
+        self.assert_region
+    }
+
+    fn credential(&self) -> Option<&str> {
+        self.credential.as_deref()
+    }
+
+    fn message_id(&self) -> Option<&str> {
+        self.ws_addressing.as_ref().map(|wsa| wsa.message_id.as_str())
+    }
+
+    fn soap_action(&self) -> Option<&str> {
+        Some(&self.action)
+    }
+
+    fn headers_mut(&mut self) -> &mut HashMap<String, String> {
+        &mut self.headers
+    }
+
+    fn response_schema(&self) -> Option<&ResponseSchemaSpec> {
+        self.response_schema.as_ref()
+    }
+
+    fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    fn set_max_response_bytes(&mut self, limit: u64) {
+        self.max_response_bytes = Some(limit);
+    }
+
+    fn set_sandbox(&mut self, route: SandboxRoute) -> std::result::Result<(), ProxyError> {
+        // The runtime follows a SOAP call's redirects, which could lead an echo endpoint's reply anywhere
+        if route == SandboxRoute::Echo {
+            return Err(ProxyError::PolicyViolation("Sandbox SOAP calls can only reach mocks".to_string()));
+        }
+        self.sandbox = Some(route);
+        Ok(())
+    }
+
+    fn minimal(&self) -> bool {
+        self.minimal
+    }
+
+    fn set_minimal(&mut self, minimal: bool) {
+        self.minimal = minimal;
+    }
+
+    fn retries(&self) -> Option<&RetryPolicy> {
+        self.retries.as_ref()
+    }
+
+    fn egress_bytes(&self) -> u64 {
+        SoapRequestData::egress_bytes(self)
+    }
+
+    async fn idempotent(&self, env: &worker::Env, host: &str) -> bool {
+        IdempotentActions::load(env).await.contains(host, &self.action)
+    }
+
+    async fn send(self, log_level: LogLevel) -> anyhow::Result<ApiResponse> {
+        process_soap_request(self, log_level).await
+    }
+}
+
+impl ProxyHandler for SoapHandler {
+    const NAME: &'static str = "SOAP";
+    type Request = SoapRequestData;
+    type Outcome = std::result::Result<ApiResponse, Failure>;
+
+    fn parse(&self, body: &str) -> std::result::Result<SoapRequestData, ProxyError> {
+        let request = from_json::<SoapRequestData>(body, Self::NAME)?;
+        request.validate()?;
+        Ok(request)
+    }
+
+    async fn execute(&self, ctx: &ProcessorContext<'_>, mut request: SoapRequestData) -> Self::Outcome {
+        log_debug!(ctx.log_level, "SOAP action: {}, namespace: {}, url: {}", request.action, request.namespace, request.url);
+
+        if request.locale.is_some() {
+            request.apply_locale(&LocaleRules::load(ctx.env).await)?;
+        }
+
+        // Extracted and transformed after the cache, which keeps the whole response for every caller
+        let extract_spec = request.extract.take();
+        let transform_spec = request.transform.take();
+        let mut outcome = fetch(ctx, request).await;
+        if let Some(spec) = &extract_spec {
+            outcome = extract::apply(spec, outcome).map_err(|failure| *failure);
+        }
+        if let Some(spec) = &transform_spec {
+            outcome = transform::apply(spec, outcome).map_err(|failure| *failure);
+        }
+        outcome
+    }
+
+    fn respond(&self, _ctx: &ProcessorContext<'_>, outcome: Self::Outcome) -> worker::Result<worker::Response> {
+        upstream::respond(Self::NAME, outcome)
+    }
+}
+
+/// The whole response of a SOAP call, from a poll, this shard's cache or the upstream
+async fn fetch(ctx: &ProcessorContext<'_>, request: SoapRequestData) -> std::result::Result<ApiResponse, Failure> {
+    // Polls always go upstream, so a cache rule can't hide a change
+    if let Some(spec) = request.poll.clone() {
+        return poll::execute(ctx, request, &spec).await;
+    }
+
+    // Pure lookups with a cache rule are answered from this shard's storage
+    let host = Url::parse(&request.url)
+        .ok()
+        .and_then(|url| url.host_str().map(str::to_string))
+        .unwrap_or_default();
+    let ttl = match CacheRules::load(ctx.env).await.ttl(&request.action, &host) {
+        Some(ttl) => ttl,
+        None => return upstream::execute(ctx, request).await,
+    };
+    let key = cache::soap_key(
+        ctx.tenant_id,
+        request.credential.as_deref(),
+        &request.url,
+        &request.namespace,
+        &request.action,
+        &request.soap_action_header(),
+        request.locale.as_deref(),
+        &request.params,
+        &request.headers,
+    );
+    cache::execute(ctx, request, &key, ttl).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Laravel payloads (tests/fixtures/laravel/*.json) and the exact nusoap
+    /// envelope production sends for each (*.xml)
+    const FIXTURES: &[(&str, &str, &str)] = &[
+        (
+            "didx_get_did_country",
+            include_str!("../../tests/fixtures/laravel/didx_get_did_country.json"),
+            include_str!("../../tests/fixtures/laravel/didx_get_did_country.xml"),
+        ),
+        (
+            "didx_buy_did_by_number",
+            include_str!("../../tests/fixtures/laravel/didx_buy_did_by_number.json"),
+            include_str!("../../tests/fixtures/laravel/didx_buy_did_by_number.xml"),
+        ),
+        (
+            "numeric_keys_and_escaping",
+            include_str!("../../tests/fixtures/laravel/numeric_keys_and_escaping.json"),
+            include_str!("../../tests/fixtures/laravel/numeric_keys_and_escaping.xml"),
+        ),
+    ];
+
+    #[test]
+    fn test_envelopes_match_nusoap_byte_for_byte() {
+        for (name, payload, envelope) in FIXTURES {
+            let data: SoapRequestData = from_json(payload, "SOAP").unwrap_or_else(|e| panic!("{}: {}", name, e));
+            assert_eq!(build_envelope(&data), *envelope, "envelope of {} changed", name);
+        }
+    }
+
+    #[test]
+    fn test_nested_params() {
+        let payload = r#"{
+            "url": "https://soap.example.com/service",
+            "action": "orderDIDs",
+            "namespace": "urn:didx",
+            "params": [
+                ["customer", {"id": 42, "name": "A&B", "tags": []}],
+                ["dids", ["15551234", "15555678"]],
+                ["mixed", [1, "two", {"0": true}]]
+            ]
+        }"#;
+        let data: SoapRequestData = from_json(payload, "SOAP").unwrap();
+        let envelope = build_envelope(&data);
+        assert!(envelope.contains(
+            "<customer xsi:type=\"SOAP-ENC:Struct\"><id xsi:type=\"xsd:int\">42</id>\
+             <name xsi:type=\"xsd:string\">A&amp;B</name>\
+             <tags xsi:type=\"SOAP-ENC:Array\" SOAP-ENC:arrayType=\"xsd:anyType[0]\"></tags></customer>"
+        ));
+        assert!(envelope.contains(
+            "<dids xsi:type=\"SOAP-ENC:Array\" SOAP-ENC:arrayType=\"xsd:string[2]\">\
+             <item xsi:type=\"xsd:string\">15551234</item><item xsi:type=\"xsd:string\">15555678</item></dids>"
+        ));
+        assert!(envelope.contains(
+            "<mixed xsi:type=\"SOAP-ENC:Array\" SOAP-ENC:arrayType=\"xsd:anyType[3]\">\
+             <item xsi:type=\"xsd:int\">1</item><item xsi:type=\"xsd:string\">two</item>\
+             <item xsi:type=\"SOAP-ENC:Struct\"><__numeric_0 xsi:type=\"xsd:boolean\">true</__numeric_0></item></mixed>"
+        ));
+    }
+
+    #[test]
+    fn test_provider_overrides() {
+        let payload = r#"{
+            "url": "https://soap.example.com/service",
+            "action": "GetRate",
+            "namespace": "http://rates.example/v2",
+            "soap_action": "http://rates.example/v2/GetRate",
+            "namespace_prefix": "tns",
+            "user_agent": "Billing/2.1"
+        }"#;
+        let data: SoapRequestData = from_json(payload, "SOAP").unwrap();
+        assert!(data.validate().is_ok());
+        assert_eq!(data.soap_action_header(), "\"http://rates.example/v2/GetRate\"");
+        assert!(build_envelope(&data)
+            .contains("<tns:GetRate xmlns:tns=\"http://rates.example/v2\"></tns:GetRate></SOAP-ENV:Body>"));
+
+        // Defaults stay nusoap's
+        let data: SoapRequestData =
+            from_json(r#"{"url": "https://soap.example.com/", "action": "a", "namespace": "urn:a"}"#, "SOAP").unwrap();
+        assert_eq!((data.soap_action_header().as_str(), data.namespace_prefix.as_str()), ("\"\"", "ns1766"));
+
+        let bad_prefix: SoapRequestData = from_json(
+            r#"{"url": "https://soap.example.com/", "action": "a", "namespace": "urn:a", "namespace_prefix": "1ns"}"#,
+            "SOAP",
+        )
+        .unwrap();
+        assert!(bad_prefix.validate().is_err());
+    }
+
+    #[test]
+    fn test_envelope_encoding() {
+        let payload = |encoding: &str| {
+            format!(
+                r#"{{"url": "https://soap.example.com/", "action": "a", "namespace": "urn:a", {}
+                    "params": [["name", "Иван\u0001 東京"]]}}"#,
+                encoding
+            )
+        };
+        let data: SoapRequestData = from_json(&payload(r#""encoding": "UTF-8","#), "SOAP").unwrap();
+        assert!(data.validate().is_ok());
+        let envelope = String::from_utf8(data.encoded_envelope()).unwrap();
+        assert!(envelope.starts_with("<?xml version=\"1.0\" encoding=\"UTF-8\"?>"));
+        assert!(envelope.contains("<name xsi:type=\"xsd:string\">Иван 東京</name>"));
+
+        // nusoap's ISO-8859-1 carries the characters it lacks as references
+        let data: SoapRequestData = from_json(&payload(""), "SOAP").unwrap();
+        let envelope = String::from_utf8(data.encoded_envelope()).unwrap();
+        assert!(envelope.contains(">&#1048;&#1074;&#1072;&#1085; &#26481;&#20140;</name>"));
+
+        let data: SoapRequestData = from_json(&payload(r#""encoding": "UTF-16","#), "SOAP").unwrap();
+        assert!(data.validate().is_err());
+    }
+
+    #[test]
+    fn test_ws_addressing_header() {
+        let payload = r#"{
+            "url": "https://soap.example.com/service?a=1&b=2",
+            "action": "getDIDCountry",
+            "namespace": "urn:didx",
+            "params": [["did", "1234567890"]],
+            "ws_addressing": {}
+        }"#;
+        let data: SoapRequestData = from_json(payload, "SOAP").unwrap();
+        let wsa = data.ws_addressing.as_ref().unwrap();
+        assert!(wsa.message_id.starts_with("urn:uuid:"));
+        assert_eq!(data.message_id(), Some(wsa.message_id.as_str()));
+
+        let envelope = build_envelope(&data);
+        assert!(envelope.contains("<wsa:To>https://soap.example.com/service?a=1&amp;b=2</wsa:To>"));
+        assert!(envelope.contains("<wsa:Action>urn:didx:getDIDCountry</wsa:Action>"));
+        assert!(envelope.contains(&format!("<wsa:MessageID>{}</wsa:MessageID>", wsa.message_id)));
+        assert!(envelope.contains(&format!("<wsa:Address>{}</wsa:Address>", WSA_ANONYMOUS)));
+        assert!(envelope.contains("</SOAP-ENV:Header><SOAP-ENV:Body>"));
+
+        // Every request gets its own id
+        let again: SoapRequestData = from_json(payload, "SOAP").unwrap();
+        assert_ne!(again.message_id(), data.message_id());
+    }
 }
@@ -0,0 +1,318 @@
+use serde::Deserialize;
+use serde_json::{Map, Number, Value};
+use std::collections::BTreeMap;
+
+use super::registry::Failure;
+use super::response::ApiResponse;
+use crate::error::ProxyError;
+
+/// A declarative reshaping of a successful response's JSON body
+///
+/// Fields are addressed by dot paths such as `order.customer.name`; a path
+/// through an array applies to each of its elements. The steps run in field
+/// order, all with the upstream's names: `pick`, `coerce`, `rename`, then
+/// `flatten`.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Transform {
+    /// Paths to keep, dropping everything else; empty keeps the whole body
+    #[serde(default)]
+    pub pick: Vec<String>,
+    /// Path to the type its value is converted to
+    #[serde(default)]
+    pub coerce: BTreeMap<String, Coercion>,
+    /// Path to the field's new name, in the same object
+    #[serde(default)]
+    pub rename: BTreeMap<String, String>,
+    /// Joins nested objects' keys into their parent's with this separator, e.g. "_"
+    #[serde(default)]
+    pub flatten: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Coercion {
+    String,
+    Integer,
+    Number,
+    Boolean,
+    /// Wraps a single value, e.g. an XML element that only occurred once
+    Array,
+}
+
+fn segments(path: &str) -> Vec<&str> {
+    path.split('.').collect()
+}
+
+fn valid_path(path: &str) -> bool {
+    segments(path).iter().all(|segment| !segment.is_empty())
+}
+
+/// Calls `visit` with the object holding the last segment of `path`, for every such object
+fn visit_parents(
+    value: &mut Value,
+    path: &[&str],
+    visit: &mut impl FnMut(&mut Map<String, Value>, &str) -> Result<(), String>,
+) -> Result<(), String> {
+    match value {
+        Value::Array(items) => items.iter_mut().try_for_each(|item| visit_parents(item, path, visit)),
+        Value::Object(object) => match path {
+            [key] => visit(object, key),
+            [key, rest @ ..] => match object.get_mut(*key) {
+                Some(child) => visit_parents(child, rest, visit),
+                None => Ok(()),
+            },
+            [] => Ok(()),
+        },
+        _ => Ok(()),
+    }
+}
+
+/// The parts of `value` on `paths`, or `None` when none of them exist in it
+fn pick(value: &Value, paths: &[&[&str]]) -> Option<Value> {
+    if paths.iter().any(|path| path.is_empty()) {
+        return Some(value.clone());
+    }
+    match value {
+        Value::Array(items) => Some(Value::Array(items.iter().filter_map(|item| pick(item, paths)).collect())),
+        Value::Object(object) => {
+            let mut picked = Map::new();
+            for (key, child) in object {
+                let rest: Vec<&[&str]> =
+                    paths.iter().filter(|path| path[0] == key).map(|path| &path[1..]).collect();
+                if rest.is_empty() {
+                    continue;
+                }
+                if let Some(child) = pick(child, &rest) {
+                    picked.insert(key.clone(), child);
+                }
+            }
+            Some(Value::Object(picked))
+        }
+        _ => None,
+    }
+}
+
+fn flatten(value: Value, separator: &str) -> Value {
+    match value {
+        Value::Array(items) => Value::Array(items.into_iter().map(|item| flatten(item, separator)).collect()),
+        Value::Object(object) => {
+            let mut flat = Map::new();
+            flatten_into(&mut flat, None, object, separator);
+            Value::Object(flat)
+        }
+        other => other,
+    }
+}
+
+fn flatten_into(flat: &mut Map<String, Value>, prefix: Option<&str>, object: Map<String, Value>, separator: &str) {
+    for (key, value) in object {
+        let key = match prefix {
+            Some(prefix) => format!("{}{}{}", prefix, separator, key),
+            None => key,
+        };
+        match value {
+            Value::Object(nested) if !nested.is_empty() => flatten_into(flat, Some(&key), nested, separator),
+            other => {
+                flat.insert(key, flatten(other, separator));
+            }
+        }
+    }
+}
+
+impl Coercion {
+    /// Converts `value`; `null` stays `null`, and so does an empty string for scalar types
+    fn apply(self, value: Value) -> Result<Value, String> {
+        let text = match &value {
+            Value::Null => return Ok(if self == Coercion::Array { Value::Array(Vec::new()) } else { Value::Null }),
+            Value::String(text) => Some(text.trim()),
+            _ => None,
+        };
+        if text == Some("") && !matches!(self, Coercion::String | Coercion::Array) {
+            return Ok(Value::Null);
+        }
+        let converted = match (self, &value) {
+            (Coercion::Array, Value::Array(_)) => Some(value.clone()),
+            (Coercion::Array, _) => Some(Value::Array(vec![value.clone()])),
+            (Coercion::String, Value::String(_)) => Some(value.clone()),
+            (Coercion::String, Value::Number(number)) => Some(Value::String(number.to_string())),
+            (Coercion::String, Value::Bool(flag)) => Some(Value::String(flag.to_string())),
+            (Coercion::Integer, Value::Number(number)) => integer(number.as_f64()),
+            (Coercion::Integer, Value::String(_)) => text.and_then(|text| match text.parse::<i64>() {
+                Ok(whole) => Some(Value::from(whole)),
+                Err(_) => integer(text.parse().ok()),
+            }),
+            (Coercion::Number, Value::Number(_)) => Some(value.clone()),
+            (Coercion::Number, Value::String(_)) => text.and_then(number),
+            (Coercion::Boolean, Value::Bool(_)) => Some(value.clone()),
+            (Coercion::Boolean, Value::Number(number)) => match number.as_i64() {
+                Some(0) => Some(Value::Bool(false)),
+                Some(1) => Some(Value::Bool(true)),
+                _ => None,
+            },
+            (Coercion::Boolean, Value::String(_)) => match text.map(str::to_lowercase).as_deref() {
+                Some("true" | "1" | "yes") => Some(Value::Bool(true)),
+                Some("false" | "0" | "no") => Some(Value::Bool(false)),
+                _ => None,
+            },
+            _ => None,
+        };
+        converted.ok_or_else(|| format!("{} isn't {}", value, self.describe()))
+    }
+
+    fn describe(self) -> &'static str {
+        match self {
+            Coercion::String => "a string",
+            Coercion::Integer => "an integer",
+            Coercion::Number => "a number",
+            Coercion::Boolean => "a boolean",
+            Coercion::Array => "an array",
+        }
+    }
+}
+
+/// A whole number within `i64`, e.g. 42 from "42" or 42.0
+fn integer(value: Option<f64>) -> Option<Value> {
+    value
+        .filter(|value| value.fract() == 0.0 && value.abs() < i64::MAX as f64)
+        .map(|value| Value::from(value as i64))
+}
+
+fn number(text: &str) -> Option<Value> {
+    match text.parse::<i64>() {
+        Ok(whole) => Some(Value::from(whole)),
+        Err(_) => text.parse::<f64>().ok().and_then(Number::from_f64).map(Value::Number),
+    }
+}
+
+impl Transform {
+    pub fn validate(&self) -> Result<(), ProxyError> {
+        let mut paths = self.pick.iter().chain(self.coerce.keys()).chain(self.rename.keys());
+        if let Some(path) = paths.find(|path| !valid_path(path)) {
+            return Err(ProxyError::InvalidRequest(format!("transform: invalid path {:?}", path)));
+        }
+        if let Some((path, name)) = self.rename.iter().find(|(_, name)| name.is_empty() || name.contains('.')) {
+            return Err(ProxyError::InvalidRequest(format!(
+                "transform: {} must be renamed to a name without dots, not {:?}",
+                path, name
+            )));
+        }
+        if self.flatten.as_deref() == Some("") {
+            return Err(ProxyError::InvalidRequest("transform: flatten needs a separator, e.g. \"_\"".to_string()));
+        }
+        Ok(())
+    }
+
+    /// The reshaped `body`, or why it couldn't be reshaped
+    pub fn apply_to(&self, body: Value) -> Result<Value, String> {
+        if !body.is_object() && !body.is_array() {
+            return Err("the response body isn't a JSON object or array".to_string());
+        }
+        let mut body = if self.pick.is_empty() {
+            body
+        } else {
+            let paths: Vec<Vec<&str>> = self.pick.iter().map(|path| segments(path)).collect();
+            let paths: Vec<&[&str]> = paths.iter().map(Vec::as_slice).collect();
+            pick(&body, &paths).unwrap_or_default()
+        };
+        for (path, coercion) in &self.coerce {
+            visit_parents(&mut body, &segments(path), &mut |object, key| {
+                if let Some(value) = object.get_mut(key) {
+                    *value = coercion.apply(value.take()).map_err(|e| format!("{}: {}", path, e))?;
+                }
+                Ok(())
+            })?;
+        }
+        // Deepest first, so renaming a parent doesn't hide its renamed children
+        let mut renames: Vec<(&String, &String)> = self.rename.iter().collect();
+        renames.sort_by_key(|(path, _)| std::cmp::Reverse(path.matches('.').count()));
+        for (path, name) in renames {
+            visit_parents(&mut body, &segments(path), &mut |object, key| {
+                if let Some(value) = object.remove(key) {
+                    object.insert(name.clone(), value);
+                }
+                Ok(())
+            })?;
+        }
+        Ok(match &self.flatten {
+            Some(separator) => flatten(body, separator),
+            None => body,
+        })
+    }
+}
+
+/// Reshapes a successful response's body; error responses are returned as they are
+///
+/// The failure is boxed, as it carries the response's metadata.
+pub fn apply(
+    transform: &Transform,
+    outcome: Result<ApiResponse, Failure>,
+) -> Result<ApiResponse, Box<Failure>> {
+    let mut response = outcome?;
+    let body = match &mut response {
+        ApiResponse::Success(data) => &mut data.body,
+        ApiResponse::Minimal(data) => &mut data.body,
+        _ => return Ok(response),
+    };
+    match transform.apply_to(body.take()) {
+        Ok(transformed) => {
+            *body = transformed;
+            Ok(response)
+        }
+        Err(e) => Err(Box::new(Failure {
+            error: ProxyError::Upstream(format!("Can't transform the response body: {}", e)),
+            metadata: Some(response.metadata_mut().clone()),
+        })),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_transform_body() {
+        let transform: Transform = serde_json::from_value(json!({
+            "pick": ["order.id", "order.customer", "order.lines.sku", "order.lines.qty", "order.paid"],
+            "coerce": {"order.id": "integer", "order.lines.qty": "number", "order.paid": "boolean", "order.lines": "array"},
+            "rename": {"order.customer": "client", "order.lines.sku": "product_code"},
+            "flatten": "_"
+        }))
+        .unwrap();
+        assert!(transform.validate().is_ok());
+
+        let body = json!({"order": {
+            "id": "42",
+            "customer": {"name": "ACME", "vat": ""},
+            "lines": {"sku": "A-1", "qty": "2.5", "note": "gift"},
+            "paid": "1",
+            "internal": {"score": 7}
+        }});
+        assert_eq!(
+            transform.apply_to(body).unwrap(),
+            json!({
+                "order_id": 42,
+                "order_client_name": "ACME",
+                "order_client_vat": "",
+                "order_lines": [{"product_code": "A-1", "qty": 2.5}],
+                "order_paid": true
+            })
+        );
+
+        let bad = json!({"order": {"id": "forty-two"}});
+        assert_eq!(transform.apply_to(bad).unwrap_err(), "order.id: \"forty-two\" isn't an integer");
+        assert!(transform.apply_to(json!("<xml/>")).is_err());
+
+        assert_eq!(Coercion::Integer.apply(json!(" ")).unwrap(), Value::Null);
+        assert_eq!(Coercion::String.apply(json!(9.5)).unwrap(), json!("9.5"));
+        assert_eq!(Coercion::Array.apply(Value::Null).unwrap(), json!([]));
+        assert!(Coercion::String.apply(json!({})).is_err());
+
+        let invalid = |spec: Value| serde_json::from_value::<Transform>(spec).map_or(true, |t| t.validate().is_err());
+        assert!(invalid(json!({"pick": ["order..id"]})));
+        assert!(invalid(json!({"rename": {"id": "order.id"}})));
+        assert!(invalid(json!({"coerce": {"id": "date"}})));
+        assert!(invalid(json!({"flatten": ""})));
+    }
+}
@@ -0,0 +1,372 @@
+use reqwest::header::{HeaderMap, HeaderValue, USER_AGENT};
+use reqwest::Client;
+use serde::Serialize;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::time::Duration;
+use worker::*;
+
+use super::registry::{Failure, ProcessorContext};
+use super::response::{ApiResponse, ResponseMetadata};
+use super::schema::{self, ResponseSchemaSpec};
+use crate::breaker;
+use crate::canonical_url;
+use crate::cost::CostEstimate;
+use crate::credentials::{self, QueryKey, UpstreamAuth};
+use crate::deadline;
+use crate::default_headers::DefaultHeaders;
+use crate::error::ProxyError;
+use crate::expiry;
+use crate::incidents;
+use crate::limits::ResponseTooLarge;
+use crate::logger::{self, LogLevel};
+use crate::pacing_report;
+use crate::processors::common;
+use crate::retry::{self, RetryPolicies, RetryPolicy, SharedBudget};
+use crate::sandbox::SandboxRoute;
+use crate::soap_allowlist;
+use crate::{log_debug, log_error, log_info};
+
+/// `User-Agent` of upstream calls that don't set their own
+pub const DEFAULT_USER_AGENT: &str = "ApiProxy/1.0";
+
+thread_local! {
+    /// Built on first use; an isolate runs on a single thread, so the edge and
+    /// every Durable Object instance in it share one client
+    static CLIENT: Client = build_client();
+}
+
+fn build_client() -> Client {
+    let mut headers = HeaderMap::new();
+    headers.insert(USER_AGENT, HeaderValue::from_static(DEFAULT_USER_AGENT));
+    Client::builder().default_headers(headers).build().unwrap_or_default()
+}
+
+/// The isolate's shared HTTP client
+///
+/// Default headers apply only where a request doesn't set its own, so
+/// callers and handlers still override the `User-Agent`.
+pub fn client() -> Client {
+    CLIENT.with(Client::clone)
+}
+
+/// What a request would send upstream, reported by `validate_only` previews
+#[derive(Debug, Serialize)]
+pub struct UpstreamPreview {
+    pub method: String,
+    /// Full URL, with the query string for GET, HEAD and DELETE
+    pub url: String,
+    /// Names only, since values may hold secrets; stored credentials and
+    /// default headers are added by the processor and not listed
+    pub header_names: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub envelope: Option<String>,
+    /// Approximate bytes of one attempt
+    pub bytes: u64,
+}
+
+/// A request that makes one upstream call (HTTP, SOAP, ...)
+///
+/// Implementors get the shared processor pipeline from `execute`: region
+/// assertion, stored credentials and query-string keys, default headers, deadline, SSRF
+/// checks, circuit breaker, retry policy, incident tracking, cost estimates, response schema
+/// checks and the minimal envelope.
+#[allow(async_fn_in_trait)]
+pub trait UpstreamRequest: Clone {
+    fn url(&self) -> &str;
+    fn url_mut(&mut self) -> &mut String;
+    fn assert_region(&self) -> bool;
+    fn credential(&self) -> Option<&str>;
+    /// The request's `auth` block; only HTTP requests have one
+    fn auth(&self) -> Option<&UpstreamAuth> {
+        None
+    }
+    /// Adds the resolved key of `query_key` auth to what's sent
+    fn set_query_key(&mut self, _key: QueryKey) {}
+    /// Id the request carries for correlation (a WS-Addressing `MessageID`)
+    fn message_id(&self) -> Option<&str> {
+        None
+    }
+    /// SOAP action the request invokes, checked against the SOAP allowlist
+    fn soap_action(&self) -> Option<&str> {
+        None
+    }
+    fn headers_mut(&mut self) -> &mut HashMap<String, String>;
+    fn response_schema(&self) -> Option<&ResponseSchemaSpec>;
+    fn set_timeout(&mut self, timeout: Option<Duration>);
+
+    /// Limits the response body read; larger ones fail with `ResponseTooLarge`
+    fn set_max_response_bytes(&mut self, limit: u64);
+    /// Confines a call the sandbox tenant made to `route`, or refuses it
+    fn set_sandbox(&mut self, route: SandboxRoute) -> std::result::Result<(), ProxyError>;
+    /// Whether the caller asked for the `{status, body}` envelope only
+    fn minimal(&self) -> bool;
+    fn set_minimal(&mut self, minimal: bool);
+    /// The request's own `retries` block
+    fn retries(&self) -> Option<&RetryPolicy>;
+    /// Approximate bytes one attempt sends upstream
+    fn egress_bytes(&self) -> u64;
+    /// Whether a failed attempt can be retried without risking a second effect
+    /// (e.g. a duplicate order); `host` is the upstream's
+    async fn idempotent(&self, env: &Env, host: &str) -> bool;
+
+    /// Makes the upstream call once
+    async fn send(self, log_level: LogLevel) -> anyhow::Result<ApiResponse>;
+}
+
+/// Runs an upstream request through the processor pipeline
+pub async fn execute<R: UpstreamRequest>(
+    ctx: &ProcessorContext<'_>,
+    mut request: R,
+) -> std::result::Result<ApiResponse, Failure> {
+    let mut metadata = ResponseMetadata {
+        message_id: request.message_id().map(str::to_string),
+        ..Default::default()
+    };
+    #[cfg(feature = "mock-mode")]
+    crate::stubs::load(ctx.env).await;
+
+    // Send the URL the allowlist and cache saw, and tell the caller when it differs from theirs
+    match canonical_url::canonicalize(request.url()) {
+        Some(canonical) if canonical != request.url() => {
+            log_debug!(ctx.log_level, "Canonical URL: {}", canonical);
+            *request.url_mut() = canonical.clone();
+            metadata.canonical_url = Some(canonical);
+        }
+        Some(_) => {}
+        None => {
+            let error = ProxyError::InvalidRequest(format!("Invalid upstream URL: {}", request.url()));
+            return Err(Failure::new(error, metadata));
+        }
+    }
+    if let Some(retries) = request.retries() {
+        if let Err(e) = retries.validate_inline() {
+            return Err(Failure::new(ProxyError::InvalidRequest(format!("Invalid retries: {}", e)), metadata));
+        }
+    }
+    // Refuse SOAP actions the target host doesn't permit this tenant, checked against the host asked for
+    if let Some(action) = request.soap_action() {
+        if let Err(e) = soap_allowlist::check(ctx.env, ctx.tenant_id, request.url(), action).await {
+            log_info!("SOAP call refused: {}", e);
+            return Err(Failure::new(e, metadata));
+        }
+    }
+    // The sandbox tenant reaches only mocks and echo endpoints, and never with stored credentials
+    if let Some(sandbox) = &ctx.guard.sandbox {
+        let route = match sandbox.route(request.url()) {
+            Ok(_) if request.credential().is_some() || request.auth().is_some() => Err(ProxyError::PolicyViolation(
+                "The sandbox tenant can't use stored credentials".to_string(),
+            )),
+            route => route,
+        };
+        if let Err(e) = route.and_then(|route| request.set_sandbox(route)) {
+            log_info!("Sandbox call refused: {}", e);
+            return Err(Failure::new(e, metadata));
+        }
+    }
+    if request.assert_region() {
+        let assertion = common::check_region(ctx.region_code, ctx.actual_colo);
+        log_info!("Region assertion: expected {}, passed: {}", assertion.expected, assertion.passed);
+        if !assertion.passed {
+            metadata.region_assertion = Some(assertion.clone());
+            return Err(Failure::new(ProxyError::RegionAssertionFailed(assertion), metadata));
+        }
+        metadata.region_assertion = Some(assertion);
+    }
+
+    // Add the stored credential's headers, falling back to its backup while unhealthy
+    let credential = match request.credential().map(str::to_string) {
+        Some(name) => {
+            let (credential, usage) = credentials::resolve(ctx.env, &name, ctx.tenant_id, request.url()).await?;
+            request.headers_mut().extend(credential.headers.clone());
+            metadata.credential = Some(usage);
+            Some(credential)
+        }
+        None => None,
+    };
+
+    // Add the vault key of `query_key` auth at send time, so it never shows up in the URL
+    let auth_credential = match request.auth().cloned() {
+        Some(UpstreamAuth::QueryKey { param_name, key_ref }) => {
+            let (credential, usage) = credentials::resolve(ctx.env, &key_ref, ctx.tenant_id, request.url()).await?;
+            // Checked on the credential actually used, which may be the backup
+            if let Err(e) = credential.check_query_key_access(ctx.tenant_id, request.url()) {
+                log_info!("Query key refused: {}", e);
+                return Err(Failure::new(e, metadata));
+            }
+            let Some(value) = credential.secret.clone() else {
+                let error = ProxyError::InvalidRequest(format!("Credential {} has no secret", credential.name));
+                return Err(Failure::new(error, metadata));
+            };
+            request.set_query_key(QueryKey { param: param_name, value });
+            metadata.credential.get_or_insert(usage);
+            Some(credential)
+        }
+        None => None,
+    };
+
+    // Configured defaults (e.g. a partner id one carrier requires) go beneath the caller's
+    // headers; sandbox calls get none, since an echo endpoint would show them
+    let host = Url::parse(request.url())
+        .ok()
+        .and_then(|url| url.host_str().map(str::to_string))
+        .unwrap_or_default();
+    if ctx.guard.sandbox.is_none() {
+        DefaultHeaders::load(ctx.env)
+            .await
+            .apply(ctx.region_code, &host, request.headers_mut());
+    }
+    if let Some(forward) = &ctx.forward_end_user {
+        forward.apply(&host, request.headers_mut());
+    }
+
+    // Don't start an upstream call the caller has already given up on
+    if let Err(e) = deadline::remaining(ctx.deadline) {
+        return Err(Failure::new(e, metadata));
+    }
+    // Nor a stale one, after pacing held it up
+    if let Err(e) = expiry::check(ctx.expiry) {
+        log_info!("Upstream call dropped: {}", e);
+        return Err(Failure::new(e, metadata));
+    }
+
+    // Refuse internal targets, checking what the host resolves to now, not just the name the allowlist saw
+    if let Err(e) = ctx.guard.check(request.url(), ctx.cost).await {
+        log_info!("Upstream call refused: {}", e);
+        return Err(Failure::new(e, metadata));
+    }
+
+    // Don't hammer a host that keeps failing; the region's shards share one breaker per host
+    let region_code = ctx.region_code.to_lowercase();
+    let breaker_tracked = match breaker::check(ctx.env, ctx.state, &region_code, &host).await {
+        Ok(tracked) => tracked,
+        Err(e) => {
+            log_info!("Upstream call refused: {}", e);
+            return Err(Failure::new(e, metadata));
+        }
+    };
+
+    request.set_max_response_bytes(ctx.max_response_bytes);
+
+    // Send under the host's policy, the request's own `retries` or the
+    // tenant's policy; each attempt may only use what's left of the caller's budget
+    let policies = RetryPolicies::load(ctx.env).await;
+    let selected = policies.select(&host, ctx.tenant_id, request.retries());
+    let idempotent = match selected {
+        Some(_) => request.idempotent(ctx.env, &host).await,
+        None => true,
+    };
+    let attempts = Cell::new(0);
+    let attempt_ids = RefCell::new(Vec::new());
+    let sent_at = Date::now().as_millis();
+    let shared_budget = ctx.tenant_budget.map(|budget| SharedBudget {
+        env: ctx.env,
+        region_code: region_code.clone(),
+        tenant: ctx.tenant_id,
+        budget,
+        cost: ctx.cost,
+    });
+    let (result, retry_usage) = retry::run(
+        selected,
+        idempotent,
+        ctx.retry_budgets,
+        shared_budget.as_ref(),
+        ctx.deadline,
+        ctx.expiry,
+        ctx.log_level,
+        |timeout| {
+            attempts.set(attempts.get() + 1);
+            let mut attempt = request.clone();
+            attempt.set_timeout(timeout);
+            if let Some(attempt_id) = logger::next_attempt_id() {
+                log_info!("Upstream attempt {} to {}", attempt_id, host);
+                if let Some(header) = &ctx.attempt_header {
+                    attempt.headers_mut().insert(header.clone(), attempt_id.clone());
+                }
+                attempt_ids.borrow_mut().push(attempt_id);
+            }
+            let status = |result: &anyhow::Result<ApiResponse>| result.as_ref().ok().map(ApiResponse::status);
+            pacing_report::track(ctx.env, ctx.outbound, &host, false, attempt.send(ctx.log_level), status)
+        },
+    )
+    .await;
+    logger::add_upstream_ms(Date::now().as_millis().saturating_sub(sent_at));
+    metadata.retry = retry_usage;
+    if let Some(usage) = &mut metadata.retry {
+        usage.attempt_ids = attempt_ids.into_inner();
+    }
+    ctx.add_cost(CostEstimate::upstream(attempts.get(), request.egress_bytes()));
+    let is_error = !matches!(&result, Ok(response) if response.status() < 500);
+    incidents::observe(ctx.env, ctx.error_rates, &host, is_error).await;
+    // A success only needs recording when it closes a stored breaker
+    if is_error || breaker_tracked {
+        breaker::record(ctx.env, ctx.state, &region_code, &host, is_error).await;
+    }
+
+    // A cross-origin redirect has already been followed by the runtime, but
+    // its response is only returned if the target passes the same check
+    if let Ok(response) = &result {
+        if let Some(target) = response.redirected_to() {
+            if let Err(e) = ctx.guard.check(target, ctx.cost).await {
+                log_info!("Redirect response withheld: {}", e);
+                return Err(Failure::new(e, metadata));
+            }
+        }
+    }
+
+    if ctx.include_cost {
+        metadata.cost = Some(ctx.cost.get());
+    }
+
+    let mut api_response = match result {
+        Ok(api_response) => api_response,
+        Err(e) => {
+            log_error!("Upstream request error: {}", e);
+            let error = match (ctx.deadline, e.downcast_ref::<ResponseTooLarge>()) {
+                (Some(deadline), _) if deadline.is_exceeded() => ProxyError::DeadlineExceeded { deadline: deadline.0 },
+                (_, Some(too_large)) => ProxyError::ResponseTooLarge { limit: too_large.limit },
+                _ => ProxyError::Upstream(format!("{:#}", e)),
+            };
+            return Err(Failure::new(error, metadata));
+        }
+    };
+
+    for credential in credential.iter().chain(&auth_credential) {
+        credentials::record_outcome(ctx.env, ctx.state, credential, &api_response).await;
+    }
+    if !metadata.is_empty() {
+        // Keep what the handler itself recorded about the body
+        let response_metadata = api_response.metadata_mut();
+        metadata.original_charset = response_metadata.original_charset.take();
+        metadata.body_encoding = response_metadata.body_encoding.take();
+        *response_metadata = metadata;
+    }
+    if let Some(spec) = request.response_schema() {
+        if let Err(e) = schema::check_response(ctx.env, spec, &mut api_response).await {
+            log_error!("Response schema check failed: {}", e);
+            return Err(Failure {
+                error: e,
+                metadata: Some(api_response.metadata_mut().clone()),
+            });
+        }
+    }
+    if request.minimal() {
+        api_response = api_response.into_minimal();
+    }
+    Ok(api_response)
+}
+
+/// Turns the pipeline outcome into the processor's response
+pub fn respond(name: &str, outcome: std::result::Result<ApiResponse, Failure>) -> Result<Response> {
+    match outcome {
+        Ok(ApiResponse::Stream(streamed)) => {
+            log_info!("{} request streaming {}", name, streamed.status);
+            streamed.into_response()
+        }
+        Ok(api_response) => {
+            log_info!("{} request completed successfully", name);
+            Response::from_json(&api_response)
+        }
+        Err(failure) => failure.error.to_response(failure.metadata),
+    }
+}
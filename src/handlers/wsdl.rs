@@ -0,0 +1,438 @@
+use anyhow::Context as AnyhowContext;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::time::Duration;
+use worker::*;
+
+use super::http_handler::RequestData;
+use super::parse::from_json;
+use super::registry::{Failure, ProcessorContext, ProxyHandler};
+use super::response::ApiResponse;
+use super::upstream::{self, UpstreamPreview};
+use crate::error::ProxyError;
+use crate::processors::storage;
+use crate::xpath::{parse_tree, Element};
+use crate::{log_error, log_info};
+
+/// Storage namespace of WSDL summaries in the processor DO (see `storage`)
+pub const WSDL_NAMESPACE: &str = "wsdl";
+
+const DEFAULT_MAX_AGE_SECS: u64 = 24 * 60 * 60;
+
+const MAX_MAX_AGE_SECS: u64 = 7 * 24 * 60 * 60;
+
+/// A WSDL to describe (`X-Request-Type: wsdl`)
+#[derive(Debug, Deserialize)]
+pub struct WsdlRequest {
+    /// Where the WSDL is served, e.g. "https://soap.example.com/service?wsdl"
+    pub url: String,
+    /// Request headers for the WSDL fetch
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    /// Stored upstream credential whose headers are added to the fetch
+    #[serde(default)]
+    pub credential: Option<String>,
+    /// How long the summary is served from this shard's storage
+    #[serde(default = "default_max_age_secs")]
+    pub max_age_secs: u64,
+    /// Fetch the WSDL again even when a summary is stored
+    #[serde(default)]
+    pub refresh: bool,
+}
+
+fn default_max_age_secs() -> u64 {
+    DEFAULT_MAX_AGE_SECS
+}
+
+impl WsdlRequest {
+    /// The GET that fetches the WSDL, as an HTTP request
+    fn fetch_request(&self) -> std::result::Result<RequestData, ProxyError> {
+        serde_json::from_value(json!({
+            "url": self.url,
+            "method": "get",
+            "headers": self.headers,
+            "credential": self.credential,
+            "response_format": "text",
+        }))
+        .map_err(|e| ProxyError::InvalidRequest(format!("Invalid WSDL request: {}", e)))
+    }
+
+    pub fn preview(&self) -> std::result::Result<UpstreamPreview, ProxyError> {
+        Ok(self.fetch_request()?.preview())
+    }
+
+    /// Summaries are per tenant, since the fetch may have used the tenant's credentials
+    fn storage_key(&self, tenant_id: &str) -> String {
+        let hash = seahash::hash(format!("{}\n{}", tenant_id, self.url).as_bytes());
+        format!("{}/{:016x}", WSDL_NAMESPACE, hash)
+    }
+}
+
+/// What a WSDL offers, for building `SoapRequestData` without reading the WSDL
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WsdlSummary {
+    pub url: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target_namespace: Option<String>,
+    pub services: Vec<WsdlService>,
+    pub operations: Vec<WsdlOperation>,
+    /// Epoch millis
+    pub fetched_at: u64,
+    /// Whether the summary came from this shard's storage
+    #[serde(default)]
+    pub cached: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WsdlService {
+    pub name: String,
+    pub ports: Vec<WsdlPort>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WsdlPort {
+    pub name: String,
+    pub binding: String,
+    /// `soap:address` location, when the port has one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub address: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WsdlOperation {
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub documentation: Option<String>,
+    /// "rpc" or "document"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub style: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub soap_action: Option<String>,
+    /// Namespace of the operation element: the binding's `soap:body` namespace, or the target namespace
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub namespace: Option<String>,
+    /// Address of the first port that binds the operation
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub address: Option<String>,
+    pub input: Vec<WsdlParam>,
+    pub output: Vec<WsdlParam>,
+    /// A `SoapRequestData` body calling the operation, with `null` parameter values
+    pub soap_request: Value,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WsdlParam {
+    pub name: String,
+    /// Schema type as written in the WSDL, e.g. "xsd:string"
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+    pub type_name: Option<String>,
+    /// `minOccurs="0"`
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub optional: bool,
+    /// `maxOccurs` over 1
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub repeated: bool,
+}
+
+/// Summarizes a WSDL 1.1 document
+///
+/// Operations come from the port types; the first binding and port of each
+/// add its SOAP action, style, namespace and address. Document/literal parts
+/// are listed as the fields of their wrapper element.
+pub fn summarize(url: &str, xml: &str, fetched_at: u64) -> anyhow::Result<WsdlSummary> {
+    let definitions = parse_tree(xml).context("Malformed WSDL")?;
+    if definitions.name != "definitions" {
+        anyhow::bail!("Not a WSDL 1.1 document: root element is {}", definitions.name);
+    }
+    let target_namespace = definitions.attribute("targetNamespace").map(str::to_string);
+    let schemas: Vec<&Element> = definitions.children("types").flat_map(|types| types.children("schema")).collect();
+
+    let services = definitions
+        .children("service")
+        .map(|service| WsdlService {
+            name: service.attribute("name").unwrap_or_default().to_string(),
+            ports: service
+                .children("port")
+                .map(|port| WsdlPort {
+                    name: port.attribute("name").unwrap_or_default().to_string(),
+                    binding: port.reference("binding").unwrap_or_default().to_string(),
+                    address: port
+                        .child("address")
+                        .and_then(|address| address.attribute("location"))
+                        .map(str::to_string),
+                })
+                .collect(),
+        })
+        .collect::<Vec<_>>();
+
+    let mut operations = Vec::new();
+    for port_type in definitions.children("portType") {
+        let port_type_name = port_type.attribute("name").unwrap_or_default();
+        for operation in port_type.children("operation") {
+            let name = operation.attribute("name").unwrap_or_default();
+            let params = |direction: &str| {
+                operation
+                    .child(direction)
+                    .and_then(|message| message.reference("message"))
+                    .and_then(|message| definitions.named("message", message))
+                    .map(|message| message_params(message, &schemas))
+                    .unwrap_or_default()
+            };
+
+            // The first binding of this port type that binds the operation
+            let bound = definitions
+                .children("binding")
+                .filter(|binding| binding.reference("type") == Some(port_type_name))
+                .find_map(|binding| binding.named("operation", name).map(|bound| (binding, bound)));
+            let soap_operation = bound.and_then(|(_, bound)| bound.child("operation"));
+            let style = soap_operation
+                .and_then(|soap| soap.attribute("style"))
+                .or_else(|| bound.and_then(|(binding, _)| binding.child("binding")?.attribute("style")));
+            let body_namespace = bound
+                .and_then(|(_, bound)| bound.child("input")?.child("body")?.attribute("namespace"))
+                .map(str::to_string);
+            let binding_name = bound.and_then(|(binding, _)| binding.attribute("name"));
+            let address = services
+                .iter()
+                .flat_map(|service| &service.ports)
+                .find(|port| Some(port.binding.as_str()) == binding_name)
+                .and_then(|port| port.address.clone());
+
+            let namespace = body_namespace.or_else(|| target_namespace.clone());
+            let soap_action = soap_operation.and_then(|soap| soap.attribute("soapAction")).map(str::to_string);
+            let input = params("input");
+            let soap_request = json!({
+                "url": address,
+                "action": name,
+                "namespace": namespace,
+                "soap_action": soap_action,
+                "params": input.iter().map(|param| json!([param.name, null])).collect::<Vec<_>>(),
+            });
+            operations.push(WsdlOperation {
+                name: name.to_string(),
+                documentation: operation
+                    .child("documentation")
+                    .map(|doc| doc.text.clone())
+                    .filter(|doc| !doc.is_empty()),
+                style: style.map(str::to_string),
+                soap_action,
+                namespace,
+                address,
+                input,
+                output: params("output"),
+                soap_request,
+            });
+        }
+    }
+
+    Ok(WsdlSummary {
+        url: url.to_string(),
+        target_namespace,
+        services,
+        operations,
+        fetched_at,
+        cached: false,
+    })
+}
+
+/// Parameters of a message: its parts, or the fields of a part's element
+fn message_params(message: &Element, schemas: &[&Element]) -> Vec<WsdlParam> {
+    let mut params = Vec::new();
+    for part in message.children("part") {
+        let name = part.attribute("name").unwrap_or_default();
+        if let Some(type_name) = part.attribute("type") {
+            params.push(WsdlParam {
+                name: name.to_string(),
+                type_name: Some(type_name.to_string()),
+                optional: false,
+                repeated: false,
+            });
+            continue;
+        }
+        let element = part
+            .reference("element")
+            .and_then(|element| schemas.iter().find_map(|schema| schema.named("element", element)));
+        match element.and_then(|element| element_fields(element, schemas)) {
+            Some(fields) => params.extend(fields),
+            None => params.push(WsdlParam {
+                name: element.and_then(|element| element.attribute("name")).unwrap_or(name).to_string(),
+                type_name: element.and_then(|element| element.attribute("type")).map(str::to_string),
+                optional: false,
+                repeated: false,
+            }),
+        }
+    }
+    params
+}
+
+/// Fields of an element whose complex type is a sequence, all or choice of elements
+fn element_fields(element: &Element, schemas: &[&Element]) -> Option<Vec<WsdlParam>> {
+    let complex_type = match element.reference("type") {
+        Some(type_name) => schemas.iter().find_map(|schema| schema.named("complexType", type_name))?,
+        None => element.child("complexType")?,
+    };
+    let group = ["sequence", "all", "choice"].iter().find_map(|kind| complex_type.child(kind))?;
+    Some(
+        group
+            .children("element")
+            .map(|field| WsdlParam {
+                name: field.attribute("name").or_else(|| field.reference("ref")).unwrap_or_default().to_string(),
+                type_name: field.attribute("type").map(str::to_string),
+                optional: field.attribute("minOccurs") == Some("0"),
+                repeated: field
+                    .attribute("maxOccurs")
+                    .is_some_and(|max| max == "unbounded" || max.parse::<u32>().is_ok_and(|max| max > 1)),
+            })
+            .collect(),
+    )
+}
+
+/// The WSDL's summary, from this shard's storage while it's fresh
+async fn describe(ctx: &ProcessorContext<'_>, request: WsdlRequest) -> std::result::Result<WsdlSummary, Failure> {
+    if request.max_age_secs > MAX_MAX_AGE_SECS {
+        let message = format!("max_age_secs can't be over {}", MAX_MAX_AGE_SECS);
+        return Err(ProxyError::InvalidRequest(message).into());
+    }
+    let key = request.storage_key(ctx.tenant_id);
+    if !request.refresh {
+        match storage::get_live::<WsdlSummary>(ctx.state, &key).await {
+            Ok(Some(summary)) => return Ok(WsdlSummary { cached: true, ..summary }),
+            Ok(None) => {}
+            Err(e) => log_error!("Failed to read stored WSDL summary: {}", e),
+        }
+    }
+
+    let fetch = request.fetch_request()?;
+    let text = match upstream::execute(ctx, fetch).await? {
+        ApiResponse::Success(response) if (200..300).contains(&response.status) => match response.body {
+            Value::String(text) => text,
+            other => other.to_string(),
+        },
+        response => {
+            let message = format!("WSDL fetch returned {}", response.status());
+            return Err(ProxyError::Upstream(message).into());
+        }
+    };
+    let summary = summarize(&request.url, &text, Date::now().as_millis())
+        .map_err(|e| ProxyError::Upstream(format!("{:#}", e)))?;
+    log_info!("Described WSDL with {} operations", summary.operations.len());
+
+    let ttl = Duration::from_secs(request.max_age_secs);
+    if !ttl.is_zero() {
+        if let Err(e) = storage::put_with_ttl(ctx.state, &key, &summary, ttl).await {
+            log_error!("Failed to store WSDL summary: {}", e);
+        }
+    }
+    Ok(summary)
+}
+
+/// `X-Request-Type: wsdl`
+pub struct WsdlHandler;
+
+impl ProxyHandler for WsdlHandler {
+    const NAME: &'static str = "WSDL";
+    type Request = WsdlRequest;
+    type Outcome = std::result::Result<WsdlSummary, Failure>;
+
+    fn parse(&self, body: &str) -> std::result::Result<WsdlRequest, ProxyError> {
+        from_json(body, Self::NAME)
+    }
+
+    async fn execute(&self, ctx: &ProcessorContext<'_>, request: WsdlRequest) -> Self::Outcome {
+        describe(ctx, request).await
+    }
+
+    fn respond(&self, _ctx: &ProcessorContext<'_>, outcome: Self::Outcome) -> Result<Response> {
+        match outcome {
+            Ok(summary) => Response::from_json(&summary),
+            Err(failure) => failure.error.to_response(failure.metadata),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WSDL: &str = r#"<?xml version="1.0"?>
+<definitions name="Didx" targetNamespace="urn:didx" xmlns="http://schemas.xmlsoap.org/wsdl/"
+    xmlns:soap="http://schemas.xmlsoap.org/wsdl/soap/" xmlns:tns="urn:didx"
+    xmlns:xsd="http://www.w3.org/2001/XMLSchema">
+  <types>
+    <xsd:schema targetNamespace="urn:didx">
+      <xsd:element name="listDIDs">
+        <xsd:complexType><xsd:sequence>
+          <xsd:element name="country" type="xsd:string"/>
+          <xsd:element name="limit" type="xsd:int" minOccurs="0"/>
+        </xsd:sequence></xsd:complexType>
+      </xsd:element>
+      <xsd:element name="listDIDsResponse" type="tns:DidList"/>
+      <xsd:complexType name="DidList"><xsd:sequence>
+        <xsd:element name="did" type="xsd:string" maxOccurs="unbounded"/>
+      </xsd:sequence></xsd:complexType>
+    </xsd:schema>
+  </types>
+  <message name="getDIDCountryRequest"><part name="did" type="xsd:string"/></message>
+  <message name="getDIDCountryResponse"><part name="country" type="xsd:string"/></message>
+  <message name="listDIDsRequest"><part name="parameters" element="tns:listDIDs"/></message>
+  <message name="listDIDsResponse"><part name="parameters" element="tns:listDIDsResponse"/></message>
+  <portType name="DidxPort">
+    <operation name="getDIDCountry">
+      <documentation>Country of a DID</documentation>
+      <input message="tns:getDIDCountryRequest"/><output message="tns:getDIDCountryResponse"/>
+    </operation>
+    <operation name="listDIDs">
+      <input message="tns:listDIDsRequest"/><output message="tns:listDIDsResponse"/>
+    </operation>
+  </portType>
+  <binding name="DidxBinding" type="tns:DidxPort">
+    <soap:binding style="rpc" transport="http://schemas.xmlsoap.org/soap/http"/>
+    <operation name="getDIDCountry">
+      <soap:operation soapAction="urn:didx#getDIDCountry"/>
+      <input><soap:body use="encoded" namespace="urn:getDIDCountry"/></input>
+    </operation>
+    <operation name="listDIDs">
+      <soap:operation soapAction="urn:didx#listDIDs" style="document"/>
+      <input><soap:body use="literal"/></input>
+    </operation>
+  </binding>
+  <service name="DidxService">
+    <port name="DidxPort" binding="tns:DidxBinding"><soap:address location="https://soap.didx.example/"/></port>
+  </service>
+</definitions>"#;
+
+    #[test]
+    fn test_summarize_rpc_and_document_operations() {
+        let summary = summarize("https://soap.didx.example/?wsdl", WSDL, 1_000).unwrap();
+        assert_eq!(summary.target_namespace.as_deref(), Some("urn:didx"));
+        assert_eq!(summary.services[0].ports[0].binding, "DidxBinding");
+
+        let rpc = &summary.operations[0];
+        assert_eq!(rpc.name, "getDIDCountry");
+        assert_eq!(rpc.documentation.as_deref(), Some("Country of a DID"));
+        assert_eq!((rpc.style.as_deref(), rpc.soap_action.as_deref()), (Some("rpc"), Some("urn:didx#getDIDCountry")));
+        assert_eq!(rpc.namespace.as_deref(), Some("urn:getDIDCountry"));
+        assert_eq!(rpc.input[0].type_name.as_deref(), Some("xsd:string"));
+        assert_eq!(
+            rpc.soap_request,
+            json!({
+                "url": "https://soap.didx.example/",
+                "action": "getDIDCountry",
+                "namespace": "urn:getDIDCountry",
+                "soap_action": "urn:didx#getDIDCountry",
+                "params": [["did", null]]
+            })
+        );
+
+        // Document/literal parts list their wrapper element's fields
+        let document = &summary.operations[1];
+        assert_eq!((document.style.as_deref(), document.namespace.as_deref()), (Some("document"), Some("urn:didx")));
+        let inputs: Vec<(&str, bool)> =
+            document.input.iter().map(|param| (param.name.as_str(), param.optional)).collect();
+        assert_eq!(inputs, vec![("country", false), ("limit", true)]);
+        assert!(document.output[0].repeated);
+
+        assert!(summarize("https://x/", "<html></html>", 0).is_err());
+    }
+}
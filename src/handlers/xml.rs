@@ -0,0 +1,296 @@
+use anyhow::Context as AnyhowContext;
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::Reader;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::log_info;
+
+/// Options for converting an XML response body to JSON
+///
+/// Consumers disagree on conventions, so each request picks its own instead
+/// of post-processing the XML on the client side.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct XmlOptions {
+    /// Drop namespace prefixes (`SOAP-ENV:Body` becomes `Body`) and `xmlns` attributes
+    pub strip_namespaces: bool,
+    /// Represent a child element that occurs once as a value instead of a one-item array
+    pub collapse_single_arrays: bool,
+    /// Prefix for attribute keys
+    pub attribute_prefix: String,
+    /// Key for text content of elements that also have attributes or children
+    pub text_key: String,
+    /// Also return the XML as received, as `raw_body`, for consumers that archive it
+    pub raw_body: Option<RawBodyOptions>,
+}
+
+/// Options for returning the upstream XML alongside the converted body
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct RawBodyOptions {
+    /// Longest XML returned, in bytes; a longer one is cut at a character boundary
+    pub max_bytes: Option<usize>,
+}
+
+/// The upstream XML behind a converted body, transcoded to UTF-8
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RawBody {
+    pub xml: String,
+    /// Size of the whole XML in bytes, also when `xml` was truncated
+    pub bytes: usize,
+    pub truncated: bool,
+}
+
+impl Default for XmlOptions {
+    fn default() -> Self {
+        XmlOptions {
+            strip_namespaces: true,
+            collapse_single_arrays: true,
+            attribute_prefix: "@".to_string(),
+            text_key: "#text".to_string(),
+            raw_body: None,
+        }
+    }
+}
+
+/// An element whose end tag hasn't been read yet
+struct Frame {
+    name: String,
+    attributes: Map<String, Value>,
+    children: Vec<(String, Value)>,
+    text: String,
+}
+
+/// Body of an upstream response: `null` when there is none, converted from
+/// XML when the request asks for it, else parsed as JSON, else kept as a string
+///
+/// 204 and 205 never carry a body, whatever the upstream sends; an empty (or
+/// whitespace-only) body of any other status is `null` too, rather than `""`.
+pub fn response_body(status: u16, text: &str, options: Option<&XmlOptions>) -> Value {
+    if matches!(status, 204 | 205) || text.trim().is_empty() {
+        return Value::Null;
+    }
+    match options {
+        Some(options) => to_json(text, options).unwrap_or_else(|e| {
+            log_info!("XML to JSON conversion failed: {:#}", e);
+            Value::String(text.to_string())
+        }),
+        None => serde_json::from_str::<Value>(text).unwrap_or_else(|_| Value::String(text.to_string())),
+    }
+}
+
+/// The XML behind `response_body`, when the request asks for it alongside the conversion
+pub fn raw_body(status: u16, text: &str, options: Option<&XmlOptions>) -> Option<RawBody> {
+    let max_bytes = options?.raw_body.as_ref()?.max_bytes.unwrap_or(usize::MAX);
+    if matches!(status, 204 | 205) || text.trim().is_empty() {
+        return None;
+    }
+    let mut end = text.len().min(max_bytes);
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    Some(RawBody {
+        xml: text[..end].to_string(),
+        bytes: text.len(),
+        truncated: end < text.len(),
+    })
+}
+
+/// HTML escape helper for SOAP and XML-RPC parameter values
+///
+/// Control characters XML 1.0 can't carry, not even as references, are dropped.
+pub fn html_escape(s: &str) -> String {
+    s.chars()
+        .filter(|c| !matches!(c, '\0'..='\x08' | '\x0b' | '\x0c' | '\x0e'..='\x1f' | '\u{fffe}' | '\u{ffff}'))
+        .collect::<String>()
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+/// Converts an XML document to JSON, keyed by the root element name
+pub fn to_json(xml: &str, options: &XmlOptions) -> anyhow::Result<Value> {
+    let mut reader = Reader::from_str(xml);
+    reader.config_mut().trim_text(true);
+
+    let mut stack: Vec<Frame> = Vec::new();
+    let mut root: Option<(String, Value)> = None;
+
+    loop {
+        match reader.read_event().context("Malformed XML")? {
+            Event::Start(start) => stack.push(open(&start, options)?),
+            Event::Empty(start) => {
+                let frame = open(&start, options)?;
+                close(frame, &mut stack, &mut root, options);
+            }
+            Event::End(_) => {
+                let frame = stack.pop().context("Unbalanced XML end tag")?;
+                close(frame, &mut stack, &mut root, options);
+            }
+            Event::Text(text) => {
+                if let Some(frame) = stack.last_mut() {
+                    frame.text.push_str(&text.unescape().context("Invalid XML text")?);
+                }
+            }
+            Event::CData(data) => {
+                if let Some(frame) = stack.last_mut() {
+                    frame.text.push_str(&String::from_utf8_lossy(&data.into_inner()));
+                }
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+
+    let (name, value) = root.context("XML document has no root element")?;
+    let mut document = Map::new();
+    document.insert(name, value);
+    Ok(Value::Object(document))
+}
+
+fn name_of(qualified: &[u8], local: &[u8], options: &XmlOptions) -> String {
+    let name = if options.strip_namespaces { local } else { qualified };
+    String::from_utf8_lossy(name).into_owned()
+}
+
+fn open(start: &BytesStart, options: &XmlOptions) -> anyhow::Result<Frame> {
+    let mut attributes = Map::new();
+    for attribute in start.attributes() {
+        let attribute = attribute.context("Invalid XML attribute")?;
+        let key = attribute.key.as_ref();
+        if options.strip_namespaces && (key == b"xmlns" || key.starts_with(b"xmlns:")) {
+            continue;
+        }
+        let name = name_of(key, attribute.key.local_name().as_ref(), options);
+        let value = attribute.unescape_value().context("Invalid XML attribute value")?;
+        attributes.insert(
+            format!("{}{}", options.attribute_prefix, name),
+            Value::String(value.into_owned()),
+        );
+    }
+
+    Ok(Frame {
+        name: name_of(start.name().as_ref(), start.local_name().as_ref(), options),
+        attributes,
+        children: Vec::new(),
+        text: String::new(),
+    })
+}
+
+/// Finishes an element and attaches it to its parent (or makes it the root)
+fn close(frame: Frame, stack: &mut [Frame], root: &mut Option<(String, Value)>, options: &XmlOptions) {
+    let name = frame.name.clone();
+    let value = finish(frame, options);
+    match stack.last_mut() {
+        Some(parent) => parent.children.push((name, value)),
+        None => *root = Some((name, value)),
+    }
+}
+
+fn finish(frame: Frame, options: &XmlOptions) -> Value {
+    if frame.attributes.is_empty() && frame.children.is_empty() {
+        return if frame.text.is_empty() {
+            Value::Null
+        } else {
+            Value::String(frame.text)
+        };
+    }
+
+    let mut object = frame.attributes;
+
+    // Group repeated child names, keeping first-occurrence order
+    let mut groups: Vec<(String, Vec<Value>)> = Vec::new();
+    for (name, value) in frame.children {
+        match groups.iter_mut().find(|(existing, _)| *existing == name) {
+            Some((_, values)) => values.push(value),
+            None => groups.push((name, vec![value])),
+        }
+    }
+    for (name, mut values) in groups {
+        let value = if values.len() == 1 && options.collapse_single_arrays {
+            values.remove(0)
+        } else {
+            Value::Array(values)
+        };
+        object.insert(name, value);
+    }
+
+    if !frame.text.is_empty() {
+        object.insert(options.text_key.clone(), Value::String(frame.text));
+    }
+    Value::Object(object)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const RESPONSE: &str = r#"<?xml version="1.0"?><SOAP-ENV:Envelope xmlns:SOAP-ENV="http://schemas.xmlsoap.org/soap/envelope/"><SOAP-ENV:Body><ns1:getDIDsResponse xmlns:ns1="urn:didx"><did type="local">15551234</did><did type="toll">18005551</did><country>US &amp; CA</country></ns1:getDIDsResponse></SOAP-ENV:Body></SOAP-ENV:Envelope>"#;
+
+    #[test]
+    fn test_default_options() {
+        let value = to_json(RESPONSE, &XmlOptions::default()).unwrap();
+        assert_eq!(
+            value,
+            json!({"Envelope": {"Body": {"getDIDsResponse": {
+                "did": [
+                    {"@type": "local", "#text": "15551234"},
+                    {"@type": "toll", "#text": "18005551"}
+                ],
+                "country": "US & CA"
+            }}}})
+        );
+    }
+
+    #[test]
+    fn test_custom_options() {
+        let options = XmlOptions {
+            strip_namespaces: false,
+            collapse_single_arrays: false,
+            attribute_prefix: "_".to_string(),
+            text_key: "value".to_string(),
+            raw_body: None,
+        };
+        let value = to_json(RESPONSE, &options).unwrap();
+        let response = &value["SOAP-ENV:Envelope"]["SOAP-ENV:Body"][0]["ns1:getDIDsResponse"][0];
+        assert_eq!(response["_xmlns:ns1"], json!("urn:didx"));
+        assert_eq!(response["did"][1], json!({"_type": "toll", "value": "18005551"}));
+        assert_eq!(response["country"], json!(["US & CA"]));
+    }
+
+    #[test]
+    fn test_empty_bodies() {
+        let options = XmlOptions::default();
+        assert_eq!(response_body(204, "", None), Value::Null);
+        assert_eq!(response_body(205, "ignored", None), Value::Null);
+        assert_eq!(response_body(200, "", None), Value::Null);
+        assert_eq!(response_body(200, "\r\n", Some(&options)), Value::Null);
+        assert_eq!(response_body(202, "accepted", None), json!("accepted"));
+        assert_eq!(response_body(200, "{\"ok\": true}", None), json!({"ok": true}));
+    }
+
+    #[test]
+    fn test_raw_body() {
+        assert_eq!(raw_body(200, RESPONSE, Some(&XmlOptions::default())), None);
+
+        let options = XmlOptions {
+            raw_body: Some(RawBodyOptions::default()),
+            ..Default::default()
+        };
+        let raw = raw_body(200, RESPONSE, Some(&options)).unwrap();
+        assert_eq!((raw.xml.as_str(), raw.bytes, raw.truncated), (RESPONSE, RESPONSE.len(), false));
+        assert_eq!(raw_body(204, RESPONSE, Some(&options)), None);
+
+        // Cut short of the multi-byte character rather than through it
+        let options = XmlOptions {
+            raw_body: Some(RawBodyOptions { max_bytes: Some(5) }),
+            ..Default::default()
+        };
+        let raw = raw_body(200, "<r>Zürich</r>", Some(&options)).unwrap();
+        assert_eq!((raw.xml.as_str(), raw.bytes, raw.truncated), ("<r>Z", 14, true));
+    }
+}
@@ -0,0 +1,295 @@
+use serde::Deserialize;
+use serde_json::{json, Map, Number, Value};
+use std::collections::HashMap;
+use worker::*;
+
+use super::http_handler::RequestData;
+use super::parse::from_json;
+use super::registry::{Failure, ProcessorContext, ProxyHandler};
+use super::response::ApiResponse;
+use super::upstream::{self, UpstreamPreview};
+use super::xml::html_escape;
+use crate::error::ProxyError;
+use crate::log_debug;
+use crate::xpath::{parse_tree, Element};
+
+/// An XML-RPC call (`X-Request-Type: xmlrpc`), POSTed as a `methodCall`
+#[derive(Debug, Deserialize)]
+pub struct XmlRpcRequestData {
+    /// The XML-RPC endpoint, e.g. "https://rpc.carrier.example/RPC2"
+    pub url: String,
+    /// Remote method to call, e.g. "numbers.reserve"
+    pub method: String,
+    /// Positional parameters; `{"$base64": ...}` and `{"$datetime": ...}`
+    /// send the string as that type instead of a struct
+    #[serde(default)]
+    pub params: Vec<Value>,
+    /// Request headers as key-value pairs
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+}
+
+impl XmlRpcRequestData {
+    fn validate(&self) -> std::result::Result<(), ProxyError> {
+        let valid = |c: char| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | ':' | '/');
+        if self.method.is_empty() || !self.method.chars().all(valid) {
+            return Err(ProxyError::InvalidRequest(format!(
+                "XML-RPC method {:?} must be letters, digits, _, ., : or /",
+                self.method
+            )));
+        }
+        Ok(())
+    }
+
+    /// The `methodCall` document
+    fn method_call(&self) -> std::result::Result<String, ProxyError> {
+        let mut out = format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?><methodCall><methodName>{}</methodName><params>",
+            self.method
+        );
+        for param in &self.params {
+            out.push_str("<param>");
+            write_value(param, &mut out)?;
+            out.push_str("</param>");
+        }
+        out.push_str("</params></methodCall>");
+        Ok(out)
+    }
+
+    /// The POST carrying the `methodCall`, as an HTTP request
+    fn fetch_request(&self) -> std::result::Result<RequestData, ProxyError> {
+        self.validate()?;
+        let mut headers = self.headers.clone();
+        if !headers.keys().any(|name| name.eq_ignore_ascii_case("content-type")) {
+            headers.insert("Content-Type".to_string(), "text/xml".to_string());
+        }
+        serde_json::from_value(json!({
+            "url": self.url,
+            "method": "post",
+            "headers": headers,
+            "body": self.method_call()?,
+            "body_format": "raw",
+        }))
+        .map_err(|e| ProxyError::InvalidRequest(format!("Invalid XML-RPC request: {}", e)))
+    }
+
+    pub fn preview(&self) -> std::result::Result<UpstreamPreview, ProxyError> {
+        Ok(self.fetch_request()?.preview())
+    }
+}
+
+/// Appends `value` as an XML-RPC `<value>`
+///
+/// `null` is sent as the widely supported `<nil/>` extension. Integers
+/// must fit XML-RPC's 32-bit `int`; larger ones are refused rather than sent
+/// as the `i8` extension, which the older servers this is for reject.
+fn write_value(value: &Value, out: &mut String) -> std::result::Result<(), ProxyError> {
+    out.push_str("<value>");
+    match value {
+        Value::Null => out.push_str("<nil/>"),
+        Value::Bool(b) => out.push_str(&format!("<boolean>{}</boolean>", u8::from(*b))),
+        Value::Number(n) => match (n.as_i64(), n.as_f64()) {
+            (Some(int), _) if i32::try_from(int).is_ok() => out.push_str(&format!("<int>{}</int>", int)),
+            (None, Some(double)) if !n.is_u64() => out.push_str(&format!("<double>{}</double>", double)),
+            _ => {
+                return Err(ProxyError::InvalidRequest(format!(
+                    "{} is outside XML-RPC's 32-bit int range; send it as a string or a double",
+                    n
+                )))
+            }
+        },
+        Value::String(s) => out.push_str(&format!("<string>{}</string>", html_escape(s))),
+        Value::Array(items) => {
+            out.push_str("<array><data>");
+            for item in items {
+                write_value(item, out)?;
+            }
+            out.push_str("</data></array>");
+        }
+        Value::Object(members) => match typed(members) {
+            Some((tag, text)) => out.push_str(&format!("<{}>{}</{}>", tag, html_escape(text), tag)),
+            None => {
+                out.push_str("<struct>");
+                for (name, member) in members {
+                    out.push_str(&format!("<member><name>{}</name>", html_escape(name)));
+                    write_value(member, out)?;
+                    out.push_str("</member>");
+                }
+                out.push_str("</struct>");
+            }
+        },
+    }
+    out.push_str("</value>");
+    Ok(())
+}
+
+/// The XML-RPC type and text of a `{"$base64": ...}` or `{"$datetime": ...}` param
+fn typed(members: &Map<String, Value>) -> Option<(&'static str, &str)> {
+    let (key, Value::String(text)) = members.iter().next().filter(|_| members.len() == 1)? else {
+        return None;
+    };
+    match key.as_str() {
+        "$base64" => Some(("base64", text)),
+        "$datetime" => Some(("dateTime.iso8601", text)),
+        _ => None,
+    }
+}
+
+/// The param of an XML-RPC `methodResponse`, as JSON
+///
+/// A `fault` becomes `ProxyError::XmlRpcFault`; anything that isn't a
+/// `methodResponse` is an upstream error.
+pub fn response_result(xml: &str) -> std::result::Result<Value, ProxyError> {
+    let invalid = |reason: String| ProxyError::Upstream(format!("Invalid XML-RPC response: {}", reason));
+    let root = parse_tree(xml).map_err(|e| invalid(format!("{:#}", e)))?;
+    if root.name != "methodResponse" {
+        return Err(invalid(format!("the root element is {}, not methodResponse", root.name)));
+    }
+    if let Some(fault) = root.child("fault") {
+        let fault = fault.child("value").map(to_json).transpose().map_err(invalid)?.unwrap_or_default();
+        return Err(ProxyError::XmlRpcFault {
+            code: fault["faultCode"].as_i64().unwrap_or_default(),
+            message: fault["faultString"].as_str().unwrap_or("no faultString").to_string(),
+        });
+    }
+    let value = root
+        .child("params")
+        .and_then(|params| params.child("param"))
+        .and_then(|param| param.child("value"))
+        .ok_or_else(|| invalid("it has neither a param nor a fault".to_string()))?;
+    to_json(value).map_err(invalid)
+}
+
+/// A `<value>` as JSON; `dateTime.iso8601` and `base64` values become their text
+fn to_json(value: &Element) -> std::result::Result<Value, String> {
+    // A value without a type element is a string
+    let Some(typed) = value.children.first() else {
+        return Ok(Value::String(value.text.clone()));
+    };
+    let text = typed.text.trim();
+    let malformed = || format!("malformed {}: {:?}", typed.name, text);
+    Ok(match typed.name.as_str() {
+        "int" | "i4" | "i8" => Value::from(text.parse::<i64>().map_err(|_| malformed())?),
+        "boolean" => match text {
+            "1" => Value::Bool(true),
+            "0" => Value::Bool(false),
+            _ => return Err(malformed()),
+        },
+        "double" => text.parse().ok().and_then(Number::from_f64).map(Value::Number).ok_or_else(malformed)?,
+        "string" | "dateTime.iso8601" | "base64" => Value::String(typed.text.clone()),
+        "nil" => Value::Null,
+        "array" => Value::Array(match typed.child("data") {
+            Some(data) => data.children("value").map(to_json).collect::<std::result::Result<_, _>>()?,
+            None => Vec::new(),
+        }),
+        "struct" => {
+            let mut members = Map::new();
+            for member in typed.children("member") {
+                let name = member.child("name").ok_or("struct member without a name")?;
+                let value =
+                    member.child("value").ok_or_else(|| format!("struct member {} without a value", name.text))?;
+                members.insert(name.text.clone(), to_json(value)?);
+            }
+            Value::Object(members)
+        }
+        other => return Err(format!("unknown value type {}", other)),
+    })
+}
+
+pub struct XmlRpcHandler;
+
+impl ProxyHandler for XmlRpcHandler {
+    const NAME: &'static str = "XML-RPC";
+    type Request = XmlRpcRequestData;
+    type Outcome = std::result::Result<ApiResponse, Failure>;
+
+    fn parse(&self, body: &str) -> std::result::Result<XmlRpcRequestData, ProxyError> {
+        from_json(body, Self::NAME)
+    }
+
+    async fn execute(&self, ctx: &ProcessorContext<'_>, request: XmlRpcRequestData) -> Self::Outcome {
+        log_debug!(ctx.log_level, "XML-RPC method: {}, url: {}", request.method, request.url);
+        let mut response = upstream::execute(ctx, request.fetch_request()?).await?;
+        if let ApiResponse::Success(data) = &mut response {
+            let result = match &data.body {
+                Value::String(xml) => response_result(xml),
+                _ => Err(ProxyError::Upstream("The response isn't an XML-RPC methodResponse".to_string())),
+            };
+            match result {
+                Ok(result) => data.body = result,
+                Err(error) => return Err(Failure { error, metadata: data.metadata.take() }),
+            }
+        }
+        Ok(response)
+    }
+
+    fn respond(&self, _ctx: &ProcessorContext<'_>, outcome: Self::Outcome) -> Result<Response> {
+        upstream::respond(Self::NAME, outcome)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_method_call_and_response_round_trip() {
+        let request: XmlRpcRequestData = serde_json::from_value(json!({
+            "url": "https://rpc.carrier.example/RPC2",
+            "method": "numbers.reserve",
+            "params": [
+                "+1 555 <1234>",
+                {"count": 2, "rate": 0.5, "test": true, "note": null},
+                [1, "two"],
+                {"$datetime": "20261016T10:00:00"}
+            ]
+        }))
+        .unwrap();
+        assert_eq!(
+            request.method_call().unwrap(),
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?><methodCall><methodName>numbers.reserve</methodName><params>\
+             <param><value><string>+1 555 &lt;1234&gt;</string></value></param>\
+             <param><value><struct>\
+             <member><name>count</name><value><int>2</int></value></member>\
+             <member><name>note</name><value><nil/></value></member>\
+             <member><name>rate</name><value><double>0.5</double></value></member>\
+             <member><name>test</name><value><boolean>1</boolean></value></member>\
+             </struct></value></param>\
+             <param><value><array><data><value><int>1</int></value><value><string>two</string></value></data></array></value></param>\
+             <param><value><dateTime.iso8601>20261016T10:00:00</dateTime.iso8601></value></param>\
+             </params></methodCall>"
+        );
+        let fetch = request.fetch_request().unwrap();
+        assert_eq!(fetch.headers.get("Content-Type").map(String::as_str), Some("text/xml"));
+
+        let response = r#"<?xml version="1.0"?>
+            <methodResponse><params><param><value><struct>
+                <member><name>reserved</name><value><boolean>1</boolean></value></member>
+                <member><name>numbers</name><value><array><data>
+                    <value><string>+15551234567</string></value>
+                    <value>+15557654321</value>
+                </data></array></value></member>
+                <member><name>balance</name><value><double>12.5</double></value></member>
+                <member><name>id</name><value><i4>42</i4></value></member>
+            </struct></value></param></params></methodResponse>"#;
+        assert_eq!(
+            response_result(response).unwrap(),
+            json!({"reserved": true, "numbers": ["+15551234567", "+15557654321"], "balance": 12.5, "id": 42})
+        );
+
+        let fault = r#"<methodResponse><fault><value><struct>
+                <member><name>faultCode</name><value><int>4</int></value></member>
+                <member><name>faultString</name><value><string>Too many parameters.</string></value></member>
+            </struct></value></fault></methodResponse>"#;
+        let error = response_result(fault).unwrap_err();
+        assert_eq!(error.code(), "XMLRPC_FAULT");
+        assert_eq!(error.message(), "XML-RPC fault 4: Too many parameters.");
+
+        for bad in ["{\"ok\": true}", "<methodCall/>", "<methodResponse><params/></methodResponse>"] {
+            assert!(matches!(response_result(bad), Err(ProxyError::Upstream(_))), "{}", bad);
+        }
+        let wide = XmlRpcRequestData { params: vec![json!(1u64 << 40)], ..request };
+        assert!(wide.method_call().is_err());
+        assert!(XmlRpcRequestData { method: "a b".to_string(), ..wide }.validate().is_err());
+    }
+}
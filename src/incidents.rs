@@ -0,0 +1,237 @@
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use worker::wasm_bindgen::JsValue;
+use worker::*;
+
+use crate::{log_error, log_info};
+
+/// Length of the window an error rate is measured over
+const WINDOW_MS: u64 = 60_000;
+
+/// Fewer outcomes than this in a window never open an incident
+const MIN_SAMPLES: u32 = 20;
+
+/// Error rate that opens an incident
+const OPEN_RATE: f64 = 0.5;
+
+/// Error rate below which an open incident ends
+const CLOSE_RATE: f64 = 0.2;
+
+/// Incident kind recorded by the error rate detector
+pub const ERROR_RATE_SPIKE: &str = "error_rate_spike";
+
+/// An upstream incident with its timeline, stored in the D1 `incidents` table
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Incident {
+    pub id: i64,
+    pub host: String,
+    pub kind: String,
+    pub started_at: u64,
+    /// Unset while the incident is ongoing
+    pub ended_at: Option<u64>,
+    pub detail: Option<String>,
+}
+
+/// Change in a host's error rate state after a window closes
+#[derive(Debug, Clone, PartialEq)]
+pub enum Transition {
+    Opened { error_rate: f64, samples: u32 },
+    Closed,
+}
+
+/// Error rate of one host in fixed windows, with hysteresis between open and close
+#[derive(Debug, Clone, Default)]
+pub struct ErrorRate {
+    window_start: u64,
+    total: u32,
+    errors: u32,
+    spiking: bool,
+}
+
+impl ErrorRate {
+    /// Records one outcome at `now` (epoch millis); returns a transition when
+    /// the previous window just completed and changed the state
+    pub fn record(&mut self, now: u64, is_error: bool) -> Option<Transition> {
+        let mut transition = None;
+        if now >= self.window_start + WINDOW_MS {
+            transition = self.evaluate();
+            self.window_start = now;
+            self.total = 0;
+            self.errors = 0;
+        }
+        self.total += 1;
+        self.errors += u32::from(is_error);
+        transition
+    }
+
+    fn evaluate(&mut self) -> Option<Transition> {
+        let rate = if self.total == 0 { 0.0 } else { self.errors as f64 / self.total as f64 };
+        if !self.spiking && self.total >= MIN_SAMPLES && rate >= OPEN_RATE {
+            self.spiking = true;
+            return Some(Transition::Opened {
+                error_rate: rate,
+                samples: self.total,
+            });
+        }
+        if self.spiking && rate < CLOSE_RATE {
+            self.spiking = false;
+            return Some(Transition::Closed);
+        }
+        None
+    }
+}
+
+/// Per-DO error rates by host, kept in memory for the life of the instance
+pub type ErrorRates = RefCell<HashMap<String, ErrorRate>>;
+
+/// Incident timeline in the optional DB D1 database
+pub struct IncidentLog {
+    db: D1Database,
+}
+
+impl IncidentLog {
+    /// Returns `None` when no DB database is bound, which disables incident recording
+    pub fn new(env: &Env) -> Option<IncidentLog> {
+        env.d1("DB").ok().map(|db| IncidentLog { db })
+    }
+
+    /// Opens an incident unless one of the same kind is already open for the host
+    pub async fn open(&self, host: &str, kind: &str, at: u64, detail: &str) -> Result<()> {
+        self.db
+            .prepare(
+                "INSERT INTO incidents (host, kind, started_at, detail) \
+                 SELECT ?1, ?2, ?3, ?4 WHERE NOT EXISTS \
+                 (SELECT 1 FROM incidents WHERE host = ?1 AND kind = ?2 AND ended_at IS NULL)",
+            )
+            .bind(&[host.into(), kind.into(), JsValue::from(at as f64), detail.into()])?
+            .run()
+            .await?;
+        Ok(())
+    }
+
+    /// Ends the host's open incident of this kind, if any
+    pub async fn close(&self, host: &str, kind: &str, at: u64) -> Result<()> {
+        self.db
+            .prepare("UPDATE incidents SET ended_at = ?3 WHERE host = ?1 AND kind = ?2 AND ended_at IS NULL")
+            .bind(&[host.into(), kind.into(), JsValue::from(at as f64)])?
+            .run()
+            .await?;
+        Ok(())
+    }
+
+    /// Incidents overlapping `[since, ..)`, newest first
+    ///
+    /// `after` is the `(started_at, id)` of the last incident of the previous
+    /// page; ties on `started_at` are ordered by id.
+    pub async fn list(
+        &self,
+        host: Option<&str>,
+        since: u64,
+        open_only: bool,
+        after: Option<(u64, i64)>,
+        limit: u32,
+    ) -> Result<Vec<Incident>> {
+        let result = self
+            .db
+            .prepare(
+                "SELECT id, host, kind, started_at, ended_at, detail FROM incidents \
+                 WHERE (?1 IS NULL OR host = ?1) AND (ended_at IS NULL OR ended_at >= ?2) \
+                 AND (?3 = 0 OR ended_at IS NULL) \
+                 AND (?4 IS NULL OR started_at < ?4 OR (started_at = ?4 AND id < ?5)) \
+                 ORDER BY started_at DESC, id DESC LIMIT ?6",
+            )
+            .bind(&[
+                host.map(JsValue::from).unwrap_or(JsValue::NULL),
+                JsValue::from(since as f64),
+                JsValue::from(u32::from(open_only)),
+                after.map(|(started_at, _)| JsValue::from(started_at as f64)).unwrap_or(JsValue::NULL),
+                JsValue::from(after.map(|(_, id)| id as f64).unwrap_or(0.0)),
+                JsValue::from(limit),
+            ])?
+            .all()
+            .await?;
+        result.results::<Incident>()
+    }
+
+    /// Number of incidents `list` would return across all pages
+    pub async fn count(&self, host: Option<&str>, since: u64, open_only: bool) -> Result<u64> {
+        #[derive(Deserialize)]
+        struct Count {
+            total: u64,
+        }
+        let count = self
+            .db
+            .prepare(
+                "SELECT COUNT(*) AS total FROM incidents \
+                 WHERE (?1 IS NULL OR host = ?1) AND (ended_at IS NULL OR ended_at >= ?2) \
+                 AND (?3 = 0 OR ended_at IS NULL)",
+            )
+            .bind(&[
+                host.map(JsValue::from).unwrap_or(JsValue::NULL),
+                JsValue::from(since as f64),
+                JsValue::from(u32::from(open_only)),
+            ])?
+            .first::<Count>(None)
+            .await?;
+        Ok(count.map(|count| count.total).unwrap_or(0))
+    }
+}
+
+/// Feeds one upstream outcome into the host's error rate and records
+/// incident transitions; never fails the request
+pub async fn observe(env: &Env, rates: &ErrorRates, host: &str, is_error: bool) {
+    if host.is_empty() {
+        return;
+    }
+    let now = Date::now().as_millis();
+    let transition = rates.borrow_mut().entry(host.to_string()).or_default().record(now, is_error);
+    let transition = match transition {
+        Some(transition) => transition,
+        None => return,
+    };
+    let log = match IncidentLog::new(env) {
+        Some(log) => log,
+        None => return,
+    };
+
+    let result = match transition {
+        Transition::Opened { error_rate, samples } => {
+            log_info!("Incident opened for {}: {:.0}% errors over {} requests", host, error_rate * 100.0, samples);
+            let detail = format!("{:.0}% of {} requests failed within a minute", error_rate * 100.0, samples);
+            log.open(host, ERROR_RATE_SPIKE, now, &detail).await
+        }
+        Transition::Closed => {
+            log_info!("Incident closed for {}", host);
+            log.close(host, ERROR_RATE_SPIKE, now).await
+        }
+    };
+    if let Err(e) = result {
+        log_error!("Failed to record incident for {}: {}", host, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_rate_opens_and_closes_with_hysteresis() {
+        let mut rate = ErrorRate::default();
+        for i in 0..30 {
+            assert_eq!(rate.record(1_000 + i, i % 3 != 0), None);
+        }
+
+        // 20 of 30 failed; the first outcome of the next window reports it
+        let opened = rate.record(61_000, false);
+        assert!(matches!(opened, Some(Transition::Opened { samples: 30, .. })));
+
+        // 20% errors is not below the close threshold
+        for i in 0..9 {
+            rate.record(61_001 + i, i < 2);
+        }
+        assert_eq!(rate.record(121_000, false), None);
+
+        assert_eq!(rate.record(181_000, false), Some(Transition::Closed));
+    }
+}
@@ -0,0 +1,135 @@
+use serde_json::Value;
+
+/// A parsed JSONPath expression, e.g. `$.body.items[0].id`
+///
+/// Supports the subset callers need to pick fields out of a response:
+/// `.name` and `['name']` children, `[n]` indexes (negative ones count from
+/// the end) and `*` / `[*]` wildcards. Filters and recursive descent aren't.
+#[derive(Debug, Clone, PartialEq)]
+pub struct JsonPath {
+    segments: Vec<Segment>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Child(String),
+    Index(i64),
+    Wildcard,
+}
+
+impl JsonPath {
+    pub fn parse(path: &str) -> Result<JsonPath, String> {
+        let rest = path
+            .trim()
+            .strip_prefix('$')
+            .ok_or_else(|| format!("JSONPath must start with $: {}", path))?;
+        let mut segments = Vec::new();
+        let mut chars = rest.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '.' => {
+                    let mut name = String::new();
+                    while let Some(&c) = chars.peek() {
+                        if c == '.' || c == '[' {
+                            break;
+                        }
+                        name.push(c);
+                        chars.next();
+                    }
+                    segments.push(match name.as_str() {
+                        "" => return Err(format!("Empty JSONPath segment in {}", path)),
+                        "*" => Segment::Wildcard,
+                        _ => Segment::Child(name),
+                    });
+                }
+                '[' => {
+                    let mut inner = String::new();
+                    let mut quote = None;
+                    let mut quoted = false;
+                    loop {
+                        let c = chars.next().ok_or_else(|| format!("Unclosed [ in JSONPath {}", path))?;
+                        match (quote, c) {
+                            (None, ']') => break,
+                            (None, '\'' | '"') if inner.is_empty() && !quoted => {
+                                quote = Some(c);
+                                quoted = true;
+                            }
+                            (Some(q), c) if c == q => quote = None,
+                            _ => inner.push(c),
+                        }
+                    }
+                    segments.push(match inner.trim() {
+                        _ if quoted => Segment::Child(inner),
+                        "*" => Segment::Wildcard,
+                        index => Segment::Index(
+                            index
+                                .parse()
+                                .map_err(|_| format!("Invalid JSONPath index [{}] in {}", index, path))?,
+                        ),
+                    });
+                }
+                _ => return Err(format!("Unexpected {:?} in JSONPath {}", c, path)),
+            }
+        }
+        Ok(JsonPath { segments })
+    }
+
+    /// Every value the path matches in `root`, in document order
+    pub fn select<'a>(&self, root: &'a Value) -> Vec<&'a Value> {
+        let mut current = vec![root];
+        for segment in &self.segments {
+            current = current
+                .into_iter()
+                .flat_map(|value| -> Vec<&'a Value> {
+                    match (segment, value) {
+                        (Segment::Child(name), Value::Object(map)) => map.get(name).into_iter().collect(),
+                        (Segment::Index(index), Value::Array(items)) => {
+                            let index = if *index < 0 { items.len() as i64 + index } else { *index };
+                            usize::try_from(index).ok().and_then(|index| items.get(index)).into_iter().collect()
+                        }
+                        (Segment::Wildcard, Value::Object(map)) => map.values().collect(),
+                        (Segment::Wildcard, Value::Array(items)) => items.iter().collect(),
+                        _ => Vec::new(),
+                    }
+                })
+                .collect();
+        }
+        current
+    }
+
+    /// Whether the path can match more than one value
+    pub fn is_multi(&self) -> bool {
+        self.segments.contains(&Segment::Wildcard)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_select() {
+        let response = json!({
+            "status": 200,
+            "body": {"access_token": "t0k", "items": [{"id": 1}, {"id": 2}], "odd.key": true}
+        });
+        let select = |path: &str| -> Vec<Value> {
+            JsonPath::parse(path).unwrap().select(&response).into_iter().cloned().collect()
+        };
+
+        assert_eq!(select("$.body.access_token"), vec![json!("t0k")]);
+        assert_eq!(select("$.body.items[-1].id"), vec![json!(2)]);
+        assert_eq!(select("$.body.items[*].id"), vec![json!(1), json!(2)]);
+        assert_eq!(select("$['body']['odd.key']"), vec![json!(true)]);
+        assert_eq!(select("$"), vec![response.clone()]);
+        assert!(select("$.body.missing").is_empty());
+        assert!(select("$.body.items[5]").is_empty());
+
+        assert!(JsonPath::parse("body.token").is_err());
+        assert!(JsonPath::parse("$.body..token").is_err());
+        assert!(JsonPath::parse("$.items[x]").is_err());
+        assert!(JsonPath::parse("$.items[0").is_err());
+        assert!(JsonPath::parse("$.items[*]").unwrap().is_multi());
+    }
+}
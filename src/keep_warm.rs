@@ -0,0 +1,172 @@
+use futures_util::future::join_all;
+use serde::{Deserialize, Serialize};
+use worker::*;
+
+use crate::config::Config;
+use crate::drain::RegionDrains;
+use crate::error::ProxyError;
+use crate::routing::{self, REGION_CODES};
+use crate::{log_error, log_info};
+
+/// KV key holding the keep-warm settings
+pub const KEEP_WARM_KEY: &str = "keep-warm";
+
+/// Longest allowed time between two pings of a shard
+const MAX_INTERVAL_MINUTES: u32 = 60;
+
+/// Regions whose shards are pinged on a schedule, so callers don't pay for
+/// Durable Object cold starts
+///
+/// Off until regions are listed. The worker's cron trigger fires every
+/// minute; pings go out on the minutes divisible by `interval_minutes`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KeepWarm {
+    #[serde(default)]
+    pub regions: Vec<String>,
+    #[serde(default = "default_interval_minutes")]
+    pub interval_minutes: u32,
+}
+
+fn default_interval_minutes() -> u32 {
+    5
+}
+
+impl Default for KeepWarm {
+    fn default() -> Self {
+        KeepWarm {
+            regions: Vec::new(),
+            interval_minutes: default_interval_minutes(),
+        }
+    }
+}
+
+/// What pinging one region found
+#[derive(Debug, Serialize)]
+pub struct PingReport {
+    pub region: String,
+    pub pinged: u32,
+    /// Shards whose instance the ping had to start
+    pub cold: u32,
+    pub failed: u32,
+    /// Drained regions aren't pinged
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub skipped: bool,
+}
+
+impl KeepWarm {
+    /// Loads the settings from the CONFIG KV namespace; unreadable config pings nothing
+    pub async fn load(env: &Env) -> KeepWarm {
+        let kv = match env.kv("CONFIG") {
+            Ok(kv) => kv,
+            Err(_) => return KeepWarm::default(),
+        };
+        match kv.get(KEEP_WARM_KEY).cache_ttl(60).json::<KeepWarm>().await {
+            Ok(settings) => settings.unwrap_or_default(),
+            Err(e) => {
+                log_error!("Failed to load keep-warm settings, pinging none: {}", e);
+                KeepWarm::default()
+            }
+        }
+    }
+
+    /// Reads the settings bypassing the edge cache, for the admin API
+    pub async fn load_fresh(env: &Env) -> Result<KeepWarm> {
+        Ok(env.kv("CONFIG")?.get(KEEP_WARM_KEY).json::<KeepWarm>().await?.unwrap_or_default())
+    }
+
+    pub async fn save(&self, env: &Env) -> Result<()> {
+        env.kv("CONFIG")?
+            .put(KEEP_WARM_KEY, serde_json::to_string(self)?)?
+            .execute()
+            .await?;
+        Ok(())
+    }
+
+    pub fn validate(&self) -> std::result::Result<(), ProxyError> {
+        if let Some(region) = self.regions.iter().find(|region| !REGION_CODES.contains(&region.as_str())) {
+            return Err(ProxyError::InvalidRequest(format!("Unknown region: {}", region)));
+        }
+        if self.interval_minutes == 0 || self.interval_minutes > MAX_INTERVAL_MINUTES {
+            return Err(ProxyError::InvalidRequest(format!(
+                "interval_minutes must be 1-{}",
+                MAX_INTERVAL_MINUTES
+            )));
+        }
+        Ok(())
+    }
+
+    /// Whether pings are due at `scheduled_at`, the cron tick in epoch millis
+    pub fn is_due(&self, scheduled_at: u64) -> bool {
+        !self.regions.is_empty() && (scheduled_at / 60_000).is_multiple_of(u64::from(self.interval_minutes.max(1)))
+    }
+}
+
+/// Pings every shard of the configured regions, the shards of a region concurrently
+pub async fn run(env: &Env, config: &Config, settings: &KeepWarm) -> Vec<PingReport> {
+    let drains = RegionDrains::load(env).await;
+    let mut reports = Vec::new();
+    for region in &settings.regions {
+        let mut report = PingReport {
+            region: region.clone(),
+            pinged: 0,
+            cold: 0,
+            failed: 0,
+            skipped: drains.get(region).is_some(),
+        };
+        if !report.skipped {
+            let shards: Vec<String> = (0..config.pool_size(region))
+                .map(|index| routing::shard_name(region, index))
+                .collect();
+            let results = join_all(shards.iter().map(|shard| ping(env, shard))).await;
+            for (shard, result) in shards.iter().zip(results) {
+                match result {
+                    Ok(cold) => {
+                        report.pinged += 1;
+                        report.cold += u32::from(cold);
+                    }
+                    Err(e) => {
+                        log_error!("Keep-warm ping of {} failed: {}", shard, e);
+                        report.failed += 1;
+                    }
+                }
+            }
+        }
+        log_info!(
+            "Keep-warm {}: {} shards pinged, {} cold, {} failed{}",
+            report.region,
+            report.pinged,
+            report.cold,
+            report.failed,
+            if report.skipped { " (draining, skipped)" } else { "" }
+        );
+        reports.push(report);
+    }
+    reports
+}
+
+/// Pings one shard; returns whether its instance had to be started
+async fn ping(env: &Env, shard: &str) -> Result<bool> {
+    let stub = routing::shard_stub(env, shard)?;
+    let mut response = stub.fetch_with_str("http://internal/__internal/ping").await?;
+    let answer: serde_json::Value = response.json().await?;
+    Ok(answer["cold"].as_bool().unwrap_or_default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pings_on_interval_minutes() {
+        let settings: KeepWarm = serde_json::from_str(r#"{"regions": ["weur"], "interval_minutes": 5}"#).unwrap();
+        assert!(settings.is_due(10 * 60_000));
+        assert!(settings.is_due(10 * 60_000 + 59_999));
+        assert!(!settings.is_due(11 * 60_000));
+
+        // Nothing is pinged until regions are listed
+        assert!(!KeepWarm::default().is_due(0));
+        assert!(KeepWarm { regions: vec!["mars".to_string()], ..KeepWarm::default() }.validate().is_err());
+        assert!(KeepWarm { interval_minutes: 0, ..KeepWarm::default() }.validate().is_err());
+        assert!(KeepWarm::default().validate().is_ok());
+    }
+}
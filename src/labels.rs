@@ -0,0 +1,123 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::error::ProxyError;
+
+/// Header carrying request labels, e.g. `X-Labels: team=billing,flow=port-in`
+pub const LABELS_HEADER: &str = "X-Labels";
+
+/// Maximum labels on one request
+const MAX_LABELS: usize = 10;
+
+/// Maximum length of a label key or value
+const MAX_LABEL_LENGTH: usize = 64;
+
+/// Caller-chosen key/value pairs for attributing and filtering traffic
+///
+/// Labels are logged, forwarded to the processors, stored with archived
+/// requests and usage, and accepted as filters by the reporting endpoints.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Labels(pub BTreeMap<String, String>);
+
+fn is_valid_label_part(part: &str) -> bool {
+    !part.is_empty()
+        && part.len() <= MAX_LABEL_LENGTH
+        && part.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.' || c == '/')
+}
+
+impl Labels {
+    /// Parses `key=value` pairs separated by commas
+    pub fn parse(header: &str) -> std::result::Result<Labels, ProxyError> {
+        let mut labels = Labels::default();
+        for pair in header.split(',').map(str::trim).filter(|pair| !pair.is_empty()) {
+            let (key, value) = pair
+                .split_once('=')
+                .ok_or_else(|| ProxyError::InvalidRequest(format!("Label must be key=value: {}", pair)))?;
+            labels.insert(key.trim(), value.trim())?;
+        }
+        Ok(labels)
+    }
+
+    /// Reads the optional top-level `labels` object of a request body
+    pub fn from_body(body: &str) -> std::result::Result<Labels, ProxyError> {
+        let value = match serde_json::from_str::<Value>(body) {
+            Ok(value) => value,
+            Err(_) => return Ok(Labels::default()),
+        };
+        let object = match value.get("labels") {
+            Some(Value::Object(object)) => object,
+            Some(_) => return Err(ProxyError::InvalidRequest("labels must be an object of strings".to_string())),
+            None => return Ok(Labels::default()),
+        };
+        let mut labels = Labels::default();
+        for (key, value) in object {
+            let value = value
+                .as_str()
+                .ok_or_else(|| ProxyError::InvalidRequest(format!("Label {} must be a string", key)))?;
+            labels.insert(key, value)?;
+        }
+        Ok(labels)
+    }
+
+    fn insert(&mut self, key: &str, value: &str) -> std::result::Result<(), ProxyError> {
+        if !is_valid_label_part(key) || !is_valid_label_part(value) {
+            return Err(ProxyError::InvalidRequest(format!(
+                "Invalid label {}={} (1-{} characters of [A-Za-z0-9-_./])",
+                key, value, MAX_LABEL_LENGTH
+            )));
+        }
+        self.0.insert(key.to_string(), value.to_string());
+        if self.0.len() > MAX_LABELS {
+            return Err(ProxyError::InvalidRequest(format!("At most {} labels are allowed", MAX_LABELS)));
+        }
+        Ok(())
+    }
+
+    /// Adds `other`'s labels, keeping this set's value on conflicts
+    pub fn merge(mut self, other: Labels) -> Labels {
+        for (key, value) in other.0 {
+            self.0.entry(key).or_insert(value);
+        }
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// True when every label of `filter` is present with the same value
+    pub fn matches(&self, filter: &Labels) -> bool {
+        filter.0.iter().all(|(key, value)| self.0.get(key) == Some(value))
+    }
+}
+
+/// Canonical `key=value,key=value` form, sorted by key
+impl fmt::Display for Labels {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let pairs: Vec<String> = self.0.iter().map(|(key, value)| format!("{}={}", key, value)).collect();
+        write!(f, "{}", pairs.join(","))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_labels_parse_merge_and_match() {
+        let header = Labels::parse("team=billing, flow=port-in").unwrap();
+        let body = Labels::from_body(r#"{"url": "https://x.example", "labels": {"team": "ops", "env": "prod"}}"#).unwrap();
+        let labels = header.merge(body);
+        assert_eq!(labels.to_string(), "env=prod,flow=port-in,team=billing");
+
+        assert!(labels.matches(&Labels::parse("team=billing").unwrap()));
+        assert!(!labels.matches(&Labels::parse("team=ops").unwrap()));
+        assert!(labels.matches(&Labels::default()));
+
+        assert!(Labels::parse("team").is_err());
+        assert!(Labels::parse("team=bill ing").is_err());
+    }
+}
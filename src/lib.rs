@@ -1,38 +1,311 @@
 use worker::*;
-use std::collections::hash_map::DefaultHasher;
-use std::hash::{Hash, Hasher};
 
+mod admin;
+mod allowlist;
+#[cfg(feature = "archive-r2")]
+mod archive;
 mod auth;
+mod batch;
+mod breaker;
+mod cache;
+mod cache_warm;
+mod canonical_url;
+mod capabilities;
+mod config;
+/// Typed wire types and a reqwest client for Rust services calling the proxy
+#[cfg(feature = "client")]
+pub mod client;
+#[cfg(feature = "archive-r2")]
+mod capture;
+#[cfg(feature = "archive-r2")]
+mod compression;
+mod cost;
+mod credentials;
+mod deadline;
+mod default_headers;
+mod dns_guard;
+mod drain;
+mod end_user;
+mod error;
+mod expiry;
+mod guardrails;
 mod handlers;
+mod incidents;
+mod json_path;
+mod keep_warm;
+mod labels;
+mod limits;
+mod locale;
 #[macro_use]
 mod logger;
+mod maintenance;
+mod pacing_report;
+mod poll;
+mod policy_override;
+mod preview;
+mod retry;
+mod routing;
+mod routing_trace;
+mod sandbox;
+mod slo;
+mod soap_allowlist;
+/// Stubbed upstreams for tests and mock-mode builds, and the sandbox tenant's mock registry
+pub mod stubs;
+mod templates;
+mod tenants;
+mod timing;
+mod usage;
+mod webhooks;
+mod websocket;
+mod xpath;
 
 #[macro_use]
 mod processors;
 
-// Re-export all processors so they're accessible to the worker runtime
+// Re-export the compiled-in processors so they're accessible to the worker runtime
+#[cfg(feature = "region-wnam")]
 pub use processors::wnam_processor::WNAMProcessor;
+#[cfg(feature = "region-enam")]
 pub use processors::enam_processor::ENAMProcessor;
+#[cfg(feature = "region-weur")]
 pub use processors::weur_processor::WEURProcessor;
+#[cfg(feature = "region-eeur")]
 pub use processors::eeur_processor::EEURProcessor;
+#[cfg(feature = "region-apac")]
 pub use processors::apac_processor::APACProcessor;
+#[cfg(feature = "region-oc")]
 pub use processors::oc_processor::OCProcessor;
+#[cfg(feature = "region-af")]
 pub use processors::af_processor::AFProcessor;
+#[cfg(feature = "region-me")]
 pub use processors::me_processor::MEProcessor;
+pub use processors::registry::ShardRegistry;
+
+/// Response header naming the region that processed the request
+const ROUTED_REGION_HEADER: &str = "X-Routed-Region";
+
+/// Countries routed to `me` rather than their continent's region
+const MIDDLE_EAST_COUNTRIES: &[&str] = &[
+    "AE", "BH", "IL", "IQ", "IR", "JO", "KW", "LB", "OM", "PS", "QA", "SA", "SY", "YE",
+];
 
 #[event(fetch)]
 async fn fetch(
     req: HttpRequest,
     env: Env,
-    _ctx: Context,
+    ctx: Context,
 ) -> Result<HttpResponse> {
     // Convert HttpRequest to worker::Request using try_from
-    let mut worker_req = Request::try_from(req)?;
+    let worker_req = Request::try_from(req)?;
+
+    // Every log line of the request, at the edge and in the processor, carries its ID
+    let request_id = logger::request_id(worker_req.headers().get(logger::REQUEST_ID_HEADER)?.as_deref());
+    let context = logger::LogContext::new(&request_id, &worker_req.path(), Date::now().as_millis());
+    let response = logger::scoped(context, handle(worker_req, &env, &ctx)).await?;
+
+    // Returned on every response, so callers can find the request in the worker logs
+    let headers = response.headers().clone();
+    headers.set(logger::REQUEST_ID_HEADER, &request_id)?;
+    response.with_headers(headers).try_into()
+}
+
+/// Cron trigger; the only scheduled work is keep-warm pings, when they're due
+#[event(scheduled)]
+async fn scheduled(event: ScheduledEvent, env: Env, _ctx: ScheduleContext) {
+    let settings = keep_warm::KeepWarm::load(&env).await;
+    if settings.is_due(event.schedule() as u64) {
+        keep_warm::run(&env, &config::Config::load(&env), &settings).await;
+    }
+}
+
+/// Answers one request reaching the edge: admin, discovery, batch or proxy
+async fn handle(worker_req: Request, env: &Env, ctx: &Context) -> Result<Response> {
+    // Variables and secrets are read once, so the whole request sees the same values
+    let config = config::Config::load(env);
+    for warning in &config.warnings {
+        log_error!("Config: {}", warning);
+    }
+
+    // Admin endpoints use their own token and never reach the processors
+    let path = worker_req.path();
+    if path.starts_with("/admin/") {
+        if auth::validate_admin_token(&worker_req, &config).is_err() {
+            return auth::AuthError::forbidden();
+        }
+        return admin::handle(worker_req, env, &config, &path).await;
+    }
+
+    // Providers verifying a webhook URL can't send a proxy token; only configured challenges are answered
+    if path.starts_with(webhooks::WEBHOOK_PREFIX) && worker_req.method() == Method::Get {
+        return webhooks::respond(&worker_req, env).await;
+    }
 
     // Validate authentication token before processing
-    if let Err(_) = auth::validate_token(&worker_req, &env) {
-        return auth::AuthError::forbidden()?.try_into();
+    let identity = match auth::authenticate(&worker_req, env, &config).await {
+        Ok(identity) => identity,
+        Err(_) => return auth::AuthError::forbidden(),
+    };
+
+    // Machine-readable catalog of the proxy's own error codes
+    if path == "/errors" && worker_req.method() == Method::Get {
+        return Response::from_json(&error::ProxyError::catalog());
+    }
+
+    // Enabled features and bound resources, for client feature detection
+    if path == "/capabilities" && worker_req.method() == Method::Get {
+        return Response::from_json(&capabilities::Capabilities::detect(env));
+    }
+
+    // The path is forwarded to the DO, so keep its maintenance endpoints unreachable
+    if path.starts_with("/__internal/") {
+        return error::ProxyError::NotFound(format!("No route for {}", path)).to_response(None);
+    }
+
+    // A batch fans its entries out through the same pipeline as single requests
+    let geo_region = geo_region(&worker_req);
+    if path == "/batch" && worker_req.method() == Method::Post {
+        return proxy_batch(worker_req, env, ctx, &config, identity, geo_region).await;
+    }
+
+    // A WebSocket is bridged to its upstream by a regional processor for as long as it stays open
+    if websocket::is_upgrade(worker_req.headers())? {
+        return proxy_websocket(worker_req, env, &config, identity, geo_region).await;
+    }
+
+    proxy(worker_req, env, ctx, &config, identity, geo_region).await
+}
+
+/// The compiled-in region closest to the caller, from Cloudflare's geolocation
+fn geo_region(req: &Request) -> Option<String> {
+    req.cf()
+        .and_then(|cf| {
+            let longitude = cf.coordinates().map(|(_, longitude)| longitude);
+            ProcessorRegion::from_location(&cf.continent()?, cf.country().as_deref(), longitude)
+        })
+        .filter(ProcessorRegion::is_compiled)
+        .map(|region| region.code().to_string())
+}
+
+/// Runs the entries of a `POST /batch` concurrently, each through `proxy`
+///
+/// An entry is authorized, routed and counted like a single request sent with
+/// the batch's headers, so one refused entry never fails the others. Results
+/// keep the order of the entries.
+async fn proxy_batch(
+    mut worker_req: Request,
+    env: &Env,
+    ctx: &Context,
+    config: &config::Config,
+    identity: auth::Identity,
+    geo_region: Option<String>,
+) -> Result<Response> {
+    use futures_util::stream::{self, StreamExt, TryStreamExt};
+
+    let body_text = match limits::read_body(&mut worker_req, config.max_request_bytes.value).await? {
+        Ok(body_text) => body_text,
+        Err(e) => return e.to_response(None),
+    };
+    let batch: batch::BatchRequest = match handlers::parse::from_json(&body_text, "batch") {
+        Ok(batch) => batch,
+        Err(e) => return e.to_response(None),
+    };
+    if let Err(e) = batch.validate() {
+        return e.to_response(None);
+    }
+    if let auth::Identity::Tenant(tenant, _) = &identity {
+        if let Some(guardrails) = &tenant.guardrails {
+            let batch_type = worker_req.headers().get("X-Request-Type")?.unwrap_or_default();
+            let subrequests = batch
+                .requests
+                .iter()
+                .map(|entry| {
+                    let request_type = entry.request_type.as_deref().unwrap_or(&batch_type);
+                    guardrails::subrequests(request_type, &entry.request)
+                })
+                .sum();
+            if let Err(e) = guardrails.check_batch(&tenant.id, batch.requests.len(), subrequests) {
+                log_info!("Tenant {} guardrail exceeded: {}", tenant.id, e);
+                return e.to_response(None);
+            }
+        }
     }
+    log_info!(
+        "Batch of {} requests for {} (concurrency {})",
+        batch.requests.len(),
+        identity.name(),
+        batch.concurrency()
+    );
+
+    // Entries are sent to the proxy root with the batch's headers, overridden per entry.
+    // Each gets its own request ID, derived from the batch's so their logs are found together.
+    let batch_id = logger::current_request_id().unwrap_or_default();
+    let mut url = worker_req.url()?;
+    url.set_path("/");
+    let entry_request = |entry: &batch::BatchEntry, request_id: &str| -> Result<Request> {
+        let headers = worker_req.headers().clone();
+        headers.delete("Content-Length")?;
+        headers.set(logger::REQUEST_ID_HEADER, request_id)?;
+        if let Some(request_type) = &entry.request_type {
+            headers.set("X-Request-Type", request_type)?;
+        }
+        if let Some(region) = &entry.region {
+            headers.set("X-CF-Region", region)?;
+        }
+        let mut init = RequestInit::new();
+        init.method = Method::Post;
+        init.headers = headers;
+        init.body = Some(entry.request.to_string().into());
+        Request::new_with_init(url.as_str(), &init)
+    };
+
+    let concurrency = batch.concurrency();
+    let timer = timing::Timer::start();
+    let (results, steps): (Vec<_>, Vec<_>) = stream::iter(batch.requests.into_iter().enumerate())
+        .map(|(index, entry)| {
+            let request_id = format!("{}-{}", batch_id, index);
+            let request = entry_request(&entry, &request_id);
+            let context = logger::LogContext::new(&request_id, "/", Date::now().as_millis());
+            let identity = identity.clone();
+            let geo_region = geo_region.clone();
+            let timer = &timer;
+            logger::scoped(context, async move {
+                let started_ms = timer.elapsed_ms();
+                let response = match request {
+                    Ok(request) => proxy(request, env, ctx, config, identity, geo_region).await,
+                    Err(e) => Err(e),
+                };
+                let response = match response {
+                    Ok(response) => response,
+                    Err(e) => error::ProxyError::Internal(e.to_string()).to_response(None)?,
+                };
+                let result = batch::BatchItemResult::from_response(response, ROUTED_REGION_HEADER, request_id).await?;
+                Ok::<_, Error>((result, timer.step(index.to_string(), started_ms, Vec::new())))
+            })
+        })
+        .buffered(concurrency)
+        .try_collect::<Vec<_>>()
+        .await?
+        .into_iter()
+        .unzip();
+
+    Response::from_json(&batch::BatchResponse {
+        results,
+        timing: timer.report(concurrency, steps),
+    })
+}
+
+/// Runs one proxy request from the edge to its regional processor
+///
+/// `geo_region` is only used when neither the request nor the tenant picks a region.
+async fn proxy(
+    mut worker_req: Request,
+    env: &Env,
+    ctx: &Context,
+    config: &config::Config,
+    identity: auth::Identity,
+    geo_region: Option<String>,
+) -> Result<Response> {
+    let path = worker_req.path();
 
     // Read X-Log-Level header to determine logging level
     let log_level = logger::LogLevel::from_header(
@@ -46,82 +319,627 @@ async fn fetch(
     let colo = worker_req.cf().map(|cf| cf.colo()).unwrap_or("unknown".to_string());
     log_info!("Request received at datacenter: {}", colo);
 
-    // Get the original URL path
-    let url = worker_req.url()?;
-    let path = url.path().to_string();
     log_debug!(log_level, "Request path: {}", path);
 
+    // Read X-Deadline header; the remaining budget is enforced at every hop
+    let deadline = match deadline::Deadline::from_headers(worker_req.headers()) {
+        Ok(deadline) => deadline,
+        Err(e) => return e.to_response(None),
+    };
+
+    // Read X-Expires-At header; a request still waiting past it is dropped instead of sent
+    let expiry = match expiry::Expiry::from_headers(worker_req.headers()) {
+        Ok(expiry) => expiry,
+        Err(e) => return e.to_response(None),
+    };
+
+    // Read X-Labels header; labels in the body are merged in after template expansion
+    let header_labels = match labels::Labels::parse(&worker_req.headers().get(labels::LABELS_HEADER)?.unwrap_or_default()) {
+        Ok(labels) => labels,
+        Err(e) => return e.to_response(None),
+    };
+
+    // Read X-End-User header; the end user is logged, archived and forwarded as the tenant configures
+    let end_user = match worker_req.headers().get(end_user::END_USER_HEADER)? {
+        Some(value) => match end_user::EndUser::parse(&value) {
+            Ok(end_user) => Some(end_user),
+            Err(e) => return e.to_response(None),
+        },
+        None => None,
+    };
+
+    // Read X-Cost-Estimate header; the cost is always measured, but only returned on request
+    let include_cost = worker_req.headers().get(cost::INCLUDE_COST_HEADER)?.as_deref() == Some("true");
+
+    // Read X-Priority header; it decides how much of the tenant's retry budget a request may use
+    let priority = match worker_req.headers().get(retry::PRIORITY_HEADER)? {
+        Some(value) => match retry::Priority::parse(&value) {
+            Some(priority) => priority,
+            None => {
+                return error::ProxyError::InvalidRequest(format!(
+                    "{} must be low, normal or high: {}",
+                    retry::PRIORITY_HEADER,
+                    value
+                ))
+                .to_response(None)
+            }
+        },
+        None => retry::Priority::default(),
+    };
+
     // Read X-CF-Region header to determine target region
-    let region_header = worker_req
-        .headers()
-        .get("X-CF-Region")?
-        .unwrap_or_else(|| "wnam".to_string()); // Default to Western North America
+    let region_header = worker_req.headers().get("X-CF-Region")?;
 
-    log_info!("Selected region: {}", region_header);
+    // Read X-Policy-Override header; an override must carry the admin token and a reason
+    let policy_override = match policy_override::requested(&worker_req, config) {
+        Ok(policy_override) => policy_override,
+        Err(e) => {
+            log_info!("Policy override refused: {}", e);
+            return e.to_response(None);
+        }
+    };
 
     // Read X-Request-Type header (soap or http)
-    let request_type = worker_req
+    let mut request_type = worker_req
         .headers()
         .get("X-Request-Type")?
         .unwrap_or_default();
 
-    // Parse incoming request body
-    let body_text = worker_req.text().await?;
+    // Read X-Routing-Key header; with one, the shard is picked from the key instead of the body
+    let routing_key = match worker_req.headers().get(routing::ROUTING_KEY_HEADER)? {
+        Some(value) => match routing::parse_routing_key(&value) {
+            Some(key) => Some(key.to_string()),
+            None => {
+                return error::ProxyError::InvalidRequest(format!(
+                    "{} must be 1-{} chars",
+                    routing::ROUTING_KEY_HEADER,
+                    routing::MAX_ROUTING_KEY_LEN
+                ))
+                .to_response(None)
+            }
+        },
+        None => None,
+    };
+
+    // A keyed request's body is only read here when the tenant's policy needs it;
+    // otherwise it's streamed through and the processor reads it
+    let tenant = match &identity {
+        auth::Identity::Tenant(tenant, _) => Some(tenant.as_ref()),
+        auth::Identity::Root => None,
+    };
+    let body_unread = routing_key.is_some() && !tenant.is_some_and(|tenant| tenant.reads_body());
+
+    // Hold the tenant to its requests per minute before anything is read or routed
+    if let Some(tenant) = tenant {
+        if let Err(e) = tenants::check_rate_limit(env, tenant).await {
+            log_info!("Tenant {} rate limited: {}", tenant.id, e);
+            return e.to_response(None);
+        }
+    }
+
+    // Read X-Expiry-Callback header; it's told when the request is dropped as expired
+    let expiry_callback = match expiry::callback_url(worker_req.headers(), tenant) {
+        Ok(callback) => callback,
+        Err(e) => return e.to_response(None),
+    };
+
+    // Parse incoming request body, refusing it past MAX_REQUEST_BYTES
+    let mut body_text = if body_unread {
+        log_debug!(log_level, "Forwarding the body unread");
+        String::new()
+    } else {
+        match limits::read_body(&mut worker_req, config.max_request_bytes.value).await? {
+            Ok(body_text) => body_text,
+            Err(e) => {
+                log_info!("Request body refused: {}", e);
+                return e.to_response(None);
+            }
+        }
+    };
+
+    // Expand saved request templates into a full payload, keeping the invocation for the archive
+    #[cfg_attr(not(feature = "archive-r2"), allow(unused_variables))]
+    let invocation = match templates::resolve(env, &body_text, tenant).await {
+        Ok(Some((expanded, template_request_type))) => {
+            log_debug!(log_level, "Expanded request template ({})", template_request_type);
+            request_type = template_request_type;
+            Some(std::mem::replace(&mut body_text, expanded))
+        }
+        Ok(None) => None,
+        Err(e) => {
+            log_info!("Template expansion failed: {}", e);
+            return e.to_response(None);
+        }
+    };
+
+    // Header labels win over a `labels` object in the body
+    let labels = match labels::Labels::from_body(&body_text) {
+        Ok(body_labels) => header_labels.merge(body_labels),
+        Err(e) => return e.to_response(None),
+    };
+    if !labels.is_empty() {
+        log_info!("Labels: {}", labels);
+    }
+    if let Some(end_user) = &end_user {
+        log_info!("End user: {}", end_user);
+    }
+
+    // Tenant routing rules on the body win over the header, then the tenant's default region.
+    // An override lets the header win, so test traffic goes where the engineer sends it.
+    // Every decision on the way to a shard is traced, and returned in debug mode.
+    let mut trace = routing_trace::RoutingTrace::default();
+    let overridden_header = policy_override.is_some() && region_header.is_some();
+    let rule_region = tenant
+        .filter(|_| !overridden_header)
+        .and_then(|tenant| tenant.rule_region(&body_text))
+        .map(str::to_string);
+    if let Some(rule_region) = &rule_region {
+        log_debug!(log_level, "Routing rule matched region {}", rule_region);
+    }
+    if overridden_header && tenant.is_some_and(|tenant| !tenant.routing_rules.is_empty()) {
+        trace.fallback("Routing rules skipped: a policy override lets X-CF-Region win");
+    }
+    trace.region = routing_trace::RegionTrace {
+        routing_rule: rule_region,
+        header: region_header,
+        tenant_default: tenant.and_then(|tenant| tenant.region_policy.default_region.clone()),
+        // Otherwise the compiled-in region closest to the caller, then Western North America in full builds
+        geo: geo_region,
+        ..Default::default()
+    };
+    let region_header = trace.region.select();
+
+    log_info!("Selected region: {}", region_header);
+
+    // Map region code to ProcessorRegion
+    let region = ProcessorRegion::from_code(&region_header).unwrap_or_else(|| {
+        log_info!("Unknown region '{}', defaulting to {}", region_header, routing::DEFAULT_REGION);
+        trace.fallback(format!("Unknown region {:?}, defaulted to {}", region_header, routing::DEFAULT_REGION));
+        ProcessorRegion::from_code(routing::DEFAULT_REGION).unwrap_or(ProcessorRegion::WesternNorthAmerica)
+    });
+    trace.region.selected = region.code().to_string();
+
+    // Never move a request to another region silently: it may be pinned there for data residency
+    let compiled = if region.is_compiled() {
+        Ok(())
+    } else {
+        Err(error::ProxyError::InvalidRequest(format!("Region {} is not enabled in this deployment", region.code())))
+    };
+    if let Err(e) = trace.check("region_enabled", compiled) {
+        log_info!("{}", e);
+        return trace.attach(e.to_response(None)?, log_level).await;
+    }
+    logger::annotate(|context| context.region = Some(region.code().to_string()));
+
+    // A draining region takes no new requests; the caller is told where to go instead
+    if let Err(e) = trace.check("region_drain", drain::RegionDrains::load(env).await.check(region.code())) {
+        log_info!("{}", e);
+        return trace.attach(e.to_response(None)?, log_level).await;
+    }
+
+    // Enforce the tenant's region and host policy before any DO is involved;
+    // an override skips the region checks, never the host allowlist
+    let checked_region = if policy_override.is_some() { None } else { Some(region.code()) };
+    if let Some(policy_override) = &policy_override {
+        trace.fallback(format!("Region policy checks skipped: policy override by {}", policy_override.actor));
+    }
+    if let auth::Identity::Tenant(tenant, token) = &identity {
+        if let Err(e) = trace.check("tenant_policy", tenants::check_policy(tenant, checked_region, &body_text)) {
+            log_info!("Tenant {} policy violation: {}", tenant.id, e);
+            return trace.attach(e.to_response(None)?, log_level).await;
+        }
+        if let Err(e) = trace.check("token_scope", token.scope.check(checked_region, &request_type)) {
+            log_info!("Tenant {} token {} policy violation: {}", tenant.id, token.label(), e);
+            return trace.attach(e.to_response(None)?, log_level).await;
+        }
+        if let Some(guardrails) = &tenant.guardrails {
+            if let Err(e) = trace.check("guardrails", guardrails.check(&tenant.id, &request_type, &body_text)) {
+                log_info!("Tenant {} guardrail exceeded: {}", tenant.id, e);
+                return trace.attach(e.to_response(None)?, log_level).await;
+            }
+        }
+    }
+
+    // Every overridden request is on record before it goes anywhere
+    if let Some(policy_override) = policy_override {
+        log_info!(
+            "Policy override by {} for {} in {}: {}",
+            policy_override.actor,
+            identity.name(),
+            region.code(),
+            policy_override.reason
+        );
+        let entry = policy_override::OverrideAuditEntry {
+            at: Date::now().as_millis(),
+            actor: policy_override.actor,
+            reason: policy_override.reason,
+            tenant: identity.name().to_string(),
+            region: region.code().to_string(),
+            request_type: request_type.clone(),
+        };
+        if let Err(e) = policy_override::record(env, entry).await {
+            log_error!("Failed to record policy override, refusing it: {}", e);
+            return error::ProxyError::Internal("Policy override could not be audited".to_string())
+                .to_response(None);
+        }
+    }
+
+    // Hold or reject requests to upstreams in a scheduled maintenance window
+    let hosts: std::collections::BTreeSet<String> = routing::target_hosts(&body_text).into_iter().flatten().collect();
+    for host in hosts {
+        if let Err(e) = maintenance::enforce(env, &host, deadline).await {
+            log_info!("Request to {} not forwarded: {}", host, e);
+            return e.to_response(None);
+        }
+    }
+
+    // Pre-flight validation: report what would happen instead of sending anything
+    match preview::requested(&body_text) {
+        Ok(true) => {
+            let shard_weights = routing::ShardWeights::load(env).await;
+            let pool_size = config.pool_size(region.code());
+            let key = routing_key.as_deref().unwrap_or(&body_text);
+            let index = shard_weights.select_shard(region.code(), pool_size, key);
+            let key_source = if routing_key.is_some() { "routing_key" } else { "body" };
+            trace.shard(&shard_weights, region.code(), pool_size, key_source, key, index);
+            let shard = routing::shard_name(region.code(), index);
+            log_info!("validate_only request previewed for shard {}", shard);
+            let previewed = match preview::Preview::build(
+                &body_text,
+                &request_type,
+                identity.name(),
+                region.code(),
+                shard,
+                region.is_eu(),
+                labels,
+            ) {
+                Ok(preview) => Response::from_json(&preview)?,
+                Err(e) => e.to_response(None)?,
+            };
+            return trace.attach(previewed, log_level).await;
+        }
+        Ok(false) => {}
+        Err(e) => return e.to_response(None),
+    }
+
+    // Keep a copy of the request for the archive (when the ARCHIVE bucket is bound)
+    #[cfg(feature = "archive-r2")]
+    let archive = archive::Archive::new(env)
+        .filter(|_| !body_unread)
+        .map(|archive| archive.compressing(config.archive_compression.value));
+    #[cfg(feature = "archive-r2")]
+    let archived_body = archive.as_ref().map(|_| body_text.clone());
 
-    // Map header value to ProcessorRegion
-    let region = match region_header.to_lowercase().as_str() {
-        "wnam" => ProcessorRegion::WesternNorthAmerica,
-        "enam" => ProcessorRegion::EasternNorthAmerica,
-        "weur" => ProcessorRegion::WesternEurope,
-        "eeur" => ProcessorRegion::EasternEurope,
-        "apac" => ProcessorRegion::AsiaPacific,
-        "oc" => ProcessorRegion::Oceania,
-        "af" => ProcessorRegion::Africa,
-        "me" => ProcessorRegion::MiddleEast,
-        _ => {
-            log_info!("Unknown region '{}', defaulting to Western North America", region_header);
-            ProcessorRegion::WesternNorthAmerica
+    // Sampled requests are also captured with their full response (see `capture`)
+    #[cfg(feature = "archive-r2")]
+    let capture = match archive::Archive::new(env) {
+        Some(bucket) if !body_unread && capture::SamplingConfig::load(env).await.sample(identity.name(), &labels) => {
+            Some((bucket.compressing(config.archive_compression.value), body_text.clone()))
         }
+        _ => None,
     };
 
+    // Don't route work the caller has already given up on
+    if let Err(e) = deadline::remaining(deadline) {
+        log_info!("Request dropped at the edge: {}", e);
+        return e.to_response(None);
+    }
+    if let Err(e) = expiry::check(expiry) {
+        log_info!("Request dropped at the edge: {}", e);
+        let response = e.to_response(None)?;
+        expiry::notify_if_expired(ctx, config, expiry_callback.as_ref(), identity.name(), &response)?;
+        return Ok(response);
+    }
+
+    // Retries of the tenant's requests draw on its region-wide budget, when it has one
+    let tenant_budget = tenant
+        .and_then(|tenant| tenant.retry_budget)
+        .map(|ratio| retry::TenantBudget { ratio, priority });
+
     // Route to the appropriate regional processor
-    route_to_processor(&env, &path, body_text, region, &request_type, log_level).await?.try_into()
+    let body = if body_unread { ForwardedBody::Unread(worker_req) } else { ForwardedBody::Read(body_text) };
+    let routed = route_to_processor(
+        env,
+        config,
+        &path,
+        routing_key.as_deref(),
+        body,
+        region,
+        &request_type,
+        &identity,
+        deadline,
+        expiry,
+        &labels,
+        end_user.as_ref(),
+        include_cost,
+        tenant_budget,
+        log_level,
+        &mut trace,
+    )
+    .await;
+
+    // Count the request toward the region's SLOs (when the DB database is bound); a
+    // processor that couldn't be reached is the proxy's fault
+    if let Some(slo_log) = slo::SloLog::new(env) {
+        let overhead_ms = logger::elapsed_ms().unwrap_or(0).saturating_sub(logger::upstream_ms());
+        let outcome = match &routed {
+            Ok(response) => slo::Outcome::of(response, overhead_ms, &config.slo_targets())?,
+            Err(_) => slo::Outcome { proxy_error: true, slow: false },
+        };
+        let region = region.code();
+        ctx.wait_until(async move {
+            if let Err(e) = slo_log.record(Date::now().as_millis(), region, outcome).await {
+                log_error!("Failed to record SLO counts: {}", e);
+            }
+        });
+    }
+    let mut response = routed?;
+
+    // A request dropped as expired on the way to the upstream is reported to the caller's callback
+    expiry::notify_if_expired(ctx, config, expiry_callback.as_ref(), identity.name(), &response)?;
+
+    // Tell the caller where the request ran, since the region may have been picked for it
+    let headers = response.headers().clone();
+    headers.set(ROUTED_REGION_HEADER, region.code())?;
+    response = response.with_headers(headers);
+
+    // Count the request and its cost per tenant and label set (when the DB database is bound)
+    if let Some(usage) = usage::UsageLog::new(env) {
+        let tenant = identity.name().to_string();
+        let labels = labels.clone();
+        let failed = !(200..300).contains(&response.status_code());
+        let cost = response
+            .headers()
+            .get(cost::COST_HEADER)?
+            .and_then(|value| cost::CostEstimate::from_header(&value))
+            .unwrap_or_else(cost::CostEstimate::processor_call);
+        ctx.wait_until(async move {
+            if let Err(e) = usage.record(Date::now().as_millis(), &tenant, &labels, failed, cost).await {
+                log_error!("Failed to record usage: {}", e);
+            }
+        });
+    }
+
+    // A streamed body is for the caller only; reading a copy would buffer what streaming avoids
+    #[cfg(feature = "archive-r2")]
+    let streamed = response.headers().get(handlers::response::STREAM_HEADER)?.is_some();
+
+    #[cfg(feature = "archive-r2")]
+    if let Some((bucket, body)) = capture {
+        let mut copy = if streamed { None } else { Some(response.cloned()?) };
+        let mut record = capture::CapturedRequest {
+            at: Date::now().as_millis(),
+            path: path.clone(),
+            region: region.code().to_string(),
+            request_type: request_type.clone(),
+            tenant: identity.name().to_string(),
+            labels: labels.clone(),
+            end_user: end_user.clone(),
+            body,
+            response_status: response.status_code(),
+            response_headers: response.headers().entries().collect(),
+            response_body: String::new(),
+        };
+        ctx.wait_until(async move {
+            if let Some(copy) = &mut copy {
+                record.response_body = copy.text().await.unwrap_or_default();
+            }
+            if let Err(e) = bucket.put_json(capture::CAPTURE_PREFIX, record.at, &record).await {
+                log_error!("Failed to capture request: {}", e);
+            }
+        });
+    }
+
+    #[cfg(feature = "archive-r2")]
+    if let (Some(archive), Some(body)) = (archive, archived_body) {
+        let mut copy = if streamed { None } else { Some(response.cloned()?) };
+        let http_status = response.status_code();
+        let mut record = archive::ArchivedRequest {
+            at: Date::now().as_millis(),
+            path,
+            region: region.code().to_string(),
+            request_type,
+            tenant: identity.name().to_string(),
+            request_id: logger::current_request_id(),
+            labels,
+            end_user,
+            body,
+            invocation,
+            response_status: http_status,
+            response_fingerprint: String::new(),
+        };
+        // Archive after the response is sent so callers never wait on R2
+        ctx.wait_until(async move {
+            let text = match &mut copy {
+                Some(copy) => copy.text().await.unwrap_or_default(),
+                None => String::new(),
+            };
+            (record.response_status, record.response_fingerprint) = archive::fingerprint(http_status, &text);
+            if let Err(e) = archive.put(&record).await {
+                log_error!("Failed to archive request: {}", e);
+            }
+        });
+    }
+
+    trace.attach(response, log_level).await
+}
+
+/// Opens a WebSocket that the selected region's processor bridges to `?url=`
+///
+/// The upgrade is routed and checked like a proxy request, with the target
+/// URL standing in for the body: routing rules, region and tenant policy,
+/// drains and maintenance windows all apply. Frames then flow between the
+/// caller and the upstream through the processor, so a region pinned to the
+/// EU jurisdiction keeps the upstream connection there too.
+async fn proxy_websocket(
+    worker_req: Request,
+    env: &Env,
+    config: &config::Config,
+    identity: auth::Identity,
+    geo_region: Option<String>,
+) -> Result<Response> {
+    let protocols = worker_req.headers().get("Sec-WebSocket-Protocol")?;
+    let target = match websocket::Target::from_query(&worker_req.url()?, protocols) {
+        Ok(target) => target,
+        Err(e) => return e.to_response(None),
+    };
+    let log_level = logger::LogLevel::from_header(&worker_req.headers().get("X-Log-Level")?.unwrap_or_default());
+    let body_text = target.policy_body();
+
+    let tenant = match &identity {
+        auth::Identity::Tenant(tenant, _) => Some(tenant.as_ref()),
+        auth::Identity::Root => None,
+    };
+
+    // An upgrade counts against the tenant's requests per minute like any proxy request
+    if let Some(tenant) = tenant {
+        if let Err(e) = tenants::check_rate_limit(env, tenant).await {
+            log_info!("Tenant {} rate limited: {}", tenant.id, e);
+            return e.to_response(None);
+        }
+    }
+
+    let mut trace = routing_trace::RoutingTrace {
+        region: routing_trace::RegionTrace {
+            routing_rule: tenant.and_then(|tenant| tenant.rule_region(&body_text)).map(str::to_string),
+            header: worker_req.headers().get("X-CF-Region")?,
+            tenant_default: tenant.and_then(|tenant| tenant.region_policy.default_region.clone()),
+            geo: geo_region,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let region_code = trace.region.select();
+
+    // Unlike a proxy request, an unusable region is refused rather than defaulted
+    let region = match ProcessorRegion::from_code(&region_code).filter(ProcessorRegion::is_compiled) {
+        Some(region) => region,
+        None => {
+            let message = format!("Region {} is not enabled in this deployment", region_code);
+            return error::ProxyError::InvalidRequest(message).to_response(None);
+        }
+    };
+    trace.region.selected = region.code().to_string();
+    logger::annotate(|context| context.region = Some(region.code().to_string()));
+
+    let checks = async {
+        drain::RegionDrains::load(env).await.check(region.code())?;
+        if let auth::Identity::Tenant(tenant, token) = &identity {
+            // The sandbox's mocks and echo endpoints answer requests, not connections
+            if tenant.id == tenants::SANDBOX_TENANT_ID {
+                return Err(error::ProxyError::PolicyViolation("The sandbox tenant can't open WebSockets".to_string()));
+            }
+            tenants::check_policy(tenant, Some(region.code()), &body_text)?;
+            token.scope.check(Some(region.code()), "websocket")?;
+            if let Some(guardrails) = &tenant.guardrails {
+                guardrails.check(&tenant.id, "websocket", &body_text)?;
+            }
+        }
+        for host in routing::target_hosts(&body_text).into_iter().flatten() {
+            maintenance::enforce(env, &host, None).await?;
+        }
+        Ok::<_, error::ProxyError>(())
+    };
+    if let Err(e) = checks.await {
+        log_info!("WebSocket to {} refused: {}", target.url, e);
+        return e.to_response(None);
+    }
+    log_info!("WebSocket to {} for {} routed to {}", target.url, identity.name(), region.code());
+
+    let response = route_to_processor(
+        env,
+        config,
+        "/",
+        None,
+        ForwardedBody::Upgrade(target),
+        region,
+        "websocket",
+        &identity,
+        None,
+        None,
+        &labels::Labels::default(),
+        None,
+        false,
+        None,
+        log_level,
+        &mut trace,
+    )
+    .await?;
+    let headers = response.headers().clone();
+    headers.set(ROUTED_REGION_HEADER, region.code())?;
+    Ok(response.with_headers(headers))
+}
+
+/// Request body the edge hands to a processor
+enum ForwardedBody {
+    Read(String),
+    /// The caller's request, whose body is streamed through without being read
+    Unread(Request),
+    /// A WebSocket upgrade, which has no body
+    Upgrade(websocket::Target),
 }
 
 /// Route request to appropriate regional processor based on location
 ///
-/// Uses hash-based distribution across 10 Durable Objects per region for 10x concurrency.
+/// Uses weighted hash-based distribution across the region's pool of Durable Objects (10 unless
+/// `DO_POOL_SIZE` says otherwise, see `Config::pool_size`). Shard weights come from the CONFIG
+/// KV namespace (see `routing::ShardWeights`). The routing key is hashed when given, else the body.
 ///
 /// EU Jurisdiction Enforcement:
 /// For GDPR compliance, Western and Eastern Europe processors use location hints
 /// "weur" and "eeur" which Cloudflare automatically maps to EU datacenters,
 /// enforcing data residency within EU jurisdiction.
+#[allow(clippy::too_many_arguments)]
 async fn route_to_processor(
     env: &Env,
+    config: &config::Config,
     path: &str,
-    body: String,
+    routing_key: Option<&str>,
+    body: ForwardedBody,
     region: ProcessorRegion,
     request_type: &str,
+    identity: &auth::Identity,
+    deadline: Option<deadline::Deadline>,
+    expiry: Option<expiry::Expiry>,
+    labels: &labels::Labels,
+    end_user: Option<&end_user::EndUser>,
+    include_cost: bool,
+    tenant_budget: Option<retry::TenantBudget>,
     log_level: logger::LogLevel,
+    trace: &mut routing_trace::RoutingTrace,
 ) -> Result<Response> {
-    // Calculate hash-based DO index (0-9) for load distribution
-    let mut hasher = DefaultHasher::new();
-    body.hash(&mut hasher);
-    let hash_value = hasher.finish();
-    let do_index = (hash_value % 10) as u32;
-
-    let (namespace_name, region_code, location_hint, is_eu) = match region {
-        ProcessorRegion::WesternNorthAmerica => ("WNAM_PROCESSOR", "wnam", "wnam", false),
-        ProcessorRegion::EasternNorthAmerica => ("ENAM_PROCESSOR", "enam", "enam", false),
-        ProcessorRegion::WesternEurope => ("WEUR_PROCESSOR", "weur", "weur", true),
-        ProcessorRegion::EasternEurope => ("EEUR_PROCESSOR", "eeur", "eeur", true),
-        ProcessorRegion::AsiaPacific => ("APAC_PROCESSOR", "apac", "apac", false),
-        ProcessorRegion::Oceania => ("OC_PROCESSOR", "oc", "oc", false),
-        ProcessorRegion::Africa => ("AF_PROCESSOR", "af", "af", false),
-        ProcessorRegion::MiddleEast => ("ME_PROCESSOR", "me", "me", false),
+    let (namespace_name, region_code, location_hint) = match region {
+        ProcessorRegion::WesternNorthAmerica => ("WNAM_PROCESSOR", "wnam", "wnam"),
+        ProcessorRegion::EasternNorthAmerica => ("ENAM_PROCESSOR", "enam", "enam"),
+        ProcessorRegion::WesternEurope => ("WEUR_PROCESSOR", "weur", "weur"),
+        ProcessorRegion::EasternEurope => ("EEUR_PROCESSOR", "eeur", "eeur"),
+        ProcessorRegion::AsiaPacific => ("APAC_PROCESSOR", "apac", "apac"),
+        ProcessorRegion::Oceania => ("OC_PROCESSOR", "oc", "oc"),
+        ProcessorRegion::Africa => ("AF_PROCESSOR", "af", "af"),
+        ProcessorRegion::MiddleEast => ("ME_PROCESSOR", "me", "me"),
     };
+    let is_eu = region.is_eu();
 
+    // Pick a DO index within the region's pool using the configured shard weights
+    let shard_weights = routing::ShardWeights::load(env).await;
+    let (key_source, key) = match (routing_key, &body) {
+        (Some(key), _) => ("routing_key", key),
+        (None, ForwardedBody::Read(body)) => ("body", body.as_str()),
+        (None, ForwardedBody::Unread(_)) => ("body", ""),
+        (None, ForwardedBody::Upgrade(target)) => ("url", target.url.as_str()),
+    };
+    let pool_size = config.pool_size(region_code);
+    let do_index = shard_weights.select_shard(region_code, pool_size, key);
+    trace.shard(&shard_weights, region_code, pool_size, key_source, key, do_index);
     let do_name = format!("{}-processor-{}", region_code, do_index);
+    trace.durable_object = Some(routing_trace::DurableObjectTrace {
+        namespace: namespace_name,
+        name: do_name.clone(),
+        location_hint,
+        eu_jurisdiction: is_eu,
+    });
+    let shard = routing::shard_name(region_code, do_index);
+    logger::annotate(|context| context.do_name = Some(shard.clone()));
 
     log_debug!(
         log_level,
@@ -143,19 +961,68 @@ async fn route_to_processor(
     // Create internal request URL preserving the path
     let internal_url = format!("http://internal{}", path);
 
-    // Create headers and forward X-Request-Type and X-Log-Level to Durable Object
+    // Create headers and forward X-Request-Type, X-Log-Level and the caller identity to Durable Object
     let headers = worker::Headers::new();
     headers.set("Content-Type", "application/json")?;
     if !request_type.is_empty() {
         headers.set("X-Request-Type", request_type)?;
     }
     headers.set("X-Log-Level", if log_level == logger::LogLevel::Debug { "debug" } else { "info" })?;
+    headers.set("X-Tenant-Id", identity.name())?;
+    if let Some(token) = identity.token_label() {
+        headers.set(auth::TOKEN_HEADER, token)?;
+    }
+    headers.set(processors::registry::SHARD_HEADER, &shard)?;
+    headers.set(config::VERSION_HEADER, &config.version)?;
+    if let Some(request_id) = logger::current_request_id() {
+        headers.set(logger::REQUEST_ID_HEADER, &request_id)?;
+    }
+    if let Some(deadline) = deadline {
+        headers.set(deadline::DEADLINE_HEADER, &deadline.0.to_string())?;
+    }
+    if let Some(expiry) = expiry {
+        headers.set(expiry::EXPIRES_AT_HEADER, &expiry.0.to_string())?;
+    }
+    if !labels.is_empty() {
+        headers.set(labels::LABELS_HEADER, &labels.to_string())?;
+    }
+    if let auth::Identity::Tenant(tenant, _) = identity {
+        if let Some(weight) = tenant.concurrency_weight {
+            headers.set(processors::slots::WEIGHT_HEADER, &weight.to_string())?;
+        }
+    }
+    if let Some(end_user) = end_user {
+        headers.set(end_user::END_USER_HEADER, &end_user.to_string())?;
+        if let auth::Identity::Tenant(tenant, _) = identity {
+            if let Some(forwarding) = &tenant.end_user_forwarding {
+                headers.set(end_user::FORWARDING_HEADER, &serde_json::to_string(forwarding)?)?;
+            }
+        }
+    }
+    if include_cost {
+        headers.set(cost::INCLUDE_COST_HEADER, "true")?;
+    }
+    if let Some(budget) = tenant_budget {
+        headers.set(retry::TENANT_BUDGET_HEADER, &budget.ratio.to_string())?;
+        headers.set(retry::PRIORITY_HEADER, budget.priority.as_str())?;
+    }
+
+    if matches!(body, ForwardedBody::Unread(_)) {
+        headers.set(routing::BODY_UNREAD_HEADER, "true")?;
+    }
+    if let ForwardedBody::Upgrade(target) = &body {
+        target.forward(&headers)?;
+    }
 
     // Forward request to Durable Object
     let mut init = RequestInit::new();
-    init.method = Method::Post;
+    init.method = if matches!(body, ForwardedBody::Upgrade(_)) { Method::Get } else { Method::Post };
     init.headers = headers;
-    init.body = Some(body.into());
+    init.body = match body {
+        ForwardedBody::Read(body) => Some(body.into()),
+        ForwardedBody::Unread(request) => request.inner().body().map(Into::into),
+        ForwardedBody::Upgrade(_) => None,
+    };
 
     let do_request = Request::new_with_init(&internal_url, &init)?;
 
@@ -174,3 +1041,86 @@ enum ProcessorRegion {
     Africa,
     MiddleEast
 }
+
+impl ProcessorRegion {
+    /// Parses a region code (case-insensitive)
+    fn from_code(code: &str) -> Option<ProcessorRegion> {
+        match code.to_lowercase().as_str() {
+            "wnam" => Some(ProcessorRegion::WesternNorthAmerica),
+            "enam" => Some(ProcessorRegion::EasternNorthAmerica),
+            "weur" => Some(ProcessorRegion::WesternEurope),
+            "eeur" => Some(ProcessorRegion::EasternEurope),
+            "apac" => Some(ProcessorRegion::AsiaPacific),
+            "oc" => Some(ProcessorRegion::Oceania),
+            "af" => Some(ProcessorRegion::Africa),
+            "me" => Some(ProcessorRegion::MiddleEast),
+            _ => None,
+        }
+    }
+
+    /// Region for a caller's location, from Cloudflare's continent code
+    ///
+    /// North America and Europe span two regions each and are split by
+    /// longitude (100°W and 20°E); without coordinates the eastern one is
+    /// used for North America, the western one for Europe. South America is
+    /// served from `enam`, the closest region.
+    fn from_location(continent: &str, country: Option<&str>, longitude: Option<f32>) -> Option<ProcessorRegion> {
+        if country.is_some_and(|country| MIDDLE_EAST_COUNTRIES.contains(&country)) {
+            return Some(ProcessorRegion::MiddleEast);
+        }
+        match continent {
+            "NA" if longitude.is_some_and(|longitude| longitude < -100.0) => Some(ProcessorRegion::WesternNorthAmerica),
+            "NA" | "SA" => Some(ProcessorRegion::EasternNorthAmerica),
+            "EU" if longitude.is_some_and(|longitude| longitude >= 20.0) => Some(ProcessorRegion::EasternEurope),
+            "EU" => Some(ProcessorRegion::WesternEurope),
+            "AS" => Some(ProcessorRegion::AsiaPacific),
+            "OC" => Some(ProcessorRegion::Oceania),
+            "AF" => Some(ProcessorRegion::Africa),
+            _ => None,
+        }
+    }
+
+    /// Whether this region's processor is compiled into the build (see the `region-*` features)
+    fn is_compiled(&self) -> bool {
+        routing::REGION_CODES.contains(&self.code())
+    }
+
+    /// Whether the region's location hint keeps processors in EU datacenters
+    fn is_eu(&self) -> bool {
+        matches!(self, ProcessorRegion::WesternEurope | ProcessorRegion::EasternEurope)
+    }
+
+    /// Region code as used in X-CF-Region and location hints
+    fn code(&self) -> &'static str {
+        match self {
+            ProcessorRegion::WesternNorthAmerica => "wnam",
+            ProcessorRegion::EasternNorthAmerica => "enam",
+            ProcessorRegion::WesternEurope => "weur",
+            ProcessorRegion::EasternEurope => "eeur",
+            ProcessorRegion::AsiaPacific => "apac",
+            ProcessorRegion::Oceania => "oc",
+            ProcessorRegion::Africa => "af",
+            ProcessorRegion::MiddleEast => "me",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_region_from_location() {
+        let code = |continent, country, longitude| {
+            ProcessorRegion::from_location(continent, country, longitude).map(|region| region.code())
+        };
+        assert_eq!(code("NA", Some("US"), Some(-122.4)), Some("wnam"));
+        assert_eq!(code("NA", Some("US"), Some(-74.0)), Some("enam"));
+        assert_eq!(code("SA", Some("BR"), None), Some("enam"));
+        assert_eq!(code("EU", Some("FR"), Some(2.3)), Some("weur"));
+        assert_eq!(code("EU", Some("RO"), Some(26.1)), Some("eeur"));
+        assert_eq!(code("AS", Some("AE"), Some(55.3)), Some("me"));
+        assert_eq!(code("AS", Some("JP"), None), Some("apac"));
+        assert_eq!(code("AN", None, None), None);
+    }
+}
@@ -0,0 +1,47 @@
+use worker::*;
+
+use crate::error::ProxyError;
+
+/// Reads the request body, refusing it when it's over `limit`
+///
+/// A declared `Content-Length` over the limit is refused before anything is
+/// read; chunked bodies are checked once read.
+pub async fn read_body(req: &mut Request, limit: u64) -> Result<std::result::Result<String, ProxyError>> {
+    let declared = req
+        .headers()
+        .get("Content-Length")?
+        .and_then(|length| length.parse::<u64>().ok());
+    if declared.is_some_and(|length| length > limit) {
+        return Ok(Err(ProxyError::PayloadTooLarge { limit }));
+    }
+    let bytes = req.bytes().await?;
+    if bytes.len() as u64 > limit {
+        return Ok(Err(ProxyError::PayloadTooLarge { limit }));
+    }
+    Ok(String::from_utf8(bytes).map_err(|_| ProxyError::InvalidRequest("Body is not valid UTF-8".to_string())))
+}
+
+/// An upstream response body over the processor's limit
+///
+/// Returned by the handlers through `anyhow`, and turned into
+/// `RESPONSE_TOO_LARGE` by the pipeline.
+#[derive(Debug)]
+pub struct ResponseTooLarge {
+    pub limit: u64,
+}
+
+impl std::fmt::Display for ResponseTooLarge {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Upstream response is larger than {} bytes", self.limit)
+    }
+}
+
+impl std::error::Error for ResponseTooLarge {}
+
+/// Fails when a response body's length (declared or read) is over `limit`
+pub fn check_response(length: Option<u64>, limit: Option<u64>) -> std::result::Result<(), ResponseTooLarge> {
+    match (length, limit) {
+        (Some(length), Some(limit)) if length > limit => Err(ResponseTooLarge { limit }),
+        _ => Ok(()),
+    }
+}
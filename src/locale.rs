@@ -0,0 +1,217 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use worker::*;
+
+use crate::error::ProxyError;
+use crate::log_error;
+
+/// KV key holding the per-host locale rules
+pub const LOCALE_RULES_KEY: &str = "locale-rules";
+
+/// Longest accepted locale, as BCP 47 tags go in practice
+const MAX_LOCALE_LENGTH: usize = 35;
+
+/// How a host expects a locale spelled
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LocaleFormat {
+    /// As a BCP 47 tag, e.g. "de-CH"
+    #[default]
+    Tag,
+    /// The language alone, e.g. "de"
+    Language,
+    /// With an underscore, e.g. "de_CH", as Java and PHP services often want it
+    Underscore,
+}
+
+impl LocaleFormat {
+    pub fn apply(self, locale: &str) -> String {
+        match self {
+            LocaleFormat::Tag => locale.to_string(),
+            LocaleFormat::Language => locale.split('-').next().unwrap_or(locale).to_string(),
+            LocaleFormat::Underscore => locale.replace('-', "_"),
+        }
+    }
+}
+
+/// Where one host expects a request's `locale` instead of `Accept-Language`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LocaleRule {
+    /// Target host, e.g. "soap.carrier.example"
+    pub host: String,
+    /// Header to send the locale in
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub header: Option<String>,
+    /// Param to send the locale as: a SOAP param, or an HTTP query or body param
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub param: Option<String>,
+    #[serde(default)]
+    pub format: LocaleFormat,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct LocaleRules(pub Vec<LocaleRule>);
+
+/// Where a request's locale goes, and how it's spelled there
+#[derive(Debug, Clone, PartialEq)]
+pub struct LocaleTarget {
+    /// Header name and value, unless a caller's header of that name wins
+    pub header: Option<(String, String)>,
+    /// Param name and value, unless a caller's param of that name wins
+    pub param: Option<(String, String)>,
+}
+
+/// Normalizes a locale such as "de_ch" to its BCP 47 spelling ("de-CH"),
+/// or refuses one that isn't a language tag
+pub fn normalize(locale: &str) -> std::result::Result<String, ProxyError> {
+    let invalid = || ProxyError::InvalidRequest(format!("locale must be a language tag such as de-CH: {:?}", locale));
+    if locale.is_empty() || locale.len() > MAX_LOCALE_LENGTH {
+        return Err(invalid());
+    }
+    let mut subtags = Vec::new();
+    for (i, subtag) in locale.split(['-', '_']).enumerate() {
+        let valid = match i {
+            0 => (2..=8).contains(&subtag.len()) && subtag.chars().all(|c| c.is_ascii_alphabetic()),
+            _ => (1..=8).contains(&subtag.len()) && subtag.chars().all(|c| c.is_ascii_alphanumeric()),
+        };
+        if !valid {
+            return Err(invalid());
+        }
+        // Language lowercase, region uppercase, script titlecase (e.g. zh-Hant-TW)
+        subtags.push(match (i, subtag.len()) {
+            (0, _) => subtag.to_lowercase(),
+            (_, 2) if subtag.chars().all(|c| c.is_ascii_alphabetic()) => subtag.to_uppercase(),
+            (_, 4) if subtag.chars().all(|c| c.is_ascii_alphabetic()) => {
+                subtag[..1].to_uppercase() + &subtag[1..].to_lowercase()
+            }
+            _ => subtag.to_lowercase(),
+        });
+    }
+    Ok(subtags.join("-"))
+}
+
+impl LocaleRules {
+    /// Loads rules from the CONFIG KV namespace; no config sends every locale as `Accept-Language`
+    pub async fn load(env: &Env) -> LocaleRules {
+        let kv = match env.kv("CONFIG") {
+            Ok(kv) => kv,
+            Err(_) => return LocaleRules::default(),
+        };
+        match kv.get(LOCALE_RULES_KEY).cache_ttl(60).json::<LocaleRules>().await {
+            Ok(rules) => rules.unwrap_or_default(),
+            Err(e) => {
+                log_error!("Failed to load locale rules, sending Accept-Language: {}", e);
+                LocaleRules::default()
+            }
+        }
+    }
+
+    /// Reads rules bypassing the edge cache, for the admin API
+    pub async fn load_fresh(env: &Env) -> Result<LocaleRules> {
+        Ok(env.kv("CONFIG")?.get(LOCALE_RULES_KEY).json::<LocaleRules>().await?.unwrap_or_default())
+    }
+
+    pub async fn save(&self, env: &Env) -> Result<()> {
+        env.kv("CONFIG")?
+            .put(LOCALE_RULES_KEY, serde_json::to_string(self)?)?
+            .execute()
+            .await?;
+        Ok(())
+    }
+
+    pub fn validate(&self) -> std::result::Result<(), ProxyError> {
+        for rule in &self.0 {
+            if rule.host.is_empty() {
+                return Err(ProxyError::InvalidRequest("Locale rule needs a host".to_string()));
+            }
+            if rule.header.is_none() && rule.param.is_none() {
+                return Err(ProxyError::InvalidRequest(format!(
+                    "Locale rule of {} needs a header or a param",
+                    rule.host
+                )));
+            }
+            if let Some(name) = &rule.header {
+                let valid_name = !name.is_empty()
+                    && name.bytes().all(|b| b.is_ascii_alphanumeric() || b"-_!#$%&'*+.^`|~".contains(&b));
+                if !valid_name {
+                    return Err(ProxyError::InvalidRequest(format!("Invalid locale header: {}", name)));
+                }
+            }
+            if rule.param.as_deref() == Some("") {
+                return Err(ProxyError::InvalidRequest(format!("Locale param of {} can't be empty", rule.host)));
+            }
+        }
+        Ok(())
+    }
+
+    /// Where a normalized `locale` goes on a call to `host`: the host's rule, else `Accept-Language`
+    pub fn target(&self, host: &str, locale: &str) -> LocaleTarget {
+        match self.0.iter().find(|rule| rule.host.eq_ignore_ascii_case(host)) {
+            Some(rule) => {
+                let value = rule.format.apply(locale);
+                LocaleTarget {
+                    header: rule.header.clone().map(|name| (name, value.clone())),
+                    param: rule.param.clone().map(|name| (name, value)),
+                }
+            }
+            None => LocaleTarget {
+                header: Some(("Accept-Language".to_string(), locale.to_string())),
+                param: None,
+            },
+        }
+    }
+}
+
+impl LocaleTarget {
+    /// Adds the header to `headers` unless the caller already set it
+    pub fn apply_header(&mut self, headers: &mut HashMap<String, String>) {
+        if let Some((name, value)) = self.header.take() {
+            if !headers.keys().any(|existing| existing.eq_ignore_ascii_case(&name)) {
+                headers.insert(name, value);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_locale_targets() {
+        assert_eq!(normalize("de_ch").unwrap(), "de-CH");
+        assert_eq!(normalize("ZH-hant-tw").unwrap(), "zh-Hant-TW");
+        assert_eq!(normalize("es-419").unwrap(), "es-419");
+        assert!(normalize("d").is_err());
+        assert!(normalize("de-CH;q=0.9").is_err());
+        assert!(normalize("de--CH").is_err());
+
+        let rules: LocaleRules = serde_json::from_str(
+            r#"[{"host": "soap.carrier.example", "param": "lang", "format": "underscore"},
+                {"host": "api.carrier.example", "header": "X-Language", "format": "language"}]"#,
+        )
+        .unwrap();
+        assert!(rules.validate().is_ok());
+
+        let soap = rules.target("SOAP.carrier.example", "de-CH");
+        assert_eq!((soap.header, soap.param), (None, Some(("lang".to_string(), "de_CH".to_string()))));
+
+        let mut headers = HashMap::from([("x-language".to_string(), "fr".to_string())]);
+        rules.target("api.carrier.example", "de-CH").apply_header(&mut headers);
+        assert_eq!(headers["x-language"], "fr");
+
+        let mut headers = HashMap::new();
+        rules.target("other.example", "de-CH").apply_header(&mut headers);
+        assert_eq!(headers["Accept-Language"], "de-CH");
+
+        assert!(LocaleRules(vec![LocaleRule {
+            host: "api.carrier.example".to_string(),
+            header: None,
+            param: None,
+            format: LocaleFormat::Tag,
+        }])
+        .validate()
+        .is_err());
+    }
+}
@@ -1,3 +1,16 @@
+use serde::Serialize;
+use std::cell::RefCell;
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+
+/// Header carrying the request ID from the caller to the edge, from the edge
+/// to the processor, and back to the caller on every response
+pub const REQUEST_ID_HEADER: &str = "X-Request-Id";
+
+const MAX_REQUEST_ID_LEN: usize = 128;
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum LogLevel {
     Info,
@@ -17,11 +30,201 @@ impl LogLevel {
     }
 }
 
+/// Severity of one log line
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Debug,
+    Info,
+    Error,
+}
+
+impl Severity {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Severity::Debug => "debug",
+            Severity::Info => "info",
+            Severity::Error => "error",
+        }
+    }
+}
+
+/// What every log line of a request carries, so edge and processor lines can
+/// be joined on `request_id`
+#[derive(Debug, Clone, Serialize)]
+pub struct LogContext {
+    pub request_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub region: Option<String>,
+    /// Processor shard handling the request, e.g. "wnam-3"
+    #[serde(rename = "do", skip_serializing_if = "Option::is_none")]
+    pub do_name: Option<String>,
+    pub path: String,
+    /// When the request reached this hop (epoch millis)
+    #[serde(skip)]
+    pub started_at: u64,
+    /// Time this hop spent waiting on upstreams, see `add_upstream_ms`
+    #[serde(skip)]
+    pub upstream_ms: u64,
+    /// Upstream attempts started so far, see `next_attempt_id`
+    #[serde(skip)]
+    pub attempts: u32,
+}
+
+impl LogContext {
+    pub fn new(request_id: &str, path: &str, started_at: u64) -> LogContext {
+        LogContext {
+            request_id: request_id.to_string(),
+            region: None,
+            do_name: None,
+            path: path.to_string(),
+            started_at,
+            upstream_ms: 0,
+            attempts: 0,
+        }
+    }
+}
+
+/// The caller's request ID when it's usable, else a new one
+///
+/// Callers (e.g. Laravel) can send their own ID to find their request in the
+/// worker logs without mapping IDs.
+pub fn request_id(header: Option<&str>) -> String {
+    header
+        .filter(|id| {
+            !id.is_empty()
+                && id.len() <= MAX_REQUEST_ID_LEN
+                && id.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | ':'))
+        })
+        .map(str::to_string)
+        .unwrap_or_else(|| uuid::Uuid::new_v4().simple().to_string())
+}
+
+thread_local! {
+    /// Context of the request whose future is being polled
+    static CURRENT: RefCell<Option<Rc<RefCell<LogContext>>>> = const { RefCell::new(None) };
+}
+
+/// A future whose log lines carry its request's context
+///
+/// Requests are interleaved on the isolate's single thread, so the context is
+/// installed for each poll and removed after it rather than set once.
+pub struct Scoped<F> {
+    context: Rc<RefCell<LogContext>>,
+    future: Pin<Box<F>>,
+}
+
+impl<F: Future> Future for Scoped<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<F::Output> {
+        let this = self.get_mut();
+        let previous = CURRENT.with(|current| current.replace(Some(this.context.clone())));
+        let result = this.future.as_mut().poll(cx);
+        CURRENT.with(|current| *current.borrow_mut() = previous);
+        result
+    }
+}
+
+/// Runs `future` with `context` on all of its log lines
+pub fn scoped<F: Future>(context: LogContext, future: F) -> Scoped<F> {
+    Scoped {
+        context: Rc::new(RefCell::new(context)),
+        future: Box::pin(future),
+    }
+}
+
+/// Updates the current request's context once routing has decided it
+pub fn annotate(update: impl FnOnce(&mut LogContext)) {
+    CURRENT.with(|current| {
+        if let Some(context) = current.borrow().as_ref() {
+            update(&mut context.borrow_mut());
+        }
+    });
+}
+
+/// Counts time the current request spent waiting on upstreams (calls, retry
+/// backoff, pacing, maintenance holds), which the proxy's latency SLO leaves out
+pub fn add_upstream_ms(ms: u64) {
+    annotate(|context| context.upstream_ms += ms);
+}
+
+/// Time the current request has waited on upstreams at this hop
+pub fn upstream_ms() -> u64 {
+    CURRENT.with(|current| current.borrow().as_ref().map_or(0, |context| context.borrow().upstream_ms))
+}
+
+/// Millis since the current request reached this hop
+pub fn elapsed_ms() -> Option<u64> {
+    let now = worker::Date::now().as_millis();
+    CURRENT.with(|current| current.borrow().as_ref().map(|context| now.saturating_sub(context.borrow().started_at)))
+}
+
+/// A copy of the current request's context, for work that outlives its future
+pub fn current_context() -> Option<LogContext> {
+    CURRENT.with(|current| current.borrow().as_ref().map(|context| context.borrow().clone()))
+}
+
+/// ID of the request whose future is being polled
+pub fn current_request_id() -> Option<String> {
+    CURRENT.with(|current| current.borrow().as_ref().map(|context| context.borrow().request_id.clone()))
+}
+
+/// ID of the current request's next upstream attempt, e.g. "req-abc#2"
+///
+/// Attempts are numbered across every upstream call of the request (retries,
+/// saga and pipeline steps), so no two calls it makes share an ID.
+pub fn next_attempt_id() -> Option<String> {
+    CURRENT.with(|current| {
+        current.borrow().as_ref().map(|context| {
+            let mut context = context.borrow_mut();
+            context.attempts += 1;
+            attempt_id(&context.request_id, context.attempts)
+        })
+    })
+}
+
+fn attempt_id(request_id: &str, attempt: u32) -> String {
+    format!("{}#{}", request_id, attempt)
+}
+
+#[derive(Serialize)]
+struct LogLine<'a> {
+    ts: u64,
+    level: &'static str,
+    message: &'a str,
+    #[serde(flatten)]
+    context: Option<&'a LogContext>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    elapsed_ms: Option<u64>,
+}
+
+/// Formats one JSON log line
+fn line(severity: Severity, message: &str, context: Option<&LogContext>, now: u64) -> String {
+    let line = LogLine {
+        ts: now,
+        level: severity.as_str(),
+        message,
+        context,
+        elapsed_ms: context.map(|context| now.saturating_sub(context.started_at)),
+    };
+    serde_json::to_string(&line).unwrap_or_else(|_| message.to_string())
+}
+
+/// Writes a log line with the current request's context; use the macros
+pub fn emit(severity: Severity, message: &str) {
+    let now = worker::Date::now().as_millis();
+    let line = CURRENT.with(|current| match current.borrow().as_ref() {
+        Some(context) => line(severity, message, Some(&context.borrow()), now),
+        None => line(severity, message, None, now),
+    });
+    worker::console_log!("{}", line);
+}
+
 /// Log at INFO level (always displayed)
 #[macro_export]
 macro_rules! log_info {
     ($($arg:tt)*) => {
-        worker::console_log!("[INFO] {}", format!($($arg)*))
+        $crate::logger::emit($crate::logger::Severity::Info, &format!($($arg)*))
     };
 }
 
@@ -30,7 +233,7 @@ macro_rules! log_info {
 macro_rules! log_debug {
     ($level:expr, $($arg:tt)*) => {
         if $level.should_log_debug() {
-            worker::console_log!("[DEBUG] {}", format!($($arg)*))
+            $crate::logger::emit($crate::logger::Severity::Debug, &format!($($arg)*))
         }
     };
 }
@@ -39,6 +242,44 @@ macro_rules! log_debug {
 #[macro_export]
 macro_rules! log_error {
     ($($arg:tt)*) => {
-        worker::console_log!("[ERROR] {}", format!($($arg)*))
+        $crate::logger::emit($crate::logger::Severity::Error, &format!($($arg)*))
     };
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+
+    #[test]
+    fn test_log_lines_carry_request_context() {
+        let line_without = line(Severity::Info, "Starting", None, 1_000);
+        assert_eq!(
+            serde_json::from_str::<Value>(&line_without).unwrap(),
+            json!({"ts": 1_000, "level": "info", "message": "Starting"})
+        );
+
+        let mut context = LogContext::new("laravel-7f3a", "/", 1_000);
+        context.region = Some("weur".to_string());
+        context.do_name = Some("weur-7".to_string());
+        let line_with = line(Severity::Error, "Upstream failed", Some(&context), 1_250);
+        assert_eq!(
+            serde_json::from_str::<Value>(&line_with).unwrap(),
+            json!({
+                "ts": 1_250,
+                "level": "error",
+                "message": "Upstream failed",
+                "request_id": "laravel-7f3a",
+                "region": "weur",
+                "do": "weur-7",
+                "path": "/",
+                "elapsed_ms": 250
+            })
+        );
+
+        assert_eq!(request_id(Some("laravel-7f3a")), "laravel-7f3a");
+        assert_ne!(request_id(Some("bad id\n")), "bad id\n");
+        assert_eq!(request_id(None).len(), 32);
+        assert_eq!(attempt_id("laravel-7f3a", 2), "laravel-7f3a#2");
+    }
+}
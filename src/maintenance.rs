@@ -0,0 +1,214 @@
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use worker::*;
+
+use crate::deadline::Deadline;
+use crate::error::ProxyError;
+use crate::logger;
+
+/// KV key prefix for per-host maintenance calendars
+const MAINTENANCE_PREFIX: &str = "maintenance:";
+
+/// Longest a `delay` window may hold a request before it's rejected instead
+///
+/// Keeps delayed requests well inside the 30-second Workers request limit.
+pub const MAX_MAINTENANCE_DELAY: Duration = Duration::from_secs(20);
+
+/// A scheduled upstream maintenance window
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MaintenanceWindow {
+    /// Start as epoch millis (inclusive)
+    pub starts_at: u64,
+    /// End as epoch millis (exclusive)
+    pub ends_at: u64,
+    #[serde(default)]
+    pub action: MaintenanceAction,
+    /// Shown to callers, e.g. "DIDx scheduled DB migration"
+    #[serde(default)]
+    pub reason: Option<String>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MaintenanceAction {
+    /// Fail fast with 503 UPSTREAM_MAINTENANCE
+    #[default]
+    Reject,
+    /// Hold the request until the window ends, if that's within `MAX_MAINTENANCE_DELAY`
+    Delay,
+}
+
+/// What to do with a request to a host right now
+#[derive(Debug, PartialEq, Eq)]
+pub enum MaintenanceDecision {
+    Proceed,
+    Wait(Duration),
+    Reject { reason: Option<String>, retry_after_secs: u64 },
+}
+
+/// Decides how a request is affected by the host's calendar at `now`
+///
+/// Overlapping windows are resolved by the one ending last, so a delay is
+/// never shorter than the combined maintenance.
+pub fn decide(windows: &[MaintenanceWindow], now: u64) -> MaintenanceDecision {
+    let active = windows
+        .iter()
+        .filter(|window| window.starts_at <= now && now < window.ends_at)
+        .max_by_key(|window| window.ends_at);
+
+    let window = match active {
+        Some(window) => window,
+        None => return MaintenanceDecision::Proceed,
+    };
+
+    let remaining = Duration::from_millis(window.ends_at - now);
+    if window.action == MaintenanceAction::Delay && remaining <= MAX_MAINTENANCE_DELAY {
+        return MaintenanceDecision::Wait(remaining);
+    }
+    MaintenanceDecision::Reject {
+        reason: window.reason.clone(),
+        retry_after_secs: remaining.as_millis().div_ceil(1000) as u64,
+    }
+}
+
+/// Applies the target host's maintenance calendar before a request is routed
+///
+/// Delays in place for short `delay` windows; otherwise returns
+/// `UpstreamMaintenance`. A delay that would outlast the caller's deadline
+/// fails fast with `DeadlineExceeded`. Calendars that can't be read never
+/// block traffic.
+pub async fn enforce(env: &Env, host: &str, deadline: Option<Deadline>) -> std::result::Result<(), ProxyError> {
+    let windows = match MaintenanceStore::new(env) {
+        Ok(store) => store.get_cached(host).await.unwrap_or_else(|e| {
+            log_info!("Failed to load maintenance calendar for {}: {}", host, e);
+            Vec::new()
+        }),
+        Err(_) => return Ok(()),
+    };
+
+    match decide(&windows, Date::now().as_millis()) {
+        MaintenanceDecision::Proceed => Ok(()),
+        MaintenanceDecision::Wait(delay) => {
+            if let Some(deadline) = deadline {
+                if deadline.check()? <= delay {
+                    return Err(ProxyError::DeadlineExceeded { deadline: deadline.0 });
+                }
+            }
+            log_info!("Delaying request to {} by {}ms for maintenance", host, delay.as_millis());
+            Delay::from(delay).await;
+            logger::add_upstream_ms(delay.as_millis() as u64);
+            Ok(())
+        }
+        MaintenanceDecision::Reject { reason, retry_after_secs } => Err(ProxyError::UpstreamMaintenance {
+            host: host.to_string(),
+            reason,
+            retry_after_secs,
+        }),
+    }
+}
+
+/// Maintenance calendars in the CONFIG KV namespace, one list of windows per host
+pub struct MaintenanceStore {
+    kv: kv::KvStore,
+}
+
+impl MaintenanceStore {
+    pub fn new(env: &Env) -> Result<MaintenanceStore> {
+        Ok(MaintenanceStore { kv: env.kv("CONFIG")? })
+    }
+
+    pub async fn get(&self, host: &str) -> Result<Vec<MaintenanceWindow>> {
+        Ok(self
+            .kv
+            .get(&format!("{}{}", MAINTENANCE_PREFIX, host))
+            .json::<Vec<MaintenanceWindow>>()
+            .await?
+            .unwrap_or_default())
+    }
+
+    async fn get_cached(&self, host: &str) -> Result<Vec<MaintenanceWindow>> {
+        Ok(self
+            .kv
+            .get(&format!("{}{}", MAINTENANCE_PREFIX, host))
+            .cache_ttl(60)
+            .json::<Vec<MaintenanceWindow>>()
+            .await?
+            .unwrap_or_default())
+    }
+
+    /// Hosts that have a calendar
+    pub async fn hosts(&self) -> Result<Vec<String>> {
+        let mut hosts = Vec::new();
+        let mut cursor: Option<String> = None;
+        loop {
+            let mut list = self.kv.list().prefix(MAINTENANCE_PREFIX.to_string());
+            if let Some(cursor) = cursor.take() {
+                list = list.cursor(cursor);
+            }
+            let page = list.execute().await?;
+            hosts.extend(
+                page.keys
+                    .iter()
+                    .filter_map(|key| key.name.strip_prefix(MAINTENANCE_PREFIX).map(str::to_string)),
+            );
+            if page.list_complete {
+                break;
+            }
+            cursor = page.cursor;
+        }
+        Ok(hosts)
+    }
+
+    /// Replaces a host's calendar; past windows are dropped on save
+    pub async fn save(&self, host: &str, windows: &[MaintenanceWindow]) -> Result<()> {
+        let now = Date::now().as_millis();
+        let upcoming: Vec<&MaintenanceWindow> = windows.iter().filter(|window| window.ends_at > now).collect();
+        self.kv
+            .put(&format!("{}{}", MAINTENANCE_PREFIX, host), serde_json::to_string(&upcoming)?)?
+            .execute()
+            .await?;
+        Ok(())
+    }
+
+    pub async fn delete(&self, host: &str) -> Result<()> {
+        self.kv.delete(&format!("{}{}", MAINTENANCE_PREFIX, host)).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn window(starts_at: u64, ends_at: u64, action: MaintenanceAction) -> MaintenanceWindow {
+        MaintenanceWindow {
+            starts_at,
+            ends_at,
+            action,
+            reason: Some("carrier maintenance".to_string()),
+        }
+    }
+
+    #[test]
+    fn test_maintenance_decision() {
+        let calendar = vec![
+            window(1_000, 61_000, MaintenanceAction::Reject),
+            window(100_000, 110_000, MaintenanceAction::Delay),
+        ];
+
+        assert_eq!(decide(&calendar, 500), MaintenanceDecision::Proceed);
+        assert_eq!(decide(&calendar, 61_000), MaintenanceDecision::Proceed);
+        assert_eq!(
+            decide(&calendar, 30_500),
+            MaintenanceDecision::Reject {
+                reason: Some("carrier maintenance".to_string()),
+                retry_after_secs: 31
+            }
+        );
+        assert_eq!(decide(&calendar, 105_000), MaintenanceDecision::Wait(Duration::from_secs(5)));
+
+        // A delay window that outlasts MAX_MAINTENANCE_DELAY rejects instead
+        let long = vec![window(0, 600_000, MaintenanceAction::Delay)];
+        assert!(matches!(decide(&long, 0), MaintenanceDecision::Reject { retry_after_secs: 600, .. }));
+    }
+}
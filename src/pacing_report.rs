@@ -0,0 +1,288 @@
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+use worker::wasm_bindgen::JsValue;
+use worker::*;
+
+use crate::usage::day_of;
+
+/// Outbound calls are counted per UTC minute
+const MINUTE_MS: u64 = 60_000;
+
+const DAY_MS: u64 = 86_400_000;
+
+/// Lower bounds of the requests-per-minute histogram buckets
+const RPM_BUCKETS: &[u64] = &[1, 10, 30, 60, 120, 300, 600, 1200];
+
+/// One host's outbound calls from one DO in one minute
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct MinuteCounts {
+    /// Start of the UTC minute as epoch millis
+    pub minute: u64,
+    pub requests: u64,
+    /// Calls the pacer made wait for a token
+    pub throttled: u64,
+    /// Calls the upstream answered with 429
+    pub rate_limited: u64,
+    /// Most calls in flight at once
+    pub peak_concurrency: u32,
+}
+
+/// A DO's calls to one host: how many are in flight, and the current minute's counts
+#[derive(Debug, Default)]
+pub struct HostCalls {
+    in_flight: u32,
+    current: MinuteCounts,
+}
+
+impl HostCalls {
+    /// Moves on to the minute of `now`, returning the previous minute's counts if anything was counted
+    fn roll(&mut self, now: u64) -> Option<MinuteCounts> {
+        let minute = now - now % MINUTE_MS;
+        if minute <= self.current.minute {
+            return None;
+        }
+        let next = MinuteCounts {
+            minute,
+            // Calls still in flight are concurrent with the new minute's
+            peak_concurrency: self.in_flight,
+            ..Default::default()
+        };
+        let finished = std::mem::replace(&mut self.current, next);
+        (finished.requests + finished.rate_limited > 0).then_some(finished)
+    }
+
+    /// Counts a call starting at `now` (epoch millis)
+    pub fn start(&mut self, now: u64, paced: bool) -> Option<MinuteCounts> {
+        let finished = self.roll(now);
+        self.in_flight += 1;
+        self.current.requests += 1;
+        self.current.throttled += u64::from(paced);
+        self.current.peak_concurrency = self.current.peak_concurrency.max(self.in_flight);
+        finished
+    }
+
+    /// Counts a call ending at `now` with the upstream's `status`, if it answered
+    pub fn finish(&mut self, now: u64, status: Option<u16>) -> Option<MinuteCounts> {
+        let finished = self.roll(now);
+        self.in_flight = self.in_flight.saturating_sub(1);
+        self.current.rate_limited += u64::from(status == Some(429));
+        finished
+    }
+}
+
+/// Outbound calls by lowercase host, kept in memory for the life of the instance
+pub type OutboundCalls = RefCell<HashMap<String, HostCalls>>;
+
+/// Runs one outbound call to `host`, counting it toward the host's pacing report
+///
+/// Counts reach D1 a minute at a time, once the instance calls the same host
+/// in a later minute; `status` reads the upstream status off the call's result.
+pub async fn track<R>(
+    env: &Env,
+    calls: &OutboundCalls,
+    host: &str,
+    paced: bool,
+    call: impl Future<Output = R>,
+    status: impl Fn(&R) -> Option<u16>,
+) -> R {
+    if host.is_empty() {
+        return call.await;
+    }
+    let host = host.to_lowercase();
+    let started = calls.borrow_mut().entry(host.clone()).or_default().start(Date::now().as_millis(), paced);
+    let result = call.await;
+    let finished = calls
+        .borrow_mut()
+        .entry(host.clone())
+        .or_default()
+        .finish(Date::now().as_millis(), status(&result));
+
+    // Written after the call, so the report never delays it
+    if let Some(log) = PacingLog::new(env) {
+        for counts in started.iter().chain(&finished) {
+            if let Err(e) = log.record(&host, counts).await {
+                log_error!("Failed to record pacing counts of {}: {}", host, e);
+            }
+        }
+    }
+    result
+}
+
+/// One host's calls in one minute across every shard, from the D1 `pacing_minutes` table
+#[derive(Debug, Clone, Deserialize)]
+pub struct MinuteRow {
+    pub minute: u64,
+    pub host: String,
+    pub requests: u64,
+    pub throttled: u64,
+    pub rate_limited: u64,
+    pub peak_concurrency: u32,
+}
+
+/// Minutes whose request count falls in `[min, max]`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RpmBucket {
+    pub min: u64,
+    /// `None` for the open-ended last bucket
+    pub max: Option<u64>,
+    pub minutes: u64,
+}
+
+/// One host's outbound calls over one day
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct HostPacing {
+    pub requests: u64,
+    /// Minutes with at least one call
+    pub active_minutes: u64,
+    pub peak_rpm: u64,
+    /// Start of the busiest minute as epoch millis
+    pub peak_minute: u64,
+    /// Most calls one shard had in flight at once
+    pub peak_concurrency: u32,
+    pub throttled: u64,
+    pub rate_limited: u64,
+    /// Active minutes by requests per minute
+    pub rpm_histogram: Vec<RpmBucket>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PacingReport {
+    /// UTC midnight as epoch millis
+    pub day: u64,
+    pub hosts: BTreeMap<String, HostPacing>,
+}
+
+impl PacingReport {
+    /// Rolls one day's minute rows up per host
+    pub fn build(day: u64, rows: &[MinuteRow]) -> PacingReport {
+        let mut hosts: BTreeMap<String, HostPacing> = BTreeMap::new();
+        for row in rows {
+            let host = hosts.entry(row.host.clone()).or_insert_with(|| HostPacing {
+                requests: 0,
+                active_minutes: 0,
+                peak_rpm: 0,
+                peak_minute: row.minute,
+                peak_concurrency: 0,
+                throttled: 0,
+                rate_limited: 0,
+                rpm_histogram: RPM_BUCKETS
+                    .iter()
+                    .enumerate()
+                    .map(|(i, min)| RpmBucket {
+                        min: *min,
+                        max: RPM_BUCKETS.get(i + 1).map(|next| next - 1),
+                        minutes: 0,
+                    })
+                    .collect(),
+            });
+            host.requests += row.requests;
+            host.throttled += row.throttled;
+            host.rate_limited += row.rate_limited;
+            host.peak_concurrency = host.peak_concurrency.max(row.peak_concurrency);
+            if row.requests == 0 {
+                continue;
+            }
+            host.active_minutes += 1;
+            if row.requests > host.peak_rpm {
+                host.peak_rpm = row.requests;
+                host.peak_minute = row.minute;
+            }
+            if let Some(bucket) = host.rpm_histogram.iter_mut().rev().find(|bucket| row.requests >= bucket.min) {
+                bucket.minutes += 1;
+            }
+        }
+        PacingReport { day, hosts }
+    }
+}
+
+/// Per-minute outbound calls in the optional DB D1 database
+pub struct PacingLog {
+    db: D1Database,
+}
+
+impl PacingLog {
+    /// Returns `None` when no DB database is bound, which disables pacing reports
+    pub fn new(env: &Env) -> Option<PacingLog> {
+        env.d1("DB").ok().map(|db| PacingLog { db })
+    }
+
+    /// Adds one instance's counts for a minute to the host's row
+    pub async fn record(&self, host: &str, counts: &MinuteCounts) -> Result<()> {
+        self.db
+            .prepare(
+                "INSERT INTO pacing_minutes (minute, host, requests, throttled, rate_limited, peak_concurrency) \
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6) \
+                 ON CONFLICT (minute, host) DO UPDATE SET \
+                 requests = requests + excluded.requests, throttled = throttled + excluded.throttled, \
+                 rate_limited = rate_limited + excluded.rate_limited, \
+                 peak_concurrency = MAX(peak_concurrency, excluded.peak_concurrency)",
+            )
+            .bind(&[
+                JsValue::from(counts.minute as f64),
+                host.into(),
+                JsValue::from(counts.requests as f64),
+                JsValue::from(counts.throttled as f64),
+                JsValue::from(counts.rate_limited as f64),
+                JsValue::from(counts.peak_concurrency),
+            ])?
+            .run()
+            .await?;
+        Ok(())
+    }
+
+    /// The report of the UTC day containing `at` (epoch millis), optionally for one host
+    pub async fn report(&self, at: u64, host: Option<&str>) -> Result<PacingReport> {
+        let day = day_of(at);
+        let result = self
+            .db
+            .prepare(
+                "SELECT minute, host, requests, throttled, rate_limited, peak_concurrency FROM pacing_minutes \
+                 WHERE minute >= ?1 AND minute < ?2 AND (?3 IS NULL OR host = ?3) ORDER BY minute",
+            )
+            .bind(&[
+                JsValue::from(day as f64),
+                JsValue::from((day + DAY_MS) as f64),
+                host.map(|host| JsValue::from(host.to_lowercase())).unwrap_or(JsValue::NULL),
+            ])?
+            .all()
+            .await?;
+        Ok(PacingReport::build(day, &result.results::<MinuteRow>()?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_minutes_roll_up_into_a_day() {
+        let mut calls = HostCalls::default();
+        assert_eq!(calls.start(1_000, false), None);
+        assert_eq!(calls.start(2_000, true), None);
+        assert_eq!(calls.finish(3_000, Some(429)), None);
+        // The next minute flushes the first, whose second call is still in flight
+        let first = calls.start(61_000, false).unwrap();
+        assert_eq!((first.minute, first.requests, first.throttled), (0, 2, 1));
+        assert_eq!((first.rate_limited, first.peak_concurrency), (1, 2));
+        assert_eq!(calls.current.peak_concurrency, 2);
+
+        let row = |minute: u64, requests: u64, peak_concurrency: u32| MinuteRow {
+            minute: minute * MINUTE_MS,
+            host: "api.carrier.example".to_string(),
+            requests,
+            throttled: 1,
+            rate_limited: 0,
+            peak_concurrency,
+        };
+        let report = PacingReport::build(0, &[row(0, 5, 2), row(1, 45, 7), row(2, 2_000, 3), row(3, 0, 1)]);
+        let host = &report.hosts["api.carrier.example"];
+        assert_eq!((host.requests, host.active_minutes, host.throttled), (2_050, 3, 4));
+        assert_eq!((host.peak_rpm, host.peak_minute, host.peak_concurrency), (2_000, 2 * MINUTE_MS, 7));
+        let minutes: Vec<u64> = host.rpm_histogram.iter().map(|bucket| bucket.minutes).collect();
+        assert_eq!(minutes, vec![1, 0, 1, 0, 0, 0, 0, 1]);
+        assert_eq!(host.rpm_histogram[1].max, Some(29));
+        assert_eq!(host.rpm_histogram[7].max, None);
+    }
+}
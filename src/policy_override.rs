@@ -0,0 +1,107 @@
+use serde::{Deserialize, Serialize};
+use worker::*;
+
+use crate::config::Config;
+use crate::error::ProxyError;
+
+/// Request header carrying the `ADMIN_TOKEN` that authorizes an override
+pub const OVERRIDE_HEADER: &str = "X-Policy-Override";
+
+/// Request header explaining an override; required, and kept in the audit trail
+pub const REASON_HEADER: &str = "X-Policy-Override-Reason";
+
+/// KV key prefix of the override audit trail, one key per entry
+pub const AUDIT_PREFIX: &str = "policy-override-audit/";
+
+/// How long audit entries are kept
+const AUDIT_TTL_SECS: u64 = 365 * 24 * 60 * 60;
+
+/// KV's limit on a key's metadata, where entries are kept so listing needs no reads
+const MAX_AUDIT_METADATA_BYTES: usize = 1024;
+
+/// An authorized bypass of the tenant's region policy for one request
+#[derive(Debug, Clone)]
+pub struct PolicyOverride {
+    /// Value of the X-Admin-Actor header, or "admin"
+    pub actor: String,
+    pub reason: String,
+}
+
+/// One request sent with a policy override
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OverrideAuditEntry {
+    pub at: u64,
+    pub actor: String,
+    pub reason: String,
+    pub tenant: String,
+    /// Region the request was routed to
+    pub region: String,
+    pub request_type: String,
+}
+
+/// Reads a policy override off the request, if it asks for one
+///
+/// The override token is the `ADMIN_TOKEN`, separate from the bearer token
+/// the request authenticates with, so a leaked tenant token can't be used
+/// to reach regions its policy forbids.
+pub fn requested(req: &Request, config: &Config) -> std::result::Result<Option<PolicyOverride>, ProxyError> {
+    let token = match req.headers().get(OVERRIDE_HEADER).ok().flatten() {
+        Some(token) => token,
+        None => return Ok(None),
+    };
+    let admin_token = config
+        .admin_token()
+        .ok_or_else(|| ProxyError::PolicyViolation("Policy overrides are not enabled".to_string()))?;
+    if token != admin_token {
+        return Err(ProxyError::PolicyViolation("Invalid policy override token".to_string()));
+    }
+    let reason = req
+        .headers()
+        .get(REASON_HEADER)
+        .ok()
+        .flatten()
+        .filter(|reason| !reason.trim().is_empty())
+        .ok_or_else(|| ProxyError::InvalidRequest(format!("{} requires {}", OVERRIDE_HEADER, REASON_HEADER)))?;
+    let actor = req
+        .headers()
+        .get("X-Admin-Actor")
+        .ok()
+        .flatten()
+        .unwrap_or_else(|| "admin".to_string());
+    Ok(Some(PolicyOverride { actor, reason }))
+}
+
+/// Key of an audit entry; inverted time sorts KV's ascending listing newest first
+fn audit_key(at: u64) -> String {
+    format!("{}{:020}-{}", AUDIT_PREFIX, u64::MAX - at, uuid::Uuid::new_v4().simple())
+}
+
+/// Writes an entry to the override audit trail, under a key of its own
+///
+/// Concurrent overrides each get their own key, so none can overwrite
+/// another's entry. Callers must not forward the request when this fails:
+/// an override that isn't on record must not happen.
+pub async fn record(env: &Env, entry: OverrideAuditEntry) -> Result<()> {
+    let value = serde_json::to_string(&entry)?;
+    let mut put = env.kv("CONFIG")?.put(&audit_key(entry.at), &value)?.expiration_ttl(AUDIT_TTL_SECS);
+    if value.len() <= MAX_AUDIT_METADATA_BYTES {
+        put = put.metadata(&entry)?;
+    }
+    put.execute().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_audit_keys_sort_newest_first() {
+        let older = audit_key(1_767_225_600_000);
+        let newer = audit_key(1_767_229_200_000);
+        assert!(newer < older);
+        assert!(older.starts_with(AUDIT_PREFIX));
+        // Overrides in the same millisecond get keys of their own
+        assert_ne!(audit_key(1_767_225_600_000), older);
+    }
+}
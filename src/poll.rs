@@ -0,0 +1,165 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::time::Duration;
+use worker::*;
+
+use crate::error::ProxyError;
+use crate::handlers::registry::{Failure, ProcessorContext};
+use crate::handlers::upstream::{self, UpstreamRequest};
+use crate::handlers::ApiResponse;
+use crate::processors::storage;
+
+/// Storage namespace of poll fingerprints in the processor DO (see `storage`)
+pub const POLL_NAMESPACE: &str = "poll";
+
+/// A fingerprint not refreshed by a poll for this long is dropped
+const POLL_STATE_TTL: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+const MAX_KEY_LEN: usize = 256;
+
+/// Change detection asked for by a request's `poll` field
+#[derive(Debug, Clone, Deserialize)]
+pub struct PollSpec {
+    /// Name of the polled resource, e.g. "did-catalog-us"; scoped to the tenant
+    pub key: String,
+    /// Drop the body (as `null`) when it hasn't changed since the last poll
+    #[serde(default)]
+    pub omit_unchanged_body: bool,
+}
+
+impl PollSpec {
+    pub fn validate(&self) -> std::result::Result<(), ProxyError> {
+        if self.key.is_empty() || self.key.len() > MAX_KEY_LEN {
+            return Err(ProxyError::InvalidRequest(format!(
+                "poll.key must be 1-{} characters",
+                MAX_KEY_LEN
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Last response seen for a polled key
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct PollState {
+    fingerprint: String,
+    /// When the content last changed (epoch millis)
+    changed_at: u64,
+}
+
+impl PollState {
+    /// State after a poll that saw `fingerprint` at `now`, and whether the
+    /// content is unchanged since the previous poll
+    fn next(previous: Option<PollState>, fingerprint: String, now: u64) -> (PollState, bool) {
+        match previous {
+            Some(previous) if previous.fingerprint == fingerprint => (previous, true),
+            _ => (
+                PollState {
+                    fingerprint,
+                    changed_at: now,
+                },
+                false,
+            ),
+        }
+    }
+}
+
+/// Change detection reported in response metadata
+#[derive(Debug, Clone, Serialize)]
+pub struct PollUsage {
+    pub not_modified: bool,
+    /// Hash of the status and body; what the next poll is compared against
+    pub fingerprint: String,
+    /// When the content last changed (epoch millis)
+    pub changed_at: u64,
+}
+
+fn sha256_hex(bytes: &[u8]) -> String {
+    use sha2::{Digest, Sha256};
+
+    Sha256::digest(bytes).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn key(tenant_id: &str, poll_key: &str) -> String {
+    format!("{}/{}", POLL_NAMESPACE, sha256_hex(serde_json::json!([tenant_id, poll_key]).to_string().as_bytes()))
+}
+
+/// Fingerprint of a successful response
+///
+/// Headers are left out: `Date` and tracing headers change on every call
+/// even when the content doesn't.
+pub fn fingerprint(status: u16, body: &Value) -> String {
+    sha256_hex(serde_json::json!([status, body]).to_string().as_bytes())
+}
+
+/// Runs the request and compares a successful response with the last one
+/// seen for the key in this shard
+///
+/// Errors are passed through without touching the stored fingerprint, so a
+/// failed poll doesn't make the next good one look changed. Storage errors
+/// report the response as modified.
+pub async fn execute<R: UpstreamRequest>(
+    ctx: &ProcessorContext<'_>,
+    request: R,
+    spec: &PollSpec,
+) -> std::result::Result<ApiResponse, Failure> {
+    spec.validate()?;
+    if request.minimal() {
+        return Err(ProxyError::InvalidRequest("poll can't be combined with minimal".to_string()).into());
+    }
+    let mut response = upstream::execute(ctx, request).await?;
+    let data = match &mut response {
+        ApiResponse::Success(data) => data,
+        _ => return Ok(response),
+    };
+
+    let storage_key = key(ctx.tenant_id, &spec.key);
+    let fingerprint = fingerprint(data.status, &data.body);
+    let previous = match storage::get_live::<PollState>(ctx.state, &storage_key).await {
+        Ok(previous) => previous,
+        Err(e) => {
+            log_error!("Failed to read poll state of {}: {}", spec.key, e);
+            None
+        }
+    };
+    let (state, not_modified) = PollState::next(previous, fingerprint, Date::now().as_millis());
+    if let Err(e) = storage::put_with_ttl(ctx.state, &storage_key, state.clone(), POLL_STATE_TTL).await {
+        log_error!("Failed to store poll state of {}: {}", spec.key, e);
+    }
+
+    if not_modified && spec.omit_unchanged_body {
+        data.body = Value::Null;
+    }
+    response.metadata_mut().poll = Some(PollUsage {
+        not_modified,
+        fingerprint: state.fingerprint,
+        changed_at: state.changed_at,
+    });
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_unchanged_content_is_not_modified() {
+        let catalog = json!({"dids": ["12125550100", "12125550101"]});
+        let (state, not_modified) = PollState::next(None, fingerprint(200, &catalog), 1_000);
+        assert!(!not_modified);
+
+        // Same content: the change time is kept
+        let (state, not_modified) = PollState::next(Some(state), fingerprint(200, &catalog), 61_000);
+        assert!(not_modified);
+        assert_eq!(state.changed_at, 1_000);
+
+        let changed = json!({"dids": ["12125550100"]});
+        let (state, not_modified) = PollState::next(Some(state), fingerprint(200, &changed), 121_000);
+        assert!(!not_modified);
+        assert_eq!(state.changed_at, 121_000);
+
+        assert_ne!(fingerprint(200, &changed), fingerprint(203, &changed));
+        assert_ne!(key("root", "catalog"), key("billing", "catalog"));
+    }
+}
@@ -0,0 +1,68 @@
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::cost::CostEstimate;
+use crate::error::ProxyError;
+use crate::handlers::registry;
+use crate::handlers::upstream::UpstreamPreview;
+use crate::labels::Labels;
+
+/// Reads the optional top-level `validate_only` flag of a request body
+pub fn requested(body: &str) -> Result<bool, ProxyError> {
+    let value = match serde_json::from_str::<Value>(body) {
+        Ok(value) => value,
+        Err(_) => return Ok(false),
+    };
+    match value.get("validate_only") {
+        Some(Value::Bool(validate_only)) => Ok(*validate_only),
+        Some(_) => Err(ProxyError::InvalidRequest("validate_only must be a boolean".to_string())),
+        None => Ok(false),
+    }
+}
+
+/// What the proxy would do with a request, returned instead of sending it
+#[derive(Debug, Serialize)]
+pub struct Preview {
+    pub tenant: String,
+    pub request_type: String,
+    pub region: String,
+    /// Processor shard the request would run on, e.g. "weur-7"
+    pub shard: String,
+    /// Whether the shard is kept in EU datacenters
+    pub eu_jurisdiction: bool,
+    #[serde(skip_serializing_if = "Labels::is_empty")]
+    pub labels: Labels,
+    pub upstream: Vec<UpstreamPreview>,
+    /// Cost of sending every call once; retries would add to it
+    pub cost: CostEstimate,
+}
+
+impl Preview {
+    /// Builds the request as its processor would, failing like the processor would
+    pub fn build(
+        body: &str,
+        request_type: &str,
+        tenant: &str,
+        region: &str,
+        shard: String,
+        eu_jurisdiction: bool,
+        labels: Labels,
+    ) -> Result<Preview, ProxyError> {
+        let request_type = request_type.to_lowercase();
+        let upstream = registry::preview(&request_type, body)?;
+        let mut cost = CostEstimate::processor_call();
+        for call in &upstream {
+            cost += CostEstimate::upstream(1, call.bytes);
+        }
+        Ok(Preview {
+            tenant: tenant.to_string(),
+            request_type: if request_type.is_empty() { "http".to_string() } else { request_type },
+            region: region.to_string(),
+            shard,
+            eu_jurisdiction,
+            labels,
+            upstream,
+            cost,
+        })
+    }
+}
@@ -1,5 +1,8 @@
+use std::cell::RefCell;
 use worker::*;
 
+use crate::handlers::RegionAssertion;
+
 /// Fetches the actual Cloudflare datacenter (colo) where code is executing
 /// by querying the Cloudflare trace endpoint.
 ///
@@ -32,7 +35,80 @@ pub async fn fetch_actual_colo() -> Result<String> {
 /// Helper function to get actual colo with error handling
 pub async fn get_actual_colo() -> String {
     fetch_actual_colo().await.unwrap_or_else(|e| {
-        console_log!("Failed to get actual colo: {}", e);
+        log_error!("Failed to get actual colo: {}", e);
         "unknown".to_string()
     })
 }
+
+/// Returns the colo for this Durable Object instance, querying the trace
+/// endpoint only on first use
+///
+/// A DO stays in the datacenter it was created in for its whole lifetime,
+/// so the trace result can be reused for every request it handles.
+pub async fn cached_colo(cache: &RefCell<Option<String>>) -> String {
+    if let Some(colo) = cache.borrow().clone() {
+        return colo;
+    }
+
+    let colo = get_actual_colo().await;
+    // Don't cache failures so the next request gets another chance
+    if colo != "unknown" {
+        *cache.borrow_mut() = Some(colo.clone());
+    }
+    colo
+}
+
+/// Maps a Cloudflare colo (IATA airport code) to the location hint region it belongs to
+///
+/// Covers the major datacenters for each of the 8 regions. Unlisted colos
+/// return `None` and are treated as outside every region.
+pub fn region_for_colo(colo: &str) -> Option<&'static str> {
+    let region = match colo.to_uppercase().as_str() {
+        "SJC" | "SFO" | "LAX" | "SAN" | "SEA" | "PDX" | "SMF" | "LAS" | "PHX" | "DEN" | "SLC"
+        | "ABQ" | "YVR" | "HNL" => "wnam",
+        "IAD" | "EWR" | "JFK" | "BOS" | "PHL" | "PIT" | "ATL" | "MIA" | "TPA" | "JAX" | "CLT"
+        | "RIC" | "ORF" | "ORD" | "DTW" | "MSP" | "MCI" | "STL" | "IND" | "CMH" | "BNA" | "MEM"
+        | "OMA" | "DFW" | "IAH" | "AUS" | "SAT" | "BUF" | "YYZ" | "YUL" | "YOW" => "enam",
+        "LHR" | "MAN" | "EDI" | "DUB" | "CDG" | "MRS" | "AMS" | "BRU" | "LUX" | "FRA" | "DUS"
+        | "HAM" | "MUC" | "TXL" | "BER" | "ZRH" | "GVA" | "MAD" | "BCN" | "LIS" | "MXP"
+        | "FCO" | "CPH" | "OSL" | "ARN" | "HEL" => "weur",
+        "WAW" | "PRG" | "BUD" | "VIE" | "OTP" | "SOF" | "ATH" | "BEG" | "ZAG" | "LJU" | "BTS"
+        | "KBP" | "KIV" | "RIX" | "TLL" | "VNO" | "IST" => "eeur",
+        "NRT" | "HND" | "KIX" | "ICN" | "HKG" | "TPE" | "SIN" | "KUL" | "BKK" | "MNL" | "CGK"
+        | "SGN" | "HAN" | "BOM" | "DEL" | "MAA" | "BLR" | "HYD" | "CCU" => "apac",
+        "SYD" | "MEL" | "BNE" | "PER" | "ADL" | "AKL" | "CHC" => "oc",
+        "JNB" | "CPT" | "DUR" | "LOS" | "ACC" | "NBO" | "MBA" | "DAR" | "CAI" | "ALG" | "CMN"
+        | "TUN" => "af",
+        "DXB" | "AUH" | "DOH" | "BAH" | "KWI" | "MCT" | "RUH" | "JED" | "AMM" | "TLV"
+        | "BEY" => "me",
+        _ => return None,
+    };
+    Some(region)
+}
+
+/// Checks whether a colo belongs to the expected region code (e.g. "weur")
+pub fn check_region(expected: &str, colo: &str) -> RegionAssertion {
+    let expected = expected.to_lowercase();
+    let actual_region = region_for_colo(colo);
+    RegionAssertion {
+        passed: actual_region == Some(expected.as_str()),
+        expected,
+        actual_colo: colo.to_string(),
+        actual_region: actual_region.map(str::to_string),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_region_assertion() {
+        assert!(check_region("WEUR", "cdg").passed);
+        assert!(!check_region("weur", "IAD").passed);
+
+        let unknown = check_region("eeur", "unknown");
+        assert!(!unknown.passed);
+        assert_eq!(unknown.actual_region, None);
+    }
+}
@@ -1,13 +1,24 @@
 pub mod common;
+pub mod registry;
+pub mod slots;
+pub mod storage;
 
 #[macro_use]
 pub mod processor_macro;
 
+#[cfg(feature = "region-wnam")]
 pub mod wnam_processor;
+#[cfg(feature = "region-enam")]
 pub mod enam_processor;
+#[cfg(feature = "region-weur")]
 pub mod weur_processor;
+#[cfg(feature = "region-eeur")]
 pub mod eeur_processor;
+#[cfg(feature = "region-apac")]
 pub mod apac_processor;
+#[cfg(feature = "region-oc")]
 pub mod oc_processor;
+#[cfg(feature = "region-af")]
 pub mod af_processor;
+#[cfg(feature = "region-me")]
 pub mod me_processor;
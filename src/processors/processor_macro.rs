@@ -5,8 +5,17 @@
 macro_rules! define_processor {
     ($struct_name:ident, $region_code:expr, $region_name:expr) => {
         use worker::*;
+        use std::cell::{Cell, RefCell};
+        use std::collections::HashMap;
+        use crate::deadline::{self, Deadline};
+        use crate::expiry::{self, Expiry};
         use crate::processors::common;
+        use crate::processors::registry;
+        use crate::processors::slots;
+        use crate::processors::storage;
         use crate::handlers;
+        use crate::incidents;
+        use crate::retry;
         use crate::logger;
 
         // Durable Object that processes requests in a specific region
@@ -16,85 +25,293 @@ macro_rules! define_processor {
             state: State,
             #[allow(dead_code)]
             env: Env,
+            // Datacenter this instance runs in, resolved once via the trace endpoint
+            colo: RefCell<Option<String>>,
+            // Per-host token buckets pacing multi-call requests from this instance
+            pacing: handlers::pacing::Buckets,
+            // Outbound calls per host and minute, written to the pacing report
+            outbound: crate::pacing_report::OutboundCalls,
+            // Per-policy retry budgets of this instance
+            retry_budgets: retry::RetryBudgets,
+            // Per-host upstream error rates feeding incident markers
+            error_rates: incidents::ErrorRates,
+            // Cache keys being refetched, so an expired hot key goes upstream once
+            cache_refreshes: crate::cache::Refreshes,
+            // Request count and report schedule for the region's shard registry
+            activity: registry::ActivityReporter,
+            // Concurrency slots shared out between tenants by weight
+            slots: slots::TenantSlots,
         }
 
         impl DurableObject for $struct_name {
             fn new(state: State, env: Env) -> Self {
-                Self { state, env }
+                Self {
+                    state,
+                    env,
+                    colo: RefCell::new(None),
+                    pacing: RefCell::default(),
+                    outbound: RefCell::default(),
+                    retry_budgets: RefCell::default(),
+                    error_rates: RefCell::default(),
+                    cache_refreshes: Default::default(),
+                    activity: registry::ActivityReporter::new(Date::now().as_millis()),
+                    slots: Default::default(),
+                }
+            }
+
+            async fn fetch(&self, req: Request) -> Result<Response> {
+                // Log lines carry the request ID forwarded by the edge and this shard's name
+                let request_id = logger::request_id(req.headers().get(logger::REQUEST_ID_HEADER)?.as_deref());
+                let mut context = logger::LogContext::new(&request_id, &req.path(), Date::now().as_millis());
+                context.region = Some($region_code.to_lowercase());
+                context.do_name = req.headers().get(registry::SHARD_HEADER)?;
+                logger::scoped(context, self.process(req)).await
+            }
+
+            async fn alarm(&self) -> Result<Response> {
+                // The only alarm is the storage GC sweep scheduled by `storage::put_with_ttl`
+                let report = storage::run_gc(&self.state).await?;
+                log_info!(
+                    "{} storage GC: evicted {} entries, {} keys remaining",
+                    stringify!($struct_name),
+                    report.gc.last_gc_evictions,
+                    report.keys
+                );
+                Response::ok("ok")
             }
+        }
+
+        impl $struct_name {
+            /// Handles one request; `fetch` runs it with the request's log context
+            async fn process(&self, mut req: Request) -> Result<Response> {
+                // Internal maintenance endpoints, only reachable via the admin API
+                match req.path().as_str() {
+                    "/__internal/storage" => {
+                        return Response::from_json(&storage::report(&self.state).await?);
+                    }
+                    "/__internal/gc" => {
+                        return Response::from_json(&storage::run_gc(&self.state).await?);
+                    }
+                    "/__internal/ping" => {
+                        let cold = self.activity.ping();
+                        return Response::from_json(&serde_json::json!({ "cold": cold }));
+                    }
+                    "/__internal/stats" => {
+                        let capacity = crate::config::Config::load(&self.env).shard_concurrency.value;
+                        return Response::from_json(&self.slots.stats(capacity));
+                    }
+                    "/__internal/export" => {
+                        return Response::from_json(&storage::export(&self.state).await?);
+                    }
+                    "/__internal/import" => {
+                        let entries: Vec<crate::drain::StoredEntry> = req.json().await?;
+                        let imported = storage::import(&self.state, &entries).await?;
+                        log_info!("{} imported {} storage entries", stringify!($struct_name), imported);
+                        return Response::from_json(&serde_json::json!({ "imported": imported }));
+                    }
+                    "/__internal/keys" => {
+                        let query: HashMap<String, String> = req.url()?.query_pairs().into_owned().collect();
+                        let keys = storage::keys(
+                            &self.state,
+                            query.get("namespace").map(String::as_str),
+                            query.get("after").map(String::as_str),
+                            query.get("limit").and_then(|limit| limit.parse().ok()).unwrap_or(100),
+                        )
+                        .await?;
+                        return Response::from_json(&keys);
+                    }
+                    "/__internal/clear" => {
+                        let namespaces: Vec<String> = req.json().await?;
+                        let deleted = storage::clear(&self.state, &namespaces).await?;
+                        log_info!(
+                            "{} cleared {} keys of namespaces {:?}",
+                            stringify!($struct_name),
+                            deleted,
+                            namespaces
+                        );
+                        return Response::from_json(&serde_json::json!({ "deleted": deleted }));
+                    }
+                    _ => {}
+                }
 
-            async fn fetch(&self, mut req: Request) -> Result<Response> {
                 // Read log level from header
                 let log_level = logger::LogLevel::from_header(
                     &req.headers().get("X-Log-Level")?.unwrap_or_default()
                 );
 
+                // Requests that waited in the DO queue past the caller's deadline are dropped
+                let deadline = match Deadline::from_headers(req.headers()) {
+                    Ok(deadline) => deadline,
+                    Err(e) => return e.to_response(None),
+                };
+                if let Err(e) = deadline::remaining(deadline) {
+                    log_info!("Dropping queued request: {}", e);
+                    return e.to_response(None);
+                }
+
+                // So are requests that went stale there, whatever the caller's deadline
+                let expiry = match Expiry::from_headers(req.headers()) {
+                    Ok(expiry) => expiry,
+                    Err(e) => return e.to_response(None),
+                };
+                if let Err(e) = expiry::check(expiry) {
+                    log_info!("Dropping queued request: {}", e);
+                    return e.to_response(None);
+                }
+
+                // Tenant id forwarded by the edge, used to pick tenant-level policies
+                let tenant_id = req.headers().get("X-Tenant-Id")?.unwrap_or_default();
+                if let Some(token) = req.headers().get(crate::auth::TOKEN_HEADER)? {
+                    log_info!("Tenant {} authenticated with token {}", tenant_id, token);
+                }
+                let labels = req.headers().get(crate::labels::LABELS_HEADER)?.unwrap_or_default();
+
+                // This invocation's settings; a mismatch with the edge means a deploy is rolling out
+                let config = crate::config::Config::load(&self.env);
+                if let Some(edge_version) = req.headers().get(crate::config::VERSION_HEADER)? {
+                    if edge_version != config.version {
+                        log_info!("Config version {} differs from the edge's {}", config.version, edge_version);
+                    }
+                }
+
                 // Get the actual datacenter where this DO is executing
-                let actual_colo = common::get_actual_colo().await;
+                let actual_colo = common::cached_colo(&self.colo).await;
                 log_info!(
                     "{} processing in datacenter: {} (Region: {})",
                     stringify!($struct_name),
                     actual_colo,
                     $region_name
                 );
+                if !labels.is_empty() {
+                    log_info!("Tenant {} labels: {}", tenant_id, labels);
+                }
+                let end_user = req.headers().get(crate::end_user::END_USER_HEADER)?;
+                if let Some(end_user) = &end_user {
+                    log_info!("Tenant {} end user: {}", tenant_id, end_user);
+                }
+                let forward_end_user = crate::end_user::ForwardedEndUser::from_headers(
+                    end_user.as_deref(),
+                    req.headers().get(crate::end_user::FORWARDING_HEADER)?.as_deref(),
+                );
 
-                // Check X-Request-Type header to determine SOAP vs HTTP
-                let request_type = req.headers().get("X-Request-Type")?.unwrap_or_default();
-                let is_soap = request_type.to_lowercase() == "soap";
+                // Tenant retry budget and request priority forwarded by the edge
+                let tenant_budget = req
+                    .headers()
+                    .get(retry::TENANT_BUDGET_HEADER)?
+                    .and_then(|ratio| ratio.parse().ok())
+                    .map(|ratio| retry::TenantBudget {
+                        ratio,
+                        priority: req
+                            .headers()
+                            .get(retry::PRIORITY_HEADER)
+                            .ok()
+                            .flatten()
+                            .and_then(|priority| retry::Priority::parse(&priority))
+                            .unwrap_or_default(),
+                    });
 
-                if is_soap {
-                    // Handle SOAP request
-                    log_info!("Processing SOAP request");
+                let weight = req
+                    .headers()
+                    .get(slots::WEIGHT_HEADER)?
+                    .and_then(|weight| weight.parse().ok())
+                    .unwrap_or(slots::DEFAULT_WEIGHT);
 
-                    let soap_request_data = match req.json::<handlers::SoapRequestData>().await {
-                        Ok(data) => {
-                            log_debug!(log_level, "SOAP action: {}, namespace: {}, url: {}", data.action, data.namespace, data.url);
-                            data
-                        }
+                let shard = req.headers().get(registry::SHARD_HEADER)?.unwrap_or_default();
+                self.activity.record(&self.env, &shard, &actual_colo).await;
+
+                // X-Request-Type selects the handler; see `handlers::registry`
+                let request_type = req.headers().get("X-Request-Type")?.unwrap_or_default().to_lowercase();
+                // A WebSocket is bridged outside the concurrency slots: it holds no slot while it stays open
+                if let Some(target) = crate::websocket::Target::from_headers(req.headers())? {
+                    return match crate::websocket::bridge(&self.env, &config, &tenant_id, &target).await {
+                        Ok(response) => Ok(response),
                         Err(e) => {
-                            log_error!("Failed to parse SOAP request JSON: {}", e);
-                            return Response::error(format!("Invalid SOAP JSON: {}", e), 400);
+                            log_info!("WebSocket to {} not opened: {}", target.url, e);
+                            e.to_response(None)
                         }
                     };
-
-                    // Process the SOAP request
-                    match handlers::process_soap_request(soap_request_data, log_level).await {
-                        Ok(api_response) => {
-                            log_info!("SOAP request completed successfully");
-                            Response::from_json(&api_response)
-                        }
-                        Err(e) => {
-                            log_error!("SOAP request processing error: {}", e);
-                            Response::error(format!("SOAP error: {}", e), 500)
+                }
+                // A body the edge streamed through unread gets the edge's size limit and maintenance holds here
+                let body = if req.headers().get(crate::routing::BODY_UNREAD_HEADER)?.is_some() {
+                    let body = match crate::limits::read_body(&mut req, config.max_request_bytes.value).await? {
+                        Ok(body) => body,
+                        Err(e) => return e.to_response(None),
+                    };
+                    // Previews are built at the edge; never send what the caller only meant to check
+                    if crate::preview::requested(&body).unwrap_or(true) {
+                        let e = crate::error::ProxyError::InvalidRequest(format!(
+                            "validate_only can't be combined with {}",
+                            crate::routing::ROUTING_KEY_HEADER
+                        ));
+                        return e.to_response(None);
+                    }
+                    let hosts: std::collections::BTreeSet<String> =
+                        crate::routing::target_hosts(&body).into_iter().flatten().collect();
+                    for host in hosts {
+                        if let Err(e) = crate::maintenance::enforce(&self.env, &host, deadline).await {
+                            log_info!("Request to {} not forwarded: {}", host, e);
+                            return e.to_response(None);
                         }
                     }
+                    body
                 } else {
-                    // Handle regular HTTP request
-                    log_info!("Processing HTTP request");
-
-                    let request_data = match req.json::<handlers::RequestData>().await {
-                        Ok(data) => {
-                            log_debug!(log_level, "HTTP method: {:?}, url: {}", data.method, data.url);
-                            data
-                        }
-                        Err(e) => {
-                            log_error!("Failed to parse request JSON: {}", e);
-                            return Response::error(format!("Invalid JSON: {}", e), 400);
-                        }
-                    };
+                    req.text().await?
+                };
+                let include_cost = req.headers().get(crate::cost::INCLUDE_COST_HEADER)?.as_deref() == Some("true");
+                let cost = Cell::new(crate::cost::CostEstimate::processor_call());
+                // The sandbox tenant's calls are confined whatever else is configured
+                let mut guard = config.target_guard();
+                if tenant_id == crate::tenants::SANDBOX_TENANT_ID {
+                    guard.sandbox = Some(crate::sandbox::Sandbox::load(&self.env).await);
+                }
+                let ctx = handlers::registry::ProcessorContext {
+                    env: &self.env,
+                    state: &self.state,
+                    region_code: $region_code,
+                    actual_colo: &actual_colo,
+                    tenant_id: &tenant_id,
+                    deadline,
+                    expiry,
+                    log_level,
+                    pacing: &self.pacing,
+                    outbound: &self.outbound,
+                    retry_budgets: &self.retry_budgets,
+                    tenant_budget,
+                    error_rates: &self.error_rates,
+                    cache_refreshes: &self.cache_refreshes,
+                    cost: &cost,
+                    include_cost,
+                    guard,
+                    max_response_bytes: config.max_response_bytes.value,
+                    forward_end_user,
+                    attempt_header: config.attempt_header.value.clone(),
+                };
 
-                    // Process the proxy request
-                    match handlers::process_request(request_data, log_level).await {
-                        Ok(api_response) => {
-                            log_info!("HTTP request completed successfully");
-                            Response::from_json(&api_response)
-                        }
-                        Err(e) => {
-                            log_error!("Proxy request processing error: {}", e);
-                            Response::error(format!("Proxy error: {}", e), 500)
+                // A tenant past its weighted share of the shard's slots waits behind its own requests
+                let admission = self.slots.acquire(&tenant_id, weight, config.shard_concurrency.value);
+                if admission.queued() {
+                    log_info!("Tenant {} is queued for a slot on {}", tenant_id, shard);
+                }
+                let _slot = match deadline {
+                    Some(deadline) => {
+                        let remaining = deadline.check().unwrap_or_default();
+                        match futures_util::future::select(admission, Box::pin(Delay::from(remaining))).await {
+                            futures_util::future::Either::Left((slot, _)) => slot,
+                            futures_util::future::Either::Right(_) => {
+                                log_info!("Dropping request queued for a slot: deadline passed");
+                                let e = crate::error::ProxyError::DeadlineExceeded { deadline: deadline.0 };
+                                return e.to_response(None);
+                            }
                         }
                     }
+                    None => admission.await,
+                };
+                // The slot and a maintenance hold may both have kept the request waiting
+                if let Err(e) = expiry::check(expiry) {
+                    log_info!("Dropping request queued for a slot: {}", e);
+                    return e.to_response(None);
                 }
+                handlers::registry::dispatch(&request_type, &ctx, &body).await
             }
         }
     };
@@ -0,0 +1,491 @@
+use serde::{Deserialize, Serialize};
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, HashMap};
+use std::time::Duration;
+use worker::*;
+
+use crate::breaker::{BreakerAnswer, BreakerCall};
+use crate::handlers::pacing::{Buckets, PacingLimit, TokenBucket};
+use crate::processors::storage;
+use crate::retry::{RetryBudgets, TenantBudgetAnswer, TenantBudgetCall};
+use crate::routing;
+use crate::tenants::{RateLimit, RateWindow};
+
+/// Binding of the registry namespace; activity reporting is off when unbound
+const REGISTRY_BINDING: &str = "SHARD_REGISTRY";
+
+/// Header carrying the shard name from the edge to the processor
+pub const SHARD_HEADER: &str = "X-Shard";
+
+/// Minimum time between two reports of one shard
+const REPORT_INTERVAL_MS: u64 = 60 * 1000;
+
+/// Storage key of the region's shard map
+const SHARDS_KEY: &str = "shards";
+
+/// What a processor instance last reported about itself
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ShardActivity {
+    pub shard: String,
+    pub colo: String,
+    /// When the reporting DO instance was initialized (epoch millis)
+    pub init_at: u64,
+    /// Last request seen by the instance, to within `REPORT_INTERVAL_MS`
+    pub last_active_at: u64,
+    /// Requests handled by the instance since `init_at`
+    pub requests: u64,
+    /// Whether the instance's first request paid for its start, rather than a keep-warm ping
+    #[serde(default)]
+    pub cold_start: bool,
+    /// Instances of the shard whose first request paid for their start, counted by the registry
+    #[serde(default)]
+    pub cold_starts: u64,
+    /// Instances of the shard a keep-warm ping started before their first request
+    #[serde(default)]
+    pub warm_starts: u64,
+}
+
+impl ShardActivity {
+    /// Carries the shard's start counts over from its previous report, counting a new instance
+    fn count_start(&mut self, previous: Option<&ShardActivity>) {
+        if let Some(previous) = previous {
+            self.cold_starts = previous.cold_starts;
+            self.warm_starts = previous.warm_starts;
+        }
+        if previous.is_none_or(|previous| previous.init_at != self.init_at) {
+            if self.cold_start {
+                self.cold_starts += 1;
+            } else {
+                self.warm_starts += 1;
+            }
+        }
+    }
+}
+
+/// A shard as listed by the admin API
+#[derive(Debug, Serialize)]
+pub struct ShardStatus {
+    pub shard: String,
+    /// Whether the shard handled a request within the queried window
+    pub active: bool,
+    /// Latest report; missing for shards that never ran or predate the registry
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub activity: Option<ShardActivity>,
+}
+
+/// Whether the registry namespace is bound in this deployment
+pub fn is_bound(env: &Env) -> bool {
+    env.durable_object(REGISTRY_BINDING).is_ok()
+}
+
+/// Returns the instance counting a tenant's requests, if the namespace is bound
+///
+/// One per tenant and not tied to a region, since the limit is global.
+fn tenant_stub(env: &Env, tenant_id: &str) -> Option<Result<Stub>> {
+    let namespace = env.durable_object(REGISTRY_BINDING).ok()?;
+    Some(namespace.get_by_name(&format!("tenant-{}", tenant_id)))
+}
+
+/// Returns the registry stub of a region, if the namespace is bound
+pub fn stub(env: &Env, region_code: &str) -> Option<Result<Stub>> {
+    let namespace = env.durable_object(REGISTRY_BINDING).ok()?;
+    Some(namespace.get_by_name_with_location_hint(&format!("{}-registry", region_code), region_code))
+}
+
+/// Lists every shard of a region, including those that never reported
+pub async fn region_status(
+    env: &Env,
+    region_code: &str,
+    pool_size: u32,
+    active_since: u64,
+) -> Result<Option<Vec<ShardStatus>>> {
+    let stub = match stub(env, region_code) {
+        Some(stub) => stub?,
+        None => return Ok(None),
+    };
+    let mut response = stub.fetch_with_str("http://internal/shards").await?;
+    let reported: BTreeMap<String, ShardActivity> = response.json().await?;
+    Ok(Some(statuses(region_code, pool_size, reported, active_since)))
+}
+
+/// Every shard of the pool, then shards that reported from outside it (the pool shrank)
+fn statuses(
+    region_code: &str,
+    pool_size: u32,
+    mut reported: BTreeMap<String, ShardActivity>,
+    active_since: u64,
+) -> Vec<ShardStatus> {
+    let status = |shard: String, activity: Option<ShardActivity>| ShardStatus {
+        active: activity.as_ref().is_some_and(|activity| activity.last_active_at >= active_since),
+        shard,
+        activity,
+    };
+    let mut statuses: Vec<ShardStatus> = (0..pool_size)
+        .map(|index| {
+            let shard = routing::shard_name(region_code, index);
+            let activity = reported.remove(&shard);
+            status(shard, activity)
+        })
+        .collect();
+    let mut outside: Vec<(u32, String, ShardActivity)> = reported
+        .into_iter()
+        .filter_map(|(shard, activity)| Some((routing::parse_shard_name(&shard)?.1, shard, activity)))
+        .collect();
+    outside.sort_by_key(|(index, _, _)| *index);
+    statuses.extend(outside.into_iter().map(|(_, shard, activity)| status(shard, Some(activity))));
+    statuses
+}
+
+/// Per-instance request counter that reports to the region's registry
+///
+/// Reports go out on the first request and then at most once per
+/// `REPORT_INTERVAL_MS`, so the registry costs one subrequest per shard
+/// per minute of activity.
+pub struct ActivityReporter {
+    init_at: u64,
+    requests: Cell<u64>,
+    last_report_at: Cell<Option<u64>>,
+    /// Set by whichever comes first: a request (cold) or a keep-warm ping (warm)
+    cold_start: Cell<Option<bool>>,
+}
+
+impl ActivityReporter {
+    pub fn new(init_at: u64) -> ActivityReporter {
+        ActivityReporter {
+            init_at,
+            requests: Cell::new(0),
+            last_report_at: Cell::new(None),
+            cold_start: Cell::new(None),
+        }
+    }
+
+    /// Records a keep-warm ping; returns whether the instance was cold, i.e. the ping started it
+    pub fn ping(&self) -> bool {
+        let cold = self.cold_start.get().is_none();
+        if cold {
+            self.cold_start.set(Some(false));
+        }
+        cold
+    }
+
+    /// Counts a request and reports to the registry when a report is due
+    pub async fn record(&self, env: &Env, shard: &str, colo: &str) {
+        self.requests.set(self.requests.get() + 1);
+        if self.cold_start.get().is_none() {
+            self.cold_start.set(Some(true));
+        }
+        let now = Date::now().as_millis();
+        if shard.is_empty() || self.last_report_at.get().is_some_and(|at| now < at + REPORT_INTERVAL_MS) {
+            return;
+        }
+        self.last_report_at.set(Some(now));
+
+        let activity = ShardActivity {
+            shard: shard.to_string(),
+            colo: colo.to_string(),
+            init_at: self.init_at,
+            last_active_at: now,
+            requests: self.requests.get(),
+            cold_start: self.cold_start.get().unwrap_or_default(),
+            cold_starts: 0,
+            warm_starts: 0,
+        };
+        if let Err(e) = report(env, &activity).await {
+            log_error!("Failed to report activity of {}: {}", shard, e);
+        }
+    }
+}
+
+async fn report(env: &Env, activity: &ShardActivity) -> Result<()> {
+    let (region_code, _) = match routing::parse_shard_name(&activity.shard) {
+        Some(parsed) => parsed,
+        None => return Ok(()),
+    };
+    let stub = match stub(env, region_code) {
+        Some(stub) => stub?,
+        None => return Ok(()),
+    };
+
+    let mut init = RequestInit::new();
+    init.method = Method::Post;
+    init.body = Some(serde_json::to_string(activity)?.into());
+    let request = Request::new_with_init("http://internal/report", &init)?;
+    stub.fetch_with_request(request).await?;
+    Ok(())
+}
+
+#[derive(Serialize, Deserialize)]
+struct PaceRequest {
+    host: String,
+    limit: PacingLimit,
+}
+
+#[derive(Serialize, Deserialize)]
+struct PaceResponse {
+    wait_ms: u64,
+}
+
+/// Takes a pacing token for `host` from the region's shared bucket
+///
+/// Returns how long to wait for it, or `None` when the registry isn't bound
+/// or can't be reached; the caller then paces with its own bucket.
+pub async fn reserve_token(env: &Env, region_code: &str, host: &str, limit: &PacingLimit) -> Option<Duration> {
+    let stub = match stub(env, region_code)? {
+        Ok(stub) => stub,
+        Err(e) => {
+            log_error!("Failed to reach {} registry for pacing: {}", region_code, e);
+            return None;
+        }
+    };
+    let pace = PaceRequest {
+        host: host.to_string(),
+        limit: *limit,
+    };
+    let result: Result<PaceResponse> = async {
+        let mut init = RequestInit::new();
+        init.method = Method::Post;
+        init.body = Some(serde_json::to_string(&pace)?.into());
+        let request = Request::new_with_init("http://internal/pace", &init)?;
+        stub.fetch_with_request(request).await?.json().await
+    }
+    .await;
+    match result {
+        Ok(response) => Some(Duration::from_millis(response.wait_ms)),
+        Err(e) => {
+            log_error!("Shared pacing for {} failed, pacing locally: {}", host, e);
+            None
+        }
+    }
+}
+
+/// Deposits or withdraws on a tenant's region-wide retry budget
+///
+/// Returns `None` when the registry isn't bound or can't be reached.
+pub async fn tenant_retry_budget(env: &Env, region_code: &str, call: &TenantBudgetCall) -> Option<TenantBudgetAnswer> {
+    let stub = match stub(env, region_code)? {
+        Ok(stub) => stub,
+        Err(e) => {
+            log_error!("Failed to reach {} registry for retry budgets: {}", region_code, e);
+            return None;
+        }
+    };
+    let result: Result<TenantBudgetAnswer> = async {
+        let mut init = RequestInit::new();
+        init.method = Method::Post;
+        init.body = Some(serde_json::to_string(call)?.into());
+        let request = Request::new_with_init("http://internal/retry-budget", &init)?;
+        stub.fetch_with_request(request).await?.json().await
+    }
+    .await;
+    match result {
+        Ok(answer) => Some(answer),
+        Err(e) => {
+            log_error!("Retry budget of tenant {} unavailable: {}", call.tenant, e);
+            None
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct RateLimitResponse {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    retry_after_secs: Option<u64>,
+}
+
+/// Counts a request against a tenant's rate limit
+///
+/// Returns the seconds to wait when the tenant is over its limit; `None`
+/// when it isn't, or when the registry isn't bound or can't be reached.
+pub async fn tenant_rate_limit(env: &Env, tenant_id: &str, limit: &RateLimit) -> Option<u64> {
+    let stub = match tenant_stub(env, tenant_id)? {
+        Ok(stub) => stub,
+        Err(e) => {
+            log_error!("Failed to reach the rate limiter of tenant {}: {}", tenant_id, e);
+            return None;
+        }
+    };
+    let result: Result<RateLimitResponse> = async {
+        let mut init = RequestInit::new();
+        init.method = Method::Post;
+        init.body = Some(serde_json::to_string(limit)?.into());
+        let request = Request::new_with_init("http://internal/rate-limit", &init)?;
+        stub.fetch_with_request(request).await?.json().await
+    }
+    .await;
+    match result {
+        Ok(response) => response.retry_after_secs,
+        Err(e) => {
+            log_error!("Rate limit of tenant {} unavailable, letting the request through: {}", tenant_id, e);
+            None
+        }
+    }
+}
+
+/// Checks or updates a host's breaker in the region's registry
+///
+/// Returns `None` when the registry isn't bound or can't be reached; the
+/// caller then keeps the breaker in its own storage.
+pub async fn breaker(env: &Env, region_code: &str, call: &BreakerCall) -> Option<BreakerAnswer> {
+    let stub = match stub(env, region_code)? {
+        Ok(stub) => stub,
+        Err(e) => {
+            log_error!("Failed to reach {} registry for circuit breakers: {}", region_code, e);
+            return None;
+        }
+    };
+    let result: Result<BreakerAnswer> = async {
+        let mut init = RequestInit::new();
+        init.method = Method::Post;
+        init.body = Some(serde_json::to_string(call)?.into());
+        let request = Request::new_with_init("http://internal/breaker", &init)?;
+        stub.fetch_with_request(request).await?.json().await
+    }
+    .await;
+    match result {
+        Ok(answer) => Some(answer),
+        Err(e) => {
+            log_error!("Shared circuit breaker of {} unavailable, using the shard's: {}", call.host, e);
+            None
+        }
+    }
+}
+
+/// Durable Object coordinating the shards of one region
+///
+/// Keeps the latest activity report of each shard, so admin and diagnostics
+/// endpoints don't wake every shard, the pacing buckets and tenant retry
+/// budgets every shard of the region draws from, and the region's circuit
+/// breakers (in storage, namespace `breaker`). Instances named `tenant-{id}`
+/// instead count one tenant's requests against its rate limit.
+#[durable_object]
+pub struct ShardRegistry {
+    state: State,
+    #[allow(dead_code)]
+    env: Env,
+    // Region-wide pacing buckets; like per-shard buckets they live in memory only
+    buckets: Buckets,
+    // Tenant retry budgets by tenant id, in memory like the per-policy ones
+    retry_budgets: RetryBudgets,
+    // The tenant's requests this minute, in a `tenant-{id}` instance
+    rate_window: Cell<RateWindow>,
+}
+
+impl DurableObject for ShardRegistry {
+    fn new(state: State, env: Env) -> Self {
+        Self {
+            state,
+            env,
+            buckets: RefCell::default(),
+            retry_budgets: RefCell::default(),
+            rate_window: Cell::default(),
+        }
+    }
+
+    async fn fetch(&self, mut req: Request) -> Result<Response> {
+        let storage = self.state.storage();
+
+        match (req.method(), req.path().as_str()) {
+            (Method::Get, "/shards") => {
+                let shards: BTreeMap<String, ShardActivity> = storage.get(SHARDS_KEY).await?.unwrap_or_default();
+                Response::from_json(&shards)
+            }
+            (Method::Post, "/report") => {
+                let mut activity: ShardActivity = req.json().await?;
+                let mut shards: BTreeMap<String, ShardActivity> = storage.get(SHARDS_KEY).await?.unwrap_or_default();
+                activity.count_start(shards.get(&activity.shard));
+                shards.insert(activity.shard.clone(), activity);
+                storage.put(SHARDS_KEY, &shards).await?;
+                Response::empty()
+            }
+            (Method::Post, "/pace") => {
+                let pace: PaceRequest = req.json().await?;
+                let now = Date::now().as_millis();
+                let wait = self
+                    .buckets
+                    .borrow_mut()
+                    .entry(pace.host)
+                    .or_insert_with(|| TokenBucket::full(&pace.limit, now))
+                    .reserve(&pace.limit, now);
+                Response::from_json(&PaceResponse {
+                    wait_ms: wait.as_millis() as u64,
+                })
+            }
+            (Method::Post, "/retry-budget") => {
+                let call: TenantBudgetCall = req.json().await?;
+                let answer = call.apply(self.retry_budgets.borrow_mut().entry(call.tenant.clone()).or_default());
+                Response::from_json(&answer)
+            }
+            (Method::Post, "/rate-limit") => {
+                let limit: RateLimit = req.json().await?;
+                let mut window = self.rate_window.get();
+                let retry_after_secs = window.admit(&limit, Date::now().as_millis());
+                self.rate_window.set(window);
+                Response::from_json(&RateLimitResponse { retry_after_secs })
+            }
+            (Method::Post, "/breaker") => {
+                let call: BreakerCall = req.json().await?;
+                Response::from_json(&call.apply(&storage).await?)
+            }
+            // The admin shard key listing and reset, for a breaker stuck open
+            (Method::Get, "/__internal/keys") => {
+                let query: HashMap<String, String> = req.url()?.query_pairs().into_owned().collect();
+                let keys = storage::keys(
+                    &self.state,
+                    query.get("namespace").map(String::as_str),
+                    query.get("after").map(String::as_str),
+                    query.get("limit").and_then(|limit| limit.parse().ok()).unwrap_or(100),
+                )
+                .await?;
+                Response::from_json(&keys)
+            }
+            (Method::Post, "/__internal/clear") => {
+                let namespaces: Vec<String> = req.json().await?;
+                let deleted = storage::clear(&self.state, &namespaces).await?;
+                log_info!("ShardRegistry cleared {} keys of namespaces {:?}", deleted, namespaces);
+                Response::from_json(&serde_json::json!({ "deleted": deleted }))
+            }
+            _ => Response::error("Not found", 404),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_statuses_cover_every_shard() {
+        let mut reported = BTreeMap::new();
+        for (shard, last_active_at) in [("weur-2", 5_000), ("weur-7", 500), ("weur-12", 2_000)] {
+            let activity = ShardActivity {
+                shard: shard.to_string(),
+                colo: "AMS".to_string(),
+                init_at: 100,
+                last_active_at,
+                requests: 3,
+                cold_start: true,
+                cold_starts: 0,
+                warm_starts: 0,
+            };
+            reported.insert(shard.to_string(), activity);
+        }
+
+        let statuses = statuses("weur", routing::DEFAULT_POOL_SIZE, reported, 1_000);
+        assert_eq!(statuses.len(), routing::DEFAULT_POOL_SIZE as usize + 1);
+        assert_eq!(statuses[10].shard, "weur-12");
+        assert!(statuses[10].active);
+        assert!(statuses[2].active);
+        assert!(!statuses[7].active && statuses[7].activity.is_some());
+        assert!(!statuses[0].active && statuses[0].activity.is_none());
+
+        // Start counts survive reports and grow with each new instance
+        let mut first = statuses[2].activity.clone().unwrap();
+        first.count_start(None);
+        let mut again = ShardActivity { requests: 9, ..first.clone() };
+        again.count_start(Some(&first));
+        let mut pinged = ShardActivity { init_at: 200, cold_start: false, ..again.clone() };
+        pinged.count_start(Some(&again));
+        assert_eq!((again.cold_starts, again.warm_starts), (1, 0));
+        assert_eq!((pinged.cold_starts, pinged.warm_starts), (1, 1));
+    }
+}
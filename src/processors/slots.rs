@@ -0,0 +1,271 @@
+use serde::Serialize;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll, Waker};
+
+/// Header carrying the tenant's `concurrency_weight` from the edge to the processor
+pub const WEIGHT_HEADER: &str = "X-Tenant-Weight";
+
+/// Weight of a tenant without a `concurrency_weight`
+pub const DEFAULT_WEIGHT: u32 = 1;
+
+/// Concurrency slots of one processor DO, shared fairly between tenants
+///
+/// Up to `capacity` requests run at once. Beyond that they queue per tenant,
+/// and each freed slot goes to the waiting tenant using the least of its
+/// weighted share (in flight / weight), so a tenant's burst waits behind its
+/// own requests rather than everyone's. One tenant never holds more than
+/// three quarters of the slots, leaving room for others to start at once.
+/// A capacity of 0 admits everything and only counts.
+#[derive(Default)]
+pub struct TenantSlots(RefCell<Slots>);
+
+#[derive(Default)]
+struct Slots {
+    tenants: BTreeMap<String, TenantQueue>,
+    /// Tickets handed a slot whose request hasn't resumed yet
+    granted: HashSet<u64>,
+    next_ticket: u64,
+}
+
+#[derive(Default)]
+struct TenantQueue {
+    weight: u32,
+    in_flight: u32,
+    /// Waiting tickets in arrival order, with the waker of each request
+    waiting: VecDeque<(u64, Option<Waker>)>,
+    admitted: u64,
+    queued: u64,
+    peak_in_flight: u32,
+}
+
+/// One tenant's slots on a shard, for capacity tuning
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TenantSlotStats {
+    pub weight: u32,
+    pub in_flight: u32,
+    pub waiting: usize,
+    /// Requests admitted since the instance started
+    pub admitted: u64,
+    /// How many of them had to wait for a slot
+    pub queued: u64,
+    pub peak_in_flight: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SlotStats {
+    /// Slots of this shard; 0 is unlimited
+    pub capacity: u32,
+    pub in_flight: u32,
+    pub waiting: usize,
+    pub tenants: BTreeMap<String, TenantSlotStats>,
+}
+
+impl Slots {
+    fn in_flight(&self) -> u32 {
+        self.tenants.values().map(|tenant| tenant.in_flight).sum()
+    }
+
+    /// Hands free slots to waiting tickets, fairest first, and wakes their requests
+    fn grant(&mut self, capacity: u32) {
+        let per_tenant = if capacity == 0 { u32::MAX } else { capacity - capacity / 4 };
+        loop {
+            if capacity > 0 && self.in_flight() >= capacity {
+                return;
+            }
+            // Least used share first, compared without division; the oldest ticket breaks ties
+            let next = self
+                .tenants
+                .values_mut()
+                .filter(|tenant| !tenant.waiting.is_empty() && tenant.in_flight < per_tenant)
+                .min_by(|a, b| {
+                    (u64::from(a.in_flight) * u64::from(b.weight))
+                        .cmp(&(u64::from(b.in_flight) * u64::from(a.weight)))
+                        .then(a.waiting[0].0.cmp(&b.waiting[0].0))
+                });
+            let Some(tenant) = next else {
+                return;
+            };
+            let (ticket, waker) = tenant.waiting.pop_front().unwrap_or_default();
+            tenant.in_flight += 1;
+            tenant.peak_in_flight = tenant.peak_in_flight.max(tenant.in_flight);
+            self.granted.insert(ticket);
+            if let Some(waker) = waker {
+                waker.wake();
+            }
+        }
+    }
+
+    fn release(&mut self, tenant_id: &str, capacity: u32) {
+        if let Some(tenant) = self.tenants.get_mut(tenant_id) {
+            tenant.in_flight = tenant.in_flight.saturating_sub(1);
+        }
+        self.grant(capacity);
+    }
+}
+
+impl TenantSlots {
+    /// Waits for a slot for one of `tenant_id`'s requests; dropping the future leaves the queue
+    pub fn acquire<'a>(&'a self, tenant_id: &str, weight: u32, capacity: u32) -> Admission<'a> {
+        let mut slots = self.0.borrow_mut();
+        let ticket = slots.next_ticket;
+        slots.next_ticket += 1;
+        let tenant = slots.tenants.entry(tenant_id.to_string()).or_default();
+        tenant.weight = weight.max(1);
+        tenant.waiting.push_back((ticket, None));
+        slots.grant(capacity);
+        let queued = !slots.granted.contains(&ticket);
+        if let Some(tenant) = slots.tenants.get_mut(tenant_id) {
+            tenant.admitted += 1;
+            tenant.queued += u64::from(queued);
+        }
+        Admission {
+            slots: self,
+            tenant_id: tenant_id.to_string(),
+            capacity,
+            ticket,
+            queued,
+            done: false,
+        }
+    }
+
+    pub fn stats(&self, capacity: u32) -> SlotStats {
+        let slots = self.0.borrow();
+        let tenants: BTreeMap<String, TenantSlotStats> = slots
+            .tenants
+            .iter()
+            .map(|(id, tenant)| {
+                let stats = TenantSlotStats {
+                    weight: tenant.weight,
+                    in_flight: tenant.in_flight,
+                    waiting: tenant.waiting.len(),
+                    admitted: tenant.admitted,
+                    queued: tenant.queued,
+                    peak_in_flight: tenant.peak_in_flight,
+                };
+                (id.clone(), stats)
+            })
+            .collect();
+        SlotStats {
+            capacity,
+            in_flight: slots.in_flight(),
+            waiting: tenants.values().map(|tenant| tenant.waiting).sum(),
+            tenants,
+        }
+    }
+}
+
+/// A request waiting for its slot
+pub struct Admission<'a> {
+    slots: &'a TenantSlots,
+    tenant_id: String,
+    capacity: u32,
+    ticket: u64,
+    queued: bool,
+    done: bool,
+}
+
+impl Admission<'_> {
+    /// Whether the request had to wait rather than start at once
+    pub fn queued(&self) -> bool {
+        self.queued
+    }
+}
+
+impl<'a> Future for Admission<'a> {
+    type Output = Slot<'a>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Slot<'a>> {
+        let mut slots = self.slots.0.borrow_mut();
+        if slots.granted.remove(&self.ticket) {
+            drop(slots);
+            self.done = true;
+            return Poll::Ready(Slot {
+                slots: self.slots,
+                tenant_id: self.tenant_id.clone(),
+                capacity: self.capacity,
+            });
+        }
+        let ticket = self.ticket;
+        if let Some(tenant) = slots.tenants.get_mut(&self.tenant_id) {
+            if let Some(entry) = tenant.waiting.iter_mut().find(|(waiting, _)| *waiting == ticket) {
+                entry.1 = Some(cx.waker().clone());
+            }
+        }
+        Poll::Pending
+    }
+}
+
+impl Drop for Admission<'_> {
+    fn drop(&mut self) {
+        if self.done {
+            return;
+        }
+        // Given up while waiting (e.g. past its deadline): leave the queue, or pass on a slot granted meanwhile
+        let mut slots = self.slots.0.borrow_mut();
+        if slots.granted.remove(&self.ticket) {
+            slots.release(&self.tenant_id, self.capacity);
+        } else if let Some(tenant) = slots.tenants.get_mut(&self.tenant_id) {
+            tenant.waiting.retain(|(waiting, _)| *waiting != self.ticket);
+        }
+    }
+}
+
+/// A running request's slot, freed for the next waiting request on drop
+pub struct Slot<'a> {
+    slots: &'a TenantSlots,
+    tenant_id: String,
+    capacity: u32,
+}
+
+impl Drop for Slot<'_> {
+    fn drop(&mut self) {
+        self.slots.0.borrow_mut().release(&self.tenant_id, self.capacity);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::FutureExt;
+
+    #[test]
+    fn test_slots_are_shared_by_weight() {
+        let slots = TenantSlots::default();
+        // Capacity 4 lets one tenant hold at most 3
+        let mut batch: Vec<_> = (0..5).map(|_| slots.acquire("batch", 1, 4)).collect();
+        assert_eq!(batch.iter().filter(|admission| !admission.queued()).count(), 3);
+        let waiting = batch.split_off(3);
+        let mut running: Vec<Option<Slot>> = batch.into_iter().map(|admission| admission.now_or_never()).collect();
+        assert!(running.iter().all(Option::is_some));
+
+        // The fourth slot goes to another tenant at once
+        let billing = slots.acquire("billing", 2, 4).now_or_never();
+        assert!(billing.is_some());
+        let web = slots.acquire("web", 1, 4);
+        assert!(web.queued());
+        let stats = slots.stats(4);
+        assert_eq!((stats.in_flight, stats.waiting), (4, 3));
+        assert_eq!(stats.tenants["batch"].waiting, 2);
+
+        // A freed batch slot goes to web, which has nothing in flight, not to batch's queue
+        running[0] = None;
+        let web = web.now_or_never();
+        assert!(web.is_some());
+        assert_eq!(slots.stats(4).tenants["batch"].waiting, 2);
+
+        // Dropped while waiting, a request leaves the queue
+        drop(waiting);
+        drop(running);
+        let stats = slots.stats(4);
+        assert_eq!((stats.in_flight, stats.waiting), (2, 0));
+        assert_eq!(stats.tenants["batch"].admitted, 5);
+        assert_eq!(stats.tenants["batch"].peak_in_flight, 3);
+
+        // Unlimited capacity only counts
+        let free: Vec<_> = (0..10).map(|_| slots.acquire("batch", 1, 0)).collect();
+        assert!(free.iter().all(|admission| !admission.queued()));
+    }
+}
@@ -0,0 +1,383 @@
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::time::Duration;
+use worker::js_sys;
+use worker::wasm_bindgen::JsValue;
+use worker::*;
+
+use crate::breaker::BREAKER_NAMESPACE;
+use crate::drain::StoredEntry;
+
+/// How often the GC alarm sweeps expired entries while TTL entries exist
+pub const GC_INTERVAL: Duration = Duration::from_secs(15 * 60);
+
+/// Key holding GC statistics (kept in its own `__gc` namespace)
+const STATS_KEY: &str = "__gc/stats";
+
+/// Number of keys read per storage list call during a sweep
+const LIST_PAGE_SIZE: usize = 500;
+
+/// Namespaces whose values a key dump shows; the rest may hold upstream
+/// responses or tenant data, so only their JSON type is shown
+const VISIBLE_NAMESPACES: &[&str] = &[BREAKER_NAMESPACE, "credential-failures", "__gc"];
+
+/// Stored wrapper for values that expire
+///
+/// Stateful features store keys as `{namespace}/{id}` (e.g. `session/abc`)
+/// so usage can be reported per namespace.
+#[derive(Serialize, Deserialize)]
+pub struct TtlEntry<T> {
+    /// Expiry as epoch millis
+    pub expires_at: u64,
+    pub value: T,
+}
+
+/// Only the expiry part of a stored value, used while sweeping
+#[derive(Deserialize)]
+struct EntryExpiry {
+    #[serde(default)]
+    expires_at: Option<u64>,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct NamespaceUsage {
+    pub keys: u64,
+    pub approx_bytes: u64,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct GcStats {
+    pub last_gc_at: Option<u64>,
+    pub last_gc_evictions: u64,
+    pub evictions_total: u64,
+    pub sweeps_total: u64,
+}
+
+/// Per-DO storage usage as returned by the admin API
+#[derive(Serialize)]
+pub struct StorageReport {
+    pub keys: u64,
+    pub approx_bytes: u64,
+    pub namespaces: BTreeMap<String, NamespaceUsage>,
+    pub gc: GcStats,
+    pub next_gc_at: Option<i64>,
+}
+
+/// Stores a value that expires after `ttl` and makes sure a GC sweep is scheduled
+pub async fn put_with_ttl<T: Serialize>(state: &State, key: &str, value: T, ttl: Duration) -> Result<()> {
+    let storage = state.storage();
+    let entry = TtlEntry {
+        expires_at: Date::now().as_millis() + ttl.as_millis() as u64,
+        value,
+    };
+    storage.put(key, entry).await?;
+    ensure_gc_alarm(&storage).await
+}
+
+/// Reads a TTL value, treating (and deleting) expired entries as missing
+pub async fn get_live<T: DeserializeOwned>(state: &State, key: &str) -> Result<Option<T>> {
+    let storage = state.storage();
+    match storage.get::<TtlEntry<T>>(key).await? {
+        Some(entry) if entry.expires_at > Date::now().as_millis() => Ok(Some(entry.value)),
+        Some(_) => {
+            storage.delete(key).await?;
+            Ok(None)
+        }
+        None => Ok(None),
+    }
+}
+
+/// Schedules the GC alarm unless one is already pending
+async fn ensure_gc_alarm(storage: &Storage) -> Result<()> {
+    if storage.get_alarm().await?.is_none() {
+        storage.set_alarm(GC_INTERVAL).await?;
+    }
+    Ok(())
+}
+
+/// Result of walking every key in the DO's storage
+struct ScanResult {
+    namespaces: BTreeMap<String, NamespaceUsage>,
+    evicted: u64,
+    ttl_entries_remaining: u64,
+}
+
+/// Walks all stored keys, accumulating usage and optionally evicting expired entries
+async fn scan(storage: &Storage, evict: bool) -> Result<ScanResult> {
+    let now = Date::now().as_millis();
+    let mut result = ScanResult {
+        namespaces: BTreeMap::new(),
+        evicted: 0,
+        ttl_entries_remaining: 0,
+    };
+
+    let mut start = String::new();
+    loop {
+        let page = storage
+            .list_with_options(ListOptions::new().start(&start).limit(LIST_PAGE_SIZE))
+            .await?;
+
+        let mut entries: Vec<(String, JsValue)> = Vec::new();
+        page.for_each(&mut |value, key| {
+            if let Some(key) = key.as_string() {
+                entries.push((key, value));
+            }
+        });
+
+        let page_len = entries.len();
+        for (key, value) in entries.iter() {
+            let expires_at = serde_wasm_bindgen::from_value::<EntryExpiry>(value.clone())
+                .ok()
+                .and_then(|entry| entry.expires_at);
+
+            if let Some(expires_at) = expires_at {
+                if evict && expires_at <= now {
+                    storage.delete(key).await?;
+                    result.evicted += 1;
+                    continue;
+                }
+                result.ttl_entries_remaining += 1;
+            }
+
+            let bytes = js_sys::JSON::stringify(value)
+                .map(|s| String::from(s).len() as u64)
+                .unwrap_or(0);
+            let namespace = key.split('/').next().unwrap_or_default().to_string();
+            let usage = result.namespaces.entry(namespace).or_default();
+            usage.keys += 1;
+            usage.approx_bytes += key.len() as u64 + bytes;
+        }
+
+        match entries.last() {
+            // "\0" makes the inclusive start skip the last key we've already seen
+            Some((last_key, _)) if page_len == LIST_PAGE_SIZE => start = format!("{}\0", last_key),
+            _ => break,
+        }
+    }
+
+    Ok(result)
+}
+
+/// Sweeps expired entries, records GC stats, and re-arms the alarm if needed
+pub async fn run_gc(state: &State) -> Result<StorageReport> {
+    let storage = state.storage();
+    let scan = scan(&storage, true).await?;
+
+    let mut stats = storage.get::<GcStats>(STATS_KEY).await?.unwrap_or_default();
+    stats.last_gc_at = Some(Date::now().as_millis());
+    stats.last_gc_evictions = scan.evicted;
+    stats.evictions_total += scan.evicted;
+    stats.sweeps_total += 1;
+    storage.put(STATS_KEY, &stats).await?;
+
+    if scan.ttl_entries_remaining > 0 {
+        ensure_gc_alarm(&storage).await?;
+    }
+
+    build_report(&storage, scan.namespaces, stats).await
+}
+
+/// Reports storage usage without evicting anything
+pub async fn report(state: &State) -> Result<StorageReport> {
+    let storage = state.storage();
+    let scan = scan(&storage, false).await?;
+    let stats = storage.get::<GcStats>(STATS_KEY).await?.unwrap_or_default();
+    build_report(&storage, scan.namespaces, stats).await
+}
+
+/// Every live entry in the DO's storage, for moving it to another region's shard
+///
+/// Expired entries and the proxy's own bookkeeping (`__gc`) are left out.
+pub async fn export(state: &State) -> Result<Vec<StoredEntry>> {
+    let storage = state.storage();
+    let now = Date::now().as_millis();
+    let mut exported = Vec::new();
+
+    let mut start = String::new();
+    loop {
+        let page = storage
+            .list_with_options(ListOptions::new().start(&start).limit(LIST_PAGE_SIZE))
+            .await?;
+
+        let mut entries: Vec<(String, JsValue)> = Vec::new();
+        page.for_each(&mut |value, key| {
+            if let Some(key) = key.as_string() {
+                entries.push((key, value));
+            }
+        });
+
+        let page_len = entries.len();
+        for (key, value) in entries.iter() {
+            let expired = serde_wasm_bindgen::from_value::<EntryExpiry>(value.clone())
+                .ok()
+                .and_then(|entry| entry.expires_at)
+                .is_some_and(|expires_at| expires_at <= now);
+            if expired || key.starts_with("__") {
+                continue;
+            }
+            // Through JSON, as `scan` measures it, so the value round-trips into `import`
+            let json = js_sys::JSON::stringify(value).map(String::from).unwrap_or_default();
+            match serde_json::from_str(&json) {
+                Ok(value) => exported.push(StoredEntry { key: key.clone(), value }),
+                Err(e) => log_error!("Not exporting {}: {}", key, e),
+            }
+        }
+
+        match entries.last() {
+            Some((last_key, _)) if page_len == LIST_PAGE_SIZE => start = format!("{}\0", last_key),
+            _ => break,
+        }
+    }
+
+    Ok(exported)
+}
+
+/// Stores entries exported from another shard, replacing values with the same key
+///
+/// Returns the number of entries stored. TTL entries keep their expiry, and
+/// arm the GC alarm like any other.
+pub async fn import(state: &State, entries: &[StoredEntry]) -> Result<u64> {
+    let storage = state.storage();
+    let mut has_ttl = false;
+    for entry in entries {
+        let value = js_sys::JSON::parse(&entry.value.to_string())
+            .map_err(|_| Error::RustError(format!("Unparseable value for {}", entry.key)))?;
+        storage.put_raw(&entry.key, value).await?;
+        has_ttl |= entry.value.get("expires_at").is_some();
+    }
+    if has_ttl {
+        ensure_gc_alarm(&storage).await?;
+    }
+    Ok(entries.len() as u64)
+}
+
+/// One stored key as dumped for the admin API
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StoredKey {
+    pub key: String,
+    pub approx_bytes: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<u64>,
+    /// The value, unwrapped from its TTL entry, in visible namespaces
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub value: Option<serde_json::Value>,
+    /// JSON type of the value ("object", "string", ...) in the other namespaces
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub redacted: Option<String>,
+}
+
+impl StoredKey {
+    /// Describes `value` stored under `key`, redacting it unless its namespace is visible
+    pub fn describe(key: &str, mut value: serde_json::Value, approx_bytes: u64) -> StoredKey {
+        let expires_at = value.get("expires_at").and_then(serde_json::Value::as_u64);
+        if expires_at.is_some() {
+            value = value.get_mut("value").map(serde_json::Value::take).unwrap_or_default();
+        }
+        let namespace = key.split('/').next().unwrap_or_default();
+        let (value, redacted) = if VISIBLE_NAMESPACES.contains(&namespace) {
+            (Some(value), None)
+        } else {
+            let kind = match value {
+                serde_json::Value::Null => "null",
+                serde_json::Value::Bool(_) => "boolean",
+                serde_json::Value::Number(_) => "number",
+                serde_json::Value::String(_) => "string",
+                serde_json::Value::Array(_) => "array",
+                serde_json::Value::Object(_) => "object",
+            };
+            (None, Some(kind.to_string()))
+        };
+        StoredKey {
+            key: key.to_string(),
+            approx_bytes,
+            expires_at,
+            value,
+            redacted,
+        }
+    }
+}
+
+/// Up to `limit` keys after `after`, optionally of one namespace, in key order
+pub async fn keys(state: &State, namespace: Option<&str>, after: Option<&str>, limit: usize) -> Result<Vec<StoredKey>> {
+    let prefix = namespace.map(|namespace| format!("{}/", namespace)).unwrap_or_default();
+    // "\0" makes the inclusive start skip the last key of the previous page
+    let start = after.map(|after| format!("{}\0", after)).unwrap_or_default();
+    let page = state
+        .storage()
+        .list_with_options(ListOptions::new().prefix(&prefix).start(&start).limit(limit))
+        .await?;
+
+    let mut keys = Vec::new();
+    page.for_each(&mut |value, key| {
+        if let Some(key) = key.as_string() {
+            let json = js_sys::JSON::stringify(&value).map(String::from).unwrap_or_default();
+            let approx_bytes = (key.len() + json.len()) as u64;
+            keys.push(StoredKey::describe(&key, serde_json::from_str(&json).unwrap_or_default(), approx_bytes));
+        }
+    });
+    keys.sort_by(|a, b| a.key.cmp(&b.key));
+    Ok(keys)
+}
+
+/// Deletes every key of `namespaces`, returning how many were deleted
+///
+/// For incident recovery (e.g. a breaker stuck open); stateful features
+/// start over in a cleared namespace as if the shard were new.
+pub async fn clear(state: &State, namespaces: &[String]) -> Result<u64> {
+    let storage = state.storage();
+    let mut deleted = 0;
+    for namespace in namespaces {
+        let prefix = format!("{}/", namespace);
+        loop {
+            let page = storage
+                .list_with_options(ListOptions::new().prefix(&prefix).limit(LIST_PAGE_SIZE))
+                .await?;
+            let mut keys: Vec<String> = Vec::new();
+            page.for_each(&mut |_, key| {
+                if let Some(key) = key.as_string() {
+                    keys.push(key);
+                }
+            });
+            for key in &keys {
+                storage.delete(key).await?;
+            }
+            deleted += keys.len() as u64;
+            if keys.len() < LIST_PAGE_SIZE {
+                break;
+            }
+        }
+    }
+    Ok(deleted)
+}
+
+async fn build_report(
+    storage: &Storage,
+    namespaces: BTreeMap<String, NamespaceUsage>,
+    gc: GcStats,
+) -> Result<StorageReport> {
+    Ok(StorageReport {
+        keys: namespaces.values().map(|usage| usage.keys).sum(),
+        approx_bytes: namespaces.values().map(|usage| usage.approx_bytes).sum(),
+        namespaces,
+        gc,
+        next_gc_at: storage.get_alarm().await?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_dumped_values_are_redacted_by_namespace() {
+        let breaker = StoredKey::describe("breaker/api.example", json!({"failures": 5, "opened_at": 1}), 60);
+        assert_eq!(breaker.value, Some(json!({"failures": 5, "opened_at": 1})));
+        assert_eq!(breaker.redacted, None);
+
+        let cached = json!({"expires_at": 2_000, "value": {"status": 200, "body": "secret"}});
+        let cached = StoredKey::describe("cache/abc", cached, 90);
+        assert_eq!((cached.expires_at, cached.value), (Some(2_000), None));
+        assert_eq!(cached.redacted.as_deref(), Some("object"));
+    }
+}
@@ -0,0 +1,712 @@
+use serde::{Deserialize, Serialize};
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::future::Future;
+use std::time::Duration;
+use worker::js_sys;
+use worker::*;
+
+use crate::cost::{self, CostEstimate};
+use crate::deadline::Deadline;
+use crate::expiry::Expiry;
+use crate::handlers::ApiResponse;
+use crate::limits::ResponseTooLarge;
+use crate::logger::LogLevel;
+use crate::processors::registry;
+
+/// KV key holding every named retry policy
+pub const RETRY_POLICIES_KEY: &str = "retry-policies";
+
+/// KV key holding the SOAP actions known to be idempotent, by host
+pub const IDEMPOTENT_ACTIONS_KEY: &str = "idempotent-actions";
+
+/// Name reported for a request's own `retries` block; also keys its retry budget
+pub const REQUEST_POLICY_NAME: &str = "request";
+
+/// Upper bound for `max_attempts`, including the first attempt
+pub const MAX_ATTEMPTS: u32 = 10;
+
+/// Retry tokens a policy can save up per DO; also the starting balance
+const MAX_BUDGET_TOKENS: f64 = 10.0;
+
+/// Request header (`low`, `normal` or `high`) deciding how much of the
+/// tenant's retry budget the request's retries may use
+pub const PRIORITY_HEADER: &str = "X-Priority";
+
+/// Header the edge forwards the tenant's retry budget ratio in
+pub const TENANT_BUDGET_HEADER: &str = "X-Tenant-Retry-Budget";
+
+/// A named retry behavior, attached to target hosts and/or tenants
+///
+/// Without an attached policy a request is sent exactly once.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RetryPolicy {
+    /// Upstream statuses that are retried
+    #[serde(default = "default_retry_statuses")]
+    pub retry_on_statuses: Vec<u16>,
+    /// Retry when the upstream can't be reached at all
+    #[serde(default = "default_true")]
+    pub retry_on_transport_errors: bool,
+    /// Total attempts including the first one
+    #[serde(default = "default_max_attempts")]
+    pub max_attempts: u32,
+    #[serde(default)]
+    pub backoff: Backoff,
+    /// Retries allowed per request, on average, per Durable Object (e.g. 0.1);
+    /// unset means unlimited
+    #[serde(default)]
+    pub retry_budget: Option<f64>,
+    /// No retry starts once this much time has passed since the first attempt
+    /// (or would pass during its backoff); unset means no cap
+    #[serde(default)]
+    pub max_elapsed_ms: Option<u64>,
+    /// Also retry requests that aren't idempotent (POST, PATCH, unregistered
+    /// SOAP actions), which may then take effect twice
+    #[serde(default)]
+    pub retry_non_idempotent: bool,
+    /// Target hosts using this policy; wins over a tenant attachment
+    #[serde(default)]
+    pub hosts: Vec<String>,
+    /// Tenant ids using this policy for hosts without their own
+    #[serde(default)]
+    pub tenants: Vec<String>,
+}
+
+fn default_retry_statuses() -> Vec<u16> {
+    vec![502, 503, 504]
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_max_attempts() -> u32 {
+    3
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BackoffCurve {
+    Constant,
+    Linear,
+    #[default]
+    Exponential,
+}
+
+/// Delay before each retry
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Backoff {
+    #[serde(default)]
+    pub curve: BackoffCurve,
+    #[serde(default = "default_base_ms")]
+    pub base_ms: u64,
+    #[serde(default = "default_max_ms")]
+    pub max_ms: u64,
+    /// Randomize each delay between half and all of its value, so callers
+    /// that failed together don't retry together
+    #[serde(default = "default_true")]
+    pub jitter: bool,
+}
+
+fn default_base_ms() -> u64 {
+    200
+}
+
+fn default_max_ms() -> u64 {
+    5_000
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff {
+            curve: BackoffCurve::default(),
+            base_ms: default_base_ms(),
+            max_ms: default_max_ms(),
+            jitter: true,
+        }
+    }
+}
+
+impl Backoff {
+    /// Delay before retry number `retry` (1 for the first retry)
+    pub fn delay(&self, retry: u32) -> Duration {
+        let ms = match self.curve {
+            BackoffCurve::Constant => self.base_ms,
+            BackoffCurve::Linear => self.base_ms.saturating_mul(retry as u64),
+            BackoffCurve::Exponential => self.base_ms.saturating_mul(1u64 << retry.saturating_sub(1).min(32)),
+        };
+        Duration::from_millis(ms.min(self.max_ms))
+    }
+
+    /// `delay` with jitter applied when enabled
+    fn jittered(&self, retry: u32) -> Duration {
+        let delay = self.delay(retry);
+        if !self.jitter {
+            return delay;
+        }
+        delay.mul_f64(0.5 + js_sys::Math::random() / 2.0)
+    }
+}
+
+impl RetryPolicy {
+    /// Checks limits; returns a message describing the first problem
+    pub fn validate(&self) -> std::result::Result<(), String> {
+        if self.max_attempts == 0 || self.max_attempts > MAX_ATTEMPTS {
+            return Err(format!("max_attempts must be 1-{}", MAX_ATTEMPTS));
+        }
+        if let Some(status) = self.retry_on_statuses.iter().find(|s| !(100..=599).contains(*s)) {
+            return Err(format!("Invalid status in retry_on_statuses: {}", status));
+        }
+        if self.backoff.max_ms < self.backoff.base_ms {
+            return Err("backoff.max_ms must be at least backoff.base_ms".to_string());
+        }
+        if self.max_elapsed_ms == Some(0) {
+            return Err("max_elapsed_ms must be positive".to_string());
+        }
+        if let Some(budget) = self.retry_budget {
+            if budget <= 0.0 || budget > 1.0 {
+                return Err("retry_budget must be in (0, 1]".to_string());
+            }
+        }
+        Ok(())
+    }
+
+    /// Validates a request's own `retries` block, which can't attach itself
+    /// to hosts or tenants
+    pub fn validate_inline(&self) -> std::result::Result<(), String> {
+        if !self.hosts.is_empty() || !self.tenants.is_empty() {
+            return Err("hosts and tenants are only allowed in admin retry policies".to_string());
+        }
+        self.validate()
+    }
+
+    fn should_retry(&self, result: &anyhow::Result<ApiResponse>) -> bool {
+        match result {
+            Ok(response) => self.retry_on_statuses.contains(&response.status()),
+            // The same upstream answers with the same oversized body again
+            Err(e) if e.is::<ResponseTooLarge>() => false,
+            Err(_) => self.retry_on_transport_errors,
+        }
+    }
+}
+
+/// Policy names are used in URLs and response metadata
+pub fn is_valid_policy_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 64
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+}
+
+/// All named policies, stored under a single CONFIG key
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct RetryPolicies(pub HashMap<String, RetryPolicy>);
+
+/// Why a policy was chosen
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MatchedBy {
+    Host,
+    Tenant,
+    /// The request's own `retries` block
+    Request,
+}
+
+impl RetryPolicies {
+    /// Loads policies from the CONFIG KV namespace; unreadable config means no retries
+    pub async fn load(env: &Env) -> RetryPolicies {
+        let kv = match env.kv("CONFIG") {
+            Ok(kv) => kv,
+            Err(_) => return RetryPolicies::default(),
+        };
+        match kv.get(RETRY_POLICIES_KEY).cache_ttl(60).json::<RetryPolicies>().await {
+            Ok(policies) => policies.unwrap_or_default(),
+            Err(e) => {
+                log_info!("Failed to load retry policies, not retrying: {}", e);
+                RetryPolicies::default()
+            }
+        }
+    }
+
+    /// Reads policies bypassing the edge cache, for admin read-modify-write
+    pub async fn load_fresh(env: &Env) -> Result<RetryPolicies> {
+        Ok(env
+            .kv("CONFIG")?
+            .get(RETRY_POLICIES_KEY)
+            .json::<RetryPolicies>()
+            .await?
+            .unwrap_or_default())
+    }
+
+    pub async fn save(&self, env: &Env) -> Result<()> {
+        env.kv("CONFIG")?
+            .put(RETRY_POLICIES_KEY, serde_json::to_string(self)?)?
+            .execute()
+            .await?;
+        Ok(())
+    }
+
+    /// Policy for a request: the target host's, then the request's own
+    /// `retries` block, then the tenant's
+    ///
+    /// The host's policy wins so whoever configured it keeps control over how
+    /// hard that upstream may be retried.
+    pub fn select<'a>(
+        &'a self,
+        host: &str,
+        tenant: &str,
+        request: Option<&'a RetryPolicy>,
+    ) -> Option<(&'a str, &'a RetryPolicy, MatchedBy)> {
+        let by_host = self
+            .0
+            .iter()
+            .find(|(_, policy)| policy.hosts.iter().any(|h| h.eq_ignore_ascii_case(host)))
+            .map(|(name, policy)| (name.as_str(), policy, MatchedBy::Host));
+        by_host
+            .or_else(|| request.map(|policy| (REQUEST_POLICY_NAME, policy, MatchedBy::Request)))
+            .or_else(|| {
+                self.0
+                    .iter()
+                    .find(|(_, policy)| policy.tenants.iter().any(|t| t == tenant))
+                    .map(|(name, policy)| (name.as_str(), policy, MatchedBy::Tenant))
+            })
+    }
+}
+
+/// SOAP actions that are safe to send twice, by upstream host
+///
+/// SOAP calls are all POSTs, so only actions listed here are retried under
+/// a policy without `retry_non_idempotent`.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct IdempotentActions(pub HashMap<String, Vec<String>>);
+
+impl IdempotentActions {
+    /// Loads the registry from the CONFIG KV namespace; unreadable config
+    /// means no action is retried
+    pub async fn load(env: &Env) -> IdempotentActions {
+        let kv = match env.kv("CONFIG") {
+            Ok(kv) => kv,
+            Err(_) => return IdempotentActions::default(),
+        };
+        match kv.get(IDEMPOTENT_ACTIONS_KEY).cache_ttl(60).json::<IdempotentActions>().await {
+            Ok(actions) => actions.unwrap_or_default(),
+            Err(e) => {
+                log_info!("Failed to load idempotent actions, retrying none: {}", e);
+                IdempotentActions::default()
+            }
+        }
+    }
+
+    /// Reads the registry bypassing the edge cache, for admin read-modify-write
+    pub async fn load_fresh(env: &Env) -> Result<IdempotentActions> {
+        Ok(env
+            .kv("CONFIG")?
+            .get(IDEMPOTENT_ACTIONS_KEY)
+            .json::<IdempotentActions>()
+            .await?
+            .unwrap_or_default())
+    }
+
+    pub async fn save(&self, env: &Env) -> Result<()> {
+        env.kv("CONFIG")?
+            .put(IDEMPOTENT_ACTIONS_KEY, serde_json::to_string(self)?)?
+            .execute()
+            .await?;
+        Ok(())
+    }
+
+    /// Whether `action` is registered for `host`; hosts match case-insensitively, actions exactly
+    pub fn contains(&self, host: &str, action: &str) -> bool {
+        self.0
+            .iter()
+            .filter(|(registered, _)| registered.eq_ignore_ascii_case(host))
+            .any(|(_, actions)| actions.iter().any(|a| a == action))
+    }
+}
+
+/// Retry allowance of one policy within one DO
+///
+/// Every request adds `retry_budget` tokens and every retry spends one, so
+/// retries can't multiply load on an upstream that's already failing.
+#[derive(Debug, Clone, Copy)]
+pub struct RetryBudget {
+    tokens: f64,
+}
+
+impl Default for RetryBudget {
+    fn default() -> Self {
+        RetryBudget { tokens: MAX_BUDGET_TOKENS }
+    }
+}
+
+impl RetryBudget {
+    pub fn deposit(&mut self, ratio: f64) {
+        self.tokens = (self.tokens + ratio).min(MAX_BUDGET_TOKENS);
+    }
+
+    pub fn withdraw(&mut self) -> bool {
+        self.withdraw_keeping(0.0)
+    }
+
+    /// Withdraws a token unless that would leave less than `reserve` (a share
+    /// of a full budget) behind
+    pub fn withdraw_keeping(&mut self, reserve: f64) -> bool {
+        if self.tokens >= 1.0 + reserve * MAX_BUDGET_TOKENS {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn tokens(&self) -> f64 {
+        self.tokens
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Priority {
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+impl Priority {
+    pub fn parse(value: &str) -> Option<Priority> {
+        match value.to_lowercase().as_str() {
+            "low" => Some(Priority::Low),
+            "normal" => Some(Priority::Normal),
+            "high" => Some(Priority::High),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Priority::Low => "low",
+            Priority::Normal => "normal",
+            Priority::High => "high",
+        }
+    }
+
+    /// Share of a full tenant budget this priority's retries leave for higher ones
+    fn reserve(&self) -> f64 {
+        match self {
+            Priority::Low => 0.5,
+            Priority::Normal => 0.2,
+            Priority::High => 0.0,
+        }
+    }
+}
+
+/// A tenant's retry budget, shared by all shards of a region
+///
+/// `ratio` caps the extra load retries may add (0.2 = at most one retry per
+/// five requests, on average), whichever policy the retries come from.
+#[derive(Debug, Clone, Copy)]
+pub struct TenantBudget {
+    pub ratio: f64,
+    pub priority: Priority,
+}
+
+/// A deposit or withdrawal on a tenant budget in the shard registry
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TenantBudgetCall {
+    pub tenant: String,
+    pub ratio: f64,
+    /// `None` deposits for a new request; `Some` withdraws for a retry
+    pub withdraw: Option<Priority>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TenantBudgetAnswer {
+    pub allowed: bool,
+    pub remaining: f64,
+}
+
+impl TenantBudgetCall {
+    /// Applies the call to the tenant's budget (in the shard registry)
+    pub fn apply(&self, budget: &mut RetryBudget) -> TenantBudgetAnswer {
+        let allowed = match self.withdraw {
+            None => {
+                budget.deposit(self.ratio);
+                true
+            }
+            Some(priority) => budget.withdraw_keeping(priority.reserve()),
+        };
+        TenantBudgetAnswer {
+            allowed,
+            remaining: budget.tokens(),
+        }
+    }
+}
+
+/// A tenant's shared budget as used by one request
+pub struct SharedBudget<'a> {
+    pub env: &'a Env,
+    pub region_code: String,
+    pub tenant: &'a str,
+    pub budget: TenantBudget,
+    /// The request's cost; every registry call adds a DO request
+    pub cost: &'a Cell<CostEstimate>,
+}
+
+impl SharedBudget<'_> {
+    async fn call(&self, withdraw: Option<Priority>) -> Option<TenantBudgetAnswer> {
+        let call = TenantBudgetCall {
+            tenant: self.tenant.to_string(),
+            ratio: self.budget.ratio,
+            withdraw,
+        };
+        let answer = registry::tenant_retry_budget(self.env, &self.region_code, &call).await?;
+        cost::add(
+            self.cost,
+            CostEstimate {
+                do_requests: 1,
+                ..CostEstimate::default()
+            },
+        );
+        Some(answer)
+    }
+
+    fn usage(&self, answer: &TenantBudgetAnswer) -> TenantBudgetUsage {
+        TenantBudgetUsage {
+            // Whole retries left, as the budget is spent one token per retry
+            remaining: answer.remaining.floor() as u32,
+            priority: self.budget.priority,
+        }
+    }
+}
+
+/// The tenant's shared retry budget as reported in response metadata
+#[derive(Debug, Clone, Serialize)]
+pub struct TenantBudgetUsage {
+    /// Retries the region has left for the tenant (before priority reserves)
+    pub remaining: u32,
+    pub priority: Priority,
+}
+
+/// Per-DO budgets by policy name, kept in memory for the life of the instance
+pub type RetryBudgets = RefCell<HashMap<String, RetryBudget>>;
+
+/// Which retry policy a request used, reported in response metadata
+#[derive(Debug, Clone, Serialize)]
+pub struct RetryUsage {
+    pub policy: String,
+    pub matched_by: MatchedBy,
+    pub attempts: u32,
+    pub max_attempts: u32,
+    /// True when a retry was skipped because the policy's or tenant's budget was spent
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub budget_exhausted: bool,
+    /// True when a retry was skipped because the request isn't idempotent
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub non_idempotent: bool,
+    /// True when a retry was dropped because it would have been sent past `X-Expires-At`
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub expired: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tenant_budget: Option<TenantBudgetUsage>,
+    /// Attempt IDs in the order they were sent, e.g. `["req-abc#1", "req-abc#2"]`
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub attempt_ids: Vec<String>,
+}
+
+/// Runs `attempt` under the selected policy (or once without one)
+///
+/// Each attempt gets the time left before the deadline as its timeout; a
+/// retry whose backoff would outlast the deadline or the policy's
+/// `max_elapsed_ms` isn't started, nor one that would be sent past the
+/// request's expiry. A request that isn't `idempotent` is
+/// only retried when the policy sets `retry_non_idempotent`.
+#[allow(clippy::too_many_arguments)]
+pub async fn run<F, Fut>(
+    selected: Option<(&str, &RetryPolicy, MatchedBy)>,
+    idempotent: bool,
+    budgets: &RetryBudgets,
+    shared: Option<&SharedBudget<'_>>,
+    deadline: Option<Deadline>,
+    expiry: Option<Expiry>,
+    log_level: LogLevel,
+    attempt: F,
+) -> (anyhow::Result<ApiResponse>, Option<RetryUsage>)
+where
+    F: Fn(Option<Duration>) -> Fut,
+    Fut: Future<Output = anyhow::Result<ApiResponse>>,
+{
+    let remaining = || deadline.and_then(|deadline| deadline.check().ok());
+
+    let (name, policy, matched_by) = match selected {
+        Some(selected) => selected,
+        None => return (attempt(remaining()).await, None),
+    };
+
+    if let Some(ratio) = policy.retry_budget {
+        budgets.borrow_mut().entry(name.to_string()).or_default().deposit(ratio);
+    }
+
+    let mut usage = RetryUsage {
+        policy: name.to_string(),
+        matched_by,
+        attempts: 1,
+        max_attempts: policy.max_attempts,
+        budget_exhausted: false,
+        non_idempotent: false,
+        expired: false,
+        tenant_budget: None,
+        attempt_ids: Vec::new(),
+    };
+    if let Some(shared) = shared {
+        usage.tenant_budget = shared.call(None).await.map(|answer| shared.usage(&answer));
+    }
+    let started_at = Date::now().as_millis();
+    let mut result = attempt(remaining()).await;
+
+    while usage.attempts < policy.max_attempts && policy.should_retry(&result) {
+        // The failed attempt may still have taken effect (e.g. placed an order)
+        if !idempotent && !policy.retry_non_idempotent {
+            log_info!("Not retrying: request isn't idempotent and policy {} doesn't allow it", name);
+            usage.non_idempotent = true;
+            break;
+        }
+        let delay = policy.backoff.jittered(usage.attempts);
+        if deadline.is_some() && remaining().is_none_or(|left| left <= delay) {
+            log_debug!(log_level, "Not retrying: backoff would pass the deadline");
+            break;
+        }
+        if expiry.is_some_and(|expiry| expiry.is_past_at(Date::now().as_millis() + delay.as_millis() as u64)) {
+            log_info!("Not retrying: the request would be sent past its expiry");
+            usage.expired = true;
+            break;
+        }
+        let elapsed = Date::now().as_millis().saturating_sub(started_at);
+        if policy.max_elapsed_ms.is_some_and(|max| elapsed + delay.as_millis() as u64 > max) {
+            log_debug!(log_level, "Not retrying: backoff would pass max_elapsed_ms");
+            break;
+        }
+        if policy.retry_budget.is_some() && !budgets.borrow_mut().entry(name.to_string()).or_default().withdraw() {
+            log_info!("Retry budget of policy {} exhausted", name);
+            usage.budget_exhausted = true;
+            break;
+        }
+        // An unreachable registry doesn't stop retries; the policy budget still applies
+        if let Some(shared) = shared {
+            if let Some(answer) = shared.call(Some(shared.budget.priority)).await {
+                usage.tenant_budget = Some(shared.usage(&answer));
+                if !answer.allowed {
+                    log_info!("Retry budget of tenant {} exhausted", shared.tenant);
+                    usage.budget_exhausted = true;
+                    break;
+                }
+            }
+        }
+
+        log_info!(
+            "Retrying (attempt {}/{}, policy {}) in {}ms",
+            usage.attempts + 1,
+            policy.max_attempts,
+            name,
+            delay.as_millis()
+        );
+        if !delay.is_zero() {
+            Delay::from(delay).await;
+        }
+        usage.attempts += 1;
+        result = attempt(remaining()).await;
+    }
+
+    (result, Some(usage))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff_curves_are_capped() {
+        let mut backoff = Backoff {
+            curve: BackoffCurve::Exponential,
+            base_ms: 100,
+            max_ms: 1_000,
+            jitter: false,
+        };
+        let delays: Vec<u128> = (1..=5).map(|retry| backoff.delay(retry).as_millis()).collect();
+        assert_eq!(delays, vec![100, 200, 400, 800, 1_000]);
+
+        backoff.curve = BackoffCurve::Linear;
+        assert_eq!(backoff.delay(3).as_millis(), 300);
+        backoff.curve = BackoffCurve::Constant;
+        assert_eq!(backoff.delay(3).as_millis(), 100);
+    }
+
+    #[test]
+    fn test_host_policy_wins_over_tenant_policy() {
+        let policies: RetryPolicies = serde_json::from_str(
+            r#"{
+                "carrier": { "hosts": ["api.carrier.example"] },
+                "gentle": { "tenants": ["billing"], "max_attempts": 2 }
+            }"#,
+        )
+        .unwrap();
+
+        let (name, _, matched_by) = policies.select("API.carrier.example", "billing", None).unwrap();
+        assert_eq!((name, matched_by), ("carrier", MatchedBy::Host));
+        let (name, policy, matched_by) = policies.select("other.example", "billing", None).unwrap();
+        assert_eq!((name, policy.max_attempts, matched_by), ("gentle", 2, MatchedBy::Tenant));
+        assert!(policies.select("other.example", "root", None).is_none());
+
+        // A request's own block sits between the host and tenant policies
+        let own: RetryPolicy = serde_json::from_str(r#"{"max_attempts": 5}"#).unwrap();
+        let (name, _, matched_by) = policies.select("API.carrier.example", "billing", Some(&own)).unwrap();
+        assert_eq!((name, matched_by), ("carrier", MatchedBy::Host));
+        let (name, policy, matched_by) = policies.select("other.example", "billing", Some(&own)).unwrap();
+        assert_eq!((name, policy.max_attempts, matched_by), (REQUEST_POLICY_NAME, 5, MatchedBy::Request));
+    }
+
+    #[test]
+    fn test_retry_budget_limits_retries() {
+        let mut budget = RetryBudget { tokens: 1.0 };
+        assert!(budget.withdraw());
+        assert!(!budget.withdraw());
+        for _ in 0..4 {
+            budget.deposit(0.25);
+        }
+        assert!(budget.withdraw());
+
+        // Lower priorities leave part of a tenant's budget to higher ones
+        let mut budget = RetryBudget::default();
+        let withdraw = |priority| TenantBudgetCall {
+            tenant: "billing".to_string(),
+            ratio: 0.2,
+            withdraw: Some(priority),
+        };
+        let mut low = 0;
+        while withdraw(Priority::Low).apply(&mut budget).allowed {
+            low += 1;
+        }
+        assert_eq!(low, 5);
+        for _ in 0..3 {
+            assert!(withdraw(Priority::Normal).apply(&mut budget).allowed);
+        }
+        assert!(!withdraw(Priority::Normal).apply(&mut budget).allowed);
+        assert!(withdraw(Priority::High).apply(&mut budget).allowed);
+        assert!(withdraw(Priority::High).apply(&mut budget).allowed);
+        let answer = withdraw(Priority::High).apply(&mut budget);
+        assert!(!answer.allowed && answer.remaining < 1.0);
+    }
+
+    #[test]
+    fn test_idempotent_actions_by_host() {
+        let actions: IdempotentActions =
+            serde_json::from_str(r#"{ "soap.carrier.example": ["GetRates", "TrackShipment"] }"#).unwrap();
+        assert!(actions.contains("SOAP.carrier.example", "GetRates"));
+        assert!(!actions.contains("soap.carrier.example", "CreateShipment"));
+        assert!(!actions.contains("other.example", "GetRates"));
+
+        let policy: RetryPolicy = serde_json::from_str("{}").unwrap();
+        assert!(!policy.retry_non_idempotent);
+    }
+}
@@ -0,0 +1,320 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use worker::*;
+
+use crate::canonical_url;
+
+/// Number of Durable Object shards per region unless configured
+pub const DEFAULT_POOL_SIZE: u32 = 10;
+
+/// Largest number of shards a region can be configured with
+pub const MAX_POOL_SIZE: u32 = 100;
+
+/// Weight a shard gets when no explicit weight is configured
+pub const DEFAULT_SHARD_WEIGHT: u32 = 100;
+
+/// Region codes of the compiled-in processors, in the order they are listed in wrangler.toml
+pub const REGION_CODES: &[&str] = &[
+    #[cfg(feature = "region-wnam")]
+    "wnam",
+    #[cfg(feature = "region-enam")]
+    "enam",
+    #[cfg(feature = "region-weur")]
+    "weur",
+    #[cfg(feature = "region-eeur")]
+    "eeur",
+    #[cfg(feature = "region-apac")]
+    "apac",
+    #[cfg(feature = "region-oc")]
+    "oc",
+    #[cfg(feature = "region-af")]
+    "af",
+    #[cfg(feature = "region-me")]
+    "me",
+];
+
+#[cfg(not(any(
+    feature = "region-wnam",
+    feature = "region-enam",
+    feature = "region-weur",
+    feature = "region-eeur",
+    feature = "region-apac",
+    feature = "region-oc",
+    feature = "region-af",
+    feature = "region-me",
+)))]
+compile_error!("enable at least one region-* feature (or a region group such as north-america)");
+
+/// Request header whose value picks the shard instead of the body
+pub const ROUTING_KEY_HEADER: &str = "X-Routing-Key";
+
+/// Set by the edge on requests whose body it forwarded without reading
+pub const BODY_UNREAD_HEADER: &str = "X-Body-Unread";
+
+/// Longest accepted `X-Routing-Key`
+pub const MAX_ROUTING_KEY_LEN: usize = 256;
+
+/// Region used when neither the request nor the tenant picks one
+pub const DEFAULT_REGION: &str = REGION_CODES[0];
+
+/// A valid pool size, 1 to `MAX_POOL_SIZE` (see `Config::pool_size`)
+pub fn parse_pool_size(value: &str) -> Option<u32> {
+    value.trim().parse().ok().filter(|size| (1..=MAX_POOL_SIZE).contains(size))
+}
+
+/// KV key holding the configured shard weights
+pub const SHARD_WEIGHTS_KEY: &str = "shard-weights";
+
+/// Per-shard routing weights keyed by shard name (e.g. "weur-7")
+///
+/// Shards without an entry use `DEFAULT_SHARD_WEIGHT`. A weight of 0 drains
+/// the shard: no new requests are routed to it.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct ShardWeights(HashMap<String, u32>);
+
+impl ShardWeights {
+    /// Loads shard weights from the CONFIG KV namespace
+    ///
+    /// Falls back to default weights if the namespace isn't bound or the
+    /// value can't be read, so routing never fails because of missing config.
+    pub async fn load(env: &Env) -> ShardWeights {
+        let kv = match env.kv("CONFIG") {
+            Ok(kv) => kv,
+            Err(_) => return ShardWeights::default(),
+        };
+
+        match kv.get(SHARD_WEIGHTS_KEY).cache_ttl(60).json::<ShardWeights>().await {
+            Ok(weights) => weights.unwrap_or_default(),
+            Err(e) => {
+                log_error!("Failed to load shard weights, using defaults: {}", e);
+                ShardWeights::default()
+            }
+        }
+    }
+
+    /// Persists shard weights to the CONFIG KV namespace
+    pub async fn save(&self, env: &Env) -> Result<()> {
+        let kv = env.kv("CONFIG")?;
+        kv.put(SHARD_WEIGHTS_KEY, serde_json::to_string(self)?)?
+            .execute()
+            .await?;
+        Ok(())
+    }
+
+    /// Returns the effective weight for a shard
+    pub fn weight(&self, region_code: &str, index: u32) -> u32 {
+        self.0
+            .get(&shard_name(region_code, index))
+            .copied()
+            .unwrap_or(DEFAULT_SHARD_WEIGHT)
+    }
+
+    /// Sets the weight for a shard, validating its name first
+    pub fn set(&mut self, shard: &str, weight: u32) -> std::result::Result<(), String> {
+        parse_shard_name(shard).ok_or_else(|| format!("Unknown shard: {}", shard))?;
+        if weight == DEFAULT_SHARD_WEIGHT {
+            self.0.remove(shard);
+        } else {
+            self.0.insert(shard.to_string(), weight);
+        }
+        Ok(())
+    }
+
+    /// Returns true if at least one shard in the region's pool can receive traffic
+    pub fn has_active_shard(&self, region_code: &str, pool_size: u32) -> bool {
+        (0..pool_size).any(|index| self.weight(region_code, index) > 0)
+    }
+
+    /// Effective weights for every shard in a region's pool, keyed by shard name
+    pub fn region_view(&self, region_code: &str, pool_size: u32) -> BTreeMap<String, u32> {
+        (0..pool_size)
+            .map(|index| (shard_name(region_code, index), self.weight(region_code, index)))
+            .collect()
+    }
+
+    /// Selects a shard index for a routing key (the request body unless the
+    /// caller sent `X-Routing-Key`) using weighted rendezvous hashing
+    ///
+    /// Each shard scores `weight / -ln(u)` where `u` is a per-shard hash of
+    /// the key, and the highest score wins. Unlike `hash % n`, changing one
+    /// shard's weight only moves traffic to or from that shard, so draining a
+    /// shard doesn't reshuffle the rest of the region. Likewise growing the
+    /// pool only moves the requests the new shards win.
+    pub fn select_shard(&self, region_code: &str, pool_size: u32, key: &str) -> u32 {
+        let key_hash = seahash::hash(key.as_bytes());
+        let weighted = self.has_active_shard(region_code, pool_size);
+
+        let mut best_index = 0;
+        let mut best_score = f64::MIN;
+        for index in 0..pool_size {
+            // If every shard is drained, ignore weights rather than dropping traffic
+            let weight = if weighted { self.weight(region_code, index) } else { DEFAULT_SHARD_WEIGHT };
+            if weight == 0 {
+                continue;
+            }
+
+            let shard_hash = seahash::hash(format!("{}:{}", key_hash, index).as_bytes());
+            // Map the top 53 bits to (0, 1) so ln() is finite and negative
+            let unit = ((shard_hash >> 11) as f64 + 1.0) / ((1u64 << 53) as f64 + 2.0);
+            let score = weight as f64 / -unit.ln();
+
+            if score > best_score {
+                best_score = score;
+                best_index = index;
+            }
+        }
+
+        best_index
+    }
+}
+
+/// A usable `X-Routing-Key` value, trimmed
+pub fn parse_routing_key(value: &str) -> Option<&str> {
+    let key = value.trim();
+    (!key.is_empty() && key.len() <= MAX_ROUTING_KEY_LEN).then_some(key)
+}
+
+/// Returns the Durable Object stub for a shard name (e.g. "weur-7")
+///
+/// Uses the same namespace binding, DO name, and location hint as request routing,
+/// so admin operations reach the exact instance that serves traffic.
+pub fn shard_stub(env: &Env, shard: &str) -> Result<Stub> {
+    let (region_code, index) = parse_shard_name(shard)
+        .ok_or_else(|| Error::RustError(format!("Unknown shard: {}", shard)))?;
+    let namespace = env.durable_object(&format!("{}_PROCESSOR", region_code.to_uppercase()))?;
+    namespace.get_by_name_with_location_hint(&format!("{}-processor-{}", region_code, index), region_code)
+}
+
+/// Formats a shard name, e.g. `shard_name("weur", 7)` -> "weur-7"
+pub fn shard_name(region_code: &str, index: u32) -> String {
+    format!("{}-{}", region_code, index)
+}
+
+/// Parses a shard name into its region code and index
+///
+/// Any index below `MAX_POOL_SIZE` is valid, so shards left outside a pool
+/// that shrank can still be inspected and drained.
+pub fn parse_shard_name(shard: &str) -> Option<(&'static str, u32)> {
+    let (region, index) = shard.rsplit_once('-')?;
+    let region = REGION_CODES.iter().find(|code| **code == region)?;
+    let index = index.parse::<u32>().ok()?;
+    (index < MAX_POOL_SIZE).then_some((*region, index))
+}
+
+/// Hosts of every upstream request in a proxy request body
+///
+/// Same entries as `target_urls`; `None` also where the URL has no host.
+pub fn target_hosts(body: &str) -> Vec<Option<String>> {
+    target_urls(body)
+        .into_iter()
+        .map(|url| url.and_then(|url| url.host_str().map(str::to_lowercase)))
+        .collect()
+}
+
+/// URLs of every upstream request in a proxy request body, in canonical form
+///
+/// Covers the `url` of HTTP and SOAP payloads and of each saga step and
+/// compensation. Entries are `None` where the URL is missing or invalid.
+pub fn target_urls(body: &str) -> Vec<Option<Url>> {
+    let value = match serde_json::from_str::<serde_json::Value>(body) {
+        Ok(value) => value,
+        Err(_) => return vec![None],
+    };
+
+    let requests: Vec<&serde_json::Value> = match value.get("steps").and_then(|steps| steps.as_array()) {
+        Some(steps) => steps
+            .iter()
+            .flat_map(|step| {
+                let compensation = step.get("compensation").and_then(|c| c.get("request"));
+                step.get("request").into_iter().chain(compensation)
+            })
+            .collect(),
+        None => vec![&value],
+    };
+
+    requests
+        .into_iter()
+        .map(|request| {
+            request
+                .get("url")
+                .and_then(|url| url.as_str())
+                .and_then(canonical_url::parse)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_drained_shard_is_never_selected() {
+        let mut weights = ShardWeights::default();
+        weights.set("weur-7", 0).unwrap();
+
+        for i in 0..500 {
+            let body = format!("{{\"n\":{}}}", i);
+            assert_ne!(weights.select_shard("weur", DEFAULT_POOL_SIZE, &body), 7);
+        }
+    }
+
+    #[test]
+    fn test_draining_only_moves_drained_traffic() {
+        let defaults = ShardWeights::default();
+        let mut drained = ShardWeights::default();
+        drained.set("weur-3", 0).unwrap();
+
+        for i in 0..500 {
+            let body = format!("{{\"n\":{}}}", i);
+            let before = defaults.select_shard("weur", DEFAULT_POOL_SIZE, &body);
+            if before != 3 {
+                assert_eq!(drained.select_shard("weur", DEFAULT_POOL_SIZE, &body), before);
+            }
+        }
+    }
+
+    #[test]
+    fn test_resizing_the_pool() {
+        let weights = ShardWeights::default();
+        for i in 0..500 {
+            let body = format!("{{\"n\":{}}}", i);
+            assert_eq!(weights.select_shard("weur", 1, &body), 0);
+
+            // Growing the pool only moves requests to the new shards
+            let before = weights.select_shard("weur", DEFAULT_POOL_SIZE, &body);
+            let after = weights.select_shard("weur", 50, &body);
+            assert!(after == before || after >= DEFAULT_POOL_SIZE);
+        }
+
+        assert_eq!(parse_pool_size(" 50 "), Some(50));
+        assert_eq!(parse_pool_size("0"), None);
+        assert_eq!(parse_pool_size("101"), None);
+    }
+
+    #[test]
+    fn test_parse_shard_name() {
+        assert_eq!(parse_shard_name("weur-7"), Some(("weur", 7)));
+        assert_eq!(parse_shard_name("weur-42"), Some(("weur", 42)));
+        assert_eq!(parse_shard_name("weur-100"), None);
+        assert_eq!(parse_shard_name("mars-1"), None);
+
+        assert_eq!(parse_routing_key(" order-42 "), Some("order-42"));
+        assert_eq!(parse_routing_key("  "), None);
+        assert_eq!(parse_routing_key(&"k".repeat(MAX_ROUTING_KEY_LEN + 1)), None);
+    }
+
+    #[test]
+    fn test_target_hosts_cover_saga_steps() {
+        assert_eq!(target_hosts(r#"{"url":"https://API.example.com/x"}"#), vec![Some("api.example.com".to_string())]);
+
+        let saga = r#"{"steps":[
+            {"name":"reserve","request":{"url":"https://a.example"},"compensation":{"request":{"url":"https://b.example"}}},
+            {"name":"confirm","request":{"url":"{{reserve.body.next}}"}}
+        ]}"#;
+        assert_eq!(
+            target_hosts(saga),
+            vec![Some("a.example".to_string()), Some("b.example".to_string()), None]
+        );
+    }
+}
@@ -0,0 +1,201 @@
+use serde::Serialize;
+use serde_json::Value;
+use worker::*;
+
+use crate::error::ProxyError;
+use crate::handlers::response::STREAM_HEADER;
+use crate::logger::LogLevel;
+use crate::routing::{self, ShardWeights, DEFAULT_SHARD_WEIGHT};
+
+/// How the edge routed a request, returned as `routing_trace` in debug mode
+///
+/// Records every decision on the way to a shard, so "why did this go to
+/// enam-4?" can be answered from the response: the region candidates and the
+/// one that won, the policy checks, the shard hash inputs, the location hint,
+/// and each fallback taken.
+#[derive(Debug, Default, Serialize)]
+pub struct RoutingTrace {
+    pub region: RegionTrace,
+    /// Policy checks in the order they ran
+    pub policy: Vec<PolicyCheck>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub shard: Option<ShardTrace>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub durable_object: Option<DurableObjectTrace>,
+    /// Defaults and overrides that changed the usual outcome
+    pub fallbacks: Vec<String>,
+}
+
+/// Region candidates, highest precedence first, and the one used
+#[derive(Debug, Default, Serialize)]
+pub struct RegionTrace {
+    /// Region of the first tenant routing rule matching the body
+    pub routing_rule: Option<String>,
+    /// `X-CF-Region` as sent
+    pub header: Option<String>,
+    pub tenant_default: Option<String>,
+    /// Compiled-in region closest to the caller
+    pub geo: Option<String>,
+    /// Which candidate won: routing_rule, header, tenant_default, geo or default
+    pub source: &'static str,
+    /// The winning candidate as given, before it's matched to a region code
+    pub candidate: String,
+    /// Region code the request was sent to
+    pub selected: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PolicyCheck {
+    pub check: &'static str,
+    /// "passed", or why the request was refused
+    pub outcome: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ShardTrace {
+    /// What was hashed: "routing_key" or "body"
+    pub key_source: &'static str,
+    pub key_bytes: usize,
+    /// seahash of the key, in hex
+    pub key_hash: String,
+    pub pool_size: u32,
+    /// Weight the chosen shard was scored with
+    pub weight: u32,
+    pub index: u32,
+    pub shard: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DurableObjectTrace {
+    pub namespace: &'static str,
+    pub name: String,
+    pub location_hint: &'static str,
+    pub eu_jurisdiction: bool,
+}
+
+impl RegionTrace {
+    /// The first candidate set, in precedence order, else `routing::DEFAULT_REGION`
+    pub fn select(&mut self) -> String {
+        let candidates = [
+            ("routing_rule", &self.routing_rule),
+            ("header", &self.header),
+            ("tenant_default", &self.tenant_default),
+            ("geo", &self.geo),
+        ];
+        let (source, candidate) = candidates
+            .into_iter()
+            .find_map(|(source, region)| region.clone().map(|region| (source, region)))
+            .unwrap_or(("default", routing::DEFAULT_REGION.to_string()));
+        self.source = source;
+        self.candidate = candidate.clone();
+        candidate
+    }
+}
+
+impl RoutingTrace {
+    pub fn fallback(&mut self, fallback: impl Into<String>) {
+        self.fallbacks.push(fallback.into());
+    }
+
+    /// Records a policy check's outcome and passes it on
+    pub fn check(
+        &mut self,
+        check: &'static str,
+        result: std::result::Result<(), ProxyError>,
+    ) -> std::result::Result<(), ProxyError> {
+        let outcome = match &result {
+            Ok(()) => "passed".to_string(),
+            Err(e) => e.to_string(),
+        };
+        self.policy.push(PolicyCheck { check, outcome });
+        result
+    }
+
+    /// Records how `key` picked shard `index` of a region's pool
+    pub fn shard(
+        &mut self,
+        weights: &ShardWeights,
+        region_code: &str,
+        pool_size: u32,
+        key_source: &'static str,
+        key: &str,
+        index: u32,
+    ) {
+        let weight = if weights.has_active_shard(region_code, pool_size) {
+            weights.weight(region_code, index)
+        } else {
+            self.fallback(format!("Every shard of {} is drained, so weights were ignored", region_code));
+            DEFAULT_SHARD_WEIGHT
+        };
+        self.shard = Some(ShardTrace {
+            key_source,
+            key_bytes: key.len(),
+            key_hash: format!("{:016x}", seahash::hash(key.as_bytes())),
+            pool_size,
+            weight,
+            index,
+            shard: routing::shard_name(region_code, index),
+        });
+    }
+
+    /// Adds the trace to a JSON response's body as `routing_trace` when debug logging is on
+    ///
+    /// Streamed and non-JSON responses are returned unchanged.
+    pub async fn attach(&self, mut response: Response, log_level: LogLevel) -> Result<Response> {
+        let headers = response.headers().clone();
+        let json = headers.get("Content-Type")?.is_some_and(|content_type| content_type.contains("json"));
+        if log_level != LogLevel::Debug || !json || headers.get(STREAM_HEADER)?.is_some() {
+            return Ok(response);
+        }
+        let status = response.status_code();
+        let text = response.text().await?;
+        let body = match serde_json::from_str::<Value>(&text) {
+            Ok(Value::Object(mut body)) => {
+                body.insert("routing_trace".to_string(), serde_json::to_value(self)?);
+                Value::Object(body).to_string()
+            }
+            _ => text,
+        };
+        headers.delete("Content-Length")?;
+        Ok(Response::ok(body)?.with_status(status).with_headers(headers))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_region_precedence_and_shard_trace() {
+        let mut region = RegionTrace {
+            header: Some("WEUR".to_string()),
+            tenant_default: Some("eeur".to_string()),
+            geo: Some("wnam".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(region.select(), "WEUR");
+        assert_eq!((region.source, region.candidate.as_str()), ("header", "WEUR"));
+
+        region.routing_rule = Some("apac".to_string());
+        assert_eq!(region.select(), "apac");
+        assert_eq!(region.source, "routing_rule");
+        assert_eq!(RegionTrace::default().select(), routing::DEFAULT_REGION);
+
+        let mut trace = RoutingTrace::default();
+        let mut weights = ShardWeights::default();
+        weights.set("weur-1", 0).unwrap();
+        trace.shard(&weights, "weur", 2, "routing_key", "order-42", 0);
+        let shard = trace.shard.as_ref().unwrap();
+        assert_eq!((shard.shard.as_str(), shard.weight, shard.key_bytes), ("weur-0", DEFAULT_SHARD_WEIGHT, 8));
+        assert_eq!(shard.key_hash.len(), 16);
+        assert!(trace.fallbacks.is_empty());
+
+        // With every shard drained, weights are ignored and the trace says so
+        weights.set("weur-0", 0).unwrap();
+        trace.shard(&weights, "weur", 2, "body", "{}", 1);
+        assert_eq!(trace.fallbacks.len(), 1);
+
+        assert!(trace.check("tenant_policy", Err(ProxyError::PolicyViolation("no".to_string()))).is_err());
+        assert_eq!(trace.policy[0].outcome, ProxyError::PolicyViolation("no".to_string()).to_string());
+    }
+}
@@ -0,0 +1,130 @@
+use serde::{Deserialize, Serialize};
+use worker::*;
+
+use crate::error::ProxyError;
+use crate::stubs;
+
+/// KV key holding the sandbox tenant's echo endpoints
+pub const SANDBOX_KEY: &str = "sandbox";
+
+/// Where the sandbox tenant may send upstream calls (see `tenants::SANDBOX_TENANT_ID`)
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct SandboxConfig {
+    /// URL prefixes of endpoints that only echo requests back (e.g.
+    /// "https://httpbin.org/anything"), which sandbox calls may reach over the network
+    #[serde(default)]
+    pub echo_endpoints: Vec<String>,
+}
+
+/// How a sandbox call is answered
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SandboxRoute {
+    /// From the mock registry, without a network call
+    Mock,
+    /// By an echo endpoint, with redirects not followed
+    Echo,
+}
+
+/// The targets a sandbox request may reach, loaded when the processor serves one
+#[derive(Debug, Clone, Default)]
+pub struct Sandbox {
+    /// URL prefixes of the mock registry (`upstream-stubs`)
+    pub mocks: Vec<String>,
+    pub echo_endpoints: Vec<String>,
+}
+
+impl SandboxConfig {
+    /// Loads the config from the CONFIG KV namespace; no config allows no echo endpoints
+    pub async fn load(env: &Env) -> SandboxConfig {
+        let kv = match env.kv("CONFIG") {
+            Ok(kv) => kv,
+            Err(_) => return SandboxConfig::default(),
+        };
+        match kv.get(SANDBOX_KEY).cache_ttl(60).json::<SandboxConfig>().await {
+            Ok(config) => config.unwrap_or_default(),
+            Err(e) => {
+                log_error!("Failed to load sandbox config, allowing no echo endpoints: {}", e);
+                SandboxConfig::default()
+            }
+        }
+    }
+
+    /// Reads the config bypassing the edge cache, for the admin API
+    pub async fn load_fresh(env: &Env) -> Result<SandboxConfig> {
+        Ok(env.kv("CONFIG")?.get(SANDBOX_KEY).json::<SandboxConfig>().await?.unwrap_or_default())
+    }
+
+    pub async fn save(&self, env: &Env) -> Result<()> {
+        env.kv("CONFIG")?
+            .put(SANDBOX_KEY, serde_json::to_string(self)?)?
+            .execute()
+            .await?;
+        Ok(())
+    }
+
+    /// Echo endpoints in normalized form, so a prefix always ends in a path
+    /// (`https://echo.example` can't match `https://echo.example.evil.test`)
+    pub fn normalized(&self) -> std::result::Result<SandboxConfig, ProxyError> {
+        let echo_endpoints = self
+            .echo_endpoints
+            .iter()
+            .map(|endpoint| match Url::parse(endpoint) {
+                Ok(url) if matches!(url.scheme(), "http" | "https") && url.has_host() => Ok(url.to_string()),
+                _ => Err(ProxyError::InvalidRequest(format!("Invalid echo endpoint: {}", endpoint))),
+            })
+            .collect::<std::result::Result<_, _>>()?;
+        Ok(SandboxConfig { echo_endpoints })
+    }
+}
+
+impl Sandbox {
+    pub async fn load(env: &Env) -> Sandbox {
+        Sandbox {
+            mocks: stubs::load_sandbox(env).await,
+            echo_endpoints: SandboxConfig::load(env).await.echo_endpoints,
+        }
+    }
+
+    /// How a call to `url` is answered; anything but a mock or an echo endpoint is refused
+    pub fn route(&self, url: &str) -> std::result::Result<SandboxRoute, ProxyError> {
+        // Matched as the mock registry matches, so a mock route always finds its mock
+        if self.mocks.iter().any(|prefix| url.starts_with(prefix.as_str())) {
+            return Ok(SandboxRoute::Mock);
+        }
+        let parsed = Url::parse(url).map_err(|_| ProxyError::InvalidRequest(format!("Invalid target URL: {}", url)))?;
+        if self.echo_endpoints.iter().any(|prefix| parsed.as_str().starts_with(prefix.as_str())) {
+            return Ok(SandboxRoute::Echo);
+        }
+        Err(ProxyError::PolicyViolation(format!(
+            "The sandbox tenant can only reach mocks and echo endpoints, not {}",
+            parsed.origin().ascii_serialization()
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sandbox_routes() {
+        let config = SandboxConfig {
+            echo_endpoints: vec!["https://Echo.example".to_string()],
+        };
+        let sandbox = Sandbox {
+            mocks: vec!["https://api.didx.example/".to_string()],
+            echo_endpoints: config.normalized().unwrap().echo_endpoints,
+        };
+        assert_eq!(sandbox.echo_endpoints, vec!["https://echo.example/".to_string()]);
+
+        assert_eq!(sandbox.route("https://api.didx.example/v1/dids").unwrap(), SandboxRoute::Mock);
+        assert_eq!(sandbox.route("https://echo.example/anything?a=1").unwrap(), SandboxRoute::Echo);
+        assert!(sandbox.route("https://echo.example.evil.test/").is_err());
+        assert!(sandbox.route("https://api.carrier.example/v1/numbers").is_err());
+
+        let invalid = SandboxConfig {
+            echo_endpoints: vec!["ftp://echo.example/".to_string()],
+        };
+        assert!(invalid.normalized().is_err());
+    }
+}
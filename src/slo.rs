@@ -0,0 +1,240 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use worker::wasm_bindgen::JsValue;
+use worker::*;
+
+use crate::error::{Fault, FAULT_HEADER};
+use crate::handlers::response::STREAM_HEADER;
+
+/// Response header the processor reports its time spent waiting on upstreams in
+pub const UPSTREAM_MS_HEADER: &str = "X-Upstream-Ms";
+
+/// SLO counts are kept per UTC hour
+const HOUR_MS: u64 = 3_600_000;
+
+/// Period the error budgets are spent over, in hours
+const BUDGET_HOURS: u64 = 30 * 24;
+
+/// Windows burn rates are reported for, in hours; the shortest is the current hour so far
+const BURN_WINDOWS: &[(&str, u64)] = &[("1h", 1), ("6h", 6), ("24h", 24), ("30d", BUDGET_HOURS)];
+
+/// The proxy's own objectives (see `Config::slo_targets`)
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct SloTargets {
+    /// Percent of requests without a proxy fault
+    pub availability: f64,
+    /// Proxy overhead, the time not spent waiting on upstreams, a request should stay under
+    pub latency_ms: u64,
+    /// Percent of requests that should stay under `latency_ms`
+    pub latency: f64,
+}
+
+/// What one request counts toward its region's objectives
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Outcome {
+    pub proxy_error: bool,
+    pub slow: bool,
+}
+
+impl Outcome {
+    /// Classifies a processor's `response`, given the edge's own `overhead_ms` so far
+    pub fn of(response: &Response, overhead_ms: u64, targets: &SloTargets) -> Result<Outcome> {
+        let headers = response.headers();
+        let fault = headers.get(FAULT_HEADER)?;
+        let streamed = headers.get(STREAM_HEADER)?.is_some();
+        let upstream_ms = headers.get(UPSTREAM_MS_HEADER)?.and_then(|ms| ms.parse().ok()).unwrap_or(0);
+        Ok(Outcome {
+            proxy_error: is_proxy_error(response.status_code(), fault.as_deref(), streamed),
+            slow: overhead_ms.saturating_sub(upstream_ms) > targets.latency_ms,
+        })
+    }
+}
+
+/// Whether a response counts against the proxy's availability
+///
+/// Error responses name their fault. A streamed response carries the
+/// upstream's own status, and any other 5xx is the proxy's (e.g. a processor
+/// that threw).
+pub fn is_proxy_error(status: u16, fault: Option<&str>, streamed: bool) -> bool {
+    match fault.and_then(Fault::parse) {
+        Some(fault) => fault == Fault::Proxy,
+        None => !streamed && status >= 500,
+    }
+}
+
+/// Requests, proxy errors and slow requests over one window
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct WindowReport {
+    pub requests: u64,
+    pub proxy_errors: u64,
+    pub slow: u64,
+    /// How fast the error budget is being spent; 1 spends exactly the budget over 30 days
+    pub availability_burn_rate: f64,
+    pub latency_burn_rate: f64,
+}
+
+/// One region's burn rates and what's left of its 30-day budgets
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RegionReport {
+    pub windows: BTreeMap<&'static str, WindowReport>,
+    /// Fraction of the budget left; negative once overspent
+    pub availability_budget_remaining: f64,
+    pub latency_budget_remaining: f64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SloReport {
+    pub targets: SloTargets,
+    pub regions: BTreeMap<String, RegionReport>,
+}
+
+/// One region's counts for one hour, from the D1 `slo` table
+#[derive(Debug, Clone, Deserialize)]
+pub struct HourCounts {
+    /// Start of the UTC hour as epoch millis
+    pub hour: u64,
+    pub region: String,
+    pub requests: u64,
+    pub errors: u64,
+    pub slow: u64,
+}
+
+/// Start of the UTC hour containing `at` (epoch millis)
+fn hour_of(at: u64) -> u64 {
+    at - at % HOUR_MS
+}
+
+/// Observed bad fraction over the allowed one (`100 - target` percent)
+fn burn_rate(bad: u64, requests: u64, target: f64) -> f64 {
+    if requests == 0 {
+        return 0.0;
+    }
+    (bad as f64 / requests as f64) / ((100.0 - target) / 100.0)
+}
+
+impl SloReport {
+    /// Burn rates per region over each window ending at `now`
+    pub fn build(rows: &[HourCounts], now: u64, targets: SloTargets) -> SloReport {
+        let current = hour_of(now);
+        let mut regions: BTreeMap<String, BTreeMap<&'static str, WindowReport>> = BTreeMap::new();
+        for row in rows {
+            let windows = regions.entry(row.region.clone()).or_default();
+            for (name, hours) in BURN_WINDOWS {
+                let window = windows.entry(*name).or_default();
+                if row.hour + (hours - 1) * HOUR_MS >= current {
+                    window.requests += row.requests;
+                    window.proxy_errors += row.errors;
+                    window.slow += row.slow;
+                }
+            }
+        }
+
+        let regions = regions
+            .into_iter()
+            .map(|(region, mut windows)| {
+                for window in windows.values_mut() {
+                    let requests = window.requests;
+                    window.availability_burn_rate = burn_rate(window.proxy_errors, requests, targets.availability);
+                    window.latency_burn_rate = burn_rate(window.slow, requests, targets.latency);
+                }
+                let budget = windows.get("30d").copied().unwrap_or_default();
+                let report = RegionReport {
+                    windows,
+                    availability_budget_remaining: 1.0 - budget.availability_burn_rate,
+                    latency_budget_remaining: 1.0 - budget.latency_burn_rate,
+                };
+                (region, report)
+            })
+            .collect();
+        SloReport { targets, regions }
+    }
+}
+
+/// Per-hour SLO counts in the optional DB D1 database
+pub struct SloLog {
+    db: D1Database,
+}
+
+impl SloLog {
+    /// Returns `None` when no DB database is bound, which disables SLO tracking
+    pub fn new(env: &Env) -> Option<SloLog> {
+        env.d1("DB").ok().map(|db| SloLog { db })
+    }
+
+    /// Counts one request routed to `region`
+    pub async fn record(&self, at: u64, region: &str, outcome: Outcome) -> Result<()> {
+        self.db
+            .prepare(
+                "INSERT INTO slo (hour, region, requests, errors, slow) VALUES (?1, ?2, 1, ?3, ?4) \
+                 ON CONFLICT (hour, region) DO UPDATE SET \
+                 requests = requests + 1, errors = errors + excluded.errors, slow = slow + excluded.slow",
+            )
+            .bind(&[
+                JsValue::from(hour_of(at) as f64),
+                region.into(),
+                JsValue::from(u32::from(outcome.proxy_error)),
+                JsValue::from(u32::from(outcome.slow)),
+            ])?
+            .run()
+            .await?;
+        Ok(())
+    }
+
+    /// Every region's counts over the 30-day budget period ending at `now`
+    pub async fn report(&self, now: u64, targets: SloTargets) -> Result<SloReport> {
+        let since = hour_of(now).saturating_sub((BUDGET_HOURS - 1) * HOUR_MS);
+        let result = self
+            .db
+            .prepare("SELECT hour, region, requests, errors, slow FROM slo WHERE hour >= ?1")
+            .bind(&[JsValue::from(since as f64)])?
+            .all()
+            .await?;
+        Ok(SloReport::build(&result.results::<HourCounts>()?, now, targets))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_burn_rates() {
+        // Upstream failures and streamed upstream errors don't count against the proxy
+        assert!(!is_proxy_error(502, Some("upstream"), false));
+        assert!(!is_proxy_error(503, None, true));
+        assert!(is_proxy_error(500, None, false));
+        assert!(is_proxy_error(400, Some("proxy"), false));
+
+        let targets = SloTargets {
+            availability: 99.0,
+            latency_ms: 250,
+            latency: 90.0,
+        };
+        let now = 1_000 * HOUR_MS + 1_234;
+        let rows = [
+            HourCounts {
+                hour: 1_000 * HOUR_MS,
+                region: "weur".to_string(),
+                requests: 100,
+                errors: 2,
+                slow: 10,
+            },
+            HourCounts {
+                hour: 990 * HOUR_MS,
+                region: "weur".to_string(),
+                requests: 900,
+                errors: 0,
+                slow: 0,
+            },
+        ];
+        let report = SloReport::build(&rows, now, targets);
+        let weur = &report.regions["weur"];
+        // 2% errors against a 1% allowance burns at twice the sustainable rate
+        assert_eq!(weur.windows["1h"].requests, 100);
+        assert!((weur.windows["1h"].availability_burn_rate - 2.0).abs() < 1e-9);
+        assert!((weur.windows["1h"].latency_burn_rate - 1.0).abs() < 1e-9);
+        assert_eq!(weur.windows["24h"].requests, 1_000);
+        assert!((weur.availability_budget_remaining - 0.8).abs() < 1e-9);
+        assert!((weur.latency_budget_remaining - 0.9).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,147 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use worker::*;
+
+use crate::error::ProxyError;
+
+/// KV key holding the SOAP actions each host permits
+pub const SOAP_ALLOWLIST_KEY: &str = "soap-allowlist";
+
+/// SOAP actions callers may invoke, by upstream host
+///
+/// Hosts without an entry accept any action. For a listed host, a tenant
+/// with its own list is held to that list, and every other caller to the
+/// host's `actions`, so a host listed for some tenants only refuses the rest.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct SoapAllowlist(pub HashMap<String, HostActions>);
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HostActions {
+    /// Actions any caller may invoke; `getStatus` exactly, or `get*` by prefix
+    #[serde(default)]
+    pub actions: Vec<String>,
+    /// Tenant id to the actions it may invoke instead of `actions`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub tenants: BTreeMap<String, Vec<String>>,
+}
+
+impl HostActions {
+    pub fn validate(&self) -> std::result::Result<(), ProxyError> {
+        for action in self.actions.iter().chain(self.tenants.values().flatten()) {
+            let name = action.strip_suffix('*').unwrap_or(action);
+            if action.trim().is_empty() || action != action.trim() || name.contains('*') {
+                return Err(ProxyError::InvalidRequest(format!(
+                    "Invalid action {:?}: use a name, or a prefix followed by *",
+                    action
+                )));
+            }
+        }
+        if self.tenants.keys().any(|tenant| tenant.trim().is_empty()) {
+            return Err(ProxyError::InvalidRequest("Tenant ids must not be empty".to_string()));
+        }
+        Ok(())
+    }
+
+    /// Sorts and dedups every list, so stored entries diff cleanly
+    pub fn normalize(&mut self) {
+        for actions in std::iter::once(&mut self.actions).chain(self.tenants.values_mut()) {
+            actions.sort();
+            actions.dedup();
+        }
+    }
+
+    fn allows(&self, tenant_id: &str, action: &str) -> bool {
+        self.tenants.get(tenant_id).unwrap_or(&self.actions).iter().any(|allowed| match allowed.strip_suffix('*') {
+            Some(prefix) => action.starts_with(prefix),
+            None => allowed == action,
+        })
+    }
+}
+
+impl SoapAllowlist {
+    /// Loads the allowlist from the CONFIG KV namespace; without the namespace
+    /// every action is allowed, but an unreadable allowlist refuses SOAP calls
+    pub async fn load(env: &Env) -> std::result::Result<SoapAllowlist, ProxyError> {
+        let kv = match env.kv("CONFIG") {
+            Ok(kv) => kv,
+            Err(_) => return Ok(SoapAllowlist::default()),
+        };
+        match kv.get(SOAP_ALLOWLIST_KEY).cache_ttl(60).json::<SoapAllowlist>().await {
+            Ok(allowlist) => Ok(allowlist.unwrap_or_default()),
+            Err(e) => Err(ProxyError::Internal(format!("Failed to load the SOAP allowlist: {}", e))),
+        }
+    }
+
+    /// Reads the allowlist bypassing the edge cache, for admin read-modify-write
+    pub async fn load_fresh(env: &Env) -> Result<SoapAllowlist> {
+        Ok(env.kv("CONFIG")?.get(SOAP_ALLOWLIST_KEY).json::<SoapAllowlist>().await?.unwrap_or_default())
+    }
+
+    pub async fn save(&self, env: &Env) -> Result<()> {
+        env.kv("CONFIG")?.put(SOAP_ALLOWLIST_KEY, serde_json::to_string(self)?)?.execute().await?;
+        Ok(())
+    }
+
+    /// Refuses `action` on `host` unless the host's entry permits it for
+    /// `tenant_id`; hosts match case-insensitively, actions exactly
+    pub fn check(&self, host: &str, tenant_id: &str, action: &str) -> std::result::Result<(), ProxyError> {
+        let entry = self.0.iter().find(|(listed, _)| listed.eq_ignore_ascii_case(host)).map(|(_, entry)| entry);
+        match entry {
+            Some(entry) if !entry.allows(tenant_id, action) => Err(ProxyError::PolicyViolation(format!(
+                "SOAP action {} is not allowed on {} for tenant {}",
+                action, host, tenant_id
+            ))),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Refuses a SOAP call to `url` that the stored allowlist doesn't permit `tenant_id` to make
+pub async fn check(env: &Env, tenant_id: &str, url: &str, action: &str) -> std::result::Result<(), ProxyError> {
+    let host = Url::parse(url).ok().and_then(|url| url.host_str().map(str::to_string)).unwrap_or_default();
+    SoapAllowlist::load(env).await?.check(&host, tenant_id, action)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_actions_are_checked_per_host_and_tenant() {
+        let allowlist: SoapAllowlist = serde_json::from_str(
+            r#"{
+                "soap.carrier.example": {
+                    "actions": ["getDIDCountry", "list*"],
+                    "tenants": {"provisioning": ["list*", "cancelDID"], "audit": []}
+                },
+                "soap.partner.example": {"tenants": {"billing": ["*"]}}
+            }"#,
+        )
+        .unwrap();
+        let allowed = |host: &str, tenant: &str, action: &str| allowlist.check(host, tenant, action).is_ok();
+
+        assert!(allowed("SOAP.carrier.example", "billing", "getDIDCountry"));
+        assert!(allowed("soap.carrier.example", "billing", "listDIDs"));
+        assert!(!allowed("soap.carrier.example", "billing", "cancelDID"));
+        assert!(!allowed("soap.carrier.example", "billing", "getdidcountry"));
+        // A tenant's own list replaces the host's
+        assert!(allowed("soap.carrier.example", "provisioning", "cancelDID"));
+        assert!(!allowed("soap.carrier.example", "provisioning", "getDIDCountry"));
+        assert!(!allowed("soap.carrier.example", "audit", "listDIDs"));
+        // Listed for one tenant only, the host refuses everyone else
+        assert!(allowed("soap.partner.example", "billing", "anything"));
+        assert!(!allowed("soap.partner.example", "root", "anything"));
+        assert!(allowed("soap.other.example", "root", "cancelDID"));
+
+        let error = allowlist.check("soap.carrier.example", "billing", "cancelDID").unwrap_err();
+        assert_eq!(error.code(), "POLICY_VIOLATION");
+
+        for bad in [r#"{"actions": ["*get"]}"#, r#"{"actions": [" "]}"#, r#"{"tenants": {"": ["a"]}}"#] {
+            assert!(serde_json::from_str::<HostActions>(bad).unwrap().validate().is_err(), "{}", bad);
+        }
+        let mut entry: HostActions = serde_json::from_str(r#"{"actions": ["b", "a", "b"]}"#).unwrap();
+        entry.normalize();
+        assert_eq!(entry.actions, vec!["a", "b"]);
+    }
+}
@@ -0,0 +1,357 @@
+use serde::Deserialize;
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::time::Duration;
+use worker::*;
+
+use crate::handlers::charset;
+use crate::handlers::response::{ApiResponse, ErrorResponseData, ResponseData, ResponseMetadata};
+use crate::handlers::xml::{self, XmlOptions};
+use crate::logger::LogLevel;
+use crate::sandbox::SandboxRoute;
+
+/// KV key holding the stubs a mock-mode build loads, which is also the sandbox tenant's mock registry
+pub const STUBS_KEY: &str = "upstream-stubs";
+
+/// How long a processor answers sandbox calls from its copy of the mock registry
+const SANDBOX_RELOAD_MS: u64 = 60_000;
+
+/// A canned upstream response
+#[derive(Debug, Clone, Deserialize)]
+pub struct StubResponse {
+    pub status: u16,
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    /// Raw body, decoded and converted like a real upstream body
+    #[serde(default)]
+    pub body: String,
+    /// How long the upstream takes to answer; past the request's timeout the
+    /// call fails like a real timeout
+    #[serde(default)]
+    pub delay_ms: u64,
+}
+
+impl StubResponse {
+    pub fn new(status: u16, body: &str) -> StubResponse {
+        StubResponse {
+            status,
+            headers: HashMap::new(),
+            body: body.to_string(),
+            delay_ms: 0,
+        }
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> StubResponse {
+        self.headers.insert(name.to_lowercase(), value.to_string());
+        self
+    }
+
+    pub fn delay(mut self, delay_ms: u64) -> StubResponse {
+        self.delay_ms = delay_ms;
+        self
+    }
+
+    /// Turns the reply into what the handlers return for a real one
+    pub fn into_api_response(self, xml_to_json: Option<&XmlOptions>, log_level: LogLevel) -> ApiResponse {
+        if !(200..300).contains(&self.status) {
+            let message = reqwest::StatusCode::from_u16(self.status)
+                .ok()
+                .and_then(|status| status.canonical_reason())
+                .unwrap_or("Unknown Status");
+            return ApiResponse::Error(ErrorResponseData {
+                status: self.status,
+                message: message.to_string(),
+                metadata: None,
+                redirected_to: None,
+            });
+        }
+        let decoded = charset::decode(self.body.as_bytes(), self.headers.get("content-type").map(String::as_str));
+        log_debug!(log_level, "Stubbed response body size: {} bytes", decoded.text.len());
+        ApiResponse::Success(ResponseData {
+            status: self.status,
+            body: xml::response_body(self.status, &decoded.text, xml_to_json),
+            raw_body: xml::raw_body(self.status, &decoded.text, xml_to_json),
+            headers: self.headers,
+            metadata: decoded.original_charset.map(|original_charset| ResponseMetadata {
+                original_charset: Some(original_charset),
+                ..Default::default()
+            }),
+            redirected_to: None,
+        })
+    }
+}
+
+/// One answer of a stubbed upstream
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum Stub {
+    /// A transport error, e.g. `{"fail": "connection reset"}`
+    Fail { fail: String },
+    Respond(StubResponse),
+}
+
+/// A stubbed upstream: its answers in order and the bodies it was sent
+#[derive(Default)]
+struct StubUpstream {
+    answers: VecDeque<Stub>,
+    requests: Vec<Option<String>>,
+}
+
+thread_local! {
+    /// Stubs by URL prefix
+    static STUBS: RefCell<HashMap<String, StubUpstream>> = RefCell::new(HashMap::new());
+    /// The mock registry as the sandbox tenant sees it, kept apart so no other
+    /// tenant's call is ever answered from it
+    static SANDBOX: RefCell<HashMap<String, StubUpstream>> = RefCell::new(HashMap::new());
+    static SANDBOX_LOADED_AT: std::cell::Cell<u64> = const { std::cell::Cell::new(0) };
+    #[cfg(feature = "mock-mode")]
+    static LOADED: std::cell::Cell<bool> = const { std::cell::Cell::new(false) };
+}
+
+/// Answers calls to URLs starting with `url_prefix` with `answers`, in
+/// order; the last answer repeats. Replaces earlier stubs for the prefix.
+pub fn register(url_prefix: &str, answers: Vec<Stub>) {
+    let upstream = StubUpstream {
+        answers: answers.into(),
+        requests: Vec::new(),
+    };
+    STUBS.with(|stubs| stubs.borrow_mut().insert(url_prefix.to_string(), upstream));
+}
+
+/// Removes all stubs
+pub fn clear() {
+    STUBS.with(|stubs| stubs.borrow_mut().clear());
+}
+
+/// Bodies sent to a stubbed upstream so far (`None` for bodiless calls)
+pub fn requests(url_prefix: &str) -> Vec<Option<String>> {
+    STUBS.with(|stubs| {
+        stubs
+            .borrow()
+            .get(url_prefix)
+            .map(|upstream| upstream.requests.clone())
+            .unwrap_or_default()
+    })
+}
+
+/// The stubs stored under `upstream-stubs` in the CONFIG KV namespace, or
+/// `None` when they can't be read
+async fn read(env: &Env) -> Option<HashMap<String, Vec<Stub>>> {
+    let kv = env.kv("CONFIG").ok()?;
+    match kv.get(STUBS_KEY).cache_ttl(60).json::<HashMap<String, Vec<Stub>>>().await {
+        Ok(stored) => Some(stored.unwrap_or_default()),
+        Err(e) => {
+            log_error!("Failed to load upstream stubs: {}", e);
+            None
+        }
+    }
+}
+
+/// Registers the stubs stored under `upstream-stubs` in the CONFIG KV
+/// namespace, once per isolate
+///
+/// The value maps URL prefixes to answer lists, e.g.
+/// `{"https://api.example.com/": [{"status": 503}, {"status": 200, "body": "{}"}]}`.
+#[cfg(feature = "mock-mode")]
+pub async fn load(env: &Env) {
+    if LOADED.with(|loaded| loaded.replace(true)) {
+        return;
+    }
+    for (url_prefix, answers) in read(env).await.unwrap_or_default() {
+        register(&url_prefix, answers);
+    }
+}
+
+/// Refreshes the sandbox's copy of the mock registry once it's a minute old,
+/// which restarts every mock's answers; returns the mocks' URL prefixes
+pub(crate) async fn load_sandbox(env: &Env) -> Vec<String> {
+    let now = Date::now().as_millis();
+    if now.saturating_sub(SANDBOX_LOADED_AT.with(|loaded_at| loaded_at.get())) > SANDBOX_RELOAD_MS {
+        if let Some(stored) = read(env).await {
+            let upstreams = stored
+                .into_iter()
+                .map(|(url_prefix, answers)| {
+                    let upstream = StubUpstream {
+                        answers: answers.into(),
+                        requests: Vec::new(),
+                    };
+                    (url_prefix, upstream)
+                })
+                .collect();
+            SANDBOX.with(|sandbox| *sandbox.borrow_mut() = upstreams);
+            SANDBOX_LOADED_AT.with(|loaded_at| loaded_at.set(now));
+        }
+    }
+    SANDBOX.with(|sandbox| sandbox.borrow().keys().cloned().collect())
+}
+
+/// The next answer for `url` from the stub with the longest matching prefix
+fn next_answer(stubs: &mut HashMap<String, StubUpstream>, url: &str, body: Option<&str>) -> Option<Stub> {
+    let (_, upstream) = stubs
+        .iter_mut()
+        .filter(|(prefix, _)| url.starts_with(prefix.as_str()))
+        .max_by_key(|(prefix, _)| prefix.len())?;
+    upstream.requests.push(body.map(str::to_string));
+    if upstream.answers.len() > 1 {
+        upstream.answers.pop_front()
+    } else {
+        upstream.answers.front().cloned()
+    }
+}
+
+/// The stubbed answer to a call to `url`, or `None` when it should go to the network
+///
+/// A sandbox call to a mock is always answered from the mock registry, failing
+/// when it has no answer; a sandbox call to an echo endpoint never is. Other
+/// calls are answered by registered stubs (tests and mock-mode builds).
+pub(crate) async fn answer(
+    url: &str,
+    body: Option<&str>,
+    timeout: Option<Duration>,
+    sandbox: Option<SandboxRoute>,
+) -> Option<anyhow::Result<StubResponse>> {
+    let answer = match sandbox {
+        Some(SandboxRoute::Mock) => match SANDBOX.with(|sandbox| next_answer(&mut sandbox.borrow_mut(), url, body)) {
+            Some(answer) => answer,
+            None => return Some(Err(anyhow::anyhow!("No sandbox mock answers {}", url))),
+        },
+        Some(SandboxRoute::Echo) => return None,
+        None => STUBS.with(|stubs| next_answer(&mut stubs.borrow_mut(), url, body))?,
+    };
+
+    Some(match answer {
+        Stub::Fail { fail } => Err(anyhow::anyhow!("Stubbed upstream failed: {}", fail)),
+        Stub::Respond(response) => {
+            let delay = Duration::from_millis(response.delay_ms);
+            match timeout {
+                Some(timeout) if delay > timeout => {
+                    Delay::from(timeout).await;
+                    Err(anyhow::anyhow!("Stubbed upstream timed out after {}ms", timeout.as_millis()))
+                }
+                _ => {
+                    if !delay.is_zero() {
+                        Delay::from(delay).await;
+                    }
+                    Ok(response)
+                }
+            }
+        }
+    })
+}
+
+#[cfg(all(test, target_arch = "wasm32"))]
+mod tests {
+    use super::*;
+    use crate::handlers::http_handler::{process_request, RequestData};
+    use crate::retry::{self, MatchedBy, RetryBudgets, RetryPolicy};
+    use serde_json::{json, Value};
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    fn request(url: &str, extra: Value) -> RequestData {
+        let mut data = json!({"url": url, "method": "get"});
+        data.as_object_mut().unwrap().extend(extra.as_object().cloned().unwrap_or_default());
+        serde_json::from_value(data).unwrap()
+    }
+
+    #[wasm_bindgen_test]
+    async fn test_handlers_against_stubbed_upstreams() {
+        // Retries: a 503 and a transport error, then a recovered upstream
+        register(
+            "https://flaky.stub/",
+            vec![
+                Stub::Respond(StubResponse::new(503, "")),
+                Stub::Fail { fail: "connection reset".to_string() },
+                Stub::Respond(StubResponse::new(200, r#"{"ok": true}"#)),
+            ],
+        );
+        let policy: RetryPolicy = serde_json::from_value(json!({
+            "max_attempts": 3,
+            "retry_on_statuses": [503],
+            "backoff": {"base_ms": 0, "max_ms": 0}
+        }))
+        .unwrap();
+        let budgets = RetryBudgets::default();
+        let data = request("https://flaky.stub/lookup", json!({}));
+        let (result, usage) = retry::run(
+            Some(("request", &policy, MatchedBy::Request)),
+            true,
+            &budgets,
+            None,
+            None,
+            None,
+            LogLevel::Info,
+            |timeout| {
+                let mut attempt = data.clone();
+                attempt.timeout = timeout;
+                process_request(attempt, LogLevel::Info)
+            },
+        )
+        .await;
+        assert_eq!(result.unwrap().status(), 200);
+        assert_eq!(usage.unwrap().attempts, 3);
+        assert_eq!(requests("https://flaky.stub/").len(), 3);
+
+        // Expiry: a retry that would be sent past X-Expires-At is dropped instead
+        register("https://stale.stub/", vec![Stub::Respond(StubResponse::new(503, ""))]);
+        let data = request("https://stale.stub/provision", json!({}));
+        let (_, usage) = retry::run(
+            Some(("request", &policy, MatchedBy::Request)),
+            true,
+            &budgets,
+            None,
+            None,
+            Some(crate::expiry::Expiry(Date::now().as_millis())),
+            LogLevel::Info,
+            |timeout| {
+                let mut attempt = data.clone();
+                attempt.timeout = timeout;
+                process_request(attempt, LogLevel::Info)
+            },
+        )
+        .await;
+        assert!(usage.unwrap().expired);
+        assert_eq!(requests("https://stale.stub/").len(), 1);
+
+        // Timeouts: an answer slower than the request's timeout fails the call
+        register("https://slow.stub/", vec![Stub::Respond(StubResponse::new(200, "{}").delay(50))]);
+        let mut data = request("https://slow.stub/", json!({}));
+        data.timeout = Some(Duration::from_millis(10));
+        assert!(process_request(data, LogLevel::Info).await.is_err());
+
+        // Transformations: XML is converted when asked
+        register(
+            "https://xml.stub/",
+            vec![Stub::Respond(
+                StubResponse::new(200, "<result><country>US</country></result>").header("Content-Type", "text/xml"),
+            )],
+        );
+        let data = request("https://xml.stub/", json!({"xml_to_json": {}}));
+        match process_request(data, LogLevel::Info).await.unwrap() {
+            ApiResponse::Success(response) => assert_eq!(response.body, json!({"result": {"country": "US"}})),
+            other => panic!("Unexpected response: {:?}", other.status()),
+        }
+
+        // Header-only: the status and headers come back, the body is never read
+        register(
+            "https://large.stub/",
+            vec![Stub::Respond(StubResponse::new(200, "0123456789").header("Content-Length", "10"))],
+        );
+        let data = request("https://large.stub/export.csv", json!({"headers_only": true}));
+        match process_request(data, LogLevel::Info).await.unwrap() {
+            ApiResponse::Success(response) => {
+                assert_eq!(response.body, Value::Null);
+                assert_eq!(response.headers.get("content-length").map(String::as_str), Some("10"));
+            }
+            other => panic!("Unexpected response: {:?}", other.status()),
+        }
+
+        // Empty bodies: a 204 comes back as `null`, not `""`
+        register("https://empty.stub/", vec![Stub::Respond(StubResponse::new(204, ""))]);
+        match process_request(request("https://empty.stub/", json!({})), LogLevel::Info).await.unwrap() {
+            ApiResponse::Success(response) => assert_eq!(response.body, Value::Null),
+            other => panic!("Unexpected response: {:?}", other.status()),
+        }
+
+        clear();
+    }
+}
@@ -0,0 +1,572 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::BTreeSet;
+use worker::*;
+
+use crate::error::ProxyError;
+use crate::tenants::Tenant;
+
+/// KV key prefix for saved request templates
+const TEMPLATE_PREFIX: &str = "template:";
+
+/// KV key prefix for a template's audit trail
+const AUDIT_PREFIX: &str = "template-audit:";
+
+/// Maximum number of audit entries kept per template (oldest are dropped)
+const MAX_AUDIT_ENTRIES: usize = 200;
+
+/// A named request template and all of its versions
+///
+/// Each version holds a full `RequestData` or `SoapRequestData` JSON body
+/// whose strings may contain `{{var}}` placeholders, filled from the `vars`
+/// of the invocation. New versions start as drafts; invocations use the
+/// published version unless the tenant is pinned to another one.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RequestTemplate {
+    pub name: String,
+    #[serde(default)]
+    pub versions: Vec<TemplateVersion>,
+    /// Version number served to unpinned callers
+    #[serde(default)]
+    pub published: Option<u32>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TemplateVersion {
+    pub version: u32,
+    pub status: VersionStatus,
+    /// "http" or "soap", forwarded as X-Request-Type
+    #[serde(default = "default_request_type")]
+    pub request_type: String,
+    pub request: Value,
+    pub created_at: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum VersionStatus {
+    Draft,
+    Published,
+    /// Was published before a newer version replaced it
+    Retired,
+}
+
+fn default_request_type() -> String {
+    "http".to_string()
+}
+
+/// One change to a template or its rollout
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub at: u64,
+    /// Value of the X-Admin-Actor header, or "admin"
+    pub actor: String,
+    /// create_version, publish, delete, pin, unpin
+    pub action: String,
+    #[serde(default)]
+    pub version: Option<u32>,
+    #[serde(default)]
+    pub tenant: Option<String>,
+}
+
+impl RequestTemplate {
+    pub fn new(name: &str) -> RequestTemplate {
+        RequestTemplate {
+            name: name.to_string(),
+            versions: Vec::new(),
+            published: None,
+        }
+    }
+
+    pub fn version(&self, version: u32) -> Option<&TemplateVersion> {
+        self.versions.iter().find(|v| v.version == version)
+    }
+
+    /// Adds a new draft version and returns its number
+    pub fn add_draft(&mut self, request_type: String, request: Value) -> u32 {
+        let version = self.versions.iter().map(|v| v.version).max().unwrap_or(0) + 1;
+        self.versions.push(TemplateVersion {
+            version,
+            status: VersionStatus::Draft,
+            request_type,
+            request,
+            created_at: Date::now().as_millis(),
+        });
+        version
+    }
+
+    /// Makes `version` the published one; the previously published version is retired
+    ///
+    /// Publishing an older version is how a rollout is rolled back.
+    pub fn publish(&mut self, version: u32) -> bool {
+        if self.version(version).is_none() {
+            return false;
+        }
+        for v in self.versions.iter_mut() {
+            if v.version == version {
+                v.status = VersionStatus::Published;
+            } else if v.status == VersionStatus::Published {
+                v.status = VersionStatus::Retired;
+            }
+        }
+        self.published = Some(version);
+        true
+    }
+}
+
+/// Body of a request that invokes a template instead of sending a full payload
+#[derive(Deserialize)]
+struct TemplateInvocation {
+    template: String,
+    /// Explicit version, mainly for testing drafts before publishing
+    #[serde(default)]
+    version: Option<u32>,
+    #[serde(default)]
+    vars: Map<String, Value>,
+}
+
+/// Template names are dotted identifiers like "didx.getDIDCountry"
+pub fn is_valid_template_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 128
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-' || c == '_')
+}
+
+/// Saved templates and their audit trails in the CONFIG KV namespace
+pub struct TemplateStore {
+    kv: kv::KvStore,
+}
+
+impl TemplateStore {
+    pub fn new(env: &Env) -> Result<TemplateStore> {
+        Ok(TemplateStore { kv: env.kv("CONFIG")? })
+    }
+
+    /// Reads a template bypassing the edge cache, for read-modify-write in the admin API
+    pub async fn get(&self, name: &str) -> Result<Option<RequestTemplate>> {
+        Ok(self
+            .kv
+            .get(&format!("{}{}", TEMPLATE_PREFIX, name))
+            .json::<RequestTemplate>()
+            .await?)
+    }
+
+    /// Reads a template through the edge cache, for request-time resolution
+    pub async fn get_cached(&self, name: &str) -> Result<Option<RequestTemplate>> {
+        Ok(self
+            .kv
+            .get(&format!("{}{}", TEMPLATE_PREFIX, name))
+            .cache_ttl(60)
+            .json::<RequestTemplate>()
+            .await?)
+    }
+
+    pub async fn list(&self) -> Result<Vec<String>> {
+        let mut names = Vec::new();
+        let mut cursor: Option<String> = None;
+        loop {
+            let mut list = self.kv.list().prefix(TEMPLATE_PREFIX.to_string());
+            if let Some(cursor) = cursor.take() {
+                list = list.cursor(cursor);
+            }
+            let page = list.execute().await?;
+            names.extend(
+                page.keys
+                    .iter()
+                    .filter_map(|key| key.name.strip_prefix(TEMPLATE_PREFIX).map(str::to_string)),
+            );
+            if page.list_complete {
+                break;
+            }
+            cursor = page.cursor;
+        }
+        Ok(names)
+    }
+
+    pub async fn save(&self, template: &RequestTemplate) -> Result<()> {
+        self.kv
+            .put(&format!("{}{}", TEMPLATE_PREFIX, template.name), serde_json::to_string(template)?)?
+            .execute()
+            .await?;
+        Ok(())
+    }
+
+    /// Deletes a template; its audit trail is kept
+    pub async fn delete(&self, name: &str) -> Result<()> {
+        self.kv.delete(&format!("{}{}", TEMPLATE_PREFIX, name)).await?;
+        Ok(())
+    }
+
+    pub async fn audit_trail(&self, name: &str) -> Result<Vec<AuditEntry>> {
+        Ok(self
+            .kv
+            .get(&format!("{}{}", AUDIT_PREFIX, name))
+            .json::<Vec<AuditEntry>>()
+            .await?
+            .unwrap_or_default())
+    }
+
+    /// Appends an entry to a template's audit trail
+    pub async fn record(&self, name: &str, entry: AuditEntry) -> Result<()> {
+        let mut trail = self.audit_trail(name).await?;
+        trail.push(entry);
+        if trail.len() > MAX_AUDIT_ENTRIES {
+            trail.drain(..trail.len() - MAX_AUDIT_ENTRIES);
+        }
+        self.kv
+            .put(&format!("{}{}", AUDIT_PREFIX, name), serde_json::to_string(&trail)?)?
+            .execute()
+            .await?;
+        Ok(())
+    }
+}
+
+/// Expands a template invocation into a full request body
+///
+/// Returns `None` when the body isn't a template invocation, so regular
+/// payloads pass through untouched. On success returns the expanded body
+/// and the request type of the version used.
+///
+/// Version selection: explicit `version` in the invocation, then the
+/// tenant's pin for this template, then the published version.
+pub async fn resolve(
+    env: &Env,
+    body: &str,
+    tenant: Option<&Tenant>,
+) -> std::result::Result<Option<(String, String)>, ProxyError> {
+    let invocation = match serde_json::from_str::<TemplateInvocation>(body) {
+        Ok(invocation) => invocation,
+        Err(_) => return Ok(None),
+    };
+
+    let template = TemplateStore::new(env)?
+        .get_cached(&invocation.template)
+        .await?
+        .ok_or_else(|| ProxyError::NotFound(format!("Unknown template: {}", invocation.template)))?;
+
+    let pinned = tenant.and_then(|tenant| tenant.template_pins.get(&template.name).copied());
+    let version_number = invocation
+        .version
+        .or(pinned)
+        .or(template.published)
+        .ok_or_else(|| ProxyError::NotFound(format!("Template {} has no published version", template.name)))?;
+    let version = template.version(version_number).ok_or_else(|| {
+        ProxyError::NotFound(format!("Template {} has no version {}", template.name, version_number))
+    })?;
+
+    let request = expand(&version.request, &invocation.vars)?;
+    Ok(Some((request.to_string(), version.request_type.clone())))
+}
+
+/// Substitutes `{{var}}` placeholders throughout a JSON value
+///
+/// A string that is exactly one placeholder is replaced by the variable's
+/// JSON value, keeping its type (numbers stay numbers for SOAP type hints).
+/// Placeholders embedded in longer strings are replaced by the variable's
+/// string form, escaped for where it lands (see `Escape`). Names may be
+/// dotted paths into object vars. Any placeholder without a matching
+/// variable, or with an unknown `|filter`, is an error.
+pub fn expand(template: &Value, vars: &Map<String, Value>) -> std::result::Result<Value, ProxyError> {
+    let mut problems = Problems::default();
+    let expanded = expand_value(template, false, vars, &mut problems);
+    if !problems.filters.is_empty() {
+        return Err(ProxyError::InvalidRequest(format!(
+            "Unknown template filters: {}",
+            problems.filters.into_iter().collect::<Vec<_>>().join(", ")
+        )));
+    }
+    if problems.missing.is_empty() {
+        Ok(expanded)
+    } else {
+        Err(ProxyError::InvalidRequest(format!(
+            "Missing template vars: {}",
+            problems.missing.into_iter().collect::<Vec<_>>().join(", ")
+        )))
+    }
+}
+
+/// Placeholders `expand` couldn't fill
+#[derive(Default)]
+struct Problems {
+    missing: BTreeSet<String>,
+    filters: BTreeSet<String>,
+}
+
+/// How an interpolated value is escaped
+///
+/// Without a `{{var|filter}}`, the context is judged from the expanded text
+/// before the placeholder: in a `url` field, the host is left as is, the path
+/// is percent-encoded keeping `/`, and the query and fragment are fully
+/// percent-encoded; in a string that starts with `<`, values are escaped as
+/// XML text or, inside a tag, as an attribute. Other strings are JSON strings,
+/// which serialization already escapes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Escape {
+    Raw,
+    /// The inside of a JSON string literal, for JSON text kept in a string
+    Json,
+    UrlPath,
+    /// A query or fragment component
+    Url,
+    XmlText,
+    XmlAttr,
+}
+
+impl Escape {
+    fn named(filter: &str) -> Option<Escape> {
+        match filter {
+            "raw" => Some(Escape::Raw),
+            "json" => Some(Escape::Json),
+            "url" => Some(Escape::Url),
+            "xml" => Some(Escape::XmlText),
+            "xml_attr" => Some(Escape::XmlAttr),
+            _ => None,
+        }
+    }
+
+    fn infer(before: &str, in_url: bool) -> Escape {
+        if in_url {
+            if before.contains(['?', '#']) {
+                return Escape::Url;
+            }
+            let authority = before.find("://").map_or(0, |scheme| scheme + 3);
+            return if before[authority..].contains('/') { Escape::UrlPath } else { Escape::Raw };
+        }
+        if !before.trim_start().starts_with('<') {
+            return Escape::Raw;
+        }
+        match (before.rfind('<'), before.rfind('>')) {
+            (Some(open), Some(close)) if close > open => Escape::XmlText,
+            _ => Escape::XmlAttr,
+        }
+    }
+
+    fn apply(self, value: &str) -> String {
+        match self {
+            Escape::Raw => value.to_string(),
+            Escape::Json => {
+                let quoted = Value::String(value.to_string()).to_string();
+                quoted[1..quoted.len() - 1].to_string()
+            }
+            Escape::UrlPath => percent_encode(value, b"/"),
+            Escape::Url => percent_encode(value, b""),
+            Escape::XmlText => value.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;"),
+            Escape::XmlAttr => Escape::XmlText.apply(value).replace('"', "&quot;").replace('\'', "&apos;"),
+        }
+    }
+}
+
+/// Percent-encodes every byte but the unreserved characters and `keep`
+fn percent_encode(value: &str, keep: &[u8]) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) || keep.contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    encoded
+}
+
+/// Splits a placeholder's inside into the variable name and its filter, if any
+fn parse_placeholder(inner: &str) -> (&str, Option<&str>) {
+    match inner.split_once('|') {
+        Some((name, filter)) => (name.trim(), Some(filter.trim())),
+        None => (inner.trim(), None),
+    }
+}
+
+/// Names of the `{{var}}` placeholders anywhere in a JSON value (e.g. `reserve.body.id`)
+pub fn placeholders(template: &Value) -> BTreeSet<String> {
+    let mut names = BTreeSet::new();
+    collect_placeholders(template, &mut names);
+    names
+}
+
+fn collect_placeholders(value: &Value, names: &mut BTreeSet<String>) {
+    match value {
+        Value::String(s) => {
+            let mut rest = s.as_str();
+            while let Some(start) = rest.find("{{") {
+                let Some(len) = rest[start + 2..].find("}}") else {
+                    break;
+                };
+                names.insert(parse_placeholder(&rest[start + 2..start + 2 + len]).0.to_string());
+                rest = &rest[start + 2 + len + 2..];
+            }
+        }
+        Value::Array(items) => items.iter().for_each(|item| collect_placeholders(item, names)),
+        Value::Object(map) => map.values().for_each(|item| collect_placeholders(item, names)),
+        _ => {}
+    }
+}
+
+/// `in_url` is set for the strings of a `url` field
+fn expand_value(value: &Value, in_url: bool, vars: &Map<String, Value>, problems: &mut Problems) -> Value {
+    match value {
+        Value::String(s) => expand_string(s, in_url, vars, problems),
+        Value::Array(items) => {
+            Value::Array(items.iter().map(|item| expand_value(item, in_url, vars, problems)).collect())
+        }
+        Value::Object(map) => Value::Object(
+            map.iter()
+                .map(|(key, item)| (key.clone(), expand_value(item, key == "url", vars, problems)))
+                .collect(),
+        ),
+        other => other.clone(),
+    }
+}
+
+fn expand_string(s: &str, in_url: bool, vars: &Map<String, Value>, problems: &mut Problems) -> Value {
+    // Whole-string placeholder without a filter: substitute the typed value
+    if let Some(inner) = s.strip_prefix("{{").and_then(|rest| rest.strip_suffix("}}")) {
+        if let (name, None) = parse_placeholder(inner) {
+            if !name.contains("{{") && !name.contains("}}") {
+                return match lookup(vars, name) {
+                    Some(value) => value.clone(),
+                    None => {
+                        problems.missing.insert(name.to_string());
+                        Value::String(s.to_string())
+                    }
+                };
+            }
+        }
+    }
+
+    let mut result = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(start) = rest.find("{{") {
+        let Some(len) = rest[start + 2..].find("}}") else {
+            break;
+        };
+        let (name, filter) = parse_placeholder(&rest[start + 2..start + 2 + len]);
+        result.push_str(&rest[..start]);
+        let escape = match filter {
+            Some(filter) => Escape::named(filter).unwrap_or_else(|| {
+                problems.filters.insert(filter.to_string());
+                Escape::Raw
+            }),
+            None => Escape::infer(&result, in_url),
+        };
+        match lookup(vars, name) {
+            Some(Value::String(value)) => result.push_str(&escape.apply(value)),
+            Some(value) => result.push_str(&escape.apply(&value.to_string())),
+            None => {
+                problems.missing.insert(name.to_string());
+                result.push_str(&rest[start..start + 2 + len + 2]);
+            }
+        }
+        rest = &rest[start + 2 + len + 2..];
+    }
+    result.push_str(rest);
+    Value::String(result)
+}
+
+/// Finds a variable by name, or by a dotted path into object and array vars
+/// (e.g. `reserve.body.items.0.id`)
+fn lookup<'a>(vars: &'a Map<String, Value>, name: &str) -> Option<&'a Value> {
+    if let Some(value) = vars.get(name) {
+        return Some(value);
+    }
+    let mut parts = name.split('.');
+    let mut current = vars.get(parts.next()?)?;
+    for part in parts {
+        current = match current {
+            Value::Object(map) => map.get(part)?,
+            Value::Array(items) => items.get(part.parse::<usize>().ok()?)?,
+            _ => return None,
+        };
+    }
+    Some(current)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_expand_keeps_types_and_interpolates() {
+        let template = json!({
+            "url": "https://{{host}}/soap",
+            "action": "getDIDCountry",
+            "params": [["did", "{{did}}"], ["limit", "{{ limit }}"]]
+        });
+        let vars = json!({"host": "api.example.com", "did": "123", "limit": 5});
+
+        let expanded = expand(&template, vars.as_object().unwrap()).unwrap();
+        assert_eq!(expanded["url"], "https://api.example.com/soap");
+        assert_eq!(expanded["params"][0][1], "123");
+        assert_eq!(expanded["params"][1][1], 5);
+
+        let nested = json!({"reserve": {"body": {"ids": [42]}}});
+        let expanded = expand(&json!({"id": "{{reserve.body.ids.0}}"}), nested.as_object().unwrap()).unwrap();
+        assert_eq!(expanded["id"], 42);
+    }
+
+    #[test]
+    fn test_expand_escapes_for_context() {
+        let template = json!({
+            "url": "https://{{host}}/v1/{{path}}/{{name}}?q={{name}}&next={{host|raw}}",
+            "body": "<order id=\"{{name}}\"><note>{{name}}</note><raw>{{name|raw}}</raw></order>",
+            "text": "{\"note\": \"{{name|json}}\"}",
+            "plain": "Note: {{name}}",
+            "quoted": "{{name|xml}}"
+        });
+        let vars = json!({"host": "api.example.com", "path": "a/b", "name": "Tom & \"Jerry\" <3"});
+
+        let expanded = expand(&template, vars.as_object().unwrap()).unwrap();
+        assert_eq!(
+            expanded["url"],
+            "https://api.example.com/v1/a/b/Tom%20%26%20%22Jerry%22%20%3C3\
+             ?q=Tom%20%26%20%22Jerry%22%20%3C3&next=api.example.com"
+        );
+        assert_eq!(
+            expanded["body"],
+            "<order id=\"Tom &amp; &quot;Jerry&quot; &lt;3\"><note>Tom &amp; \"Jerry\" &lt;3</note>\
+             <raw>Tom & \"Jerry\" <3</raw></order>"
+        );
+        assert_eq!(expanded["text"], r#"{"note": "Tom & \"Jerry\" <3"}"#);
+        assert_eq!(expanded["plain"], "Note: Tom & \"Jerry\" <3");
+        assert_eq!(expanded["quoted"], "Tom &amp; \"Jerry\" &lt;3");
+
+        assert_eq!(placeholders(&json!("{{ reserve.id | url }}")), BTreeSet::from(["reserve.id".to_string()]));
+        let err = expand(&json!("id={{name|base64}}"), vars.as_object().unwrap()).unwrap_err();
+        assert!(err.message().contains("base64"));
+    }
+
+    #[test]
+    fn test_publish_retires_previous_version() {
+        let mut template = RequestTemplate::new("didx.getDIDCountry");
+        template.versions.push(TemplateVersion {
+            version: 1,
+            status: VersionStatus::Draft,
+            request_type: "soap".to_string(),
+            request: json!({"url": "https://a"}),
+            created_at: 0,
+        });
+        template.versions.push(TemplateVersion {
+            version: 2,
+            status: VersionStatus::Draft,
+            request_type: "soap".to_string(),
+            request: json!({"url": "https://b"}),
+            created_at: 0,
+        });
+
+        assert!(template.publish(1));
+        assert!(template.publish(2));
+        assert_eq!(template.published, Some(2));
+        assert_eq!(template.version(1).unwrap().status, VersionStatus::Retired);
+        assert!(!template.publish(3));
+    }
+
+    #[test]
+    fn test_expand_reports_missing_vars() {
+        let template = json!({"url": "https://{{host}}/{{path}}"});
+        let vars = json!({"host": "api.example.com"});
+
+        let err = expand(&template, vars.as_object().unwrap()).unwrap_err();
+        assert!(err.message().contains("path"));
+    }
+}
@@ -0,0 +1,554 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use worker::*;
+
+use crate::allowlist::HostRule;
+use crate::end_user::EndUserForwarding;
+use crate::error::ProxyError;
+use crate::guardrails::Guardrails;
+use crate::processors::registry;
+use crate::routing::target_urls;
+use crate::webhooks::WebhookChallenge;
+
+/// KV key prefix for tenant records
+const TENANT_PREFIX: &str = "tenant:";
+
+/// KV key prefix mapping a token hash to its tenant id
+const TOKEN_PREFIX: &str = "token:";
+
+/// Prefix of generated tenant tokens, so they're recognizable in secret scanners
+const TOKEN_MARKER: &str = "apx_";
+
+/// Built-in tenant whose calls only reach mocks and echo endpoints (see `sandbox`)
+pub const SANDBOX_TENANT_ID: &str = "sandbox";
+
+/// An internal team (or service) allowed to use the proxy with its own tokens
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Tenant {
+    /// Slug used in logs and admin URLs (e.g. "billing")
+    pub id: String,
+    pub name: String,
+    pub created_at: u64,
+    /// Issued tokens; only hashes are stored
+    #[serde(default)]
+    pub tokens: Vec<TenantToken>,
+    #[serde(default)]
+    pub rate_limit: RateLimit,
+    /// Allowlist rules for target URLs (see `HostRule`); empty allows any host
+    #[serde(default)]
+    pub allowed_hosts: Vec<String>,
+    #[serde(default)]
+    pub region_policy: RegionPolicy,
+    /// Template name to the version this tenant is pinned to
+    #[serde(default)]
+    pub template_pins: HashMap<String, u32>,
+    /// Body-aware region selection, evaluated in order; the first match wins
+    #[serde(default)]
+    pub routing_rules: Vec<RoutingRule>,
+    /// Extra load retries may add across all the tenant's requests in a
+    /// region (0.2 = 20%); unset leaves retries to the per-policy budgets
+    #[serde(default)]
+    pub retry_budget: Option<f64>,
+    /// Share of each shard's concurrency slots relative to other tenants
+    /// (1-100); unset weighs 1
+    #[serde(default)]
+    pub concurrency_weight: Option<u32>,
+    /// Verification handshakes answered at `GET /webhooks/{id}/{name}`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub webhook_challenges: Vec<WebhookChallenge>,
+    /// Upstream headers the `X-End-User` context is sent in; unset forwards nothing
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub end_user_forwarding: Option<EndUserForwarding>,
+    /// Caps on steps, batch entries, attempts and subrequests per request
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub guardrails: Option<Guardrails>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TenantToken {
+    /// Short identifier (hash prefix) used to revoke the token
+    pub id: String,
+    pub hash: String,
+    pub created_at: u64,
+    #[serde(flatten)]
+    pub scope: TokenScope,
+}
+
+impl TenantToken {
+    /// Name used in logs: the token's name, or its id when it has none
+    pub fn label(&self) -> &str {
+        self.scope.name.as_deref().unwrap_or(&self.id)
+    }
+}
+
+/// A label and optional limits of one token, on top of the tenant's policy
+///
+/// Lets a tenant hand a narrower token to one job, e.g. a SOAP-only token
+/// for the EU catalog sync.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct TokenScope {
+    /// Shown in logs, e.g. "catalog-sync"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// Regions this token may route to; empty leaves it to the tenant's policy
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allowed_regions: Vec<String>,
+    /// `X-Request-Type` values this token may send; empty allows all
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allowed_request_types: Vec<String>,
+}
+
+impl TokenScope {
+    /// Checks a proxy request against the token's limits; `region_code` is
+    /// `None` when a policy override skips the region check
+    pub fn check(&self, region_code: Option<&str>, request_type: &str) -> std::result::Result<(), ProxyError> {
+        let label = self.name.as_deref().unwrap_or("this token");
+        let disallowed = |region_code: &&str| {
+            !self.allowed_regions.is_empty()
+                && !self.allowed_regions.iter().any(|allowed| allowed.eq_ignore_ascii_case(region_code))
+        };
+        if let Some(region_code) = region_code.filter(disallowed) {
+            return Err(ProxyError::PolicyViolation(format!(
+                "Region {} is not allowed for {}",
+                region_code, label
+            )));
+        }
+        // A missing X-Request-Type is an HTTP request
+        let request_type = if request_type.is_empty() { "http" } else { request_type };
+        if !self.allowed_request_types.is_empty()
+            && !self.allowed_request_types.iter().any(|allowed| allowed.eq_ignore_ascii_case(request_type))
+        {
+            return Err(ProxyError::PolicyViolation(format!(
+                "Request type {} is not allowed for {}",
+                request_type, label
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Requests a tenant may send per minute, counted at the edge across all regions
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RateLimit {
+    pub requests_per_minute: u32,
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        RateLimit { requests_per_minute: 600 }
+    }
+}
+
+/// A tenant's requests in the current minute, kept by its limiter instance (see `registry`)
+#[derive(Debug, Default, Clone, Copy)]
+pub struct RateWindow {
+    minute: u64,
+    requests: u32,
+}
+
+impl RateWindow {
+    /// Counts a request at `now` (epoch millis); over the limit, returns the
+    /// seconds until the next minute instead
+    pub fn admit(&mut self, limit: &RateLimit, now: u64) -> Option<u64> {
+        let minute = now / 60_000;
+        if minute != self.minute {
+            *self = RateWindow { minute, requests: 0 };
+        }
+        if self.requests >= limit.requests_per_minute {
+            return Some(((minute + 1) * 60_000 - now).div_ceil(1000));
+        }
+        self.requests += 1;
+        None
+    }
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct RegionPolicy {
+    /// Regions this tenant may route to; empty allows every region
+    #[serde(default)]
+    pub allowed_regions: Vec<String>,
+    /// Region used when the request has no X-CF-Region header
+    #[serde(default)]
+    pub default_region: Option<String>,
+}
+
+/// Routes requests whose body has one of `values` at `pointer` to `region`
+///
+/// `pointer` is a JSON pointer (e.g. `/params/country`). For SOAP bodies,
+/// `/params/{name}` also finds the value of the `[name, value]` param pair.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RoutingRule {
+    pub pointer: String,
+    pub values: Vec<serde_json::Value>,
+    pub region: String,
+}
+
+impl RoutingRule {
+    fn matches(&self, body: &serde_json::Value) -> bool {
+        let found = body.pointer(&self.pointer).or_else(|| soap_param(body, &self.pointer));
+        found.is_some_and(|value| self.values.contains(value))
+    }
+}
+
+/// Looks up `/params/{name}` in SOAP's ordered `[name, value]` param pairs
+fn soap_param<'a>(body: &'a serde_json::Value, pointer: &str) -> Option<&'a serde_json::Value> {
+    let name = pointer.strip_prefix("/params/")?;
+    body.get("params")?
+        .as_array()?
+        .iter()
+        .filter_map(|pair| pair.as_array())
+        .find(|pair| pair.first().and_then(|key| key.as_str()) == Some(name))
+        .and_then(|pair| pair.get(1))
+}
+
+impl Tenant {
+    /// Creates a tenant with default rate limits and an open host/region policy
+    pub fn new(id: &str, name: &str) -> Tenant {
+        Tenant {
+            id: id.to_string(),
+            name: name.to_string(),
+            created_at: Date::now().as_millis(),
+            tokens: Vec::new(),
+            rate_limit: RateLimit::default(),
+            allowed_hosts: Vec::new(),
+            region_policy: RegionPolicy::default(),
+            template_pins: HashMap::new(),
+            routing_rules: Vec::new(),
+            retry_budget: None,
+            concurrency_weight: None,
+            webhook_challenges: Vec::new(),
+            end_user_forwarding: None,
+            guardrails: None,
+        }
+    }
+
+    /// Whether enforcing this tenant's policy at the edge needs the request body
+    pub fn reads_body(&self) -> bool {
+        !self.allowed_hosts.is_empty() || !self.routing_rules.is_empty() || self.guardrails.is_some()
+    }
+
+    /// Region chosen by the first routing rule matching the request body
+    pub fn rule_region(&self, body: &str) -> Option<&str> {
+        if self.routing_rules.is_empty() {
+            return None;
+        }
+        let body = serde_json::from_str::<serde_json::Value>(body).ok()?;
+        self.routing_rules
+            .iter()
+            .find(|rule| rule.matches(&body))
+            .map(|rule| rule.region.as_str())
+    }
+
+    pub fn allows_region(&self, region_code: &str) -> bool {
+        self.region_policy.allowed_regions.is_empty()
+            || self
+                .region_policy
+                .allowed_regions
+                .iter()
+                .any(|allowed| allowed.eq_ignore_ascii_case(region_code))
+    }
+
+    pub fn allows_url(&self, url: &Url) -> bool {
+        self.allowed_hosts.is_empty()
+            || self
+                .allowed_hosts
+                .iter()
+                .any(|rule| HostRule::parse(rule).is_ok_and(|rule| rule.matches(url)))
+    }
+
+    /// Issues a new token, storing only its hash; returns the plaintext token
+    pub fn issue_token(&mut self, scope: TokenScope) -> String {
+        let token = format!(
+            "{}{}{}",
+            TOKEN_MARKER,
+            uuid::Uuid::new_v4().simple(),
+            uuid::Uuid::new_v4().simple()
+        );
+        let hash = hash_token(&token);
+        self.tokens.push(TenantToken {
+            id: hash[..12].to_string(),
+            hash,
+            created_at: Date::now().as_millis(),
+            scope,
+        });
+        token
+    }
+}
+
+/// Counts a proxy request against the tenant's rate limit, failing with
+/// `RATE_LIMITED` over it
+///
+/// Requests go through when the registry isn't bound or can't be reached.
+pub async fn check_rate_limit(env: &Env, tenant: &Tenant) -> std::result::Result<(), ProxyError> {
+    match registry::tenant_rate_limit(env, &tenant.id, &tenant.rate_limit).await {
+        Some(retry_after_secs) => Err(ProxyError::RateLimited {
+            tenant: tenant.id.clone(),
+            requests_per_minute: tenant.rate_limit.requests_per_minute,
+            retry_after_secs,
+        }),
+        None => Ok(()),
+    }
+}
+
+/// Checks a proxy request against the tenant's region and host policy
+///
+/// `region_code` is `None` when an audited policy override skips the region check.
+pub fn check_policy(tenant: &Tenant, region_code: Option<&str>, body: &str) -> std::result::Result<(), ProxyError> {
+    if let Some(region_code) = region_code.filter(|region_code| !tenant.allows_region(region_code)) {
+        return Err(ProxyError::PolicyViolation(format!(
+            "Region {} is not allowed for tenant {}",
+            region_code, tenant.id
+        )));
+    }
+
+    if tenant.allowed_hosts.is_empty() {
+        return Ok(());
+    }
+
+    let urls = target_urls(body);
+    if urls.is_empty() {
+        return Err(ProxyError::PolicyViolation("Request has no target URL".to_string()));
+    }
+    for url in urls {
+        let url = url
+            .filter(|url| url.has_host())
+            .ok_or_else(|| ProxyError::PolicyViolation("Target URL has no host".to_string()))?;
+        if !tenant.allows_url(&url) {
+            return Err(ProxyError::PolicyViolation(format!(
+                "Host {} is not allowed for tenant {}",
+                url.origin().ascii_serialization(),
+                tenant.id
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+/// Tenant ids are used in KV keys and URLs, so keep them to a simple slug
+///
+/// `root` is taken by the shared token, whose access a tenant mustn't inherit.
+pub fn is_valid_tenant_id(id: &str) -> bool {
+    id != crate::auth::ROOT_TENANT_ID
+        && !id.is_empty()
+        && id.len() <= 64
+        && id.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_')
+}
+
+/// SHA-256 of a token as lowercase hex
+pub fn hash_token(token: &str) -> String {
+    Sha256::digest(token.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Tenant records and token index in the CONFIG KV namespace
+pub struct TenantStore {
+    kv: kv::KvStore,
+}
+
+impl TenantStore {
+    pub fn new(env: &Env) -> Result<TenantStore> {
+        Ok(TenantStore { kv: env.kv("CONFIG")? })
+    }
+
+    /// The stored tenant; the sandbox tenant is built in, so until it's saved a
+    /// default record stands in and its tokens can be issued without creating it
+    pub async fn get(&self, id: &str) -> Result<Option<Tenant>> {
+        let stored = self.kv.get(&format!("{}{}", TENANT_PREFIX, id)).json::<Tenant>().await?;
+        Ok(stored.or_else(|| (id == SANDBOX_TENANT_ID).then(|| Tenant::new(SANDBOX_TENANT_ID, "Sandbox"))))
+    }
+
+    /// Resolves a plaintext token to its tenant and the token's record
+    pub async fn find_by_token(&self, token: &str) -> Result<Option<(Tenant, TenantToken)>> {
+        let hash = hash_token(token);
+        let tenant_id = self
+            .kv
+            .get(&format!("{}{}", TOKEN_PREFIX, hash))
+            .cache_ttl(60)
+            .text()
+            .await?;
+        let tenant = match tenant_id {
+            Some(id) => self.get(&id).await?,
+            None => return Ok(None),
+        };
+        // The index is edge-cached, so double-check the token wasn't revoked since
+        Ok(tenant.and_then(|tenant| {
+            let token = tenant.tokens.iter().find(|t| t.hash == hash)?.clone();
+            Some((tenant, token))
+        }))
+    }
+
+    pub async fn list(&self) -> Result<Vec<Tenant>> {
+        let mut tenants = Vec::new();
+        let mut cursor: Option<String> = None;
+        loop {
+            let mut list = self.kv.list().prefix(TENANT_PREFIX.to_string());
+            if let Some(cursor) = cursor.take() {
+                list = list.cursor(cursor);
+            }
+            let page = list.execute().await?;
+            for key in page.keys {
+                if let Some(id) = key.name.strip_prefix(TENANT_PREFIX) {
+                    if let Some(tenant) = self.get(id).await? {
+                        tenants.push(tenant);
+                    }
+                }
+            }
+            if page.list_complete {
+                break;
+            }
+            cursor = page.cursor;
+        }
+        Ok(tenants)
+    }
+
+    /// Saves a tenant and makes sure every one of its tokens is indexed
+    pub async fn save(&self, tenant: &Tenant) -> Result<()> {
+        self.kv
+            .put(&format!("{}{}", TENANT_PREFIX, tenant.id), serde_json::to_string(tenant)?)?
+            .execute()
+            .await?;
+        for token in &tenant.tokens {
+            self.kv
+                .put(&format!("{}{}", TOKEN_PREFIX, token.hash), tenant.id.clone())?
+                .execute()
+                .await?;
+        }
+        Ok(())
+    }
+
+    pub async fn revoke_token(&self, token: &TenantToken) -> Result<()> {
+        self.kv.delete(&format!("{}{}", TOKEN_PREFIX, token.hash)).await?;
+        Ok(())
+    }
+
+    /// Deletes a tenant and revokes all of its tokens
+    pub async fn delete(&self, tenant: &Tenant) -> Result<()> {
+        for token in &tenant.tokens {
+            self.revoke_token(token).await?;
+        }
+        self.kv.delete(&format!("{}{}", TENANT_PREFIX, tenant.id)).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tenant_policy() {
+        let mut tenant = Tenant {
+            id: "billing".to_string(),
+            name: "Billing".to_string(),
+            created_at: 0,
+            tokens: Vec::new(),
+            rate_limit: RateLimit::default(),
+            allowed_hosts: vec!["api.example.com".to_string()],
+            region_policy: RegionPolicy::default(),
+            template_pins: HashMap::new(),
+            routing_rules: Vec::new(),
+            retry_budget: None,
+            concurrency_weight: None,
+            webhook_challenges: Vec::new(),
+            end_user_forwarding: None,
+            guardrails: None,
+        };
+        assert!(tenant.allows_url(&Url::parse("https://API.example.com/v1").unwrap()));
+        assert!(!tenant.allows_url(&Url::parse("https://evil.example.com/v1").unwrap()));
+        assert!(tenant.allows_region("apac"));
+
+        tenant.region_policy.allowed_regions = vec!["weur".to_string()];
+        assert!(tenant.allows_region("WEUR"));
+        assert!(!tenant.allows_region("wnam"));
+    }
+
+    #[test]
+    fn test_token_scope_narrows_requests() {
+        assert!(TokenScope::default().check(Some("apac"), "saga").is_ok());
+
+        let scope: TokenScope = serde_json::from_str(
+            r#"{"name": "catalog-sync", "allowed_regions": ["weur"], "allowed_request_types": ["soap"]}"#,
+        )
+        .unwrap();
+        assert!(scope.check(Some("WEUR"), "SOAP").is_ok());
+        assert!(matches!(scope.check(Some("wnam"), "soap"), Err(ProxyError::PolicyViolation(_))));
+        // An override skips the region, never the request type
+        assert!(scope.check(None, "soap").is_ok());
+        // No X-Request-Type is an HTTP request
+        assert!(scope.check(Some("weur"), "").is_err());
+
+        // Tokens issued before scopes existed load unscoped
+        let token: TenantToken = serde_json::from_str(r#"{"id": "ab12", "hash": "ab12cd", "created_at": 0}"#).unwrap();
+        assert!(token.scope.allowed_regions.is_empty());
+        assert_eq!(token.label(), "ab12");
+    }
+
+    #[test]
+    fn test_rate_window_caps_each_minute() {
+        let limit = RateLimit { requests_per_minute: 3 };
+        let mut window = RateWindow::default();
+        let minute = 29_000_000 * 60_000;
+        for at in [0, 10_000, 20_000] {
+            assert_eq!(window.admit(&limit, minute + at), None);
+        }
+        assert_eq!(window.admit(&limit, minute + 45_500), Some(15));
+        assert_eq!(window.admit(&limit, minute + 59_999), Some(1));
+        // A new minute starts over
+        assert_eq!(window.admit(&limit, minute + 60_000), None);
+        let error = ProxyError::RateLimited {
+            tenant: "billing".to_string(),
+            requests_per_minute: 3,
+            retry_after_secs: 15,
+        };
+        assert_eq!((error.code(), error.status()), ("RATE_LIMITED", 429));
+    }
+
+    #[test]
+    fn test_routing_rules_match_json_and_soap_params() {
+        let mut tenant = Tenant {
+            id: "numbers".to_string(),
+            name: "Numbers".to_string(),
+            created_at: 0,
+            tokens: Vec::new(),
+            rate_limit: RateLimit::default(),
+            allowed_hosts: Vec::new(),
+            region_policy: RegionPolicy::default(),
+            template_pins: HashMap::new(),
+            routing_rules: Vec::new(),
+            retry_budget: None,
+            concurrency_weight: None,
+            webhook_challenges: Vec::new(),
+            end_user_forwarding: None,
+            guardrails: None,
+        };
+        tenant.routing_rules = vec![
+            RoutingRule {
+                pointer: "/params/country".to_string(),
+                values: vec![serde_json::json!("DE"), serde_json::json!("FR")],
+                region: "weur".to_string(),
+            },
+            RoutingRule {
+                pointer: "/params/country".to_string(),
+                values: vec![serde_json::json!("AU")],
+                region: "oc".to_string(),
+            },
+        ];
+
+        assert_eq!(tenant.rule_region(r#"{"url":"https://a","params":{"country":"FR"}}"#), Some("weur"));
+        assert_eq!(tenant.rule_region(r#"{"url":"https://a","params":[["did","1"],["country","AU"]]}"#), Some("oc"));
+        assert_eq!(tenant.rule_region(r#"{"url":"https://a","params":{"country":"US"}}"#), None);
+    }
+
+    #[test]
+    fn test_tenant_id_validation() {
+        assert!(is_valid_tenant_id("billing-eu_2"));
+        assert!(!is_valid_tenant_id("Billing"));
+        assert!(!is_valid_tenant_id("a/b"));
+        assert!(!is_valid_tenant_id(""));
+        assert!(!is_valid_tenant_id("root"));
+    }
+}
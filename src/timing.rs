@@ -0,0 +1,128 @@
+use serde::Serialize;
+use worker::Date;
+
+/// When one request of a batch or saga ran, relative to the start of the whole call
+#[derive(Debug, Clone, Serialize)]
+pub struct StepTiming {
+    /// Saga step name, or batch entry index
+    pub name: String,
+    pub started_ms: u64,
+    pub duration_ms: u64,
+    /// Earlier steps whose responses this one uses; it can't start before them
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub depends_on: Vec<String>,
+}
+
+/// Where the time of a batch or saga went
+#[derive(Debug, Clone, Serialize)]
+pub struct TimingReport {
+    pub total_ms: u64,
+    /// The longest step
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dominant_step: Option<String>,
+    /// The longest step's share of `total_ms`, in percent
+    pub dominant_percent: u64,
+    /// Steps allowed in flight at once (a batch's `concurrency`, 1 for sagas)
+    pub configured_parallelism: usize,
+    /// Most steps that were actually in flight at once
+    pub observed_parallelism: usize,
+    pub steps: Vec<StepTiming>,
+}
+
+/// Clock for the steps of one call, started when the call is
+pub struct Timer {
+    started_at: u64,
+}
+
+impl Timer {
+    pub fn start() -> Timer {
+        Timer {
+            started_at: Date::now().as_millis(),
+        }
+    }
+
+    /// Millis since the call started
+    pub fn elapsed_ms(&self) -> u64 {
+        Date::now().as_millis().saturating_sub(self.started_at)
+    }
+
+    /// Times a step that started at `started_ms` and has just finished
+    pub fn step(&self, name: impl Into<String>, started_ms: u64, depends_on: Vec<String>) -> StepTiming {
+        StepTiming {
+            name: name.into(),
+            started_ms,
+            duration_ms: self.elapsed_ms().saturating_sub(started_ms),
+            depends_on,
+        }
+    }
+
+    pub fn report(&self, configured_parallelism: usize, steps: Vec<StepTiming>) -> TimingReport {
+        TimingReport::new(self.elapsed_ms(), configured_parallelism, steps)
+    }
+}
+
+impl TimingReport {
+    pub fn new(total_ms: u64, configured_parallelism: usize, steps: Vec<StepTiming>) -> TimingReport {
+        let dominant = steps.iter().filter(|step| step.duration_ms > 0).max_by_key(|step| step.duration_ms);
+        // Steps running when each step started; the clock only advances during I/O,
+        // so steps that took no measurable time overlap nothing
+        let observed_parallelism = steps
+            .iter()
+            .map(|step| {
+                steps
+                    .iter()
+                    .filter(|other| {
+                        std::ptr::eq(*other, step)
+                            || (other.started_ms <= step.started_ms
+                                && step.started_ms < other.started_ms + other.duration_ms)
+                    })
+                    .count()
+            })
+            .max()
+            .unwrap_or(0);
+        TimingReport {
+            total_ms,
+            dominant_step: dominant.map(|step| step.name.clone()),
+            dominant_percent: match dominant {
+                Some(step) if total_ms > 0 => (step.duration_ms * 100 / total_ms).min(100),
+                _ => 0,
+            },
+            configured_parallelism,
+            observed_parallelism,
+            steps,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn step(name: &str, started_ms: u64, duration_ms: u64) -> StepTiming {
+        StepTiming {
+            name: name.to_string(),
+            started_ms,
+            duration_ms,
+            depends_on: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_report_finds_dominant_step_and_overlap() {
+        // Three entries allowed at once, but the third waited for the first to finish
+        let report = TimingReport::new(400, 3, vec![step("0", 0, 100), step("1", 0, 400), step("2", 100, 50)]);
+        assert_eq!(report.dominant_step.as_deref(), Some("1"));
+        assert_eq!(report.dominant_percent, 100);
+        assert_eq!(report.observed_parallelism, 2);
+
+        // Sequential steps never overlap
+        let report = TimingReport::new(300, 1, vec![step("reserve", 0, 100), step("buy", 100, 200)]);
+        assert_eq!(report.dominant_step.as_deref(), Some("buy"));
+        assert_eq!(report.dominant_percent, 66);
+        assert_eq!(report.observed_parallelism, 1);
+
+        let instant = TimingReport::new(0, 1, vec![step("cached", 0, 0)]);
+        assert_eq!(instant.dominant_step, None);
+        assert_eq!(instant.observed_parallelism, 1);
+    }
+}
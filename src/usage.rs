@@ -0,0 +1,122 @@
+use serde::{Deserialize, Serialize};
+use worker::wasm_bindgen::JsValue;
+use worker::*;
+
+use crate::cost::CostEstimate;
+use crate::labels::Labels;
+
+/// Usage is counted per UTC day
+const DAY_MS: u64 = 86_400_000;
+
+/// Maximum rows read by one report
+const MAX_REPORT_ROWS: u32 = 5_000;
+
+/// Requests of one tenant and label set on one day, from the D1 `usage` table
+#[derive(Debug, Clone, Serialize)]
+pub struct UsageRow {
+    /// UTC midnight as epoch millis
+    pub day: u64,
+    pub tenant: String,
+    pub labels: Labels,
+    pub requests: u64,
+    /// Requests the proxy answered with a non-2xx status
+    pub failed: u64,
+    /// Summed cost estimates of the requests
+    pub subrequests: u64,
+    pub do_requests: u64,
+    pub bytes_egressed: u64,
+}
+
+#[derive(Deserialize)]
+struct StoredRow {
+    day: u64,
+    tenant: String,
+    labels: String,
+    requests: u64,
+    failed: u64,
+    subrequests: u64,
+    do_requests: u64,
+    bytes_egressed: u64,
+}
+
+/// Start of the UTC day containing `at` (epoch millis)
+pub fn day_of(at: u64) -> u64 {
+    at - at % DAY_MS
+}
+
+/// Per-day request counts in the optional DB D1 database
+pub struct UsageLog {
+    db: D1Database,
+}
+
+impl UsageLog {
+    /// Returns `None` when no DB database is bound, which disables usage accounting
+    pub fn new(env: &Env) -> Option<UsageLog> {
+        env.d1("DB").ok().map(|db| UsageLog { db })
+    }
+
+    /// Counts one request and its cost
+    pub async fn record(&self, at: u64, tenant: &str, labels: &Labels, failed: bool, cost: CostEstimate) -> Result<()> {
+        self.db
+            .prepare(
+                "INSERT INTO usage (day, tenant, labels, requests, failed, subrequests, do_requests, bytes_egressed) \
+                 VALUES (?1, ?2, ?3, 1, ?4, ?5, ?6, ?7) \
+                 ON CONFLICT (day, tenant, labels) DO UPDATE SET \
+                 requests = requests + 1, failed = failed + excluded.failed, \
+                 subrequests = subrequests + excluded.subrequests, \
+                 do_requests = do_requests + excluded.do_requests, \
+                 bytes_egressed = bytes_egressed + excluded.bytes_egressed",
+            )
+            .bind(&[
+                JsValue::from(day_of(at) as f64),
+                tenant.into(),
+                labels.to_string().into(),
+                JsValue::from(u32::from(failed)),
+                JsValue::from(cost.subrequests),
+                JsValue::from(cost.do_requests),
+                JsValue::from(cost.bytes_egressed as f64),
+            ])?
+            .run()
+            .await?;
+        Ok(())
+    }
+
+    /// Usage in `[from, to)` (epoch millis, whole days), optionally for one tenant and
+    /// only for label sets containing every label of `filter`
+    pub async fn report(&self, from: u64, to: u64, tenant: Option<&str>, filter: &Labels) -> Result<Vec<UsageRow>> {
+        let result = self
+            .db
+            .prepare(
+                "SELECT day, tenant, labels, requests, failed, subrequests, do_requests, bytes_egressed FROM usage \
+                 WHERE day >= ?1 AND day < ?2 AND (?3 IS NULL OR tenant = ?3) \
+                 ORDER BY day, tenant, labels LIMIT ?4",
+            )
+            .bind(&[
+                JsValue::from(day_of(from) as f64),
+                JsValue::from(to as f64),
+                tenant.map(JsValue::from).unwrap_or(JsValue::NULL),
+                JsValue::from(MAX_REPORT_ROWS),
+            ])?
+            .all()
+            .await?;
+
+        Ok(result
+            .results::<StoredRow>()?
+            .into_iter()
+            .filter_map(|row| {
+                // Stored in canonical form, so it always parses back
+                let labels = Labels::parse(&row.labels).ok()?;
+                labels.matches(filter).then_some(UsageRow {
+                    day: row.day,
+                    tenant: row.tenant,
+                    labels,
+                    requests: row.requests,
+                    failed: row.failed,
+                    subrequests: row.subrequests,
+                    do_requests: row.do_requests,
+                    bytes_egressed: row.bytes_egressed,
+                })
+            })
+            .collect())
+    }
+}
@@ -0,0 +1,193 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use worker::*;
+
+use crate::credentials::{is_valid_credential_name, CredentialStore};
+use crate::error::ProxyError;
+use crate::tenants::TenantStore;
+
+/// Path prefix of webhook verification endpoints: `GET /webhooks/{tenant}/{name}`
+pub const WEBHOOK_PREFIX: &str = "/webhooks/";
+
+/// Longest challenge echoed back
+const MAX_CHALLENGE_LEN: usize = 1024;
+
+/// A webhook verification handshake the proxy answers for a tenant
+///
+/// Providers that verify a webhook URL with a GET (Meta's `hub.challenge`,
+/// Dropbox's `challenge`, ...) expect the challenge echoed back. Answering is
+/// idempotent: nothing is stored and nothing is forwarded.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WebhookChallenge {
+    /// Last path segment of `GET /webhooks/{tenant}/{name}`
+    pub name: String,
+    /// Query parameter holding the challenge, e.g. "hub.challenge"
+    pub challenge_param: String,
+    /// Query parameter carrying the verify token, e.g. "hub.verify_token"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub verify_param: Option<String>,
+    /// Stored credential whose `secret` the verify token must equal
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub verify_token_ref: Option<String>,
+    #[serde(default)]
+    pub response: ChallengeFormat,
+}
+
+/// How the challenge is echoed back
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(tag = "format", rename_all = "snake_case")]
+pub enum ChallengeFormat {
+    /// The challenge as a `text/plain` body
+    #[default]
+    Text,
+    /// `{"<field>": "<challenge>"}`
+    Json { field: String },
+}
+
+impl WebhookChallenge {
+    pub fn validate(&self) -> std::result::Result<(), ProxyError> {
+        if !is_valid_credential_name(&self.name) {
+            return Err(ProxyError::InvalidRequest(format!(
+                "Webhook challenge name must be 1-64 chars of [A-Za-z0-9._-]: {}",
+                self.name
+            )));
+        }
+        if self.challenge_param.is_empty() {
+            return Err(ProxyError::InvalidRequest("challenge_param can't be empty".to_string()));
+        }
+        if self.verify_param.is_some() != self.verify_token_ref.is_some() {
+            return Err(ProxyError::InvalidRequest(
+                "verify_param and verify_token_ref must be set together".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// The challenge to echo, once the verify token (when one is required) equals `expected`
+    fn challenge(&self, query: &[(String, String)], expected: Option<&str>) -> std::result::Result<String, ProxyError> {
+        let param = |name: &str| query.iter().find(|(key, _)| key == name).map(|(_, value)| value.as_str());
+        if let Some(verify_param) = &self.verify_param {
+            // A verify token whose credential is missing or has no secret never matches
+            if expected.is_none() || param(verify_param) != expected {
+                return Err(ProxyError::PolicyViolation("Webhook verify token doesn't match".to_string()));
+            }
+        }
+        let challenge = param(&self.challenge_param)
+            .filter(|challenge| !challenge.is_empty())
+            .ok_or_else(|| ProxyError::InvalidRequest(format!("Missing {} parameter", self.challenge_param)))?;
+        if challenge.len() > MAX_CHALLENGE_LEN {
+            return Err(ProxyError::InvalidRequest(format!(
+                "{} is longer than {} chars",
+                self.challenge_param, MAX_CHALLENGE_LEN
+            )));
+        }
+        Ok(challenge.to_string())
+    }
+
+    fn respond(&self, challenge: String) -> Result<Response> {
+        let response = match &self.response {
+            ChallengeFormat::Text => Response::ok(challenge)?,
+            ChallengeFormat::Json { field } => {
+                let mut body = Map::new();
+                body.insert(field.clone(), Value::String(challenge));
+                Response::from_json(&body)?
+            }
+        };
+        // The challenge is caller-chosen text; don't let a browser render it as anything else
+        let headers = response.headers().clone();
+        headers.set("X-Content-Type-Options", "nosniff")?;
+        headers.set("Cache-Control", "no-store")?;
+        Ok(response.with_headers(headers))
+    }
+}
+
+/// GET /webhooks/{tenant}/{name} - answers a provider's verification handshake
+///
+/// Unauthenticated, since providers can't send a proxy token: only challenges
+/// a tenant configured are answered, and the verify token guards those that
+/// set one. Unknown tenants and names get the same 404.
+pub async fn respond(req: &Request, env: &Env) -> Result<Response> {
+    let path = req.path();
+    let (tenant_id, name) = match path.trim_start_matches(WEBHOOK_PREFIX).split_once('/') {
+        Some((tenant_id, name)) if !name.contains('/') => (tenant_id.to_string(), name.to_string()),
+        _ => return ProxyError::NotFound(format!("No route for {}", path)).to_response(None),
+    };
+    let challenge = TenantStore::new(env)?
+        .get(&tenant_id)
+        .await?
+        .and_then(|tenant| tenant.webhook_challenges.into_iter().find(|challenge| challenge.name == name));
+    let challenge = match challenge {
+        Some(challenge) => challenge,
+        None => {
+            return ProxyError::NotFound(format!("No webhook challenge {}/{}", tenant_id, name)).to_response(None)
+        }
+    };
+
+    let expected = match &challenge.verify_token_ref {
+        Some(credential) => CredentialStore::new(env)?
+            .get(credential)
+            .await?
+            .and_then(|credential| credential.secret),
+        None => None,
+    };
+    let query: Vec<(String, String)> = req.url()?.query_pairs().into_owned().collect();
+    match challenge.challenge(&query, expected.as_deref()) {
+        Ok(value) => {
+            log_info!("Webhook challenge {}/{} answered", tenant_id, name);
+            challenge.respond(value)
+        }
+        Err(e) => {
+            log_info!("Webhook challenge {}/{} refused: {}", tenant_id, name, e);
+            e.to_response(None)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_challenge_answers() {
+        let meta: WebhookChallenge = serde_json::from_value(json!({
+            "name": "meta",
+            "challenge_param": "hub.challenge",
+            "verify_param": "hub.verify_token",
+            "verify_token_ref": "meta-verify"
+        }))
+        .unwrap();
+        assert!(meta.validate().is_ok());
+        assert!(matches!(meta.response, ChallengeFormat::Text));
+
+        let query = |pairs: &[(&str, &str)]| -> Vec<(String, String)> {
+            pairs.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect()
+        };
+        let handshake = query(&[
+            ("hub.mode", "subscribe"),
+            ("hub.challenge", "1158201444"),
+            ("hub.verify_token", "s3cr3t"),
+        ]);
+        assert_eq!(meta.challenge(&handshake, Some("s3cr3t")).unwrap(), "1158201444");
+        assert!(meta.challenge(&handshake, Some("other")).is_err());
+        assert!(meta.challenge(&handshake, None).is_err());
+        assert!(meta.challenge(&query(&[("hub.verify_token", "s3cr3t")]), Some("s3cr3t")).is_err());
+
+        let unverified: WebhookChallenge = serde_json::from_value(json!({
+            "name": "files",
+            "challenge_param": "challenge",
+            "response": {"format": "json", "field": "challenge"}
+        }))
+        .unwrap();
+        assert!(unverified.validate().is_ok());
+        assert_eq!(unverified.challenge(&query(&[("challenge", "abc")]), None).unwrap(), "abc");
+        let too_long = "x".repeat(MAX_CHALLENGE_LEN + 1);
+        assert!(unverified.challenge(&query(&[("challenge", &too_long)]), None).is_err());
+
+        let half_configured = WebhookChallenge {
+            verify_token_ref: None,
+            ..meta
+        };
+        assert!(half_configured.validate().is_err());
+    }
+}
@@ -0,0 +1,245 @@
+use futures_util::StreamExt;
+use serde_json::json;
+use std::cell::Cell;
+use std::collections::HashMap;
+use worker::*;
+
+use crate::config::Config;
+use crate::cost::CostEstimate;
+use crate::credentials::{self, is_valid_credential_name};
+use crate::error::ProxyError;
+use crate::logger;
+
+/// Header carrying the upstream WebSocket URL from the edge to the processor
+pub const TARGET_HEADER: &str = "X-WebSocket-Url";
+
+/// Header carrying the name of the credential the upstream upgrade is sent with
+pub const CREDENTIAL_HEADER: &str = "X-WebSocket-Credential";
+
+/// Subprotocols offered by the client, and the one the upstream picked
+const PROTOCOL_HEADER: &str = "Sec-WebSocket-Protocol";
+
+/// Whether a request asks to be upgraded to a WebSocket
+pub fn is_upgrade(headers: &Headers) -> Result<bool> {
+    Ok(headers.get("Upgrade")?.is_some_and(|upgrade| upgrade.eq_ignore_ascii_case("websocket")))
+}
+
+/// The upstream endpoint a client's WebSocket is bridged to
+#[derive(Debug, Clone, PartialEq)]
+pub struct Target {
+    /// A ws:// or wss:// URL
+    pub url: String,
+    /// Credential whose headers are sent with the upstream upgrade
+    pub credential: Option<String>,
+    /// The client's `Sec-WebSocket-Protocol`, offered to the upstream as is
+    pub protocols: Option<String>,
+}
+
+impl Target {
+    /// Reads the target from the upgrade's `url` and `credential` query parameters
+    pub fn from_query(url: &Url, protocols: Option<String>) -> std::result::Result<Target, ProxyError> {
+        let mut query: HashMap<String, String> = url.query_pairs().into_owned().collect();
+        let target = query.remove("url").ok_or_else(|| {
+            ProxyError::InvalidRequest("A WebSocket upgrade needs the upstream URL in ?url=".to_string())
+        })?;
+        let parsed = Url::parse(&target)
+            .map_err(|_| ProxyError::InvalidRequest(format!("Invalid WebSocket URL: {}", target)))?;
+        if !matches!(parsed.scheme(), "ws" | "wss") || !parsed.has_host() {
+            return Err(ProxyError::InvalidRequest(format!("WebSocket URL must be ws:// or wss://: {}", target)));
+        }
+        let credential = query.remove("credential");
+        if let Some(name) = credential.as_deref().filter(|name| !is_valid_credential_name(name)) {
+            return Err(ProxyError::InvalidRequest(format!("Invalid credential name: {}", name)));
+        }
+        Ok(Target { url: parsed.to_string(), credential, protocols })
+    }
+
+    /// Reads the target the edge forwarded; `None` for any other request
+    pub fn from_headers(headers: &Headers) -> Result<Option<Target>> {
+        let url = match headers.get(TARGET_HEADER)? {
+            Some(url) => url,
+            None => return Ok(None),
+        };
+        Ok(Some(Target { url, credential: headers.get(CREDENTIAL_HEADER)?, protocols: headers.get(PROTOCOL_HEADER)? }))
+    }
+
+    /// Adds the upgrade and its target to the edge's request to the processor
+    pub fn forward(&self, headers: &Headers) -> Result<()> {
+        headers.set("Upgrade", "websocket")?;
+        headers.set(TARGET_HEADER, &self.url)?;
+        if let Some(credential) = &self.credential {
+            headers.set(CREDENTIAL_HEADER, credential)?;
+        }
+        if let Some(protocols) = &self.protocols {
+            headers.set(PROTOCOL_HEADER, protocols)?;
+        }
+        Ok(())
+    }
+
+    /// The URL the upgrade is fetched from: http for ws, https for wss
+    pub fn fetch_url(&self) -> String {
+        match self.url.split_once("://") {
+            Some(("ws", rest)) => format!("http://{}", rest),
+            Some(("wss", rest)) => format!("https://{}", rest),
+            _ => self.url.clone(),
+        }
+    }
+
+    /// A request body standing in for the upgrade in tenant host, routing rule
+    /// and maintenance checks, which read the target from `url`
+    pub fn policy_body(&self) -> String {
+        json!({ "url": self.fetch_url() }).to_string()
+    }
+}
+
+/// Connects to `target` and relays frames between it and a new client socket
+///
+/// Runs in the processor, so the upstream connection is made from the
+/// processor's region. Frames over the request size limit (client to
+/// upstream) or the response size limit (upstream to client) close both
+/// sides with 1009. Either side closing closes the other.
+pub async fn bridge(
+    env: &Env,
+    config: &Config,
+    tenant_id: &str,
+    target: &Target,
+) -> std::result::Result<Response, ProxyError> {
+    let fetch_url = target.fetch_url();
+    config.target_guard().check(&fetch_url, &Cell::new(CostEstimate::default())).await?;
+
+    let headers = Headers::new();
+    if let Some(name) = &target.credential {
+        let (credential, _) = credentials::resolve(env, name, tenant_id, &fetch_url).await?;
+        for (name, value) in &credential.headers {
+            headers.set(name, value)?;
+        }
+    }
+    headers.set("Upgrade", "websocket")?;
+    if let Some(protocols) = &target.protocols {
+        headers.set(PROTOCOL_HEADER, protocols)?;
+    }
+    let mut init = RequestInit::new();
+    init.method = Method::Get;
+    init.headers = headers;
+    let upgrade = Fetch::Request(Request::new_with_init(&fetch_url, &init)?)
+        .send()
+        .await
+        .map_err(|e| ProxyError::Upstream(format!("WebSocket connection to {} failed: {}", target.url, e)))?;
+    let status = upgrade.status_code();
+    let protocol = upgrade.headers().get(PROTOCOL_HEADER)?;
+    let upstream = upgrade.websocket().ok_or_else(|| {
+        ProxyError::Upstream(format!("{} answered the WebSocket upgrade with status {}", target.url, status))
+    })?;
+    upstream.accept()?;
+
+    let pair = WebSocketPair::new()?;
+    pair.server.accept()?;
+    log_info!("WebSocket to {} open", target.url);
+
+    // The relays outlive this request; the open sockets keep the processor running
+    let context = logger::current_context()
+        .unwrap_or_else(|| logger::LogContext::new(&logger::request_id(None), "/", Date::now().as_millis()));
+    let client_side = relay(pair.server.clone(), upstream.clone(), "client", config.max_request_bytes.value);
+    let upstream_side = relay(upstream, pair.server, "upstream", config.max_response_bytes.value);
+    wasm_bindgen_futures::spawn_local(logger::scoped(context.clone(), client_side));
+    wasm_bindgen_futures::spawn_local(logger::scoped(context, upstream_side));
+
+    let mut response = Response::from_websocket(pair.client)?;
+    if let Some(protocol) = protocol {
+        response.headers_mut().set(PROTOCOL_HEADER, &protocol)?;
+    }
+    Ok(response)
+}
+
+/// Sends every frame `from` receives on to `to`, until either closes
+async fn relay(from: WebSocket, to: WebSocket, side: &'static str, max_bytes: u64) {
+    let mut events = match from.events() {
+        Ok(events) => events,
+        Err(e) => {
+            log_error!("WebSocket {} events unavailable: {}", side, e);
+            return;
+        }
+    };
+    let mut frames = 0u64;
+    let close_both = |code: u16, reason: &str| {
+        let _ = from.close(Some(code), Some(reason));
+        let _ = to.close(Some(code), Some(reason));
+    };
+    while let Some(event) = events.next().await {
+        let message = match event {
+            Ok(WebsocketEvent::Message(message)) => message,
+            Ok(WebsocketEvent::Close(close)) => {
+                log_info!("WebSocket closed by the {} with {} after {} frames", side, close.code(), frames);
+                let _ = to.close(Some(close_code(close.code())), Some(close.reason()));
+                return;
+            }
+            Err(e) => {
+                log_error!("WebSocket {} failed after {} frames: {}", side, frames, e);
+                close_both(1011, "Relay failed");
+                return;
+            }
+        };
+        let text = message.text();
+        let bytes = if text.is_none() { message.bytes() } else { None };
+        let size = text.as_ref().map_or(0, String::len) + bytes.as_ref().map_or(0, Vec::len);
+        if size as u64 > max_bytes {
+            log_info!("WebSocket {} frame of {} bytes is over the {}-byte limit", side, size, max_bytes);
+            close_both(1009, &format!("Frames are limited to {} bytes", max_bytes));
+            return;
+        }
+        let sent = match (text, bytes) {
+            (Some(text), _) => to.send_with_str(text),
+            (None, Some(bytes)) => to.send_with_bytes(bytes),
+            (None, None) => Ok(()),
+        };
+        if let Err(e) = sent {
+            log_error!("WebSocket frame from the {} not relayed: {}", side, e);
+            close_both(1011, "Relay failed");
+            return;
+        }
+        frames += 1;
+    }
+}
+
+/// The code a close is passed on with; reserved codes only describe how the
+/// other side's connection ended and can't be sent
+fn close_code(code: u16) -> u16 {
+    match code {
+        1000..=1003 | 1007..=1014 | 3000..=4999 => code,
+        // Closed without a code
+        1005 => 1000,
+        // Dropped without a close frame, or a code no endpoint may send
+        _ => 1011,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_target_from_query() {
+        let upgrade = |query: &str| Url::parse(&format!("https://proxy.example/ws?{}", query)).unwrap();
+        let target = Target::from_query(
+            &upgrade("url=wss%3A%2F%2Fevents.carrier.example%2Fcalls%3Faccount%3D7&credential=carrier-prod"),
+            Some("v2.events".to_string()),
+        )
+        .unwrap();
+        assert_eq!(target.url, "wss://events.carrier.example/calls?account=7");
+        assert_eq!(target.credential.as_deref(), Some("carrier-prod"));
+        assert_eq!(target.fetch_url(), "https://events.carrier.example/calls?account=7");
+        assert_eq!(target.policy_body(), r#"{"url":"https://events.carrier.example/calls?account=7"}"#);
+        let plain = Target::from_query(&upgrade("url=ws://events.carrier.example:8080/"), None).unwrap();
+        assert_eq!(plain.fetch_url(), "http://events.carrier.example:8080/");
+
+        for bad in ["", "url=https://events.carrier.example/", "url=wss://", "url=wss://a.example&credential=a%20b"] {
+            let error = Target::from_query(&upgrade(bad), None).unwrap_err();
+            assert_eq!(error.code(), "INVALID_REQUEST", "{}", bad);
+        }
+
+        assert_eq!(close_code(1000), 1000);
+        assert_eq!(close_code(4001), 4001);
+        assert_eq!(close_code(1005), 1000);
+        assert_eq!(close_code(1006), 1011);
+    }
+}
@@ -0,0 +1,332 @@
+use anyhow::Context as AnyhowContext;
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::Reader;
+use serde_json::{Map, Value};
+use std::collections::{HashMap, HashSet};
+
+/// An XML element with namespace prefixes dropped from its name and attribute names
+#[derive(Debug, Default)]
+pub struct Element {
+    pub name: String,
+    pub attributes: HashMap<String, String>,
+    pub children: Vec<Element>,
+    pub text: String,
+}
+
+impl Element {
+    pub fn attribute(&self, name: &str) -> Option<&str> {
+        self.attributes.get(name).map(String::as_str)
+    }
+
+    /// An attribute naming another definition, without its prefix ("tns:Foo" is "Foo")
+    pub fn reference(&self, name: &str) -> Option<&str> {
+        self.attribute(name).map(local_name)
+    }
+
+    pub fn children<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a Element> {
+        self.children.iter().filter(move |child| child.name == name)
+    }
+
+    pub fn child(&self, name: &str) -> Option<&Element> {
+        self.children.iter().find(|child| child.name == name)
+    }
+
+    pub fn named<'a>(&'a self, kind: &'a str, name: &str) -> Option<&'a Element> {
+        self.children(kind).find(|child| child.attribute("name") == Some(name))
+    }
+
+    /// The element as JSON, with `xml_to_json`'s default conventions: a text-only
+    /// element is its text, others an object of `@` attributes, children and `#text`
+    pub fn to_json(&self) -> Value {
+        if self.attributes.is_empty() && self.children.is_empty() {
+            return Value::String(self.text.clone());
+        }
+        let mut object = Map::new();
+        for (name, value) in &self.attributes {
+            object.insert(format!("@{}", name), Value::String(value.clone()));
+        }
+        for child in &self.children {
+            let value = child.to_json();
+            match object.get_mut(&child.name) {
+                Some(Value::Array(items)) => items.push(value),
+                Some(first) => *first = Value::Array(vec![first.take(), value]),
+                None => {
+                    object.insert(child.name.clone(), value);
+                }
+            }
+        }
+        if !self.text.is_empty() {
+            object.insert("#text".to_string(), Value::String(self.text.clone()));
+        }
+        Value::Object(object)
+    }
+
+    /// The element and everything below it, in document order
+    fn descendants_or_self<'a>(&'a self, into: &mut Vec<&'a Element>) {
+        into.push(self);
+        for child in &self.children {
+            child.descendants_or_self(into);
+        }
+    }
+}
+
+pub fn local_name(qualified: &str) -> &str {
+    qualified.rsplit(':').next().unwrap_or(qualified)
+}
+
+/// Reads an XML document into the tree of its root element
+pub fn parse_tree(xml: &str) -> anyhow::Result<Element> {
+    let mut reader = Reader::from_str(xml);
+    reader.config_mut().trim_text(true);
+    let mut stack: Vec<Element> = Vec::new();
+
+    loop {
+        match reader.read_event().context("Malformed XML")? {
+            Event::Start(start) => stack.push(open(&start)?),
+            Event::Empty(start) => {
+                let element = open(&start)?;
+                match stack.last_mut() {
+                    Some(parent) => parent.children.push(element),
+                    None => return Ok(element),
+                }
+            }
+            Event::End(_) => {
+                let element = stack.pop().context("Unbalanced XML end tag")?;
+                match stack.last_mut() {
+                    Some(parent) => parent.children.push(element),
+                    None => return Ok(element),
+                }
+            }
+            Event::Text(text) => {
+                if let Some(element) = stack.last_mut() {
+                    element.text.push_str(&text.unescape().context("Invalid XML text")?);
+                }
+            }
+            Event::CData(data) => {
+                if let Some(element) = stack.last_mut() {
+                    element.text.push_str(&String::from_utf8_lossy(&data));
+                }
+            }
+            Event::Eof => anyhow::bail!("XML has no root element"),
+            _ => {}
+        }
+    }
+}
+
+fn open(start: &BytesStart) -> anyhow::Result<Element> {
+    let mut attributes = HashMap::new();
+    for attribute in start.attributes() {
+        let attribute = attribute.context("Invalid XML attribute")?;
+        let key = attribute.key.as_ref();
+        if key == b"xmlns" || key.starts_with(b"xmlns:") {
+            continue;
+        }
+        let value = attribute.unescape_value().context("Invalid XML attribute value")?;
+        attributes.insert(
+            String::from_utf8_lossy(attribute.key.local_name().as_ref()).into_owned(),
+            value.into_owned(),
+        );
+    }
+    Ok(Element {
+        name: String::from_utf8_lossy(start.local_name().as_ref()).into_owned(),
+        attributes,
+        ..Default::default()
+    })
+}
+
+/// A parsed XPath expression, e.g. `//GetQuoteResult/Price/text()`
+///
+/// Supports the subset callers need to pick fragments out of a SOAP or XML
+/// response: `/` children, `//` descendants, `*` wildcards, `[n]` positions
+/// (from 1), and a final `@name` attribute or `text()`. Names match local
+/// names, so `soap:Body` and `Body` are the same step. Predicates other than
+/// positions, axes and functions aren't supported.
+#[derive(Debug, Clone, PartialEq)]
+pub struct XPath {
+    steps: Vec<Step>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Step {
+    Element {
+        descendant: bool,
+        /// `None` for `*`
+        name: Option<String>,
+        position: Option<usize>,
+    },
+    Attribute(String),
+    Text,
+}
+
+/// Something an XPath selects
+#[derive(Debug, Clone, Copy)]
+pub enum Node<'a> {
+    Element(&'a Element),
+    Attribute(&'a str),
+    Text(&'a str),
+}
+
+impl Node<'_> {
+    pub fn to_json(self) -> Value {
+        match self {
+            Node::Element(element) => element.to_json(),
+            Node::Attribute(value) | Node::Text(value) => Value::String(value.to_string()),
+        }
+    }
+}
+
+impl XPath {
+    pub fn parse(path: &str) -> Result<XPath, String> {
+        let mut rest = path.trim();
+        if !rest.starts_with('/') {
+            return Err(format!("XPath must start with /: {}", path));
+        }
+        let mut steps: Vec<Step> = Vec::new();
+        while !rest.is_empty() {
+            if !matches!(steps.last(), None | Some(Step::Element { .. })) {
+                return Err(format!("@attributes and text() must end the XPath {}", path));
+            }
+            let descendant = rest.starts_with("//");
+            // Every step but the first follows the `/` it was split off at
+            rest = rest.strip_prefix("//").or_else(|| rest.strip_prefix('/')).unwrap_or(rest);
+            let end = rest.find('/').unwrap_or(rest.len());
+            let (step, next) = rest.split_at(end);
+            rest = next;
+            steps.push(match step {
+                "" | "@" => return Err(format!("Empty XPath step in {}", path)),
+                _ if descendant && (step == "text()" || step.starts_with('@')) => {
+                    return Err(format!("Only elements can follow // in {}", path))
+                }
+                "text()" => Step::Text,
+                _ if step.starts_with('@') => Step::Attribute(local_name(&step[1..]).to_string()),
+                _ => {
+                    let (name, position) = match step.split_once('[') {
+                        Some((name, index)) => {
+                            let position = index
+                                .strip_suffix(']')
+                                .and_then(|index| index.trim().parse::<usize>().ok())
+                                .filter(|position| *position > 0)
+                                .ok_or_else(|| format!("Invalid XPath position [{} in {}", index, path))?;
+                            (name, Some(position))
+                        }
+                        None => (step, None),
+                    };
+                    if name.is_empty() || name.contains(['[', ']', '(', ')', '@']) {
+                        return Err(format!("Unsupported XPath step {:?} in {}", step, path));
+                    }
+                    Step::Element {
+                        descendant,
+                        name: (name != "*").then(|| local_name(name).to_string()),
+                        position,
+                    }
+                }
+            });
+        }
+        if steps.is_empty() {
+            return Err(format!("XPath selects no element: {}", path));
+        }
+        Ok(XPath { steps })
+    }
+
+    /// Every node the path matches under the document whose root is `root`
+    pub fn select<'a>(&self, root: &'a Element) -> Vec<Node<'a>> {
+        // `None` is the document itself, whose only child is the root element
+        let mut current: Vec<Option<&'a Element>> = vec![None];
+        for step in &self.steps {
+            let (descendant, name, position) = match step {
+                Step::Element { descendant, name, position } => (*descendant, name, *position),
+                Step::Attribute(name) => {
+                    return current
+                        .into_iter()
+                        .flatten()
+                        .filter_map(|e| e.attribute(name))
+                        .map(Node::Attribute)
+                        .collect()
+                }
+                Step::Text => {
+                    return current
+                        .into_iter()
+                        .flatten()
+                        .filter(|e| !e.text.is_empty())
+                        .map(|e| Node::Text(e.text.as_str()))
+                        .collect()
+                }
+            };
+            let parents: Vec<Option<&'a Element>> = if descendant {
+                let mut parents = Vec::new();
+                for parent in current {
+                    let mut below = Vec::new();
+                    parent.unwrap_or(root).descendants_or_self(&mut below);
+                    // The document's descendants start with the root element
+                    parents.extend(parent.is_none().then_some(None));
+                    parents.extend(below.into_iter().map(Some));
+                }
+                parents
+            } else {
+                current
+            };
+
+            // Nested context nodes reach the same descendants more than once
+            let mut seen = HashSet::new();
+            current = parents
+                .into_iter()
+                .flat_map(|parent| {
+                    let children: Vec<&'a Element> = match parent {
+                        None => vec![root],
+                        Some(parent) => parent.children.iter().collect(),
+                    };
+                    let matching = children
+                        .into_iter()
+                        .filter(|child| name.as_ref().is_none_or(|name| &child.name == name));
+                    match position {
+                        Some(position) => matching.skip(position - 1).take(1).collect::<Vec<_>>(),
+                        None => matching.collect(),
+                    }
+                })
+                .filter(|element| seen.insert(*element as *const Element))
+                .map(Some)
+                .collect();
+        }
+        current.into_iter().flatten().map(Node::Element).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_select() {
+        let xml = r#"<?xml version="1.0"?>
+            <soap:Envelope xmlns:soap="http://schemas.xmlsoap.org/soap/envelope/">
+              <soap:Body>
+                <ns1:getDIDCountryResponse xmlns:ns1="urn:didx">
+                  <country code="US"><name>United States</name><prefix>1</prefix></country>
+                  <country code="GB"><name>United Kingdom</name><prefix>44</prefix></country>
+                  <note><![CDATA[a < b]]></note>
+                </ns1:getDIDCountryResponse>
+              </soap:Body>
+            </soap:Envelope>"#;
+        let root = parse_tree(xml).unwrap();
+        let select = |path: &str| -> Vec<Value> {
+            XPath::parse(path).unwrap().select(&root).into_iter().map(Node::to_json).collect()
+        };
+
+        assert_eq!(select("/Envelope/Body/getDIDCountryResponse/country[2]/name"), vec![json!("United Kingdom")]);
+        assert_eq!(select("//country/@code"), vec![json!("US"), json!("GB")]);
+        assert_eq!(select("//soap:Body/*/note/text()"), vec![json!("a < b")]);
+        assert_eq!(select("//prefix[1]"), vec![json!("1"), json!("44")]);
+        assert_eq!(select("//country[1]"), vec![json!({"@code": "US", "name": "United States", "prefix": "1"})]);
+        assert_eq!(select("//Body//name").len(), 2);
+        assert!(select("/Body").is_empty());
+        assert!(select("//country[3]").is_empty());
+
+        assert!(XPath::parse("Envelope/Body").is_err());
+        assert!(XPath::parse("//@code").is_err());
+        assert!(XPath::parse("/a/text()/b").is_err());
+        assert!(XPath::parse("/a[0]").is_err());
+        assert!(XPath::parse("/a[@id='1']").is_err());
+        assert!(XPath::parse("/").is_err());
+    }
+}